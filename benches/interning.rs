@@ -0,0 +1,54 @@
+//! Compares the records of a full feed with `String` IDs and with interned IDs.
+//!
+//! Run with `DYSTONSE_BENCH_SCHEDULE=<path to a GTFS zip> cargo bench --bench interning`, ideally with
+//! the full feed of a whole region (e.g. VBN), as that is what the importer and monitor load. Each stop
+//! time of the schedule stands for one record of an import, with its own trip and stop ID. Adding the
+//! schedule to the symbol table is measured as well, as it happens on each schedule (re)load.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use dystonse_gtfs_data::types::{Id, SymbolTable};
+use gtfs_structures::Gtfs;
+use std::mem::size_of;
+
+fn load_schedule() -> Gtfs {
+    let filename = std::env::var("DYSTONSE_BENCH_SCHEDULE").expect("DYSTONSE_BENCH_SCHEDULE must be the path of a GTFS schedule.");
+    let schedule = Gtfs::new(&filename).expect("Could not load the schedule.");
+    SymbolTable::global().add_schedule(&schedule);
+    schedule
+}
+
+fn records_with_strings(schedule: &Gtfs) -> Vec<(String, String)> {
+    schedule.trips.values()
+        .flat_map(|trip| trip.stop_times.iter().map(move |stop_time| (trip.id.clone(), stop_time.stop.id.clone())))
+        .collect()
+}
+
+fn records_with_ids(schedule: &Gtfs) -> Vec<(Id, Id)> {
+    schedule.trips.values()
+        .flat_map(|trip| trip.stop_times.iter().map(move |stop_time| (Id::new(&trip.id), Id::new(&stop_time.stop.id))))
+        .collect()
+}
+
+fn report_memory(schedule: &Gtfs) {
+    let strings = records_with_strings(schedule);
+    let string_bytes: usize = strings.iter().map(|(trip_id, stop_id)| 2 * size_of::<String>() + trip_id.capacity() + stop_id.capacity()).sum();
+    let ids = records_with_ids(schedule);
+    let id_bytes = ids.len() * 2 * size_of::<Id>();
+    println!("{} records: {} bytes for the IDs as strings, {} bytes as interned IDs (plus {} IDs in the symbol table).",
+        strings.len(), string_bytes, id_bytes, SymbolTable::global().len());
+}
+
+fn bench_interning(c: &mut Criterion) {
+    let schedule = load_schedule();
+    report_memory(&schedule);
+
+    let mut group = c.benchmark_group("records of a full feed");
+    group.sample_size(10);
+    group.bench_function("string ids", |b| b.iter(|| records_with_strings(&schedule)));
+    group.bench_function("interned ids", |b| b.iter(|| records_with_ids(&schedule)));
+    group.bench_function("add schedule", |b| b.iter(|| SymbolTable::global().add_schedule(&schedule)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_interning);
+criterion_main!(benches);
@@ -0,0 +1,284 @@
+use chrono::{Duration, Local};
+use clap::ArgMatches;
+use itertools::Itertools;
+use mysql::*;
+use mysql::prelude::*;
+use parse_duration::parse;
+use serde::{Serialize, Deserialize};
+use simple_error::bail;
+use std::collections::HashMap;
+
+use crate::types::{DbItem, EventType};
+use crate::{FnResult, Main};
+
+/// Aggregates old rows of the `records` table into compact histograms, which are stored in the
+/// `record_histograms` table, and deletes the raw rows afterwards.
+///
+/// There is one histogram per route variant, pair of stops and event type. It counts how often
+/// a combination of departure delay at the start stop and arrival/departure delay at the end stop
+/// occured, with both delays rounded to buckets. Histograms where start and end stop are the same
+/// contain the plain delay distribution of that stop, with the start delay bucket always being 0.
+pub struct RecordArchiver<'a> {
+    pub main: &'a Main,
+    pub args: &'a ArgMatches,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DelayHistogram {
+    /// size of each bucket in seconds
+    pub bucket_size: i32,
+    /// number of records for each pair of (start delay bucket, end delay bucket)
+    pub counts: HashMap<(i32, i32), u32>,
+}
+
+impl DelayHistogram {
+    pub fn new(bucket_size: i32) -> Self {
+        DelayHistogram {
+            bucket_size,
+            counts: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, start_delay: i32, end_delay: i32) {
+        let key = (self.bucket(start_delay), self.bucket(end_delay));
+        *self.counts.entry(key).or_insert(0) += 1;
+    }
+
+    pub fn merge(&mut self, other: &DelayHistogram) -> FnResult<()> {
+        if other.bucket_size != self.bucket_size {
+            bail!("Can't merge histograms with bucket sizes {} and {}.", self.bucket_size, other.bucket_size);
+        }
+        for (key, count) in &other.counts {
+            *self.counts.entry(*key).or_insert(0) += count;
+        }
+        Ok(())
+    }
+
+    pub fn sample_size(&self) -> u32 {
+        self.counts.values().sum()
+    }
+
+    /// Serializes the histogram for the `histogram` column of the `record_histograms` table.
+    pub fn to_bytes(&self) -> FnResult<Vec<u8>> {
+        Ok(rmp_serde::to_vec(self)?)
+    }
+
+    /// Reads a histogram from the `histogram` column of the `record_histograms` table.
+    pub fn from_bytes(data: &[u8]) -> FnResult<Self> {
+        Ok(rmp_serde::from_read_ref(data)?)
+    }
+
+    // the bucket is identified by the delay at its center
+    fn bucket(&self, delay: i32) -> i32 {
+        ((delay as f32 / self.bucket_size as f32).round() as i32) * self.bucket_size
+    }
+}
+
+// route_variant, start_stop_sequence, end_stop_sequence, event_type
+pub type HistogramKey = (u64, u16, u16, EventType);
+
+/// Reads all archived histograms of a route from the `record_histograms` table.
+pub fn read_histograms(con: &mut PooledConn, source: &str, route_id: &str) -> FnResult<HashMap<HistogramKey, DelayHistogram>> {
+    let rows: Vec<(u64, u16, u16, u8, Vec<u8>)> = con.exec(
+        r"SELECT route_variant, start_stop_sequence, end_stop_sequence, event_type, histogram
+        FROM record_histograms WHERE source = :source AND route_id = :route_id",
+        params! {
+            source,
+            route_id,
+        },
+    )?;
+    let mut histograms = HashMap::new();
+    for (route_variant, start_stop_sequence, end_stop_sequence, event_type, data) in rows {
+        histograms.insert((route_variant, start_stop_sequence, end_stop_sequence, EventType::from_int(event_type)), DelayHistogram::from_bytes(&data)?);
+    }
+    Ok(histograms)
+}
+
+impl<'a> RecordArchiver<'a> {
+    pub fn run_archive(&self) -> FnResult<()> {
+        let max_age = Duration::from_std(parse(self.args.value_of("older-than").unwrap())?)?;
+        let bucket_size = parse(self.args.value_of("bucket-size").unwrap())?.as_secs() as i32;
+        if bucket_size < 1 {
+            bail!("Bucket size must be at least one second.");
+        }
+        let dry_run = self.args.is_present("dry-run");
+        let cutoff_date = (Local::now() - max_age).date();
+
+        info!("Archiving records with trip start before {}{}…", cutoff_date.naive_local(), if dry_run { " (dry run)" } else { "" });
+
+        let mut con = self.main.pool.get_conn()?;
+        if !dry_run {
+            con.query_drop(r"CREATE TABLE IF NOT EXISTS `record_histograms` (
+                `source` VARCHAR(255) NOT NULL,
+                `route_id` VARCHAR(255) NOT NULL,
+                `route_variant` BIGINT UNSIGNED NOT NULL,
+                `start_stop_sequence` SMALLINT UNSIGNED NOT NULL,
+                `end_stop_sequence` SMALLINT UNSIGNED NOT NULL,
+                `event_type` TINYINT UNSIGNED NOT NULL,
+                `sample_size` INT UNSIGNED NOT NULL,
+                `histogram` MEDIUMBLOB NOT NULL,
+                PRIMARY KEY (`source`, `route_id`, `route_variant`, `start_stop_sequence`, `end_stop_sequence`, `event_type`)
+            );")?;
+        }
+
+        let route_ids: Vec<String> = con.exec(
+            r"SELECT DISTINCT route_id FROM records WHERE source = :source AND trip_start_date < :cutoff_date",
+            params! {
+                "source" => &self.main.source,
+                "cutoff_date" => cutoff_date.naive_local(),
+            },
+        )?;
+        info!("Found old records for {} routes.", route_ids.len());
+
+        let mut total_records = 0;
+        let mut total_histograms = 0;
+        for route_id in route_ids {
+            let (record_count, histogram_count) = self.archive_route(&mut con, &route_id, cutoff_date.naive_local(), bucket_size, dry_run)?;
+            total_records += record_count;
+            total_histograms += histogram_count;
+        }
+
+        info!("Archived {} records into {} histograms.", total_records, total_histograms);
+        Ok(())
+    }
+
+    fn archive_route(&self, con: &mut PooledConn, route_id: &str, cutoff_date: chrono::NaiveDate, bucket_size: i32, dry_run: bool) -> FnResult<(usize, usize)> {
+        let stmt = con.prep(
+            r"SELECT
+                delay_arrival,
+                delay_departure,
+                trip_start_date,
+                trip_start_time,
+                trip_id,
+                stop_id,
+                stop_sequence,
+                route_variant
+            FROM
+                records
+            WHERE
+                source = :source AND
+                route_id = :route_id AND
+                trip_start_date < :cutoff_date
+            ORDER BY
+                trip_start_date,
+                trip_start_time,
+                trip_id,
+                stop_sequence",
+        )?;
+
+        let db_items: Vec<DbItem> = con.exec(
+            &stmt,
+            params! {
+                "source" => &self.main.source,
+                route_id,
+                cutoff_date,
+            },
+        )?;
+
+        let mut histograms: HashMap<HistogramKey, DelayHistogram> = HashMap::new();
+        let trips = db_items.iter().group_by(|item| (item.trip_start_date, item.trip_start_time, item.trip_id.clone()));
+        for (_trip, items) in &trips {
+            let items: Vec<&DbItem> = items.collect();
+            for (i, end) in items.iter().enumerate() {
+                for et in &EventType::TYPES {
+                    let end_delay = match end.delay[**et] {
+                        Some(delay) => delay,
+                        None => continue,
+                    };
+                    histograms.entry((end.route_variant, end.stop_sequence, end.stop_sequence, **et))
+                        .or_insert_with(|| DelayHistogram::new(bucket_size))
+                        .add(0, end_delay);
+                    for start in &items[..i] {
+                        if let Some(start_delay) = start.delay.departure {
+                            histograms.entry((end.route_variant, start.stop_sequence, end.stop_sequence, **et))
+                                .or_insert_with(|| DelayHistogram::new(bucket_size))
+                                .add(start_delay, end_delay);
+                        }
+                    }
+                }
+            }
+        }
+
+        info!("Route {}: {} records, {} histograms.", route_id, db_items.len(), histograms.len());
+        if dry_run {
+            return Ok((db_items.len(), histograms.len()));
+        }
+
+        // merge with histograms from previous runs
+        for (key, existing_histogram) in read_histograms(con, &self.main.source, route_id)? {
+            if let Some(histogram) = histograms.get_mut(&key) {
+                histogram.merge(&existing_histogram)?;
+            }
+        }
+
+        // write histograms and delete raw records in one transaction, so that no data gets lost or counted twice
+        let mut tx = con.start_transaction(TxOpts::default())?;
+        let mut params_vec = Vec::new();
+        for ((route_variant, start_stop_sequence, end_stop_sequence, et), histogram) in &histograms {
+            params_vec.push(params! {
+                "source" => &self.main.source,
+                route_id,
+                "route_variant" => *route_variant,
+                "start_stop_sequence" => *start_stop_sequence,
+                "end_stop_sequence" => *end_stop_sequence,
+                "event_type" => et.to_int(),
+                "sample_size" => histogram.sample_size(),
+                "histogram" => histogram.to_bytes()?,
+            });
+        }
+        tx.exec_batch(
+            r"INSERT INTO record_histograms
+                (source, route_id, route_variant, start_stop_sequence, end_stop_sequence, event_type, sample_size, histogram)
+            VALUES
+                (:source, :route_id, :route_variant, :start_stop_sequence, :end_stop_sequence, :event_type, :sample_size, :histogram)
+            ON DUPLICATE KEY UPDATE
+                sample_size = VALUES(sample_size),
+                histogram = VALUES(histogram)",
+            params_vec,
+        )?;
+        tx.exec_drop(
+            r"DELETE FROM records WHERE source = :source AND route_id = :route_id AND trip_start_date < :cutoff_date",
+            params! {
+                "source" => &self.main.source,
+                route_id,
+                cutoff_date,
+            },
+        )?;
+        tx.commit()?;
+
+        Ok((db_items.len(), histograms.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = DelayHistogram::new(30);
+        histogram.add(0, 14);
+        histogram.add(10, -14);
+        histogram.add(40, 100);
+        assert_eq!(histogram.sample_size(), 3);
+        assert_eq!(histogram.counts.get(&(0, 0)), Some(&2));
+        assert_eq!(histogram.counts.get(&(30, 90)), Some(&1));
+
+        let mut other = DelayHistogram::new(30);
+        other.add(-5, 5);
+        histogram.merge(&other).unwrap();
+        assert_eq!(histogram.counts.get(&(0, 0)), Some(&3));
+        assert!(histogram.merge(&DelayHistogram::new(60)).is_err());
+    }
+
+    #[test]
+    fn test_histogram_round_trip() {
+        let mut histogram = DelayHistogram::new(30);
+        histogram.add(0, 14);
+        histogram.add(-120, 600);
+        let read = DelayHistogram::from_bytes(&histogram.to_bytes().unwrap()).unwrap();
+        assert_eq!(read.bucket_size, 30);
+        assert_eq!(read.counts, histogram.counts);
+        assert!(DelayHistogram::from_bytes(&[0xc1]).is_err());
+    }
+}
@@ -1,20 +1,106 @@
 use mysql::*;
 use mysql::prelude::*;
 use parse_duration::parse;
-use simple_error::SimpleError;
-use chrono::Local;
+use serde::Serialize;
+use simple_error::{SimpleError, bail};
+use chrono::{Local, NaiveDateTime};
 use chrono::offset::TimeZone;
+use clap::ArgMatches;
 
 use super::Analyser;
 
 use crate::FnResult;
-use crate::read_dir_simple;
+use crate::read_dir_recursive;
+use crate::types::OriginType;
 
-use std::fs;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
 
-pub fn run_count(analyser: &Analyser) -> FnResult<()> {
+/// Names of the breakdowns of `analyse count --by`.
+pub const COUNT_BREAKDOWNS: [&str; 5] = ["time", "route", "stop", "agency", "origin-type"];
+
+// delays outside of this range (in seconds) are not counted as valid
+const MAX_VALID_DELAY: i32 = 36000;
+
+/// Number of records (or for the `origin-type` breakdown: predictions) of one route, stop, agency
+/// or origin type.
+#[derive(Serialize)]
+struct GroupCount {
+    id: String,
+    name: String,
+    records: i64,
+    /// records with a valid arrival delay
+    delays: i64,
+    average_delay: Option<f64>,
+    first_recording: Option<String>,
+    last_recording: Option<String>,
+    #[serde(skip)]
+    delay_sum: f64,
+}
+
+/// Number of records and realtime files per time interval.
+#[derive(Serialize)]
+struct IntervalCount {
+    time_min: String,
+    time_max: String,
+    records: i32,
+    average_delay: f32,
+    rt_file_count: usize,
+    rt_file_size: u64,
+}
+
+pub fn run_count(analyser: &Analyser, sub_args: &ArgMatches) -> FnResult<()> {
+    let format = sub_args.value_of("format").unwrap(); // has a default value
+    let mut output: Box<dyn Write> = match sub_args.value_of("output") {
+        Some(file_name) => Box::new(File::create(file_name)?),
+        None => Box::new(std::io::stdout()),
+    };
+    match sub_args.value_of("by").unwrap() { // has a default value
+        "time" => {
+            let rows = count_by_time(analyser, sub_args)?;
+            write_rows(&mut output, format, &rows, "time_min; time_max; stop time update count; average delay; rt file count; rt file size", |row| {
+                format!("{}; {}; {}; {}; {}; {}", row.time_min, row.time_max, row.records, row.average_delay, row.rt_file_count, row.rt_file_size)
+            })
+        },
+        breakdown => {
+            let rows = if breakdown == "origin-type" { count_predictions_by_origin_type(analyser)? } else { count_records_by(analyser, breakdown)? };
+            write_rows(&mut output, format, &rows, "id; name; records; valid delays; average delay; first recording; last recording", |row| {
+                format!("{}; {}; {}; {}; {}; {}; {}", row.id, row.name, row.records, row.delays,
+                    row.average_delay.map_or(String::new(), |delay| format!("{:.1}", delay)),
+                    row.first_recording.as_deref().unwrap_or(""), row.last_recording.as_deref().unwrap_or(""))
+            })
+        },
+    }
+}
+
+// writes the rows as semicolon-separated text (with `header`), as CSV or as JSON
+fn write_rows<T: Serialize>(output: &mut Box<dyn Write>, format: &str, rows: &[T], header: &str, to_text: impl Fn(&T) -> String) -> FnResult<()> {
+    match format {
+        "csv" => {
+            let mut writer = csv::Writer::from_writer(output);
+            for row in rows {
+                writer.serialize(row)?;
+            }
+            writer.flush()?;
+        },
+        "json" => {
+            serde_json::to_writer_pretty(&mut *output, rows)?;
+            writeln!(output)?;
+        },
+        _ => {
+            writeln!(output, "{}", header)?;
+            for row in rows {
+                writeln!(output, "{}", to_text(row))?;
+            }
+        },
+    }
+    Ok(())
+}
+
+fn count_by_time(analyser: &Analyser, sub_args: &ArgMatches) -> FnResult<Vec<IntervalCount>> {
     let imported_dir = format!("{}/imported", &analyser.main.dir);
-    let rt_filenames = read_dir_simple(&imported_dir)?;
+    let rt_filenames = read_dir_recursive(&imported_dir)?;
 
     if rt_filenames.is_empty() {
         return Err(Box::from(SimpleError::new("No realtime data.")));
@@ -27,35 +113,31 @@ pub fn run_count(analyser: &Analyser) -> FnResult<()> {
         let start = Local.from_local_datetime(&start_naive).unwrap();
         let end = Local.from_local_datetime(&end_naive).unwrap();
 
-    let std_date = parse(
-        analyser.args
-            .subcommand_matches("count")
-            .unwrap()
-            .value_of("interval")
-            .unwrap(),
-    )?;
+    let std_date = parse(sub_args.value_of("interval").unwrap())?; // has a default value
     let step: chrono::Duration = chrono::Duration::from_std(std_date)?;
     let mut time_min = start;
     let mut time_max = start + step;
-    println!(
-        "time_min; time_max; stop time update count; average delay; rt file count; rt file size"
-    );
+    let mut rows = Vec::new();
     loop {
         let mut rt_file_count = 0;
         let mut rt_file_size = 0;
         let row: mysql::Row = con
             .exec_first(
-                "SELECT COUNT(*), AVG(delay_arrival) 
-                FROM records 
-                WHERE (`time_of_recording` BETWEEN ? AND ?) 
-                AND (delay_arrival BETWEEN - 36000 AND 36000) 
-                AND source = ?",
-                (time_min.naive_local(), time_max.naive_local(), &analyser.main.source),
+                "SELECT COUNT(*), AVG(delay_arrival)
+                FROM records
+                WHERE (`time_of_recording` BETWEEN :time_min AND :time_max)
+                AND (delay_arrival BETWEEN -:max_delay AND :max_delay)
+                AND source = :source",
+                params! {
+                    "time_min" => time_min.naive_local(),
+                    "time_max" => time_max.naive_local(),
+                    "max_delay" => MAX_VALID_DELAY,
+                    "source" => &analyser.main.source,
+                },
             )?
             .unwrap();
         let count: i32 = row.get(0).unwrap();
         let delay: f32 = row.get_opt(1).unwrap().unwrap_or(-1.0);
-        // println!("Between {} and {} there are {} delay values, average is {} seconds.", time_min, time_max, count, delay);
 
         for rt_filename in &rt_filenames {
             let rt_date = Analyser::date_time_from_filename(&rt_filename).unwrap();
@@ -65,10 +147,14 @@ pub fn run_count(analyser: &Analyser) -> FnResult<()> {
             }
         }
 
-        println!(
-            "{}; {}; {}; {}; {}; {}",
-            time_min, time_max, count, delay, rt_file_count, rt_file_size
-        );
+        rows.push(IntervalCount {
+            time_min: time_min.to_string(),
+            time_max: time_max.to_string(),
+            records: count,
+            average_delay: delay,
+            rt_file_count,
+            rt_file_size,
+        });
         time_min = time_max;
         time_max = time_min + step;
         if time_max > end {
@@ -76,5 +162,166 @@ pub fn run_count(analyser: &Analyser) -> FnResult<()> {
         }
     }
 
-    Ok(())
-}
\ No newline at end of file
+    Ok(rows)
+}
+
+// counts the records by route, stop or agency, for the routes of the selected agencies
+fn count_records_by(analyser: &Analyser, breakdown: &str) -> FnResult<Vec<GroupCount>> {
+    let schedule = &analyser.schedule;
+    let mut con = analyser.main.pool.get_conn()?;
+    let db_rows: Vec<(String, String, i64, i64, Option<f64>, Option<NaiveDateTime>, Option<NaiveDateTime>)> = con.exec(
+        r"SELECT
+            route_id,
+            stop_id,
+            COUNT(*),
+            COUNT(CASE WHEN delay_arrival BETWEEN -:max_delay AND :max_delay THEN 1 END),
+            SUM(CASE WHEN delay_arrival BETWEEN -:max_delay AND :max_delay THEN delay_arrival END),
+            MIN(time_of_recording),
+            MAX(time_of_recording)
+        FROM
+            records
+        WHERE
+            source = :source
+        GROUP BY
+            route_id,
+            stop_id",
+        params! {
+            "source" => &analyser.main.source,
+            "max_delay" => MAX_VALID_DELAY,
+        },
+    )?;
+
+    let mut counts: HashMap<String, GroupCount> = HashMap::new();
+    for (route_id, stop_id, records, delays, delay_sum, first, last) in db_rows {
+        if !analyser.is_route_selected(&route_id) {
+            continue;
+        }
+        let (id, name) = match breakdown {
+            "route" => {
+                let name = schedule.get_route(&route_id).map_or_else(|_| String::new(), |route| route.short_name.clone());
+                (route_id, name)
+            },
+            "stop" => {
+                let name = schedule.get_stop(&stop_id).map_or_else(|_| String::new(), |stop| stop.name.clone());
+                (stop_id, name)
+            },
+            "agency" => {
+                let agency_id = schedule.get_route(&route_id).ok().and_then(|route| route.agency_id.clone()).unwrap_or_default();
+                let name = schedule.agencies.iter()
+                    .find(|agency| agency.id.as_deref() == Some(agency_id.as_str()))
+                    .map_or_else(String::new, |agency| agency.name.clone());
+                (agency_id, name)
+            },
+            _ => bail!("Unknown breakdown {}.", breakdown),
+        };
+        counts.entry(id.clone()).or_insert_with(|| GroupCount::new(id, name)).add(records, delays, delay_sum.unwrap_or(0.0), first, last);
+    }
+    Ok(GroupCount::into_sorted_rows(counts))
+}
+
+// counts the current predictions by origin type, for the routes of the selected agencies
+fn count_predictions_by_origin_type(analyser: &Analyser) -> FnResult<Vec<GroupCount>> {
+    let mut con = analyser.main.pool.get_conn()?;
+    let db_rows: Vec<(u8, String, i64)> = con.exec(
+        r"SELECT
+            origin_type,
+            route_id,
+            COUNT(*)
+        FROM
+            predictions
+        WHERE
+            source = :source
+        GROUP BY
+            origin_type,
+            route_id",
+        params! {
+            "source" => &analyser.main.source,
+        },
+    )?;
+
+    let mut counts: HashMap<String, GroupCount> = HashMap::new();
+    for (origin_type, route_id, predictions) in db_rows {
+        if !analyser.is_route_selected(&route_id) {
+            continue;
+        }
+        let id = origin_type.to_string();
+        counts.entry(id.clone())
+            .or_insert_with(|| GroupCount::new(id, format!("{:?}", OriginType::from_int(origin_type))))
+            .add(predictions, 0, 0.0, None, None);
+    }
+    Ok(GroupCount::into_sorted_rows(counts))
+}
+
+impl GroupCount {
+    fn new(id: String, name: String) -> Self {
+        GroupCount {
+            id,
+            name,
+            records: 0,
+            delays: 0,
+            average_delay: None,
+            first_recording: None,
+            last_recording: None,
+            delay_sum: 0.0,
+        }
+    }
+
+    fn add(&mut self, records: i64, delays: i64, delay_sum: f64, first: Option<NaiveDateTime>, last: Option<NaiveDateTime>) {
+        self.records += records;
+        self.delays += delays;
+        self.delay_sum += delay_sum;
+        if self.delays > 0 {
+            self.average_delay = Some(self.delay_sum / self.delays as f64);
+        }
+        // the formatted times sort like the times themselves
+        let format = |time: NaiveDateTime| time.format("%Y-%m-%d %H:%M:%S").to_string();
+        if let Some(first) = first.map(format) {
+            if self.first_recording.as_ref().map_or(true, |current| first < *current) {
+                self.first_recording = Some(first);
+            }
+        }
+        if let Some(last) = last.map(format) {
+            if self.last_recording.as_ref().map_or(true, |current| last > *current) {
+                self.last_recording = Some(last);
+            }
+        }
+    }
+
+    // sorted by id, so that reports of different days can be compared line by line
+    fn into_sorted_rows(counts: HashMap<String, GroupCount>) -> Vec<GroupCount> {
+        let mut rows: Vec<GroupCount> = counts.into_iter().map(|(_, count)| count).collect();
+        rows.sort_by(|a, b| a.id.cmp(&b.id));
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn time(day: u32, hour: u32) -> Option<NaiveDateTime> {
+        Some(NaiveDate::from_ymd(2020, 10, day).and_hms(hour, 0, 0))
+    }
+
+    #[test]
+    fn test_group_count() {
+        let mut count = GroupCount::new(String::from("r1"), String::from("1"));
+        count.add(10, 0, 0.0, time(2, 8), time(2, 9));
+        assert_eq!(count.average_delay, None);
+        count.add(20, 4, 240.0, time(1, 12), time(3, 7));
+        count.add(5, 1, -40.0, time(2, 6), time(2, 23));
+        assert_eq!(count.records, 35);
+        assert_eq!(count.delays, 5);
+        assert_eq!(count.average_delay, Some(40.0));
+        assert_eq!(count.first_recording.as_deref(), Some("2020-10-01 12:00:00"));
+        assert_eq!(count.last_recording.as_deref(), Some("2020-10-03 07:00:00"));
+
+        let mut counts = HashMap::new();
+        for id in &["s2", "s10", "s1"] {
+            counts.insert(id.to_string(), GroupCount::new(id.to_string(), String::new()));
+        }
+        let ids: Vec<String> = GroupCount::into_sorted_rows(counts).into_iter().map(|count| count.id).collect();
+        assert_eq!(ids, vec!["s1", "s10", "s2"]);
+    }
+}
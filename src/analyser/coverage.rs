@@ -0,0 +1,167 @@
+use chrono::{Date, Local, NaiveDate, TimeZone};
+use clap::ArgMatches;
+use gtfs_structures::Trip;
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+use super::Analyser;
+
+use crate::{FnResult, Main};
+use crate::predictor::Predictor;
+use crate::time_util::date_and_time;
+use crate::types::{EventType, PrecisionType, PredictionBasis, PredictionResult};
+
+// the precision types which the predictor uses for the stop events of a trip, from the best to the worst
+const PRECISION_TYPES: [PrecisionType; 7] = [
+    PrecisionType::Specific,
+    PrecisionType::FallbackSpecific,
+    PrecisionType::BlockPropagated,
+    PrecisionType::SemiSpecific,
+    PrecisionType::General,
+    PrecisionType::FallbackGeneral,
+    PrecisionType::SuperGeneral,
+];
+
+/// How many of the stop events of a route would be predicted with each precision type.
+#[derive(Default, Clone)]
+pub struct RouteCoverage {
+    pub events: usize,
+    /// number of events per precision type, indexed by `PrecisionType::to_int`
+    pub by_precision: [usize; 8],
+    /// events for which no prediction could be made at all
+    pub failed: usize,
+}
+
+impl RouteCoverage {
+    fn add(&mut self, other: &RouteCoverage) {
+        self.events += other.events;
+        for (count, other_count) in self.by_precision.iter_mut().zip(other.by_precision.iter()) {
+            *count += other_count;
+        }
+        self.failed += other.failed;
+    }
+
+    pub fn count(&self, precision_type: &PrecisionType) -> usize {
+        self.by_precision[precision_type.to_int() as usize]
+    }
+
+    /// Events that are only predicted from the default curves, or not at all. More records
+    /// of the route would improve their predictions.
+    pub fn general_or_worse(&self) -> usize {
+        self.count(&PrecisionType::General) + self.count(&PrecisionType::FallbackGeneral) + self.count(&PrecisionType::SuperGeneral) + self.failed
+    }
+
+    fn percentage(&self, count: usize) -> f32 {
+        if self.events == 0 {
+            return 0.0;
+        }
+        100.0 * count as f32 / self.events as f32
+    }
+}
+
+pub struct CoverageAnalyser<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches,
+}
+
+impl<'a> CoverageAnalyser<'a> {
+    /// Makes the predictions for all stop events of the scheduled trips of a day, and prints per
+    /// route which share of them would be made with each precision type.
+    pub fn run_coverage(&self) -> FnResult<()> {
+        let date = match self.args.value_of("date") {
+            Some(date) => NaiveDate::parse_from_str(date, "%Y-%m-%d")?,
+            None => Local::today().naive_local(),
+        };
+        let with_realtime = self.args.is_present("assume-realtime");
+        let predictor = Predictor::new(self.main, self.args)?;
+        let schedule = &predictor.schedule;
+
+        let mut runs_by_service: HashMap<&String, bool> = HashMap::new();
+        let trips: Vec<&Trip> = schedule.trips.values().filter(|trip| {
+            self.analyser.is_route_selected(&trip.route_id) && *runs_by_service.entry(&trip.service_id).or_insert_with(|| {
+                schedule.trip_days(&trip.service_id, date).contains(&0)
+            })
+        }).collect();
+        info!("Making predictions for the {} trips on {}…", trips.len(), date);
+
+        let service_day = Local.from_local_date(&date).unwrap();
+        let trip_coverages: Vec<(&String, RouteCoverage)> = trips.par_iter()
+            .map(|trip| (&trip.route_id, Self::get_trip_coverage(&predictor, trip, &service_day, with_realtime)))
+            .collect();
+        let mut total = RouteCoverage::default();
+        let mut coverage_by_route: HashMap<&String, RouteCoverage> = HashMap::new();
+        for (route_id, coverage) in &trip_coverages {
+            coverage_by_route.entry(route_id).or_default().add(coverage);
+            total.add(coverage);
+        }
+        // the routes which need data the most come first
+        let mut routes: Vec<(&String, RouteCoverage)> = coverage_by_route.into_iter().collect();
+        routes.sort_by(|(_, a), (_, b)| b.general_or_worse().cmp(&a.general_or_worse()));
+
+        println!("Precision of the predictions for {} stop events of {} trips on {}, {}:", total.events, trips.len(), date,
+            if with_realtime { "with a realtime delay at the first stop" } else { "without realtime data" });
+        let type_names: Vec<String> = PRECISION_TYPES.iter().map(|precision_type| format!("{:?}", precision_type)).collect();
+        println!("route_id; route name; events; {}; failed; general or worse", type_names.join("; "));
+        println!("{}", Self::format_line("all", "", &total));
+        for (route_id, coverage) in &routes {
+            let route_name = schedule.get_route(route_id).map(|route| route.short_name.clone()).unwrap_or_default();
+            println!("{}", Self::format_line(route_id, &route_name, coverage));
+        }
+        Ok(())
+    }
+
+    fn format_line(route_id: &str, route_name: &str, coverage: &RouteCoverage) -> String {
+        let shares: Vec<String> = PRECISION_TYPES.iter()
+            .map(|precision_type| format!("{:.1}%", coverage.percentage(coverage.count(precision_type))))
+            .collect();
+        format!("{}; {}; {}; {}; {:.1}%; {:.1}%", route_id, route_name, coverage.events, shares.join("; "),
+            coverage.percentage(coverage.failed), coverage.percentage(coverage.general_or_worse()))
+    }
+
+    // predicts all stop events of the trip, like the importer does for trips without realtime data, or
+    // with `with_realtime`, like for a vehicle that has just departed from its first stop on time
+    fn get_trip_coverage(predictor: &Predictor, trip: &Trip, service_day: &Date<Local>, with_realtime: bool) -> RouteCoverage {
+        let mut coverage = RouteCoverage::default();
+        let first_stop_time = match trip.stop_times.first() {
+            Some(stop_time) => stop_time,
+            None => return coverage,
+        };
+        let start = date_and_time(service_day, first_stop_time.departure_time.unwrap_or(0) as i32);
+        let (basis, skip) = if with_realtime {
+            (Some(PredictionBasis { stop_sequence: first_stop_time.stop_sequence, delay_departure: Some(0) }), 1)
+        } else {
+            (None, 0)
+        };
+
+        for stop_time in trip.stop_times.iter().skip(skip) {
+            for event_type in &EventType::TYPES {
+                coverage.events += 1;
+                match predictor.predict(&trip.route_id, &trip.id, &basis, stop_time.stop_sequence, **event_type, start) {
+                    Ok(PredictionResult::CurveData(curve_data)) => coverage.by_precision[curve_data.precision_type.to_int() as usize] += 1,
+                    Ok(PredictionResult::CurveSetData(curve_set_data)) => coverage.by_precision[curve_set_data.precision_type.to_int() as usize] += 1,
+                    Err(_) => coverage.failed += 1,
+                }
+            }
+        }
+        coverage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shares_add_up() {
+        // one event for each precision type that the predictor can return, and one that failed
+        let mut coverage = RouteCoverage { events: 8, by_precision: [0, 1, 1, 1, 1, 1, 1, 1], failed: 1 };
+        let shares: f32 = PRECISION_TYPES.iter().map(|precision_type| coverage.percentage(coverage.count(precision_type))).sum();
+        assert!((shares + coverage.percentage(coverage.failed) - 100.0).abs() < 0.01);
+        assert_eq!(coverage.general_or_worse(), 4);
+
+        coverage.add(&coverage.clone());
+        assert_eq!(coverage.events, 16);
+        assert_eq!(coverage.count(&PrecisionType::BlockPropagated), 2);
+    }
+}
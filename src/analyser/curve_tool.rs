@@ -0,0 +1,252 @@
+use chrono::{NaiveDate, NaiveTime};
+use clap::{Arg, ArgMatches};
+use mysql::*;
+use mysql::prelude::*;
+use serde::Serialize;
+use simple_error::bail;
+
+use dystonse_curves::Curve;
+use dystonse_curves::irregular_dynamic::*;
+
+use super::Analyser;
+use super::curve_utils::{convolve, transfer_probability};
+use crate::predictor::parse_event_type;
+use crate::types::curve_format::PredictionCurve;
+use crate::types::{CurveSetKey, DbPrediction, DelayStatistics, PrecisionType, RouteSection, TimeSlot, WeatherCondition};
+use crate::{FnResult, Main, OrError};
+
+// the quantiles that are printed for each curve
+const QUANTILES: [f32; 9] = [0.01, 0.05, 0.1, 0.25, 0.5, 0.75, 0.9, 0.95, 0.99];
+
+// resolution (in seconds) of convolved curves
+const CONVOLUTION_STEP: f32 = 6.0;
+
+/// Loads single curves from the predictions table or the delay statistics, and prints them or
+/// computes something from them, so that weird predictions can be debugged from the command line.
+pub struct CurveTool<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches,
+}
+
+/// A curve as it is printed, with the place where it came from.
+#[derive(Serialize)]
+struct CurveDump {
+    description: String,
+    precision_type: Option<PrecisionType>,
+    sample_size: Option<u32>,
+    /// (probability, delay in seconds)
+    quantiles: Vec<(f32, f32)>,
+    /// (delay in seconds, cumulative probability)
+    points: Vec<(f32, f32)>,
+}
+
+impl CurveDump {
+    fn new(description: String, curve: &IrregularDynamicCurve<f32, f32>, precision_type: Option<PrecisionType>, sample_size: Option<u32>) -> Self {
+        let (xs, ys) = curve.get_values_as_vectors();
+        CurveDump {
+            description,
+            precision_type,
+            sample_size,
+            quantiles: QUANTILES.iter().map(|q| (*q, curve.x_at_y(*q))).collect(),
+            points: xs.into_iter().zip(ys.into_iter()).collect(),
+        }
+    }
+
+    fn print(&self, format: &str) -> FnResult<()> {
+        match format {
+            "json" => println!("{}", serde_json::to_string_pretty(self)?),
+            "csv" => {
+                let mut writer = csv::Writer::from_writer(std::io::stdout());
+                writer.write_record(&["delay", "probability"])?;
+                for (x, y) in &self.points {
+                    writer.write_record(&[x.to_string(), y.to_string()])?;
+                }
+                writer.flush()?;
+            },
+            _ => {
+                println!("{}", self.description);
+                if let (Some(precision_type), Some(sample_size)) = (&self.precision_type, self.sample_size) {
+                    println!("precision type {:?}, sample size {}", precision_type, sample_size);
+                }
+                println!("{} points from {:.0} s to {:.0} s", self.points.len(), self.points.first().unwrap().0, self.points.last().unwrap().0);
+                for (q, x) in &self.quantiles {
+                    println!("{:>3.0} %: {:>6.0} s", q * 100.0, x);
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// The `--format` argument of the subcommands that print a curve.
+pub fn get_format_arg<'a>() -> Arg<'a> {
+    Arg::new("format")
+        .long("format")
+        .about("Prints the description and quantiles of the curve as text, all of its points as csv, or both as json.")
+        .possible_values(&["text", "csv", "json"])
+        .default_value("text")
+        .value_name("FORMAT")
+        .takes_value(true)
+}
+
+impl<'a> CurveTool<'a> {
+    pub fn run_curve_tool(&self) -> FnResult<()> {
+        match self.args.subcommand() {
+            ("show", Some(sub_args)) => {
+                self.load_curve(sub_args.value_of("curve").unwrap())?.print(sub_args.value_of("format").unwrap()) // both are required or have a default value
+            },
+            ("convolve", Some(sub_args)) => {
+                let first = self.load_curve(sub_args.value_of("first").unwrap())?; // is required
+                let second = self.load_curve(sub_args.value_of("second").unwrap())?; // is required
+                let curve = convolve(&curve_from_dump(&first), &curve_from_dump(&second), CONVOLUTION_STEP);
+                let description = format!("sum of ({}) and ({})", first.description, second.description);
+                CurveDump::new(description, &curve, None, None).print(sub_args.value_of("format").unwrap()) // has a default value
+            },
+            ("transfer", Some(sub_args)) => {
+                let arrival = self.load_curve(sub_args.value_of("arrival").unwrap())?; // is required
+                let departure = self.load_curve(sub_args.value_of("departure").unwrap())?; // is required
+                let arrival_time = parse_time(sub_args.value_of("arrival-time").unwrap())?; // is required
+                let departure_time = parse_time(sub_args.value_of("departure-time").unwrap())?; // is required
+                let slack = departure_time.signed_duration_since(arrival_time).num_seconds() as f32;
+                let probability = transfer_probability(&curve_from_dump(&arrival), &curve_from_dump(&departure), slack);
+                println!("Arrival: {}\nDeparture: {}\nScheduled transfer time: {:.0} s\nTransfer probability: {:.1} %",
+                    arrival.description, departure.description, slack, probability * 100.0);
+                Ok(())
+            },
+            ("schema", Some(_)) => {
+                println!("{}", PredictionCurve::json_schema()?);
+                Ok(())
+            },
+            _ => bail!("Use one of the subcommands show, convolve, transfer or schema."),
+        }
+    }
+
+    /// Loads a curve which is given as `<kind>:<fields>`, see the help of the subcommand. The first field
+    /// contains all remaining colons, because route_ids and trip_ids may contain colons themselves.
+    fn load_curve(&self, spec: &str) -> FnResult<CurveDump> {
+        let (kind, fields) = match spec.find(':') {
+            Some(index) => (&spec[..index], &spec[index + 1..]),
+            None => (spec, ""),
+        };
+        match kind {
+            "prediction" => {
+                let fields = split_fields(fields, 4)?;
+                let date = NaiveDate::parse_from_str(fields[1], "%Y-%m-%d")?;
+                let stop_sequence: u16 = fields[2].parse()?;
+                let event_type = parse_event_type(fields[3])?;
+                let mut con = self.main.pool.get_conn()?;
+                // a prediction from realtime data is better than one from the schedule
+                let prediction: Option<DbPrediction> = con.exec_first(
+                    r"SELECT
+                        `route_id`,
+                        `trip_id`,
+                        `trip_start_date`,
+                        `trip_start_time`,
+                        `prediction_min`,
+                        `prediction_max`,
+                        `precision_type`,
+                        `origin_type`,
+                        `sample_size`,
+                        `prediction_curve`,
+                        `stop_id`,
+                        `stop_sequence`,
+                        `event_type`
+                    FROM
+                        `predictions`
+                    WHERE
+                        `source` = :source AND
+                        `trip_id` = :trip_id AND
+                        `trip_start_date` = :date AND
+                        `stop_sequence` = :stop_sequence AND
+                        `event_type` = :event_type
+                    ORDER BY
+                        `origin_type`
+                    LIMIT 1",
+                    params! {
+                        "source" => &self.main.source,
+                        "trip_id" => fields[0],
+                        date,
+                        stop_sequence,
+                        "event_type" => event_type.to_int(),
+                    },
+                )?;
+                let prediction = prediction.or_error("No such prediction in the database.")?;
+                let description = format!("prediction for trip {} on {} at stop {} ({:?}, {:?})",
+                    prediction.trip_id, date, prediction.stop_id, event_type, prediction.origin_type);
+                Ok(CurveDump::new(description, &prediction.prediction_curve, Some(prediction.precision_type), Some(prediction.sample_size as u32)))
+            },
+            "specific" => {
+                let fields = split_fields(fields, 7)?;
+                let key = CurveSetKey {
+                    start_stop_index: fields[2].parse()?,
+                    end_stop_index: fields[3].parse()?,
+                    time_slot: parse_time_slot(fields[4])?,
+                    weather: WeatherCondition::Unknown,
+                };
+                let event_type = parse_event_type(fields[5])?;
+                let start_delay: f32 = fields[6].parse()?;
+                let curve_set_data = self.get_statistics()?.specific.get(fields[0])
+                    .and_then(|route_data| route_data.variants.get(&fields[1].parse().ok()?))
+                    .and_then(|variant_data| variant_data.curve_sets[event_type].get(&key).cloned())
+                    .or_error("No such curve set in the statistics.")?;
+                let curve = curve_set_data.curve_set.curve_at_x_with_continuation(start_delay);
+                let description = format!("specific curve of route {} variant {} from stop index {} to {} in {}, {:?}, starting with {} s delay",
+                    fields[0], fields[1], key.start_stop_index, key.end_stop_index, key.time_slot.description, event_type, start_delay);
+                Ok(CurveDump::new(description, &curve, Some(curve_set_data.precision_type), Some(curve_set_data.sample_size)))
+            },
+            "general" => {
+                let fields = split_fields(fields, 4)?;
+                let stop_index: u32 = fields[2].parse()?;
+                let event_type = parse_event_type(fields[3])?;
+                let curve_data = self.get_statistics()?.specific.get(fields[0])
+                    .and_then(|route_data| route_data.variants.get(&fields[1].parse().ok()?))
+                    .and_then(|variant_data| variant_data.general_delay[event_type].get(&stop_index).cloned())
+                    .or_error("No such curve in the statistics.")?;
+                let description = format!("general delay of route {} variant {} at stop index {}, {:?}", fields[0], fields[1], stop_index, event_type);
+                Ok(CurveDump::new(description, &curve_data.curve, Some(curve_data.precision_type), Some(curve_data.sample_size)))
+            },
+            "default" => {
+                let fields = split_fields(fields, 4)?;
+                let route_section = RouteSection::from_name(fields[1])?;
+                let time_slot = parse_time_slot(fields[2])?;
+                let event_type = parse_event_type(fields[3])?;
+                let statistics = self.get_statistics()?;
+                // route types are compared by name, e.g. "bus" or "tramway"
+                let (_, curve_data) = statistics.general.all_default_curves.iter()
+                    .find(|(key, _)| format!("{:?}", key.route_type).to_lowercase() == fields[0].to_lowercase()
+                        && key.route_section == route_section && key.time_slot == time_slot && key.event_type == event_type)
+                    .or_error("No such default curve in the statistics.")?;
+                let description = format!("default curve for {} in the {:?} of the route in {}, {:?}", fields[0], route_section, time_slot.description, event_type);
+                Ok(CurveDump::new(description, &curve_data.curve, Some(curve_data.precision_type), Some(curve_data.sample_size)))
+            },
+            _ => bail!("Unknown kind of curve '{}', use prediction, specific, general or default.", kind),
+        }
+    }
+
+    fn get_statistics(&self) -> FnResult<std::sync::Arc<DelayStatistics>> {
+        self.main.get_delay_statistics()
+    }
+}
+
+// splits the fields of a curve specification from the right, so that the first field may contain colons
+fn split_fields(fields: &str, count: usize) -> FnResult<Vec<&str>> {
+    let mut fields: Vec<&str> = fields.rsplitn(count, ':').collect();
+    if fields.len() != count {
+        bail!("Expected {} fields separated by colons, found {}.", count, fields.len());
+    }
+    fields.reverse();
+    Ok(fields)
+}
+
+fn parse_time_slot(id: &str) -> FnResult<TimeSlot> {
+    Ok(TimeSlot::from_id(id.parse()?).or_error(&format!("Unknown time slot id {}.", id))?.clone())
+}
+
+fn parse_time(time: &str) -> FnResult<NaiveTime> {
+    Ok(NaiveTime::parse_from_str(time, "%H:%M:%S").or_else(|_| NaiveTime::parse_from_str(time, "%H:%M"))?)
+}
+
+fn curve_from_dump(dump: &CurveDump) -> IrregularDynamicCurve<f32, f32> {
+    IrregularDynamicCurve::new(dump.points.iter().map(|(x, y)| Tup { x: *x, y: *y }).collect())
+}
@@ -0,0 +1,260 @@
+use clap::ArgMatches;
+use itertools::Itertools;
+use rayon::prelude::*;
+use simple_error::bail;
+
+use dystonse_curves::Curve;
+use dystonse_curves::irregular_dynamic::IrregularDynamicCurve;
+use dystonse_curves::tree::{SerdeFormat, NodeData};
+
+use super::Analyser;
+use super::curve_utils::crps;
+use super::progress::Progress;
+use super::specific_curves::SpecificCurveCreator;
+use crate::types::{CurveParameters, DbItem, DelayStatistics, EventType, OutlierPolicy};
+
+use crate::{FnResult, Main, Loadable};
+
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Chooses the parameters for the curve sets of each route (see `CurveParameters`) by k-fold
+/// cross-validation: for each pair of stops, the curve sets are computed from all but one fold
+/// of the matching pairs, and the predictions for the remaining fold are scored against the
+/// delays that actually occured.
+pub struct CurveTuner<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches,
+}
+
+/// Quality of the predictions of one set of parameters for held-out data.
+#[derive(Clone, Default)]
+struct Evaluation {
+    count: usize,
+    crps_sum: f64,
+    /// sum of the widths of the 10 % to 90 % intervals
+    width_sum: f64,
+    /// how often the observation fell into each decile of the predicted distribution
+    pit_histogram: [usize; 10],
+}
+
+impl Evaluation {
+    fn add_observation(&mut self, curve: &IrregularDynamicCurve<f32, f32>, observation: f32) {
+        self.count += 1;
+        self.crps_sum += crps(curve, observation) as f64;
+        self.width_sum += (curve.x_at_y(0.9) - curve.x_at_y(0.1)) as f64;
+        let decile = (curve.y_at_x(observation) * 10.0) as usize;
+        self.pit_histogram[usize::min(decile, 9)] += 1;
+    }
+
+    /// mean continuous ranked probability score in seconds, lower is better
+    fn score(&self) -> f64 {
+        self.crps_sum / self.count as f64
+    }
+
+    /// mean width of the 10 % to 90 % intervals in seconds
+    fn sharpness(&self) -> f64 {
+        self.width_sum / self.count as f64
+    }
+
+    /// share of observations that would have to fall into another decile of the predicted
+    /// distributions to make them perfectly calibrated, between 0 (perfect) and 0.9
+    fn calibration_error(&self) -> f64 {
+        let deviation: f64 = self.pit_histogram.iter().map(|c| (*c as f64 / self.count as f64 - 0.1).abs()).sum();
+        deviation / 2.0
+    }
+}
+
+impl<'a> CurveTuner<'a> {
+    pub fn run_tune_curves(&self) -> FnResult<()> {
+        let mut route_ids : Vec<String> = if let Some(route_ids) = self.args.values_of("route-ids") {
+            route_ids.map(String::from).collect()
+        } else if self.args.is_present("all") {
+            self.analyser.schedule.routes.keys().cloned().collect()
+        } else {
+            warn!("I've got no route!");
+            return Ok(());
+        };
+        self.analyser.retain_selected_routes(&mut route_ids);
+
+        let candidates = self.get_candidates()?;
+        let folds: usize = self.args.value_of("folds").unwrap().parse()?; // has a default value
+        if folds < 2 {
+            bail!("Number of folds must be at least 2.");
+        }
+        let max_score_loss: f64 = self.args.value_of("max-score-loss").unwrap().parse()?; // has a default value
+
+        let filename = format!("{}/all_curves.exp", self.main.dir);
+        if !Path::new(&filename).exists() {
+            bail!("{} does not exist yet, run compute-curves first.", filename);
+        }
+        // the parameters are tuned for the curves as they are computed now
+        let outlier_policy = self.analyser.get_outlier_policy();
+
+        let mut thread_pool_builder = rayon::ThreadPoolBuilder::new();
+        if let Some(jobs) = self.args.value_of("jobs") {
+            let jobs : usize = jobs.parse()?;
+            if jobs < 1 {
+                bail!("Number of jobs must be at least 1.");
+            }
+            thread_pool_builder = thread_pool_builder.num_threads(jobs);
+        }
+        let thread_pool = thread_pool_builder.build()?;
+        info!("Trying {} parameter combinations with {} folds for {} routes…", candidates.len(), folds, route_ids.len());
+        let progress = Progress::new("tune curves", route_ids.len(), None)?;
+
+        // a route without usable data must not stop the others, so errors are only logged
+        let chosen : Vec<(String, Option<CurveParameters>)> = thread_pool.install(|| {
+            route_ids.par_iter().map(|route_id| {
+                match self.tune_route(route_id, &candidates, &outlier_policy, folds, max_score_loss) {
+                    Ok((parameters, record_count)) => {
+                        progress.item_done(&format!("route {}", route_id), record_count, true);
+                        (route_id.clone(), parameters)
+                    },
+                    Err(e) => {
+                        warn!("Could not tune curves for route {}: {}", route_id, e);
+                        progress.item_done(&format!("route {}", route_id), 0, false);
+                        (route_id.clone(), None)
+                    },
+                }
+            }).collect()
+        });
+
+        let tuned: HashMap<String, CurveParameters> = chosen.into_iter()
+            .filter_map(|(route_id, parameters)| parameters.map(|p| (route_id, p)))
+            .collect();
+        progress.finish(&[format!("Chose parameters for {} of {} routes.", tuned.len(), route_ids.len())]);
+
+        if self.args.is_present("dry-run") {
+            return Ok(());
+        }
+
+        let mut statistics = <DelayStatistics as Loadable<DelayStatistics>>::load(&filename)?;
+        statistics.curve_parameters.extend(tuned);
+        statistics.save_to_file(&self.main.dir, "all_curves", &SerdeFormat::MessagePack)?;
+        info!("Saved curve parameters to {}. They will be used the next time the curves are computed.", filename);
+        Ok(())
+    }
+
+    /// Returns all combinations of the tolerances and minimum point counts given on the command line,
+    /// ordered from the coarsest to the finest.
+    fn get_candidates(&self) -> FnResult<Vec<CurveParameters>> {
+        let parse_list = |name: &str| -> FnResult<Vec<f32>> {
+            let mut values = Vec::new();
+            for value in self.args.value_of(name).unwrap().split(',') { // has a default value
+                values.push(value.trim().parse::<f32>()?);
+            }
+            values.sort_by(|a, b| b.partial_cmp(a).unwrap());
+            Ok(values)
+        };
+        let tolerances = parse_list("tolerances")?;
+        let min_points = parse_list("min-points")?;
+        if tolerances.is_empty() || min_points.is_empty() {
+            bail!("Need at least one tolerance and one minimum point count.");
+        }
+
+        Ok(tolerances.iter().cartesian_product(min_points.iter()).map(|(tolerance, points)| CurveParameters {
+            simplification_tolerance: *tolerance,
+            min_points_per_marker: *points,
+        }).collect())
+    }
+
+    // returns the chosen parameters (if there was enough data) and the number of records they are based on
+    fn tune_route(&self, route_id: &str, candidates: &[CurveParameters], outlier_policy: &OutlierPolicy, folds: usize, max_score_loss: f64) -> FnResult<(Option<CurveParameters>, usize)> {
+        let scc = SpecificCurveCreator {
+            main: self.main,
+            analyser: self.analyser,
+            args: self.args,
+        };
+        let db_items = scc.get_db_items(route_id)?;
+
+        let mut evaluations = vec![Evaluation::default(); candidates.len()];
+        for pairs in self.get_stop_pair_samples(route_id, &db_items, outlier_policy) {
+            for fold in 0..folds {
+                // the pairs are ordered by day, so every k-th pair is held out instead of a
+                // contiguous block, to spread each fold over all days
+                let (test, train): (Vec<_>, Vec<_>) = pairs.iter().enumerate().partition(|(i, _)| i % folds == fold);
+                let train: Vec<(f32, f32)> = train.into_iter().map(|(_, p)| *p).collect();
+                if train.len() <= 20 {
+                    continue;
+                }
+
+                // only use folds for which all candidates could create a curve set,
+                // so that all of them are scored on the same data
+                let curve_sets: FnResult<Vec<_>> = candidates.iter().map(|parameters| {
+                    SpecificCurveCreator::generate_curves_for_stop_pair(&train, parameters)
+                }).collect();
+                let curve_sets = match curve_sets {
+                    Ok(curve_sets) => curve_sets,
+                    Err(_) => continue,
+                };
+
+                for (evaluation, curve_set_data) in evaluations.iter_mut().zip(curve_sets.iter()) {
+                    for (_, (start_delay, end_delay)) in &test {
+                        let curve = curve_set_data.curve_set.curve_at_x_with_continuation(*start_delay);
+                        evaluation.add_observation(&curve, *end_delay);
+                    }
+                }
+            }
+        }
+
+        let chosen = Self::choose_parameters(candidates, &evaluations, max_score_loss);
+        match chosen {
+            Some(index) => {
+                let best = evaluations.iter().map(|e| e.score()).fold(f64::INFINITY, f64::min);
+                let e = &evaluations[index];
+                info!("Route {}: chose tolerance {} and {} points per marker with a score of {:.1} s (best: {:.1} s), sharpness {:.0} s and calibration error {:.3}, based on {} predictions.",
+                    route_id, candidates[index].simplification_tolerance, candidates[index].min_points_per_marker,
+                    e.score(), best, e.sharpness(), e.calibration_error(), e.count);
+                Ok((Some(candidates[index]), db_items.len()))
+            },
+            None => {
+                info!("Route {}: not enough data to choose curve parameters.", route_id);
+                Ok((None, db_items.len()))
+            }
+        }
+    }
+
+    /// Returns the index of the coarsest candidate whose score is at most `max_score_loss`
+    /// (as a fraction) worse than the best score. Candidates must be ordered from coarsest to finest.
+    fn choose_parameters(candidates: &[CurveParameters], evaluations: &[Evaluation], max_score_loss: f64) -> Option<usize> {
+        // all evaluations are based on the same observations
+        if evaluations.first()?.count == 0 {
+            return None;
+        }
+        let best = evaluations.iter().map(|e| e.score()).fold(f64::INFINITY, f64::min);
+        (0..candidates.len()).find(|i| evaluations[*i].score() <= best * (1.0 + max_score_loss))
+    }
+
+    /// Returns the matching pairs of all pairs of stops of all route variants, for arrival and
+    /// departure, as long as there are enough of them to compute curve sets.
+    fn get_stop_pair_samples(&self, route_id: &str, db_items: &[DbItem], outlier_policy: &OutlierPolicy) -> Vec<Vec<(f32, f32)>> {
+        let schedule = &self.analyser.schedule;
+        let mut samples = Vec::new();
+
+        for route_variant in db_items.iter().map(|item| item.route_variant).unique() {
+            let variant_as_string = Some(format!("{}", route_variant));
+            let trip = match schedule.trips.values().find(|trip| trip.route_id == route_id && trip.route_variant == variant_as_string) {
+                Some(trip) => trip,
+                None => continue,
+            };
+            let rows_matching_variant: Vec<&DbItem> = db_items.iter().filter(|item| item.route_variant == route_variant).collect();
+            let rows_by_stop: Vec<Vec<&DbItem>> = trip.stop_times.iter().map(|st| {
+                rows_matching_variant.iter().filter(|item| item.stop_id == st.stop.id).copied().collect()
+            }).collect();
+
+            for et in &EventType::TYPES {
+                for (i_s, rows_matching_start) in rows_by_stop.iter().enumerate() {
+                    for rows_matching_end in rows_by_stop.iter().skip(i_s + 1) {
+                        let pairs = SpecificCurveCreator::get_matching_pairs(rows_matching_start, rows_matching_end, **et, outlier_policy);
+                        if pairs.len() > 20 {
+                            samples.push(pairs);
+                        }
+                    }
+                }
+            }
+        }
+        samples
+    }
+}
@@ -7,7 +7,7 @@ use crate::FnResult;
 
 // This method determines whether there should be another marker between the ones already present at lower and upper.
 // Upper and lower are initial delay by seconds.
-pub fn recurse(initial_delay_curve: &IrregularDynamicCurve<f32, f32>, markers: &mut Vec<f32>, lower: f32, upper: f32, count: f32) {
+pub fn recurse(initial_delay_curve: &IrregularDynamicCurve<f32, f32>, markers: &mut Vec<f32>, lower: f32, upper: f32, count: f32, min_points: f32) {
     // let's recap what initial_delay_curve is: Along the x axis, we have the initial delays in seconds. Along the y axis,
     // we have the share of vehicles which had this delay or less. We need the count to make that into abolute numbers.
 
@@ -15,13 +15,13 @@ pub fn recurse(initial_delay_curve: &IrregularDynamicCurve<f32, f32>, markers: &
     let min_x_by_delay = lower + 20.0;
     let max_x_by_delay = upper - 20.0;
 
-    // between the new marker and existing ones, at least 20 data points must exist
+    // between the new marker and existing ones, at least min_points data points must exist
     // this computation is tedious because y is measured relatively but we have an
-    // absolute distance (20 datapoints) to keep. 
+    // absolute distance (min_points datapoints) to keep. 
     let lower_y = initial_delay_curve.y_at_x(lower);
     let upper_y = initial_delay_curve.y_at_x(upper);
-    let min_y_by_count = lower_y + (20.0 / count);
-    let max_y_by_count = upper_y - (20.0 / count);
+    let min_y_by_count = lower_y + (min_points / count);
+    let max_y_by_count = upper_y - (min_points / count);
     
     // Also, we need x bounds:
     let min_x_by_count = initial_delay_curve.x_at_y(min_y_by_count);
@@ -35,9 +35,9 @@ pub fn recurse(initial_delay_curve: &IrregularDynamicCurve<f32, f32>, markers: &
     // The bounds might contradict, and in that case, we won't subdivide
     if min_x <= max_x {
         let mid_x = (min_x + max_x) / 2.0;
-        recurse(initial_delay_curve, markers, lower, mid_x, count);
+        recurse(initial_delay_curve, markers, lower, mid_x, count, min_points);
         markers.push(mid_x);
-        recurse(initial_delay_curve, markers, mid_x, upper, count);
+        recurse(initial_delay_curve, markers, mid_x, upper, count, min_points);
     }
 }
 
@@ -63,12 +63,19 @@ pub fn get_weight(delay: f32, focus: Option<f32>, min_delay: f32, max_delay: f32
 }
 
 pub fn make_curve(values: &Vec<f32>, focus: Option<f32>) -> FnResult<(IrregularDynamicCurve<f32, f32>, f32)> {
-    let mut own_values = values.clone(); // TODO maybe we don't need to clone this
-    own_values.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    let min_delay = *own_values.first().unwrap();
-    let max_delay = *own_values.last().unwrap();
+    let weighted_values: Vec<(f32, f32)> = values.iter().map(|v| (*v, 1.0)).collect();
+    make_weighted_curve(&weighted_values, focus)
+}
+
+/// Like `make_curve`, but each value comes with its own weight (e.g. from its age, see `AgeWeighting`),
+/// which is multiplied with the weight from the focus.
+pub fn make_weighted_curve(values: &[(f32, f32)], focus: Option<f32>) -> FnResult<(IrregularDynamicCurve<f32, f32>, f32)> {
+    let mut own_values = values.to_vec();
+    own_values.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    let min_delay = own_values.first().unwrap().0;
+    let max_delay = own_values.last().unwrap().0;
     
-    let pairs: Vec<(f32,f32)> = own_values.iter().map(|v| (*v, get_weight(*v, focus, min_delay, max_delay))).collect();
+    let pairs: Vec<(f32,f32)> = own_values.iter().map(|(v, weight)| (*v, weight * get_weight(*v, focus, min_delay, max_delay))).collect();
 
     let sum_of_weights: f32 = pairs.iter().map(|(_v, w)| *w).sum();
 
@@ -91,4 +98,136 @@ pub fn make_curve(values: &Vec<f32>, focus: Option<f32>) -> FnResult<(IrregularD
     tups.last_mut().unwrap().y = 1.0;
 
     Ok((IrregularDynamicCurve::new(tups), sum_of_weights))
-}
\ No newline at end of file
+}
+
+/// Computes the continuous ranked probability score of the delay distribution given by the curve
+/// for an observed delay. It punishes both wide and miscalibrated distributions, is measured in
+/// seconds and lower is better.
+pub fn crps(curve: &IrregularDynamicCurve<f32, f32>, observation: f32) -> f32 {
+    // integral over (a + (b - a) * t)² for t from 0 to 1, times the width of the interval
+    fn squared_integral(x0: f32, x1: f32, a: f32, b: f32) -> f32 {
+        (x1 - x0) * (a * a + a * b + b * b) / 3.0
+    }
+
+    let (xs, ys) = curve.get_values_as_vectors();
+    let first_x = *xs.first().unwrap();
+    let last_x = *xs.last().unwrap();
+
+    // outside of the curve, the probability is 0 (below) or 1 (above) while the observation
+    // is 1 or 0, so the squared difference is 1
+    let mut sum = f32::max(first_x - observation, 0.0) + f32::max(observation - last_x, 0.0);
+    for i in 1..xs.len() {
+        let (x0, x1, y0, y1) = (xs[i - 1], xs[i], ys[i - 1], ys[i]);
+        if observation >= x1 {
+            sum += squared_integral(x0, x1, y0, y1);
+        } else if observation <= x0 {
+            sum += squared_integral(x0, x1, y0 - 1.0, y1 - 1.0);
+        } else {
+            let y_at_observation = y0 + (y1 - y0) * (observation - x0) / (x1 - x0);
+            sum += squared_integral(x0, observation, y0, y_at_observation);
+            sum += squared_integral(observation, x1, y_at_observation - 1.0, y1 - 1.0);
+        }
+    }
+    sum
+}
+
+/// Returns the distribution of the sum of two independent delays, e.g. of a departure delay and
+/// a travel time. The mass of `b` is collected in intervals of `step` seconds.
+pub fn convolve(a: &IrregularDynamicCurve<f32, f32>, b: &IrregularDynamicCurve<f32, f32>, step: f32) -> IrregularDynamicCurve<f32, f32> {
+    // a delay that is always the same only shifts the other distribution, and would have no intervals
+    if b.max_x() <= b.min_x() {
+        return shift(a, b.min_x());
+    }
+    if a.max_x() <= a.min_x() {
+        return shift(b, a.min_x());
+    }
+
+    // probability that b falls into each interval, with the center of the interval
+    let interval_count = ((b.max_x() - b.min_x()) / step).ceil() as usize;
+    let masses: Vec<(f32, f32)> = (0..interval_count).map(|i| {
+        let start = b.min_x() + i as f32 * step;
+        (start + step / 2.0, b.y_at_x(start + step) - b.y_at_x(start))
+    }).collect();
+
+    // P(a + b <= x) is the sum of P(b in interval) * P(a <= x - center of interval)
+    let min_x = a.min_x() + b.min_x();
+    let max_x = a.max_x() + b.max_x() + step;
+    let mut tups = Vec::new();
+    let mut x = min_x;
+    while x <= max_x {
+        let y: f32 = masses.iter().map(|(center, mass)| mass * a.y_at_x(x - center)).sum();
+        tups.push(Tup { x, y: f32::min(y, 1.0) });
+        x += step;
+    }
+    tups.first_mut().unwrap().y = 0.0;
+    tups.last_mut().unwrap().y = 1.0;
+
+    let mut curve = IrregularDynamicCurve::new(tups);
+    curve.simplify(0.001);
+    curve
+}
+
+// the same distribution, moved by `offset` seconds
+fn shift(curve: &IrregularDynamicCurve<f32, f32>, offset: f32) -> IrregularDynamicCurve<f32, f32> {
+    let (xs, ys) = curve.get_values_as_vectors();
+    IrregularDynamicCurve::new(xs.into_iter().zip(ys.into_iter()).map(|(x, y)| Tup { x: x + offset, y }).collect())
+}
+
+/// Returns the probability to catch a departure whose scheduled time is `slack` seconds after
+/// the scheduled arrival, given the delay distributions of the arrival and the departure.
+pub fn transfer_probability(arrival: &IrregularDynamicCurve<f32, f32>, departure: &IrregularDynamicCurve<f32, f32>, slack: f32) -> f32 {
+    // average over the percentiles of the arrival, of the probability that the departure is already gone
+    let miss_probability: f32 = (0..100)
+        .map(|percentile| departure.y_at_x(arrival.x_at_y((percentile as f32 + 0.5) / 100.0) - slack))
+        .sum::<f32>() / 100.0;
+    1.0 - miss_probability
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve(start: f32, end: f32) -> IrregularDynamicCurve<f32, f32> {
+        IrregularDynamicCurve::new(vec![Tup { x: start, y: 0.0 }, Tup { x: end, y: 1.0 }])
+    }
+
+    #[test]
+    fn test_crps() {
+        let uniform = curve(0.0, 100.0);
+        // two halves of the integral over (x / 100)² from 0 to 50
+        assert!((crps(&uniform, 50.0) - 25.0 / 3.0).abs() < 0.01);
+        // the whole integral, plus the distance to the observation
+        assert!((crps(&uniform, 200.0) - (100.0 / 3.0 + 100.0)).abs() < 0.01);
+        assert!((crps(&uniform, -100.0) - (100.0 / 3.0 + 100.0)).abs() < 0.01);
+        // narrower distributions score better
+        assert!(crps(&curve(40.0, 60.0), 50.0) < crps(&uniform, 50.0));
+    }
+
+    #[test]
+    fn test_convolve() {
+        // the sum of two uniform delays has a triangular distribution
+        let sum = convolve(&curve(0.0, 60.0), &curve(0.0, 60.0), 1.0);
+        assert_eq!((sum.min_x(), sum.y_at_x(sum.min_x())), (0.0, 0.0));
+        assert!(sum.max_x() >= 120.0);
+        assert!((sum.y_at_x(60.0) - 0.5).abs() < 0.02, "{}", sum.y_at_x(60.0));
+        assert!((sum.y_at_x(30.0) - 0.125).abs() < 0.02, "{}", sum.y_at_x(30.0));
+
+        // a delay without any spread only shifts the other one
+        let fixed = curve(30.0, 30.0);
+        for shifted in &[convolve(&curve(0.0, 60.0), &fixed, 1.0), convolve(&fixed, &curve(0.0, 60.0), 1.0)] {
+            assert_eq!(shifted.get_values_as_vectors(), (vec![30.0, 90.0], vec![0.0, 1.0]));
+        }
+    }
+
+    #[test]
+    fn test_transfer_probability() {
+        let arrival = curve(0.0, 60.0);
+        let departure = curve(0.0, 1.0);
+        // the arrival is always early enough
+        assert_eq!(transfer_probability(&arrival, &departure, 120.0), 1.0);
+        // the departure is on time, while the arrival is late almost always
+        assert!(transfer_probability(&arrival, &departure, 0.0) < 0.02);
+        // only the arrivals in the first half of the minute catch the departure
+        assert!((transfer_probability(&arrival, &departure, 30.0) - 0.5).abs() < 0.03);
+    }
+}
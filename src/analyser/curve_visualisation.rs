@@ -9,7 +9,7 @@ use gnuplot::*;
 use dystonse_curves::irregular_dynamic::*;
 use dystonse_curves::Curve;
 
-use crate::types::{RouteData, RouteVariantData, TimeSlot, CurveData, CurveSetData};
+use crate::types::{RouteData, RouteVariantData, TimeSlot, CurveData, CurveSetData, WeatherCondition};
 
 use super::Analyser;
 
@@ -26,12 +26,12 @@ impl<'a> CurveDrawer<'a> {
 
     pub fn run_curves(&self) -> FnResult<()> {
         if let Some(route_ids) = self.args.values_of("route-ids") {
-            println!("Handling {} route ids…", route_ids.len());
+            info!("Handling {} route ids…", route_ids.len());
             for route_id in route_ids {
                 self.create_curves_for_route(&String::from(route_id))?;
             }
         } else {
-            println!("I've got no route!");
+            warn!("I've got no route!");
         }
         Ok(())
     }
@@ -59,7 +59,7 @@ impl<'a> CurveDrawer<'a> {
         let route_data: RouteData = rmp_serde::from_read_ref(&buffer).unwrap();
 
 
-        println!("Working on route {} of agency {}.", route.short_name, agency_name);
+        info!("Working on route {} of agency {}.", route.short_name, agency_name);
 
         for (route_variant, route_variant_data) in route_data.variants {
             let variant_as_string = Some(format!("{}", route_variant));
@@ -67,7 +67,7 @@ impl<'a> CurveDrawer<'a> {
 
             match trip {
                 None => {
-                    println!("Could not find trip for route_variant {}.", route_variant);
+                    warn!("Could not find trip for route_variant {}.", route_variant);
                 },
                 Some(trip) => {
                     let mode = match route.route_type {
@@ -352,11 +352,16 @@ impl<'a> CurveDrawer<'a> {
             let i_s = key.start_stop_index;
             let i_e = key.end_stop_index;
             let ts = key.time_slot;
+            let weather = key.weather;
 
             let st_s = schedule.get_stop(&data.stop_ids[i_s as usize]).unwrap();
             let st_e = schedule.get_stop(&data.stop_ids[i_e as usize]).unwrap();
 
-            let sub_dir_name = format!("{}/{}", &dir_name, self.get_time_slot_description(&ts));
+            let sub_dir_name = if weather == WeatherCondition::Unknown {
+                format!("{}/{}", &dir_name, self.get_time_slot_description(&ts))
+            } else {
+                format!("{}/{}/{:?}", &dir_name, self.get_time_slot_description(&ts), weather)
+            };
             fs::create_dir_all(&sub_dir_name)?;
             let file_name = format!("{}/curve_{}_to_{}.svg", &sub_dir_name, i_s, i_e);
             let title = &format!("{} - Verspätungsentwicklung von #{} '{}' bis #{} '{}'", title_prefix, i_s, st_s.name, i_e, st_e.name);
@@ -481,7 +486,7 @@ impl<'a> CurveDrawer<'a> {
         }
 
         if curve.max_x() <  curve.min_x() + 13.0 {
-            println!("Curve too short.");
+            debug!("Curve too short.");
             return Ok(());
         }
 
@@ -3,13 +3,14 @@ use clap::ArgMatches;
 use dystonse_curves::tree::{SerdeFormat, NodeData};
 
 use super::Analyser;
-use crate::types::DelayStatistics;
+use crate::types::{DelayStatistics, OutlierPolicy};
 
 use crate::{ FnResult, Main };
 
 use std::collections::HashMap;
 
 use super::{SpecificCurveCreator, DefaultCurveCreator};
+use super::operation::OperationCounter;
 
 pub struct CurveCreator<'a> {
     pub main: &'a Main,
@@ -30,6 +31,12 @@ impl<'a> CurveCreator<'a> {
             analyser: self.analyser,
             args: self.args, 
         };
+
+        let oc = OperationCounter {
+            main: self.main,
+            analyser: self.analyser,
+            args: self.args,
+        };
         
         let delay_stats = DelayStatistics {
             specific: if !self.args.is_present("default-only") { 
@@ -37,10 +44,24 @@ impl<'a> CurveCreator<'a> {
             } else {
                 HashMap::new()
             },
-            general: dcc.get_default_curves()?
+            general: dcc.get_default_curves()?,
+            operation: oc.get_operation_counts()?,
+            // keep the parameters that have been chosen with tune-curves before
+            curve_parameters: self.analyser.get_curve_parameters(),
+            // saved so that it is known how the curve sets have been computed
+            outlier_policy: OutlierPolicy::from_args(self.args, &self.main.feed_quirks)?,
+            feed_quirks: self.main.feed_quirks,
+            // keep the calibration that has been collected with replay before
+            calibration: self.analyser.get_calibration(),
         };
        
         delay_stats.save_to_file(&self.analyser.main.dir, "all_curves", &SerdeFormat::MessagePack)?;
+        if self.args.is_present("store-in-db") {
+            if !self.args.is_present("default-only") {
+                scc.store_in_db(&delay_stats.specific)?;
+            }
+            dcc.store_in_db(&delay_stats.general)?;
+        }
         Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,307 @@
+use chrono::{Duration, Local};
+use clap::ArgMatches;
+use mysql::*;
+use mysql::prelude::*;
+use std::collections::BTreeMap;
+
+use super::Analyser;
+
+use crate::{FnResult, Main};
+
+/// An index that the queries of the monitor, the importer or the analyser need to be fast.
+struct RequiredIndex {
+    table: &'static str,
+    name: &'static str,
+    columns: &'static [&'static str],
+    /// the number of leading `columns` that the queries compare for equality, so that they may be in any
+    /// order in an existing index. The following columns must be in the given order.
+    equality_columns: usize,
+    used_by: &'static str,
+}
+
+const REQUIRED_INDEXES: [RequiredIndex; 4] = [
+    RequiredIndex {
+        table: "predictions",
+        name: "idx_predictions_stop",
+        columns: &["source", "event_type", "stop_id", "prediction_min", "prediction_max"],
+        equality_columns: 3,
+        used_by: "stop pages",
+    },
+    RequiredIndex {
+        table: "predictions",
+        name: "idx_predictions_trip",
+        columns: &["source", "event_type", "trip_id", "trip_start_date", "trip_start_time", "stop_sequence"],
+        equality_columns: 5,
+        used_by: "trip pages and the importer",
+    },
+    RequiredIndex {
+        table: "records",
+        name: "idx_records_trip",
+        columns: &["source", "route_id", "trip_id", "trip_start_date", "trip_start_time", "stop_sequence"],
+        equality_columns: 5,
+        used_by: "curve computation and the record pair statistics",
+    },
+    RequiredIndex {
+        table: "records",
+        name: "idx_records_route_variant",
+        columns: &["source", "route_id", "route_variant"],
+        equality_columns: 3,
+        used_by: "the record pair statistics",
+    },
+];
+
+/// A query whose plan is checked, with the same conditions as the original one.
+struct CheckedQuery {
+    name: &'static str,
+    sql: &'static str,
+}
+
+// the queries of the monitor and the analyser which filter the largest tables
+const CHECKED_QUERIES: [CheckedQuery; 4] = [
+    CheckedQuery {
+        name: "stop page predictions",
+        sql: r"SELECT `trip_id` FROM `predictions`
+            WHERE `source`=:source AND `event_type`=:event_type AND `stop_id`=:stop_id AND
+                `prediction_min` < :max_time AND `prediction_max` > :min_time",
+    },
+    CheckedQuery {
+        name: "trip page predictions",
+        sql: r"SELECT `stop_id` FROM `predictions`
+            WHERE `source`=:source AND `event_type`=:event_type AND `trip_id`=:trip_id AND
+                `trip_start_date`=:trip_start_date AND `trip_start_time`=:trip_start_time AND `stop_sequence`>=:start_sequence",
+    },
+    CheckedQuery {
+        name: "record pair statistics",
+        sql: r"SELECT r1.stop_sequence, r2.stop_sequence, COUNT(*)
+            FROM `records` as r1, `records` as r2
+            WHERE r1.source = r2.source AND r1.route_id = r2.route_id AND r1.trip_id = r2.trip_id AND
+                r1.trip_start_date = r2.trip_start_date AND r1.trip_start_time = r2.trip_start_time AND
+                r1.stop_sequence < r2.stop_sequence AND r1.source = :source AND r1.route_id = :route_id AND
+                r1.route_variant = :route_variant
+            GROUP BY r1.stop_sequence, r2.stop_sequence",
+    },
+    CheckedQuery {
+        name: "route records for curves",
+        sql: r"SELECT delay_arrival, delay_departure FROM records r
+            WHERE r.source=:source AND r.route_id=:route_id
+            ORDER BY trip_start_date, trip_start_time, trip_id, stop_sequence",
+    },
+];
+
+/// Checks that the tables have the indexes which the queries of the monitor and the analyser need,
+/// creates the missing ones, and reports the queries whose plans look slow.
+pub struct DbTuner<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches,
+}
+
+/// One line of the output of EXPLAIN, which describes how one table is accessed.
+struct PlanStep {
+    table: String,
+    access_type: String,
+    key: String,
+    rows: u64,
+    extra: String,
+}
+
+impl PlanStep {
+    fn from_row(row: &Row) -> Self {
+        PlanStep {
+            table: get_text(row, "table"),
+            access_type: get_text(row, "type"),
+            key: get_text(row, "key"),
+            rows: get_text(row, "rows").parse().unwrap_or(0),
+            extra: get_text(row, "Extra"),
+        }
+    }
+
+    /// Returns why this step is slow, if it is.
+    fn get_problem(&self, max_rows: u64) -> Option<String> {
+        if self.access_type == "ALL" {
+            Some(format!("reads all {} rows of {}", self.rows, self.table))
+        } else if self.key.is_empty() {
+            Some(format!("uses no index on {}", self.table))
+        } else if self.rows > max_rows {
+            Some(format!("reads about {} rows of {} with {}", self.rows, self.table, self.key))
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> DbTuner<'a> {
+    pub fn run_db_tune(&self) -> FnResult<()> {
+        let mut con = self.main.pool.get_conn()?;
+
+        let mut missing_count = 0;
+        for index in &REQUIRED_INDEXES {
+            let existing = Self::get_index_columns(&mut con, index.table)?;
+            if let Some(name) = find_covering_index(index.columns, index.equality_columns, &existing) {
+                info!("Index for {} on {} exists: {}.", index.used_by, index.table, name);
+                continue;
+            }
+            missing_count += 1;
+            let statement = format!("CREATE INDEX `{}` ON `{}` ({})", index.name, index.table,
+                index.columns.iter().map(|column| format!("`{}`", column)).collect::<Vec<_>>().join(", "));
+            if self.args.is_present("check-only") {
+                warn!("Index for {} on {} is missing, it can be created with: {};", index.used_by, index.table, statement);
+                continue;
+            }
+            info!("Creating index {} for {} on {}, this may take a while for large tables…", index.name, index.used_by, index.table);
+            con.query_drop(&statement)?;
+        }
+        if missing_count == 0 {
+            info!("All {} required indexes exist.", REQUIRED_INDEXES.len());
+        }
+
+        let max_rows: u64 = self.args.value_of("max-rows").unwrap().parse()?; // has a default value
+        let example_params = self.get_example_params(&mut con)?;
+        let mut slow_count = 0;
+        for query in &CHECKED_QUERIES {
+            let steps = match self.explain(&mut con, query, &example_params) {
+                Ok(steps) => steps,
+                Err(e) => {
+                    warn!("Could not check the plan of the query for {}: {}", query.name, e);
+                    continue;
+                }
+            };
+            let problems: Vec<String> = steps.iter().filter_map(|step| step.get_problem(max_rows)).collect();
+            for step in &steps {
+                debug!("{}: table {}, access type {}, key {}, about {} rows, {}", query.name, step.table, step.access_type, step.key, step.rows, step.extra);
+            }
+            if problems.is_empty() {
+                info!("The plan of the query for {} looks fine.", query.name);
+            } else {
+                slow_count += 1;
+                warn!("The query for {} may be slow: {}.", query.name, problems.join(", "));
+            }
+        }
+        info!("{} of {} indexes were missing, {} of {} query plans look slow.", missing_count, REQUIRED_INDEXES.len(), slow_count, CHECKED_QUERIES.len());
+        Ok(())
+    }
+
+    // the columns of all indexes of the table by index name, in the order of the index
+    fn get_index_columns(con: &mut PooledConn, table: &str) -> FnResult<BTreeMap<String, Vec<String>>> {
+        let rows: Vec<(String, String)> = con.exec(
+            r"SELECT `INDEX_NAME`, `COLUMN_NAME` FROM `information_schema`.`STATISTICS`
+            WHERE `TABLE_SCHEMA` = DATABASE() AND `TABLE_NAME` = :table
+            ORDER BY `INDEX_NAME`, `SEQ_IN_INDEX`",
+            params! { table },
+        )?;
+        let mut indexes: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for (index_name, column_name) in rows {
+            indexes.entry(index_name).or_default().push(column_name);
+        }
+        Ok(indexes)
+    }
+
+    // parameters for all checked queries, taken from the data of the source where possible,
+    // because the plans depend on the values
+    fn get_example_params(&self, con: &mut PooledConn) -> FnResult<Params> {
+        let source = &self.main.source;
+        let record: Option<(String, String, Option<u64>)> = con.exec_first(
+            r"SELECT `trip_id`, `route_id`, `route_variant` FROM `records` WHERE `source` = :source LIMIT 1",
+            params! { source },
+        )?;
+        let stop_id: Option<String> = con.exec_first(
+            r"SELECT `stop_id` FROM `predictions` WHERE `source` = :source LIMIT 1",
+            params! { source },
+        )?;
+        let (trip_id, route_id, route_variant) = record.unwrap_or_default();
+        let now = Local::now();
+        Ok(Params::from(params! {
+            source,
+            "event_type" => 1,
+            "stop_id" => stop_id.unwrap_or_default(),
+            "min_time" => now.naive_local(),
+            "max_time" => (now + Duration::hours(1)).naive_local(),
+            trip_id,
+            "trip_start_date" => now.date().naive_local(),
+            "trip_start_time" => Duration::hours(8),
+            "start_sequence" => 0,
+            route_id,
+            "route_variant" => route_variant.unwrap_or(0),
+        }))
+    }
+
+    fn explain(&self, con: &mut PooledConn, query: &CheckedQuery, example_params: &Params) -> FnResult<Vec<PlanStep>> {
+        let statement = con.prep(format!("EXPLAIN {}", query.sql))?;
+        // each statement only accepts the parameters that it uses
+        let params = match example_params {
+            Params::Named(named) => Params::Named(named.iter()
+                .filter(|(name, _)| query.sql.contains(&format!(":{}", name)))
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect()),
+            other => other.clone(),
+        };
+        let rows: Vec<Row> = con.exec(&statement, params)?;
+        Ok(rows.iter().map(PlanStep::from_row).collect())
+    }
+}
+
+/// Returns the name of an index whose first columns are the required ones. The first `equality_columns`
+/// of them may be in any order, the others must follow in the required order. MySQL can use such an index
+/// for the equality conditions and then for the range conditions on these columns, but not for a range
+/// condition on a column after another range column.
+fn find_covering_index<'i>(required: &[&str], equality_columns: usize, existing: &'i BTreeMap<String, Vec<String>>) -> Option<&'i str> {
+    let (equal, ordered) = required.split_at(usize::min(equality_columns, required.len()));
+    existing.iter().find(|(_, columns)| {
+        columns.len() >= required.len() &&
+            equal.iter().all(|column| columns[..equal.len()].iter().any(|c| c.eq_ignore_ascii_case(column))) &&
+            ordered.iter().zip(&columns[equal.len()..]).all(|(column, c)| c.eq_ignore_ascii_case(column))
+    }).map(|(name, _)| name.as_str())
+}
+
+// the value of a column of the EXPLAIN output as text, which differs in type between MySQL and MariaDB
+fn get_text(row: &Row, column: &str) -> String {
+    match row.get_opt::<Value, &str>(column) {
+        Some(Ok(Value::NULL)) | Some(Err(_)) | None => String::new(),
+        Some(Ok(Value::Bytes(bytes))) => String::from_utf8_lossy(&bytes).into_owned(),
+        Some(Ok(value)) => value.as_sql(true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn indexes(indexes: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        indexes.iter().map(|(name, columns)| (name.to_string(), columns.iter().map(|c| c.to_string()).collect())).collect()
+    }
+
+    #[test]
+    fn test_find_covering_index() {
+        let required = ["source", "event_type", "stop_id", "prediction_min", "prediction_max"];
+        let existing = indexes(&[
+            ("PRIMARY", &["id"]),
+            ("too_short", &["source", "event_type", "stop_id", "prediction_min"]),
+            ("wrong_range_order", &["source", "event_type", "stop_id", "prediction_max", "prediction_min"]),
+        ]);
+        assert_eq!(find_covering_index(&required, 3, &existing), None);
+
+        let existing = indexes(&[
+            ("equality_in_other_order", &["STOP_ID", "source", "event_type", "prediction_min", "prediction_max", "trip_id"]),
+        ]);
+        assert_eq!(find_covering_index(&required, 3, &existing), Some("equality_in_other_order"));
+        // a range column may not be among the first columns
+        let existing = indexes(&[("range_first", &["prediction_min", "source", "event_type", "stop_id", "prediction_max"])]);
+        assert_eq!(find_covering_index(&required, 3, &existing), None);
+        assert_eq!(find_covering_index(&required, 5, &existing), Some("range_first"));
+    }
+
+    #[test]
+    fn test_get_problem() {
+        let step = |access_type: &str, key: &str, rows: u64| PlanStep {
+            table: String::from("predictions"),
+            access_type: String::from(access_type),
+            key: String::from(key),
+            rows,
+            extra: String::new(),
+        };
+        assert_eq!(step("ALL", "", 5000).get_problem(1000), Some(String::from("reads all 5000 rows of predictions")));
+        assert_eq!(step("index_merge", "", 50).get_problem(1000), Some(String::from("uses no index on predictions")));
+        assert_eq!(step("range", "idx_predictions_stop", 5000).get_problem(1000), Some(String::from("reads about 5000 rows of predictions with idx_predictions_stop")));
+        assert_eq!(step("ref", "idx_predictions_stop", 50).get_problem(1000), None);
+    }
+}
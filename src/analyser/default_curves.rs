@@ -1,7 +1,6 @@
 use std::collections::{HashSet, HashMap};
-use std::u16;
 
-use crate::types::{TimeSlot, DbItem, RouteSection, DefaultCurves, EventType, EventPair, DefaultCurveKey, CurveData, PrecisionType};
+use crate::types::{TimeSlot, DbItem, RouteSection, RouteSectioning, DefaultCurves, EventType, EventPair, DefaultCurveKey, CurveData, PrecisionType, CurveStore, AgeWeighting, effective_sample_size};
 
 use super::curve_utils::*;
 
@@ -14,6 +13,7 @@ use rayon::prelude::*;
 use dystonse_curves::tree::{SerdeFormat, NodeData};
 
 use super::Analyser;
+use super::progress::Progress;
 
 use crate::{FnResult, Main};
 
@@ -41,6 +41,8 @@ impl<'a> DefaultCurveCreator<'a> {
 
     pub fn get_default_curves(&self) -> FnResult<DefaultCurves> {
         let schedule = &self.analyser.schedule;
+        let route_sectioning = RouteSectioning::from_args(self.args)?;
+        let age_weighting = AgeWeighting::from_args(self.args)?;
 
         let route_types = [
             RouteType::Tramway,
@@ -62,9 +64,14 @@ impl<'a> DefaultCurveCreator<'a> {
             RouteSection::End
             ];
 
+        let route_variant_count : usize = route_types.iter()
+            .map(|rt| self.get_routes_for_type(*rt).iter().map(|r| self.get_variants_for_route(r).len()).sum::<usize>())
+            .sum();
+        let progress = Progress::new("default curves", route_variant_count, self.args.value_of("progress-json"))?;
+
         //iterate over route types
         let mut general_curves = route_types.par_iter().map(|rt| {
-            println!("Starting with route type {:?}", rt);
+            info!("Starting with route type {:?}", rt);
 
             //find all routes for this type
             let routes = self.get_routes_for_type(*rt);
@@ -75,7 +82,7 @@ impl<'a> DefaultCurveCreator<'a> {
                 route_variants.extend(self.get_variants_for_route(r));
             }
 
-            println!("Found {} route variants in {} {:?} routes", route_variants.len(), routes.len(), rt);
+            info!("Found {} route variants in {} {:?} routes", route_variants.len(), routes.len(), rt);
 
             //iterate over route variants
             //for (ri, rv) in route_variants {
@@ -91,30 +98,27 @@ impl<'a> DefaultCurveCreator<'a> {
                 // take the list of stops from this trip
                 let rv_stops = &trip.stop_times;
 
-                //find the borders between the route sections
-                let mut max_beginning_stop : u16 = 0;
-                let mut max_middle_stop : u16 = 0;
+                // find the route section of each stop. Depending on the sectioning, the sections
+                // are not necessarily contiguous.
+                let sections_by_stop_sequence : HashMap<u16, RouteSection> = rv_stops.iter().enumerate()
+                    .filter_map(|(i, s)| route_sectioning.get_route_section(trip, i).ok().map(|sec| (s.stop_sequence, sec)))
+                    .collect();
 
-                for s in rv_stops {
-                    if let Ok(sec) = RouteSection::get_route_section_by_stop_sequence(&schedule, &trip.id, s.stop_sequence) {
-                        if sec == RouteSection::Beginning {
-                            max_beginning_stop = s.stop_sequence;
-                        }
-                        else if sec == RouteSection::Middle {
-                            max_middle_stop = s.stop_sequence;
-                        }
+                // Get rt data from the database for this route variant and sort it into the route sections
+                // TODO: fix this, because it panics if anything went wrong in the database connection etc.!
+                let data = self.get_data_from_db(&ri, &rv).unwrap();
+                let record_count = data.len();
+                let mut beginning_data = Vec::new();
+                let mut middle_data = Vec::new();
+                let mut end_data = Vec::new();
+                for item in data {
+                    match sections_by_stop_sequence.get(&item.stop_sequence) {
+                        Some(RouteSection::Beginning) => beginning_data.push(item),
+                        Some(RouteSection::Middle) => middle_data.push(item),
+                        Some(RouteSection::End) => end_data.push(item),
+                        None => (),
                     }
                 }
-                //...now the borders should be known.
-
-                // println!("For route variant {} with {} stops, the route sections are at {} and {}.",
-                //     rv, rv_stops.len(), max_beginning_stop, max_middle_stop);
-
-                // Get rt data from the database for all route sections in this route variant
-                // TODO: fix this, because it panics if anything went wrong in the database connection etc.!
-                let beginning_data = self.get_data_from_db(&ri, &rv, 0, max_beginning_stop).unwrap();
-                let middle_data = self.get_data_from_db(&ri, &rv, max_beginning_stop + 1, max_middle_stop).unwrap();
-                let end_data = self.get_data_from_db(&ri, &rv, max_middle_stop + 1, u16::MAX).unwrap();
 
                 // for each of these sections, separate the data into time slots
                 let beginning_data_by_timeslot = self.sort_dbitems_by_timeslot(beginning_data).unwrap();
@@ -136,21 +140,25 @@ impl<'a> DefaultCurveCreator<'a> {
                     for ts in &TimeSlot::TIME_SLOTS {
                         // println!("Create curves for section {:?} and time slot {}.", rs, ts.description);
 
-                        // collect delays in vectors:
-                        let mut delays : EventPair<Vec<f32>> = EventPair { arrival: Vec::new(), departure: Vec::new() };
+                        // collect delays in vectors, together with the weight of each record:
+                        let mut delays : EventPair<Vec<(f32, f32)>> = EventPair { arrival: Vec::new(), departure: Vec::new() };
                         for e_t in &EventType::TYPES {
                             delays[**e_t] = data_by_route_section_and_timeslot[rs][ts].iter()
-                                .filter_map(|item| item.delay[**e_t]).map(|i| i as f32).collect();
+                                .filter_map(|item| {
+                                    let weight = age_weighting.map_or(1.0, |age_weighting| age_weighting.get_weight(item.trip_start_date));
+                                    Some((item.delay[**e_t]? as f32, weight))
+                                }).collect();
                         }
                         for e_t in &EventType::TYPES {
                             if delays[**e_t].len() >= MIN_DATA_FOR_CURVE {
-                                if let Ok((mut curve, _)) = make_curve(&delays[**e_t], None) {
+                                if let Ok((mut curve, _)) = make_weighted_curve(&delays[**e_t], None) {
                                     curve.simplify(0.001);
                                     // only create vectors that will have entries
                                     let curve_data = CurveData {
                                         curve,
                                         precision_type: PrecisionType::Unknown,
                                         sample_size: delays[**e_t].len() as u32,
+                                        effective_sample_size: effective_sample_size(delays[**e_t].iter().map(|(_, weight)| *weight)),
                                     };
                                     collection_for_route_variant[**e_t].entry((rt, rs, *ts)).or_insert(Vec::new()).push(curve_data);
                                 }
@@ -158,6 +166,7 @@ impl<'a> DefaultCurveCreator<'a> {
                         }
                     }
                 }
+                progress.item_done(&format!("route {} variant {}", ri, rv), record_count, true);
                 collection_for_route_variant
             }).reduce(
                 || Self::empty_collection(),
@@ -170,7 +179,7 @@ impl<'a> DefaultCurveCreator<'a> {
         );
 
 
-        println!("Done with curves for each route variant, now computing average curves…");
+        info!("Done with curves for each route variant, now computing average curves…");
 
         // on each leaf of the trees, there is now a vector of curves 
         // with one curve for each route_variant.
@@ -179,6 +188,7 @@ impl<'a> DefaultCurveCreator<'a> {
 
         // new datastructure for all the default curves:
         let mut dc : DefaultCurves = DefaultCurves::new();
+        dc.route_sectioning = route_sectioning;
 
         // temporary collections for building broader defaults 
         // (one only sorted by route_type and EventType, and one completely unsorted) as a fallback
@@ -211,7 +221,7 @@ impl<'a> DefaultCurveCreator<'a> {
         for rt in &route_types {
             for rs in &route_sections {
                 for ts in &TimeSlot::TIME_SLOTS {
-                    println!("Create average curves for route type {:?}, route section {:?} and time slot {}", rt, rs, ts.description);
+                    info!("Create average curves for route type {:?}, route section {:?} and time slot {}", rt, rs, ts.description);
 
                     for e_t in &EventType::TYPES {
                         let key = DefaultCurveKey{route_type: *rt, route_section: rs.clone(), time_slot: (**ts).clone(), event_type: **e_t};
@@ -228,13 +238,13 @@ impl<'a> DefaultCurveCreator<'a> {
                             // if there is no entry for this (rt, rs, ts) combination in this e_t,
                             // we need something to fill that gap
                             // so we use the fallback that is only split up by route type and event type:
-                            println!("No data for {:?} at {:?}, {:?}, {}. Looking up fallback instead: {:?} for {:?}.", e_t, rt, rs, ts.description, e_t, rt);
+                            debug!("No data for {:?} at {:?}, {:?}, {}. Looking up fallback instead: {:?} for {:?}.", e_t, rt, rs, ts.description, e_t, rt);
                             if let Some(fc) = fallback_general_curves.get_mut(&(*rt, **e_t)) {
                                 let mut fallback_curve_data = CurveData::average(fc, PrecisionType::FallbackGeneral)?;
                                 fallback_curve_data.curve.simplify(0.001);
                                 dc.all_default_curves.insert(key, fallback_curve_data);
                             } else {
-                                println!("No data for fallback {:?} for {:?}. Using super default curve instead.", e_t, rt);
+                                debug!("No data for fallback {:?} for {:?}. Using super default curve instead.", e_t, rt);
                                 dc.all_default_curves.insert(key, super_general_curve_data.clone());
                             }
                         }
@@ -242,7 +252,8 @@ impl<'a> DefaultCurveCreator<'a> {
                 }
             }
         }
-        println!("Done with everything but saving."); // Result: {:?}", dc.all_default_curves);
+        info!("Done with everything but saving."); // Result: {:?}", dc.all_default_curves);
+        progress.finish(&[format!("Created {} default curves.", dc.all_default_curves.len())]);
 
         Ok(dc)
     }
@@ -264,7 +275,7 @@ impl<'a> DefaultCurveCreator<'a> {
     pub fn run_default_curves(&self) -> FnResult<()> {
         let dc = self.get_default_curves()?;
 
-        println!("Saving to binary file.");
+        info!("Saving to binary file.");
 
         // save curve types to a binary file
         dc.save_to_file(&self.analyser.main.dir, "default_curves", &SerdeFormat::MessagePack)?;
@@ -274,8 +285,21 @@ impl<'a> DefaultCurveCreator<'a> {
         // // save curve types to a json file
         // save_to_file(&all_default_curves, "data/curve_data/default_curves", "Default_Curves.json", SerdeFormat::Json)?;
 
-        println!("Done!");
+        if self.args.is_present("store-in-db") {
+            self.store_in_db(&dc)?;
+        }
+
+        info!("Done!");
+
+        Ok(())
+    }
 
+    /// Writes the default curves into the database, replacing the previous ones.
+    pub fn store_in_db(&self, dc: &DefaultCurves) -> FnResult<()> {
+        let store = CurveStore::new(self.main.pool.clone(), &self.main.source);
+        store.create_tables()?;
+        store.save_default_curves(dc)?;
+        info!("Stored {} default curves in the database.", dc.all_default_curves.len());
         Ok(())
     }
 
@@ -284,7 +308,7 @@ impl<'a> DefaultCurveCreator<'a> {
         let mut routes : Vec<&Route> = Vec::new();
 
         for r in self.analyser.schedule.routes.values() {
-            if r.route_type == rt {
+            if r.route_type == rt && self.analyser.is_route_selected(&r.id) {
                 routes.push(r);
             }
         }
@@ -303,8 +327,8 @@ impl<'a> DefaultCurveCreator<'a> {
         return variants;
     }
 
-    // picks all rows from the database for a given route section and variant
-    fn get_data_from_db(&self, ri: &str, rv: &str, min: u16, max: u16) -> FnResult<Vec<DbItem>> {
+    // picks all rows from the database for a given route variant
+    fn get_data_from_db(&self, ri: &str, rv: &str) -> FnResult<Vec<DbItem>> {
         let mut con = self.main.pool.get_conn()?;
         let stmt = con.prep(
             r"SELECT 
@@ -321,9 +345,7 @@ impl<'a> DefaultCurveCreator<'a> {
             WHERE 
                 source=:source AND 
                 route_id = :route_id AND
-                route_variant=:route_variant AND
-                stop_sequence >= :lower_bound AND
-                stop_sequence <= :upper_bound",
+                route_variant=:route_variant",
         )?;
 
         let mut result = con.exec_iter(
@@ -332,8 +354,6 @@ impl<'a> DefaultCurveCreator<'a> {
                 "source" => &self.main.source,
                 "route_id" => ri,
                 "route_variant" => rv,
-                "lower_bound" => min,
-                "upper_bound" => max,
             },
         )?;
 
@@ -341,7 +361,8 @@ impl<'a> DefaultCurveCreator<'a> {
 
         let db_items: Vec<_> = result_set
             .map(|row| {
-                let item: DbItem = from_row(row.unwrap());
+                let mut item: DbItem = from_row(row.unwrap());
+                self.main.feed_quirks.clean_item(&mut item);
                 item
             })
             .collect();
@@ -367,7 +388,7 @@ impl<'a> DefaultCurveCreator<'a> {
             }
             // should always be some now, but to be sure...
             if dt.is_some() {
-                let ts : &TimeSlot = TimeSlot::from_datetime(dt.unwrap());
+                let ts : &TimeSlot = TimeSlot::from_datetime(dt.unwrap(), &self.main.holidays);
                 sorted_items.get_mut(ts).unwrap().push(i);
             }
         }
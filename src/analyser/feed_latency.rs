@@ -0,0 +1,119 @@
+use chrono::{Duration, Local};
+use clap::ArgMatches;
+use mysql::*;
+use mysql::prelude::*;
+use parse_duration::parse;
+use std::collections::BTreeMap;
+
+use super::Analyser;
+
+use crate::{FnResult, Main};
+
+// quantiles of the skew distribution which are shown in the report
+const SKEW_QUANTILES: [(&str, f32); 6] = [("min", 0.0), ("10%", 0.1), ("median", 0.5), ("90%", 0.9), ("99%", 0.99), ("max", 1.0)];
+
+// for each of these ages (in seconds), the report shows the share of records with older trip updates
+const AGE_THRESHOLDS: [i64; 4] = [60, 300, 900, 1800];
+
+/// How much older than their feed header the trip updates of a source were, for the records
+/// within the lookback time.
+#[derive(Default)]
+pub struct LatencyDistribution {
+    /// number of records per skew in seconds
+    pub counts: BTreeMap<i64, usize>,
+    /// records from messages whose trip updates have no (plausible) timestamp
+    pub records_without_skew: usize,
+}
+
+impl LatencyDistribution {
+    pub fn records_with_skew(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    /// Returns the skew at quantile `q` (between 0 and 1), or `None` if no record has a skew.
+    pub fn quantile(&self, q: f32) -> Option<i64> {
+        let total = self.records_with_skew();
+        if total == 0 {
+            return None;
+        }
+        let index = ((total - 1) as f32 * q).round() as usize;
+        let mut seen = 0;
+        for (skew, count) in &self.counts {
+            seen += count;
+            if seen > index {
+                return Some(*skew);
+            }
+        }
+        None
+    }
+
+    /// Share of the records with a skew, whose trip updates were older than `seconds`, in percent.
+    pub fn percentage_older_than(&self, seconds: i64) -> f32 {
+        let total = self.records_with_skew();
+        if total == 0 {
+            return 0.0;
+        }
+        let older: usize = self.counts.range(seconds + 1..).map(|(_, count)| count).sum();
+        100.0 * older as f32 / total as f32
+    }
+}
+
+pub struct FeedLatencyAnalyser<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches,
+}
+
+impl<'a> FeedLatencyAnalyser<'a> {
+    /// Prints the distribution of the feed skew for each source in the records table, so that
+    /// a sensible `max-delay-age` can be chosen for the importer.
+    pub fn run_feed_latency(&self) -> FnResult<()> {
+        let lookback = Duration::from_std(parse(self.args.value_of("lookback").unwrap())?)?; // already validated by clap
+        let distributions = self.compute_distributions(lookback)?;
+        if distributions.is_empty() {
+            println!("No records within the last {} hours.", lookback.num_hours());
+            return Ok(());
+        }
+
+        let quantile_names: Vec<&str> = SKEW_QUANTILES.iter().map(|(name, _)| *name).collect();
+        let threshold_names: Vec<String> = AGE_THRESHOLDS.iter().map(|seconds| format!("older than {} min", seconds / 60)).collect();
+        println!("Age of the trip updates compared to their feed header, in seconds, for records within the last {} hours:", lookback.num_hours());
+        println!("source; records; records with timestamp; {}; {}", quantile_names.join("; "), threshold_names.join("; "));
+        for (source, distribution) in &distributions {
+            let quantiles: Vec<String> = SKEW_QUANTILES.iter()
+                .map(|(_, q)| distribution.quantile(*q).map(|skew| skew.to_string()).unwrap_or_else(|| String::from("-")))
+                .collect();
+            let shares: Vec<String> = AGE_THRESHOLDS.iter()
+                .map(|seconds| format!("{:.1}%", distribution.percentage_older_than(*seconds)))
+                .collect();
+            println!("{}; {}; {}; {}; {}",
+                source,
+                distribution.records_with_skew() + distribution.records_without_skew,
+                distribution.records_with_skew(),
+                quantiles.join("; "),
+                shares.join("; "));
+        }
+        Ok(())
+    }
+
+    fn compute_distributions(&self, lookback: Duration) -> FnResult<BTreeMap<String, LatencyDistribution>> {
+        let mut con = self.main.pool.get_conn()?;
+        let rows: Vec<(String, Option<i64>, usize)> = con.exec(
+            r"SELECT `source`, `feed_skew`, COUNT(*) FROM `records`
+            WHERE `time_of_recording` > :min_time
+            GROUP BY `source`, `feed_skew`",
+            params! { "min_time" => (Local::now() - lookback).naive_local() },
+        )?;
+
+        let mut distributions: BTreeMap<String, LatencyDistribution> = BTreeMap::new();
+        for (source, skew, count) in rows {
+            let distribution = distributions.entry(source).or_default();
+            match skew {
+                Some(skew) => *distribution.counts.entry(skew).or_insert(0) += count,
+                None => distribution.records_without_skew += count,
+            }
+        }
+        Ok(distributions)
+    }
+}
+
@@ -0,0 +1,137 @@
+use chrono::NaiveDate;
+use gtfs_structures::{Gtfs, Trip};
+use plotters::prelude::*;
+use simple_error::bail;
+
+use super::visual_schedule::VsDbItem;
+
+use crate::{FnResult, OrError};
+
+use std::collections::{HashMap, HashSet};
+
+// average delay changes (in seconds) at which the color scale reaches green and red
+const MIN_DELAY_CHANGE: f32 = -30.0;
+const MAX_DELAY_CHANGE: f32 = 120.0;
+// segments with fewer observed trips are drawn in gray
+const MIN_SAMPLES_PER_SEGMENT: usize = 5;
+// the height of the image follows from the extent of the shape
+const IMAGE_WIDTH: u32 = 1600;
+const MAX_IMAGE_HEIGHT: u32 = 4096;
+
+/// Draws the shape of a route variant (from shapes.txt) onto a map. Each segment between two
+/// consecutive stops is colored by the average change of the delay on it, so that the parts
+/// of the route where vehicles lose time stand out.
+pub struct GeographicPlotter<'a> {
+    pub schedule: &'a Gtfs,
+    pub db_items: &'a [VsDbItem],
+}
+
+impl<'a> GeographicPlotter<'a> {
+    pub fn draw_route_variant(&self, route_variant_id: &str, file_name: &str) -> FnResult<()> {
+        let trips: Vec<&Trip> = self.schedule.trips.values()
+            .filter(|trip| trip.route_variant.as_deref() == Some(route_variant_id))
+            .collect();
+        // all trips of a variant have the same stops, so any of them can stand for the variant
+        let primary_trip = trips.first().or_error("No trips for route variant")?;
+        let shape_id = primary_trip.shape_id.as_ref().or_error("Trip has no shape_id")?;
+        let mut shape = self.schedule.shapes.get(shape_id).or_error("Shape is not in the schedule")?.clone();
+        shape.sort_by_key(|point| point.sequence);
+        if shape.len() < 2 {
+            bail!("Shape {} has less than two points.", shape_id);
+        }
+
+        // plain equirectangular projection, which is good enough at the scale of a single route
+        let mean_latitude = shape.iter().map(|point| point.latitude).sum::<f64>() / shape.len() as f64;
+        let x_scale = mean_latitude.to_radians().cos();
+        let project = |latitude: f64, longitude: f64| (longitude * x_scale, latitude);
+        let points: Vec<(f64, f64)> = shape.iter().map(|point| project(point.latitude, point.longitude)).collect();
+        let stop_points: Vec<Option<(f64, f64)>> = primary_trip.stop_times.iter()
+            .map(|stop_time| Some(project(stop_time.stop.latitude?, stop_time.stop.longitude?)))
+            .collect();
+
+        let stop_ids: Vec<&str> = primary_trip.stop_times.iter().map(|stop_time| stop_time.stop.id.as_str()).collect();
+        let trip_ids: HashSet<&str> = trips.iter().map(|trip| trip.id.as_str()).collect();
+        let delay_changes = self.average_delay_changes(&stop_ids, &trip_ids);
+        let shape_indices = match_stops_to_shape(&points, &stop_points);
+
+        let min_x = points.iter().map(|p| p.0).fold(f64::INFINITY, f64::min);
+        let max_x = points.iter().map(|p| p.0).fold(f64::NEG_INFINITY, f64::max);
+        let min_y = points.iter().map(|p| p.1).fold(f64::INFINITY, f64::min);
+        let max_y = points.iter().map(|p| p.1).fold(f64::NEG_INFINITY, f64::max);
+        let height = ((max_y - min_y) / (max_x - min_x).max(1e-6) * IMAGE_WIDTH as f64) as u32;
+
+        let root = SVGBackend::new(file_name, (IMAGE_WIDTH, height.max(200).min(MAX_IMAGE_HEIGHT))).into_drawing_area();
+        root.fill(&WHITE)?;
+        let root = root.margin(40, 40, 40, 40);
+        let mut chart = ChartBuilder::on(&root).build_ranged(min_x..max_x, min_y..max_y)?;
+
+        // DRAW SEGMENTS BETWEEN STOPS
+        chart.draw_series(shape_indices.windows(2).zip(delay_changes.iter()).map(|(indices, delay_change)| {
+            PathElement::new(points[indices[0]..=indices[1]].to_vec(), ShapeStyle::from(&delay_change_color(*delay_change)).stroke_width(4))
+        }))?;
+        // DRAW STOPS
+        chart.draw_series(stop_points.iter().filter_map(|point| *point).map(|point| {
+            Circle::new(point, 3, ShapeStyle::from(&BLACK).filled())
+        }))?;
+        chart.draw_series(stop_points.iter().zip(primary_trip.stop_times.iter()).filter_map(|(point, stop_time)| {
+            point.map(|point| Text::new(stop_time.stop.name.clone(), point, ("sans-serif", 12).into_font()))
+        }))?;
+
+        info!("Drew route variant {} with {} trips to {}.", route_variant_id, trips.len(), file_name);
+        Ok(())
+    }
+
+    /// For each pair of consecutive stops, the average difference between the arrival delay at the
+    /// second stop and the departure delay at the first stop, if enough trips have been recorded.
+    fn average_delay_changes(&self, stop_ids: &[&str], trip_ids: &HashSet<&str>) -> Vec<Option<f32>> {
+        // the records of each trip by stop index, grouped by date and trip_id
+        let mut items_by_trip: HashMap<(Option<NaiveDate>, &str), HashMap<usize, &VsDbItem>> = HashMap::new();
+        for item in self.db_items.iter().filter(|item| trip_ids.contains(item.trip_id.as_str())) {
+            if let Some(index) = stop_ids.iter().position(|id| *id == item.stop_id) {
+                items_by_trip.entry((item.date, item.trip_id.as_str())).or_insert_with(HashMap::new).insert(index, item);
+            }
+        }
+
+        let mut sums = vec![(0.0, 0); stop_ids.len().saturating_sub(1)];
+        for items in items_by_trip.values() {
+            for (index, (sum, count)) in sums.iter_mut().enumerate() {
+                let start = items.get(&index).and_then(|item| item.delay_departure);
+                let end = items.get(&(index + 1)).and_then(|item| item.delay_arrival);
+                if let (Some(start), Some(end)) = (start, end) {
+                    *sum += (end - start) as f32;
+                    *count += 1;
+                }
+            }
+        }
+        sums.into_iter().map(|(sum, count)| if count >= MIN_SAMPLES_PER_SEGMENT { Some(sum / count as f32) } else { None }).collect()
+    }
+}
+
+/// Finds the index of the shape point that is closest to each stop. The indices never decrease,
+/// so that routes which pass the same place twice are split correctly. Stops without coordinates
+/// get the index of the previous stop.
+fn match_stops_to_shape(points: &[(f64, f64)], stop_points: &[Option<(f64, f64)>]) -> Vec<usize> {
+    let mut indices = Vec::with_capacity(stop_points.len());
+    let mut min_index = 0;
+    for stop_point in stop_points {
+        if let Some((x, y)) = stop_point {
+            let distance = |index: &usize| (points[*index].0 - x).powi(2) + (points[*index].1 - y).powi(2);
+            min_index = (min_index..points.len())
+                .min_by(|a, b| distance(a).partial_cmp(&distance(b)).unwrap_or(std::cmp::Ordering::Equal))
+                .unwrap_or(min_index);
+        }
+        indices.push(min_index);
+    }
+    indices
+}
+
+// gray if unknown, otherwise from green (delay is reduced) via yellow to red (delay grows)
+fn delay_change_color(delay_change: Option<f32>) -> RGBColor {
+    match delay_change {
+        None => RGBColor(170, 170, 170),
+        Some(delay_change) => {
+            let t = ((delay_change - MIN_DELAY_CHANGE) / (MAX_DELAY_CHANGE - MIN_DELAY_CHANGE)).max(0.0).min(1.0);
+            RGBColor((255.0 * (2.0 * t).min(1.0)) as u8, (200.0 * (2.0 * (1.0 - t)).min(1.0)) as u8, 0)
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use chrono::{Date, Local, TimeZone};
+use clap::ArgMatches;
+use itertools::Itertools;
+use mysql::*;
+use mysql::prelude::*;
+use std::collections::HashMap;
+
+use super::Analyser;
+use crate::types::{DbItem, EventType, HeadwayEntry, HeadwayStatistics, TimeSlot};
+use crate::{FnResult, Main};
+use crate::stats_util::{median, quantile};
+
+/// Finds the route variants that run so frequently that passengers don't look at the timetable,
+/// and computes how long they actually have to wait between two vehicles at the same stop.
+/// The result is written to headways.json in dir, where the monitor can find it.
+pub struct HeadwayAnalyser<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches,
+}
+
+// route_variant, time slot id
+type HeadwayKey = (u64, u8);
+
+/// scheduled and observed headways (in seconds) between consecutive departures of a route variant
+#[derive(Default)]
+struct Headways {
+    scheduled: Vec<i32>,
+    observed: Vec<i32>,
+}
+
+impl<'a> HeadwayAnalyser<'a> {
+    pub fn run_compute_headways(&self) -> FnResult<()> {
+        let max_headway: u32 = self.args.value_of("max-headway").unwrap().parse()?; // has a default value
+        let min_samples: usize = self.args.value_of("min-samples").unwrap().parse()?; // has a default value
+
+        let mut route_ids: Vec<String> = if let Some(route_ids) = self.args.values_of("route-ids") {
+            route_ids.map(String::from).collect()
+        } else {
+            self.analyser.schedule.routes.keys().cloned().collect()
+        };
+        self.analyser.retain_selected_routes(&mut route_ids);
+        route_ids.sort();
+
+        // when only some routes are analysed, keep the results of all others
+        let mut statistics = match (self.args.is_present("route-ids"), HeadwayStatistics::load_from_dir(&self.main.dir)) {
+            (true, Ok(mut statistics)) => {
+                statistics.variants.retain(|entry| !route_ids.contains(&entry.route_id));
+                statistics
+            },
+            _ => HeadwayStatistics::default(),
+        };
+        statistics.max_headway = max_headway;
+
+        info!("Computing the headways of {} routes…", route_ids.len());
+        for route_id in &route_ids {
+            let entries = self.compute_route(route_id, max_headway, min_samples)?;
+            statistics.variants.extend(entries);
+        }
+
+        statistics.save_to_dir(&self.main.dir)?;
+        info!("Found {} high-frequency route variants and time slots, wrote them to {}/{}.", statistics.variants.len(), self.main.dir, HeadwayStatistics::FILE_NAME);
+        Ok(())
+    }
+
+    fn compute_route(&self, route_id: &str, max_headway: u32, min_samples: usize) -> FnResult<Vec<HeadwayEntry>> {
+        let schedule = &self.analyser.schedule;
+        let mut con = self.main.pool.get_conn()?;
+        let db_items: Vec<DbItem> = con.exec(
+            r"SELECT
+                delay_arrival,
+                delay_departure,
+                trip_start_date,
+                trip_start_time,
+                trip_id,
+                stop_id,
+                stop_sequence,
+                route_variant
+            FROM
+                records
+            WHERE
+                source = :source AND
+                route_id = :route_id",
+            params! {
+                "source" => &self.main.source,
+                route_id,
+            },
+        )?;
+
+        // scheduled and actual departure times of all vehicles at each stop of each route variant and day
+        let mut departures: HashMap<(u64, u16, Date<Local>), Vec<(i64, i64)>> = HashMap::new();
+        for item in &db_items {
+            let (delay, date) = match (item.delay.departure, item.trip_start_date) {
+                (Some(delay), Some(date)) => (delay, date),
+                _ => continue,
+            };
+            let trip = match schedule.get_trip(&item.trip_id) {
+                Ok(trip) => trip,
+                Err(_) => continue,
+            };
+            let departure_time = match item.get_datetime_from_trip(trip, EventType::Departure) {
+                Some(departure_time) => departure_time.timestamp(),
+                None => continue,
+            };
+            departures.entry((item.route_variant, item.stop_sequence, date)).or_insert_with(Vec::new)
+                .push((departure_time, departure_time + delay as i64));
+        }
+
+        let mut headways: HashMap<HeadwayKey, Headways> = HashMap::new();
+        for ((route_variant, _, _), times) in departures {
+            let (scheduled, observed) = get_headways(times, max_headway);
+            for (time, headway) in scheduled {
+                let time_slot = TimeSlot::from_datetime(Local.timestamp(time, 0), &self.main.holidays);
+                headways.entry((route_variant, time_slot.id)).or_default().scheduled.push(headway);
+            }
+            for (time, headway) in observed {
+                let time_slot = TimeSlot::from_datetime(Local.timestamp(time, 0), &self.main.holidays);
+                headways.entry((route_variant, time_slot.id)).or_default().observed.push(headway);
+            }
+        }
+
+        let mut entries = Vec::new();
+        for ((route_variant, time_slot_id), mut times) in headways.into_iter().sorted_by_key(|(key, _)| *key) {
+            let sample_size = times.observed.len();
+            if sample_size == 0 || sample_size < min_samples {
+                continue;
+            }
+            let scheduled_headway = median(&mut times.scheduled) as u32;
+            if scheduled_headway > max_headway {
+                continue;
+            }
+            entries.push(HeadwayEntry {
+                route_id: String::from(route_id),
+                route_variant,
+                time_slot: time_slot_id,
+                scheduled_headway,
+                observed_p10: quantile(&mut times.observed, 0.1) as u32,
+                observed_median: median(&mut times.observed) as u32,
+                observed_p90: quantile(&mut times.observed, 0.9) as u32,
+                sample_size,
+            });
+        }
+
+        info!("Route {}: {} records, {} high-frequency route variants and time slots.", route_id, db_items.len(), entries.len());
+        Ok(entries)
+    }
+}
+
+// Computes the headways between the departures of one route variant at one stop on one day, given as pairs
+// of scheduled and actual departure time (as timestamps). Returns the scheduled and the observed headways,
+// each with the scheduled time of the first of both departures. The vehicles may overtake each other, but
+// passengers just wait for the next one, so the observed headways are those between consecutive actual
+// departures. Pairs of departures that are scheduled more than `max_headway` apart are left out, because
+// that's a break in the service, e.g. at night.
+fn get_headways(mut times: Vec<(i64, i64)>, max_headway: u32) -> (Vec<(i64, i32)>, Vec<(i64, i32)>) {
+    times.sort();
+    let scheduled = times.iter().tuple_windows()
+        .filter(|((scheduled_first, _), (scheduled_second, _))| scheduled_second - scheduled_first <= max_headway as i64)
+        .map(|((scheduled_first, _), (scheduled_second, _))| (*scheduled_first, (scheduled_second - scheduled_first) as i32))
+        .collect();
+
+    times.sort_by_key(|(scheduled, actual)| (*actual, *scheduled));
+    let observed = times.iter().tuple_windows()
+        .filter(|((scheduled_first, _), (scheduled_second, _))| (scheduled_second - scheduled_first).abs() <= max_headway as i64)
+        .map(|((scheduled_first, actual_first), (_, actual_second))| (*scheduled_first, (actual_second - actual_first) as i32))
+        .collect();
+
+    (scheduled, observed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_headways() {
+        // the second vehicle is 5 minutes late and gets overtaken by the third one, which is 3 minutes early
+        let times = vec![(0, 0), (600, 900), (900, 720), (1200, 1200)];
+        let (scheduled, observed) = get_headways(times, 900);
+        assert_eq!(scheduled, vec![(0, 600), (600, 300), (900, 300)]);
+        assert_eq!(observed, vec![(0, 720), (900, 180), (600, 300)]);
+
+        // a break in the service, even if the vehicle before it is late
+        let times = vec![(0, 0), (300, 600), (3600, 3600), (3900, 3900)];
+        let (scheduled, observed) = get_headways(times, 900);
+        assert_eq!(scheduled, vec![(0, 300), (3600, 300)]);
+        assert_eq!(observed, vec![(0, 600), (3600, 300)]);
+
+        assert_eq!(get_headways(vec![(0, 0)], 900), (Vec::new(), Vec::new()));
+    }
+}
@@ -0,0 +1,232 @@
+use chrono::{DateTime, Duration, Local, Timelike};
+use chrono::offset::TimeZone;
+use clap::ArgMatches;
+use gtfs_structures::Gtfs;
+use mysql::*;
+use mysql::prelude::*;
+use parse_duration::parse;
+use simple_error::bail;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use super::Analyser;
+
+use crate::{FnResult, Main, read_dir_recursive};
+use crate::importer::{get_archive_dir, get_archive_path};
+use crate::time_util::date_and_time;
+
+/// Realtime coverage of the trips of one agency that were scheduled to start today, until now.
+pub struct AgencyCoverage {
+    pub agency_name: String,
+    pub scheduled_trips: usize,
+    pub covered_trips: usize,
+}
+
+impl AgencyCoverage {
+    pub fn percentage(&self) -> f32 {
+        if self.scheduled_trips == 0 {
+            return 0.0;
+        }
+        100.0 * self.covered_trips as f32 / self.scheduled_trips as f32
+    }
+}
+
+/// Describes how well the realtime feed of a source is working.
+pub struct HealthReport {
+    pub created: DateTime<Local>,
+    /// the longest time between two realtime files that is considered normal
+    pub max_gap: Duration,
+    /// start of the time span in which the realtime files are counted, which is the lookback time
+    /// before `created`, or the end of the last day within it whose files have already been archived
+    pub since: DateTime<Local>,
+    pub last_rt_file: Option<(String, DateTime<Local>)>,
+    pub rt_file_count: usize,
+    pub last_recording: Option<DateTime<Local>>,
+    /// periods since `since` in which no realtime files were imported
+    pub gaps: Vec<(DateTime<Local>, DateTime<Local>)>,
+    pub agencies: Vec<AgencyCoverage>,
+}
+
+impl HealthReport {
+    /// Collects the report from the imported realtime files, the records table and the schedule.
+    pub fn compute(main: &Main, schedule: &Gtfs, max_gap: Duration, lookback: Duration) -> FnResult<Self> {
+        let now = Local::now();
+
+        // realtime files and the gaps between them:
+        let since = get_unarchived_start(&get_archive_dir(&main.dir), now - lookback, now);
+        let imported_dir = format!("{}/imported", &main.dir);
+        let rt_times: Vec<(String, DateTime<Local>)> = read_dir_recursive(&imported_dir).unwrap_or_default().into_iter()
+            .filter_map(|filename| Analyser::date_time_from_filename(&filename).ok().map(|time| (filename, time)))
+            .filter(|(_, time)| *time > since)
+            .collect();
+        let mut gaps = Vec::new();
+        let mut previous_time = since;
+        // the time since the last file counts as a gap as well
+        for time in rt_times.iter().map(|(_, time)| *time).chain(std::iter::once(now)) {
+            if time - previous_time > max_gap {
+                gaps.push((previous_time, time));
+            }
+            previous_time = time;
+        }
+
+        let mut con = main.pool.get_conn()?;
+        let last_recording: Option<Option<mysql::chrono::NaiveDateTime>> = con.exec_first(
+            "SELECT MAX(time_of_recording) FROM records WHERE `source` = :source",
+            params! { "source" => &main.source },
+        )?;
+        // times of recording are written in local time, so they can't fall into the gap of a DST change
+        let last_recording = last_recording.flatten().and_then(|naive| Local.from_local_datetime(&naive).earliest());
+
+        Ok(HealthReport {
+            created: now,
+            max_gap,
+            since,
+            last_rt_file: rt_times.last().cloned(),
+            rt_file_count: rt_times.len(),
+            last_recording,
+            gaps,
+            agencies: Self::compute_agency_coverage(main, schedule, now)?,
+        })
+    }
+
+    // compares the trips that started today until now with the trips for which there are records
+    fn compute_agency_coverage(main: &Main, schedule: &Gtfs, now: DateTime<Local>) -> FnResult<Vec<AgencyCoverage>> {
+        let mut con = main.pool.get_conn()?;
+        let covered_trip_ids: HashSet<String> = con.exec(
+            "SELECT DISTINCT trip_id FROM records WHERE `source` = :source AND trip_start_date = :date",
+            params! { "source" => &main.source, "date" => now.date().naive_local() },
+        )?.into_iter().collect();
+
+        let agency_names: HashMap<Option<String>, String> = schedule.agencies.iter().map(|agency| (agency.id.clone(), agency.name.clone())).collect();
+        let seconds_since_midnight = now.num_seconds_from_midnight();
+        let mut runs_today_by_service: HashMap<&String, bool> = HashMap::new();
+        let mut coverage_by_agency: HashMap<String, AgencyCoverage> = HashMap::new();
+
+        for trip in schedule.trips.values() {
+            let runs_today = *runs_today_by_service.entry(&trip.service_id).or_insert_with(|| {
+                schedule.trip_days(&trip.service_id, now.date().naive_local()).contains(&0)
+            });
+            let has_started = trip.stop_times.first().and_then(|st| st.departure_time).map_or(false, |t| t <= seconds_since_midnight);
+            if !runs_today || !has_started {
+                continue;
+            }
+            let agency_name = match schedule.get_route(&trip.route_id) {
+                Ok(route) if schedule.agencies.len() > 1 => agency_names.get(&route.agency_id).cloned().unwrap_or_else(|| String::from("unknown agency")),
+                _ => schedule.agencies.first().map(|agency| agency.name.clone()).unwrap_or_else(|| String::from("unknown agency")),
+            };
+            let coverage = coverage_by_agency.entry(agency_name.clone()).or_insert_with(|| AgencyCoverage {
+                agency_name,
+                scheduled_trips: 0,
+                covered_trips: 0,
+            });
+            coverage.scheduled_trips += 1;
+            if covered_trip_ids.contains(&trip.id) {
+                coverage.covered_trips += 1;
+            }
+        }
+
+        let mut agencies: Vec<AgencyCoverage> = coverage_by_agency.into_iter().map(|(_, coverage)| coverage).collect();
+        agencies.sort_by(|a, b| b.scheduled_trips.cmp(&a.scheduled_trips));
+        Ok(agencies)
+    }
+
+    /// The feed is stale if the last realtime file is older than the maximum gap.
+    pub fn is_stale(&self) -> bool {
+        match &self.last_rt_file {
+            Some((_, time)) => self.created - *time > self.max_gap,
+            None => true,
+        }
+    }
+
+    pub fn scheduled_trips(&self) -> usize {
+        self.agencies.iter().map(|agency| agency.scheduled_trips).sum()
+    }
+
+    pub fn covered_trips(&self) -> usize {
+        self.agencies.iter().map(|agency| agency.covered_trips).sum()
+    }
+}
+
+// The realtime files of past days may have been moved from the imported directory into the archives of
+// their days (see `import --archive-rt`). Listing the archives would mean to unpack them completely, so
+// the report starts after the last archived day within the lookback instead, which avoids false gaps.
+fn get_unarchived_start(archive_dir: &str, start: DateTime<Local>, now: DateTime<Local>) -> DateTime<Local> {
+    let mut unarchived_start = start;
+    let mut date = start.date();
+    while date < now.date() {
+        let next_date = date.succ();
+        if Path::new(&get_archive_path(archive_dir, date.naive_local())).exists() {
+            unarchived_start = std::cmp::max(unarchived_start, date_and_time(&next_date, 0));
+        }
+        date = next_date;
+    }
+    unarchived_start
+}
+
+pub struct HealthChecker<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches,
+}
+
+impl<'a> HealthChecker<'a> {
+    /// Prints the health report. Fails if the feed is stale, so that it can be used in scripts.
+    pub fn run_health(&self) -> FnResult<()> {
+        let max_gap = Duration::from_std(parse(self.args.value_of("max-gap").unwrap())?)?; // already validated by clap
+        let lookback = Duration::from_std(parse(self.args.value_of("lookback").unwrap())?)?; // already validated by clap
+        let report = HealthReport::compute(self.main, &self.analyser.schedule, max_gap, lookback)?;
+
+        match &report.last_rt_file {
+            Some((filename, time)) => println!("Last realtime file: {} ({} minutes ago)", filename, (report.created - *time).num_minutes()),
+            None => println!("No realtime file since {}.", report.since.format("%Y-%m-%d %H:%M")),
+        }
+        println!("Realtime files since {}: {}", report.since.format("%Y-%m-%d %H:%M"), report.rt_file_count);
+        match report.last_recording {
+            Some(time) => println!("Last record: {} ({} minutes ago)", time, (report.created - time).num_minutes()),
+            None => println!("No records."),
+        }
+
+        println!("Gaps of more than {} minutes: {}", max_gap.num_minutes(), report.gaps.len());
+        for (start, end) in &report.gaps {
+            println!("  {} to {} ({} minutes)", start.format("%Y-%m-%d %H:%M"), end.format("%Y-%m-%d %H:%M"), (*end - *start).num_minutes());
+        }
+
+        println!("Trips with realtime data today (until now): {} of {}", report.covered_trips(), report.scheduled_trips());
+        println!("agency; scheduled trips; trips with realtime data; coverage");
+        for agency in &report.agencies {
+            println!("{}; {}; {}; {:.1}%", agency.agency_name, agency.scheduled_trips, agency.covered_trips, agency.percentage());
+        }
+
+        if report.is_stale() {
+            bail!("The realtime feed is stale.");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use std::fs;
+
+    #[test]
+    fn test_get_unarchived_start() {
+        let archive_dir = std::env::temp_dir().join(format!("health_test_{}", std::process::id()));
+        fs::create_dir_all(&archive_dir).unwrap();
+        let archive_dir = archive_dir.to_str().unwrap();
+        let start = Local.ymd(2020, 11, 4).and_hms(10, 0, 0);
+        let now = Local.ymd(2020, 11, 5).and_hms(10, 0, 0);
+
+        // nothing archived, or only days before the lookback
+        assert_eq!(get_unarchived_start(archive_dir, start, now), start);
+        fs::write(get_archive_path(archive_dir, NaiveDate::from_ymd(2020, 11, 3)), b"").unwrap();
+        assert_eq!(get_unarchived_start(archive_dir, start, now), start);
+
+        // the files of yesterday have been archived, so only those of today are counted
+        fs::write(get_archive_path(archive_dir, NaiveDate::from_ymd(2020, 11, 4)), b"").unwrap();
+        assert_eq!(get_unarchived_start(archive_dir, start, now), Local.ymd(2020, 11, 5).and_hms(0, 0, 0));
+
+        fs::remove_dir_all(archive_dir).unwrap();
+    }
+}
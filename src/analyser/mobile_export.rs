@@ -0,0 +1,60 @@
+use clap::ArgMatches;
+use simple_error::bail;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufWriter;
+
+use super::Analyser;
+
+use crate::{FnResult, Main};
+use crate::types::MobileBundle;
+
+/// Exports a compact extract of the delay statistics for offline mobile apps, see `MobileBundle`.
+pub struct MobileExporter<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches,
+}
+
+impl<'a> MobileExporter<'a> {
+    pub fn run_export_mobile(&self) -> FnResult<()> {
+        let filename = self.args.value_of("file").unwrap(); // already validated by clap
+        let min_samples: u32 = self.args.value_of("min-samples").unwrap().parse()?; // has a default value
+        let region_stop_ids = match self.args.value_of("region") {
+            Some(region) => Some(self.get_stop_ids_in_region(region)?),
+            None => None,
+        };
+
+        let statistics = self.main.get_delay_statistics()?;
+        let bundle = MobileBundle::from_delay_statistics(&statistics, min_samples, |route_id, rvdata| {
+            self.analyser.is_route_selected(route_id) && region_stop_ids.as_ref().map_or(true, |stop_ids| {
+                rvdata.stop_ids.iter().any(|stop_id| stop_ids.contains(stop_id.as_str()))
+            })
+        });
+        let variant_count: usize = bundle.routes.iter().map(|route| route.variants.len()).sum();
+        let mut writer = BufWriter::new(File::create(filename)?);
+        bundle.write(&mut writer)?;
+        drop(writer);
+        info!("Exported {} stops, {} routes with {} route variants and {} default tables to {} ({} kB).",
+            bundle.stop_ids.len(), bundle.routes.len(), variant_count, bundle.default_tables.len(), filename,
+            std::fs::metadata(filename)?.len() / 1024);
+        Ok(())
+    }
+
+    // returns the IDs of the stops within the bounding box "min_lat,min_lon,max_lat,max_lon"
+    fn get_stop_ids_in_region(&self, region: &str) -> FnResult<HashSet<&str>> {
+        let bounds: Vec<f64> = region.split(',').map(|value| value.trim().parse::<f64>()).collect::<Result<_, _>>()?;
+        if bounds.len() != 4 || bounds[0] > bounds[2] || bounds[1] > bounds[3] {
+            bail!("Region must be given as min_lat,min_lon,max_lat,max_lon.");
+        }
+        let stop_ids: HashSet<&str> = self.analyser.schedule.stops.values()
+            .filter(|stop| match (stop.latitude, stop.longitude) {
+                (Some(lat), Some(lon)) => lat >= bounds[0] && lon >= bounds[1] && lat <= bounds[2] && lon <= bounds[3],
+                _ => false,
+            })
+            .map(|stop| stop.id.as_str())
+            .collect();
+        info!("Found {} stops in the region.", stop_ids.len());
+        Ok(stop_ids)
+    }
+}
@@ -4,9 +4,27 @@ mod curve_visualisation;
 pub mod specific_curves;
 pub mod default_curves;
 pub mod curves;
+mod realistic_schedule;
+mod archive;
+mod progress;
+pub mod health;
+mod feed_latency;
+mod coverage;
+pub mod operation;
+mod stats_exchange;
+mod mobile_export;
+mod curve_tuning;
+mod schedule_check;
+mod replay;
+mod headways;
+mod curve_tool;
+mod stop_id_matching;
+mod db_tune;
 
 #[cfg(feature = "visual-schedule")]
 mod visual_schedule;
+#[cfg(feature = "visual-schedule")]
+mod geographic_plot;
 
 use chrono::{Local, DateTime};
 use clap::{App, Arg, ArgMatches};
@@ -18,12 +36,28 @@ use specific_curves::SpecificCurveCreator;
 use default_curves::DefaultCurveCreator;
 use curves::CurveCreator;
 use curve_visualisation::CurveDrawer;
+use realistic_schedule::RealisticScheduleCreator;
+use archive::RecordArchiver;
+use health::HealthChecker;
+use feed_latency::FeedLatencyAnalyser;
+use coverage::CoverageAnalyser;
+use stats_exchange::StatisticsExchanger;
+use mobile_export::MobileExporter;
+use curve_tuning::CurveTuner;
+use schedule_check::ScheduleChecker;
+use replay::ReplayRunner;
+use headways::HeadwayAnalyser;
+use curve_tool::{CurveTool, get_format_arg};
+use stop_id_matching::StopIdMatcher;
+use db_tune::DbTuner;
 
 #[cfg(feature = "visual-schedule")]
 use visual_schedule::*;
 
 use crate::{Main, FnResult, OrError};
+use crate::types::{PortableFormat, CurveParameters, RouteSectioning, AgencyFilter, OutlierPolicy, AgeWeighting, StopIdMapping, CalibrationKey, CalibrationCounts};
 
+use std::collections::{BTreeMap, HashMap};
 use std::str::FromStr;
 use std::sync::Arc;
 
@@ -32,12 +66,24 @@ pub struct Analyser<'a> {
     main: &'a Main,
     args: &'a ArgMatches,
     schedule: Arc<Gtfs>,
+    agency_filter: AgencyFilter,
+    /// stop IDs of older schedules which have been renamed, empty if there is no stop_id_mapping.csv
+    stop_id_mapping: StopIdMapping,
 }
 
 impl<'a> Analyser<'a> {
     pub fn get_subcommand() -> App<'a> {
         let mut analyse = App::new("analyse").about("Performs some statistical analyses on the stored data.")
+            .arg(Arg::new("agency-ids")
+                .about("If provided, curves and operation counts are only computed for the routes of these agencies (comma-separated agency_ids).")
+                .long("agency-ids")
+                .env("AGENCY_IDS")
+                .takes_value(true)
+                .use_delimiter(true)
+                .value_name("AGENCY_ID")
+            )
             .subcommand(App::new("count")
+                .about("Counts the records per time interval, or their total per route, stop or agency, or the current predictions per origin type.")
                 .arg(Arg::new("interval")
                     .short('i')
                     .long("interval")
@@ -45,6 +91,26 @@ impl<'a> Analyser<'a> {
                     .about("Sets the step size for counting entries. The value will be parsed by the `parse_duration` crate, which acceps a superset of the `systemd.time` syntax.")
                     .value_name("INTERVAL")
                     .takes_value(true)
+                ).arg(Arg::new("by")
+                    .long("by")
+                    .default_value("time")
+                    .possible_values(&COUNT_BREAKDOWNS)
+                    .about("Counts the records per time interval (see `interval`), or in total per route, stop or agency, or counts the current predictions per origin type. All but `time` respect `agency-ids`.")
+                    .value_name("BREAKDOWN")
+                    .takes_value(true)
+                ).arg(Arg::new("format")
+                    .long("format")
+                    .default_value("text")
+                    .possible_values(&["text", "csv", "json"])
+                    .about("Writes the counts as semicolon-separated text, as CSV with a header line, or as JSON array.")
+                    .value_name("FORMAT")
+                    .takes_value(true)
+                ).arg(Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .about("File to which the counts are written. Defaults to stdout.")
+                    .value_name("OUTPUT_FILE")
+                    .takes_value(true)
                 )
             )
             .subcommand(App::new("compute-specific-curves")
@@ -60,10 +126,64 @@ impl<'a> Analyser<'a> {
                     .long("all")
                     .about("If provided, curves will be computed for each route of the schedule.")
                     .conflicts_with("route-ids")
-                )
+                ).arg(Arg::new("jobs")
+                    .short('j')
+                    .long("jobs")
+                    .about("Maximum number of routes for which curves are computed in parallel. Defaults to the number of CPU cores.")
+                    .value_name("N")
+                    .takes_value(true)
+                ).arg(Arg::new("progress-json")
+                    .long("progress-json")
+                    .about("If provided, progress and a summary are also written as JSON lines to this file, so that other tools can follow the progress.")
+                    .value_name("FILE")
+                    .takes_value(true)
+                ).arg(Arg::new("store-in-db")
+                    .long("store-in-db")
+                    .about("If provided, the curves are also written to database tables, from which the predictor can load them with --prediction-model database. Only the curves of the computed routes are replaced.")
+                ).arg(Arg::new("weather-curves")
+                    .long("weather-curves")
+                    .about("If provided, curve sets are additionally computed for each weather condition (dry, rain, snow), using the weather that the importer recorded.")
+                ).arg(Arg::new("merge-variants")
+                    .long("merge-variants")
+                    .about("If provided, route variants with near-identical stop sequences are merged, and the curves are computed from their pooled records.")
+                ).arg(Arg::new("merge-similarity")
+                    .long("merge-similarity")
+                    .default_value("0.9")
+                    .about("Route variants are merged if their common stops make up at least this fraction of the stops of the longer one.")
+                    .value_name("FRACTION")
+                    .takes_value(true)
+                ).args(OutlierPolicy::get_args())
+                .arg(AgeWeighting::get_arg())
             )
             .subcommand(App::new("compute-default-curves")
                 .about("Generates default curve data from realtime data out of the database")
+                .arg(Arg::new("progress-json")
+                    .long("progress-json")
+                    .about("If provided, progress and a summary are also written as JSON lines to this file, so that other tools can follow the progress.")
+                    .value_name("FILE")
+                    .takes_value(true)
+                ).arg(Arg::new("store-in-db")
+                    .long("store-in-db")
+                    .about("If provided, the curves are also written to database tables, from which the predictor can load them with --prediction-model database. Only the curves of the computed routes are replaced.")
+                ).arg(Arg::new("route-sections")
+                    .long("route-sections")
+                    .default_value("stop-count")
+                    .possible_values(&RouteSectioning::NAMES)
+                    .about("How the stops of each route are divided into beginning, middle and end for the default curves: by number of stops, by distance from the first and last stop, by the fare zones of the first and last stop, or by a list of stops.")
+                    .value_name("STRATEGY")
+                    .takes_value(true)
+                ).arg(Arg::new("section-length")
+                    .long("section-length")
+                    .default_value("5000")
+                    .about("Length of the beginning and end sections in meters, if route-sections is distance.")
+                    .value_name("METERS")
+                    .takes_value(true)
+                ).arg(Arg::new("section-stops")
+                    .long("section-stops")
+                    .about("CSV file with the columns stop_id, section (beginning, middle or end) and optionally route_id, if route-sections is stop-list.")
+                    .value_name("FILE")
+                    .takes_value(true)
+                ).arg(AgeWeighting::get_arg())
             )
             .subcommand(App::new("compute-curves")
                 .about("Generates default and specific curve data from realtime data out of the database")
@@ -83,6 +203,377 @@ impl<'a> Analyser<'a> {
                     .long("default-only")
                     .about("If provided, only default curves will be generated, but the output format is still the same.")
                     .conflicts_with("route-ids")
+                ).arg(Arg::new("jobs")
+                    .short('j')
+                    .long("jobs")
+                    .about("Maximum number of routes for which curves are computed in parallel. Defaults to the number of CPU cores.")
+                    .value_name("N")
+                    .takes_value(true)
+                ).arg(Arg::new("progress-json")
+                    .long("progress-json")
+                    .about("If provided, progress and a summary are also written as JSON lines to this file, so that other tools can follow the progress.")
+                    .value_name("FILE")
+                    .takes_value(true)
+                ).arg(Arg::new("store-in-db")
+                    .long("store-in-db")
+                    .about("If provided, the curves are also written to database tables, from which the predictor can load them with --prediction-model database. Only the curves of the computed routes are replaced.")
+                ).arg(Arg::new("weather-curves")
+                    .long("weather-curves")
+                    .about("If provided, curve sets are additionally computed for each weather condition (dry, rain, snow), using the weather that the importer recorded.")
+                ).arg(Arg::new("merge-variants")
+                    .long("merge-variants")
+                    .about("If provided, route variants with near-identical stop sequences are merged, and the curves are computed from their pooled records.")
+                ).arg(Arg::new("merge-similarity")
+                    .long("merge-similarity")
+                    .default_value("0.9")
+                    .about("Route variants are merged if their common stops make up at least this fraction of the stops of the longer one.")
+                    .value_name("FRACTION")
+                    .takes_value(true)
+                ).arg(Arg::new("route-sections")
+                    .long("route-sections")
+                    .default_value("stop-count")
+                    .possible_values(&RouteSectioning::NAMES)
+                    .about("How the stops of each route are divided into beginning, middle and end for the default curves: by number of stops, by distance from the first and last stop, by the fare zones of the first and last stop, or by a list of stops.")
+                    .value_name("STRATEGY")
+                    .takes_value(true)
+                ).arg(Arg::new("section-length")
+                    .long("section-length")
+                    .default_value("5000")
+                    .about("Length of the beginning and end sections in meters, if route-sections is distance.")
+                    .value_name("METERS")
+                    .takes_value(true)
+                ).arg(Arg::new("section-stops")
+                    .long("section-stops")
+                    .about("CSV file with the columns stop_id, section (beginning, middle or end) and optionally route_id, if route-sections is stop-list.")
+                    .value_name("FILE")
+                    .takes_value(true)
+                ).args(OutlierPolicy::get_args())
+                .arg(AgeWeighting::get_arg())
+            )
+            .subcommand(App::new("tune-curves")
+                .about("Chooses the simplification tolerance and the minimum number of data points per curve of the curve sets for each route by cross-validation, and saves them in all_curves.exp for the next computation of the curves.")
+                .arg(Arg::new("route-ids")
+                    .short('r')
+                    .long("route-ids")
+                    .about("If provided, the parameters will be chosen for each of the selected routes.")
+                    .value_name("ROUTE_ID")
+                    .multiple(true)
+                ).arg(Arg::new("all")
+                    .short('a')
+                    .long("all")
+                    .about("If provided, the parameters will be chosen for each route of the schedule.")
+                    .conflicts_with("route-ids")
+                ).arg(Arg::new("tolerances")
+                    .long("tolerances")
+                    .default_value("0.0005,0.001,0.002,0.005,0.01")
+                    .about("Comma-separated list of simplification tolerances to try.")
+                    .value_name("TOLERANCES")
+                    .takes_value(true)
+                ).arg(Arg::new("min-points")
+                    .long("min-points")
+                    .default_value("10,20,40,80")
+                    .about("Comma-separated list of minimum numbers of data points between two curves of a curve set to try.")
+                    .value_name("COUNTS")
+                    .takes_value(true)
+                ).arg(Arg::new("folds")
+                    .short('k')
+                    .long("folds")
+                    .default_value("5")
+                    .about("Number of folds for the cross-validation.")
+                    .value_name("K")
+                    .takes_value(true)
+                ).arg(Arg::new("max-score-loss")
+                    .long("max-score-loss")
+                    .default_value("0.01")
+                    .about("The coarsest parameters whose score is at most this fraction worse than the best score are chosen, so that curves don't get bigger for negligible improvements.")
+                    .value_name("FRACTION")
+                    .takes_value(true)
+                ).arg(Arg::new("jobs")
+                    .short('j')
+                    .long("jobs")
+                    .about("Maximum number of routes which are evaluated in parallel. Defaults to the number of CPU cores.")
+                    .value_name("N")
+                    .takes_value(true)
+                ).arg(Arg::new("dry-run")
+                    .long("dry-run")
+                    .about("If provided, only prints the chosen parameters, without saving them.")
+                )
+            )
+            .subcommand(App::new("compute-realistic-schedule")
+                .about("Generates a modified copy of the schedule, in which all times are shifted by their median delay according to the previously generated curve data")
+                .arg(Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .about("File name for the realistic schedule. Defaults to a file with the name of the schedule in the realistic_schedule subdirectory of dir.")
+                    .value_name("OUTPUT_FILE")
+                    .takes_value(true)
+                )
+            )
+            .subcommand(App::new("schedule-check")
+                .about("Finds pairs of consecutive stops on which most trips of a route variant and time slot need more time than scheduled, and writes them into a CSV report.")
+                .arg(Arg::new("route-ids")
+                    .short('r')
+                    .long("route-ids")
+                    .about("If provided, only the selected routes are checked. Defaults to all routes of the schedule.")
+                    .value_name("ROUTE_ID")
+                    .multiple(true)
+                ).arg(Arg::new("min-excess")
+                    .long("min-excess")
+                    .default_value("60")
+                    .about("A stop pair is reported if the median observed travel time exceeds the scheduled one by at least this many seconds…")
+                    .value_name("SECONDS")
+                    .takes_value(true)
+                ).arg(Arg::new("min-percentage")
+                    .long("min-percentage")
+                    .default_value("75")
+                    .about("…and if at least this percentage of the trips needed more time than scheduled.")
+                    .value_name("PERCENT")
+                    .takes_value(true)
+                ).arg(Arg::new("min-samples")
+                    .long("min-samples")
+                    .default_value("20")
+                    .about("Stop pairs with fewer recorded trips in a time slot are not reported.")
+                    .value_name("N")
+                    .takes_value(true)
+                ).arg(Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .about("File name for the report. Defaults to schedule_check.csv in dir.")
+                    .value_name("OUTPUT_FILE")
+                    .takes_value(true)
+                )
+            )
+            .subcommand(App::new("replay")
+                .about("Makes the predictions of a past day again from the archived realtime files and the current statistics, scores them against the recorded delays and appends the day's scores to a CSV file.")
+                .arg(Arg::new("date")
+                    .short('d')
+                    .long("date")
+                    .required(true)
+                    .about("The day (YYYY-MM-DD) that shall be replayed.")
+                    .value_name("DATE")
+                    .takes_value(true)
+                ).arg(Arg::new("rt-dir")
+                    .long("rt-dir")
+                    .about("Directory with the realtime files (in GTFS realtime format) of that day. Defaults to the imported subdirectory of dir.")
+                    .value_name("DIR")
+                    .takes_value(true)
+                ).arg(Arg::new("rt-archive-dir")
+                    .long("rt-archive-dir")
+                    .about("Directory with the daily archives of realtime files, which is used if there are no files of that day in rt-dir. Defaults to the rt_archive subdirectory of dir.")
+                    .value_name("DIR")
+                    .takes_value(true)
+                ).arg(Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .about("CSV file to which the scores are appended. Defaults to replay_scores.csv in dir.")
+                    .value_name("OUTPUT_FILE")
+                    .takes_value(true)
+                ).arg(Arg::new("save-calibration")
+                    .long("save-calibration")
+                    .about("If provided, the calibration of the replayed predictions per route and precision type is saved for the replayed day in the statistics in all_curves.exp, from which the monitor shows how trustworthy each kind of prediction has been. Replaying a day again replaces its calibration. Nothing is saved if the statistics have been computed on or after the replayed day.")
+                )
+            )
+            .subcommand(App::new("compute-headways")
+                .about("Finds the route variants that run so frequently that passengers don't look at the timetable, and computes the observed time between their vehicles per time slot. The monitor can show these headways instead of the delays of single trips.")
+                .arg(Arg::new("route-ids")
+                    .short('r')
+                    .long("route-ids")
+                    .about("If provided, only the selected routes are analysed and the results for all other routes are kept. Defaults to all routes of the schedule.")
+                    .value_name("ROUTE_ID")
+                    .multiple(true)
+                ).arg(Arg::new("max-headway")
+                    .long("max-headway")
+                    .default_value("600")
+                    .about("Route variants with a longer median scheduled time (in seconds) between two vehicles are not considered high-frequency.")
+                    .value_name("SECONDS")
+                    .takes_value(true)
+                ).arg(Arg::new("min-samples")
+                    .long("min-samples")
+                    .default_value("20")
+                    .about("Route variants with fewer observed headways in a time slot are not considered high-frequency.")
+                    .value_name("N")
+                    .takes_value(true)
+                )
+            )
+            .subcommand(App::new("curve-tool")
+                .about("Loads single curves from the predictions table or the delay statistics, prints them, convolves them or computes transfer probabilities, e.g. to debug a weird prediction. Curves are given as prediction:<TRIP_ID>:<YYYY-MM-DD>:<STOP_SEQUENCE>:<EVENT_TYPE>, specific:<ROUTE_ID>:<ROUTE_VARIANT>:<START_STOP_INDEX>:<END_STOP_INDEX>:<TIME_SLOT_ID>:<EVENT_TYPE>:<START_DELAY>, general:<ROUTE_ID>:<ROUTE_VARIANT>:<STOP_INDEX>:<EVENT_TYPE> or default:<ROUTE_TYPE>:<ROUTE_SECTION>:<TIME_SLOT_ID>:<EVENT_TYPE>.")
+                .subcommand(App::new("show")
+                    .about("Prints the quantiles or all points of a curve.")
+                    .arg(Arg::new("curve")
+                        .about("The curve to show.")
+                        .value_name("CURVE")
+                        .required(true)
+                    ).arg(get_format_arg())
+                )
+                .subcommand(App::new("convolve")
+                    .about("Prints the curve of the sum of two independent delays, e.g. of two consecutive sections of a journey.")
+                    .arg(Arg::new("first")
+                        .value_name("CURVE")
+                        .required(true)
+                    ).arg(Arg::new("second")
+                        .value_name("CURVE")
+                        .required(true)
+                    ).arg(get_format_arg())
+                )
+                .subcommand(App::new("transfer")
+                    .about("Computes the probability to catch a departure after an arrival, given both delay curves and the scheduled times.")
+                    .arg(Arg::new("arrival")
+                        .about("The delay curve of the arrival.")
+                        .value_name("ARRIVAL_CURVE")
+                        .required(true)
+                    ).arg(Arg::new("arrival-time")
+                        .about("The scheduled arrival time as HH:MM or HH:MM:SS.")
+                        .value_name("ARRIVAL_TIME")
+                        .required(true)
+                    ).arg(Arg::new("departure")
+                        .about("The delay curve of the departure.")
+                        .value_name("DEPARTURE_CURVE")
+                        .required(true)
+                    ).arg(Arg::new("departure-time")
+                        .about("The scheduled departure time as HH:MM or HH:MM:SS.")
+                        .value_name("DEPARTURE_TIME")
+                        .required(true)
+                    )
+                )
+                .subcommand(App::new("schema")
+                    .about("Prints the JSON schema of the stable JSON format of prediction curves.")
+                )
+            )
+            .subcommand(App::new("archive")
+                .about("Aggregates old records into histograms per stop pair and deletes the raw records from the database")
+                .arg(Arg::new("older-than")
+                    .short('o')
+                    .long("older-than")
+                    .default_value("90d")
+                    .about("Records of trips that started longer ago than this will be archived. The value will be parsed by the `parse_duration` crate, which acceps a superset of the `systemd.time` syntax.")
+                    .value_name("AGE")
+                    .takes_value(true)
+                ).arg(Arg::new("bucket-size")
+                    .short('b')
+                    .long("bucket-size")
+                    .default_value("30s")
+                    .about("Delays will be rounded to multiples of this duration before they are counted in the histograms. Must stay the same between runs.")
+                    .value_name("DURATION")
+                    .takes_value(true)
+                ).arg(Arg::new("dry-run")
+                    .long("dry-run")
+                    .about("If provided, only prints what would be archived, without changing the database.")
+                )
+            )
+            .subcommand(App::new("health")
+                .about("Reports how well the realtime feed is working: time of the last import, gaps between imports and realtime coverage of today's trips per agency. Fails if the feed is stale.")
+                .arg(Arg::new("max-gap")
+                    .long("max-gap")
+                    .default_value("10m")
+                    .about("Periods of this length without realtime files are reported as gaps. If the last file is older than this, the feed is considered stale.")
+                    .value_name("DURATION")
+                    .takes_value(true)
+                ).arg(Arg::new("lookback")
+                    .long("lookback")
+                    .default_value("24h")
+                    .about("Gaps are searched within this period before now.")
+                    .value_name("DURATION")
+                    .takes_value(true)
+                )
+            )
+            .subcommand(App::new("feed-latency")
+                .about("Reports per source how much older than their feed header the trip updates of the recorded realtime data were, which helps to choose the `max-delay-age` of the importer.")
+                .arg(Arg::new("lookback")
+                    .long("lookback")
+                    .default_value("24h")
+                    .about("Only records within this period before now are considered.")
+                    .value_name("DURATION")
+                    .takes_value(true)
+                )
+            )
+            .subcommand(App::new("coverage")
+                .about("Makes the predictions for all stop events of the trips that are scheduled on a day, and reports per route how many of them would be based on specific, semi-specific or only on default curves.")
+                .arg(Arg::new("date")
+                    .short('d')
+                    .long("date")
+                    .about("The day (YYYY-MM-DD) whose scheduled trips are predicted. Defaults to today.")
+                    .value_name("DATE")
+                    .takes_value(true)
+                ).arg(Arg::new("assume-realtime")
+                    .long("assume-realtime")
+                    .about("If provided, each trip is predicted as if it had just departed from its first stop on time, like trips with realtime data. Otherwise, trips are predicted without realtime data, like by the schedule-based predictions.")
+                )
+            )
+            .subcommand(App::new("export-stats")
+                .about("Exports the curves from all_curves.exp (and default_curves.exp) into a portable format that can be imported by other installations, even with other versions of this tool.")
+                .arg(Arg::new("format")
+                    .short('f')
+                    .long("format")
+                    .default_value("binary")
+                    .possible_values(&PortableFormat::NAMES)
+                    .about("JSON is readable by other tools, the binary format is much smaller. Both are described in the README.")
+                    .value_name("FORMAT")
+                    .takes_value(true)
+                ).arg(Arg::new("file")
+                    .index(1)
+                    .value_name("FILE")
+                    .required_unless("help")
+                    .about("The file to which the statistics are written.")
+                )
+            )
+            .subcommand(App::new("export-mobile")
+                .about("Exports a compact bundle of quantile tables from the statistics, for offline mobile apps which approximate the predictions without access to a server. The format is described in the README.")
+                .arg(Arg::new("region")
+                    .long("region")
+                    .about("If provided, only route variants with at least one stop within this bounding box are exported, given as min_lat,min_lon,max_lat,max_lon. Routes can also be restricted with --agency-ids.")
+                    .value_name("BOUNDING_BOX")
+                    .takes_value(true)
+                ).arg(Arg::new("min-samples")
+                    .long("min-samples")
+                    .default_value("10")
+                    .about("Stops with fewer recorded delays get no quantiles of their own, apps use the default tables for them instead.")
+                    .value_name("COUNT")
+                    .takes_value(true)
+                ).arg(Arg::new("file")
+                    .index(1)
+                    .value_name("FILE")
+                    .required_unless("help")
+                    .about("The file to which the bundle is written.")
+                )
+            )
+            .subcommand(App::new("match-stop-ids")
+                .about("Finds the stops whose stop_id has changed between two schedules, by their name and location, and adds them to stop_id_mapping.csv in dir, so that compute-specific-curves still uses the records of the old stop IDs.")
+                .arg(Arg::new("old-schedule")
+                    .long("old-schedule")
+                    .about("The older schedule. Defaults to the schedule file before the current one in the schedule subdirectory of dir.")
+                    .value_name("FILE")
+                    .takes_value(true)
+                ).arg(Arg::new("max-distance")
+                    .long("max-distance")
+                    .default_value("100")
+                    .about("Stops with the same name are only matched if they are at most this far apart, in meters.")
+                    .value_name("METERS")
+                    .takes_value(true)
+                ).arg(Arg::new("dry-run")
+                    .long("dry-run")
+                    .about("If provided, only prints the mapping, without saving it.")
+                )
+            )
+            .subcommand(App::new("db-tune")
+                .about("Checks that the predictions and records tables have the indexes which the stop pages, trip pages and curve computations need, creates the missing ones, and reports the queries whose plans look slow.")
+                .arg(Arg::new("check-only")
+                    .long("check-only")
+                    .about("If provided, missing indexes are only reported, with the statements that would create them.")
+                ).arg(Arg::new("max-rows")
+                    .long("max-rows")
+                    .default_value("100000")
+                    .about("Query plans which read more rows than this from one table are reported as slow.")
+                    .value_name("ROWS")
+                    .takes_value(true)
+                )
+            )
+            .subcommand(App::new("import-stats")
+                .about("Imports curves that have been exported with export-stats (in any format) and saves them as all_curves.exp, replacing the existing file.")
+                .arg(Arg::new("file")
+                    .index(1)
+                    .value_name("FILE")
+                    .required_unless("help")
+                    .about("The file from which the statistics are read.")
                 )
             )
             .subcommand(App::new("draw-curves")
@@ -124,6 +615,25 @@ impl<'a> Analyser<'a> {
                         .long("all")
                         .about("If provided, graphical schedules will be created for each route of the schedule.")
                         .conflicts_with("route-ids")
+                    ).arg(Arg::new("geographic")
+                        .short('g')
+                        .long("geographic")
+                        .about("If provided, the shape of each route variant is drawn onto a map as well (as svg), with each section between two stops colored by the average change of the delay on it. Needs shapes.txt in the schedule.")
+                    ).arg(Arg::new("format")
+                        .long("format")
+                        .about("Image format of the graphical schedules.")
+                        .value_name("FORMAT")
+                        .possible_values(&GraphFormat::NAMES)
+                        .default_value("png")
+                    ).arg(Arg::new("band-percentiles")
+                        .long("band-percentiles")
+                        .about("Each scheduled trip is drawn with a shaded band between these two percentiles of the arrival delays at each stop, as known from the delay statistics (comma-separated).")
+                        .value_name("PERCENTILE")
+                        .use_delimiter(true)
+                        .default_value("10,90")
+                    ).arg(Arg::new("no-bands")
+                        .long("no-bands")
+                        .about("If provided, the percentile bands are not drawn.")
                     )
                 );
             }
@@ -136,19 +646,26 @@ impl<'a> Analyser<'a> {
             main,
             args,
             schedule: main.get_schedule().unwrap(),
+            agency_filter: AgencyFilter::from_args(args),
+            stop_id_mapping: StopIdMapping::load_from_dir(&main.dir).unwrap_or_else(|e| {
+                warn!("Could not load {}, renamed stop IDs are not considered: {}", StopIdMapping::FILE_NAME, e);
+                StopIdMapping::default()
+            }),
         }
     }
 
     /// Runs the actions that are selected via the command line args
     pub fn run(&mut self) -> FnResult<()> {
         match self.args.clone().subcommand() {
-            ("count", Some(_sub_args)) => run_count(&self),
+            ("count", Some(sub_args)) => run_count(&self, sub_args),
             #[cfg(feature = "visual-schedule")]
             ("graph", Some(sub_args)) => {
                 let mut vsc = VisualScheduleCreator { 
                     main: self.main, 
                     analyser: self,
                     args: sub_args,
+                    format: GraphFormat::from_args(sub_args),
+                    bands: PercentileBands::from_args(self.main, sub_args)?,
                 };
                 vsc.run_visual_schedule()
             },
@@ -176,6 +693,123 @@ impl<'a> Analyser<'a> {
                 };
                 cc.run_curves()
             },
+            ("compute-realistic-schedule", Some(sub_args)) => {
+                let rsc = RealisticScheduleCreator {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                rsc.run_realistic_schedule()
+            },
+            ("schedule-check", Some(sub_args)) => {
+                let sc = ScheduleChecker {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                sc.run_schedule_check()
+            },
+            ("replay", Some(sub_args)) => {
+                let rr = ReplayRunner {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                rr.run_replay()
+            },
+            ("compute-headways", Some(sub_args)) => {
+                let ha = HeadwayAnalyser {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                ha.run_compute_headways()
+            },
+            ("curve-tool", Some(sub_args)) => {
+                let ct = CurveTool {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                ct.run_curve_tool()
+            },
+            ("archive", Some(sub_args)) => {
+                let ra = RecordArchiver {
+                    main: self.main,
+                    args: sub_args,
+                };
+                ra.run_archive()
+            },
+            ("health", Some(sub_args)) => {
+                let hc = HealthChecker {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                hc.run_health()
+            },
+            ("feed-latency", Some(sub_args)) => {
+                let fl = FeedLatencyAnalyser {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                fl.run_feed_latency()
+            },
+            ("coverage", Some(sub_args)) => {
+                let ca = CoverageAnalyser {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                ca.run_coverage()
+            },
+            ("export-stats", Some(sub_args)) => {
+                let se = StatisticsExchanger {
+                    main: self.main,
+                    args: sub_args,
+                };
+                se.run_export()
+            },
+            ("match-stop-ids", Some(sub_args)) => {
+                let sm = StopIdMatcher {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                sm.run_match_stop_ids()
+            },
+            ("export-mobile", Some(sub_args)) => {
+                let me = MobileExporter {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                me.run_export_mobile()
+            },
+            ("db-tune", Some(sub_args)) => {
+                let dt = DbTuner {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                dt.run_db_tune()
+            },
+            ("import-stats", Some(sub_args)) => {
+                let se = StatisticsExchanger {
+                    main: self.main,
+                    args: sub_args,
+                };
+                se.run_import()
+            },
+            ("tune-curves", Some(sub_args)) => {
+                let ct = CurveTuner {
+                    main: self.main,
+                    analyser: self,
+                    args: sub_args,
+                };
+                ct.run_tune_curves()
+            },
             ("draw-curves", Some(sub_args)) => {
                 let cd = CurveDrawer {
                     main: self.main,
@@ -188,6 +822,47 @@ impl<'a> Analyser<'a> {
         }
     }
 
+    /// Returns whether the route belongs to the agencies selected with `agency-ids`.
+    pub fn is_route_selected(&self, route_id: &str) -> bool {
+        self.agency_filter.contains_route(&self.schedule, route_id)
+    }
+
+    /// Removes the routes that don't belong to the agencies selected with `agency-ids`.
+    pub fn retain_selected_routes(&self, route_ids: &mut Vec<String>) {
+        if self.agency_filter.is_active() {
+            let count = route_ids.len();
+            route_ids.retain(|route_id| self.is_route_selected(route_id));
+            info!("Selected {} of {} routes by agency.", route_ids.len(), count);
+        }
+    }
+
+    /// Returns the curve parameters that have been chosen per route with tune-curves,
+    /// or an empty map if there are no delay statistics yet.
+    pub fn get_curve_parameters(&self) -> HashMap<String, CurveParameters> {
+        match self.main.get_delay_statistics() {
+            Ok(statistics) => statistics.curve_parameters.clone(),
+            Err(_) => HashMap::new(),
+        }
+    }
+
+    /// Returns the calibration per replayed day, route and precision type that has been collected with replay,
+    /// or an empty map if there are no delay statistics yet.
+    pub fn get_calibration(&self) -> BTreeMap<String, HashMap<CalibrationKey, CalibrationCounts>> {
+        match self.main.get_delay_statistics() {
+            Ok(statistics) => statistics.calibration.clone(),
+            Err(_) => BTreeMap::new(),
+        }
+    }
+
+    /// Returns the outlier policy with which the delay statistics have been computed,
+    /// or the default policy if there are no delay statistics yet.
+    pub fn get_outlier_policy(&self) -> OutlierPolicy {
+        match self.main.get_delay_statistics() {
+            Ok(statistics) => statistics.outlier_policy,
+            Err(_) => OutlierPolicy::DEFAULT,
+        }
+    }
+
     pub fn date_time_from_filename(filename: &str) -> FnResult<DateTime<Local>> {
         lazy_static! {
             static ref FIND_DATE: Regex = Regex::new(r"(\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2})").unwrap(); // can't fail because our hard-coded regex is known to be ok
@@ -0,0 +1,81 @@
+use chrono::{Local, NaiveDate, TimeZone};
+use clap::ArgMatches;
+use mysql::*;
+use mysql::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+use super::Analyser;
+
+use crate::{FnResult, Main};
+use crate::time_util::date_and_time;
+use crate::types::{OperationKey, OperationCounts, TimeSlot};
+
+/// Computes how many of the scheduled trips of each route were actually operated, by time slot.
+/// Trips without any realtime data are considered as not operated (e.g. cancelled).
+pub struct OperationCounter<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches,
+}
+
+impl<'a> OperationCounter<'a> {
+    pub fn get_operation_counts(&self) -> FnResult<HashMap<OperationKey, OperationCounts>> {
+        let schedule = &self.analyser.schedule;
+        let mut con = self.main.pool.get_conn()?;
+
+        info!("Looking up which trips have realtime data…");
+        let operated: HashSet<(String, NaiveDate)> = con.exec(
+            "SELECT DISTINCT trip_id, trip_start_date FROM records WHERE `source` = :source",
+            params! { "source" => &self.main.source },
+        )?.into_iter().collect();
+
+        // Only count days on which the importer was running, and only routes that have realtime
+        // data at all. Otherwise, all trips of routes that are not part of the realtime feed
+        // would look like they were cancelled.
+        let days: HashSet<NaiveDate> = operated.iter().map(|(_, day)| *day).collect();
+        let routes_with_realtime: HashSet<&String> = operated.iter()
+            .filter_map(|(trip_id, _)| schedule.trips.get(trip_id))
+            .map(|trip| &trip.route_id)
+            .filter(|route_id| self.analyser.is_route_selected(route_id))
+            .collect();
+        info!("Counting scheduled trips of {} routes on {} days…", routes_with_realtime.len(), days.len());
+
+        let mut runs_by_service_and_day: HashMap<(&String, NaiveDate), bool> = HashMap::new();
+        let mut counts: HashMap<OperationKey, OperationCounts> = HashMap::new();
+
+        for trip in schedule.trips.values().filter(|trip| routes_with_realtime.contains(&trip.route_id)) {
+            let start_time = match trip.stop_times.first().and_then(|st| st.departure_time) {
+                Some(time) => time,
+                None => continue,
+            };
+            for day in &days {
+                let runs = *runs_by_service_and_day.entry((&trip.service_id, *day)).or_insert_with(|| {
+                    schedule.trip_days(&trip.service_id, *day).contains(&0)
+                });
+                if !runs {
+                    continue;
+                }
+                let is_operated = operated.contains(&(trip.id.clone(), *day));
+                let date_time = date_and_time(&Local.from_local_date(day).unwrap(), start_time as i32);
+                let time_slot = TimeSlot::from_datetime(date_time, &self.main.holidays);
+
+                // count each trip for its own time slot and for the default one
+                for ts in &[time_slot, &TimeSlot::DEFAULT] {
+                    let entry = counts.entry(OperationKey {
+                        route_id: trip.route_id.clone(),
+                        time_slot: (*ts).clone(),
+                    }).or_default();
+                    entry.scheduled_trips += 1;
+                    if is_operated {
+                        entry.operated_trips += 1;
+                    }
+                }
+            }
+        }
+
+        let default_counts = counts.iter().filter(|(key, _)| key.time_slot == TimeSlot::DEFAULT).map(|(_, counts)| counts);
+        let (scheduled_sum, operated_sum) = default_counts.fold((0, 0), |(s, o), c| (s + c.scheduled_trips, o + c.operated_trips));
+        info!("{} of {} scheduled trips were operated.", operated_sum, scheduled_sum);
+        Ok(counts)
+    }
+}
@@ -0,0 +1,130 @@
+use serde_json::json;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::FnResult;
+
+/// Reports the progress of a long-running job, which consists of a known number of items
+/// (e.g. routes). Each finished item is reported as a human-readable line on stdout and,
+/// if a file name is given, as a line of JSON in that file, so that other tools can follow it.
+///
+/// Items may be finished by several threads at once.
+pub struct Progress {
+    phase: String,
+    total: usize,
+    done: AtomicUsize,
+    failed: AtomicUsize,
+    records: AtomicU64,
+    start: Instant,
+    json_output: Option<Mutex<File>>,
+}
+
+impl Progress {
+    pub fn new(phase: &str, total: usize, json_filename: Option<&str>) -> FnResult<Self> {
+        let json_output = match json_filename {
+            Some(filename) => Some(Mutex::new(OpenOptions::new().create(true).append(true).open(filename)?)),
+            None => None,
+        };
+        let progress = Progress {
+            phase: String::from(phase),
+            total,
+            done: AtomicUsize::new(0),
+            failed: AtomicUsize::new(0),
+            records: AtomicU64::new(0),
+            start: Instant::now(),
+            json_output,
+        };
+        info!("[{}] Starting with {} items.", progress.phase, total);
+        progress.write_json(json!({
+            "event": "start",
+            "phase": progress.phase,
+            "total": total,
+        }));
+        Ok(progress)
+    }
+
+    /// Reports that one item is done, and how many records were processed for it.
+    pub fn item_done(&self, item: &str, records: usize, success: bool) {
+        let done = self.done.fetch_add(1, Ordering::SeqCst) + 1;
+        if !success {
+            self.failed.fetch_add(1, Ordering::SeqCst);
+        }
+        let records_total = self.records.fetch_add(records as u64, Ordering::SeqCst) + records as u64;
+        let elapsed = self.start.elapsed();
+        let eta = self.estimate_remaining(done, elapsed);
+
+        info!(
+            "[{}] {}/{} ({:.1}%) {} {}, {} records so far, elapsed {}, ETA {}",
+            self.phase,
+            done,
+            self.total,
+            100.0 * done as f32 / usize::max(self.total, 1) as f32,
+            item,
+            if success { "done" } else { "failed" },
+            records_total,
+            format_duration(elapsed),
+            eta.map_or(String::from("unknown"), format_duration),
+        );
+        self.write_json(json!({
+            "event": "progress",
+            "phase": self.phase,
+            "item": item,
+            "success": success,
+            "done": done,
+            "total": self.total,
+            "records": records_total,
+            "elapsed_secs": elapsed.as_secs(),
+            "eta_secs": eta.map(|eta| eta.as_secs()),
+        }));
+    }
+
+    /// Prints a summary of the whole job. `details` are additional lines that describe the result.
+    pub fn finish(&self, details: &[String]) {
+        let done = self.done.load(Ordering::SeqCst);
+        let failed = self.failed.load(Ordering::SeqCst);
+        let records = self.records.load(Ordering::SeqCst);
+        let elapsed = self.start.elapsed();
+
+        info!("[{}] Finished {} of {} items ({} failed) with {} records in {}.", self.phase, done, self.total, failed, records, format_duration(elapsed));
+        for line in details {
+            info!("[{}] {}", self.phase, line);
+        }
+        self.write_json(json!({
+            "event": "summary",
+            "phase": self.phase,
+            "done": done,
+            "failed": failed,
+            "total": self.total,
+            "records": records,
+            "elapsed_secs": elapsed.as_secs(),
+            "details": details,
+        }));
+    }
+
+    // assumes that the remaining items take as long as the finished ones, on average
+    fn estimate_remaining(&self, done: usize, elapsed: Duration) -> Option<Duration> {
+        if done == 0 || done > self.total {
+            return None;
+        }
+        let seconds_per_item = elapsed.as_secs_f64() / done as f64;
+        Some(Duration::from_secs_f64(seconds_per_item * (self.total - done) as f64))
+    }
+
+    fn write_json(&self, value: serde_json::Value) {
+        if let Some(json_output) = &self.json_output {
+            let mut file = json_output.lock().unwrap();
+            // progress reporting must never stop the actual job, so errors are only printed
+            if let Err(e) = writeln!(file, "{}", value) {
+                warn!("Could not write progress: {}", e);
+            }
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!("{}h {:02}m {:02}s", seconds / 3600, (seconds / 60) % 60, seconds % 60)
+}
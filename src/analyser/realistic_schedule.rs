@@ -0,0 +1,167 @@
+use clap::ArgMatches;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::Path;
+use zip::write::FileOptions;
+
+use super::Analyser;
+use crate::types::{EventType, Id};
+use crate::{FnResult, Main, OrError};
+
+/// Creates a "realistic" schedule ("Plan realistisch"), in which each arrival and departure
+/// time of the official schedule is shifted by the median historic delay of that event.
+/// It is written as a complete GTFS file, so that it can be compared with the official one.
+pub struct RealisticScheduleCreator<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches,
+}
+
+// median delays in seconds for arrival and departure, indexed by trip_id and stop_sequence
+type DelayMap = HashMap<(Id, u16), (Option<i32>, Option<i32>)>;
+
+impl<'a> RealisticScheduleCreator<'a> {
+    pub fn run_realistic_schedule(&self) -> FnResult<()> {
+        let delays = self.compute_median_delays()?;
+
+        let schedule_filename = self.main.get_schedule_filename()?;
+        let output_filename = match self.args.value_of("output") {
+            Some(filename) => filename.to_string(),
+            None => {
+                let dir = format!("{}/realistic_schedule", self.main.dir);
+                fs::create_dir_all(&dir)?;
+                let base_name = Path::new(&schedule_filename).file_name().or_error("Schedule has no file name")?.to_string_lossy().to_string();
+                format!("{}/{}", dir, base_name)
+            }
+        };
+
+        self.write_realistic_schedule(&schedule_filename, &output_filename, &delays)?;
+        info!("Wrote realistic schedule to {}.", output_filename);
+        Ok(())
+    }
+
+    fn compute_median_delays(&self) -> FnResult<DelayMap> {
+        let schedule = &self.analyser.schedule;
+        let statistics = self.main.get_delay_statistics()?;
+
+        let mut delays = DelayMap::new();
+        let mut missing_count = 0;
+        for (trip_id, trip) in &schedule.trips {
+            for (stop_index, stop_time) in trip.stop_times.iter().enumerate() {
+                let arrival = statistics.get_median_delay(schedule, trip, stop_index, EventType::Arrival).ok().map(|d| d.round() as i32);
+                let departure = statistics.get_median_delay(schedule, trip, stop_index, EventType::Departure).ok().map(|d| d.round() as i32);
+                if arrival.is_none() || departure.is_none() {
+                    missing_count += 1;
+                }
+                delays.insert((Id::new(trip_id), stop_time.stop_sequence), (arrival, departure));
+            }
+        }
+
+        info!("Computed median delays for {} stop times, {} of them are incomplete.", delays.len(), missing_count);
+        Ok(delays)
+    }
+
+    /// Copies the GTFS file, replacing the times in stop_times.txt with the realistic ones.
+    fn write_realistic_schedule(&self, schedule_filename: &str, output_filename: &str, delays: &DelayMap) -> FnResult<()> {
+        let mut archive = zip::ZipArchive::new(File::open(schedule_filename)?)?;
+        let mut writer = zip::ZipWriter::new(File::create(output_filename)?);
+
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            let name = file.name().to_string();
+            let mut content = Vec::new();
+            file.read_to_end(&mut content)?;
+
+            writer.start_file(name.as_str(), FileOptions::default())?;
+            if name.ends_with("stop_times.txt") {
+                writer.write_all(&Self::adjust_stop_times(&content, delays)?)?;
+            } else {
+                writer.write_all(&content)?;
+            }
+        }
+
+        writer.finish()?;
+        Ok(())
+    }
+
+    fn adjust_stop_times(stop_times: &[u8], delays: &DelayMap) -> FnResult<Vec<u8>> {
+        let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(stop_times);
+        let header = reader.headers()?.clone();
+        let column_index = |name: &str| header.iter().position(|c| c.trim_start_matches('\u{feff}') == name).or_error(&format!("Column {} missing in stop_times.txt", name));
+        let trip_id_index = column_index("trip_id")?;
+        let stop_sequence_index = column_index("stop_sequence")?;
+        let arrival_index = column_index("arrival_time")?;
+        let departure_index = column_index("departure_time")?;
+
+        let mut writer = csv::WriterBuilder::new().terminator(csv::Terminator::CRLF).from_writer(Vec::with_capacity(stop_times.len()));
+        writer.write_record(&header)?;
+
+        for record in reader.records() {
+            let record = record?;
+            let mut fields: Vec<String> = record.iter().map(String::from).collect();
+
+            let trip_id = Id::new(&fields[trip_id_index]);
+            let stop_sequence: u16 = fields[stop_sequence_index].parse()?;
+            if let Some((arrival_delay, departure_delay)) = delays.get(&(trip_id, stop_sequence)) {
+                let arrival = parse_gtfs_time(&fields[arrival_index]).map(|t| t + arrival_delay.unwrap_or(0));
+                let departure = parse_gtfs_time(&fields[departure_index]).map(|t| t + departure_delay.unwrap_or(0));
+
+                if let Some(arrival) = arrival {
+                    fields[arrival_index] = format_gtfs_time(arrival);
+                }
+                if let Some(departure) = departure {
+                    // the vehicle can't leave before it arrived
+                    fields[departure_index] = format_gtfs_time(i32::max(departure, arrival.unwrap_or(departure)));
+                }
+            }
+
+            writer.write_record(&fields)?;
+        }
+
+        Ok(writer.into_inner().map_err(|e| e.into_error())?)
+    }
+}
+
+// parses times in the format H:MM:SS, which may be greater than 24:00:00
+fn parse_gtfs_time(time: &str) -> Option<i32> {
+    let parts: Vec<i32> = time.split(':').map(|p| p.parse().ok()).collect::<Option<Vec<i32>>>()?;
+    if parts.len() != 3 {
+        return None;
+    }
+    Some(parts[0] * 3600 + parts[1] * 60 + parts[2])
+}
+
+fn format_gtfs_time(time: i32) -> String {
+    // GTFS can't express times before the start of the service day
+    let time = i32::max(time, 0);
+    format!("{:02}:{:02}:{:02}", time / 3600, (time / 60) % 60, time % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adjust_stop_times() {
+        let stop_times = "trip_id,arrival_time,departure_time,stop_id,stop_sequence,stop_headsign\r\n\
+            t1,08:00:00,08:00:00,s1,1,\"Hauptbahnhof, Bremen\"\r\n\
+            t1,08:10:00,08:11:00,s2,2,\r\n\
+            \r\n\
+            t1,23:59:00,23:59:00,s3,3,\r\n\
+            t2,09:00:00,09:00:00,s1,1,\r\n";
+        let mut delays = DelayMap::new();
+        delays.insert((Id::new("t1"), 1), (Some(60), Some(-30)));
+        delays.insert((Id::new("t1"), 2), (None, Some(120)));
+        delays.insert((Id::new("t1"), 3), (Some(120), Some(120)));
+        let adjusted = RealisticScheduleCreator::adjust_stop_times(stop_times.as_bytes(), &delays).unwrap();
+        assert_eq!(String::from_utf8(adjusted).unwrap(), "trip_id,arrival_time,departure_time,stop_id,stop_sequence,stop_headsign\r\n\
+            t1,08:01:00,08:01:00,s1,1,\"Hauptbahnhof, Bremen\"\r\n\
+            t1,08:10:00,08:13:00,s2,2,\r\n\
+            t1,24:01:00,24:01:00,s3,3,\r\n\
+            t2,09:00:00,09:00:00,s1,1,\r\n");
+
+        assert!(RealisticScheduleCreator::adjust_stop_times(b"trip_id,stop_sequence\r\nt1,1\r\n", &delays).is_err());
+        assert!(RealisticScheduleCreator::adjust_stop_times(b"trip_id,arrival_time,departure_time,stop_sequence\r\nt1,08:00:00,08:00:00\r\n", &delays).is_err());
+    }
+}
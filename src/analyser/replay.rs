@@ -0,0 +1,388 @@
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
+use clap::ArgMatches;
+use gtfs_rt::FeedMessage as GtfsRealtimeMessage;
+use mysql::*;
+use mysql::prelude::*;
+use prost::Message; // need to use this, otherwise GtfsRealtimeMessage won't have a `decode` method
+use rayon::prelude::*;
+use serde::Serialize;
+use simple_error::bail;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::Mutex;
+
+use dystonse_curves::Curve;
+use dystonse_curves::tree::{SerdeFormat, NodeData};
+
+use super::Analyser;
+use super::curve_utils::crps;
+use crate::importer::{read_realtime_file, for_each_archived_file, get_archive_dir, get_archive_path};
+use crate::predictor::Predictor;
+use crate::types::{CalibrationCounts, CalibrationKey, DbItem, DelayStatistics, EventPair, EventType, GtfsDateTime, Id, PredictionBasis, PredictionResult, VehicleIdentifier};
+use crate::{FnResult, Loadable, Main, OrError, read_dir_recursive, date_from_filename};
+
+// trip_id, trip start time in seconds, stop_sequence
+type ObservationKey = (Id, i64, u16);
+
+/// Replays the realtime files of a past day: for each trip update, the predictions are made again
+/// from the current statistics, just like the importer would have made them at the time of the
+/// file. Each prediction is scored against the delay that was recorded later, and the day's
+/// scores are appended to a CSV file, so that the quality of the model can be tracked over time.
+///
+/// The scores are only meaningful if the statistics have been computed before the replayed day,
+/// otherwise the predictions are scored against the delays that they have been computed from.
+pub struct ReplayRunner<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches,
+}
+
+/// Scores of all predictions of one day, which is one line of the output file.
+#[derive(Serialize)]
+struct ReplayScore {
+    date: NaiveDate,
+    realtime_files: usize,
+    predictions: usize,
+    /// predictions for which a delay has been recorded
+    scored_predictions: usize,
+    /// mean continuous ranked probability score in seconds, lower is better
+    mean_crps: f64,
+    /// mean absolute error in seconds if the current delay was assumed to stay the same
+    mean_persistence_error: f64,
+    /// 1 - mean_crps / mean_persistence_error, higher is better. Positive values mean that the
+    /// predictions are better than just assuming that the delay stays the same.
+    skill_score: f64,
+    /// mean absolute difference between the median of the predictions and the observed delays in seconds
+    mean_abs_median_error: f64,
+    /// share of the observed delays that fell between the 10th and 90th percentile of the predictions
+    interval_coverage: f64,
+    /// whether the statistics may contain the records of the day, see `is_in_sample`
+    in_sample: bool,
+}
+
+#[derive(Default)]
+struct ReplayEvaluation {
+    predictions: usize,
+    scored: usize,
+    crps_sum: f64,
+    persistence_error_sum: f64,
+    median_error_sum: f64,
+    covered: usize,
+    calibration: HashMap<CalibrationKey, CalibrationCounts>,
+}
+
+impl ReplayEvaluation {
+    fn merge(mut self, other: Self) -> Self {
+        self.predictions += other.predictions;
+        self.scored += other.scored;
+        self.crps_sum += other.crps_sum;
+        self.persistence_error_sum += other.persistence_error_sum;
+        self.median_error_sum += other.median_error_sum;
+        self.covered += other.covered;
+        for (key, counts) in other.calibration {
+            self.calibration.entry(key).or_default().add(&counts);
+        }
+        self
+    }
+
+    fn to_score(&self, date: NaiveDate, realtime_files: usize, in_sample: bool) -> ReplayScore {
+        let mean = |sum: f64| if self.scored == 0 { 0.0 } else { sum / self.scored as f64 };
+        let mean_crps = mean(self.crps_sum);
+        let mean_persistence_error = mean(self.persistence_error_sum);
+        ReplayScore {
+            date,
+            realtime_files,
+            predictions: self.predictions,
+            scored_predictions: self.scored,
+            mean_crps,
+            mean_persistence_error,
+            skill_score: if mean_persistence_error > 0.0 { 1.0 - mean_crps / mean_persistence_error } else { 0.0 },
+            mean_abs_median_error: mean(self.median_error_sum),
+            interval_coverage: mean(self.covered as f64),
+            in_sample,
+        }
+    }
+}
+
+impl<'a> ReplayRunner<'a> {
+    pub fn run_replay(&self) -> FnResult<()> {
+        let date = NaiveDate::parse_from_str(self.args.value_of("date").unwrap(), "%Y-%m-%d")?; // is required
+        let rt_dir = match self.args.value_of("rt-dir") {
+            Some(rt_dir) => String::from(rt_dir),
+            None => format!("{}/imported", self.main.dir),
+        };
+        let output = match self.args.value_of("output") {
+            Some(output) => String::from(output),
+            None => format!("{}/replay_scores.csv", self.main.dir),
+        };
+
+        let archive_dir = match self.args.value_of("rt-archive-dir") {
+            Some(archive_dir) => String::from(archive_dir),
+            None => get_archive_dir(&self.main.dir),
+        };
+
+        // file names start with the time of download, and read_dir_recursive sorts them by date and name, so they are in time order
+        let service_day = Local.from_local_date(&date).unwrap();
+        let rt_filenames: Vec<String> = read_dir_recursive(&rt_dir).unwrap_or_default().into_iter()
+            .filter(|filename| date_from_filename(filename).map_or(false, |file_date| file_date == service_day))
+            .collect();
+        // the files of past days may have been archived by the importer
+        let archive_path = get_archive_path(&archive_dir, date);
+        if rt_filenames.is_empty() && !Path::new(&archive_path).exists() {
+            bail!("No realtime files of {} in {} and no archive {}.", date, rt_dir, archive_path);
+        }
+
+        let in_sample = is_in_sample(self.get_statistics_time(), date);
+        if in_sample {
+            warn!("The statistics have been computed on or after {}, so they probably contain the records of that day. \
+                The predictions are scored against the delays that they have been computed from, and the scores will look better than they are.", date);
+        }
+
+        let observations = self.get_observations(date)?;
+        let predictor = Predictor::new(self.main, self.args)?;
+        info!("Replaying the realtime files of {} with {} recorded events…", date, observations.len());
+
+        let previous_bases: Mutex<HashMap<VehicleIdentifier, PredictionBasis>> = Mutex::new(HashMap::new());
+        let mut evaluation = ReplayEvaluation::default();
+        let mut replay = |rt_filename: &str, data: &[u8]| {
+            match self.replay_file(data, date, &predictor, &observations, &previous_bases) {
+                Ok(file_evaluation) => evaluation = std::mem::take(&mut evaluation).merge(file_evaluation),
+                Err(e) => warn!("Could not replay {}: {}", rt_filename, e),
+            }
+        };
+        let realtime_files = if rt_filenames.is_empty() {
+            info!("Reading the realtime files from {}.", archive_path);
+            for_each_archived_file(&archive_path, |rt_filename, data| {
+                replay(rt_filename, &data);
+                Ok(())
+            })?
+        } else {
+            for rt_filename in &rt_filenames {
+                match read_realtime_file(rt_filename) {
+                    Ok(data) => replay(rt_filename, &data),
+                    Err(e) => warn!("Could not read {}: {}", rt_filename, e),
+                }
+            }
+            rt_filenames.len()
+        };
+
+        let score = evaluation.to_score(date, realtime_files, in_sample);
+        info!(
+            "Replayed {} predictions of {}, {} of them could be scored: mean CRPS {:.1}s, skill score {:.3}.",
+            score.predictions, date, score.scored_predictions, score.mean_crps, score.skill_score
+        );
+
+        // one line per replayed day, so the header is only written into new files
+        let has_header = Path::new(&output).exists();
+        let file = OpenOptions::new().create(true).append(true).open(&output)?;
+        let mut writer = csv::WriterBuilder::new().has_headers(!has_header).from_writer(file);
+        writer.serialize(&score)?;
+        writer.flush()?;
+        info!("Appended the scores to {}.", output);
+
+        if self.args.is_present("save-calibration") {
+            if in_sample {
+                // the monitor shows the calibration as a trust rating, which in-sample scores would overstate
+                warn!("Not saving the calibration, because the predictions have been scored in-sample.");
+            } else {
+                self.save_calibration(date, evaluation.calibration)?;
+            }
+        }
+        Ok(())
+    }
+
+    // saves the calibration of the replayed day next to the one of other days in all_curves.exp, replacing it if the day has been replayed before
+    fn save_calibration(&self, date: NaiveDate, calibration: HashMap<CalibrationKey, CalibrationCounts>) -> FnResult<()> {
+        let filename = format!("{}/all_curves.exp", self.main.dir);
+        if !Path::new(&filename).exists() {
+            bail!("{} does not exist yet, run compute-curves first.", filename);
+        }
+        let mut statistics = <DelayStatistics as Loadable<DelayStatistics>>::load(&filename)?;
+        let count = calibration.len();
+        if statistics.calibration.insert(date.to_string(), calibration).is_some() {
+            info!("Replacing the calibration that has been saved for {} before.", date);
+        }
+        statistics.save_to_file(&self.main.dir, "all_curves", &SerdeFormat::MessagePack)?;
+        info!("Saved the calibration of {} routes and precision types for {} to {}.", count, date, filename);
+        Ok(())
+    }
+
+    // the time at which the statistics have last been computed, if there are any
+    fn get_statistics_time(&self) -> Option<DateTime<Local>> {
+        ["all_curves.exp", "default_curves.exp"].iter()
+            .filter_map(|name| std::fs::metadata(format!("{}/{}", self.main.dir, name)).ok()?.modified().ok())
+            .map(DateTime::<Local>::from)
+            .max()
+    }
+
+    // the last recorded delays of all trips of the day, which are the actual delays of all past events
+    fn get_observations(&self, date: NaiveDate) -> FnResult<HashMap<ObservationKey, EventPair<Option<i32>>>> {
+        let mut con = self.main.pool.get_conn()?;
+        let db_items: Vec<DbItem> = con.exec(
+            r"SELECT
+                delay_arrival,
+                delay_departure,
+                trip_start_date,
+                trip_start_time,
+                trip_id,
+                stop_id,
+                stop_sequence,
+                route_variant
+            FROM
+                records
+            WHERE
+                source = :source AND
+                trip_start_date = :date",
+            params! {
+                "source" => &self.main.source,
+                date,
+            },
+        )?;
+
+        Ok(db_items.into_iter().filter_map(|item| {
+            let start_time = item.trip_start_time?.num_seconds();
+            Some(((item.trip_id, start_time, item.stop_sequence), item.delay))
+        }).collect())
+    }
+
+    fn replay_file(
+        &self,
+        data: &[u8],
+        date: NaiveDate,
+        predictor: &Predictor,
+        observations: &HashMap<ObservationKey, EventPair<Option<i32>>>,
+        previous_bases: &Mutex<HashMap<VehicleIdentifier, PredictionBasis>>,
+    ) -> FnResult<ReplayEvaluation> {
+        let message = GtfsRealtimeMessage::decode(data)?;
+
+        Ok(message.entity.par_iter().filter_map(|entity| entity.trip_update.as_ref()).map(|trip_update| {
+            match self.replay_trip_update(trip_update, date, predictor, observations, previous_bases) {
+                Ok(evaluation) => evaluation,
+                Err(e) => {
+                    debug!("Could not replay trip update: {}", e);
+                    ReplayEvaluation::default()
+                }
+            }
+        }).reduce(ReplayEvaluation::default, ReplayEvaluation::merge))
+    }
+
+    fn replay_trip_update(
+        &self,
+        trip_update: &gtfs_rt::TripUpdate,
+        date: NaiveDate,
+        predictor: &Predictor,
+        observations: &HashMap<ObservationKey, EventPair<Option<i32>>>,
+        previous_bases: &Mutex<HashMap<VehicleIdentifier, PredictionBasis>>,
+    ) -> FnResult<ReplayEvaluation> {
+        let mut evaluation = ReplayEvaluation::default();
+        let route_id = trip_update.trip.route_id.as_ref().or_error("Trip needs route_id")?;
+        let trip_id = trip_update.trip.trip_id.as_ref().or_error("Trip needs id")?;
+        if !self.analyser.is_route_selected(route_id) {
+            return Ok(evaluation);
+        }
+        let schedule_trip = predictor.schedule.get_trip(trip_id)?;
+        // same as in the importer: use the service day of the schedule, even if the feed gives trips after midnight with the calendar date
+        let start = GtfsDateTime::from_trip_descriptor(&trip_update.trip)?;
+        let start = match schedule_trip.stop_times.first().and_then(|st| st.departure_time) {
+            Some(scheduled_start) => start.with_scheduled_time(scheduled_start as i32),
+            None => start,
+        };
+        // the records of other days have not been loaded, so the predictions could not be scored
+        if start.service_day().naive_local() != date {
+            return Ok(evaluation);
+        }
+
+        // same as in the importer: the first stop with a departure delay is the basis for the predictions
+        let basis = trip_update.stop_time_update.iter().find_map(|stop_time_update| {
+            let delay = stop_time_update.departure.as_ref()?.delay?;
+            Some(PredictionBasis {
+                stop_sequence: stop_time_update.stop_sequence? as u16,
+                delay_departure: Some(delay as i64),
+            })
+        });
+        let basis = match basis {
+            Some(basis) => basis,
+            None => return Ok(evaluation),
+        };
+
+        // the importer only makes new predictions for a vehicle if the basis has changed
+        let vehicle_id = VehicleIdentifier { trip_id: Id::new(trip_id), start: start.clone() };
+        {
+            let mut bases = previous_bases.lock().unwrap();
+            if bases.get(&vehicle_id) == Some(&basis) {
+                return Ok(evaluation);
+            }
+            bases.insert(vehicle_id, basis.clone());
+        }
+
+        let basis_stop_sequence = basis.stop_sequence;
+        let current_delay = basis.delay_departure.unwrap() as f32; // was set above
+        let basis = Some(basis);
+        for stop_time in schedule_trip.stop_times.iter().filter(|st| st.stop_sequence > basis_stop_sequence) {
+            for event_type in &EventType::TYPES {
+                let curve_data = match predictor.predict(route_id, trip_id, &basis, stop_time.stop_sequence, **event_type, start.date_time()) {
+                    Ok(PredictionResult::CurveData(curve_data)) => curve_data,
+                    _ => continue,
+                };
+                evaluation.predictions += 1;
+
+                let observed = observations.get(&(Id::new(trip_id), start.duration().num_seconds(), stop_time.stop_sequence))
+                    .and_then(|delays| delays[**event_type]);
+                if let Some(observed) = observed {
+                    let observed = observed as f32;
+                    let curve = &curve_data.curve;
+                    evaluation.scored += 1;
+                    evaluation.crps_sum += crps(curve, observed) as f64;
+                    evaluation.persistence_error_sum += (observed - current_delay).abs() as f64;
+                    evaluation.median_error_sum += (observed - curve.x_at_y(0.5)).abs() as f64;
+                    if observed >= curve.x_at_y(0.1) && observed <= curve.x_at_y(0.9) {
+                        evaluation.covered += 1;
+                    }
+                    evaluation.calibration.entry(CalibrationKey::new(route_id, &curve_data.precision_type))
+                        .or_default()
+                        .add_observation(curve.y_at_x(observed));
+                }
+            }
+        }
+        Ok(evaluation)
+    }
+}
+
+/// Whether statistics that have been computed at `statistics_time` may contain the records of trips that
+/// started on `date`. If so, the predictions are scored in-sample. Unknown times count as in-sample.
+fn is_in_sample(statistics_time: Option<DateTime<Local>>, date: NaiveDate) -> bool {
+    statistics_time.map_or(true, |time| time.naive_local().date() >= date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_in_sample() {
+        let date = NaiveDate::from_ymd(2020, 10, 1);
+        assert!(!is_in_sample(Some(Local.ymd(2020, 9, 30).and_hms(23, 59, 0)), date));
+        assert!(is_in_sample(Some(Local.ymd(2020, 10, 1).and_hms(0, 10, 0)), date));
+        assert!(is_in_sample(Some(Local.ymd(2020, 10, 5).and_hms(12, 0, 0)), date));
+        assert!(is_in_sample(None, date));
+    }
+
+    #[test]
+    fn test_to_score() {
+        let date = NaiveDate::from_ymd(2020, 10, 1);
+        let first = ReplayEvaluation { predictions: 3, scored: 2, crps_sum: 40.0, persistence_error_sum: 100.0, median_error_sum: 60.0, covered: 1, ..Default::default() };
+        let second = ReplayEvaluation { predictions: 1, scored: 2, crps_sum: 40.0, persistence_error_sum: 60.0, median_error_sum: 20.0, covered: 2, ..Default::default() };
+        let score = first.merge(second).to_score(date, 5, false);
+        assert_eq!((score.predictions, score.scored_predictions, score.realtime_files), (4, 4, 5));
+        assert_eq!(score.mean_crps, 20.0);
+        assert_eq!(score.mean_persistence_error, 40.0);
+        assert_eq!(score.skill_score, 0.5);
+        assert_eq!(score.mean_abs_median_error, 20.0);
+        assert_eq!(score.interval_coverage, 0.75);
+        assert!(!score.in_sample);
+
+        let empty = ReplayEvaluation::default().to_score(date, 0, true);
+        assert_eq!((empty.mean_crps, empty.skill_score, empty.interval_coverage), (0.0, 0.0, 0.0));
+    }
+}
@@ -0,0 +1,206 @@
+use clap::ArgMatches;
+use itertools::Itertools;
+use mysql::*;
+use mysql::prelude::*;
+use serde::Serialize;
+use simple_error::bail;
+use std::collections::HashMap;
+
+use super::Analyser;
+use crate::types::{DbItem, EventType, TimeSlot};
+use crate::{FnResult, Main};
+use crate::stats_util::median;
+
+/// Finds the parts of the schedule that can't be kept: pairs of consecutive stops on which the
+/// vehicles of a route variant need more time than scheduled for most trips of a time slot.
+/// The result is written as CSV, so that agencies can use it to fix their timetables.
+pub struct ScheduleChecker<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches,
+}
+
+/// One line of the report, for one pair of consecutive stops of a route variant in one time slot.
+#[derive(Serialize)]
+struct ScheduleCheckRow {
+    route_id: String,
+    route_name: String,
+    route_variant: u64,
+    time_slot: u8,
+    time_slot_description: &'static str,
+    start_stop_sequence: u16,
+    start_stop_id: String,
+    start_stop_name: String,
+    end_stop_sequence: u16,
+    end_stop_id: String,
+    end_stop_name: String,
+    /// all times in seconds, as median over the observed trips
+    scheduled_travel_time: i32,
+    observed_travel_time: i32,
+    excess_travel_time: i32,
+    /// share of the trips (in percent) that needed more time than scheduled
+    exceeded_percentage: f32,
+    sample_size: usize,
+}
+
+// route_variant, start_stop_sequence, end_stop_sequence, time slot id
+type PairKey = (u64, u16, u16, u8);
+
+/// scheduled and observed travel times (in seconds) of all trips on a pair of stops
+struct TravelTimes {
+    start_stop_id: String,
+    end_stop_id: String,
+    scheduled: Vec<i32>,
+    observed: Vec<i32>,
+}
+
+impl<'a> ScheduleChecker<'a> {
+    pub fn run_schedule_check(&self) -> FnResult<()> {
+        let min_excess: i32 = self.args.value_of("min-excess").unwrap().parse()?; // has a default value
+        let min_percentage: f32 = self.args.value_of("min-percentage").unwrap().parse()?; // has a default value
+        let min_samples: usize = self.args.value_of("min-samples").unwrap().parse()?; // has a default value
+        if !(0.0..=100.0).contains(&min_percentage) {
+            bail!("The minimum percentage must be between 0 and 100.");
+        }
+
+        let mut route_ids: Vec<String> = if let Some(route_ids) = self.args.values_of("route-ids") {
+            route_ids.map(String::from).collect()
+        } else {
+            self.analyser.schedule.routes.keys().cloned().collect()
+        };
+        self.analyser.retain_selected_routes(&mut route_ids);
+        route_ids.sort();
+
+        let output = match self.args.value_of("output") {
+            Some(output) => String::from(output),
+            None => format!("{}/schedule_check.csv", self.main.dir),
+        };
+        let mut writer = csv::Writer::from_path(&output)?;
+
+        info!("Checking the scheduled travel times of {} routes…", route_ids.len());
+        let mut row_count = 0;
+        for route_id in &route_ids {
+            let rows = self.check_route(route_id, min_excess, min_percentage, min_samples)?;
+            for row in &rows {
+                writer.serialize(row)?;
+            }
+            row_count += rows.len();
+        }
+        writer.flush()?;
+
+        info!("Found {} stop pairs with infeasible travel times, wrote them to {}.", row_count, output);
+        Ok(())
+    }
+
+    fn check_route(&self, route_id: &str, min_excess: i32, min_percentage: f32, min_samples: usize) -> FnResult<Vec<ScheduleCheckRow>> {
+        let schedule = &self.analyser.schedule;
+        let mut con = self.main.pool.get_conn()?;
+        let db_items: Vec<DbItem> = con.exec(
+            r"SELECT
+                delay_arrival,
+                delay_departure,
+                trip_start_date,
+                trip_start_time,
+                trip_id,
+                stop_id,
+                stop_sequence,
+                route_variant
+            FROM
+                records
+            WHERE
+                source = :source AND
+                route_id = :route_id
+            ORDER BY
+                trip_start_date,
+                trip_start_time,
+                trip_id,
+                stop_sequence",
+            params! {
+                "source" => &self.main.source,
+                route_id,
+            },
+        )?;
+
+        let mut travel_times: HashMap<PairKey, TravelTimes> = HashMap::new();
+        let trips = db_items.iter().group_by(|item| (item.trip_start_date, item.trip_start_time, item.trip_id.clone()));
+        for ((_, _, trip_id), items) in &trips {
+            let trip = match schedule.get_trip(&trip_id) {
+                Ok(trip) => trip,
+                Err(_) => continue,
+            };
+            for (start, end) in items.tuple_windows() {
+                let start_index = match trip.get_stop_index_by_stop_sequence(start.stop_sequence) {
+                    Some(index) => index,
+                    None => continue,
+                };
+                // only consecutive stops, otherwise the time spent at the stops in between would be counted as well
+                if trip.get_stop_index_by_stop_sequence(end.stop_sequence) != Some(start_index + 1) {
+                    continue;
+                }
+                let (departure, arrival) = match (trip.stop_times[start_index].departure_time, trip.stop_times[start_index + 1].arrival_time) {
+                    (Some(departure), Some(arrival)) => (departure as i32, arrival as i32),
+                    _ => continue,
+                };
+                let (start_delay, end_delay) = match (start.delay.departure, end.delay.arrival) {
+                    (Some(start_delay), Some(end_delay)) => (start_delay, end_delay),
+                    _ => continue,
+                };
+                let departure_time = match start.get_datetime_from_trip(trip, EventType::Departure) {
+                    Some(departure_time) => departure_time,
+                    None => continue,
+                };
+                let time_slot = TimeSlot::from_datetime(departure_time, &self.main.holidays);
+
+                let times = travel_times.entry((start.route_variant, start.stop_sequence, end.stop_sequence, time_slot.id)).or_insert_with(|| TravelTimes {
+                    start_stop_id: start.stop_id.to_string(),
+                    end_stop_id: end.stop_id.to_string(),
+                    scheduled: Vec::new(),
+                    observed: Vec::new(),
+                });
+                times.scheduled.push(arrival - departure);
+                times.observed.push(arrival + end_delay - departure - start_delay);
+            }
+        }
+
+        let route_name = schedule.get_route(route_id).map(|route| route.short_name.clone()).unwrap_or_default();
+        let stop_name = |stop_id: &str| schedule.get_stop(stop_id).map(|stop| stop.name.clone()).unwrap_or_default();
+        let mut rows = Vec::new();
+        for ((route_variant, start_stop_sequence, end_stop_sequence, time_slot_id), mut times) in travel_times.into_iter()
+            .sorted_by_key(|(key, _)| *key)
+        {
+            let sample_size = times.observed.len();
+            if sample_size == 0 || sample_size < min_samples {
+                continue;
+            }
+            let exceeded_count = times.observed.iter().zip(times.scheduled.iter()).filter(|(observed, scheduled)| observed > scheduled).count();
+            let exceeded_percentage = exceeded_count as f32 * 100.0 / sample_size as f32;
+            let scheduled_travel_time = median(&mut times.scheduled);
+            let observed_travel_time = median(&mut times.observed);
+            let excess_travel_time = observed_travel_time - scheduled_travel_time;
+            if excess_travel_time < min_excess || exceeded_percentage < min_percentage {
+                continue;
+            }
+            rows.push(ScheduleCheckRow {
+                route_id: String::from(route_id),
+                route_name: route_name.clone(),
+                route_variant,
+                time_slot: time_slot_id,
+                time_slot_description: TimeSlot::from_id(time_slot_id).map_or("", |ts| ts.description),
+                start_stop_sequence,
+                start_stop_name: stop_name(&times.start_stop_id),
+                start_stop_id: times.start_stop_id,
+                end_stop_sequence,
+                end_stop_name: stop_name(&times.end_stop_id),
+                end_stop_id: times.end_stop_id,
+                scheduled_travel_time,
+                observed_travel_time,
+                excess_travel_time,
+                exceeded_percentage,
+                sample_size,
+            });
+        }
+
+        info!("Route {}: {} records, {} stop pairs with infeasible travel times.", route_id, db_items.len(), rows.len());
+        Ok(rows)
+    }
+}
@@ -5,6 +5,7 @@ use mysql::*;
 use mysql::prelude::*;
 use simple_error::bail;
 use chrono::{DateTime, Local};
+use rayon::prelude::*;
 
 use dystonse_curves::irregular_dynamic::*;
 use dystonse_curves::{Curve, curve_set::CurveSet};
@@ -12,9 +13,11 @@ use dystonse_curves::tree::{SerdeFormat, NodeData};
 
 use super::Analyser;
 use super::curve_utils::*;
+use super::progress::Progress;
 use crate::types::*;
 
 use crate::{ FnResult, Main, OrError };
+use crate::time_util::date_and_time;
 
 use std::collections::HashMap;
 
@@ -27,35 +30,100 @@ pub struct SpecificCurveCreator<'a> {
 impl<'a> SpecificCurveCreator<'a> {
 
     pub fn get_specific_curves(&self) -> FnResult<HashMap<String, RouteData>> {
-        let mut map = HashMap::new();
-        if let Some(route_ids) = self.args.values_of("route-ids") {
-            println!("Handling {} route ids…", route_ids.len());
-            for route_id in route_ids {
-                let route_data = self.create_curves_for_route(&String::from(route_id))?;
-                map.insert(String::from(route_id), route_data);
-            }
+        let mut route_ids : Vec<String> = if let Some(route_ids) = self.args.values_of("route-ids") {
+            route_ids.map(String::from).collect()
         } else if self.args.is_present("all") {
-            let route_ids = self.analyser.schedule.routes.keys();
-            println!("Handling {} route ids…", route_ids.len());
-            for route_id in route_ids {
-                let route_data = self.create_curves_for_route(&String::from(route_id))?;
-                map.insert(String::from(route_id), route_data);
-            }
+            self.analyser.schedule.routes.keys().cloned().collect()
         } else {
-            println!("I've got no route!");
+            warn!("I've got no route!");
+            return Ok(HashMap::new());
+        };
+        self.analyser.retain_selected_routes(&mut route_ids);
+
+        // routes are independent of each other, so they can be computed in parallel.
+        // Each route gets its own database connection from the pool.
+        let mut thread_pool_builder = rayon::ThreadPoolBuilder::new();
+        if let Some(jobs) = self.args.value_of("jobs") {
+            let jobs : usize = jobs.parse()?;
+            if jobs < 1 {
+                bail!("Number of jobs must be at least 1.");
+            }
+            thread_pool_builder = thread_pool_builder.num_threads(jobs);
+        }
+        let thread_pool = thread_pool_builder.build()?;
+        info!("Handling {} route ids with {} parallel jobs…", route_ids.len(), thread_pool.current_num_threads());
+        let progress = Progress::new("specific curves", route_ids.len(), self.args.value_of("progress-json"))?;
+        let curve_parameters = self.analyser.get_curve_parameters();
+        let outlier_policy = OutlierPolicy::from_args(self.args, &self.main.feed_quirks)?;
+        info!("Removing outliers with {:?}.", outlier_policy);
+        let age_weighting = AgeWeighting::from_args(self.args)?;
+        if let Some(age_weighting) = &age_weighting {
+            info!("Weighting records with a half-life of {} days.", age_weighting.half_life_days);
+        }
+
+        // errors are converted to strings, because our error type can't be sent between threads
+        let route_data_vec : std::result::Result<Vec<(String, RouteData)>, String> = thread_pool.install(|| {
+            route_ids.par_iter().map(|route_id| {
+                let parameters = curve_parameters.get(route_id).cloned().unwrap_or_default();
+                match self.create_curves_for_route(route_id, &parameters, &outlier_policy, age_weighting.as_ref()) {
+                    Ok((route_data, record_count)) => {
+                        progress.item_done(&format!("route {}", route_id), record_count, true);
+                        Ok((route_id.clone(), route_data))
+                    },
+                    Err(e) => {
+                        progress.item_done(&format!("route {}", route_id), 0, false);
+                        Err(format!("Could not create curves for route {}: {}", route_id, e))
+                    },
+                }
+            }).collect()
+        });
+
+        match route_data_vec {
+            Ok(route_data_vec) => {
+                let map : HashMap<String, RouteData> = route_data_vec.into_iter().collect();
+                let variants = map.values().flat_map(|route_data| route_data.variants.values());
+                let (variant_count, curve_set_count) = variants.fold((0, 0), |(v, c), variant_data| {
+                    (v + 1, c + variant_data.curve_sets.arrival.len() + variant_data.curve_sets.departure.len())
+                });
+                progress.finish(&[
+                    format!("Computed curves for {} routes with {} route variants.", map.len(), variant_count),
+                    format!("Created {} curve sets.", curve_set_count),
+                ]);
+                Ok(map)
+            },
+            Err(e) => {
+                progress.finish(&[e.clone()]);
+                bail!(e)
+            },
         }
-        
-        Ok(map)
     }
 
     pub fn run_specific_curves(&self) -> FnResult<()> {
         let map = self.get_specific_curves()?;
         
         map.save_to_file(&self.analyser.main.dir, "specific_curves", &SerdeFormat::Json)?;
+        // saved so that it is known how the curve sets have been computed
+        let outlier_policy = OutlierPolicy::from_args(self.args, &self.main.feed_quirks)?;
+        outlier_policy.save_to_file(&self.analyser.main.dir, "specific_curves_outlier_policy", &SerdeFormat::Json)?;
+        if self.args.is_present("store-in-db") {
+            self.store_in_db(&map)?;
+        }
         Ok(())
     }
 
-    fn create_curves_for_route(&self, route_id: &String)  -> FnResult<RouteData> {
+    /// Writes the curves of each route into the database, replacing only the curves of those routes.
+    pub fn store_in_db(&self, map: &HashMap<String, RouteData>) -> FnResult<()> {
+        let store = CurveStore::new(self.main.pool.clone(), &self.main.source);
+        store.create_tables()?;
+        for route_data in map.values() {
+            store.save_route_data(route_data)?;
+        }
+        info!("Stored specific curves for {} routes in the database.", map.len());
+        Ok(())
+    }
+
+    // returns the curves and the number of records they are based on
+    fn create_curves_for_route(&self, route_id: &String, curve_parameters: &CurveParameters, outlier_policy: &OutlierPolicy, age_weighting: Option<&AgeWeighting>)  -> FnResult<(RouteData, usize)> {
         let schedule = &self.analyser.schedule;
         let route = schedule.get_route(route_id)?;
         let agencies_count = schedule.agencies.len();
@@ -73,13 +141,164 @@ impl<'a> SpecificCurveCreator<'a> {
             schedule.agencies[0].name.clone()
         };
 
-        println!("Working on route {} of agency {}.", route.short_name, agency_name);
+        info!("Working on route {} of agency {}.", route.short_name, agency_name);
 
         let mut route_data = RouteData::new(route_id);
+        let record_counts = self.get_record_counts(route_id)?;
+        debug!("For route {} there are {} variants: {:?}", route_id, record_counts.len(), record_counts.keys());
+
+        // each route variant needs a trip from which its stops are known
+        let mut variant_trips : Vec<(u64, &Trip)> = Vec::new();
+        // route variants of older schedules whose stops have been renamed, with the current route variant of the same stops
+        let mut renamed_variants : Vec<(u64, u64)> = Vec::new();
+        for route_variant in record_counts.keys() {
+            let variant_as_string = Some(format!("{}", route_variant));
+            let trip = schedule.trips.values().filter(|trip| trip.route_id == *route.id && trip.route_variant == variant_as_string).next();
 
+            match trip {
+                None => match self.find_trip_with_renamed_stops(route_id, *route_variant)? {
+                    Some((new_route_variant, trip)) => {
+                        info!("Using the records of route variant {} for route variant {}, whose stops have been renamed.", route_variant, new_route_variant);
+                        renamed_variants.push((*route_variant, new_route_variant));
+                        // route variants with records of their own are added in their own iteration
+                        if !record_counts.contains_key(&new_route_variant) && !variant_trips.iter().any(|(v, _)| *v == new_route_variant) {
+                            variant_trips.push((new_route_variant, trip));
+                        }
+                    },
+                    None => warn!("Could not find trip for route_variant {}.", route_variant),
+                },
+                Some(trip) => variant_trips.push((*route_variant, trip)),
+            }
+        }
+
+        let mut clusters = if self.args.is_present("merge-variants") {
+            let min_similarity : f32 = self.args.value_of("merge-similarity").unwrap().parse()?; // has a default value
+            if min_similarity <= 0.0 || min_similarity > 1.0 {
+                bail!("merge-similarity must be greater than 0 and at most 1.");
+            }
+            Self::cluster_route_variants(&variant_trips, &record_counts, min_similarity)
+        } else {
+            variant_trips.iter().map(|(route_variant, trip)| (*route_variant, *trip, vec![*route_variant])).collect()
+        };
+        for (old_route_variant, new_route_variant) in &renamed_variants {
+            if let Some((_, _, members)) = clusters.iter_mut().find(|(_, _, members)| members.contains(new_route_variant)) {
+                members.push(*old_route_variant);
+            }
+        }
+        // The records are read separately for each cluster of route variants, and only the records of one vehicle
+        // are kept at a time. They are reduced to the delays at each stop, which take much less memory, and from
+        // which the pairs for all curve sets of the cluster are built. Those are dropped before the next cluster.
+        let mut record_count = 0;
+        for (route_variant, trip, members) in clusters {
+            if members.len() > 1 {
+                info!("Pooling the records of route variants {:?} of route {} into the curves of route variant {}.", members, route_id, route_variant);
+            }
+            let mut vehicles : Vec<VehicleDelays> = Vec::new();
+            record_count += self.for_each_vehicle(route_id, &members, |rows| {
+                match self.get_vehicle_delays(rows, trip, age_weighting) {
+                    Ok(vehicle_delays) => vehicles.push(vehicle_delays),
+                    Err(e) => debug!("Skipping records of trip {}: {}", rows[0].trip_id, e),
+                }
+            })?;
+            let mut variant_data = self.create_curves_for_route_variant(&vehicles, trip, curve_parameters, outlier_policy)?;
+            variant_data.dwell_times = Self::create_dwell_time_curves(&vehicles, trip, curve_parameters, outlier_policy);
+            route_data.variants.insert(route_variant, variant_data);
+            for member in members.into_iter().filter(|member| *member != route_variant) {
+                route_data.merged_variants.insert(member, route_variant);
+            }
+        }
+
+        Ok((route_data, record_count))
+    }
+
+    /// Groups the route variants whose stop sequences are near-identical, i.e. whose longest common subsequence
+    /// of stops contains at least `min_similarity` of the stops of the longer one, so that their records can be
+    /// pooled. The curves of each group are computed for its route variant with the most records. Returns that
+    /// route variant, its trip and all route variants of the group (including itself).
+    fn cluster_route_variants<'t>(variant_trips: &[(u64, &'t Trip)], record_counts: &HashMap<u64, usize>, min_similarity: f32) -> Vec<(u64, &'t Trip, Vec<u64>)> {
+        let mut sorted_variant_trips = variant_trips.to_vec();
+        sorted_variant_trips.sort_by_key(|(route_variant, _)| (std::cmp::Reverse(record_counts.get(route_variant).copied().unwrap_or(0)), *route_variant));
+
+        let mut clusters : Vec<(u64, &'t Trip, Vec<u64>)> = Vec::new();
+        for (route_variant, trip) in sorted_variant_trips {
+            let stop_ids : Vec<&str> = trip.stop_times.iter().map(|st| st.stop.id.as_str()).collect();
+            let cluster = clusters.iter_mut().find(|(_, representative, _)| {
+                let representative_stop_ids : Vec<&str> = representative.stop_times.iter().map(|st| st.stop.id.as_str()).collect();
+                let common = align_stop_ids(&stop_ids, &representative_stop_ids).iter().filter(|index| index.is_some()).count();
+                common as f32 >= min_similarity * usize::max(stop_ids.len(), representative_stop_ids.len()) as f32
+            });
+            match cluster {
+                Some((_, _, members)) => members.push(route_variant),
+                None => clusters.push((route_variant, trip, vec![route_variant])),
+            }
+        }
+        clusters
+    }
+
+    /// For a route variant of an older schedule, finds the current route variant of the route which serves all of
+    /// its recorded stops in the same order, after renaming them with the `StopIdMapping`. Only route variants
+    /// with renamed stops are considered. If several route variants match, the one with the fewest stops is used.
+    fn find_trip_with_renamed_stops(&self, route_id: &str, route_variant: u64) -> FnResult<Option<(u64, &Trip)>> {
+        let mapping = &self.analyser.stop_id_mapping;
+        if mapping.is_empty() {
+            return Ok(None);
+        }
         let mut con = self.main.pool.get_conn()?;
-        let stmt = con.prep(
-            r"SELECT 
+        let recorded_stop_ids : Vec<String> = con.exec(
+            r"SELECT stop_id FROM records WHERE source=:source AND route_id=:route_id AND route_variant=:route_variant GROUP BY stop_sequence, stop_id ORDER BY stop_sequence",
+            params! {
+                "source" => &self.main.source,
+                route_id,
+                route_variant,
+            },
+        )?;
+        if !recorded_stop_ids.iter().any(|stop_id| mapping.map(stop_id) != stop_id) {
+            return Ok(None);
+        }
+        let stop_ids : Vec<&str> = recorded_stop_ids.iter().map(|stop_id| mapping.map(stop_id)).collect();
+
+        let candidate = self.analyser.schedule.trips.values()
+            .filter(|trip| trip.route_id == route_id)
+            .filter(|trip| {
+                let trip_stop_ids : Vec<&str> = trip.stop_times.iter().map(|st| st.stop.id.as_str()).collect();
+                align_stop_ids(&stop_ids, &trip_stop_ids).iter().all(|index| index.is_some())
+            })
+            .min_by_key(|trip| (trip.stop_times.len(), trip.route_variant.clone()));
+        Ok(match candidate {
+            Some(trip) => Some((trip.route_variant.as_ref().or_error("Trip has no route_variant")?.parse()?, trip)),
+            None => None,
+        })
+    }
+
+    // returns the number of records of each route variant of the route
+    fn get_record_counts(&self, route_id: &str) -> FnResult<HashMap<u64, usize>> {
+        let mut con = self.main.pool.get_conn()?;
+        let rows : Vec<(u64, u64)> = con.exec(
+            r"SELECT route_variant, COUNT(*) FROM records WHERE source=:source AND route_id=:routeid GROUP BY route_variant",
+            params! {
+                "source" => &self.main.source,
+                "routeid" => route_id
+            },
+        )?;
+        Ok(rows.into_iter().map(|(route_variant, count)| (route_variant, count as usize)).collect())
+    }
+
+    // the query for all records of the route (or only those of some of its route variants), ordered by vehicle and stop_sequence
+    fn get_records_query(&self, route_variants: Option<&[u64]>) -> String {
+        // the weather is only needed (and only recorded) if curves per weather condition shall be computed
+        let (weather_column, weather_join) = if self.args.is_present("weather-curves") {
+            ("w.weather_condition", "LEFT JOIN weather_observations w ON w.source = r.source AND w.hour = DATE_FORMAT(r.time_of_recording, '%Y-%m-%d %H:00:00')")
+        } else {
+            ("NULL", "")
+        };
+        // route variants are numbers, so they can be written into the query
+        let route_variant_condition = match route_variants {
+            Some(route_variants) => format!(" AND r.route_variant IN ({})", route_variants.iter().map(|route_variant| route_variant.to_string()).join(", ")),
+            None => String::new(),
+        };
+
+        format!(
+            r"SELECT
                 delay_arrival,
                 delay_departure,
                 trip_start_date,
@@ -87,17 +306,45 @@ impl<'a> SpecificCurveCreator<'a> {
                 trip_id,
                 stop_id,
                 stop_sequence,
-                route_variant
-            FROM 
-                records 
-            WHERE 
-                source=:source AND 
-                route_id=:routeid
-            ORDER BY 
+                route_variant,
+                {}
+            FROM
+                records r
+                {}
+            WHERE
+                r.source=:source AND
+                r.route_id=:routeid{}
+            ORDER BY
                 trip_start_date,
-                trip_id",
+                trip_start_time,
+                trip_id,
+                stop_sequence",
+            weather_column, weather_join, route_variant_condition
+        )
+    }
+
+    /// Returns all records of the route, sorted by trip, without the delays that can't be trusted (see `FeedQuirks`).
+    pub fn get_db_items(&self, route_id: &str) -> FnResult<Vec<DbItem>> {
+        let mut con = self.main.pool.get_conn()?;
+        let mut db_items: Vec<DbItem> = con.exec(
+            self.get_records_query(None),
+            params! {
+                "source" => &self.main.source,
+                "routeid" => route_id
+            },
         )?;
+        for item in &mut db_items {
+            self.main.feed_quirks.clean_item(item);
+        }
 
+        Ok(db_items)
+    }
+
+    /// Streams the records of the given route variants of the route from the database and calls `handle_vehicle`
+    /// with the records of each vehicle (i.e. each trip on each day), sorted by stop_sequence. Returns the number of records.
+    fn for_each_vehicle<F: FnMut(&[DbItem])>(&self, route_id: &str, route_variants: &[u64], mut handle_vehicle: F) -> FnResult<usize> {
+        let mut con = self.main.pool.get_conn()?;
+        let stmt = con.prep(self.get_records_query(Some(route_variants)))?;
         let mut result = con.exec_iter(
             &stmt,
             params! {
@@ -105,242 +352,159 @@ impl<'a> SpecificCurveCreator<'a> {
                 "routeid" => route_id
             },
         )?;
-
         let result_set = result.next_set().unwrap()?;
 
-        let db_items: Vec<_> = result_set
-            .map(|row| {
-                let item: DbItem = from_row(row.unwrap());
-                item
-            })
-            .collect();
-
-        let route_variants : Vec<_> = db_items.iter().map(|item| &item.route_variant).unique().collect();
-        println!("For route {} there are {} variants: {:?}", route_id, route_variants.len(), route_variants);
-
-        for route_variant in route_variants {
-            let variant_as_string = Some(format!("{}", route_variant));
-            let trip = schedule.trips.values().filter(|trip| trip.route_id == *route.id && trip.route_variant == variant_as_string).next();
-
-            match trip {
-                None => {
-                    println!("Could not find trip for route_variant {}.", route_variant);
-                },
-                Some(trip) => {
-                    let rows_matching_variant : Vec<_> = db_items.iter().filter(|item| item.route_variant == *route_variant).collect();
-
-                    println!("trying to compute projection of missing delays…");
-                    // try to do projections
-                    match self.compute_projections_for_route_variant(&rows_matching_variant) {
-                        Ok(rows_matching_variant_with_projection) => {
-                            println!("projection successful for route_variant {}.", route_variant);
-
-                            // convert vec into vec of references:
-                            let rows_matching_variant_with_projection_refs = rows_matching_variant_with_projection.iter().collect();
-
-                            let variant_data = self.create_curves_for_route_variant(&rows_matching_variant_with_projection_refs, trip)?;
-                            route_data.variants.insert(*route_variant, variant_data);
-                        },
-                        Err(e) => { // if making projections failed, proceed as usual
-                            println!("projection failed for route_variant {}. Now using only the data we already had before. Reason: {}", route_variant, e);
-                            let variant_data = self.create_curves_for_route_variant(&rows_matching_variant, trip)?;
-                            route_data.variants.insert(*route_variant, variant_data);
-                        }
-                    }
+        let mut record_count = 0;
+        let mut vehicle_rows : Vec<DbItem> = Vec::new();
+        for row in result_set {
+            let mut item: DbItem = from_row(row?);
+            self.main.feed_quirks.clean_item(&mut item);
+            record_count += 1;
+            if let Some(previous) = vehicle_rows.last() {
+                if previous.trip_start_date != item.trip_start_date || previous.trip_start_time != item.trip_start_time || previous.trip_id != item.trip_id {
+                    handle_vehicle(&vehicle_rows);
+                    vehicle_rows.clear();
                 }
             }
+            vehicle_rows.push(item);
         }
-
-        Ok(route_data)
+        if !vehicle_rows.is_empty() {
+            handle_vehicle(&vehicle_rows);
+        }
+        Ok(record_count)
     }
 
-    // project the delay at the previous stop onto each following stop where we have no data
-    fn compute_projections_for_route_variant(&self, rows_from_db: &Vec<&DbItem>) -> FnResult<Vec<DbItem>> {
+    /// Reduces the records of one vehicle to its delays at the stops of `trip`, which is the trip of the route
+    /// variant for which the curves are computed. If the vehicle belongs to another (merged) route variant,
+    /// its stops are matched with those of `trip` by their longest common subsequence.
+    ///
+    /// Stops without data get the delays of the previous stop for which there is data, see `project_missing_delays`.
+    ///
+    /// Vehicles of older schedules, whose trips don't exist anymore, are matched with `trip` by their stop IDs
+    /// as given by the `StopIdMapping`, with the scheduled times of `trip` shifted to their start time.
+    ///
+    /// With `age_weighting`, the delays get a weight according to the start date of the vehicle's trip.
+    fn get_vehicle_delays(&self, rows: &[DbItem], trip: &Trip, age_weighting: Option<&AgeWeighting>) -> FnResult<VehicleDelays> {
+        let mapping = &self.analyser.stop_id_mapping;
+        let (own_trip, time_offset, by_stop_id) = match self.analyser.schedule.get_trip(&rows[0].trip_id) {
+            Ok(own_trip) => (own_trip, 0, false),
+            Err(_) if !mapping.is_empty() => {
+                let start_time = rows[0].trip_start_time.or_error("No trip_start_time found in DbItem, this should not happen!")?;
+                let first_departure = trip.stop_times[0].departure_time.or_error("Trip has no departure time")?;
+                (trip, start_time.num_seconds() as i32 - first_departure as i32, true)
+            },
+            Err(e) => return Err(e.into()),
+        };
+        let start_date = rows[0].trip_start_date.or_error("No trip_start_date found in DbItem, this should not happen!")?;
+        let own_stop_ids : Vec<&str> = own_trip.stop_times.iter().map(|st| st.stop.id.as_str()).collect();
+        let stop_ids : Vec<&str> = trip.stop_times.iter().map(|st| st.stop.id.as_str()).collect();
+        let alignment : Vec<Option<usize>> = if own_stop_ids == stop_ids {
+            (0..stop_ids.len()).map(Some).collect()
+        } else {
+            align_stop_ids(&own_stop_ids, &stop_ids)
+        };
 
-        let route_variant = rows_from_db[0].route_variant;
-        
-        let mut resulting_rows : Vec<DbItem> = Vec::new();
-
-        // first step: sort the items by vehicle id
-        let mut rows_by_vehicle : HashMap<VehicleIdentifier, Vec<&DbItem>> = HashMap::new();
-
-        for item in rows_from_db {
-            let trip_id = item.trip_id.clone();
-            if let Some(start_date) = item.trip_start_date {
-                if let Some(start_time) = item.trip_start_time {
-                    let start = GtfsDateTime::new(start_date, start_time.num_seconds() as i32);
-                    let v_id = VehicleIdentifier{
-                        trip_id,
-                        start
-                    };
-                    // sort the item into the corresponding vec for its vehicle id
-                    let vec = rows_by_vehicle.entry(v_id).or_insert_with(|| Vec::new());
-                    vec.push(item);
-                } else {
-                    eprintln!("No trip_start_time found in DbItem, this should not happen!");
+        // the time slots of the scheduled events of the vehicle, at the stops that are part of both trips
+        let mut time_slots : Vec<Option<EventPair<u16>>> = vec![None; stop_ids.len()];
+        for (own_index, index) in alignment.iter().enumerate() {
+            if let Some(index) = index {
+                let stop_time = &own_trip.stop_times[own_index];
+                let mut slots = EventPair { arrival: 0, departure: 0 };
+                for et in &EventType::TYPES {
+                    if let Some(seconds) = stop_time.get_time(**et) {
+                        slots[**et] = time_slot_mask(date_and_time(&start_date, seconds as i32 + time_offset), &self.main.holidays);
+                    }
                 }
-            } else {
-                eprintln!("No trip_start_date found in DbItem, this should not happen!");
+                time_slots[*index] = Some(slots);
             }
         }
 
-        // second step: for each vehicle id, fill in the gaps
-
-        for (v_id, vec) in rows_by_vehicle {
-            
-            // find out which stops this trip is supposed to have
-            let stop_times = if let Ok(trip) = self.analyser.schedule.get_trip(&v_id.trip_id) {
-                &trip.stop_times
+        let mut stops : Vec<Option<StopDelays>> = vec![None; stop_ids.len()];
+        for item in rows {
+            let own_position = if by_stop_id {
+                let stop_id = mapping.map(&item.stop_id);
+                own_trip.stop_times.iter().position(|st| st.stop.id == stop_id)
             } else {
-                //TODO: maybe use eprintln and continue instead of bail?
-                bail!("no stop times found in schedule for trip {}", v_id.trip_id);
+                own_trip.stop_times.iter().position(|st| st.stop_sequence == item.stop_sequence)
             };
-
-            let items_iter = vec.iter();
-            let mut st_iter = stop_times.iter();
-
-            let mut delay_found = false;
-
-            'item_loop: for item in items_iter {
-
-                // remember the delays:
-                let delay_arr = item.delay.arrival;
-                let delay_dep = item.delay.departure;
-
-                'stop_time_loop: loop {
-
-                    if let Some(st) = st_iter.next() {
-
-                        if item.stop_sequence == st.stop_sequence {
-
-                            resulting_rows.push((**item).clone());
-
-                            delay_found = true;
-
-                            
-                        } else if item.stop_sequence > st.stop_sequence {
-
-                            if delay_found {
-                                eprintln!("ERROR: stop_sequence of dbitem is bigger than stop_sequence from schedule. This should not happen after delay was found once!");
-                            } 
-                            continue 'stop_time_loop;
-
-                        } else if item.stop_sequence < st.stop_sequence {
-
-                            // we have found a gap and have to fill it in now:
-
-                            //TODO: if delay_arrival was None before, we should probably use delay_departure for projecting the next arrival
-                            let new_item = DbItem{
-                                delay : EventPair { arrival: delay_arr, departure: delay_dep },
-                                trip_start_date : Some(v_id.start.service_day()),
-                                trip_start_time : Some(v_id.start.duration()),
-                                trip_id : v_id.trip_id.clone(),
-                                stop_sequence : st.stop_sequence,
-                                stop_id : st.stop.id.clone(),
-                                route_variant : route_variant
-                            };
-
-                            resulting_rows.push(new_item);
-                        }
-                    } else {
-                        break 'item_loop;
-                    }
-                }
+            let own_index = match own_position {
+                Some(own_index) => own_index,
+                None => continue,
+            };
+            if let Some(index) = alignment[own_index] {
+                stops[index] = Some(StopDelays {
+                    delay: item.delay.clone(),
+                    time_slots: time_slots[index].clone().unwrap(), // is set for all aligned stops
+                    weather: item.weather,
+                    projected: false,
+                });
             }
         }
-        Ok(resulting_rows)
+
+        project_missing_delays(&mut stops, &time_slots);
+        let weight = age_weighting.map_or(1.0, |age_weighting| age_weighting.get_weight(Some(start_date)));
+        Ok(VehicleDelays { stops, weight })
     }
 
     fn create_curves_for_route_variant(
-        &self, 
-        rows_matching_variant: &Vec<&DbItem>, 
-        trip: &Trip
+        &self,
+        vehicles: &[VehicleDelays],
+        trip: &Trip,
+        curve_parameters: &CurveParameters,
+        outlier_policy: &OutlierPolicy,
     ) -> FnResult<RouteVariantData> {
         let mut route_variant_data = RouteVariantData::new();
         route_variant_data.stop_ids = trip.stop_times.iter().map(|st| st.stop.id.clone()).collect();
 
-        // threshold of delay (in seconds) that will be considered. 
-        // Every stop with more than t or less then -t delay will be ignored.
-        let t = 3000; 
-        
+        let mut weather_conditions = vec![WeatherCondition::Unknown];
+        if self.args.is_present("weather-curves") {
+            weather_conditions.extend_from_slice(&WeatherCondition::KNOWN_CONDITIONS);
+        }
+
         for et in &EventType::TYPES {
-            let item_times: Vec<(&DbItem, DateTime<Local>)> = rows_matching_variant.iter().filter_map(|item| { 
-                if let Some(datetime) = item.get_datetime_from_trip(trip, **et) {
-                    Some((*item, datetime))
-                } else {
-                    None
-                }
-            }).collect();
-            for ts in &TimeSlot::TIME_SLOTS_WITH_DEFAULT {
-           
-                let rows_matching_time_slot : Vec<&DbItem> = item_times.iter().filter_map(|(item, datetime)| if ts.matches(*datetime) { Some(*item)} else {None} ).collect();
-
-                // Iterate over all start stations
-                for (i_s, st_s) in trip.stop_times.iter().enumerate() {
-                    // Locally select the rows which match the start station
-                    let rows_matching_start : Vec<&DbItem> = rows_matching_time_slot.iter().filter(|item| item.stop_id == st_s.stop.id).map(|i| *i).collect();
-
-                    // this is where the general_delay curves are created
-                    if let Ok(res) = self.generate_delay_curve_data(&rows_matching_start, **et) {
-                        route_variant_data.general_delay[**et].insert(i_s as u32, res);
-                    }
-                     
-                    // Iterate over end stations, and only use the ones after the start station
-                    for (i_e, st_e) in trip.stop_times.iter().enumerate() {
-                        if i_e > i_s {
-                            // Locally select rows that are matching the end station
-                            let rows_matching_end : Vec<_> = rows_matching_time_slot.iter().filter(|item| item.stop_id == st_e.stop.id).collect();
-                            
-                            // now rows_matching_start and rows_matching_end are disjunctive sets which can be joined by their vehicle
-                            // which is given by (date, trip_id).
-                            // TODO: also match start_time? 
-                            // TODO: use VehicleIdentifier from PerScheduleImporter (should be moved to types)
-
-                            let vec_size = usize::min(rows_matching_start.len(), rows_matching_end.len());
-
-                            let mut matching_pairs : EventPair<Vec<(f32, f32)>> = EventPair{
-                                arrival: Vec::<(f32, f32)>::with_capacity(vec_size), 
-                                departure: Vec::<(f32, f32)>::with_capacity(vec_size)
-                            };
-                            for row_s in &rows_matching_start {
-                                for row_e in &rows_matching_end {
-                                    if row_s.trip_start_date == row_e.trip_start_date && 
-                                    row_s.trip_start_time == row_e.trip_start_time && 
-                                            row_s.trip_id == row_e.trip_id {
-                                        // Only use rows where delay is not None
-                                        // TODO filter those out at the DB level or in the above filter expressions
-                                        if let Some(d_s) = row_s.delay.departure {
-                                            if let Some(d_e) = row_e.delay[**et] {
-                                                // Filter out rows with too much positive or negative delay
-                                                if d_s < t && d_s > -t && d_e < t && d_e > -t {
-                                                    // Now we round the delays to multiples of 12. Much of the data that we get from the agencies
-                                                    // tends to be rounded that way, and mixing up rounded and non-rounded data leads to all
-                                                    // kinds of problems.
-                                                    let rounded_d_s = (d_s / 12) * 12;
-                                                    let rounded_d_e = (d_e / 12) * 12;
-                                                    matching_pairs[**et].push((rounded_d_s as f32, rounded_d_e as f32));
-                                                }
-                                            }
-                                        }
-                                        break;
-                                    }
-                                }
+            for (ts_index, ts) in TimeSlot::TIME_SLOTS_WITH_DEFAULT.iter().enumerate() {
+                // Unknown stands for curve sets that use all rows, regardless of the weather
+                for weather in &weather_conditions {
+                    let is_selected = |stop_delays: &StopDelays| {
+                        stop_delays.time_slots[**et] & (1 << ts_index) != 0 && (*weather == WeatherCondition::Unknown || stop_delays.weather == *weather)
+                    };
+
+                    // Iterate over all start stations
+                    for i_s in 0..trip.stop_times.len() {
+                        // this is where the general_delay curves are created, which don't depend on the weather or time slot
+                        if *weather == WeatherCondition::Unknown && **ts == TimeSlot::DEFAULT {
+                            let values : Vec<(f32, f32)> = vehicles.iter()
+                                .filter_map(|vehicle| Some((vehicle.stops[i_s].as_ref()?, vehicle.weight)))
+                                .filter(|(stop_delays, _)| is_selected(stop_delays))
+                                .filter_map(|(stop_delays, weight)| Some((stop_delays.delay[**et]? as f32, weight)))
+                                .collect();
+                            if let Ok(res) = self.generate_delay_curve_data(&values) {
+                                route_variant_data.general_delay[**et].insert(i_s as u32, res);
                             }
+                        }
+
+                        // Iterate over end stations, and only use the ones after the start station
+                        for i_e in (i_s + 1)..trip.stop_times.len() {
+                            let delay_pairs : Vec<((i32, i32), f32)> = vehicles.iter().filter_map(|vehicle| {
+                                match (&vehicle.stops[i_s], &vehicle.stops[i_e]) {
+                                    (Some(start), Some(end)) if is_selected(start) && is_selected(end) => Some(((start.delay.departure?, end.delay[**et]?), vehicle.weight)),
+                                    _ => None,
+                                }
+                            }).collect();
+                            let matching_pairs = outlier_policy.apply_weighted(&delay_pairs);
                             // For the start station i_s and the end station i_e we now have a collection of matching
                             // pairs of observations, i.e. each pair means:
                             // "The vehicle which had p.0 delay at i_s arrived with p.1 delay at i_e."
 
-                            // println!("Stop #{} and #{} have {} and {} rows each, with {} matching", i_s, i_e, rows_matching_start.len(), rows_matching_end.len(), matching_pairs.len());
-                            
-                            
                             // Don't generate statistics if we have too few pairs.
-                            if matching_pairs[**et].len() > 20 {
-                                let stop_pair_data = self.generate_curves_for_stop_pair(&matching_pairs[**et]);
+                            if matching_pairs.len() > 20 {
+                                let stop_pair_data = Self::generate_weighted_curves_for_stop_pair(&matching_pairs, curve_parameters);
                                 if let Ok(actual_data) = stop_pair_data {
                                     let key = CurveSetKey {
-                                        start_stop_index: i_s as u32, 
-                                        end_stop_index: i_e as u32, 
-                                        time_slot: (**ts).clone()
+                                        start_stop_index: i_s as u32,
+                                        end_stop_index: i_e as u32,
+                                        time_slot: (**ts).clone(),
+                                        weather: *weather,
                                     };
                                     route_variant_data.curve_sets[**et].insert(key, actual_data);
                                 }
@@ -353,31 +517,90 @@ impl<'a> SpecificCurveCreator<'a> {
         Ok(route_variant_data)
     }
 
-    fn generate_delay_curve_data(&self, items: &Vec<&DbItem>, event_type: EventType) -> FnResult<CurveData> {
-        let values: Vec<f32> = items.iter().filter_map(|r| r.delay[event_type]).map(|t| t as f32).collect();
+    /// Computes a curve set for each stop (except the first and last one) and time slot, which describes
+    /// the departure delay depending on the arrival delay at the same stop. At stops where vehicles wait for
+    /// their scheduled departure, an early arrival does not lead to an early departure.
+    fn create_dwell_time_curves(vehicles: &[VehicleDelays], trip: &Trip, curve_parameters: &CurveParameters, outlier_policy: &OutlierPolicy) -> HashMap<DwellTimeKey, CurveSetData> {
+        let mut dwell_times = HashMap::new();
+        if trip.stop_times.len() < 3 {
+            return dwell_times;
+        }
+        for (ts_index, ts) in TimeSlot::TIME_SLOTS_WITH_DEFAULT.iter().enumerate() {
+            for i in 1..(trip.stop_times.len() - 1) {
+                let delay_pairs : Vec<((i32, i32), f32)> = vehicles.iter()
+                    .filter_map(|vehicle| Some((vehicle.stops[i].as_ref()?, vehicle.weight)))
+                    // projected delays are those of an earlier stop for both events, so they would fake a dwell time
+                    .filter(|(stop_delays, _)| !stop_delays.projected && stop_delays.time_slots.arrival & (1 << ts_index) != 0)
+                    .filter_map(|(stop_delays, weight)| Some(((stop_delays.delay.arrival?, stop_delays.delay.departure?), weight)))
+                    .collect();
+                let pairs = outlier_policy.apply_weighted(&delay_pairs);
+                // Don't generate statistics if we have too few pairs, same as for the curve sets between two stops.
+                if pairs.len() > 20 {
+                    if let Ok(curve_set_data) = Self::generate_weighted_curves_for_stop_pair(&pairs, curve_parameters) {
+                        dwell_times.insert(DwellTimeKey { stop_index: i as u32, time_slot: (**ts).clone() }, curve_set_data);
+                    }
+                }
+            }
+        }
+        dwell_times
+    }
 
+    // values are pairs of delay and weight
+    fn generate_delay_curve_data(&self, values: &[(f32, f32)]) -> FnResult<CurveData> {
         if values.len() < 20 {
             bail!("Less than 20 data rows.");
         }
-        let mut curve = make_curve(&values, None)?.0;
+        let mut curve = make_weighted_curve(values, None)?.0;
         curve.simplify(0.01);
         Ok(CurveData {
             curve,
             precision_type: PrecisionType::SemiSpecific,
-            sample_size: values.len() as u32
+            sample_size: values.len() as u32,
+            effective_sample_size: effective_sample_size(values.iter().map(|(_, weight)| *weight)),
         })
     }
 
-    fn generate_curves_for_stop_pair(&self, pairs: &Vec<(f32, f32)>) -> FnResult<CurveSetData> {
+    /// Joins the records of the start and end stop by their vehicle and returns pairs of the departure delay
+    /// at the start stop and the delay of the event at the end stop, without the outliers of `outlier_policy`.
+    pub fn get_matching_pairs(rows_matching_start: &[&DbItem], rows_matching_end: &[&DbItem], event_type: EventType, outlier_policy: &OutlierPolicy) -> Vec<(f32, f32)> {
+        // now rows_matching_start and rows_matching_end are disjunctive sets which can be joined by their vehicle
+        // which is given by (date, trip_id).
+        // TODO: use VehicleIdentifier from PerScheduleImporter (should be moved to types)
+        let mut delay_pairs = Vec::<(i32, i32)>::with_capacity(usize::min(rows_matching_start.len(), rows_matching_end.len()));
+        for row_s in rows_matching_start {
+            for row_e in rows_matching_end {
+                if row_s.trip_start_date == row_e.trip_start_date &&
+                row_s.trip_start_time == row_e.trip_start_time &&
+                        row_s.trip_id == row_e.trip_id {
+                    // Only use rows where delay is not None
+                    // TODO filter those out at the DB level or in the above filter expressions
+                    if let (Some(d_s), Some(d_e)) = (row_s.delay.departure, row_e.delay[event_type]) {
+                        delay_pairs.push((d_s, d_e));
+                    }
+                    break;
+                }
+            }
+        }
+        outlier_policy.apply(&delay_pairs)
+    }
+
+    pub fn generate_curves_for_stop_pair(pairs: &Vec<(f32, f32)>, curve_parameters: &CurveParameters) -> FnResult<CurveSetData> {
+        let weighted_pairs: Vec<((f32, f32), f32)> = pairs.iter().map(|pair| (*pair, 1.0)).collect();
+        Self::generate_weighted_curves_for_stop_pair(&weighted_pairs, curve_parameters)
+    }
+
+    /// Like `generate_curves_for_stop_pair`, but each pair comes with a weight (see `AgeWeighting`). The markers
+    /// only depend on the number of pairs, the weights are used for the curves between them.
+    pub fn generate_weighted_curves_for_stop_pair(pairs: &[((f32, f32), f32)], curve_parameters: &CurveParameters) -> FnResult<CurveSetData> {
         // Clone the pairs so that we may sort them. We sort them by delay at the start station
         // because we will group them by that criterion.
-        let mut own_pairs = pairs.clone();
-        own_pairs.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        let mut own_pairs = pairs.to_vec();
+        own_pairs.sort_by(|a, b| (a.0).0.partial_cmp(&(b.0).0).unwrap());
         let count = own_pairs.len();
 
         // Try to make a curve out of initial delays. This curve is different from the actual
         // output curve(s), but is needed as a intermediate result to compute the markers.
-        let (initial_curve, _sum) = make_curve(&own_pairs.iter().map(|(s,_e)| *s).collect(), None).or_error("Could not make curve.")?;
+        let (initial_curve, _sum) = make_curve(&own_pairs.iter().map(|((s, _e), _w)| *s).collect(), None).or_error("Could not make curve.")?;
         // We build a list of "markers", which are x-coordinates / initial delays for which we 
         // will build a curve. That curve will consist of rows with "similar" delays.
         // All the "middle" will be inserted in-order by the recurse function. 
@@ -386,11 +609,14 @@ impl<'a> SpecificCurveCreator<'a> {
         let mut markers = Vec::<f32>::new();
         markers.push(initial_curve.min_x());
         markers.push(initial_curve.min_x());
-        recurse(&initial_curve, &mut markers, initial_curve.min_x(), initial_curve.max_x(), count as f32);
+        recurse(&initial_curve, &mut markers, initial_curve.min_x(), initial_curve.max_x(), count as f32, curve_parameters.min_points_per_marker);
         markers.push(initial_curve.max_x());
         markers.push(initial_curve.max_x());
         
         let mut sample_size: u32 = 0;
+        // the effective sample size is only set if any of the pairs has less than the full weight
+        let mut effective_sample_size_sum: f32 = 0.0;
+        let mut is_weighted = false;
         let mut curve_set = CurveSet::<f32, IrregularDynamicCurve<f32, f32>>::new();
         // Now generate and draw one or more actual result curves.
         // Each curve will focus on the mid marker, and include all the data points from
@@ -399,11 +625,18 @@ impl<'a> SpecificCurveCreator<'a> {
         for (lower, mid, upper) in markers.iter().tuple_windows() {
             let min_index = (count as f32 * initial_curve.y_at_x(*lower)) as usize;
             let max_index = (count as f32 * initial_curve.y_at_x(*upper)) as usize;
-            let slice : Vec<f32> = own_pairs[min_index .. max_index].iter().map(|(_s,e)| *e).collect();
+            let slice : Vec<(f32, f32)> = own_pairs[min_index .. max_index].iter().map(|((_s, e), w)| (*e, *w)).collect();
             sample_size += slice.len() as u32;
+            match effective_sample_size(slice.iter().map(|(_e, w)| *w)) {
+                Some(slice_effective_sample_size) => {
+                    effective_sample_size_sum += slice_effective_sample_size;
+                    is_weighted = true;
+                },
+                None => effective_sample_size_sum += slice.len() as f32,
+            }
             if slice.len() > 1 {
-                if let Ok((mut curve, _sum)) = make_curve(&slice,  Some(*mid)) {
-                    curve.simplify(0.001);
+                if let Ok((mut curve, _sum)) = make_weighted_curve(&slice,  Some(*mid)) {
+                    curve.simplify(curve_parameters.simplification_tolerance);
                     if curve.max_x() <  curve.min_x() + 13.0 {
                         continue;
                     }
@@ -417,11 +650,112 @@ impl<'a> SpecificCurveCreator<'a> {
             bail!("Curve set would consist of 0 curves.");
         }
 
-        sample_size /= curve_set.curves.len() as u32;
+        let curve_count = curve_set.curves.len();
+        sample_size /= curve_count as u32;
         return Ok(CurveSetData {
             curve_set,
             sample_size, //average amount of samples per curve
-            precision_type: PrecisionType::Specific
+            precision_type: PrecisionType::Specific,
+            effective_sample_size: if is_weighted { Some(effective_sample_size_sum / curve_count as f32) } else { None },
         });
     }
-}
\ No newline at end of file
+}
+/// Returns a bit mask in which bit i is set if the time matches `TimeSlot::TIME_SLOTS_WITH_DEFAULT[i]`.
+fn time_slot_mask(time: DateTime<Local>, holidays: &HolidayCalendar) -> u16 {
+    TimeSlot::TIME_SLOTS_WITH_DEFAULT.iter().enumerate()
+        .filter(|(_, ts)| ts.matches(time, holidays))
+        .fold(0, |mask, (index, _)| mask | (1 << index))
+}
+
+// the delays of one vehicle at one stop
+#[derive(Clone)]
+struct StopDelays {
+    delay: EventPair<Option<i32>>,
+    /// time slots of the scheduled events, see `time_slot_mask`
+    time_slots: EventPair<u16>,
+    weather: WeatherCondition,
+    /// whether the delays have been copied from an earlier stop
+    projected: bool,
+}
+
+// Projects the delays at each stop with data onto the following stops without data, for which the vehicle most
+// likely has kept its delay. Stops before the first stop with data, and stops that the vehicle's own trip doesn't
+// serve (i.e. that have no time slots) get no delays.
+fn project_missing_delays(stops: &mut [Option<StopDelays>], time_slots: &[Option<EventPair<u16>>]) {
+    let mut previous : Option<StopDelays> = None;
+    for (stop, slots) in stops.iter_mut().zip(time_slots) {
+        if let Some(stop_delays) = stop {
+            previous = Some(stop_delays.clone());
+        } else if let (Some(previous), Some(slots)) = (&previous, slots) {
+            *stop = Some(StopDelays {
+                time_slots: slots.clone(),
+                projected: true,
+                ..previous.clone()
+            });
+        }
+    }
+}
+
+// the delays of one vehicle at each stop of the trip for which the curves are computed
+#[derive(Clone)]
+struct VehicleDelays {
+    stops: Vec<Option<StopDelays>>,
+    /// weight of all delays of the vehicle, see `AgeWeighting`
+    weight: f32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop_delays(arrival: i32, departure: i32) -> Option<StopDelays> {
+        Some(StopDelays {
+            delay: EventPair { arrival: Some(arrival), departure: Some(departure) },
+            time_slots: EventPair { arrival: 1, departure: 1 },
+            weather: WeatherCondition::Unknown,
+            projected: false,
+        })
+    }
+
+    fn delays(stops: &[Option<StopDelays>]) -> Vec<Option<(i32, bool)>> {
+        stops.iter().map(|stop| stop.as_ref().map(|stop| (stop.delay.departure.unwrap(), stop.projected))).collect()
+    }
+
+    #[test]
+    fn test_project_missing_delays() {
+        let slots = Some(EventPair { arrival: 2, departure: 2 });
+        // the vehicle's own trip doesn't serve the fifth stop
+        let time_slots = vec![slots.clone(), slots.clone(), slots.clone(), slots.clone(), None, slots.clone()];
+        let mut stops = vec![None, stop_delays(50, 60), None, stop_delays(110, 120), None, None];
+        project_missing_delays(&mut stops, &time_slots);
+        // no data before the first record, then the delays of the previous record
+        assert_eq!(delays(&stops), vec![None, Some((60, false)), Some((60, true)), Some((120, false)), None, Some((120, true))]);
+        let projected = stops[2].as_ref().unwrap();
+        assert_eq!(projected.delay.arrival, Some(50));
+        assert_eq!(projected.time_slots.arrival, 2);
+    }
+
+    #[test]
+    fn test_create_dwell_time_curves() {
+        let trip = Trip { stop_times: vec![Default::default(); 3], ..Default::default() };
+        // the vehicles wait at the middle stop if they arrive early, and all delays are in the first and the default time slot
+        let default_slot = 1 << (TimeSlot::TIME_SLOTS_WITH_DEFAULT.len() - 1);
+        let mut vehicles: Vec<VehicleDelays> = (0..40).map(|i| {
+            let arrival = i * 12 - 180;
+            let mut stop = stop_delays(arrival, i32::max(arrival, 0)).unwrap();
+            stop.time_slots.arrival = 1 | default_slot;
+            VehicleDelays { stops: vec![stop_delays(0, 0), Some(stop), stop_delays(0, 0)], weight: 1.0 }
+        }).collect();
+        let dwell_times = SpecificCurveCreator::create_dwell_time_curves(&vehicles, &trip, &CurveParameters::DEFAULT, &OutlierPolicy::DEFAULT);
+        let mut keys: Vec<(u32, u8)> = dwell_times.keys().map(|key| (key.stop_index, key.time_slot.id)).collect();
+        keys.sort();
+        assert_eq!(keys, vec![(1, TimeSlot::WORKDAY_MORNING.id), (1, TimeSlot::DEFAULT.id)]);
+        assert!(dwell_times.values().all(|curve_set_data| curve_set_data.sample_size > 20));
+
+        // projected delays don't count
+        for vehicle in &mut vehicles {
+            vehicle.stops[1].as_mut().unwrap().projected = true;
+        }
+        assert!(SpecificCurveCreator::create_dwell_time_curves(&vehicles, &trip, &CurveParameters::DEFAULT, &OutlierPolicy::DEFAULT).is_empty());
+    }
+}
@@ -0,0 +1,39 @@
+use clap::ArgMatches;
+use dystonse_curves::tree::{NodeData, SerdeFormat};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use crate::{FnResult, Main};
+use crate::types::{PortableStatistics, PortableFormat};
+
+/// Exports the delay statistics into one of the portable formats, or imports them from there,
+/// so that they can be used by other installations, which may run a different version of this crate.
+pub struct StatisticsExchanger<'a> {
+    pub main: &'a Main,
+    pub args: &'a ArgMatches,
+}
+
+impl<'a> StatisticsExchanger<'a> {
+    pub fn run_export(&self) -> FnResult<()> {
+        let format = PortableFormat::from_name(self.args.value_of("format").unwrap())?; // has a default value
+        let filename = self.args.value_of("file").unwrap(); // already validated by clap
+
+        let statistics = self.main.get_delay_statistics()?;
+        let portable = PortableStatistics::from_delay_statistics(&statistics);
+        info!("Exporting {} default curves and {} route variants to {}…", portable.default_curves.len(), portable.route_variants.len(), filename);
+        let mut writer = BufWriter::new(File::create(filename)?);
+        portable.write(&mut writer, format)?;
+        Ok(())
+    }
+
+    pub fn run_import(&self) -> FnResult<()> {
+        let filename = self.args.value_of("file").unwrap(); // already validated by clap
+
+        let portable = PortableStatistics::read(&mut BufReader::new(File::open(filename)?))?;
+        info!("Importing {} default curves and {} route variants, written by {}…", portable.default_curves.len(), portable.route_variants.len(), portable.created_by);
+        let statistics = portable.into_delay_statistics()?;
+        statistics.save_to_file(&self.main.dir, "all_curves", &SerdeFormat::MessagePack)?;
+        info!("Saved statistics to {}/all_curves.exp.", self.main.dir);
+        Ok(())
+    }
+}
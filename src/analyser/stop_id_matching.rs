@@ -0,0 +1,68 @@
+use clap::ArgMatches;
+use gtfs_structures::Gtfs;
+use simple_error::bail;
+use std::collections::HashSet;
+
+use super::Analyser;
+
+use crate::{FnResult, Loadable, Main, OrError, read_dir_recursive};
+use crate::types::{StopIdMapping, StopIdMatch};
+
+/// Finds the stop IDs that have been renamed between two schedules, and adds them to the `StopIdMapping`
+/// in dir, which is used by the curve creators to find the records of the old stop IDs.
+pub struct StopIdMatcher<'a> {
+    pub main: &'a Main,
+    pub analyser: &'a Analyser<'a>,
+    pub args: &'a ArgMatches,
+}
+
+impl<'a> StopIdMatcher<'a> {
+    pub fn run_match_stop_ids(&self) -> FnResult<()> {
+        let max_distance: f32 = self.args.value_of("max-distance").unwrap().parse()?; // has a default value
+        let old_schedule_filename = match self.args.value_of("old-schedule") {
+            Some(filename) => String::from(filename),
+            None => self.get_previous_schedule_filename()?,
+        };
+        info!("Loading the old schedule {}…", old_schedule_filename);
+        let old_schedule = Gtfs::load(&old_schedule_filename)?;
+        let new_schedule = &self.analyser.schedule;
+
+        let found = StopIdMapping::match_schedules(&old_schedule, new_schedule, max_distance);
+        let old_only_count = old_schedule.stops.keys().filter(|stop_id| !new_schedule.stops.contains_key(*stop_id)).count();
+        info!("Matched {} of the {} stop IDs which only exist in the old schedule.", found.matches.len(), old_only_count);
+
+        // earlier renames are kept, and follow the stops if they have been renamed again
+        let existing = StopIdMapping::load_from_dir(&self.main.dir)?;
+        let mut matches: Vec<StopIdMatch> = existing.matches.iter().map(|existing_match| StopIdMatch {
+            new_stop_id: found.map(&existing_match.new_stop_id).to_string(),
+            ..existing_match.clone()
+        }).collect();
+        let known_stop_ids: HashSet<String> = matches.iter().map(|m| m.old_stop_id.clone()).collect();
+        matches.extend(found.matches.into_iter().filter(|m| !known_stop_ids.contains(&m.old_stop_id)));
+        matches.sort_by(|a, b| a.old_stop_id.cmp(&b.old_stop_id));
+
+        if self.args.is_present("dry-run") {
+            for stop_id_match in &matches {
+                println!("{} -> {} ({}, {})", stop_id_match.old_stop_id, stop_id_match.new_stop_id, stop_id_match.stop_name,
+                    stop_id_match.distance.map_or(String::from("unknown distance"), |distance| format!("{:.0} m", distance)));
+            }
+            return Ok(());
+        }
+        let mapping = StopIdMapping::new(matches);
+        mapping.save_to_dir(&self.main.dir)?;
+        info!("Saved {} renamed stop IDs to {}.", mapping.matches.len(), StopIdMapping::FILE_NAME);
+        Ok(())
+    }
+
+    // the schedule file before the current one in the schedule subdirectory
+    fn get_previous_schedule_filename(&self) -> FnResult<String> {
+        let current = self.main.get_schedule_filename()?;
+        let schedule_filenames = read_dir_recursive(&format!("{}/schedule", self.main.dir))?;
+        let index = schedule_filenames.iter().position(|filename| *filename == current)
+            .or_error("The current schedule is not in the schedule directory, please provide --old-schedule.")?;
+        if index == 0 {
+            bail!("There is no schedule before the current one, please provide --old-schedule.");
+        }
+        Ok(schedule_filenames[index - 1].clone())
+    }
+}
@@ -1,30 +1,35 @@
 use clap::ArgMatches;
-use chrono::{Datelike, NaiveDate, Weekday};
+use chrono::{Datelike, Duration, Local, NaiveDate, TimeZone, Weekday};
 use gtfs_structures::{Gtfs, Trip};
 use itertools::Itertools;
 use mysql::*;
 use mysql::prelude::*;
+use plotters::coord::Shift;
 use plotters::palette::LinSrgba;
 use plotters::prelude::*;
 use plotters::style::text_anchor::*;
 use rand::Rng;
 use rayon::prelude::*;
+use simple_error::bail;
 
 use super::Analyser;
+use super::geographic_plot::GeographicPlotter;
 
 use crate::FnResult;
 use crate::Main;
+use crate::types::{DelayStatistics, EventType, HolidayCalendar, TimeSlot};
 
 use std::collections::HashSet;
 use std::fs;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-struct VsDbItem {
-    delay_arrival: Option<i32>,
-    delay_departure: Option<i32>,
-    date: Option<NaiveDate>,
-    trip_id: String,
-    stop_id: String
+pub(super) struct VsDbItem {
+    pub delay_arrival: Option<i32>,
+    pub delay_departure: Option<i32>,
+    pub date: Option<NaiveDate>,
+    pub trip_id: String,
+    pub stop_id: String
 }
 
 impl FromRow for VsDbItem {
@@ -40,23 +45,118 @@ impl FromRow for VsDbItem {
 }
 
 
+#[derive(Clone, Copy, PartialEq)]
+pub enum GraphFormat {
+    Png,
+    Svg,
+}
+
+impl GraphFormat {
+    pub const NAMES: [&'static str; 2] = ["png", "svg"];
+
+    pub fn from_args(args: &ArgMatches) -> Self {
+        match args.value_of("format") {
+            Some("svg") => GraphFormat::Svg,
+            _ => GraphFormat::Png,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            GraphFormat::Png => "png",
+            GraphFormat::Svg => "svg",
+        }
+    }
+}
+
+/// Shaded areas around each scheduled trip, between two percentiles of the delays at each stop
+/// as known from the delay statistics.
+pub struct PercentileBands {
+    pub statistics: Arc<DelayStatistics>,
+    /// lower and upper quantile, between 0 and 1
+    pub quantiles: (f32, f32),
+}
+
+impl PercentileBands {
+    /// Reads the percentiles from the `band-percentiles` argument. Returns None if the bands are disabled
+    /// with `no-bands`, or if there are no delay statistics.
+    pub fn from_args(main: &Main, args: &ArgMatches) -> FnResult<Option<Self>> {
+        if args.is_present("no-bands") {
+            return Ok(None);
+        }
+        let percentiles: Vec<&str> = match args.values_of("band-percentiles") {
+            Some(values) => values.collect(),
+            None => vec!["10", "90"],
+        };
+        let quantiles = Self::parse_quantiles(&percentiles)?;
+        match main.get_delay_statistics() {
+            Ok(statistics) => Ok(Some(PercentileBands { statistics, quantiles })),
+            Err(e) => {
+                warn!("Drawing the graphs without percentile bands, because the delay statistics could not be loaded: {}", e);
+                Ok(None)
+            },
+        }
+    }
+
+    // converts the two percentiles (between 0 and 100) to quantiles (between 0 and 1)
+    fn parse_quantiles(percentiles: &[&str]) -> FnResult<(f32, f32)> {
+        let percentiles: Vec<f32> = percentiles.iter().map(|value| value.trim().parse()).collect::<std::result::Result<_, _>>()?;
+        if percentiles.len() != 2 || !(0.0 < percentiles[0] && percentiles[0] < percentiles[1] && percentiles[1] < 100.0) {
+            bail!("Band percentiles must be two numbers between 0 and 100, the lower one first.");
+        }
+        Ok((percentiles[0] / 100.0, percentiles[1] / 100.0))
+    }
+
+    // The graph mixes the records of all days, so the bands are drawn for a regular workday, e.g. Wednesday,
+    // 2020-01-08, and each trip gets the time slot of its scheduled start on that day.
+    fn get_time_slot(trip: &Trip) -> &'static TimeSlot {
+        let start = trip.stop_times.first().and_then(|stop_time| stop_time.departure_time).unwrap_or(0);
+        let date_time = Local.ymd(2020, 1, 8).and_hms(0, 0, 0) + Duration::seconds(start as i64);
+        TimeSlot::from_datetime(date_time, &HolidayCalendar::default())
+    }
+
+    /// Returns the outline of the band of the trip, from the first to the last of its stops that are among
+    /// `relevant_stop_ids` along the lower percentile, and back along the upper one. Stops without curves are skipped.
+    fn get_outline(&self, schedule: &Gtfs, trip: &Trip, relevant_stop_ids: &[String]) -> Vec<(f64, f64)> {
+        let time_slot = Self::get_time_slot(trip);
+        let mut lower = Vec::new();
+        let mut upper = Vec::new();
+        for (stop_index, stop_time) in trip.stop_times.iter().enumerate() {
+            let (x, time) = match (relevant_stop_ids.iter().position(|id| *id == stop_time.stop.id), stop_time.arrival_time) {
+                (Some(x), Some(time)) => (x as f64, time as f32),
+                _ => continue,
+            };
+            let quantile = |q: f32| self.statistics.get_delay_quantile_in_time_slot(schedule, trip, stop_index, EventType::Arrival, time_slot, q).ok();
+            if let (Some(low), Some(high)) = (quantile(self.quantiles.0), quantile(self.quantiles.1)) {
+                lower.push((x, make_hours(time + low)));
+                upper.push((x, make_hours(time + high)));
+            }
+        }
+        upper.reverse();
+        lower.append(&mut upper);
+        lower
+    }
+}
+
 pub struct VisualScheduleCreator<'a> {
     pub main: &'a Main,
     pub analyser:&'a Analyser<'a>,
-    pub args: &'a ArgMatches
+    pub args: &'a ArgMatches,
+    pub format: GraphFormat,
+    pub bands: Option<PercentileBands>,
 }
 
 impl<'a> VisualScheduleCreator<'a> {
     pub fn run_visual_schedule(&mut self) -> FnResult<()> {
         let schedule = &self.analyser.schedule;
         if let Some(route_ids) = self.args.values_of("route-ids") {
-            println!("Handling {} route ids…", route_ids.len());
+            info!("Handling {} route ids…", route_ids.len());
             for route_id in route_ids {
                 self.create_visual_schedule_for_route(&String::from(route_id))?;
             }
         }
         if let Some(shape_ids) = self.args.values_of("shape-ids") {
-            println!("Handling {} shape ids…", shape_ids.len());
+            info!("Handling {} shape ids…", shape_ids.len());
             for shape_id in shape_ids {
                 self.create_visual_schedule_for_shapes(
                     &String::from(shape_id),
@@ -68,7 +168,7 @@ impl<'a> VisualScheduleCreator<'a> {
             }
         }
         if self.args.is_present("all") {
-            println!("Creating graphs for all routes. First, selecting route_ids for which we actually have data…");
+            info!("Creating graphs for all routes. First, selecting route_ids for which we actually have data…");
 
             let mut con = self.main.pool.get_conn()?;
 
@@ -81,7 +181,7 @@ impl<'a> VisualScheduleCreator<'a> {
                 })
                 .collect();
 
-            println!(
+            info!(
                 "Found data for {} of {} route_ids.",
                 route_ids.len(),
                 schedule.routes.len()
@@ -97,7 +197,7 @@ impl<'a> VisualScheduleCreator<'a> {
                     Ok(()) => {
                         let curr_suc = 1 + success_counter.fetch_add(1, Ordering::SeqCst);
                         let curr_err = error_counter.load(Ordering::SeqCst);
-                        println!(
+                        info!(
                             "Status: {} of {} ({} succeeded, {} errors)",
                             curr_suc + curr_err, total_count, curr_suc, curr_err
                         );
@@ -106,11 +206,11 @@ impl<'a> VisualScheduleCreator<'a> {
                     Err(e) => {
                         let curr_err = 1 + error_counter.fetch_add(1, Ordering::SeqCst);
                         let curr_suc = error_counter.load(Ordering::SeqCst);
-                        println!(
+                        info!(
                             "Status: {} of {} ({} succeeded, {} errors)",
                             curr_suc + curr_err, total_count, curr_suc, curr_err
                         );
-                        eprintln!("Error while processing route {}: {}", &id, e);
+                        error!("Error while processing route {}: {}", &id, e);
                         (1, 0)
                     }
                  })
@@ -118,7 +218,7 @@ impl<'a> VisualScheduleCreator<'a> {
                     || (0, 0), 
                     |a, b| (a.0 + b.0, a.1 + b.1)
                 );
-            println!(
+            info!(
                 "Tried to create graphs for {} routes, had success with {} of them.",
                 count, success
             );
@@ -166,7 +266,7 @@ impl<'a> VisualScheduleCreator<'a> {
             .collect();
 
         if db_items.len() < 10 {
-            println!(
+            info!(
                 "Skipping route id {} because there are only {} data points.",
                 route_id,
                 db_items.len()
@@ -213,7 +313,7 @@ impl<'a> VisualScheduleCreator<'a> {
         stop_ids_by_route_variant_id
             .sort_by_key(|(_route_variant_id, stop_ids)| -(stop_ids.len() as i32));
 
-        println!(
+        info!(
             "Handling {} route variant ids for route id {}…",
             route_variant_ids.len(),
             route_id
@@ -233,6 +333,21 @@ impl<'a> VisualScheduleCreator<'a> {
             .name
             .clone();
 
+        if self.args.is_present("geographic") {
+            let plotter = GeographicPlotter {
+                schedule,
+                db_items: &db_items,
+            };
+            let path = format!("data/img/agency_{}/route_{}", agency_name, route_name);
+            fs::create_dir_all(&path)?;
+            for route_variant_id in &route_variant_ids {
+                // routes without shapes.txt still get their visual schedules
+                if let Err(e) = plotter.draw_route_variant(route_variant_id, &format!("{}/variant_{}_map.svg", path, route_variant_id)) {
+                    warn!("Could not draw map of route variant {}: {}", route_variant_id, e);
+                }
+            }
+        }
+
         // now create the actual images, one for each variant
         loop {
             let (primary_route_variant_id, primary_stop_ids) =
@@ -305,7 +420,7 @@ impl<'a> VisualScheduleCreator<'a> {
             })
             .collect();
 
-        println!(
+        info!(
             "Filtered {} trips and fround {} trips with route_variant_id {}.",
             all_trips.len(),
             trips.len(),
@@ -316,9 +431,9 @@ impl<'a> VisualScheduleCreator<'a> {
         fs::create_dir_all(path)?;
 
         let filename = if route_variant_ids.len() > 1 {
-            format!("{}/variant_{}_and_{}_others.png", path, primary_route_variant_id, route_variant_ids.len() - 1)
+            format!("{}/variant_{}_and_{}_others.{}", path, primary_route_variant_id, route_variant_ids.len() - 1, self.format.extension())
         } else {
-            format!("{}/variant_{}.png", path, primary_route_variant_id)
+            format!("{}/variant_{}.{}", path, primary_route_variant_id, self.format.extension())
         };
 
         self.create_visual_schedule_for_trips(
@@ -350,7 +465,7 @@ impl<'a> VisualScheduleCreator<'a> {
             .values()
             .filter(|trip| shape_ids.contains(&trip.shape_id.as_ref().unwrap_or(&empty_string)))
             .collect();
-        println!(
+        info!(
             "Filtered {} trips and fround {} trips with shape_id {}.",
             all_trips.len(),
             trips.len(),
@@ -362,7 +477,7 @@ impl<'a> VisualScheduleCreator<'a> {
         self.create_visual_schedule_for_trips(
             primary_trip,
             trips,
-            &format!("{}/shape_{}.png", path, primary_shape_id),
+            &format!("{}/shape_{}.{}", path, primary_shape_id, self.format.extension()),
             db_items,
         )
     }
@@ -382,6 +497,8 @@ impl<'a> VisualScheduleCreator<'a> {
             schedule,
             self.main,
             db_items,
+            self.format,
+            self.bands.as_ref(),
         );
 
         creator.create()?;
@@ -395,10 +512,12 @@ struct GraphCreator<'a> {
     primary_trip: &'a Trip,
     trips: Vec<&'a Trip>,
     schedule: &'a Gtfs,
-    _main: &'a Main,
+    main: &'a Main,
     relevant_stop_ids: Vec<String>,
     relevant_stop_names: Vec<String>,
     db_items: &'a Vec<VsDbItem>,
+    format: GraphFormat,
+    bands: Option<&'a PercentileBands>,
 }
 
 impl<'a> GraphCreator<'a> {
@@ -409,21 +528,25 @@ impl<'a> GraphCreator<'a> {
         schedule: &'a Gtfs,
         main: &'a Main,
         db_items: &'a Vec<VsDbItem>,
+        format: GraphFormat,
+        bands: Option<&'a PercentileBands>,
     ) -> GraphCreator<'a> {
         GraphCreator {
             primary_trip,
             trips,
             name,
-            _main: main,
+            main,
             schedule,
             relevant_stop_ids: Vec::new(),
             relevant_stop_names: Vec::new(),
             db_items,
+            format,
+            bands,
         }
     }
 
     fn create(&mut self) -> FnResult<()> {
-        println!(
+        info!(
             "Creating visual schedule of {} trips with name '{}'.",
             self.trips.len(),
             self.name
@@ -450,7 +573,7 @@ impl<'a> GraphCreator<'a> {
             .collect();
 
         if data_for_current_trips.len() < 10 {
-            println!(
+            info!(
                 "Skipping some trips because there are only {} data points.",
                 data_for_current_trips.len()
             );
@@ -501,21 +624,28 @@ impl<'a> GraphCreator<'a> {
             }
         }
 
-        println!(
+        info!(
             "Found {} data points for those trips spread over {} dates.",
             data_for_current_trips.len(),
             date_count
         );
 
+        let size = (stop_count as u32 * 30 + 40, 4096);
+        match self.format {
+            GraphFormat::Png => self.draw(BitMapBackend::new(&self.name, size).into_drawing_area(), actual_trip_shapes),
+            GraphFormat::Svg => self.draw(SVGBackend::new(&self.name, size).into_drawing_area(), actual_trip_shapes),
+        }
+    }
+
+    fn draw<DB: DrawingBackend>(&self, mut root: DrawingArea<DB, Shift>, actual_trip_shapes: Vec<PathElement<(f64, f64)>>) -> FnResult<()>
+    where DB::ErrorType: 'static {
+        let stop_count = self.relevant_stop_ids.len();
         let rotated = TextStyle::from(("sans-serif", 20).into_font())
             .pos(Pos::new(HPos::Center, VPos::Center))
             .transform(FontTransform::Rotate270);
         let transparent = LinSrgba::new(0.0, 0.0, 0.0, 0.0);
         let invisible = ShapeStyle::from(&transparent);
 
-        let mut root =
-            BitMapBackend::new(&self.name, (stop_count as u32 * 30 + 40, 4096)).into_drawing_area();
-
         root.fill(&WHITE)?;
         root = root.margin(20, 200, 20, 20);
 
@@ -537,6 +667,12 @@ impl<'a> GraphCreator<'a> {
             .y_labels(45)
             .draw()?;
 
+        // DRAW PERCENTILE BANDS
+        if let Some(bands) = self.bands {
+            let band_color = LinSrgba::new(0.0, 0.2, 0.8, 0.15);
+            graphic_schedule.draw_series(self.trips.iter()
+                .filter_map(|trip| self.make_band_drawable(trip, bands, ShapeStyle::from(&band_color).filled())))?;
+        }
         // DRAW REALTIME DATA
         graphic_schedule.draw_series(actual_trip_shapes)?;
         // DRAW SCHEDULE DATA
@@ -557,6 +693,15 @@ impl<'a> GraphCreator<'a> {
         )
     }
 
+    // the area between the lower and the upper percentile of the arrival delays at each stop of the trip
+    fn make_band_drawable(&self, trip: &Trip, bands: &PercentileBands, style: ShapeStyle) -> Option<Polygon<(f64, f64)>> {
+        let outline = bands.get_outline(self.schedule, trip, &self.relevant_stop_ids);
+        if outline.len() < 4 {
+            return None;
+        }
+        Some(Polygon::new(outline, style))
+    }
+
     fn make_coordinate(&self, stop_id: &str, time: Option<u32>) -> Option<(f64, f64)> {
         if let Some(mut time) = time {
             if let Some(x) = self.relevant_stop_ids.iter().position(|id| *id == stop_id) {
@@ -576,8 +721,8 @@ impl<'a> GraphCreator<'a> {
             return None;
         }
 
-        // Some providers seem to set the delay to 0 instead of Null when they have no data.
-        if item.delay_arrival.unwrap() == 0 {
+        // some providers set the delay to 0 instead of Null when they have no data
+        if !self.main.feed_quirks.is_trustworthy(item.delay_arrival.unwrap() as i64) {
             return None;
         }
 
@@ -623,4 +768,67 @@ impl<'a> GraphCreator<'a> {
             Some((idx, _)) => &s[..idx],
         }
     }
-}
\ No newline at end of file
+}
+// like in make_coordinate, times until 3 am belong to the previous day
+fn make_hours(time: f32) -> f64 {
+    let time = if time < 3600.0 * 3.0 { time + 3600.0 * 24.0 } else { time };
+    time as f64 / 3600.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dystonse_curves::{IrregularDynamicCurve, Tup};
+    use gtfs_structures::{RouteType, StopTime};
+    use crate::test_util;
+    use crate::types::{CurveData, DefaultCurveKey, PrecisionType, RouteSection};
+
+    #[test]
+    fn test_parse_quantiles() {
+        assert_eq!(PercentileBands::parse_quantiles(&["10", "90"]).unwrap(), (0.1, 0.9));
+        assert_eq!(PercentileBands::parse_quantiles(&["25", " 75"]).unwrap(), (0.25, 0.75));
+        assert!(PercentileBands::parse_quantiles(&["90", "10"]).is_err());
+        assert!(PercentileBands::parse_quantiles(&["0", "90"]).is_err());
+        assert!(PercentileBands::parse_quantiles(&["10"]).is_err());
+        assert!(PercentileBands::parse_quantiles(&["10", "ninety"]).is_err());
+    }
+
+    #[test]
+    fn test_get_time_slot() {
+        let trip = |start: u32| Trip {
+            stop_times: vec![StopTime { departure_time: Some(start), ..Default::default() }],
+            ..Default::default()
+        };
+        assert_eq!(PercentileBands::get_time_slot(&trip(7 * 3600)), &TimeSlot::WORKDAY_MORNING_RUSH);
+        assert_eq!(PercentileBands::get_time_slot(&trip(13 * 3600 + 1800)), &TimeSlot::WORKDAY_NOON_RUSH);
+    }
+
+    #[test]
+    fn test_get_outline() {
+        // three stops, but only the first two are drawn
+        let trip = test_util::trip("t1", "r1", 8 * 3600, 360, &["a", "b", "c"]);
+        let schedule = test_util::schedule(vec![trip.clone()]);
+        let mut statistics = DelayStatistics::new();
+        for route_section in &[RouteSection::Beginning, RouteSection::Middle] {
+            statistics.general.all_default_curves.insert(DefaultCurveKey {
+                route_type: RouteType::Bus,
+                route_section: route_section.clone(),
+                time_slot: TimeSlot::DEFAULT,
+                event_type: EventType::Arrival,
+            }, CurveData {
+                curve: IrregularDynamicCurve::new(vec![Tup { x: 0.0, y: 0.0 }, Tup { x: 360.0, y: 1.0 }]),
+                precision_type: PrecisionType::General,
+                sample_size: 100,
+                effective_sample_size: None,
+            });
+        }
+        let bands = PercentileBands { statistics: Arc::new(statistics), quantiles: (0.1, 0.9) };
+        let outline = bands.get_outline(&schedule, &trip, &[String::from("a"), String::from("b")]);
+        let expected = [(0.0, 8.01), (1.0, 8.11), (1.0, 8.19), (0.0, 8.09)];
+        assert_eq!(outline.len(), expected.len());
+        for ((x, y), (expected_x, expected_y)) in outline.iter().zip(expected.iter()) {
+            assert_eq!(x, expected_x);
+            assert!((y - expected_y).abs() < 0.0001, "{} != {}", y, expected_y);
+        }
+    }
+}
@@ -1,108 +1,391 @@
+use clap::ArgMatches;
 use mysql::prelude::*;
 use mysql::*;
+use parse_duration::parse;
+use simple_error::bail;
 use crate::FnResult;
-use std::sync::Mutex;
-use std::thread;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-const MAX_BATCH_SIZE: usize = 1000;
+/// How BatchedStatements write to the database. Set with the `batch-size`, `max-retries`,
+/// `retry-backoff`, `max-in-flight` and `writer-threads` arguments of the import command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BatchSettings {
+    /// number of parameter sets that are written within one transaction
+    pub batch_size: usize,
+    /// how often a batch is written again after a deadlock before the import fails
+    pub max_retries: u32,
+    /// time to wait before the first retry, which doubles with each further retry
+    pub retry_backoff: Duration,
+    /// number of full batches that may wait for the writers at the same time. If there are
+    /// more, the threads that add parameter sets are blocked until one of them is taken.
+    pub max_in_flight: usize,
+    /// number of threads (each with its own database connection) that write the batches of one table
+    pub writer_threads: usize,
+}
+
+impl BatchSettings {
+    pub fn from_args(args: &ArgMatches) -> FnResult<Self> {
+        let settings = BatchSettings {
+            batch_size: args.value_of("batch-size").unwrap().parse()?, // has a default value
+            max_retries: args.value_of("max-retries").unwrap().parse()?, // has a default value
+            retry_backoff: parse(args.value_of("retry-backoff").unwrap())?, // has a default value
+            max_in_flight: args.value_of("max-in-flight").unwrap().parse()?, // has a default value
+            writer_threads: args.value_of("writer-threads").unwrap().parse()?, // has a default value
+        };
+        if settings.batch_size < 1 {
+            bail!("Batch size must be at least 1.");
+        }
+        if settings.max_in_flight < 1 {
+            bail!("Maximum number of batches in flight must be at least 1.");
+        }
+        if settings.writer_threads < 1 {
+            bail!("Number of writer threads must be at least 1.");
+        }
+        Ok(settings)
+    }
+}
+
+/// Parameter sets that are waiting to be written, the number of batches which have been
+/// taken out of the buffer but are not written yet, and the first error of the writers.
+struct BatchState {
+    params_vec: Vec<Params>,
+    in_flight: usize,
+    error: Option<String>,
+}
+
+// writes one batch on the connection of a writer thread
+type BatchWriter = Box<dyn FnMut(Vec<Params>) -> FnResult<()> + Send>;
+
+/// The part of BatchedStatements that is shared with its writer threads.
+struct SharedState {
+    name: String,
+    state_mutex: Mutex<BatchState>,
+    batch_written: Condvar,
+    settings: BatchSettings,
+}
 
 /// This struct lets you execute multiple SQL statements for multiple parameter sets
 /// wihtin a single transaction.
-/// 
+///
 /// When you create a BatchedStatements instance, you provide one or more statements.
 /// Then you call add_parameter_set several times. The struct will collect the parameters.
-/// Whenever there would be more collected parameter_sets than MAX_BATCH_SIZE,
-/// they will be written to the database within the call to add_parameter_set.
-/// 
+/// Whenever there would be more collected parameter_sets than the batch size,
+/// they will be handed to the writer threads within the call to add_parameter_set.
+///
 /// When finished, you have to call write_to_database to handle the leftover parameter_sets.
-/// 
+/// It returns when all batches have been written.
+///
 /// This struct is thread safe. Multiple threads can call add_parameter_set at once.
-/// The thread which reaches the MAX_BATCH_SIZE limit will be blocked until the data 
-/// is written, but other threads can continue to call add_parameter_set and will only
-/// block if they add another MAX_BATCH_SIZE before the first one is written.
+/// The batches are written by writer_threads dedicated threads, each with its own
+/// database connection, so the threads that add parameter sets don't wait for the
+/// database. Only if max_in_flight batches are already waiting for the writers, they
+/// are blocked, so that a slow database slows down the importer instead of letting the
+/// buffered parameter sets grow without limit.
+///
+/// Batches that fail because of a deadlock are retried up to max_retries times, waiting
+/// a bit longer before each retry.
+///
+/// In a dry run, the parameter sets are only counted and never written, so there are no writer
+/// threads and the statements are not prepared.
 pub struct BatchedStatements {
-    name: String,
-    params_vec_mutex: Mutex<Vec<Params>>,
-    conn_mutex: Mutex<PooledConn>,
-    statements: Vec<Statement>,
+    shared: Arc<SharedState>,
+    // None once the writers are being stopped
+    sender: Mutex<Option<SyncSender<Vec<Params>>>>,
+    writers: Vec<JoinHandle<()>>,
+    dry_run: bool,
+    parameter_set_count: AtomicUsize,
 }
 
 impl<'a> BatchedStatements {
-    pub fn new(name: &str, conn: PooledConn, statements: Vec<Statement>) -> Self {
-        BatchedStatements {
+    /// Starts the writer threads, which prepare the statements on their own connections.
+    pub fn new(name: &str, pool: &Pool, statements: &[&str], settings: BatchSettings, dry_run: bool) -> FnResult<Self> {
+        Self::with_writers(name, settings, dry_run, |shared| {
+            // prepared statements belong to the connection on which they have been prepared
+            let mut conn = pool.get_conn()?;
+            let prepared = statements.iter().map(|statement| conn.prep(*statement)).collect::<Result<Vec<Statement>>>()?;
+            let shared = shared.clone();
+            Ok(Box::new(move |params_vec| write_batch(&shared, &mut conn, &prepared, params_vec)))
+        })
+    }
+
+    // starts the writer threads, each with the writer that `create_writer` returns for it
+    fn with_writers(name: &str, settings: BatchSettings, dry_run: bool, mut create_writer: impl FnMut(&Arc<SharedState>) -> FnResult<BatchWriter>) -> FnResult<Self> {
+        let shared = Arc::new(SharedState {
             name: name.to_string(),
-            params_vec_mutex: Mutex::new(Vec::with_capacity(MAX_BATCH_SIZE)),
-            conn_mutex: Mutex::new(conn),
-            statements
+            state_mutex: Mutex::new(BatchState {
+                params_vec: Vec::with_capacity(settings.batch_size),
+                in_flight: 0,
+                error: None,
+            }),
+            batch_written: Condvar::new(),
+            settings,
+        });
+        let (sender, receiver) = sync_channel(settings.max_in_flight);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let writer_threads = if dry_run { 0 } else { settings.writer_threads };
+        let mut writers = Vec::with_capacity(writer_threads);
+        for i in 0..writer_threads {
+            let write = create_writer(&shared)?;
+            let shared = shared.clone();
+            let receiver = receiver.clone();
+            writers.push(thread::Builder::new()
+                .name(format!("{}-writer-{}", name, i))
+                .spawn(move || run_writer(&shared, &receiver, write))?);
         }
+        Ok(BatchedStatements {
+            shared,
+            sender: Mutex::new(Some(sender)),
+            writers,
+            dry_run,
+            parameter_set_count: AtomicUsize::new(0),
+        })
     }
 
-    pub fn add_parameter_set(&self, paramter_set: Params) -> FnResult<()> {
-        let mut items_to_write: Vec<Params> = Vec::new();
+    /// Number of parameter sets that have been added so far, including those that are not written yet.
+    pub fn parameter_set_count(&self) -> usize {
+        self.parameter_set_count.load(Ordering::SeqCst)
+    }
 
-        {
-            let mut params_vec = self.params_vec_mutex.lock().unwrap();
-            params_vec.push(paramter_set);
-            // println!("  *** add_parameter_set");
-            if params_vec.len() >= MAX_BATCH_SIZE {
-                items_to_write.extend(params_vec.drain(..));
+    pub fn add_parameter_set(&self, paramter_set: Params) -> FnResult<()> {
+        self.parameter_set_count.fetch_add(1, Ordering::SeqCst);
+        if self.dry_run {
+            return Ok(());
+        }
+        let items_to_write = {
+            let mut state = self.shared.state_mutex.lock().unwrap();
+            // the error is kept until write_to_database reports it, so that callers which ignore this one still fail
+            if let Some(error) = &state.error {
+                bail!("Could not write to {}: {}", self.shared.name, error);
+            }
+            state.params_vec.push(paramter_set);
+            if state.params_vec.len() >= self.shared.settings.batch_size {
+                state.in_flight += 1;
+                state.params_vec.drain(..).collect()
+            } else {
+                Vec::new()
             }
         };
 
         if !items_to_write.is_empty() {
-            self.write_to_database_internal(items_to_write)?;
+            self.send_batch(items_to_write)?;
+        }
+
+        Ok(())
+    }
+
+    // hands a batch that has been counted as in flight to the writers, and waits if too many batches are waiting already.
+    // Fails if all writers have stopped, e.g. because they panicked.
+    fn send_batch(&self, params_vec: Vec<Params>) -> FnResult<()> {
+        let sender = self.sender.lock().unwrap();
+        if sender.as_ref().map_or(true, |sender| sender.send(params_vec).is_err()) {
+            self.shared.state_mutex.lock().unwrap().in_flight -= 1;
+            bail!("The writers of {} have stopped.", self.shared.name);
+        }
+        Ok(())
+    }
+
+    pub fn write_to_database(&self) -> FnResult<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let items_to_write: Vec<Params> = {
+            let mut state = self.shared.state_mutex.lock().unwrap();
+            if !state.params_vec.is_empty() {
+                state.in_flight += 1;
+            }
+            state.params_vec.drain(..).collect()
+        };
+        if !items_to_write.is_empty() {
+            self.send_batch(items_to_write)?;
+        }
+
+        // callers rely on the data being in the database afterwards, so wait for all batches
+        let mut state = self.shared.state_mutex.lock().unwrap();
+        while state.in_flight > 0 {
+            state = self.shared.batch_written.wait(state).unwrap();
+        }
+        if let Some(error) = state.error.take() {
+            bail!("Could not write to {}: {}", self.shared.name, error);
         }
-        
         Ok(())
     }
+}
+
+impl Drop for BatchedStatements {
+    fn drop(&mut self) {
+        // without a sender, the writers stop after the batches that are still waiting
+        self.sender.lock().unwrap().take();
+        for writer in self.writers.drain(..) {
+            if writer.join().is_err() {
+                error!("A writer of {} has panicked.", self.shared.name);
+            }
+        }
+    }
+}
+
+// writes the batches from the channel until all senders are gone, and wakes up the threads that wait for them
+fn run_writer(shared: &SharedState, receiver: &Mutex<Receiver<Vec<Params>>>, mut write: BatchWriter) {
+    loop {
+        let params_vec = match receiver.lock().unwrap().recv() {
+            Ok(params_vec) => params_vec,
+            Err(_) => return,
+        };
+        let _in_flight = InFlightGuard(shared);
+        if let Err(e) = write(params_vec) {
+            shared.state_mutex.lock().unwrap().error.get_or_insert(e.to_string());
+        }
+    }
+}
+
+// counts a batch as written when it is dropped, even if the writer panics, so that write_to_database doesn't wait forever
+struct InFlightGuard<'a>(&'a SharedState);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        {
+            let mut state = self.0.state_mutex.lock().unwrap_or_else(PoisonError::into_inner);
+            state.in_flight -= 1;
+            if thread::panicking() {
+                state.error.get_or_insert_with(|| String::from("a writer has panicked"));
+            }
+        }
+        self.0.batch_written.notify_all();
+    }
+}
 
-    fn write_to_database_internal(&self, params_vec: Vec<Params>) -> FnResult<()> {
-        // println!("Trying to write to database ({})", self.name);
+fn write_batch(shared: &SharedState, conn: &mut PooledConn, statements: &[Statement], params_vec: Vec<Params>) -> FnResult<()> {
+    let mut backoff = shared.settings.retry_backoff;
+    for retry_count in 0..=shared.settings.max_retries {
+        if retry_count > 0 {
+            thread::sleep(backoff);
+            backoff *= 2;
+            warn!("…retrying now ({} of {}):", retry_count, shared.settings.max_retries);
+        }
         let mut retry = false;
         {
-            let mut conn = self.conn_mutex.lock().unwrap();
             let mut tx = conn.start_transaction(TxOpts::default())?;
-            for statement in &self.statements {
-                retry |= self.should_mysql_operation_be_retried("exec_batch", tx.exec_batch(statement, params_vec.iter()));
+            for statement in statements {
+                retry |= should_mysql_operation_be_retried(&shared.name, "exec_batch", tx.exec_batch(statement, params_vec.iter()));
             }
-            retry |= self.should_mysql_operation_be_retried("commit", tx.commit());
+            retry |= should_mysql_operation_be_retried(&shared.name, "commit", tx.commit());
+        }
+        if !retry {
+            return Ok(());
         }
+    }
+
+    bail!("Could not write {} parameter sets to {} after {} retries.", params_vec.len(), shared.name, shared.settings.max_retries);
+}
 
-        if retry {
-            thread::sleep(std::time::Duration::from_millis(5000));
-            println!("…retrying now:");
-            self.write_to_database_internal(params_vec)?;
+fn should_mysql_operation_be_retried(name: &str, action_name: &str, mysql_result: Result<()>) -> bool {
+    match mysql_result {
+        Ok(_) => {},
+        Err(Error::MySqlError(mse)) => {
+            if mse.code == 1213 {
+                warn!("Caught MySql Deadlock Error during {}.{}. Will retry shortly…", name, action_name);
+                return true;
+            } else {
+                error!("Unexpected MySql Error during {}.{}. Will not retry. Error: {}", name, action_name, mse);
+            }
+        },
+        Err(e) => {
+            error!("Unexpected Error during {}.{}. Will not retry. Error: {}", name, action_name, e);
         }
+    }
+    return false;
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    fn settings(batch_size: usize, max_in_flight: usize, writer_threads: usize) -> BatchSettings {
+        BatchSettings { batch_size, max_retries: 0, retry_backoff: Duration::from_secs(0), max_in_flight, writer_threads }
     }
 
-    fn should_mysql_operation_be_retried(&self, action_name: &str, mysql_result: Result<()>) -> bool {
-        match mysql_result {
-            Ok(_) => {},
-            Err(Error::MySqlError(mse)) => {
-                if mse.code == 1213 {
-                    println!("Caught MySql Deadlock Error during {}.{}. Will retry shortly…", self.name, action_name);
-                    return true;
-                } else {
-                    println!("Unexpected MySql Error during {}.{}. Will not retry. Error: {}", self.name, action_name, mse);
-                }
-            },
-            Err(e) => {
-                println!("Unexpected Error during {}.{}. Will not retry. Error: {}", self.name, action_name, e);
-            }
+    // batched statements whose writers call `write` instead of writing to a database
+    fn fake(settings: BatchSettings, write: impl FnMut(Vec<Params>) -> FnResult<()> + Send + Clone + 'static) -> BatchedStatements {
+        BatchedStatements::with_writers("test", settings, false, |_| Ok(Box::new(write.clone()))).unwrap()
+    }
+
+    #[test]
+    fn test_batches() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let writer_written = written.clone();
+        let statements = fake(settings(3, 2, 2), move |params_vec| {
+            writer_written.lock().unwrap().push(params_vec.len());
+            Ok(())
+        });
+        for _ in 0..7 {
+            statements.add_parameter_set(Params::Empty).unwrap();
         }
-        return false;
+        statements.write_to_database().unwrap();
+        let mut written = written.lock().unwrap().clone();
+        written.sort();
+        assert_eq!(written, vec![1, 3, 3]);
+        assert_eq!(statements.parameter_set_count(), 7);
     }
 
-    pub fn write_to_database(&self) -> FnResult<()> {
-        let mut items_to_write: Vec<Params> = Vec::new();
-        {
-            let mut params_vec = self.params_vec_mutex.lock().unwrap();
-            items_to_write.extend(params_vec.drain(..));
+    #[test]
+    fn test_backpressure() {
+        // the writer waits until the gate is dropped
+        let (open_gate, gate) = channel::<()>();
+        let gate = Arc::new(Mutex::new(gate));
+        let written = Arc::new(AtomicUsize::new(0));
+        let writer_written = written.clone();
+        let statements = Arc::new(fake(settings(1, 1, 1), move |_| {
+            gate.lock().unwrap().recv().ok();
+            writer_written.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }));
+
+        let added = Arc::new(AtomicUsize::new(0));
+        let adder = {
+            let statements = statements.clone();
+            let added = added.clone();
+            thread::spawn(move || for _ in 0..4 {
+                statements.add_parameter_set(Params::Empty).unwrap();
+                added.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(200));
+        // one batch is being written and one waits in the channel, so the third one is blocked
+        assert_eq!(added.load(Ordering::SeqCst), 2);
+        assert_eq!(written.load(Ordering::SeqCst), 0);
+
+        drop(open_gate);
+        adder.join().unwrap();
+        statements.write_to_database().unwrap();
+        assert_eq!(written.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_errors_are_sticky() {
+        let statements = fake(settings(1, 1, 1), |_| Err(Box::from("disk full")));
+        statements.add_parameter_set(Params::Empty).unwrap();
+        let mut failed = false;
+        for _ in 0..100 {
+            thread::sleep(Duration::from_millis(10));
+            if statements.add_parameter_set(Params::Empty).is_err() {
+                failed = true;
+                break;
+            }
         }
+        assert!(failed);
+        // the error is still reported at the end, although add_parameter_set has reported it before
+        assert!(statements.write_to_database().is_err());
+    }
 
-        let ret = self.write_to_database_internal(items_to_write);
-        ret
+    #[test]
+    fn test_panicking_writer() {
+        let statements = fake(settings(1, 1, 1), |_| panic!("writer panics on purpose"));
+        statements.add_parameter_set(Params::Empty).unwrap();
+        // doesn't wait forever for the batch of the panicked writer
+        assert!(statements.write_to_database().is_err());
     }
 }
@@ -0,0 +1,242 @@
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone, Duration};
+use clap::ArgMatches;
+use csv::{ReaderBuilder, StringRecord};
+use gtfs_structures::{Gtfs, StopTime};
+use mysql::*;
+use simple_error::bail;
+use std::collections::HashMap;
+
+use super::batched_statements::BatchedStatements;
+use super::{Importer, get_record_statements};
+use super::dry_run::DryRunReport;
+
+use crate::{FnResult, OrError};
+use crate::time_util::date_and_time;
+
+/// Names of the fields that can be read from the CSV files, see `ColumnMapping`.
+pub const FIELD_NAMES: [&str; 7] = ["trip_id", "trip_start_date", "stop_sequence", "stop_id", "delay_arrival", "delay_departure", "time_of_recording"];
+
+/// Imports historical delay observations from CSV files (e.g. dumps from third-party archives)
+/// into the `records` table, so that statistics can be computed without having recorded
+/// realtime data before. Each row describes the delays of one trip at one stop.
+/// Route, route variant and start time of the trips are looked up in the schedule.
+pub struct CsvImporter<'a> {
+    pub importer: &'a Importer<'a>,
+    pub args: &'a ArgMatches,
+}
+
+/// Maps the fields of a record to the names of the CSV columns that contain them.
+#[derive(Debug, PartialEq)]
+pub struct ColumnMapping {
+    columns: HashMap<&'static str, String>,
+}
+
+impl ColumnMapping {
+    /// Parses a mapping like `trip_id=Fahrt,stop_id=Halt`. Fields that are not mentioned
+    /// are expected in a column with the same name as the field.
+    pub fn parse(mapping: &str) -> FnResult<Self> {
+        let mut columns: HashMap<&'static str, String> = FIELD_NAMES.iter().map(|field| (*field, String::from(*field))).collect();
+        for pair in mapping.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+            let mut parts = pair.splitn(2, '=');
+            let field = parts.next().unwrap().trim(); // splitn always returns at least one part
+            let column = parts.next().or_error(&format!("Invalid column mapping '{}', expected <field>=<column>.", pair))?.trim();
+            let field = FIELD_NAMES.iter().find(|name| **name == field)
+                .or_error(&format!("Unknown field '{}' in column mapping. Known fields are: {}", field, FIELD_NAMES.join(", ")))?;
+            columns.insert(field, String::from(column));
+        }
+        Ok(ColumnMapping { columns })
+    }
+
+    /// Finds the index of each field's column within the header. Fields without a matching column are left out.
+    fn get_indices(&self, headers: &StringRecord) -> HashMap<&'static str, usize> {
+        self.columns.iter()
+            .filter_map(|(field, column)| headers.iter().position(|header| header.trim() == column).map(|index| (*field, index)))
+            .collect()
+    }
+}
+
+/// One row of a CSV file, with the values already parsed.
+struct Observation {
+    trip_id: String,
+    trip_start_date: NaiveDate,
+    stop_sequence: Option<u16>,
+    stop_id: Option<String>,
+    delay_arrival: Option<i64>,
+    delay_departure: Option<i64>,
+    time_of_recording: Option<i64>,
+}
+
+impl<'a> CsvImporter<'a> {
+    pub fn run(&self) -> FnResult<()> {
+        if !self.importer.args.is_present("record") {
+            bail!("The csv subcommand can only write records, so --record is required.");
+        }
+        let main = self.importer.main;
+        let schedule = main.get_schedule()?;
+        let schedule_filename = main.get_schedule_filename()?;
+        let mapping = ColumnMapping::parse(self.args.value_of("column-mapping").unwrap_or(""))?;
+        let delimiter = self.args.value_of("delimiter").unwrap(); // has a default value
+        if delimiter.len() != 1 {
+            bail!("The delimiter needs to be a single ASCII character, but it was '{}'.", delimiter);
+        }
+
+        let record_statements = get_record_statements(main.pool.clone(), self.importer.batch_settings, self.importer.dry_run)?;
+        for filename in self.args.values_of("files").unwrap() { // already validated by clap
+            let span = info_span!("csv", file = %filename);
+            let _entered = span.enter();
+            match self.import_file(filename, delimiter.as_bytes()[0], &mapping, &schedule, &schedule_filename, &record_statements) {
+                Ok((imported, skipped)) => info!("Imported {} rows, skipped {} rows.", imported, skipped),
+                Err(e) => error!("Could not import file: {}", e),
+            }
+        }
+        record_statements.write_to_database()?;
+        DryRunReport::add(&self.importer.dry_run_report.records, record_statements.parameter_set_count());
+        Ok(())
+    }
+
+    /// Imports all rows of one file and returns the number of imported and skipped rows.
+    fn import_file(
+        &self,
+        filename: &str,
+        delimiter: u8,
+        mapping: &ColumnMapping,
+        schedule: &Gtfs,
+        schedule_filename: &str,
+        record_statements: &BatchedStatements
+    ) -> FnResult<(usize, usize)> {
+        let mut reader = ReaderBuilder::new().delimiter(delimiter).flexible(true).from_path(filename)?;
+        let indices = mapping.get_indices(reader.headers()?);
+        for field in &["trip_id", "trip_start_date"] {
+            if !indices.contains_key(field) {
+                bail!("No column for {} (expected '{}').", field, mapping.columns[field]);
+            }
+        }
+        if !indices.contains_key("stop_sequence") && !indices.contains_key("stop_id") {
+            bail!("Neither a column for stop_sequence nor for stop_id.");
+        }
+        if !indices.contains_key("delay_arrival") && !indices.contains_key("delay_departure") {
+            bail!("Neither a column for delay_arrival nor for delay_departure.");
+        }
+
+        let mut imported = 0;
+        let mut skipped = 0;
+        for (line, row) in reader.records().enumerate() {
+            let result: FnResult<()> = row.map_err(|e| e.into())
+                .and_then(|row| self.parse_row(&row, &indices))
+                .and_then(|observation| self.import_observation(&observation, schedule, schedule_filename, record_statements));
+            match result {
+                Ok(()) => imported += 1,
+                Err(e) => {
+                    // line numbers start at 1, plus one line for the header
+                    debug!("Skipping line {}: {}", line + 2, e);
+                    skipped += 1;
+                }
+            }
+        }
+        Ok((imported, skipped))
+    }
+
+    fn parse_row(&self, row: &StringRecord, indices: &HashMap<&'static str, usize>) -> FnResult<Observation> {
+        // empty cells are treated like missing columns
+        let get = |field: &str| indices.get(field).and_then(|index| row.get(*index)).map(str::trim).filter(|value| !value.is_empty());
+
+        let date_format = self.args.value_of("date-format").unwrap(); // has a default value
+        let time_format = self.args.value_of("time-format").unwrap(); // has a default value
+
+        Ok(Observation {
+            trip_id: String::from(get("trip_id").or_error("no trip_id")?),
+            trip_start_date: NaiveDate::parse_from_str(get("trip_start_date").or_error("no trip_start_date")?, date_format)?,
+            stop_sequence: get("stop_sequence").map(|value| value.parse()).transpose()?,
+            stop_id: get("stop_id").map(String::from),
+            delay_arrival: get("delay_arrival").map(|value| value.parse()).transpose()?,
+            delay_departure: get("delay_departure").map(|value| value.parse()).transpose()?,
+            time_of_recording: get("time_of_recording").map(|value| parse_time_of_recording(value, time_format)).transpose()?,
+        })
+    }
+
+    fn import_observation(&self, observation: &Observation, schedule: &Gtfs, schedule_filename: &str, record_statements: &BatchedStatements) -> FnResult<()> {
+        if observation.delay_arrival.is_none() && observation.delay_departure.is_none() {
+            bail!("no delay");
+        }
+        let trip = schedule.get_trip(&observation.trip_id)?;
+        if !self.importer.agency_filter.contains_route(schedule, &trip.route_id) {
+            bail!("trip {} belongs to an agency that is not selected", trip.id);
+        }
+        let stop_time: &StopTime = match (observation.stop_sequence, &observation.stop_id) {
+            (Some(stop_sequence), _) => trip.stop_times.iter().find(|st| st.stop_sequence == stop_sequence),
+            (None, Some(stop_id)) => trip.stop_times.iter().find(|st| st.stop.id == *stop_id),
+            (None, None) => bail!("neither stop_sequence nor stop_id"),
+        }.or_error(&format!("trip {} has no matching stop", trip.id))?;
+        let trip_start_time = trip.stop_times.first().and_then(|st| st.departure_time).or_error("trip has no start time")?;
+
+        // Without an explicit time of recording, assume that the data was recorded when the event happened.
+        let time_of_recording = match observation.time_of_recording {
+            Some(time) => time,
+            None => {
+                let (scheduled_time, delay) = match observation.delay_departure {
+                    Some(delay) => (stop_time.departure_time, delay),
+                    None => (stop_time.arrival_time, observation.delay_arrival.unwrap()), // checked above
+                };
+                let service_day = Local.from_local_date(&observation.trip_start_date).single().or_error("invalid trip_start_date")?;
+                let scheduled_time = scheduled_time.or_error("no scheduled time for the event")?;
+                (date_and_time(&service_day, scheduled_time as i32) + Duration::seconds(delay)).timestamp()
+            }
+        };
+
+        record_statements.add_parameter_set(Params::from(params! {
+            "source" => &self.importer.main.source,
+            "route_id" => &trip.route_id,
+            "route_variant" => trip.route_variant.as_ref().or_error("no route variant")?,
+            "trip_id" => &trip.id,
+            "trip_start_date" => observation.trip_start_date,
+            "trip_start_time" => Duration::seconds(trip_start_time as i64),
+            "stop_sequence" => stop_time.stop_sequence,
+            "stop_id" => &stop_time.stop.id,
+            time_of_recording,
+            "delay_arrival" => observation.delay_arrival,
+            "delay_departure" => observation.delay_departure,
+            "schedule_file_name" => schedule_filename,
+            // csv files have no feed header
+            "feed_skew" => None::<i64>
+        }))
+    }
+}
+
+/// Parses either a unix timestamp or a local time in the given format.
+fn parse_time_of_recording(value: &str, format: &str) -> FnResult<i64> {
+    if let Ok(timestamp) = value.parse::<i64>() {
+        return Ok(timestamp);
+    }
+    let naive = NaiveDateTime::parse_from_str(value, format)?;
+    Ok(Local.from_local_datetime(&naive).earliest().or_error("invalid time_of_recording")?.timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_column_mapping() {
+        let mapping = ColumnMapping::parse(" trip_id=Fahrt ,delay_departure=Verspätung Abfahrt").unwrap();
+        assert_eq!(mapping.columns["trip_id"], "Fahrt");
+        assert_eq!(mapping.columns["delay_departure"], "Verspätung Abfahrt");
+        assert_eq!(mapping.columns["stop_id"], "stop_id");
+        assert_eq!(ColumnMapping::parse("").unwrap().columns.len(), FIELD_NAMES.len());
+
+        assert!(ColumnMapping::parse("trip=Fahrt").is_err());
+        assert!(ColumnMapping::parse("trip_id").is_err());
+
+        let headers = StringRecord::from(vec!["Fahrt", "stop_id", "Verspätung Abfahrt"]);
+        let indices = mapping.get_indices(&headers);
+        assert_eq!(indices.len(), 3);
+        assert_eq!(indices["delay_departure"], 2);
+    }
+
+    #[test]
+    fn test_parse_time_of_recording() {
+        assert_eq!(parse_time_of_recording("1590000000", "%Y-%m-%d %H:%M:%S").unwrap(), 1590000000);
+        let expected = Local.ymd(2020, 5, 20).and_hms(18, 40, 0).timestamp();
+        assert_eq!(parse_time_of_recording("2020-05-20 18:40:00", "%Y-%m-%d %H:%M:%S").unwrap(), expected);
+        assert!(parse_time_of_recording("20.05.2020", "%Y-%m-%d %H:%M:%S").is_err());
+    }
+}
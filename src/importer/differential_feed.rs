@@ -0,0 +1,112 @@
+use gtfs_rt::{FeedEntity, FeedMessage as GtfsRealtimeMessage};
+use gtfs_rt::feed_header::Incrementality;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// entities without any update for this long (in seconds) are dropped, e.g. if their deletion was missed
+const MAX_ENTITY_AGE: u64 = 3 * 3600;
+
+/// Keeps the current entities of differential GTFS realtime feeds, in which each message only contains
+/// the entities that have changed (or been deleted) since the previous one. Each differential message is
+/// merged into this state and turned into a full dataset, so that it can be recorded and used for
+/// predictions like a message of a full feed.
+///
+/// The realtime files may be processed in parallel and thus out of order. Therefore, each entity
+/// remembers the timestamp of the message that changed it last, and older changes are ignored.
+#[derive(Default)]
+pub struct DifferentialFeedState {
+    /// per entity id, the timestamp of the last change, and the entity, or `None` if it was deleted
+    entities: Mutex<HashMap<String, (u64, Option<FeedEntity>)>>,
+}
+
+impl DifferentialFeedState {
+    pub fn is_differential(message: &GtfsRealtimeMessage) -> bool {
+        message.header.incrementality == Some(Incrementality::Differential as i32)
+    }
+
+    /// Returns messages of full feeds unchanged. Differential messages are merged into the state, and
+    /// all entities which were current at `timestamp` (the timestamp of the message) are returned instead.
+    pub fn to_full_dataset(&self, mut message: GtfsRealtimeMessage, timestamp: u64) -> GtfsRealtimeMessage {
+        if !Self::is_differential(&message) {
+            return message;
+        }
+        let mut entities = self.entities.lock().unwrap();
+        let changed = message.entity.len();
+        for entity in message.entity.drain(..) {
+            // entities are replaced as a whole, as required by the GTFS realtime reference
+            if let Some((changed_at, _)) = entities.get(&entity.id) {
+                if *changed_at > timestamp {
+                    continue;
+                }
+            }
+            let id = entity.id.clone();
+            let current = if entity.is_deleted == Some(true) { None } else { Some(entity) };
+            entities.insert(id, (timestamp, current));
+        }
+        entities.retain(|_, (changed_at, _)| *changed_at + MAX_ENTITY_AGE >= timestamp);
+
+        // newer entities belong to messages that are processed on their own
+        message.entity = entities.values()
+            .filter(|(changed_at, _)| *changed_at <= timestamp)
+            .filter_map(|(_, entity)| entity.clone())
+            .collect();
+        message.header.incrementality = Some(Incrementality::FullDataset as i32);
+        debug!("Merged {} changed entities of a differential message into {} current entities.", changed, message.entity.len());
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gtfs_rt::FeedHeader;
+
+    fn entity(id: &str, is_deleted: bool) -> FeedEntity {
+        FeedEntity {
+            id: String::from(id),
+            is_deleted: Some(is_deleted),
+            ..Default::default()
+        }
+    }
+
+    fn message(incrementality: Incrementality, timestamp: u64, entities: Vec<FeedEntity>) -> GtfsRealtimeMessage {
+        GtfsRealtimeMessage {
+            header: FeedHeader {
+                gtfs_realtime_version: String::from("2.0"),
+                incrementality: Some(incrementality as i32),
+                timestamp: Some(timestamp),
+                ..Default::default()
+            },
+            entity: entities,
+            ..Default::default()
+        }
+    }
+
+    fn ids(message: &GtfsRealtimeMessage) -> Vec<&str> {
+        let mut ids: Vec<&str> = message.entity.iter().map(|entity| entity.id.as_str()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_merge_differential_messages() {
+        let state = DifferentialFeedState::default();
+        let full = state.to_full_dataset(message(Incrementality::FullDataset, 100, vec![entity("x", false)]), 100);
+        assert_eq!(ids(&full), vec!["x"]);
+
+        let first = state.to_full_dataset(message(Incrementality::Differential, 100, vec![entity("a", false), entity("b", false)]), 100);
+        assert_eq!(ids(&first), vec!["a", "b"]);
+        assert!(!DifferentialFeedState::is_differential(&first));
+
+        let second = state.to_full_dataset(message(Incrementality::Differential, 130, vec![entity("a", true), entity("c", false)]), 130);
+        assert_eq!(ids(&second), vec!["b", "c"]);
+
+        // a late message can't bring back the deleted entity, and doesn't see the newer one
+        let late = state.to_full_dataset(message(Incrementality::Differential, 120, vec![entity("a", false), entity("d", false)]), 120);
+        assert_eq!(ids(&late), vec!["b", "d"]);
+
+        // entities without updates expire
+        let much_later = state.to_full_dataset(message(Incrementality::Differential, 130 + MAX_ENTITY_AGE, vec![entity("e", false)]), 130 + MAX_ENTITY_AGE);
+        assert_eq!(ids(&much_later), vec!["c", "e"]);
+    }
+}
@@ -0,0 +1,51 @@
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Counts what an import with `--dry-run` would have changed. Records and predictions are
+/// counted once per computed row, whether the row would have been inserted or would have
+/// updated an existing one.
+#[derive(Default)]
+pub struct DryRunReport {
+    pub realtime_files: AtomicUsize,
+    pub failed_realtime_files: AtomicUsize,
+    pub duplicate_realtime_files: AtomicUsize,
+    pub records: AtomicUsize,
+    pub predictions: AtomicUsize,
+    pub deleted_predictions: AtomicUsize,
+}
+
+impl DryRunReport {
+    pub fn add(counter: &AtomicUsize, count: usize) {
+        counter.fetch_add(count, Ordering::SeqCst);
+    }
+}
+
+impl Display for DryRunReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let get = |counter: &AtomicUsize| counter.load(Ordering::SeqCst);
+        write!(f, "{} realtime files would have been imported, {} failed and {} would have been skipped as duplicates. \
+            {} records and {} predictions would have been written, {} outdated predictions would have been deleted.",
+            get(&self.realtime_files),
+            get(&self.failed_realtime_files),
+            get(&self.duplicate_realtime_files),
+            get(&self.records),
+            get(&self.predictions),
+            get(&self.deleted_predictions),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let report = DryRunReport::default();
+        DryRunReport::add(&report.realtime_files, 3);
+        DryRunReport::add(&report.records, 1200);
+        DryRunReport::add(&report.records, 34);
+        assert_eq!(report.to_string(), "3 realtime files would have been imported, 0 failed and 0 would have been skipped as duplicates. \
+            1234 records and 0 predictions would have been written, 0 outdated predictions would have been deleted.");
+    }
+}
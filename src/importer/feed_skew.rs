@@ -0,0 +1,46 @@
+use mysql::Pool;
+
+use crate::FnResult;
+
+// entity timestamps which differ more than this (in seconds) from the feed header are considered broken
+const MAX_PLAUSIBLE_SKEW: i64 = 24 * 3600;
+
+/// Returns the time of recording for the records of a trip update, and the skew between the feed
+/// header and the trip update in seconds (positive if the trip update is older than its message).
+///
+/// Some feeds publish their messages minutes after the trip updates have been made, so the timestamp
+/// of the trip update is used if it is present and plausible. Otherwise, the header timestamp is used
+/// and the skew is unknown.
+pub fn get_time_of_recording(header_timestamp: u64, entity_timestamp: Option<u64>) -> (u64, Option<i64>) {
+    match entity_timestamp {
+        Some(entity_timestamp) => {
+            let skew = header_timestamp as i64 - entity_timestamp as i64;
+            if skew.abs() > MAX_PLAUSIBLE_SKEW {
+                debug!("Ignoring trip update timestamp {}, which is {} seconds away from the feed header.", entity_timestamp, skew);
+                (header_timestamp, None)
+            } else {
+                (entity_timestamp, Some(skew))
+            }
+        },
+        None => (header_timestamp, None),
+    }
+}
+
+/// Adds the `feed_skew` column to the `records` table, if it doesn't exist yet (see `super::add_column`).
+pub fn add_column(pool: &Pool, dry_run: bool) -> FnResult<()> {
+    super::add_column(pool, "records", "feed_skew", "INT NULL DEFAULT NULL", dry_run)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_time_of_recording() {
+        assert_eq!(get_time_of_recording(1000, None), (1000, None));
+        assert_eq!(get_time_of_recording(100_000, Some(99_820)), (99_820, Some(180)));
+        // clocks of the feed may differ a bit
+        assert_eq!(get_time_of_recording(100_000, Some(100_005)), (100_005, Some(-5)));
+        assert_eq!(get_time_of_recording(100_000, Some(1)), (100_000, None));
+    }
+}
@@ -0,0 +1,208 @@
+use flate2::read::GzDecoder;
+use mysql::*;
+use mysql::prelude::*;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::sync::Arc;
+
+use crate::{FnResult, OrError};
+
+/// What is remembered about each realtime file that has been imported.
+pub struct ImportedFile {
+    /// SHA-256 of the (unzipped) content, as hex string
+    pub content_hash: String,
+    /// timestamp from the header of the feed
+    pub header_timestamp: u64,
+}
+
+/// Keeps track of the content of all realtime files that have been imported, so that
+/// the same content is not imported again if it arrives a second time under a different name.
+pub struct ImportedFiles {
+    pool: Arc<Pool>,
+    source: String,
+}
+
+impl ImportedFiles {
+    pub fn new(pool: Arc<Pool>, source: &str) -> Self {
+        ImportedFiles {
+            pool,
+            source: String::from(source),
+        }
+    }
+
+    pub fn create_table(&self) -> FnResult<()> {
+        let mut con = self.pool.get_conn()?;
+        con.query_drop(r"CREATE TABLE IF NOT EXISTS `imported_files` (
+            `source` VARCHAR(255) NOT NULL,
+            `content_hash` CHAR(64) NOT NULL,
+            `file_name` VARCHAR(255) NOT NULL,
+            `header_timestamp` BIGINT UNSIGNED NOT NULL,
+            `time_of_import` TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (`source`, `content_hash`)
+        );")?;
+        Ok(())
+    }
+
+    /// Returns the name of the file with the given content, if such a file has already been imported.
+    pub fn find(&self, content_hash: &str) -> FnResult<Option<String>> {
+        let mut con = self.pool.get_conn()?;
+        let file_name: Option<String> = con.exec_first(
+            r"SELECT `file_name` FROM `imported_files` WHERE `source` = :source AND `content_hash` = :content_hash",
+            params! {
+                "source" => &self.source,
+                content_hash,
+            },
+        )?;
+        Ok(file_name)
+    }
+
+    pub fn insert(&self, file_name: &str, imported_file: &ImportedFile) -> FnResult<()> {
+        let mut con = self.pool.get_conn()?;
+        // if the content was imported again with --force, the latest file name is kept
+        con.exec_drop(
+            r"REPLACE INTO `imported_files` (`source`, `content_hash`, `file_name`, `header_timestamp`)
+            VALUES (:source, :content_hash, :file_name, :header_timestamp)",
+            params! {
+                "source" => &self.source,
+                "content_hash" => &imported_file.content_hash,
+                file_name,
+                "header_timestamp" => imported_file.header_timestamp,
+            },
+        )?;
+        Ok(())
+    }
+}
+
+/// Reads a realtime file, which may be compressed.
+pub fn read_realtime_file(path: &str) -> FnResult<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut vec = Vec::<u8>::new();
+    file.read_to_end(&mut vec)?;
+    unpack_realtime_data(path, vec)
+}
+
+/// Returns the content of a realtime file with the given name, which is unpacked if the name ends with
+/// `.zip`, `.zst` (Zstandard) or `.gz` (gzip), e.g. `2020-10-17T12:00:00.pb.zst`.
+pub fn unpack_realtime_data(name: &str, data: Vec<u8>) -> FnResult<Vec<u8>> {
+    let mut vec = Vec::<u8>::new();
+    if name.ends_with(".zip") {
+        let mut archive = zip::ZipArchive::new(Cursor::new(data)).or_error("Zip file not found.")?;
+        let mut zipped_file = archive.by_index(0).or_error("Zip file was empty")?;
+        debug!("Reading {} from zip…", zipped_file.name());
+        zipped_file.read_to_end(&mut vec)?;
+    } else if name.ends_with(".zst") {
+        zstd::stream::read::Decoder::new(Cursor::new(data))?.read_to_end(&mut vec)?;
+    } else if name.ends_with(".gz") {
+        GzDecoder::new(Cursor::new(data)).read_to_end(&mut vec)?;
+    } else {
+        return Ok(data);
+    }
+    Ok(vec)
+}
+
+pub fn content_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Splits the given realtime files into those which shall be imported and the duplicates, whose content
+/// has already been imported (as told by `find_imported`, see `ImportedFiles::find`) or appears in an
+/// earlier file of the list. Each duplicate comes with the name of the file with the same content.
+/// With `force`, there are no duplicates, so that all files are imported again.
+pub fn split_duplicates<F>(rt_filenames: Vec<String>, force: bool, find_imported: F) -> FnResult<(Vec<String>, Vec<(String, String)>)>
+    where F: Fn(&str) -> FnResult<Option<String>> {
+    if force {
+        return Ok((rt_filenames, Vec::new()));
+    }
+
+    let mut hashes_in_list: HashMap<String, String> = HashMap::new(); // content hash -> file name
+    let mut remaining = Vec::new();
+    let mut duplicates = Vec::new();
+    for rt_filename in rt_filenames {
+        let hash = match read_realtime_file(&rt_filename) {
+            Ok(data) => content_hash(&data),
+            Err(e) => {
+                // the import will fail as well and handle the file
+                debug!("Could not read {} to check for duplicates: {}", rt_filename, e);
+                remaining.push(rt_filename);
+                continue;
+            }
+        };
+        let earlier_filename = match hashes_in_list.get(&hash) {
+            Some(filename) => Some(filename.clone()),
+            None => find_imported(&hash)?,
+        };
+        match earlier_filename {
+            Some(earlier_filename) => duplicates.push((rt_filename, earlier_filename)),
+            None => {
+                hashes_in_list.insert(hash, rt_filename.clone());
+                remaining.push(rt_filename);
+            }
+        }
+    }
+    Ok((remaining, duplicates))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_hash() {
+        assert_eq!(content_hash(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(content_hash(b"abc").len(), 64);
+        assert_ne!(content_hash(b"abc"), content_hash(b"abd"));
+    }
+
+    #[test]
+    fn test_unpack_realtime_data() {
+        let data = b"realtime data".to_vec();
+        assert_eq!(unpack_realtime_data("2020-10-17T12:00:00.pb", data.clone()).unwrap(), data);
+
+        let zstd_data = zstd::stream::encode_all(Cursor::new(data.clone()), 3).unwrap();
+        assert_eq!(unpack_realtime_data("2020-10-17T12:00:00.pb.zst", zstd_data).unwrap(), data);
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&data).unwrap();
+        assert_eq!(unpack_realtime_data("2020-10-17T12:00:00.pb.gz", encoder.finish().unwrap()).unwrap(), data);
+
+        assert!(unpack_realtime_data("2020-10-17T12:00:00.pb.zst", data).is_err());
+    }
+
+    #[test]
+    fn test_split_duplicates() {
+        let dir = std::env::temp_dir().join(format!("imported_files_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write_file = |name: &str, content: &[u8]| {
+            let path = dir.join(name);
+            std::fs::write(&path, content).unwrap();
+            String::from(path.to_str().unwrap())
+        };
+        let new_file = write_file("2020-10-17T12:00:00.pb", b"new");
+        let imported_content = write_file("2020-10-17T12:01:00.pb", b"imported");
+        let repeated_content = write_file("2020-10-17T12:02:00.pb", b"new");
+        let missing_file = String::from(dir.join("2020-10-17T12:03:00.pb").to_str().unwrap());
+        let rt_filenames = vec![new_file.clone(), imported_content.clone(), repeated_content.clone(), missing_file.clone()];
+
+        // the content of one file has already been imported under another name, and its content hash is unchanged
+        let mut imported: HashMap<String, String> = HashMap::new();
+        imported.insert(content_hash(b"imported"), String::from("2020-10-16T12:00:00.pb"));
+        let find_imported = |hash: &str| -> FnResult<Option<String>> { Ok(imported.get(hash).cloned()) };
+
+        let (remaining, duplicates) = split_duplicates(rt_filenames.clone(), false, find_imported).unwrap();
+        // files that can't be read are left for the import
+        assert_eq!(remaining, vec![new_file.clone(), missing_file]);
+        assert_eq!(duplicates, vec![
+            (imported_content, String::from("2020-10-16T12:00:00.pb")),
+            (repeated_content, new_file),
+        ]);
+
+        // --force imports all of them again
+        let (remaining, duplicates) = split_duplicates(rt_filenames.clone(), true, find_imported).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(remaining, rt_filenames);
+        assert!(duplicates.is_empty());
+    }
+}
@@ -1,6 +1,19 @@
 mod per_schedule_importer;
 mod scheduled_predictions_importer;
+mod realtime_format;
+mod siri;
 mod batched_statements;
+mod schedule_transition;
+mod shadow_evaluation;
+mod csv_importer;
+mod imported_files;
+mod dry_run;
+mod rt_archive;
+mod validate;
+mod differential_feed;
+mod feed_skew;
+mod vehicle_progress;
+mod vehicle_identity;
 
 use simple_error::bail;
 use clap::{App, Arg, ArgMatches, ArgGroup};
@@ -11,17 +24,31 @@ use std::{thread, time};
 use ureq::get;
 use mysql::*;
 use mysql::prelude::*;
-use chrono::{Local, Duration, DateTime, Timelike};
+use chrono::{Local, Duration, DateTime};
 use std::sync::Mutex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use batched_statements::BatchedStatements;
+use batched_statements::{BatchedStatements, BatchSettings};
+use gtfs_structures::Gtfs;
 
-use crate::{Main, FileCache, FnResult, read_dir_simple, date_from_filename, OrError};
-use crate::types::{PredictionBasis, VehicleIdentifier};
+use crate::{Main, FileCache, FnResult, Loadable, read_dir_recursive, date_from_filename, OrError};
+use crate::types::{PredictionBasis, VehicleIdentifier, WeatherProvider, AgencyFilter, GtfsDateTime};
+use crate::prediction_events::PredictionEventPublisher;
+use crate::predictor::CurveBlending;
 
 use per_schedule_importer::PerScheduleImporter;
-use scheduled_predictions_importer::ScheduledPredictionsImporter;
+use scheduled_predictions_importer::{ScheduledPredictionsImporter, ScheduledPredictionSettings, RouteGroup};
+use realtime_format::{RealtimeFormat, FORMAT_NAMES, create_format};
+use schedule_transition::ScheduleTransition;
+use shadow_evaluation::ShadowEvaluation;
+use csv_importer::CsvImporter;
+use imported_files::{ImportedFiles, split_duplicates};
+pub use imported_files::read_realtime_file;
+use rt_archive::RealtimeArchiver;
+pub use rt_archive::{for_each_archived_file, get_archive_dir, get_archive_path};
+use dry_run::DryRunReport;
+use validate::FeedValidator;
+use differential_feed::DifferentialFeedState;
 
 lazy_static! {
     static ref MAX_ESTIMATED_TRIP_DURATION: Duration =  Duration::hours(12);
@@ -36,11 +63,24 @@ pub struct Importer<'a>  {
     schedule_dir: Option<String>,
     target_dir: Option<String>,
     fail_dir: Option<String>,
-    verbose: bool,
     perform_cleanup: bool,
     last_ping_time_mutex: Mutex<Option<DateTime<Local>>>,
     current_prediction_basis: Mutex<HashMap<VehicleIdentifier, PredictionBasis>>, //used in per_schedule_importer, but declared here for persistence
-    timeout_until: Mutex<Option<DateTime<Local>>>, //used in scheduled_predictions_importer, but declared here for persistence
+    timeout_until: Mutex<HashMap<RouteGroup, DateTime<Local>>>, //used in scheduled_predictions_importer, but declared here for persistence
+    schedule_transitions_done: Mutex<HashSet<String>>, // file names of schedules for which predictions have already been migrated in this run, see ScheduleTransition::is_done for earlier runs
+    shadow_evaluation: Option<ShadowEvaluation>, // used in both kinds of prediction importers, but declared here for persistence
+    realtime_format: Box<dyn RealtimeFormat>,
+    imported_files: ImportedFiles,
+    agency_filter: AgencyFilter,
+    dry_run: bool,
+    dry_run_report: DryRunReport,
+    batch_settings: BatchSettings,
+    rt_archiver: Option<RealtimeArchiver>,
+    prediction_events: Option<PredictionEventPublisher>, // only if enabled, and never in dry runs
+    differential_feed: DifferentialFeedState, // used in per_schedule_importer, but declared here for persistence
+    max_delay_age: Option<i64>, // in seconds, trip updates that are older than their message by more than this are not used for predictions
+    use_vehicle_positions: bool,
+    thread_pool: rayon::ThreadPool, // computes the records and predictions, while the BatchedStatements have their own writer threads
 }
 
 
@@ -55,7 +95,9 @@ impl<'a> Importer<'a>  {
             Processing can involve:
              - *record*ing for later analysis
              - creating updated *predict*ions
-             - both")
+             - both
+            
+            At least one of --record, --predict or --cleanup is needed, except for the validate subcommand.")
             .arg(Arg::new("record")
                 .about("Indicates that realtime data shall be recorded for later analysis.")
                 .short('r')
@@ -74,9 +116,127 @@ impl<'a> Importer<'a>  {
                 .long("cleanup")
                 .takes_value(false)
             )
+            .arg(Arg::new("shadow-statistics")
+                .about("Statistics file (like all_curves.exp) which shall be evaluated in shadow mode. Predictions are computed from it in addition to the current statistics, and their differences are written to a report, but they are not written to the database.")
+                .long("shadow-statistics")
+                .requires("predict")
+                .takes_value(true)
+                .value_name("FILE")
+            )
+            .arg(Arg::new("shadow-report")
+                .about("JSON file to which the shadow mode report is written. Defaults to shadow_report.json in the data directory.")
+                .long("shadow-report")
+                .requires("shadow-statistics")
+                .takes_value(true)
+                .value_name("FILE")
+            )
+            .arg(Arg::new("realtime-format")
+                .about("Format of the realtime files. Data in other formats than gtfs-rt is converted into GTFS realtime trip updates before it is processed.")
+                .long("realtime-format")
+                .env("REALTIME_FORMAT")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&FORMAT_NAMES)
+                .default_value("gtfs-rt")
+            )
+            .arg(Arg::new("siri-trip-mapping")
+                .about("CSV file that maps the DatedVehicleJourneyRef of SIRI journeys (first column) to GTFS trip_ids (second column). Journeys that are not listed need to use the trip_id as their reference.")
+                .long("siri-trip-mapping")
+                .takes_value(true)
+                .value_name("FILE")
+            )
+            .arg(Arg::new("siri-stop-mapping")
+                .about("CSV file that maps SIRI StopPointRefs (first column) to GTFS stop_ids (second column). Stops that are not listed need to use the stop_id as their reference.")
+                .long("siri-stop-mapping")
+                .takes_value(true)
+                .value_name("FILE")
+            )
+            .arg(Arg::new("agency-ids")
+                .about("If provided, only trips of routes of these agencies (comma-separated agency_ids) are imported.")
+                .long("agency-ids")
+                .env("AGENCY_IDS")
+                .takes_value(true)
+                .use_delimiter(true)
+                .value_name("AGENCY_ID")
+            )
+            .arg(Arg::new("force")
+                .about("Imports realtime files in automatic and batch mode even if a file with the same content has already been imported.")
+                .long("force")
+                .takes_value(false)
+            )
+            .arg(Arg::new("dry-run")
+                .about("Parses the schedules and realtime files and computes all records and predictions, but writes nothing into the database and leaves the files where they are. Reports how many records and predictions would have been written. Not available in automatic mode.")
+                .long("dry-run")
+                .takes_value(false)
+            )
+            .arg(Arg::new("batch-size")
+                .about("Number of records or predictions that are written to the database within one transaction.")
+                .long("batch-size")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("1000")
+            )
+            .arg(Arg::new("max-retries")
+                .about("How often a transaction is retried after a deadlock before the import fails.")
+                .long("max-retries")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("5")
+            )
+            .arg(Arg::new("retry-backoff")
+                .about("Time to wait before the first retry after a deadlock. The time doubles with each further retry. The value will be parsed by the `parse_duration` crate, which acceps a superset of the `systemd.time` syntax.")
+                .long("retry-backoff")
+                .takes_value(true)
+                .value_name("DURATION")
+                .default_value("5s")
+            )
+            .arg(Arg::new("max-in-flight")
+                .about("Number of full batches per table that may wait for the database at the same time. If the database is slower than that, the import is slowed down.")
+                .long("max-in-flight")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("2")
+            )
+            .arg(Arg::new("writer-threads")
+                .about("Number of threads per table that write records or predictions to the database, each with its own connection. The records and predictions are computed in other threads, which only wait for the writers if max-in-flight batches are waiting already.")
+                .long("writer-threads")
+                .takes_value(true)
+                .value_name("N")
+                .default_value("2")
+            )
+            .arg(Arg::new("jobs")
+                .short('j')
+                .long("jobs")
+                .about("Maximum number of realtime files and trip updates that are processed in parallel. Defaults to the number of CPU cores.")
+                .value_name("N")
+                .takes_value(true)
+            )
+            .arg(Arg::new("archive-rt")
+                .about("In automatic and batch mode, bundles the imported realtime files of each past day into a compressed archive (<date>.tar.zst) in the rt_archive subdirectory and deletes them from the imported subdirectory.")
+                .long("archive-rt")
+                .takes_value(false)
+            )
+            .arg(Arg::new("rt-retention")
+                .about("Archives of realtime files which are older than this are deleted. The value will be parsed by the `parse_duration` crate, which acceps a superset of the `systemd.time` syntax. If not provided, the archives are kept forever.")
+                .long("rt-retention")
+                .requires("archive-rt")
+                .takes_value(true)
+                .value_name("DURATION")
+            )
+            .arg(Arg::new("max-delay-age")
+                .about("Trip updates whose timestamp is older than their feed header by more than this are still recorded, but their delays are not used for predictions, because the vehicle has most likely moved on since. The value will be parsed by the `parse_duration` crate, which acceps a superset of the `systemd.time` syntax. If not provided, all trip updates are used. `analyse feed-latency` shows how old the trip updates of a feed usually are.")
+                .long("max-delay-age")
+                .takes_value(true)
+                .value_name("DURATION")
+            )
+            .arg(Arg::new("use-vehicle-positions")
+                .about("Bases the predictions on the vehicle positions of the realtime messages, if they show that a vehicle has progressed further than its last delay report. The scheduled time at the position of the vehicle is interpolated between the previous and the next stop.")
+                .long("use-vehicle-positions")
+                .takes_value(false)
+            )
+            // required for all subcommands except validate, which is checked in `run`
             .group(ArgGroup::new("processing")
                 .args(&["record", "predict", "cleanup"])
-                .required(true)
                 .multiple(true)
             )
             .subcommand(App::new("automatic")
@@ -87,6 +247,25 @@ impl<'a> Importer<'a>  {
                     .takes_value(true)
                     .about("An URL that will be pinged (using HTTP GET) after each iteration.")
                 )
+                .arg(Arg::new("schedule-look-ahead")
+                    .long("schedule-look-ahead")
+                    .value_name("HOURS")
+                    .default_value("180")
+                    .about("Schedule-based predictions are made for trips starting within this many hours from now.")
+                )
+                .arg(Arg::new("schedule-batch-size")
+                    .long("schedule-batch-size")
+                    .value_name("TRIPS")
+                    .default_value("1000")
+                    .about("Minimum number of trips for which schedule-based predictions are made between two directory scans.")
+                )
+                .arg(Arg::new("schedule-priority-routes")
+                    .long("schedule-priority-routes")
+                    .value_name("ROUTE_ID")
+                    .multiple(true)
+                    .takes_value(true)
+                    .about("Routes for which schedule-based predictions are made for the whole look-ahead before the other routes.")
+                )
             )
             .subcommand(App::new("batch")
                 .about("Imports all files which are present at the time it is started.")
@@ -113,30 +292,150 @@ impl<'a> Importer<'a>  {
                     .index(2)
                     .multiple(true)
                     .value_name("PBs")
-                    .about("One or more files with real time data, as .pb or .zip")
+                    .about("One or more files with real time data, as .pb (or .xml, depending on the realtime format), optionally compressed as .zip, .zst or .gz")
+                )
+            )
+            .subcommand(App::new("validate")
+                .about("Checks how well realtime files match a schedule and reports problems of the feed, without writing anything into the database or moving any files.")
+                .arg(Arg::new("schedule")
+                    .index(1)
+                    .value_name("SCHEDULE")
+                    .required_unless("help")
+                    .about("The static GTFS schedule, as directory or .zip")
+                ).arg(Arg::new("rt")
+                    .index(2)
+                    .multiple(true)
+                    .value_name("PBs")
+                    .required_unless("help")
+                    .about("One or more files with real time data, as .pb (or .xml, depending on the realtime format), optionally compressed as .zip, .zst or .gz")
+                )
+            )
+            .subcommand(App::new("csv")
+                .about("Imports historical delay observations from CSV files into the records table. Only works with --record.")
+                .long_about(
+                    "Imports historical delay observations from CSV files into the records table. Only works with --record. \
+                    Each row contains the delays (in seconds) of one trip at one stop. The trips are looked up in the schedule \
+                    given with --schedule (or the newest one in the data directory). Rows that can't be matched with the schedule are skipped. \
+                    Known fields are: trip_id, trip_start_date, stop_sequence, stop_id, delay_arrival, delay_departure, time_of_recording."
+                )
+                .arg(Arg::new("column-mapping")
+                    .long("column-mapping")
+                    .takes_value(true)
+                    .value_name("MAPPING")
+                    .about("Names of the columns that contain the fields, like 'trip_id=Fahrt,delay_departure=Abfahrtsverspaetung'. Fields that are not mentioned are expected in a column with the same name as the field.")
+                )
+                .arg(Arg::new("delimiter")
+                    .long("delimiter")
+                    .takes_value(true)
+                    .value_name("CHAR")
+                    .default_value(",")
+                    .about("The character that separates the columns.")
+                )
+                .arg(Arg::new("date-format")
+                    .long("date-format")
+                    .takes_value(true)
+                    .value_name("FORMAT")
+                    .default_value("%Y-%m-%d")
+                    .about("Format of the trip_start_date column, as used by strftime.")
+                )
+                .arg(Arg::new("time-format")
+                    .long("time-format")
+                    .takes_value(true)
+                    .value_name("FORMAT")
+                    .default_value("%Y-%m-%d %H:%M:%S")
+                    .about("Format of the time_of_recording column (local time), as used by strftime. Unix timestamps are accepted as well.")
+                )
+                .arg(Arg::new("files")
+                    .index(1)
+                    .multiple(true)
+                    .value_name("CSVs")
+                    .required_unless("help")
+                    .about("One or more CSV files with a header row")
                 )
             )
     }
 
-    pub fn new(main: &'a Main, args: &'a ArgMatches) -> Importer<'a> {
-        Importer {
+    pub fn new(main: &'a Main, args: &'a ArgMatches) -> FnResult<Importer<'a>> {
+        let curve_blending = CurveBlending::from_args(&main.args)?;
+        Ok(Importer {
             main,
             args,
             target_dir: None,
             fail_dir: None,
             schedule_dir: None,
             rt_dir: None,
-            verbose: main.verbose,
             perform_cleanup: args.is_present("cleanup"),
             last_ping_time_mutex: Mutex::new(None),
             current_prediction_basis: Mutex::new(HashMap::new()),
-            timeout_until: Mutex::new(None),
+            timeout_until: Mutex::new(HashMap::new()),
+            schedule_transitions_done: Mutex::new(HashSet::new()),
+            shadow_evaluation: args.value_of("shadow-statistics").map(|filename| {
+                let report_filename = match args.value_of("shadow-report") {
+                    Some(report_filename) => String::from(report_filename),
+                    None => format!("{}/shadow_report.json", main.dir),
+                };
+                ShadowEvaluation::new(filename, &report_filename, curve_blending)
+            }),
+            realtime_format: create_format(args.value_of("realtime-format").unwrap(), args)?, // has a default value
+            imported_files: ImportedFiles::new(main.pool.clone(), &main.source),
+            agency_filter: AgencyFilter::from_args(args),
+            dry_run: args.is_present("dry-run"),
+            dry_run_report: DryRunReport::default(),
+            batch_settings: BatchSettings::from_args(args)?,
+            rt_archiver: RealtimeArchiver::from_args(args, &main.dir)?,
+            prediction_events: if args.is_present("dry-run") { None } else { PredictionEventPublisher::from_args(&main.args, &main.source)? },
+            differential_feed: DifferentialFeedState::default(),
+            max_delay_age: match args.value_of("max-delay-age") {
+                Some(max_delay_age) => Some(parse_duration::parse(max_delay_age)?.as_secs() as i64),
+                None => None,
+            },
+            use_vehicle_positions: args.is_present("use-vehicle-positions"),
+            thread_pool: Self::build_thread_pool(args)?,
+        })
+    }
+
+    fn build_thread_pool(args: &ArgMatches) -> FnResult<rayon::ThreadPool> {
+        let mut thread_pool_builder = rayon::ThreadPoolBuilder::new();
+        if let Some(jobs) = args.value_of("jobs") {
+            let jobs : usize = jobs.parse()?;
+            if jobs < 1 {
+                bail!("Number of jobs must be at least 1.");
+            }
+            thread_pool_builder = thread_pool_builder.num_threads(jobs);
         }
+        Ok(thread_pool_builder.build()?)
     }
 
     /// Runs the actions that are selected via the command line args
     pub fn run(&mut self) -> FnResult<()> {
-        match self.args.clone().subcommand() {
+        // validation doesn't write anything, not even the tables
+        if let ("validate", Some(sub_args)) = self.args.subcommand() {
+            return FeedValidator { importer: self, args: sub_args }.run();
+        }
+        if !["record", "predict", "cleanup"].iter().any(|arg| self.args.is_present(arg)) {
+            bail!("At least one of --record, --predict or --cleanup is needed.");
+        }
+        if self.dry_run {
+            if let ("automatic", _) = self.args.subcommand() {
+                bail!("A dry run would import the same files again and again, so it is not available in automatic mode.");
+            }
+            info!("Dry run, nothing will be written into the database and no files will be moved.");
+        }
+        if self.args.is_present("record") && self.main.weather.is_some() && !self.dry_run {
+            WeatherProvider::create_table(&self.main.pool)?;
+        }
+        // the duplicate check needs this table in dry runs as well, and an empty table changes no data
+        self.imported_files.create_table()?;
+        // databases from before these columns need to be migrated, but not in dry runs, which don't prepare the statements
+        if self.args.is_present("record") {
+            feed_skew::add_column(&self.main.pool, self.dry_run)?;
+        }
+        if self.args.is_present("predict") {
+            vehicle_identity::add_columns(&self.main.pool, self.dry_run)?;
+            // like the table of imported files, this one is needed in dry runs as well
+            ScheduleTransition::create_table(&self.main.pool)?;
+        }
+        let result = match self.args.clone().subcommand() {
             ("automatic", Some(_sub_args)) => {
                 self.set_dir_paths()?;
                 self.run_as_non_manual(true)
@@ -146,8 +445,13 @@ impl<'a> Importer<'a>  {
                 self.run_as_non_manual(false)
             }
             ("manual", Some(sub_args)) => self.run_as_manual(sub_args),
+            ("csv", Some(sub_args)) => CsvImporter { importer: self, args: sub_args }.run(),
             _ => panic!("Invalid arguments."),
+        };
+        if self.dry_run {
+            info!("Dry run finished. {}", self.dry_run_report);
         }
+        result
     }
 
     /// Handle manual mode
@@ -164,7 +468,7 @@ impl<'a> Importer<'a>  {
                 .map(|s| String::from(s))
                 .collect();
             if let Err(e) = self.process_schedule_and_realtimes(&gtfs_schedule_filename, &gtfs_realtime_filenames) {
-                eprintln!("Error while processing schedule and realtimes: {}.", e);
+                error!("Error while processing schedule and realtimes: {}.", e);
             }
         }
         Ok(())
@@ -173,18 +477,45 @@ impl<'a> Importer<'a>  {
     /// Handle cleanup command
     fn run_cleanup(&self) -> FnResult<()> {
         let min = Local::now() - *MAX_ESTIMATED_TRIP_DURATION;
-        let min_start_date = min.date();
-        let min_start_time = Duration::seconds(min.time().num_seconds_from_midnight() as i64);
-        if self.verbose {
-            println!("Deleting all predictions with trip start before {}.", min);
-        }
+        // trips of the previous service day may start after midnight, with times after 24:00:00
+        let min_start = GtfsDateTime::from_date_time(&min);
+        let min_start_previous_day = min_start.relative_to(min_start.service_day().pred());
         let mut con = self.main.pool.get_conn()?;
+        if self.dry_run {
+            let count: Option<usize> = con.exec_first(
+                r"SELECT COUNT(*) FROM 
+                    predictions 
+                WHERE 
+                    `source` = :source AND (
+                        `trip_start_date` < :previous_start_date OR (
+                            `trip_start_date` = :previous_start_date AND
+                            `trip_start_time` < :previous_start_time
+                        ) OR (
+                            `trip_start_date` = :min_start_date AND
+                            `trip_start_time` < :min_start_time
+                        )
+                    );",
+                params!{
+                    "source" => self.main.source.clone(),
+                    "previous_start_date" => min_start_previous_day.service_day_param(),
+                    "previous_start_time" => min_start_previous_day.duration(),
+                    "min_start_date" => min_start.service_day_param(),
+                    "min_start_time" => min_start.duration(),
+                },
+            )?;
+            DryRunReport::add(&self.dry_run_report.deleted_predictions, count.unwrap_or(0));
+            return Ok(());
+        }
+        debug!("Deleting all predictions with trip start before {}.", min);
         let statement = con.prep(
             r"DELETE FROM 
                 predictions 
             WHERE 
                 `source` = :source AND (
-                    `trip_start_date` < :min_start_date OR (
+                    `trip_start_date` < :previous_start_date OR (
+                        `trip_start_date` = :previous_start_date AND
+                        `trip_start_time` < :previous_start_time
+                    ) OR (
                         `trip_start_date` = :min_start_date AND
                         `trip_start_time` < :min_start_time
                     )
@@ -192,15 +523,15 @@ impl<'a> Importer<'a>  {
         )?;
         con.exec_drop(statement, params!{
             "source" => self.main.source.clone(),
-            "min_start_date" => min_start_date.naive_local(),
-            "min_start_time" => min_start_time,
+            "previous_start_date" => min_start_previous_day.service_day_param(),
+            "previous_start_time" => min_start_previous_day.duration(),
+            "min_start_date" => min_start.service_day_param(),
+            "min_start_time" => min_start.duration(),
         })?;
         // TODO handle deadlock error here, like we already do in BatchedStatements.
 
         // Clean up outdated entries from the current_prediction_basis:
-        if self.verbose {
-            println!("Database prediction cleanup successful. Now deleting old entries from prediction basis cache.");
-        }
+        debug!("Database prediction cleanup successful. Now deleting old entries from prediction basis cache.");
         { // block for mutex
             let mut cpr = self.current_prediction_basis.lock().unwrap();
             let mut to_remove : Vec<VehicleIdentifier> = Vec::new();
@@ -215,9 +546,7 @@ impl<'a> Importer<'a>  {
             // TODO: try out if we need to call cpr.shrink_to_fit() here. 
             // It might be useful to prevent unlimited growth of its allocated space.
             // But it might also slow down the predictions because the map would be reallocated more often.
-            if self.verbose {
-                println!("Deleted {} entries from prediction basis cache", to_remove.len());
-            }
+            debug!("Deleted {} entries from prediction basis cache", to_remove.len());
         }
         Ok(())
     }
@@ -226,29 +555,27 @@ impl<'a> Importer<'a>  {
     // was *very* ugly and has been deleted. We need a new way to handle success statistics
     // now that there are multiple possible import targets (record and/or predict). 
     fn _output_statistics(&self, statistics: ((u32, u32), (u32, u32), (u32, u32), (u32, u32))) {
-        if self.verbose {
-            println!("Finished processing files.");
-            println!(
-                "Schedule files   : {} of {} successful.",
-                (statistics.0).1,
-                (statistics.0).0
-            );
-            println!(
-                "Realtime files   : {} of {} successful.",
-                (statistics.1).1,
-                (statistics.1).0
-            );
-            println!(
-                "Trip updates     : {} of {} successful.",
-                (statistics.2).1,
-                (statistics.2).0
-            );
-            println!(
-                "Stop time updates: {} of {} successful.",
-                (statistics.3).1,
-                (statistics.3).0
-            );
-        }
+        debug!("Finished processing files.");
+        debug!(
+            "Schedule files   : {} of {} successful.",
+            (statistics.0).1,
+            (statistics.0).0
+        );
+        debug!(
+            "Realtime files   : {} of {} successful.",
+            (statistics.1).1,
+            (statistics.1).0
+        );
+        debug!(
+            "Trip updates     : {} of {} successful.",
+            (statistics.2).1,
+            (statistics.2).0
+        );
+        debug!(
+            "Stop time updates: {} of {} successful.",
+            (statistics.3).1,
+            (statistics.3).0
+        );
     }
 
     /// Construct the full directory paths used for storing input files and processed files
@@ -275,8 +602,8 @@ impl<'a> Importer<'a>  {
             if last_ping_time.is_none() || last_ping_time.unwrap() < Local::now() - Duration::minutes(1) {
                 perform_ping = true;
                 *last_ping_time = Some(Local::now());
-            } else if self.verbose {
-                println!("Last ping less then a minute ago, skip Pinging.");
+            } else {
+                debug!("Last ping less then a minute ago, skip Pinging.");
             }
             // If url_opt is None, perform_ping will be false anyway,
             // so we can perform the ping outside this block to
@@ -284,13 +611,23 @@ impl<'a> Importer<'a>  {
         }
 
         if perform_ping {
-            if self.verbose {
-                println!("Pinging URL {}", url_opt.unwrap());
-            }
+            debug!("Pinging URL {}", url_opt.unwrap());
             get(url_opt.unwrap()).call();
         }
     }
 
+    /// Archives the imported realtime files of past days, if selected. Failures are only logged,
+    /// because the files stay where they are and will be archived on the next day.
+    fn archive_realtime_files(&self) {
+        if let Some(archiver) = &self.rt_archiver {
+            if self.dry_run {
+                debug!("Dry run, not archiving realtime files.");
+            } else if let Err(e) = archiver.run_daily() {
+                error!("Could not archive realtime files: {}", e);
+            }
+        }
+    }
+
     /// Handle automatic mode and batch mode, which are very similar to each other
     fn run_as_non_manual(&self, is_automatic: bool) -> FnResult<()> {
         // ensure that the directory exists
@@ -299,45 +636,46 @@ impl<'a> Importer<'a>  {
         builder.create(self.target_dir.as_ref().unwrap())?; // if target dir can't be created, there's no good way to continue execution
         builder.create(self.fail_dir.as_ref().unwrap())?; // if fail dir can't be created, there's no good way to continue execution
         if is_automatic {
+            let settings = ScheduledPredictionSettings::from_args(self.args.subcommand_matches("automatic").unwrap())?;
             loop {
                 match self.process_all_files() {
                     Ok(true) => {
-                        if self.verbose {
-                            println!("Finished one iteration. Sleeping until next directory scan.");
-                        }
+                        debug!("Imported realtime data.");
                     },
                     Ok(false) => {
-                        match ScheduledPredictionsImporter::new(&self, self.verbose) {
-                            Ok(mut spi) => {
-                                if self.verbose {
-                                    println!("No realtime data to import. Starting to import predictions from schedule...");
-                                }
-                                match spi.make_scheduled_predictions() {
-                                    Ok(_) => { 
-                                        if self.verbose {
-                                            println!("Sucessfully imported some schedule-based predictions. Sleeping until next directory scan.");
-                                        }
-                                    },
-                                    Err(e) => {
-                                        eprintln!("Error while trying to import schedule-based predictions: {}. Sleeping until next directory scan.", e);
-                                    },
-                                }
-                            },
-                            Err(e) => {
-                                eprintln!("Could not initialize ScheduledPredictionsImporter: {}", e);
+                        debug!("No realtime data to import.");
+                    },
+                    Err(e) => error!(
+                        "Iteration failed with error: {}.",
+                        e
+                    ),
+                }
+                // Schedule-based predictions are made in small batches after each directory scan,
+                // so that they keep up even if there are always new realtime files.
+                if self.args.is_present("predict") {
+                    match ScheduledPredictionsImporter::new(&self, &settings) {
+                        Ok(mut spi) => {
+                            debug!("Starting to import predictions from schedule...");
+                            match spi.make_scheduled_predictions() {
+                                Ok(_) => { 
+                                    debug!("Sucessfully imported some schedule-based predictions. Sleeping until next directory scan.");
+                                },
+                                Err(e) => {
+                                    error!("Error while trying to import schedule-based predictions: {}. Sleeping until next directory scan.", e);
+                                },
                             }
+                        },
+                        Err(e) => {
+                            error!("Could not initialize ScheduledPredictionsImporter: {}", e);
                         }
                     }
-                    Err(e) => eprintln!(
-                        "Iteration failed with error: {}. Sleeping until next directory scan.",
-                        e
-                    ),
                 }
                 if self.perform_cleanup {
                     if let Err(e) = self.run_cleanup() {
-                        println!("Error during cleanup: {}", e);
+                        error!("Error during cleanup: {}", e);
                     }
                 }
+                self.archive_realtime_files();
                 self.ping_url();
 
                 thread::sleep(TIME_BETWEEN_DIR_SCANS);
@@ -345,26 +683,25 @@ impl<'a> Importer<'a>  {
         } else {
             match self.process_all_files() {
                 Ok(_) => {
-                    if self.verbose {
-                        println!("Finished.");
-                    }
+                    debug!("Finished.");
                 }
-                Err(e) => eprintln!("Failed with error: {}.", e),
+                Err(e) => error!("Failed with error: {}.", e),
             }
             if self.perform_cleanup {
                 self.run_cleanup()?;
             }
+            self.archive_realtime_files();
             return Ok(());
         }
     }
 
     fn process_all_files(&self) -> FnResult<bool> {
-        if self.verbose {
-            println!("Scan directory");
-        }
-        // list files in both directories
-        let mut schedule_filenames = read_dir_simple(&self.schedule_dir.as_ref().unwrap())?;
-        let rt_filenames = read_dir_simple(&self.rt_dir.as_ref().unwrap())?;
+        debug!("Scan directory");
+        // list files in both directories, including their date-based subdirectories
+        let mut schedule_filenames = read_dir_recursive(&self.schedule_dir.as_ref().unwrap())?;
+        let rt_filenames = read_dir_recursive(&self.rt_dir.as_ref().unwrap())?;
+
+        let rt_filenames = self.skip_duplicate_files(rt_filenames)?;
 
         if rt_filenames.is_empty() {
             return Ok(false); //false for "no realtime files imported"
@@ -389,10 +726,10 @@ impl<'a> Importer<'a>  {
                 Err(e) => {
                     match &self.fail_dir {
                         Some(d) => {
-                            Importer::move_file_to_dir(&rt_filename, &d)?;
-                            eprintln!("Rt file {} does not contain a valid date and was moved to {}. (Error was {})", rt_filename, d, e);
+                            self.move_file_to_dir(&rt_filename, &d)?;
+                            error!("Rt file {} does not contain a valid date and was moved to {}. (Error was {})", rt_filename, d, e);
                         }
-                        None => eprintln!(
+                        None => error!(
                             "Rt file {} does not contain a valid date. (Error was {})",
                             rt_filename, e
                         ),
@@ -402,7 +739,7 @@ impl<'a> Importer<'a>  {
             };
 
             if rt_date < oldest_schedule_date {
-                eprintln!(
+                warn!(
                     "Realtime data {} is older than any schedule, skipping.",
                     rt_filename
                 );
@@ -416,10 +753,10 @@ impl<'a> Importer<'a>  {
                     Err(e) => {
                         match &self.fail_dir {
                             Some(d) => {
-                                Importer::move_file_to_dir(schedule_filename, &d)?;
-                                eprintln!("Schedule file {} does not contain a valid date and was moved to {}. (Error was {})", schedule_filename, d, e);
+                                self.move_file_to_dir(schedule_filename, &d)?;
+                                error!("Schedule file {} does not contain a valid date and was moved to {}. (Error was {})", schedule_filename, d, e);
                             }
-                            None => eprintln!(
+                            None => error!(
                                 "Schedule file {} does not contain a valid date. (Error was {})",
                                 schedule_filename, e
                             ),
@@ -436,7 +773,7 @@ impl<'a> Importer<'a>  {
                                 &current_schedule_file,
                                 &realtime_files_for_current_schedule,
                             ) {
-                                 eprintln!("Error while working with schedule file {}: {}", current_schedule_file, e);
+                                 error!("Error while working with schedule file {}: {}", current_schedule_file, e);
                             }
                         }
                         // go on with the next schedule
@@ -453,31 +790,46 @@ impl<'a> Importer<'a>  {
         // process last schedule's collection
         if !realtime_files_for_current_schedule.is_empty() {
             if let Err(e) = self.process_schedule_and_realtimes(&current_schedule_file, &realtime_files_for_current_schedule) {
-                eprintln!("Error while working with schedule file {}: {}", current_schedule_file, e);
+                error!("Error while working with schedule file {}: {}", current_schedule_file, e);
             };
         }
         Ok(true)
     }
 
+    /// Returns the given realtime files without the duplicates (see `split_duplicates`), unless
+    /// `--force` is given. The skipped files are moved to the target dir.
+    fn skip_duplicate_files(&self, rt_filenames: Vec<String>) -> FnResult<Vec<String>> {
+        let force = self.args.is_present("force");
+        let (remaining, duplicates) = split_duplicates(rt_filenames, force, |hash| self.imported_files.find(hash))?;
+        for (rt_filename, earlier_filename) in duplicates {
+            info!("Realtime file {} has the same content as {}, skipping.", rt_filename, earlier_filename);
+            DryRunReport::add(&self.dry_run_report.duplicate_realtime_files, 1);
+            if let Some(dir) = &self.target_dir {
+                self.move_file_to_dir(&rt_filename, &dir)?;
+            }
+        }
+        Ok(remaining)
+    }
+
     /// Perform the import of one or more realtime data sets relating to a single schedule
     fn process_schedule_and_realtimes(
         &self,
         gtfs_schedule_filename: &str,
         gtfs_realtime_filenames: &Vec<String>,
     ) -> FnResult<()> {
-        if self.verbose {
-            println!("Parsing schedule…");
-        }
+        let span = info_span!("schedule", schedule = %gtfs_schedule_filename);
+        let _entered = span.enter();
+        debug!("Parsing schedule…");
 
         let schedule = match FileCache::get_cached_simple(&self.main.gtfs_cache, gtfs_schedule_filename) {
             Ok(schedule) => schedule,
             Err(e) => {
                 match &self.fail_dir {
                     Some(d) => {
-                        Importer::move_file_to_dir(gtfs_schedule_filename, &d)?;
-                        eprintln!("Schedule file {} could not be parsed and was moved to {}. (Error was {})", gtfs_schedule_filename, d, e);
+                        self.move_file_to_dir(gtfs_schedule_filename, &d)?;
+                        error!("Schedule file {} could not be parsed and was moved to {}. (Error was {})", gtfs_schedule_filename, d, e);
                     }
-                    None => eprintln!(
+                    None => error!(
                         "Schedule file {} could not be parsed. (Error was {})",
                         gtfs_schedule_filename, e
                     ),
@@ -486,18 +838,25 @@ impl<'a> Importer<'a>  {
             }
         };
 
-        if self.verbose {
-            println!("Importing realtime data…");
+        if self.args.is_present("predict") {
+            if let Err(e) = self.run_schedule_transition(gtfs_schedule_filename, &schedule) {
+                error!("Error while migrating predictions to schedule {}: {}", gtfs_schedule_filename, e);
+            }
         }
 
+        debug!("Importing realtime data…");
+
         let short_filename = &gtfs_schedule_filename[gtfs_schedule_filename.rfind('/').unwrap() + 1 ..];
 
         // create importer for this schedule and iterate over all given realtime files
-        let imp = PerScheduleImporter::new(schedule.clone(), &self, self.verbose, short_filename)?;
+        let imp = PerScheduleImporter::new(schedule.clone(), &self, short_filename)?;
 
-        let (success, total) = gtfs_realtime_filenames
+        // keep the context of the log messages in the worker threads. The trip updates of each file are
+        // processed in parallel as well, within the same thread pool.
+        let (success, total) = self.thread_pool.install(|| gtfs_realtime_filenames
             .par_iter()
             .map(|gtfs_realtime_filename| {
+                let _entered = span.enter();
                 match self.process_realtime(&gtfs_realtime_filename, &imp) {
                     Ok(()) => { 
                         // if a realtime file was successfull, send a ping
@@ -505,7 +864,7 @@ impl<'a> Importer<'a>  {
                         (1,1)
                     },
                     Err(e) => {
-                        eprintln!("Error while reading {}: {}", &gtfs_realtime_filename, e);
+                        error!("Error while reading {}: {}", &gtfs_realtime_filename, e);
                         (0,1)
                     }
                 }
@@ -513,9 +872,52 @@ impl<'a> Importer<'a>  {
             .reduce(
                 || (0, 0),
                 |(a_s, a_t), (b_s, b_t)| (a_s + b_s, a_t + b_t),
-            );
-        if self.verbose {
-            println!("Done with realtime files, {} of {} successfull!", success, total);
+            ));
+        debug!("Done with realtime files, {} of {} successfull!", success, total);
+        if self.dry_run {
+            let (record_count, prediction_count) = imp.parameter_set_counts();
+            DryRunReport::add(&self.dry_run_report.realtime_files, success);
+            DryRunReport::add(&self.dry_run_report.failed_realtime_files, total - success);
+            DryRunReport::add(&self.dry_run_report.records, record_count);
+            DryRunReport::add(&self.dry_run_report.predictions, prediction_count);
+        }
+        Ok(())
+    }
+
+    /// When a new schedule is used for the first time, trip_ids may have changed compared to the
+    /// previous schedule. This maps the trips of both schedules onto each other, migrates the 
+    /// predictions based on the previous schedule and deletes those of trips that don't exist anymore.
+    /// This is done at most once per schedule, which is remembered in the database across runs.
+    fn run_schedule_transition(&self, gtfs_schedule_filename: &str, schedule: &Gtfs) -> FnResult<()> {
+        if !self.schedule_transitions_done.lock().unwrap().insert(gtfs_schedule_filename.to_string()) {
+            return Ok(());
+        }
+        let short_filename = |f: &str| Path::new(f).file_name().unwrap().to_string_lossy().to_string();
+        if ScheduleTransition::is_done(&self.main.pool, &self.main.source, &short_filename(gtfs_schedule_filename))? {
+            debug!("Predictions have already been migrated to schedule {}.", gtfs_schedule_filename);
+            return Ok(());
+        }
+
+        // find the schedule that precedes the given one in the schedule directory, which may be in another date-based subdirectory
+        let schedule_dir = match &self.schedule_dir {
+            Some(schedule_dir) => String::from(schedule_dir),
+            None => String::from(Path::new(gtfs_schedule_filename).parent().or_error("Schedule has no parent directory")?.to_str().or_error("Invalid schedule directory")?),
+        };
+        let schedule_filenames = read_dir_recursive(&schedule_dir)?;
+        let index = schedule_filenames.iter().position(|f| Path::new(f) == Path::new(gtfs_schedule_filename)).or_error("Schedule not found in its directory")?;
+        if index == 0 {
+            return Ok(()); // there is no previous schedule
+        }
+        let old_schedule_filename = &schedule_filenames[index - 1];
+
+        debug!("Computing schedule transition from {} to {}…", old_schedule_filename, gtfs_schedule_filename);
+        let old_schedule = Gtfs::load(old_schedule_filename)?;
+        let transition = ScheduleTransition::new(&old_schedule, schedule);
+        debug!("Schedule transition maps {} trips and removes {} trips. Updating predictions…", transition.trip_mapping.len(), transition.removed_trip_ids.len());
+
+        transition.apply_to_predictions(&self, &short_filename(old_schedule_filename), &short_filename(gtfs_schedule_filename))?;
+        if !self.dry_run {
+            ScheduleTransition::set_done(&self.main.pool, &self.main.source, &short_filename(gtfs_schedule_filename))?;
         }
         Ok(())
     }
@@ -526,38 +928,65 @@ impl<'a> Importer<'a>  {
         gtfs_realtime_filename: &str,
         imp: &PerScheduleImporter,
     ) -> FnResult<()> {
-        if let Err(e) = imp.handle_realtime_file(&gtfs_realtime_filename) {
-            // Don't print the error itself, because it will be handled by the calling function
-            eprintln!("Error in realtime file, moving to fail_dir…");
-            if let Some(dir) = &self.fail_dir {
-                Importer::move_file_to_dir(gtfs_realtime_filename, &dir)?;
+        let imported_file = match imp.handle_realtime_file(&gtfs_realtime_filename) {
+            Ok(imported_file) => imported_file,
+            Err(e) => {
+                // Don't print the error itself, because it will be handled by the calling function
+                error!("Error in realtime file, moving to fail_dir…");
+                if let Some(dir) = &self.fail_dir {
+                    self.move_file_to_dir(gtfs_realtime_filename, &dir)?;
+                }
+                return Err(e);
             }
-            return Err(e);
         };
-        // TODO possibly make an error file per failed file to capture the error in place
-        if self.verbose {
-            println!("Finished importing file: {}", &gtfs_realtime_filename);
-        } else {
-            println!("{}", &gtfs_realtime_filename);
+        if self.dry_run {
+            info!("Finished processing file {} (dry run)", &gtfs_realtime_filename);
+            return Ok(());
+        }
+        // the data has been imported anyway, so this is no reason to fail
+        let short_filename = Path::new(gtfs_realtime_filename).file_name().unwrap().to_string_lossy(); // assume that the filename does not end in `..` because we got it from a directory listing
+        if let Err(e) = self.imported_files.insert(&short_filename, &imported_file) {
+            warn!("Could not remember content of {}: {}", gtfs_realtime_filename, e);
         }
+        // TODO possibly make an error file per failed file to capture the error in place
+        info!("Finished importing file {}", &gtfs_realtime_filename);
         // move file into target_dir if target_dir is defined
         if let Some(dir) = &self.target_dir {
-            Importer::move_file_to_dir(gtfs_realtime_filename, &dir)?;
+            self.move_file_to_dir(gtfs_realtime_filename, &dir)?;
         }
         Ok(())
     }
 
-    fn move_file_to_dir(filename: &str, dir: &String) -> FnResult<()> {
+    fn move_file_to_dir(&self, filename: &str, dir: &String) -> FnResult<()> {
+        if self.dry_run {
+            debug!("Dry run, not moving {} to {}.", filename, dir);
+            return Ok(());
+        }
         let mut target_path = PathBuf::from(dir);
-        target_path.push(Path::new(&filename).file_name().unwrap()); // assume that the filename does not end in `..` because we got it from a directory listing
+        // files from date-based subdirectories of the realtime directory keep their subdirectories, so that their date is still known
+        let relative_path = self.rt_dir.as_ref().and_then(|rt_dir| Path::new(filename).strip_prefix(rt_dir).ok());
+        match relative_path {
+            Some(relative_path) => {
+                target_path.push(relative_path);
+                std::fs::create_dir_all(target_path.parent().unwrap())?; // has at least dir as parent
+            },
+            None => target_path.push(Path::new(&filename).file_name().unwrap()), // assume that the filename does not end in `..` because we got it from a directory listing
+        }
         std::fs::rename(filename, target_path)?;
         Ok(())
     }
 }
 
-pub fn get_predictions_statements(pool: Arc<Pool>) -> FnResult<BatchedStatements> {
-    let mut conn = pool.get_conn()?;
-    let update_statement = conn.prep(r"UPDATE `predictions`
+/// Prepares the statements that write predictions. Each prediction replaces the existing prediction
+/// of the same vehicle and stop, unless that one has a better origin type. The vehicle is identified by
+/// its route, start date and time, direction and first stop, so that a new trip_id updates the predictions
+/// of the vehicle instead of duplicating them (see `vehicle_identity`).
+///
+/// Unlike the origin type, the precision type is not compared: a newer prediction of the same origin type
+/// always replaces the older one, even if that one was based on more specific curves, because it is based
+/// on newer data. So all statements agree on which prediction wins.
+pub fn get_predictions_statements(pool: Arc<Pool>, settings: BatchSettings, dry_run: bool) -> FnResult<BatchedStatements> {
+    let update_statement = r"UPDATE `predictions`
     SET 
         `stop_id` = :stop_id,
         `prediction_min` = :prediction_min,
@@ -566,7 +995,9 @@ pub fn get_predictions_statements(pool: Arc<Pool>) -> FnResult<BatchedStatements
         `origin_type` = :origin_type,
         `sample_size` = :sample_size,
         `prediction_curve` = :prediction_curve,
-        `schedule_file_name` = :schedule_file_name
+        `schedule_file_name` = :schedule_file_name,
+        `direction_id` = :direction_id,
+        `first_stop_id` = :first_stop_id
         WHERE
         `source` = :source AND
         `event_type` = :event_type AND
@@ -574,9 +1005,40 @@ pub fn get_predictions_statements(pool: Arc<Pool>) -> FnResult<BatchedStatements
         `route_id` = :route_id AND
         `trip_id` = :trip_id AND
         `trip_start_date` = :trip_start_date AND
-        `trip_start_time` = :trip_start_time;").expect("Could not prepare update statement"); // Should never happen because of hard-coded statement string
+        `trip_start_time` = :trip_start_time AND
+        `origin_type` >= :origin_type;";
+
+    // Predictions for the same vehicle and stop, but with a different trip_id (probably from an
+    // outdated schedule), get the new trip_id. If the vehicle already has a prediction with the new
+    // trip_id, the update is ignored, and the delete statement below removes the old prediction.
+    let resolve_statement = r"UPDATE IGNORE `predictions`
+    SET 
+        `trip_id` = :trip_id,
+        `stop_id` = :stop_id,
+        `prediction_min` = :prediction_min,
+        `prediction_max` = :prediction_max,
+        `precision_type` = :precision_type,
+        `origin_type` = :origin_type,
+        `sample_size` = :sample_size,
+        `prediction_curve` = :prediction_curve,
+        `schedule_file_name` = :schedule_file_name,
+        `direction_id` = :direction_id,
+        `first_stop_id` = :first_stop_id
+        WHERE
+        `source` = :source AND
+        `event_type` = :event_type AND
+        `stop_sequence` = :stop_sequence AND
+        `route_id` = :route_id AND
+        `trip_id` != :trip_id AND
+        `trip_start_date` = :trip_start_date AND
+        `trip_start_time` = :trip_start_time AND
+        (`direction_id` IS NULL OR :direction_id IS NULL OR `direction_id` = :direction_id) AND
+        (`first_stop_id` IS NULL OR :first_stop_id IS NULL OR `first_stop_id` = :first_stop_id) AND
+        `origin_type` >= :origin_type;";
 
-    let insert_statement = conn.prep(r"INSERT IGNORE INTO `predictions` (
+    // Only insert if there is no prediction of a better origin type for the same vehicle and stop,
+    // which may exist with a different trip_id.
+    let insert_statement = r"INSERT IGNORE INTO `predictions` (
         `source`,
         `event_type`,
         `stop_id`,
@@ -591,8 +1053,10 @@ pub fn get_predictions_statements(pool: Arc<Pool>) -> FnResult<BatchedStatements
         `origin_type`,
         `sample_size`,
         `prediction_curve`,
-        `schedule_file_name`
-    ) VALUES ( 
+        `schedule_file_name`,
+        `direction_id`,
+        `first_stop_id`
+    ) SELECT
         :source,
         :event_type,
         :stop_id,
@@ -607,10 +1071,108 @@ pub fn get_predictions_statements(pool: Arc<Pool>) -> FnResult<BatchedStatements
         :origin_type,
         :sample_size,
         :prediction_curve,
-        :schedule_file_name
-    );")
-    .expect("Could not prepare insert statement"); // Should never happen because of hard-coded statement string
+        :schedule_file_name,
+        :direction_id,
+        :first_stop_id
+    FROM DUAL WHERE NOT EXISTS (SELECT 1 FROM `predictions` WHERE
+        `source` = :source AND
+        `event_type` = :event_type AND
+        `stop_sequence` = :stop_sequence AND
+        `route_id` = :route_id AND
+        `trip_start_date` = :trip_start_date AND
+        `trip_start_time` = :trip_start_time AND
+        (`direction_id` IS NULL OR :direction_id IS NULL OR `direction_id` = :direction_id) AND
+        (`first_stop_id` IS NULL OR :first_stop_id IS NULL OR `first_stop_id` = :first_stop_id) AND
+        `origin_type` < :origin_type
+    );";
+
+    // Predictions for the same vehicle and stop with a different trip_id, which could not get the
+    // new trip_id above, are superseded unless their origin type is better (regardless of the precision type, see above).
+    let delete_statement = r"DELETE FROM `predictions`
+        WHERE
+        `source` = :source AND
+        `event_type` = :event_type AND
+        `stop_sequence` = :stop_sequence AND
+        `route_id` = :route_id AND
+        `trip_id` != :trip_id AND
+        `trip_start_date` = :trip_start_date AND
+        `trip_start_time` = :trip_start_time AND
+        (`direction_id` IS NULL OR :direction_id IS NULL OR `direction_id` = :direction_id) AND
+        (`first_stop_id` IS NULL OR :first_stop_id IS NULL OR `first_stop_id` = :first_stop_id) AND
+        `origin_type` >= :origin_type;";
+
+    // TODO: update where old.time_of_recording < new.time_of_recording...; INSERT IGNORE...;
+    BatchedStatements::new("predictions", &pool, &[update_statement, resolve_statement, insert_statement, delete_statement], settings, dry_run)
+}
+
+/// Adds a column to a table of the database, if it doesn't exist yet. The tables themselves are not
+/// created by the importer, but databases from before the column need to be migrated. In a dry run,
+/// the missing column is only reported.
+pub fn add_column(pool: &Pool, table: &str, column: &str, definition: &str, dry_run: bool) -> FnResult<()> {
+    let mut con = pool.get_conn()?;
+    let count: Option<usize> = con.exec_first(r"SELECT COUNT(*) FROM information_schema.COLUMNS
+        WHERE `TABLE_SCHEMA` = DATABASE() AND `TABLE_NAME` = :table AND `COLUMN_NAME` = :column;", params! { table, column })?;
+    if count == Some(0) {
+        if dry_run {
+            info!("Dry run, so column {} is not added to table {}.", column, table);
+        } else {
+            info!("Adding column {} to table {}.", column, table);
+            con.query_drop(format!(r"ALTER TABLE `{}` ADD COLUMN `{}` {};", table, column, definition))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn get_record_statements(pool: Arc<Pool>, settings: BatchSettings, dry_run: bool) -> FnResult<BatchedStatements> {
+    let update_statement = r"UPDATE `records`
+    SET 
+        `stop_id` = :stop_id,
+        `time_of_recording` = FROM_UNIXTIME(:time_of_recording),
+        `delay_arrival` = :delay_arrival,
+        `delay_departure` = :delay_departure,
+        `schedule_file_name` = :schedule_file_name,
+        `feed_skew` = :feed_skew
+    WHERE 
+        `source` = :source AND
+        `route_id` = :route_id AND
+        `route_variant` = :route_variant AND
+        `trip_id` = :trip_id AND
+        `trip_start_date` = :trip_start_date AND
+        `trip_start_time` = :trip_start_time AND
+        `stop_sequence` = :stop_sequence AND
+        `time_of_recording` < FROM_UNIXTIME(:time_of_recording);";
+
+    
+    let insert_statement = r"INSERT IGNORE INTO `records` (
+        `source`, 
+        `route_id`,
+        `route_variant`,
+        `trip_id`,
+        `trip_start_date`,
+        `trip_start_time`,
+        `stop_sequence`,
+        `stop_id`,
+        `time_of_recording`,
+        `delay_arrival`,
+        `delay_departure`,
+        `schedule_file_name`,
+        `feed_skew`
+    ) VALUES ( 
+        :source,
+        :route_id,
+        :route_variant,
+        :trip_id,
+        :trip_start_date,
+        :trip_start_time,
+        :stop_sequence,
+        :stop_id,
+        FROM_UNIXTIME(:time_of_recording),
+        :delay_arrival,
+        :delay_departure, 
+        :schedule_file_name,
+        :feed_skew
+    );";
 
     // TODO: update where old.time_of_recording < new.time_of_recording...; INSERT IGNORE...;
-    Ok(BatchedStatements::new("predictions", conn, vec![update_statement, insert_statement]))
-}
\ No newline at end of file
+    BatchedStatements::new("records", &pool, &[update_statement, insert_statement], settings, dry_run)
+}
@@ -1,35 +1,39 @@
-use chrono::{Duration, Local, DateTime};
+use chrono::{Duration, Local, TimeZone};
 use gtfs_rt::FeedMessage as GtfsRealtimeMessage;
 use gtfs_structures::{Gtfs, StopTime};
 use gtfs_structures::Trip as ScheduleTrip;
 use mysql::*;
-use prost::Message; // need to use this, otherwise GtfsRealtimeMessage won't have a `decode` method
 use simple_error::bail;
-use std::fs::File;
-use std::io::prelude::*;
-use mysql::prelude::*;
+use std::collections::HashMap;
 use std::sync::Arc;
 use rayon::prelude::*;
 
 use super::batched_statements::BatchedStatements;
-use super::{Importer, VehicleIdentifier, get_predictions_statements};
+use super::imported_files::{ImportedFile, read_realtime_file, content_hash};
+use super::{Importer, VehicleIdentifier, get_predictions_statements, get_record_statements};
+use super::feed_skew::get_time_of_recording;
+use super::vehicle_progress::VehicleProgress;
+use super::vehicle_identity::{get_direction_id, get_first_stop_id};
 use crate::types::PredictionResult;
+use crate::types::curve_format::encode_compact;
 
-use crate::{FnResult, OrError, date_and_time_local};
-use crate::types::{EventType, GetByEventType, PredictionBasis, CurveData, OriginType, GtfsDateTime};
-use crate::predictor::Predictor;
+use crate::{FnResult, OrError};
+use crate::time_util::date_and_time;
+use crate::types::{EventType, GetByEventType, Id, PredictionBasis, CurveData, OriginType, GtfsDateTime, FeedQuirks};
+use crate::predictor::{Predictor, PredictionTarget, PredictionContext, StatisticsModel, BlockIndex, mix_curves};
 use dystonse_curves::Curve;
 
 pub struct PerScheduleImporter<'a> {
     importer: &'a Importer<'a>,
     gtfs_schedule: Arc<Gtfs>,
-    verbose: bool,
     filename: &'a str,
     record_statements: Option<BatchedStatements>,
     predictions_statements: Option<BatchedStatements>,
     perform_record: bool,
     perform_predict: bool,
     predictor: Option<Predictor<'a>>,
+    shadow_model: Option<StatisticsModel>,
+    block_index: Option<BlockIndex>,
 }
 
 /// For an event (which may be an arrival or a departure), this struct
@@ -59,19 +63,19 @@ impl<'a> PerScheduleImporter<'a> {
     pub fn new(
         gtfs_schedule: Arc<Gtfs>,
         importer: &'a Importer,
-        verbose: bool,
         filename: &'a str,
     ) -> FnResult<PerScheduleImporter<'a>> {
         let mut instance = PerScheduleImporter {
             gtfs_schedule: Arc::clone(&gtfs_schedule),
             importer,
-            verbose,
             filename,
             record_statements: None,
             predictions_statements: None,
             perform_record: importer.args.is_present("record"),
             perform_predict: importer.args.is_present("predict"),
             predictor: None,
+            shadow_model: None,
+            block_index: None,
         };
 
         if instance.perform_record {
@@ -82,9 +86,16 @@ impl<'a> PerScheduleImporter<'a> {
                 Ok(predictor) => { 
                     instance.predictor = Some(predictor); 
                     instance.init_predictions_statements()?;
+                    instance.block_index = Some(BlockIndex::new(&gtfs_schedule));
+                    if let Some(shadow_evaluation) = &importer.shadow_evaluation {
+                        match shadow_evaluation.get_candidate_model() {
+                            Ok(model) => instance.shadow_model = Some(model),
+                            Err(e) => error!("Could not load candidate statistics for shadow evaluation: {}", e),
+                        }
+                    }
                 }
                 Err(e) => {
-                    println!("Disabling perform_predict. Reason: {}", e);
+                    warn!("Disabling perform_predict. Reason: {}", e);
                     instance.perform_predict = false;
                 }
             };
@@ -93,39 +104,69 @@ impl<'a> PerScheduleImporter<'a> {
         Ok(instance)
     }
 
-    pub fn handle_realtime_file(&self, path: &str) -> FnResult<()> {
-        let mut file = File::open(path)?;
-        let mut vec = Vec::<u8>::new();
-        if path.ends_with(".zip") {
-            let mut archive = zip::ZipArchive::new(file).or_error("Zip file not found.")?;
-            let mut zipped_file = archive.by_index(0).or_error("Zip file was empty")?;
-            if self.verbose {
-                println!("Reading {} from zip…", zipped_file.name());
-            }
-            zipped_file.read_to_end(&mut vec)?;
-        } else {
-            file.read_to_end(&mut vec)?;
-        }
+    pub fn handle_realtime_file(&self, path: &str) -> FnResult<ImportedFile> {
+        // adds the file name to all log messages about this file
+        let span = info_span!("realtime_file", file = %path);
+        let _enter = span.enter();
+
         // suboptimal, I'd rather not read the whole file into memory, but maybe Prost just works like this
-        let message = GtfsRealtimeMessage::decode(&vec)?;
+        let vec = read_realtime_file(path)?;
+        let message = self.importer.realtime_format.parse(&vec, &self.gtfs_schedule)?;
         let time_of_recording = message.header.timestamp.or_error(
             "No global timestamp in realtime data, skipping."
         )?;
+        let message = self.importer.differential_feed.to_full_dataset(message, time_of_recording);
 
+        // the forecast is fetched here, so that the weather API is never requested while predicting
+        if self.perform_predict {
+            if let Some(weather) = &self.importer.main.weather {
+                let time = Local.timestamp(time_of_recording as i64, 0);
+                weather.refresh(time, time);
+            }
+        }
         self.process_message(&message, time_of_recording)?;
-        Ok(())
+        if self.perform_record && !self.importer.dry_run {
+            self.record_weather(time_of_recording);
+        }
+        Ok(ImportedFile {
+            content_hash: content_hash(&vec),
+            header_timestamp: time_of_recording,
+        })
+    }
+
+    // stores the weather at the time of recording, so that the analyser can compute curves per weather condition
+    fn record_weather(&self, time_of_recording: u64) {
+        if let Some(weather) = &self.importer.main.weather {
+            let time = Local.timestamp(time_of_recording as i64, 0);
+            match weather.record(&self.importer.main.pool, &self.importer.main.source, time) {
+                Ok(condition) => debug!("Recorded weather condition {:?}.", condition),
+                // the delays are more important than the weather, so the import continues anyway
+                Err(e) => warn!("Could not record weather: {}", e),
+            }
+        }
     }
 
     fn process_message(&self, message: &GtfsRealtimeMessage, time_of_recording: u64) -> FnResult<()> { 
         // `message.entity` is actually a collection of entities
-        println!("Processing {} entitites in prallel.", message.entity.len());
+        debug!("Processing {} entitites in prallel.", message.entity.len());
+        // spans are per thread, so the context needs to be passed to the threads of rayon explicitly
+        let file_span = tracing::Span::current();
+        let vehicle_positions = if self.perform_predict && self.importer.use_vehicle_positions {
+            self.get_vehicle_positions(message, time_of_recording)
+        } else {
+            HashMap::new()
+        };
         let (success, total) = message.entity.par_iter().map(
             |entity| {
                 if let Some(trip_update) = &entity.trip_update {
-                    match self.process_trip_update(trip_update, time_of_recording) {
+                    let _file_enter = file_span.enter();
+                    let trip_id = trip_update.trip.trip_id.as_deref().unwrap_or("unknown");
+                    let trip_span = info_span!("trip_update", trip_id);
+                    let _trip_enter = trip_span.enter();
+                    match self.process_trip_update(trip_update, time_of_recording, &vehicle_positions) {
                         Ok(()) => (1, 1),
                         Err(e) => {
-                            println!("Error in process_trip_update: {}", e);
+                            warn!("Error in process_trip_update: {}", e);
                             (0, 1)
                         }
                     }
@@ -137,37 +178,91 @@ impl<'a> PerScheduleImporter<'a> {
             || (0, 0),
             |(a_s, a_t), (b_s, b_t)| (a_s + b_s, a_t + b_t),
         );
-        println!("Finished message, {} of {} successful.", success, total);
+        info!("Finished message, {} of {} successful.", success, total);
 
         if self.perform_record {
             self.record_statements.as_ref().unwrap().write_to_database()?;
         }
         if self.perform_predict {
             self.predictions_statements.as_ref().unwrap().write_to_database()?;
+            if let Some(prediction_events) = &self.importer.prediction_events {
+                prediction_events.publish();
+            }
+        }
+        if let (Some(shadow_evaluation), Some(_)) = (&self.importer.shadow_evaluation, &self.shadow_model) {
+            if let Err(e) = shadow_evaluation.write_report() {
+                error!("Could not write shadow evaluation report: {}", e);
+            }
         }
         Ok(())
     }
 
+    /// Finds out where the vehicles with a vehicle position in the message are on their trips.
+    fn get_vehicle_positions(&self, message: &GtfsRealtimeMessage, header_timestamp: u64) -> HashMap<VehicleIdentifier, VehicleProgress> {
+        let positions: HashMap<VehicleIdentifier, VehicleProgress> = message.entity.iter()
+            .filter_map(|entity| entity.vehicle.as_ref())
+            .filter_map(|vehicle| self.get_vehicle_progress(vehicle, header_timestamp))
+            .collect();
+        debug!("Found the progress of {} vehicles.", positions.len());
+        positions
+    }
+
+    fn get_vehicle_progress(&self, vehicle: &gtfs_rt::VehiclePosition, header_timestamp: u64) -> Option<(VehicleIdentifier, VehicleProgress)> {
+        let realtime_trip = vehicle.trip.as_ref()?;
+        let trip_id = realtime_trip.trip_id.as_ref()?;
+        let schedule_trip = self.gtfs_schedule.get_trip(trip_id).ok()?;
+        let progress = VehicleProgress::from_vehicle_position(vehicle, schedule_trip, header_timestamp)?;
+        // like stale trip updates, stale positions are not used for predictions
+        if let Some(max_delay_age) = self.importer.max_delay_age {
+            if header_timestamp as i64 - progress.timestamp as i64 > max_delay_age {
+                return None;
+            }
+        }
+        let scheduled_start = schedule_trip.stop_times.first()?.departure_time?;
+        let vehicle_id = VehicleIdentifier {
+            trip_id: Id::new(trip_id),
+            start: GtfsDateTime::from_trip_descriptor(realtime_trip).ok()?.with_scheduled_time(scheduled_start as i32),
+        };
+        Some((vehicle_id, progress))
+    }
+
     fn process_trip_update(
         &self,
         trip_update: &gtfs_rt::TripUpdate,
-        time_of_recording: u64,
+        header_timestamp: u64,
+        vehicle_positions: &HashMap<VehicleIdentifier, VehicleProgress>,
     ) -> FnResult<()> {
         let realtime_trip = &trip_update.trip;
         let route_id = &realtime_trip.route_id.as_ref().or_error("Trip needs route_id")?;
         let trip_id = &realtime_trip.trip_id.as_ref().or_error("Trip needs id")?;
-        let realtime_trip_start = GtfsDateTime::from_trip_descriptor(realtime_trip)?;
-     
+        if !self.importer.agency_filter.contains_route(&self.gtfs_schedule, route_id) {
+            return Ok(());
+        }
         let schedule_trip = self.gtfs_schedule.get_trip(&trip_id)
             .or_error(&format!("Did not find trip {} in schedule. Skipping.", trip_id))?;
 
         let schedule_start_time = Duration::seconds(schedule_trip.stop_times[0].departure_time.unwrap() as i64);
+        // use the service day of the schedule, even if the feed gives trips after midnight with the calendar date
+        let realtime_trip_start = GtfsDateTime::from_trip_descriptor(realtime_trip)?
+            .with_scheduled_time(schedule_start_time.num_seconds() as i32);
         let time_difference = realtime_trip_start.duration() - schedule_start_time;
         if !time_difference.is_zero() {
-            eprintln!("Trip {} has a difference of {} seconds between scheduled start times in schedule data and realtime data.", trip_id, time_difference);
+            warn!("Trip {} has a difference of {} seconds between scheduled start times in schedule data and realtime data.", trip_id, time_difference);
         }
 
-        let mut prediction_done = false;
+        let (time_of_recording, feed_skew) = get_time_of_recording(header_timestamp, trip_update.timestamp);
+        // stale delays are still recorded, but the existing predictions for the trip are kept
+        let mut prediction_done = match (feed_skew, self.importer.max_delay_age) {
+            (Some(skew), Some(max_delay_age)) if skew > max_delay_age => {
+                debug!("Skip trip {} for predictions, because its trip update is {} seconds older than the message.", trip_id, skew);
+                true
+            },
+            _ => false,
+        };
+        let vehicle_progress = vehicle_positions.get(&VehicleIdentifier {
+            trip_id: Id::new(trip_id),
+            start: realtime_trip_start.clone(),
+        });
         for stop_time_update in &trip_update.stop_time_update {
             
             let res = self.process_stop_time_update(
@@ -177,14 +272,16 @@ impl<'a> PerScheduleImporter<'a> {
                 &trip_id,
                 &route_id,
                 time_of_recording,
+                feed_skew,
+                vehicle_progress,
                 &mut prediction_done
             );
             if let Err(e) = res {
-                println!("Error with stop_time_update: {}", e);
+                warn!("Error with stop_time_update: {}", e);
             }
         }
         if self.perform_predict && !prediction_done {
-            println!("At the end, still no prediction.");
+            debug!("At the end, still no prediction.");
         }
 
         Ok(())
@@ -198,6 +295,8 @@ impl<'a> PerScheduleImporter<'a> {
         trip_id: &String,
         route_id: &String,
         time_of_recording: u64,
+        feed_skew: Option<i64>,
+        vehicle_progress: Option<&VehicleProgress>,
         prediction_done: &mut bool
     ) -> FnResult<()> {
         let start_date_time = start_gtfs_time.date_time();
@@ -207,17 +306,19 @@ impl<'a> PerScheduleImporter<'a> {
         let stop_sequence = stop_time_update.stop_sequence.or_error("no stop_sequence")?;
         let arrival = PerScheduleImporter::get_event_times(
             stop_time_update.arrival.as_ref(),
-            start_date_time,
+            start_gtfs_time,
             EventType::Arrival,
             &schedule_trip,
             stop_sequence,
+            &self.importer.main.feed_quirks,
         );
         let departure = PerScheduleImporter::get_event_times(
             stop_time_update.departure.as_ref(),
-            start_date_time,
+            start_gtfs_time,
             EventType::Departure,
             &schedule_trip,
             stop_sequence,
+            &self.importer.main.feed_quirks,
         );
 
         if arrival.is_empty() && departure.is_empty() {
@@ -231,14 +332,15 @@ impl<'a> PerScheduleImporter<'a> {
                 "route_id" => &route_id,
                 "route_variant" => &schedule_trip.route_variant.as_ref().or_error("no route variant")?,
                 "trip_id" => &trip_id,
-                "trip_start_date" => start_gtfs_time.service_day().naive_local(),
+                "trip_start_date" => start_gtfs_time.service_day_param(),
                 "trip_start_time" => start_gtfs_time.duration(),
                 stop_sequence,
                 "stop_id" => &stop_id,
                 time_of_recording,
                 "delay_arrival" => arrival.delay,
                 "delay_departure" => departure.delay,
-                "schedule_file_name" => self.filename
+                "schedule_file_name" => self.filename,
+                feed_skew
             }))?;
         }
 
@@ -249,13 +351,13 @@ impl<'a> PerScheduleImporter<'a> {
             // skip trips from too long ago:
             if start_date_time < (Local::now() - Duration::hours(12)) {
 
-                println!("Skip trip {} for predictions, because it happened more than 12 hours in the past.", trip_id);
+                debug!("Skip trip {} for predictions, because it happened more than 12 hours in the past.", trip_id);
                 *prediction_done = true; //because we can ignore this trip from now on
             
             // skip stop if we don't have a departure update:
             } else if departure.is_empty() {
 
-                println!("Skip stop_sequence {} for predictions, because departure is empty.", stop_sequence);
+                debug!("Skip stop_sequence {} for predictions, because departure is empty.", stop_sequence);
 
             // for a current trip, and stop with departure not empty, go on:
             } else {
@@ -263,12 +365,23 @@ impl<'a> PerScheduleImporter<'a> {
                 // TODO: instead of using the first stop for which we have data, 
                 // it would be better to use the most recent stop that is already in the past!
 
-                let basis = PredictionBasis { 
+                let mut basis = PredictionBasis { 
                     stop_sequence: stop_sequence as u16,
                     delay_departure: departure.delay
                 };
+                // if the vehicle position shows that the vehicle is already past that stop, predict from its position instead
+                let mut next_basis = None;
+                if let Some(progress) = vehicle_progress {
+                    if let Some(position_basis) = progress.get_basis(schedule_trip, start_gtfs_time) {
+                        if position_basis.stop_sequence > basis.stop_sequence || (position_basis.stop_sequence == basis.stop_sequence && !progress.stopped) {
+                            debug!("Predict trip {} from the position of the vehicle after stop_sequence {}.", trip_id, position_basis.stop_sequence);
+                            basis = position_basis;
+                            next_basis = progress.get_next_basis(schedule_trip, start_gtfs_time);
+                        }
+                    }
+                }
                 let vehicle_id = VehicleIdentifier {
-                    trip_id: trip_id.clone(),
+                    trip_id: Id::new(trip_id),
                     start: start_gtfs_time.clone(),
                 };
 
@@ -287,29 +400,46 @@ impl<'a> PerScheduleImporter<'a> {
 
                 //check if we can make any predictions for the future stops of this trip:
                 let mut actual_success = false; 
+                // the arrival at the last stop, from which the next trip of the vehicle is predicted
+                let mut last_arrival: Option<CurveData> = None;
+                let last_stop_sequence = schedule_trip.stop_times.last().map(|st| st.stop_sequence);
 
                 for stop_time in &schedule_trip.stop_times {
-                    if stop_time.stop_sequence as u32 > stop_sequence {
+                    if stop_time.stop_sequence > basis.stop_sequence {
                         for event_type in &EventType::TYPES {
                             match self.make_prediction(
                                 route_id,
                                 &vehicle_id,
                                 basis.clone(),
+                                next_basis.as_ref(),
                                 stop_time,
                                 **event_type
                             ) {
-                                Ok(()) => actual_success = true,
-                                Err(e) => println!("Prediction error: {}", e)
+                                Ok(curve_data) => {
+                                    actual_success = true;
+                                    if **event_type == EventType::Arrival && Some(stop_time.stop_sequence) == last_stop_sequence {
+                                        last_arrival = Some(curve_data);
+                                    }
+                                },
+                                Err(e) => warn!("Prediction error: {}", e)
                             }
                         }
                     }
                 }
                 if actual_success {
-                    let mut cpr = self.importer.current_prediction_basis.lock().unwrap();
-                    cpr.insert(vehicle_id, basis.clone());
+                    {
+                        let mut cpr = self.importer.current_prediction_basis.lock().unwrap();
+                        cpr.insert(vehicle_id.clone(), basis.clone());
+                    }
 
                     // We set this flag so that we don't do it all again for the following stop_time_updates:
                     *prediction_done = true;
+
+                    if let Some(last_arrival) = last_arrival {
+                        if let Err(e) = self.predict_next_trip_in_block(&vehicle_id, schedule_trip, &last_arrival) {
+                            warn!("Could not predict the next trip of the vehicle: {}", e);
+                        }
+                    }
                 }
             }
         }
@@ -322,28 +452,74 @@ impl<'a> PerScheduleImporter<'a> {
         route_id: &String,
         vehicle_id: &VehicleIdentifier,
         actual_begin: PredictionBasis,
+        next_begin: Option<&(PredictionBasis, f32)>,
         scheduled_end: &StopTime,
         event_type: EventType,
-    ) -> FnResult<()> {
+    ) -> FnResult<CurveData> {
+        let basis = Some(actual_begin);
         let arrival_prediction = self.predictor.as_ref().unwrap().predict(
             &route_id,
             &vehicle_id.trip_id, 
-            &Some(actual_begin),
+            &basis,
             scheduled_end.stop_sequence,
             event_type, 
             vehicle_id.start.date_time())?;
         // TODO in the previous line, we used to compute a broken date time from the schedued_end
         // now we use the start, which has a valid date, but behaviour might change anyway.
+
+        // make the same prediction from the candidate statistics, which is only used for the shadow evaluation report
+        if let Some(shadow_model) = &self.shadow_model {
+            let target = PredictionTarget {
+                route_id,
+                trip: self.gtfs_schedule.get_trip(&vehicle_id.trip_id)?,
+                stop_sequence: scheduled_end.stop_sequence,
+                event_type,
+            };
+            let context = PredictionContext {
+                schedule: &self.gtfs_schedule,
+                holidays: &self.importer.main.holidays,
+                weather: self.importer.main.weather.as_ref(),
+                date_time: vehicle_id.start.date_time(),
+            };
+            self.importer.shadow_evaluation.as_ref().unwrap().compare(shadow_model, &arrival_prediction, &basis, &target, &context);
+        }
             
-        let curve_data : CurveData = match arrival_prediction {
+        let mut curve_data : CurveData = match arrival_prediction {
             PredictionResult::CurveData(curve_data) => curve_data,
             _ => bail!("Result of unexpected type, can't write to DB!")
         };
 
+        // for a vehicle between two stops, the prediction from the next stop is mixed in by the progress of the vehicle
+        if let Some((next_basis, weight)) = next_begin.filter(|(next_basis, _)| scheduled_end.stop_sequence > next_basis.stop_sequence) {
+            match self.predictor.as_ref().unwrap().predict(
+                &route_id,
+                &vehicle_id.trip_id,
+                &Some(next_basis.clone()),
+                scheduled_end.stop_sequence,
+                event_type,
+                vehicle_id.start.date_time()) {
+                Ok(PredictionResult::CurveData(next_curve_data)) => curve_data.curve = mix_curves(&next_curve_data.curve, &curve_data.curve, *weight),
+                _ => debug!("No prediction from stop_sequence {}, using the one from the previous stop only.", next_basis.stop_sequence),
+            }
+        }
+
+        self.add_prediction(route_id, vehicle_id, scheduled_end, event_type, &curve_data)?;
+        Ok(curve_data)
+    }
+
+    // writes a prediction that is based on realtime data into the database
+    fn add_prediction(
+        &self,
+        route_id: &str,
+        vehicle_id: &VehicleIdentifier,
+        scheduled_end: &StopTime,
+        event_type: EventType,
+        curve_data: &CurveData,
+    ) -> FnResult<()> {
         let scheduled_event_time = event_type.get_time_from_stop_time(scheduled_end).unwrap();
 
-        let prediction_min = date_and_time_local(&vehicle_id.start.date(), scheduled_event_time + curve_data.curve.min_x() as i32);
-        let prediction_max = date_and_time_local(&vehicle_id.start.date(), scheduled_event_time + curve_data.curve.max_x() as i32);
+        let prediction_min = date_and_time(&vehicle_id.start.service_day(), scheduled_event_time + curve_data.curve.min_x() as i32);
+        let prediction_max = date_and_time(&vehicle_id.start.service_day(), scheduled_event_time + curve_data.curve.max_x() as i32);
         
         self.predictions_statements.as_ref().unwrap().add_parameter_set(Params::from(params! {
             "source" => self.importer.main.source.clone(),
@@ -353,45 +529,99 @@ impl<'a> PerScheduleImporter<'a> {
             "prediction_max" => prediction_max.naive_local(),
             route_id,
             "trip_id" => vehicle_id.trip_id.clone(),
-            "trip_start_date" => vehicle_id.start.service_day().naive_local(),
+            "trip_start_date" => vehicle_id.start.service_day_param(),
             "trip_start_time" => vehicle_id.start.duration(),
             "stop_sequence" => scheduled_end.stop_sequence,
             "precision_type" => curve_data.precision_type.to_int(),
             "origin_type" => OriginType::Realtime.to_int(),
             "sample_size" => curve_data.sample_size,
-            "prediction_curve" => curve_data.curve.serialize_compact_limited(120),
-            "schedule_file_name" => self.filename
+            "prediction_curve" => encode_compact(&curve_data.curve),
+            "schedule_file_name" => self.filename,
+            "direction_id" => get_direction_id(&self.gtfs_schedule, &vehicle_id.trip_id),
+            "first_stop_id" => get_first_stop_id(&self.gtfs_schedule, &vehicle_id.trip_id),
         }))?;
+        if let Some(prediction_events) = &self.importer.prediction_events {
+            prediction_events.add(&scheduled_end.stop.id, &vehicle_id.trip_id);
+        }
+        Ok(())
+    }
+
+    /// Predicts the trip that the vehicle will operate after the given one, from the predicted arrival at the
+    /// last stop (see `BlockIndex`). Trips for which there is realtime data are predicted from that instead.
+    fn predict_next_trip_in_block(&self, vehicle_id: &VehicleIdentifier, schedule_trip: &ScheduleTrip, last_arrival: &CurveData) -> FnResult<()> {
+        let service_day = vehicle_id.start.service_day();
+        let (next_trip, layover) = match self.block_index.as_ref().unwrap().get_next_trip(&self.gtfs_schedule, schedule_trip, service_day) {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+        let next_start = next_trip.stop_times.first().and_then(|st| st.departure_time).or_error("Next trip has no departure time")?;
+        let next_vehicle_id = VehicleIdentifier {
+            trip_id: Id::new(&next_trip.id),
+            start: GtfsDateTime::new(service_day, next_start as i32),
+        };
+        if self.importer.current_prediction_basis.lock().unwrap().contains_key(&next_vehicle_id) {
+            return Ok(());
+        }
+
+        let predictor = self.predictor.as_ref().unwrap();
+        for stop_time in &next_trip.stop_times {
+            for event_type in &EventType::TYPES {
+                if stop_time.get_time(**event_type).is_none() {
+                    continue;
+                }
+                let prediction = predictor.predict_after_previous_trip(
+                    &next_trip.route_id,
+                    &next_trip.id,
+                    last_arrival,
+                    layover,
+                    stop_time.stop_sequence,
+                    **event_type,
+                    next_vehicle_id.start.date_time(),
+                )?;
+                match prediction {
+                    PredictionResult::CurveData(curve_data) => self.add_prediction(&next_trip.route_id, &next_vehicle_id, stop_time, **event_type, &curve_data)?,
+                    _ => bail!("Result of unexpected type, can't write to DB!"),
+                }
+            }
+        }
+        debug!("Predicted trip {} from the end of trip {}.", next_trip.id, schedule_trip.id);
         Ok(())
     }
 
     fn get_event_times(
         event: Option<&gtfs_rt::trip_update::StopTimeEvent>,
-        start_date_time: DateTime<Local>,
+        start_gtfs_time: &GtfsDateTime,
         event_type: EventType,
         schedule_trip: &ScheduleTrip,
         stop_sequence: u32,
+        feed_quirks: &FeedQuirks,
     ) -> EventTimes {
-        let delay = if let Some(event) = event {
-            if let Some(delay) = event.delay {
-                delay as i64
-            } else {
-                eprintln!("Stop time update {:?} without delay. Skipping.", event_type);
-                return EventTimes::empty();
-            }
-        } else {
-            return EventTimes::empty();
+        let event = match event {
+            Some(event) => event,
+            None => return EventTimes::empty(),
         };
 
         let potential_stop_time = schedule_trip.stop_times.iter().filter(|st| st.stop_sequence == stop_sequence as u16).nth(0);
         let event_time = if let Some(stop_time) = potential_stop_time {
             stop_time.get_time(event_type)
         } else {
-            eprintln!("Realtime data references stop_sequence {}, which does not exist in trip {}.", stop_sequence, schedule_trip.id);
+            warn!("Realtime data references stop_sequence {}, which does not exist in trip {}.", stop_sequence, schedule_trip.id);
             // TODO return Error or something
             return EventTimes::empty();
         };
-        let schedule = start_date_time.timestamp() + event_time.expect("no arrival/departure time") as i64;
+        let schedule = date_and_time(&start_gtfs_time.service_day(), event_time.expect("no arrival/departure time") as i32).timestamp();
+
+        let delay = match get_delay(event, schedule) {
+            Some(delay) => delay,
+            None => {
+                warn!("Stop time update {:?} without delay or time. Skipping.", event_type);
+                return EventTimes::empty();
+            }
+        };
+        if !feed_quirks.is_trustworthy(delay) {
+            debug!("Stop time update {:?} with delay {}, which can't be trusted. Skipping.", event_type, delay);
+            return EventTimes::empty();
+        }
         let estimate = schedule + delay;
 
         EventTimes {
@@ -401,62 +631,48 @@ impl<'a> PerScheduleImporter<'a> {
         }
     }
 
-    fn init_record_statements(&mut self) -> FnResult<()> {
-        let mut conn = self.importer.main.pool.get_conn()?;
-        let update_statement = conn.prep(r"UPDATE `records`
-        SET 
-            `stop_id` = :stop_id,
-            `time_of_recording` = FROM_UNIXTIME(:time_of_recording),
-            `delay_arrival` = :delay_arrival,
-            `delay_departure` = :delay_departure,
-            `schedule_file_name` = :schedule_file_name
-        WHERE 
-            `source` = :source AND
-            `route_id` = :route_id AND
-            `route_variant` = :route_variant AND
-            `trip_id` = :trip_id AND
-            `trip_start_date` = :trip_start_date AND
-            `trip_start_time` = :trip_start_time AND
-            `stop_sequence` = :stop_sequence AND
-            `time_of_recording` < FROM_UNIXTIME(:time_of_recording);").expect("Could not prepare update statement"); // Should never happen because of hard-coded statement string
+    /// Number of records and predictions that have been computed so far.
+    pub fn parameter_set_counts(&self) -> (usize, usize) {
+        (
+            self.record_statements.as_ref().map_or(0, |statements| statements.parameter_set_count()),
+            self.predictions_statements.as_ref().map_or(0, |statements| statements.parameter_set_count()),
+        )
+    }
 
-        
-        let insert_statement = conn.prep(r"INSERT IGNORE INTO `records` (
-            `source`, 
-            `route_id`,
-            `route_variant`,
-            `trip_id`,
-            `trip_start_date`,
-            `trip_start_time`,
-            `stop_sequence`,
-            `stop_id`,
-            `time_of_recording`,
-            `delay_arrival`,
-            `delay_departure`,
-            `schedule_file_name`
-        ) VALUES ( 
-            :source,
-            :route_id,
-            :route_variant,
-            :trip_id,
-            :trip_start_date,
-            :trip_start_time,
-            :stop_sequence,
-            :stop_id,
-            FROM_UNIXTIME(:time_of_recording),
-            :delay_arrival,
-            :delay_departure, 
-            :schedule_file_name
-        );")
-        .expect("Could not prepare insert statement"); // Should never happen because of hard-coded statement string
-
-        // TODO: update where old.time_of_recording < new.time_of_recording...; INSERT IGNORE...;
-        self.record_statements = Some(BatchedStatements::new("records", conn, vec![update_statement, insert_statement]));
+    fn init_record_statements(&mut self) -> FnResult<()> {
+        self.record_statements = Some(get_record_statements(self.importer.main.pool.clone(), self.importer.batch_settings, self.importer.dry_run)?);
         Ok(())
     }
 
     fn init_predictions_statements(&mut self) -> FnResult<()> {
-        self.predictions_statements = Some(get_predictions_statements(self.importer.main.pool.clone())?);
+        self.predictions_statements = Some(get_predictions_statements(self.importer.main.pool.clone(), self.importer.batch_settings, self.importer.dry_run)?);
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Returns the delay of a stop time event in seconds, for the scheduled time as unix timestamp. Some feeds
+/// only give the absolute time of the event, then the delay is computed from it. If both are given, the delay
+/// is used, because that's what the feed means for sure, while its clock might differ from ours.
+fn get_delay(event: &gtfs_rt::trip_update::StopTimeEvent, schedule: i64) -> Option<i64> {
+    match (event.delay, event.time) {
+        (Some(delay), _) => Some(delay as i64),
+        (None, Some(time)) => Some(time - schedule),
+        (None, None) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gtfs_rt::trip_update::StopTimeEvent;
+
+    #[test]
+    fn test_get_delay() {
+        let schedule = 1_600_000_000;
+        let with_delay = StopTimeEvent { delay: Some(-30), time: Some(schedule + 120), ..Default::default() };
+        assert_eq!(get_delay(&with_delay, schedule), Some(-30));
+        let with_time = StopTimeEvent { time: Some(schedule + 120), ..Default::default() };
+        assert_eq!(get_delay(&with_time, schedule), Some(120));
+        assert_eq!(get_delay(&StopTimeEvent::default(), schedule), None);
+    }
+}
@@ -0,0 +1,49 @@
+use clap::ArgMatches;
+use gtfs_rt::FeedMessage as GtfsRealtimeMessage;
+use gtfs_structures::Gtfs;
+use prost::Message; // need to use this, otherwise GtfsRealtimeMessage won't have a `decode` method
+use simple_error::bail;
+
+use crate::FnResult;
+use super::siri::SiriEtFormat;
+
+/// Names of all formats that can be selected with the `realtime-format` argument.
+pub const FORMAT_NAMES: [&str; 2] = [GtfsRealtimeFormat::NAME, SiriEtFormat::NAME];
+
+/// A format in which realtime data can be read. Each format converts its data into a
+/// GTFS realtime message, so that all formats share the same records/predictions pipeline.
+/// Formats are shared between the threads of the importer, so they need to be thread-safe.
+pub trait RealtimeFormat: Send + Sync {
+    /// short name of the format, as used on the command line
+    fn name(&self) -> &'static str;
+
+    /// Parses the content of one realtime file. The schedule is needed by formats
+    /// that don't reference GTFS trips directly.
+    fn parse(&self, data: &[u8], schedule: &Gtfs) -> FnResult<GtfsRealtimeMessage>;
+}
+
+/// Creates the format with the given name, using the format-specific args of the import command.
+pub fn create_format(name: &str, args: &ArgMatches) -> FnResult<Box<dyn RealtimeFormat>> {
+    match name {
+        GtfsRealtimeFormat::NAME => Ok(Box::new(GtfsRealtimeFormat {})),
+        SiriEtFormat::NAME => Ok(Box::new(SiriEtFormat::new(args.value_of("siri-trip-mapping"), args.value_of("siri-stop-mapping"))?)),
+        _ => bail!("Unknown realtime format: {}. Known formats are: {}", name, FORMAT_NAMES.join(", ")),
+    }
+}
+
+/// GTFS realtime protobuf data, which needs no conversion.
+pub struct GtfsRealtimeFormat {}
+
+impl GtfsRealtimeFormat {
+    pub const NAME: &'static str = "gtfs-rt";
+}
+
+impl RealtimeFormat for GtfsRealtimeFormat {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn parse(&self, data: &[u8], _schedule: &Gtfs) -> FnResult<GtfsRealtimeMessage> {
+        Ok(GtfsRealtimeMessage::decode(data)?)
+    }
+}
@@ -0,0 +1,208 @@
+use chrono::{Date, Duration, Local, NaiveDate};
+use clap::ArgMatches;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::imported_files::unpack_realtime_data;
+use crate::{FnResult, read_dir_simple, read_dir_recursive, date_from_filename};
+
+// higher levels are much slower, but hardly make the archives of protobuf data smaller
+const COMPRESSION_LEVEL: i32 = 9;
+
+/// Bundles the realtime files that have been imported into one compressed archive (`<date>.tar.zst`)
+/// per day, because millions of small files fill the disk and make directory listings slow.
+/// Only the files of past days are archived, because more files of today will arrive.
+pub struct RealtimeArchiver {
+    imported_dir: String,
+    archive_dir: String,
+    /// archives of days longer ago than this are deleted, if set
+    retention: Option<Duration>,
+    /// the day of the last run, so that the imported files are only listed once per day
+    last_run: Mutex<Option<Date<Local>>>,
+}
+
+impl RealtimeArchiver {
+    pub fn new(imported_dir: &str, archive_dir: &str, retention: Option<Duration>) -> Self {
+        RealtimeArchiver {
+            imported_dir: String::from(imported_dir),
+            archive_dir: String::from(archive_dir),
+            retention,
+            last_run: Mutex::new(None),
+        }
+    }
+
+    /// Returns an archiver for the imported files in `dir`, if archiving has been selected via the args.
+    pub fn from_args(args: &ArgMatches, dir: &str) -> FnResult<Option<Self>> {
+        if !args.is_present("archive-rt") {
+            return Ok(None);
+        }
+        let retention = match args.value_of("rt-retention") {
+            Some(retention) => Some(Duration::from_std(parse_duration::parse(retention)?)?),
+            None => None,
+        };
+        Ok(Some(Self::new(&format!("{}/imported", dir), &get_archive_dir(dir), retention)))
+    }
+
+    /// Runs the archiving, unless it has already been done today.
+    pub fn run_daily(&self) -> FnResult<()> {
+        let today = Local::today();
+        let mut last_run = self.last_run.lock().unwrap();
+        if *last_run == Some(today) {
+            return Ok(());
+        }
+        self.run()?;
+        *last_run = Some(today);
+        Ok(())
+    }
+
+    /// Moves the imported files of all past days into their archives, and deletes
+    /// the archives that are older than the retention time.
+    pub fn run(&self) -> FnResult<()> {
+        fs::create_dir_all(&self.archive_dir)?;
+        let today = Local::today();
+        let mut files_by_day: BTreeMap<NaiveDate, Vec<String>> = BTreeMap::new();
+        for filename in read_dir_recursive(&self.imported_dir)? {
+            match date_from_filename(&filename) {
+                Ok(date) if date < today => files_by_day.entry(date.naive_local()).or_insert_with(Vec::new).push(filename),
+                Ok(_) => {},
+                Err(e) => warn!("Not archiving {}: {}", filename, e),
+            }
+        }
+        for (date, filenames) in &files_by_day {
+            self.archive_day(*date, filenames)?;
+        }
+        if let Some(retention) = self.retention {
+            self.delete_archives_before((today - retention).naive_local())?;
+        }
+        Ok(())
+    }
+
+    /// Adds the files (which must be sorted by name) to the archive of the day and deletes them.
+    fn archive_day(&self, date: NaiveDate, filenames: &[String]) -> FnResult<()> {
+        let path = get_archive_path(&self.archive_dir, date);
+        // the archive is written under another name first, so that it is never incomplete
+        let temp_path = format!("{}.tmp", path);
+        let encoder = zstd::stream::write::Encoder::new(BufWriter::new(File::create(&temp_path)?), COMPRESSION_LEVEL)?;
+        let mut builder = tar::Builder::new(encoder);
+
+        // Files that arrive late are merged into the existing archive of their day,
+        // so that all files are still in the order in which they were downloaded.
+        let mut new_files = filenames.iter().peekable();
+        if Path::new(&path).exists() {
+            let mut archive = tar::Archive::new(zstd::stream::read::Decoder::new(BufReader::new(File::open(&path)?))?);
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                let entry_name = entry.path()?.to_string_lossy().into_owned();
+                while let Some(&filename) = new_files.peek() {
+                    if file_name(filename) >= entry_name.as_str() {
+                        break;
+                    }
+                    builder.append_path_with_name(filename, file_name(filename))?;
+                    new_files.next();
+                }
+                let header = entry.header().clone();
+                builder.append(&header, &mut entry)?;
+            }
+        }
+        for filename in new_files {
+            builder.append_path_with_name(filename, file_name(filename))?;
+        }
+        builder.into_inner()?.finish()?.flush()?;
+        fs::rename(&temp_path, &path)?;
+
+        for filename in filenames {
+            fs::remove_file(filename)?;
+        }
+        info!("Archived {} realtime files of {} into {}.", filenames.len(), date, path);
+        Ok(())
+    }
+
+    fn delete_archives_before(&self, oldest_date: NaiveDate) -> FnResult<()> {
+        for filename in read_dir_simple(&self.archive_dir)?.iter().filter(|filename| filename.ends_with(".tar.zst")) {
+            if let Ok(date) = date_from_filename(filename) {
+                if date.naive_local() < oldest_date {
+                    fs::remove_file(filename)?;
+                    info!("Deleted realtime archive {}, which is older than the retention time.", filename);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the default directory for the archives of realtime files.
+pub fn get_archive_dir(dir: &str) -> String {
+    format!("{}/rt_archive", dir)
+}
+
+/// Returns the path of the archive with the realtime files of the given day.
+pub fn get_archive_path(archive_dir: &str, date: NaiveDate) -> String {
+    format!("{}/{}.tar.zst", archive_dir, date.format("%Y-%m-%d"))
+}
+
+/// Calls `handle` with the name and the (unzipped) content of each file in the archive, in the order in
+/// which they were downloaded, without unpacking the whole archive. Returns the number of files.
+pub fn for_each_archived_file<F>(path: &str, mut handle: F) -> FnResult<usize>
+    where F: FnMut(&str, Vec<u8>) -> FnResult<()>
+{
+    let mut archive = tar::Archive::new(zstd::stream::read::Decoder::new(BufReader::new(File::open(path)?))?);
+    let mut count = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        handle(&name, unpack_realtime_data(&name, data)?)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+// the file name without the directory
+fn file_name(path: &str) -> &str {
+    Path::new(path).file_name().and_then(|name| name.to_str()).unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive_and_read_back() {
+        let dir = std::env::temp_dir().join(format!("rt_archive_test_{}", std::process::id()));
+        let imported_dir = dir.join("imported");
+        fs::create_dir_all(&imported_dir).unwrap();
+        let imported_dir = imported_dir.to_str().unwrap();
+        let archive_dir = get_archive_dir(dir.to_str().unwrap());
+        let archiver = RealtimeArchiver::new(imported_dir, &archive_dir, None);
+        fs::create_dir_all(&archive_dir).unwrap();
+        let date = NaiveDate::from_ymd(2020, 11, 5);
+
+        let write_file = |name: &str| {
+            let path = format!("{}/{}", imported_dir, name);
+            fs::write(&path, name.as_bytes()).unwrap();
+            path
+        };
+        let first = vec![write_file("2020-11-05T10:00:00.pb"), write_file("2020-11-05T12:00:00.pb")];
+        archiver.archive_day(date, &first).unwrap();
+        assert!(!Path::new(&first[0]).exists());
+
+        // a file that arrives late is merged into the existing archive
+        let late = vec![write_file("2020-11-05T11:00:00.pb")];
+        archiver.archive_day(date, &late).unwrap();
+
+        let mut names = Vec::new();
+        let count = for_each_archived_file(&get_archive_path(&archive_dir, date), |name, data| {
+            assert_eq!(data, name.as_bytes());
+            names.push(String::from(name));
+            Ok(())
+        }).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(names, vec!["2020-11-05T10:00:00.pb", "2020-11-05T11:00:00.pb", "2020-11-05T12:00:00.pb"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
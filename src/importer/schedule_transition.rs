@@ -0,0 +1,201 @@
+use gtfs_structures::{Gtfs, Trip};
+use mysql::*;
+use mysql::prelude::*;
+use std::collections::HashMap;
+
+use super::Importer;
+use super::batched_statements::BatchedStatements;
+use crate::FnResult;
+
+/// Identifies a trip independently of its trip_id, which may change between schedule versions.
+#[derive(Hash, PartialEq, Eq, Debug)]
+struct TripKey {
+    route_id: String,
+    start_time: Option<u32>,
+    stop_ids: Vec<String>,
+}
+
+impl TripKey {
+    fn from_trip(trip: &Trip) -> Self {
+        TripKey {
+            route_id: trip.route_id.clone(),
+            start_time: trip.stop_times.first().and_then(|st| st.departure_time),
+            stop_ids: trip.stop_times.iter().map(|st| st.stop.id.clone()).collect(),
+        }
+    }
+}
+
+/// Describes how the trips of one schedule version correspond to the trips of the next one.
+/// Trips are matched by their trip_id first, and by route, start time and stop pattern
+/// if the trip_id has changed.
+pub struct ScheduleTransition {
+    /// trips that exist in both schedules, as pairs of (old trip_id, new trip_id), which may be equal
+    pub trip_mapping: Vec<(String, String)>,
+    /// trips that can't be found in the new schedule (or whose trip_id now belongs to a different trip)
+    pub removed_trip_ids: Vec<String>,
+}
+
+impl ScheduleTransition {
+    /// Creates the table that remembers the schedules to which the predictions have already been migrated,
+    /// so that the migration isn't repeated after a restart of the importer.
+    pub fn create_table(pool: &Pool) -> FnResult<()> {
+        let mut con = pool.get_conn()?;
+        con.query_drop(r"CREATE TABLE IF NOT EXISTS `schedule_transitions` (
+            `source` VARCHAR(255) NOT NULL,
+            `schedule_file_name` VARCHAR(255) NOT NULL,
+            `time_of_transition` TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            PRIMARY KEY (`source`, `schedule_file_name`)
+        );")?;
+        Ok(())
+    }
+
+    /// Whether the predictions of the source have already been migrated to the schedule with the given (short) file name.
+    pub fn is_done(pool: &Pool, source: &str, schedule_file_name: &str) -> FnResult<bool> {
+        let mut con = pool.get_conn()?;
+        let done: Option<String> = con.exec_first(
+            r"SELECT `schedule_file_name` FROM `schedule_transitions` WHERE `source` = :source AND `schedule_file_name` = :schedule_file_name",
+            params! {
+                source,
+                schedule_file_name,
+            },
+        )?;
+        Ok(done.is_some())
+    }
+
+    pub fn set_done(pool: &Pool, source: &str, schedule_file_name: &str) -> FnResult<()> {
+        let mut con = pool.get_conn()?;
+        con.exec_drop(
+            r"INSERT IGNORE INTO `schedule_transitions` (`source`, `schedule_file_name`) VALUES (:source, :schedule_file_name)",
+            params! {
+                source,
+                schedule_file_name,
+            },
+        )?;
+        Ok(())
+    }
+
+    pub fn new(old_schedule: &Gtfs, new_schedule: &Gtfs) -> Self {
+        let mut new_trips_by_key: HashMap<TripKey, Vec<&String>> = HashMap::new();
+        for (trip_id, trip) in &new_schedule.trips {
+            new_trips_by_key.entry(TripKey::from_trip(trip)).or_insert_with(Vec::new).push(trip_id);
+        }
+
+        let mut trip_mapping = Vec::new();
+        let mut removed_trip_ids = Vec::new();
+
+        for (old_trip_id, old_trip) in &old_schedule.trips {
+            let key = TripKey::from_trip(old_trip);
+
+            if let Ok(new_trip) = new_schedule.get_trip(old_trip_id) {
+                if TripKey::from_trip(new_trip) == key {
+                    trip_mapping.push((old_trip_id.clone(), old_trip_id.clone()));
+                    continue;
+                }
+            }
+
+            match new_trips_by_key.get(&key) {
+                // only migrate if the match is unambiguous
+                Some(new_trip_ids) if new_trip_ids.len() == 1 => {
+                    trip_mapping.push((old_trip_id.clone(), new_trip_ids[0].clone()));
+                },
+                _ => removed_trip_ids.push(old_trip_id.clone()),
+            }
+        }
+
+        ScheduleTransition {
+            trip_mapping,
+            removed_trip_ids,
+        }
+    }
+
+    /// Migrates the predictions of mapped trips to the new trip_ids and schedule file, and deletes
+    /// the predictions of removed trips. Predictions that could not be migrated because the
+    /// new trip already has its own prediction are deleted as well.
+    pub fn apply_to_predictions(&self, importer: &Importer, old_schedule_filename: &str, new_schedule_filename: &str) -> FnResult<()> {
+        let update_statement = r"UPDATE IGNORE `predictions`
+            SET
+                `trip_id` = :new_trip_id,
+                `schedule_file_name` = :new_schedule_file_name
+            WHERE
+                `source` = :source AND
+                `trip_id` = :old_trip_id AND
+                `schedule_file_name` = :old_schedule_file_name;";
+        let delete_statement = r"DELETE FROM `predictions`
+            WHERE
+                `source` = :source AND
+                `trip_id` = :old_trip_id AND
+                `schedule_file_name` = :old_schedule_file_name;";
+
+        let update_statements = BatchedStatements::new("transition_update", &importer.main.pool, &[update_statement], importer.batch_settings, importer.dry_run)?;
+        for (old_trip_id, new_trip_id) in &self.trip_mapping {
+            update_statements.add_parameter_set(Params::from(params! {
+                "source" => importer.main.source.clone(),
+                "old_trip_id" => old_trip_id.clone(),
+                "new_trip_id" => new_trip_id.clone(),
+                "old_schedule_file_name" => old_schedule_filename,
+                "new_schedule_file_name" => new_schedule_filename,
+            }))?;
+        }
+        update_statements.write_to_database()?;
+
+        // this needs to happen after all updates are written, so that we don't delete predictions that can still be migrated
+        let delete_statements = BatchedStatements::new("transition_delete", &importer.main.pool, &[delete_statement], importer.batch_settings, importer.dry_run)?;
+        let all_old_trip_ids = self.trip_mapping.iter().map(|(old, _new)| old).chain(self.removed_trip_ids.iter());
+        for old_trip_id in all_old_trip_ids {
+            delete_statements.add_parameter_set(Params::from(params! {
+                "source" => importer.main.source.clone(),
+                "old_trip_id" => old_trip_id.clone(),
+                "old_schedule_file_name" => old_schedule_filename,
+            }))?;
+        }
+        delete_statements.write_to_database()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, schedule};
+
+    // five minutes between the stops
+    fn trip(id: &str, route_id: &str, start_time: u32, stop_ids: &[&str]) -> Trip {
+        test_util::trip(id, route_id, start_time, 300, stop_ids)
+    }
+
+    #[test]
+    fn test_schedule_transition() {
+        let old_schedule = schedule(vec![
+            trip("kept", "r1", 8 * 3600, &["s1", "s2", "s3"]),
+            trip("renamed", "r1", 9 * 3600, &["s1", "s2", "s3"]),
+            trip("removed", "r2", 8 * 3600, &["s3", "s4"]),
+            trip("reused", "r2", 9 * 3600, &["s3", "s4"]),
+            trip("ambiguous", "r3", 8 * 3600, &["s5", "s6"]),
+        ]);
+        let new_schedule = schedule(vec![
+            trip("kept", "r1", 8 * 3600, &["s1", "s2", "s3"]),
+            trip("renamed_2", "r1", 9 * 3600, &["s1", "s2", "s3"]),
+            // the trip_id now belongs to a trip that leaves at another time
+            trip("reused", "r2", 10 * 3600, &["s3", "s4"]),
+            trip("ambiguous_1", "r3", 8 * 3600, &["s5", "s6"]),
+            trip("ambiguous_2", "r3", 8 * 3600, &["s5", "s6"]),
+            trip("added", "r1", 10 * 3600, &["s1", "s2", "s3"]),
+        ]);
+
+        let transition = ScheduleTransition::new(&old_schedule, &new_schedule);
+        let mut trip_mapping = transition.trip_mapping.clone();
+        trip_mapping.sort();
+        assert_eq!(trip_mapping, vec![
+            (String::from("kept"), String::from("kept")),
+            (String::from("renamed"), String::from("renamed_2")),
+        ]);
+        let mut removed_trip_ids = transition.removed_trip_ids.clone();
+        removed_trip_ids.sort();
+        assert_eq!(removed_trip_ids, vec!["ambiguous", "removed", "reused"]);
+
+        // trips that are only in the new schedule have no predictions that could be migrated
+        let transition = ScheduleTransition::new(&schedule(Vec::new()), &new_schedule);
+        assert!(transition.trip_mapping.is_empty() && transition.removed_trip_ids.is_empty());
+    }
+}
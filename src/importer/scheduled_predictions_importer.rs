@@ -1,45 +1,79 @@
-use chrono::{NaiveDate, Duration, Local, DateTime};
+use chrono::{NaiveDate, NaiveDateTime, Duration, Local, DateTime};
 use chrono::offset::TimeZone;
+use clap::ArgMatches;
 use gtfs_structures::{Gtfs, Trip};
+use simple_error::bail;
+use std::collections::HashSet;
 use std::sync::Arc;
 use mysql::*;
 use mysql::prelude::*;
 
 use super::{Importer, VehicleIdentifier, get_predictions_statements};
+use super::vehicle_identity::{get_direction_id, get_first_stop_id};
 use super::MAX_ESTIMATED_TRIP_DURATION;
 use super::batched_statements::BatchedStatements;
-use crate::{FnResult, date_and_time_local};
-use crate::types::{OriginType, EventType, PredictionResult, GtfsDateTime};
+use crate::{FnResult, OrError};
+use crate::time_util::date_and_time;
+use crate::types::{OriginType, EventType, Id, PredictionResult, GtfsDateTime};
 use crate::types::CurveData;
-use crate::predictor::Predictor;
+use crate::types::curve_format::encode_compact;
+use crate::predictor::{Predictor, PredictionTarget, PredictionContext, StatisticsModel};
 use dystonse_curves::Curve;
 
+/// Options for schedule-based predictions in automatic mode.
+pub struct ScheduledPredictionSettings {
+    /// predictions are made for trips which start until this time after now
+    pub look_ahead: Duration,
+    /// minimum number of trips for which predictions will be made during one batch.
+    /// The time range will be extended until this number of trips is found.
+    pub min_batch_count: usize,
+    /// routes for which predictions are made up to the look-ahead before the other routes are continued
+    pub priority_routes: HashSet<String>,
+}
+
+impl ScheduledPredictionSettings {
+    pub fn from_args(args: &ArgMatches) -> FnResult<Self> {
+        let look_ahead_hours: i64 = args.value_of("schedule-look-ahead").unwrap().parse()?; // has a default value
+        let min_batch_count: usize = args.value_of("schedule-batch-size").unwrap().parse()?; // has a default value
+        // with 0, predictions may stall forever if there is a time span without any trips
+        if look_ahead_hours < 1 || min_batch_count < 1 {
+            bail!("Look-ahead and batch size of scheduled predictions must be at least 1.");
+        }
+        Ok(ScheduledPredictionSettings {
+            look_ahead: Duration::hours(look_ahead_hours),
+            min_batch_count,
+            priority_routes: args.values_of("schedule-priority-routes").map(|v| v.map(String::from).collect()).unwrap_or_default(),
+        })
+    }
+}
+
+/// Scheduled predictions are made separately for the priority routes and for all other routes,
+/// so that each group has its own progress and timeout.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum RouteGroup {
+    Priority,
+    Other,
+}
+
 /// This imports predictions to the database that are based on schedule data
 /// and can be generated for any trip, regardless of realtime data availability
 pub struct ScheduledPredictionsImporter<'a> {
     importer: &'a Importer<'a>,
+    settings: &'a ScheduledPredictionSettings,
     gtfs_schedule: Arc<Gtfs>,
-    verbose: bool,
     predictor: Predictor<'a>,
+    shadow_model: Option<StatisticsModel>,
     predictions_statements: Option<BatchedStatements>,
     filename: String,
 }
 
 lazy_static!{
-    // For how many days in the future we want to prepare predictions:
-    static ref PREDICTION_BUFFER_SIZE : Duration = Duration::days(7) + Duration::hours(12);
-
     // How many minutes of scheduled predictions we want to compute in one iteration,
     // before we try to process the next batch of realtime updates:
     static ref PREDICTION_MIN_BATCH_DURATION : Duration = Duration::minutes(6);
 
-    // Minimum number of trips for which predictions will be made during one batch.
-    // The time range will be extended until this number of trips is found.
-    // Don't set this const below 1 or predictions may stall forever.
-    static ref PREDICTION_MIN_BATCH_COUNT : usize = 1000;
-
     // How long we pause scheduled scheduled predictions when we reached
-    // the end of the PREDICTION_BUFFER_SIZE
+    // the end of the look-ahead
     static ref PREDICTION_FULL_TIMEOUT : Duration = Duration::minutes(20);
 }
 
@@ -47,30 +81,49 @@ impl<'a> ScheduledPredictionsImporter<'a> {
     
     pub fn new(
         importer: &'a Importer,
-        verbose: bool
+        settings: &'a ScheduledPredictionSettings,
     ) -> FnResult<ScheduledPredictionsImporter<'a>> {
         let mut instance = ScheduledPredictionsImporter {
             importer,
+            settings,
             gtfs_schedule: importer.main.get_schedule()?,
-            verbose,
             predictor: Predictor::new(importer.main, &importer.main.args)?,
+            shadow_model: None,
             predictions_statements: None,
             filename: importer.main.get_schedule_filename()?.split("/").last().unwrap().to_string(),
         };
         instance.init_predictions_statements()?;
+        if let Some(shadow_evaluation) = &importer.shadow_evaluation {
+            match shadow_evaluation.get_candidate_model() {
+                Ok(model) => instance.shadow_model = Some(model),
+                Err(e) => error!("Could not load candidate statistics for shadow evaluation: {}", e),
+            }
+        }
         Ok(instance)
     }
 
+    /// Makes one batch of predictions for the priority routes (if there are any), and one for the other routes.
     pub fn make_scheduled_predictions(&mut self) -> FnResult<()> {
+        if !self.settings.priority_routes.is_empty() {
+            self.make_scheduled_predictions_for_group(RouteGroup::Priority)?;
+        }
+        self.make_scheduled_predictions_for_group(RouteGroup::Other)
+    }
+
+    fn is_in_group(&self, trip: &Trip, group: RouteGroup) -> bool {
+        self.settings.priority_routes.contains(&trip.route_id) == (group == RouteGroup::Priority)
+    }
+
+    fn make_scheduled_predictions_for_group(&mut self, group: RouteGroup) -> FnResult<()> {
         { //block for mutex
-            let mut until_option = self.importer.timeout_until.lock().unwrap();
-            if let Some(until) = *until_option {
+            let mut timeouts = self.importer.timeout_until.lock().unwrap();
+            if let Some(until) = timeouts.get(&group).cloned() {
                 if Local::now() < until {
-                    println!("Skipping scheduled prediction because of timeout until {}.", until);
+                    info!("Skipping scheduled prediction for {:?} routes because of timeout until {}.", group, until);
                     return Ok(());
                 } else {
-                    println!("Reached end of timeout.");
-                    *until_option = None;
+                    info!("Reached end of timeout for {:?} routes.", group);
+                    timeouts.remove(&group);
                 }
             }
         }
@@ -78,26 +131,31 @@ impl<'a> ScheduledPredictionsImporter<'a> {
         // we use absolute timestamps of scheduled trip start times to track
         // which is the latest trip for which we already have schedule-based
         // predictions
-        let initial_begin = self.get_latest_prediction_time_from_database()?;
+        let initial_begin = self.get_latest_prediction_time_from_database(group)?;
 
         // compute the time span for which predictions shall be made in this iteration:
         let mut begin = initial_begin; 
 
         // this is the absolute time limit. Predictions shall never be made for
         // trips which start after this time.
-        let time_limit = Local::now() + *PREDICTION_BUFFER_SIZE;
+        let time_limit = Local::now() + self.settings.look_ahead;
 
         let mut end = if begin >= (time_limit - *PREDICTION_MIN_BATCH_DURATION) {
             { //block for mutex
-                let mut until_option = self.importer.timeout_until.lock().unwrap();
-                *until_option = Some(Local::now() + *PREDICTION_FULL_TIMEOUT);
+                let mut timeouts = self.importer.timeout_until.lock().unwrap();
+                timeouts.insert(group, Local::now() + *PREDICTION_FULL_TIMEOUT);
             }
-            println!("Prediction buffer will be full after this iteration, setting timeout.");
+            info!("Prediction buffer for {:?} routes will be full after this iteration, setting timeout.", group);
             time_limit
         } else {
             begin + *PREDICTION_MIN_BATCH_DURATION
         };
 
+        // the forecast is fetched here, so that the weather API is never requested while predicting
+        if let Some(weather) = &self.importer.main.weather {
+            weather.refresh(begin, end + *MAX_ESTIMATED_TRIP_DURATION);
+        }
+
         // Now things get complicated. Trip start times may be larger than 23:59:59,
         // in fact there are good reasons to use times up to 27:00:00, see
         // https://gist.github.com/derhuerst/574edc94981a21ef0ce90713f1cff7f6
@@ -112,8 +170,8 @@ impl<'a> ScheduledPredictionsImporter<'a> {
         let mut current_day = end.date();
         let mut previous_day = end.date() - Duration::days(1);
 
-        let mut current_day_trips : Vec<&Trip> = self.gtfs_schedule.trips_for_date(current_day.naive_local())?;
-        let mut previous_day_trips : Vec<&Trip> = self.gtfs_schedule.trips_for_date(previous_day.naive_local())?;
+        let mut current_day_trips : Vec<&Trip> = self.trips_for_date(current_day.naive_local(), group)?;
+        let mut previous_day_trips : Vec<&Trip> = self.trips_for_date(previous_day.naive_local(), group)?;
 
         // collect trips for which we want to make predictions during this batach in this vec:
         let mut trip_selection : Vec<(GtfsDateTime, &Trip)> = Vec::new();
@@ -142,10 +200,8 @@ impl<'a> ScheduledPredictionsImporter<'a> {
             // predictions would never move on, as get_latest_prediction_time_from_database would
             // always return the same time. Also, if the span contains at least one trip, but only
             // a very small number, we extend the range to advance our predictions more quickly.
-            if trip_selection.len() < *PREDICTION_MIN_BATCH_COUNT {
-                if self.verbose {
-                    println!("Only {} trips found in total after adding trips between {} and {}, extending range…", trip_selection.len(), begin, end);
-                }
+            if trip_selection.len() < self.settings.min_batch_count {
+                debug!("Only {} trips found in total after adding trips between {} and {}, extending range…", trip_selection.len(), begin, end);
                 begin = end;
                 end = end + *PREDICTION_MIN_BATCH_DURATION;
 
@@ -160,10 +216,10 @@ impl<'a> ScheduledPredictionsImporter<'a> {
                     current_day = end.date();
                     previous_day = end.date() - Duration::days(1);
                     previous_day_trips = current_day_trips; // we can reuse the selected trips, as the old today is the new yesterday
-                    current_day_trips = self.gtfs_schedule.trips_for_date(current_day.naive_local())?;
+                    current_day_trips = self.trips_for_date(current_day.naive_local(), group)?;
                 }
                 if end.date() != current_day {
-                    println!("end.date() is {} and current_day is {}, which is an invalid state.", end.date(), current_day);
+                    error!("end.date() is {} and current_day is {}, which is an invalid state.", end.date(), current_day);
                 }
             } else {
                 break;
@@ -171,15 +227,11 @@ impl<'a> ScheduledPredictionsImporter<'a> {
         }
 
         if trip_selection.len() == 0 {
-            if self.verbose {
-                println!("No more schedule-based predictions to make.");
-            }
+            debug!("No more schedule-based predictions to make for {:?} routes.", group);
             return Ok(());
         }
 
-        if self.verbose {
-            println!("Making schedule-based predictions for {} trips starting between {} and {}.", trip_selection.len(), initial_begin, end);
-        }
+        debug!("Making schedule-based predictions for {} trips of {:?} routes starting between {} and {}.", trip_selection.len(), group, initial_begin, end);
 
         // make predictions for all stops of those trips
         for (start_time, trip) in trip_selection {
@@ -187,7 +239,7 @@ impl<'a> ScheduledPredictionsImporter<'a> {
             // println!("trip {}, {:?} = {}", trip.id, start_time, start_time.date_time());
             let route_id = &trip.route_id;
             let vehicle_id = VehicleIdentifier {
-                trip_id: trip.id.clone(), 
+                trip_id: Id::new(&trip.id),
                 start: start_time,
             };
             for st in &trip.stop_times {
@@ -195,52 +247,82 @@ impl<'a> ScheduledPredictionsImporter<'a> {
                     if let Some(scheduled_time) = et.get_time_from_stop_time(&st) {
                         // try to make a prediction:
                         let result = self.predictor.predict(&trip.route_id, &trip.id, &None, st.stop_sequence, **et, begin);
+                        if let (Ok(prediction), Some(shadow_model)) = (&result, &self.shadow_model) {
+                            self.compare_with_shadow_model(shadow_model, prediction, trip, st.stop_sequence, **et, begin);
+                        }
                         match result {
                             Ok(PredictionResult::CurveData(c)) => {
                                 let result = self.save_scheduled_prediction_to_database(c, **et, st.stop.id.clone(), st.stop_sequence, 
                                     scheduled_time, vehicle_id.clone(), route_id.to_string());
                                 if let Err(e) = result {
-                                    eprintln!("Error while saving scheduled predictions to database: {}", e);
+                                    error!("Error while saving scheduled predictions to database: {}", e);
                                 }
                             },
                             Ok(PredictionResult::CurveSetData(_cs)) => { 
-                                eprintln!("Error while trying to predict {:?} at stop {} of trip {}: result should be a Curve but is a CurveSet.",
+                                warn!("Error while trying to predict {:?} at stop {} of trip {}: result should be a Curve but is a CurveSet.",
                                 **et, st.stop_sequence, trip.id);
                             },
                             Err(e) => {
-                               eprintln!("Error while trying to predict {:?} at stop {} of trip {}: {}",
+                               warn!("Error while trying to predict {:?} at stop {} of trip {}: {}",
                                  **et, st.stop_sequence, trip.id, e);
                             }
                         };
                     } else {
                         // skip empty arrival/departure times
-                        if self.verbose {
-                            println!("(Scheduled predictions:) No {:?} scheduled at stop {} of trip {}. Skipping {:?} prediction.",
+                        debug!("(Scheduled predictions:) No {:?} scheduled at stop {} of trip {}. Skipping {:?} prediction.",
                                  **et, st.stop_sequence, trip.id, **et);
-                        }
                     }
                 }
             }
         }
         self.predictions_statements.as_ref().unwrap().write_to_database()?;
+        if let Some(prediction_events) = &self.importer.prediction_events {
+            prediction_events.publish();
+        }
+        if let (Some(shadow_evaluation), Some(_)) = (&self.importer.shadow_evaluation, &self.shadow_model) {
+            if let Err(e) = shadow_evaluation.write_report() {
+                error!("Could not write shadow evaluation report: {}", e);
+            }
+        }
 
-        let latest_prediction = self.get_latest_prediction_time_from_database()?;
+        let latest_prediction = self.get_latest_prediction_time_from_database(group)?;
         if latest_prediction > end {
             panic!("latest prediction is {}, should not be later than {}", latest_prediction, end);
         } else {
-            println!("Wrote predictions until {}.", latest_prediction);
+            info!("Wrote predictions for {:?} routes until {}.", group, latest_prediction);
         }
 
         // now cleanup schedule based predictions which are based on an outdated schedule and were not 
         // updated by the recent batch, even though they were in the relevant time window.
         // Those are probably caused by changed trip_ids and would show up as duplicate trips in the
         // monitor if not deleted.
-        self.delete_outdated_predictions(end)?;
-        println!("Deleted outdated predictions before {}", end);
+        // This deletes the predictions of all routes, so it must only happen with the progress of the other
+        // routes, which is always behind the progress of the priority routes.
+        if group == RouteGroup::Other {
+            self.delete_outdated_predictions(end)?;
+            info!("Deleted outdated predictions before {}", end);
+        }
 
         Ok(())
     }
 
+    // makes the same prediction from the candidate statistics, which is only used for the shadow evaluation report
+    fn compare_with_shadow_model(&self, shadow_model: &StatisticsModel, prediction: &PredictionResult, trip: &Trip, stop_sequence: u16, et: EventType, date_time: DateTime<Local>) {
+        let target = PredictionTarget {
+            route_id: &trip.route_id,
+            trip,
+            stop_sequence,
+            event_type: et,
+        };
+        let context = PredictionContext {
+            schedule: &self.gtfs_schedule,
+            holidays: &self.importer.main.holidays,
+            weather: self.importer.main.weather.as_ref(),
+            date_time,
+        };
+        self.importer.shadow_evaluation.as_ref().unwrap().compare(shadow_model, prediction, &None, &target, &context);
+    }
+
     fn delete_outdated_predictions(&self, date_time: DateTime<Local>) -> FnResult<()> {
         let mut con = self.importer.main.pool.get_conn()?;
         
@@ -276,8 +358,8 @@ impl<'a> ScheduledPredictionsImporter<'a> {
         route_id: String
     ) -> FnResult<()> {
 
-        let prediction_min = date_and_time_local(&vehicle_id.start.service_day(), scheduled_time + curve_data.curve.min_x() as i32);
-        let prediction_max = date_and_time_local(&vehicle_id.start.service_day(), scheduled_time + curve_data.curve.max_x() as i32);
+        let prediction_min = date_and_time(&vehicle_id.start.service_day(), scheduled_time + curve_data.curve.min_x() as i32);
+        let prediction_max = date_and_time(&vehicle_id.start.service_day(), scheduled_time + curve_data.curve.max_x() as i32);
         
         self.predictions_statements.as_ref().unwrap().add_parameter_set(Params::from(params! {
             "source" => self.importer.main.source.clone(),
@@ -287,46 +369,61 @@ impl<'a> ScheduledPredictionsImporter<'a> {
             "prediction_max" => prediction_max.naive_local(),
             route_id,
             "trip_id" => vehicle_id.trip_id.clone(),
-            "trip_start_date" => vehicle_id.start.service_day().naive_local(),
+            "trip_start_date" => vehicle_id.start.service_day_param(),
             "trip_start_time" => vehicle_id.start.duration(),
             stop_sequence,
             "precision_type" => curve_data.precision_type.to_int(),
             "origin_type" => OriginType::Schedule.to_int(),
             "sample_size" => curve_data.sample_size,
-            "prediction_curve" => curve_data.curve.serialize_compact_limited(120),
+            "prediction_curve" => encode_compact(&curve_data.curve),
             "schedule_file_name" => self.filename.clone(),
+            "direction_id" => get_direction_id(&self.gtfs_schedule, &vehicle_id.trip_id),
+            "first_stop_id" => get_first_stop_id(&self.gtfs_schedule, &vehicle_id.trip_id),
         }))?;
-        
+        if let Some(prediction_events) = &self.importer.prediction_events {
+            prediction_events.add(&stop_id, &vehicle_id.trip_id);
+        }
         Ok(())
     }
 
+    // returns the trips of the route group (and the selected agencies) which are scheduled for the given date
+    fn trips_for_date(&self, date: NaiveDate, group: RouteGroup) -> FnResult<Vec<&Trip>> {
+        let trips = self.gtfs_schedule.trips_for_date(date)?;
+        Ok(trips.into_iter().filter(|trip| {
+            self.is_in_group(trip, group) && self.importer.agency_filter.contains_route(&self.gtfs_schedule, &trip.route_id)
+        }).collect())
+    }
+
     // this helps us find the point from where we want to start/continue making predictions
-    fn get_latest_prediction_time_from_database(&self) -> FnResult<DateTime<Local>> {
+    // for the routes of the group
+    fn get_latest_prediction_time_from_database(&self, group: RouteGroup) -> FnResult<DateTime<Local>> {
 
         let mut conn = self.importer.main.pool.get_conn()?;
         
         let select_statement = conn.prep(r"
             SELECT 
-                `trip_start_date`,`trip_start_time` 
+                `route_id`, MAX(trip_start_date + INTERVAL TIME_TO_SEC(trip_start_time) SECOND)
             FROM 
                 `predictions` 
             WHERE 
                 `origin_type` = :origin_type AND `source` = :source AND `schedule_file_name` = :schedule_file_name
-            ORDER BY 
-                trip_start_date + INTERVAL TIME_TO_SEC(trip_start_time) SECOND DESC 
-            LIMIT 
-                0,1;
+            GROUP BY 
+                `route_id`;
         ").expect("Could not prepare select statement");
  
-        let query_result : Option<(NaiveDate, Duration)> = conn.exec_first(select_statement, 
+        let query_result : Vec<(String, NaiveDateTime)> = conn.exec(select_statement, 
             params!{
                 "source" => self.importer.main.source.clone(), 
                 "origin_type" => OriginType::Schedule.to_int(),
                 "schedule_file_name" => self.filename.clone(),
             })?; 
             //actual errors will be thrown here if they occur
-        if let Some((date, duration)) = query_result {
-            return Ok(GtfsDateTime::new(Local.from_local_date(&date).unwrap(), duration.num_seconds() as i32).date_time());
+        let latest = query_result.into_iter()
+            .filter(|(route_id, _)| self.settings.priority_routes.contains(route_id) == (group == RouteGroup::Priority))
+            .map(|(_, start)| start)
+            .max();
+        if let Some(start) = latest {
+            return Ok(Local.from_local_datetime(&start).earliest().or_error("Invalid local time of latest prediction")?);
         } else {
             // if there aren't any scheduled predictions in the database yet 
             // (this is not an error and can happen when we start),
@@ -336,7 +433,7 @@ impl<'a> ScheduledPredictionsImporter<'a> {
     }
 
     fn init_predictions_statements(&mut self) -> FnResult<()> {
-        self.predictions_statements = Some(get_predictions_statements(self.importer.main.pool.clone())?);
+        self.predictions_statements = Some(get_predictions_statements(self.importer.main.pool.clone(), self.importer.batch_settings, self.importer.dry_run)?);
         Ok(())
     }
 }
\ No newline at end of file
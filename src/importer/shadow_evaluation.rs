@@ -0,0 +1,262 @@
+use chrono::{DateTime, Local};
+use dystonse_curves::Curve;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::Mutex;
+
+use crate::{FileCache, FnResult};
+use crate::predictor::{CurveBlending, PredictionModel, PredictionTarget, PredictionContext, StatisticsModel};
+use crate::types::{DelayStatistics, PredictionBasis, PredictionResult};
+
+// how many routes with the largest divergence are listed in the report
+const REPORT_ROUTE_COUNT: usize = 50;
+
+/// Computes predictions from a candidate statistics file in addition to the real ones
+/// ("shadow mode"), and collects metrics about how much they differ. The candidate's
+/// predictions are never written to the database, so users don't see any of them.
+pub struct ShadowEvaluation {
+    candidate_filename: String,
+    candidate_cache: Mutex<FileCache<DelayStatistics>>,
+    report_filename: String,
+    /// the same as for the real predictions, so that only the statistics differ
+    curve_blending: Option<CurveBlending>,
+    started: DateTime<Local>,
+    metrics: Mutex<DivergenceMetrics>,
+}
+
+#[derive(Default)]
+struct DivergenceMetrics {
+    comparisons: u64,
+    candidate_failures: u64,
+    type_mismatches: u64,
+    precision_type_changes: u64,
+    sum_abs_p10_difference: f64,
+    sum_abs_median_difference: f64,
+    sum_abs_p90_difference: f64,
+    max_abs_median_difference: f32,
+    routes: HashMap<String, RouteDivergence>,
+}
+
+#[derive(Serialize, Default, Clone)]
+pub struct RouteDivergence {
+    pub route_id: String,
+    pub comparisons: u64,
+    pub mean_abs_median_difference: f64,
+    #[serde(skip)]
+    sum_abs_median_difference: f64,
+}
+
+/// The comparison report, which is written as JSON.
+#[derive(Serialize)]
+pub struct ShadowReport {
+    pub candidate_file: String,
+    pub since: String,
+    pub updated: String,
+    /// number of predictions for which both statistics produced a curve
+    pub comparisons: u64,
+    /// number of predictions which the current statistics could make, but the candidate could not
+    pub candidate_failures: u64,
+    /// number of predictions for which one statistics produced a curve, and the other one a curve set
+    pub type_mismatches: u64,
+    /// number of predictions for which the candidate's curve has a different precision type
+    pub precision_type_changes: u64,
+    /// mean absolute difference of the 10th percentile, median and 90th percentile of the delay, in seconds
+    pub mean_abs_p10_difference: f64,
+    pub mean_abs_median_difference: f64,
+    pub mean_abs_p90_difference: f64,
+    pub max_abs_median_difference: f32,
+    /// routes with the largest mean absolute difference of the median
+    pub routes: Vec<RouteDivergence>,
+}
+
+impl ShadowEvaluation {
+    pub fn new(candidate_filename: &str, report_filename: &str, curve_blending: Option<CurveBlending>) -> Self {
+        ShadowEvaluation {
+            candidate_filename: String::from(candidate_filename),
+            candidate_cache: Mutex::new(FileCache::new()),
+            report_filename: String::from(report_filename),
+            curve_blending,
+            started: Local::now(),
+            metrics: Mutex::new(DivergenceMetrics::default()),
+        }
+    }
+
+    /// Loads the candidate statistics (or reuses them if the file didn't change) and
+    /// returns a model that makes predictions from them.
+    pub fn get_candidate_model(&self) -> FnResult<StatisticsModel> {
+        Ok(StatisticsModel {
+            delay_statistics: FileCache::get_cached_simple(&self.candidate_cache, &self.candidate_filename)?,
+            curve_blending: self.curve_blending,
+        })
+    }
+
+    /// Makes the same prediction with the candidate model and records how much it differs
+    /// from the current prediction.
+    pub fn compare(
+        &self,
+        candidate_model: &StatisticsModel,
+        current: &PredictionResult,
+        basis: &Option<PredictionBasis>,
+        target: &PredictionTarget,
+        context: &PredictionContext,
+    ) {
+        let candidate = candidate_model.predict(basis, target, context);
+        self.record(current, candidate, target.route_id);
+    }
+
+    // records how much the candidate's prediction differs from the current one
+    fn record(&self, current: &PredictionResult, candidate: FnResult<PredictionResult>, route_id: &str) {
+        let mut metrics = self.metrics.lock().unwrap();
+        let (current_curve_data, candidate_curve_data) = match (current, candidate) {
+            (_, Err(_)) => {
+                metrics.candidate_failures += 1;
+                return;
+            },
+            (PredictionResult::CurveData(current), Ok(PredictionResult::CurveData(candidate))) => (current, candidate),
+            (PredictionResult::CurveSetData(_), Ok(PredictionResult::CurveSetData(_))) => {
+                // curve sets can't be compared easily, and they are not written to the database anyway
+                return;
+            },
+            _ => {
+                metrics.type_mismatches += 1;
+                return;
+            }
+        };
+
+        let difference_at = |y: f32| (candidate_curve_data.curve.x_at_y(y) - current_curve_data.curve.x_at_y(y)).abs();
+        let median_difference = difference_at(0.5);
+
+        metrics.comparisons += 1;
+        metrics.sum_abs_p10_difference += difference_at(0.1) as f64;
+        metrics.sum_abs_median_difference += median_difference as f64;
+        metrics.sum_abs_p90_difference += difference_at(0.9) as f64;
+        metrics.max_abs_median_difference = f32::max(metrics.max_abs_median_difference, median_difference);
+        if candidate_curve_data.precision_type.to_int() != current_curve_data.precision_type.to_int() {
+            metrics.precision_type_changes += 1;
+        }
+
+        let route = metrics.routes.entry(String::from(route_id)).or_insert_with(|| RouteDivergence {
+            route_id: String::from(route_id),
+            ..RouteDivergence::default()
+        });
+        route.comparisons += 1;
+        route.sum_abs_median_difference += median_difference as f64;
+    }
+
+    /// Writes the comparison report with all metrics that were collected since the importer started.
+    pub fn write_report(&self) -> FnResult<()> {
+        let report = self.get_report();
+        info!(
+            "Shadow evaluation: {} comparisons, mean absolute median difference {:.1}s, {} candidate failures.",
+            report.comparisons, report.mean_abs_median_difference, report.candidate_failures
+        );
+        let file = File::create(&self.report_filename)?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &report)?;
+        Ok(())
+    }
+
+    fn get_report(&self) -> ShadowReport {
+        let metrics = self.metrics.lock().unwrap();
+        let mean = |sum: f64, count: u64| if count == 0 { 0.0 } else { sum / count as f64 };
+
+        let mut routes: Vec<RouteDivergence> = metrics.routes.values().map(|route| RouteDivergence {
+            mean_abs_median_difference: mean(route.sum_abs_median_difference, route.comparisons),
+            ..route.clone()
+        }).collect();
+        routes.sort_by(|a, b| b.mean_abs_median_difference.partial_cmp(&a.mean_abs_median_difference).unwrap_or(Ordering::Equal));
+        routes.truncate(REPORT_ROUTE_COUNT);
+
+        ShadowReport {
+            candidate_file: self.candidate_filename.clone(),
+            since: self.started.to_rfc3339(),
+            updated: Local::now().to_rfc3339(),
+            comparisons: metrics.comparisons,
+            candidate_failures: metrics.candidate_failures,
+            type_mismatches: metrics.type_mismatches,
+            precision_type_changes: metrics.precision_type_changes,
+            mean_abs_p10_difference: mean(metrics.sum_abs_p10_difference, metrics.comparisons),
+            mean_abs_median_difference: mean(metrics.sum_abs_median_difference, metrics.comparisons),
+            mean_abs_p90_difference: mean(metrics.sum_abs_p90_difference, metrics.comparisons),
+            max_abs_median_difference: metrics.max_abs_median_difference,
+            routes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dystonse_curves::{curve_set::CurveSet, IrregularDynamicCurve, Tup};
+    use crate::types::{CurveData, CurveSetData, PrecisionType};
+
+    // a uniform distribution of the delay between `start` and `end`
+    fn prediction(start: f32, end: f32, precision_type: PrecisionType) -> PredictionResult {
+        PredictionResult::CurveData(CurveData {
+            curve: IrregularDynamicCurve::new(vec![Tup { x: start, y: 0.0 }, Tup { x: end, y: 1.0 }]),
+            precision_type,
+            sample_size: 100,
+            effective_sample_size: None,
+        })
+    }
+
+    #[test]
+    fn test_compare() {
+        let evaluation = ShadowEvaluation::new("candidate.exp", "report.json", None);
+        let current = prediction(0.0, 100.0, PrecisionType::Specific);
+
+        // shifted by 20 seconds
+        evaluation.record(&current, Ok(prediction(20.0, 120.0, PrecisionType::Specific)), "r1");
+        // twice as wide, so the median is 50 seconds later, and based on worse data
+        evaluation.record(&current, Ok(prediction(0.0, 200.0, PrecisionType::General)), "r1");
+        // the same
+        evaluation.record(&current, Ok(prediction(0.0, 100.0, PrecisionType::Specific)), "r2");
+        evaluation.record(&current, Err(String::from("no statistics").into()), "r2");
+        let curve_set = CurveSetData {
+            curve_set: CurveSet::new(),
+            precision_type: PrecisionType::Specific,
+            sample_size: 100,
+            effective_sample_size: None,
+        };
+        evaluation.record(&current, Ok(PredictionResult::CurveSetData(curve_set)), "r2");
+
+        let report = evaluation.get_report();
+        assert_eq!(report.comparisons, 3);
+        assert_eq!(report.candidate_failures, 1);
+        assert_eq!(report.type_mismatches, 1);
+        assert_eq!(report.precision_type_changes, 1);
+        assert!((report.mean_abs_p10_difference - 10.0).abs() < 0.01);
+        assert!((report.mean_abs_median_difference - 70.0 / 3.0).abs() < 0.01);
+        assert!((report.mean_abs_p90_difference - 110.0 / 3.0).abs() < 0.01);
+        assert!((report.max_abs_median_difference - 50.0).abs() < 0.01);
+
+        // sorted by divergence
+        assert_eq!(report.routes.len(), 2);
+        assert_eq!(report.routes[0].route_id, "r1");
+        assert_eq!(report.routes[0].comparisons, 2);
+        assert!((report.routes[0].mean_abs_median_difference - 35.0).abs() < 0.01);
+        assert_eq!(report.routes[1].route_id, "r2");
+        assert_eq!(report.routes[1].comparisons, 1);
+        assert_eq!(report.routes[1].mean_abs_median_difference, 0.0);
+    }
+
+    #[test]
+    fn test_write_report() {
+        let report_path = std::env::temp_dir().join(format!("shadow_report_test_{}.json", std::process::id()));
+        let evaluation = ShadowEvaluation::new("candidate.exp", report_path.to_str().unwrap(), None);
+        let current = prediction(0.0, 100.0, PrecisionType::Specific);
+        evaluation.record(&current, Ok(prediction(10.0, 110.0, PrecisionType::Specific)), "r1");
+        evaluation.write_report().unwrap();
+
+        let report: serde_json::Value = serde_json::from_reader(File::open(&report_path).unwrap()).unwrap();
+        std::fs::remove_file(&report_path).unwrap();
+        assert_eq!(report["candidate_file"], "candidate.exp");
+        assert_eq!(report["comparisons"], 1);
+        assert!((report["mean_abs_median_difference"].as_f64().unwrap() - 10.0).abs() < 0.01);
+        assert_eq!(report["routes"][0]["route_id"], "r1");
+        // the sum is only used internally
+        assert!(report["routes"][0].get("sum_abs_median_difference").is_none());
+    }
+}
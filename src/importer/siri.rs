@@ -0,0 +1,332 @@
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, TimeZone};
+use gtfs_rt::FeedMessage as GtfsRealtimeMessage;
+use gtfs_rt::{FeedEntity, FeedHeader, TripDescriptor, TripUpdate};
+use gtfs_rt::trip_update::{StopTimeEvent, StopTimeUpdate};
+use gtfs_structures::{Gtfs, Trip};
+use simple_error::bail;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+
+use crate::{FnResult, OrError};
+use super::realtime_format::RealtimeFormat;
+
+/// One call of a vehicle journey at a stop, as read from SIRI. Times are absolute.
+#[derive(Debug, PartialEq)]
+pub struct SiriCall {
+    pub stop_point_ref: String,
+    pub order: Option<u32>,
+    pub aimed_arrival: Option<DateTime<FixedOffset>>,
+    pub actual_arrival: Option<DateTime<FixedOffset>>,
+    pub aimed_departure: Option<DateTime<FixedOffset>>,
+    pub actual_departure: Option<DateTime<FixedOffset>>,
+}
+
+/// An `EstimatedVehicleJourney` from a SIRI-ET delivery. Recorded and estimated calls
+/// are combined, because both contain delays.
+#[derive(Debug, PartialEq)]
+pub struct SiriJourney {
+    pub dated_vehicle_journey_ref: String,
+    pub data_frame_ref: Option<NaiveDate>,
+    pub line_ref: Option<String>,
+    pub calls: Vec<SiriCall>,
+}
+
+/// SIRI Estimated Timetable XML. SIRI journeys and stops are mapped to GTFS trips and stops
+/// via optional mapping files. Journeys and stops without a mapping are expected to use the
+/// GTFS trip_id and stop_id as their references.
+pub struct SiriEtFormat {
+    /// DatedVehicleJourneyRef -> trip_id
+    trip_mapping: HashMap<String, String>,
+    /// StopPointRef -> stop_id
+    stop_mapping: HashMap<String, String>,
+}
+
+impl SiriEtFormat {
+    pub const NAME: &'static str = "siri-et";
+
+    pub fn new(trip_mapping_filename: Option<&str>, stop_mapping_filename: Option<&str>) -> FnResult<Self> {
+        let trip_mapping = match trip_mapping_filename {
+            Some(filename) => read_mapping_csv(filename)?,
+            None => HashMap::new(),
+        };
+        let stop_mapping = match stop_mapping_filename {
+            Some(filename) => read_mapping_csv(filename)?,
+            None => HashMap::new(),
+        };
+        info!("Using SIRI-ET with {} mapped journeys and {} mapped stops.", trip_mapping.len(), stop_mapping.len());
+        Ok(SiriEtFormat { trip_mapping, stop_mapping })
+    }
+
+    fn get_trip_id<'a>(&'a self, journey: &'a SiriJourney) -> &'a str {
+        self.trip_mapping.get(&journey.dated_vehicle_journey_ref).unwrap_or(&journey.dated_vehicle_journey_ref)
+    }
+
+    fn get_stop_id<'a>(&'a self, call: &'a SiriCall) -> &'a str {
+        self.stop_mapping.get(&call.stop_point_ref).unwrap_or(&call.stop_point_ref)
+    }
+
+    // converts a SIRI journey into a GTFS realtime trip update for the corresponding scheduled trip
+    fn create_trip_update(&self, journey: &SiriJourney, schedule: &Gtfs) -> FnResult<TripUpdate> {
+        let trip_id = self.get_trip_id(journey);
+        let trip: &Trip = schedule.get_trip(trip_id).or_error(&format!("No trip {} for journey {}.", trip_id, journey.dated_vehicle_journey_ref))?;
+        let start_time = trip.stop_times.first().and_then(|st| st.departure_time).or_error("Trip has no departure time")?;
+
+        // the service day is given by the DataFrameRef, or else it's the day of the first call
+        let service_day = match journey.data_frame_ref {
+            Some(date) => date,
+            None => {
+                let first_call = journey.calls.first().or_error("Journey has no calls")?;
+                let first_time = first_call.aimed_departure.or(first_call.aimed_arrival).or_error("Call has no aimed time")?;
+                Local.from_utc_datetime(&first_time.naive_utc()).date().naive_local()
+            },
+        };
+
+        let mut stop_time_updates = Vec::new();
+        let mut min_index = 0;
+        for call in &journey.calls {
+            let stop_id = self.get_stop_id(call);
+            // Find the stop by its id. Searching only after the previous stop handles
+            // routes that visit the same stop twice. Use the order if the stop is unknown.
+            let index = match trip.stop_times.iter().skip(min_index).position(|st| st.stop.id == stop_id) {
+                Some(position) => min_index + position,
+                None => match call.order {
+                    Some(order) if order >= 1 && (order as usize) <= trip.stop_times.len() => order as usize - 1,
+                    _ => {
+                        warn!("Stop {} of journey {} not found in trip {}.", stop_id, journey.dated_vehicle_journey_ref, trip_id);
+                        continue;
+                    },
+                },
+            };
+            min_index = index + 1;
+            let stop_time = &trip.stop_times[index];
+            let arrival = get_stop_time_event(call.aimed_arrival, call.actual_arrival);
+            let departure = get_stop_time_event(call.aimed_departure, call.actual_departure);
+            if arrival.is_none() && departure.is_none() {
+                continue;
+            }
+            stop_time_updates.push(StopTimeUpdate {
+                stop_sequence: Some(stop_time.stop_sequence as u32),
+                stop_id: Some(stop_time.stop.id.clone()),
+                arrival,
+                departure,
+                ..Default::default()
+            });
+        }
+
+        Ok(TripUpdate {
+            trip: TripDescriptor {
+                trip_id: Some(trip.id.clone()),
+                route_id: Some(trip.route_id.clone()),
+                start_date: Some(service_day.format("%Y%m%d").to_string()),
+                start_time: Some(format!("{:02}:{:02}:{:02}", start_time / 3600, (start_time / 60) % 60, start_time % 60)),
+                ..Default::default()
+            },
+            stop_time_update: stop_time_updates,
+            ..Default::default()
+        })
+    }
+}
+
+impl RealtimeFormat for SiriEtFormat {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn parse(&self, data: &[u8], schedule: &Gtfs) -> FnResult<GtfsRealtimeMessage> {
+        let document = roxmltree::Document::parse(std::str::from_utf8(data)?)?;
+        let timestamp = document.descendants().find(|n| n.tag_name().name() == "ResponseTimestamp").and_then(|n| n.text())
+            .or_error("No ResponseTimestamp in SIRI data")?;
+        let timestamp = DateTime::parse_from_rfc3339(timestamp.trim())?.timestamp() as u64;
+
+        let journeys = parse_journeys(&document)?;
+        let mut entities = Vec::with_capacity(journeys.len());
+        for journey in &journeys {
+            match self.create_trip_update(journey, schedule) {
+                Ok(trip_update) => entities.push(FeedEntity {
+                    id: journey.dated_vehicle_journey_ref.clone(),
+                    trip_update: Some(trip_update),
+                    ..Default::default()
+                }),
+                Err(e) => debug!("Skipping SIRI journey {} of line {}: {}", journey.dated_vehicle_journey_ref, journey.line_ref.as_deref().unwrap_or("unknown"), e),
+            }
+        }
+        debug!("Mapped {} of {} SIRI journeys to scheduled trips.", entities.len(), journeys.len());
+
+        Ok(GtfsRealtimeMessage {
+            header: FeedHeader {
+                gtfs_realtime_version: String::from("2.0"),
+                timestamp: Some(timestamp),
+                ..Default::default()
+            },
+            entity: entities,
+            ..Default::default()
+        })
+    }
+}
+
+// the delay is the difference between the actual (or expected) and the aimed time
+fn get_stop_time_event(aimed: Option<DateTime<FixedOffset>>, actual: Option<DateTime<FixedOffset>>) -> Option<StopTimeEvent> {
+    match (aimed, actual) {
+        (Some(aimed), Some(actual)) => Some(StopTimeEvent {
+            delay: Some((actual - aimed).num_seconds() as i32),
+            time: Some(actual.timestamp()),
+            ..Default::default()
+        }),
+        _ => None,
+    }
+}
+
+// returns the text of the (direct) child element with the given local name
+fn find_child_text<'a>(node: roxmltree::Node<'a, 'a>, name: &str) -> Option<&'a str> {
+    node.children().find(|n| n.is_element() && n.tag_name().name() == name).and_then(|n| n.text())
+}
+
+fn find_child_time(node: roxmltree::Node, name: &str) -> FnResult<Option<DateTime<FixedOffset>>> {
+    match find_child_text(node, name) {
+        Some(text) => Ok(Some(DateTime::parse_from_rfc3339(text.trim())?)),
+        None => Ok(None),
+    }
+}
+
+/// Reads all vehicle journeys from a SIRI-ET document. Namespaces are ignored, so that
+/// documents with and without the SIRI namespace can be read.
+pub fn parse_journeys(document: &roxmltree::Document) -> FnResult<Vec<SiriJourney>> {
+    let mut journeys = Vec::new();
+    for journey_node in document.descendants().filter(|n| n.tag_name().name() == "EstimatedVehicleJourney") {
+        // the reference is either given directly or as part of a FramedVehicleJourneyRef
+        let framed_ref = journey_node.children().find(|n| n.tag_name().name() == "FramedVehicleJourneyRef");
+        let dated_vehicle_journey_ref = match find_child_text(journey_node, "DatedVehicleJourneyRef")
+            .or_else(|| framed_ref.and_then(|n| find_child_text(n, "DatedVehicleJourneyRef"))) {
+            Some(reference) => String::from(reference.trim()),
+            None => {
+                warn!("Skipping EstimatedVehicleJourney without DatedVehicleJourneyRef.");
+                continue;
+            }
+        };
+        let data_frame_ref = match framed_ref.and_then(|n| find_child_text(n, "DataFrameRef")) {
+            Some(text) => Some(NaiveDate::parse_from_str(text.trim(), "%Y-%m-%d")?),
+            None => None,
+        };
+
+        let mut calls = Vec::new();
+        for call_node in journey_node.descendants().filter(|n| n.tag_name().name() == "RecordedCall" || n.tag_name().name() == "EstimatedCall") {
+            let stop_point_ref = match find_child_text(call_node, "StopPointRef") {
+                Some(reference) => String::from(reference.trim()),
+                None => continue,
+            };
+            let recorded = call_node.tag_name().name() == "RecordedCall";
+            calls.push(SiriCall {
+                stop_point_ref,
+                order: find_child_text(call_node, "Order").and_then(|order| order.trim().parse().ok()),
+                aimed_arrival: find_child_time(call_node, "AimedArrivalTime")?,
+                actual_arrival: find_child_time(call_node, if recorded { "ActualArrivalTime" } else { "ExpectedArrivalTime" })?,
+                aimed_departure: find_child_time(call_node, "AimedDepartureTime")?,
+                actual_departure: find_child_time(call_node, if recorded { "ActualDepartureTime" } else { "ExpectedDepartureTime" })?,
+            });
+        }
+
+        journeys.push(SiriJourney {
+            dated_vehicle_journey_ref,
+            data_frame_ref,
+            line_ref: find_child_text(journey_node, "LineRef").map(|line| String::from(line.trim())),
+            calls,
+        });
+    }
+    Ok(journeys)
+}
+
+/// Reads a CSV file with two columns: the SIRI reference and the corresponding GTFS id.
+/// A header line is optional.
+fn read_mapping_csv(filename: &str) -> FnResult<HashMap<String, String>> {
+    parse_mapping_csv(File::open(filename)?, filename)
+}
+
+fn parse_mapping_csv<R: Read>(input: R, filename: &str) -> FnResult<HashMap<String, String>> {
+    let mut reader = csv::ReaderBuilder::new().has_headers(false).flexible(true).trim(csv::Trim::All).from_reader(input);
+    let mut mapping = HashMap::new();
+    for (index, record) in reader.records().enumerate() {
+        let record = record?;
+        if index == 0 && record.get(0).map_or(false, |column| column.starts_with("siri")) {
+            continue;
+        }
+        let line = record.position().map_or(index as u64 + 1, |position| position.line());
+        match (record.get(0), record.get(1)) {
+            (Some(siri_ref), Some(gtfs_id)) => {
+                mapping.insert(String::from(siri_ref), String::from(gtfs_id));
+            },
+            _ => bail!("Line {} of {} has less than two columns.", line, filename),
+        }
+    }
+    Ok(mapping)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIRI_ET: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Siri xmlns="http://www.siri.org.uk/siri" version="2.0">
+  <ServiceDelivery>
+    <ResponseTimestamp>2020-08-01T12:00:00+02:00</ResponseTimestamp>
+    <EstimatedTimetableDelivery>
+      <EstimatedJourneyVersionFrame>
+        <EstimatedVehicleJourney>
+          <LineRef>de:vbn:420</LineRef>
+          <FramedVehicleJourneyRef>
+            <DataFrameRef>2020-08-01</DataFrameRef>
+            <DatedVehicleJourneyRef>journey-1</DatedVehicleJourneyRef>
+          </FramedVehicleJourneyRef>
+          <RecordedCalls>
+            <RecordedCall>
+              <StopPointRef>stop-a</StopPointRef>
+              <Order>1</Order>
+              <AimedDepartureTime>2020-08-01T11:50:00+02:00</AimedDepartureTime>
+              <ActualDepartureTime>2020-08-01T11:52:00+02:00</ActualDepartureTime>
+            </RecordedCall>
+          </RecordedCalls>
+          <EstimatedCalls>
+            <EstimatedCall>
+              <StopPointRef>stop-b</StopPointRef>
+              <Order>2</Order>
+              <AimedArrivalTime>2020-08-01T12:05:00+02:00</AimedArrivalTime>
+              <ExpectedArrivalTime>2020-08-01T12:06:30+02:00</ExpectedArrivalTime>
+            </EstimatedCall>
+          </EstimatedCalls>
+        </EstimatedVehicleJourney>
+      </EstimatedJourneyVersionFrame>
+    </EstimatedTimetableDelivery>
+  </ServiceDelivery>
+</Siri>"#;
+
+    #[test]
+    fn test_parse_journeys() {
+        let document = roxmltree::Document::parse(SIRI_ET).unwrap();
+        let journeys = parse_journeys(&document).unwrap();
+        assert_eq!(journeys.len(), 1);
+        let journey = &journeys[0];
+        assert_eq!(journey.dated_vehicle_journey_ref, "journey-1");
+        assert_eq!(journey.data_frame_ref, Some(NaiveDate::from_ymd(2020, 8, 1)));
+        assert_eq!(journey.line_ref.as_deref(), Some("de:vbn:420"));
+        assert_eq!(journey.calls.len(), 2);
+        assert_eq!(journey.calls[0].stop_point_ref, "stop-a");
+        assert_eq!(journey.calls[1].order, Some(2));
+
+        let departure = get_stop_time_event(journey.calls[0].aimed_departure, journey.calls[0].actual_departure).unwrap();
+        assert_eq!(departure.delay, Some(120));
+        let arrival = get_stop_time_event(journey.calls[1].aimed_arrival, journey.calls[1].actual_arrival).unwrap();
+        assert_eq!(arrival.delay, Some(90));
+        assert!(get_stop_time_event(journey.calls[1].aimed_departure, journey.calls[1].actual_departure).is_none());
+    }
+
+    #[test]
+    fn test_parse_mapping_csv() {
+        let csv = "siri_ref,gtfs_id\nde:vbn:420, 420-1\n\n\"de:vbn:6,N\",6-N\n";
+        let mapping = parse_mapping_csv(csv.as_bytes(), "mapping.csv").unwrap();
+        assert_eq!(mapping.len(), 2);
+        assert_eq!(mapping["de:vbn:420"], "420-1");
+        assert_eq!(mapping["de:vbn:6,N"], "6-N");
+
+        assert!(parse_mapping_csv("de:vbn:420\n".as_bytes(), "mapping.csv").is_err());
+    }
+}
@@ -0,0 +1,331 @@
+use chrono::{Duration, Local, TimeZone};
+use clap::ArgMatches;
+use gtfs_rt::FeedMessage as GtfsRealtimeMessage;
+use gtfs_structures::Gtfs;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+
+use super::Importer;
+use super::imported_files::read_realtime_file;
+
+use crate::{FileCache, FnResult, date_from_filename};
+use crate::stats_util::quantile;
+use crate::time_util::date_and_time;
+use crate::types::{EventType, GetByEventType, GtfsDateTime};
+
+// delays beyond this (in seconds, early or late) are most likely errors in the feed
+const MAX_PLAUSIBLE_DELAY: i32 = 3600;
+
+// allowed difference between the time of an event and its scheduled time plus delay, in seconds
+const MAX_TIME_DEVIATION: i64 = 60;
+
+// feed headers may be a bit ahead of our clock
+const MAX_CLOCK_SKEW_SECONDS: i64 = 300;
+
+// how many of the unknown trip_ids are listed in the report
+const MAX_UNKNOWN_TRIP_IDS: usize = 10;
+
+// quantiles of the delay distribution which are shown in the report
+const DELAY_QUANTILES: [(&str, f32); 7] = [("min", 0.0), ("1%", 0.01), ("10%", 0.1), ("median", 0.5), ("90%", 0.9), ("99%", 0.99), ("max", 1.0)];
+
+/// Checks how well realtime files match a schedule, without writing anything into the database
+/// or moving any files, so that problems of a feed can be found before running a long import.
+pub struct FeedValidator<'a> {
+    pub importer: &'a Importer<'a>,
+    pub args: &'a ArgMatches,
+}
+
+/// What `import validate` found in the realtime files.
+#[derive(Default)]
+pub struct ValidationReport {
+    pub files: usize,
+    pub failed_files: usize,
+    pub headers_without_timestamp: usize,
+    pub headers_in_future: usize,
+    pub headers_not_matching_filename: usize,
+    pub trip_updates: usize,
+    pub trip_updates_without_trip_id: usize,
+    pub unknown_trip_updates: usize,
+    /// the first few of the trip_ids that are not in the schedule
+    pub unknown_trip_ids: Vec<String>,
+    pub matched_trip_updates: usize,
+    pub trip_updates_newer_than_header: usize,
+    pub start_time_mismatches: usize,
+    pub stop_time_updates: usize,
+    pub resolved_stop_sequences: usize,
+    pub missing_stop_sequences: usize,
+    pub unknown_stop_sequences: usize,
+    pub stop_id_mismatches: usize,
+    pub events: usize,
+    pub events_without_delay: usize,
+    pub time_mismatches: usize,
+    /// delays of all events of resolved stop time updates, in seconds
+    pub delays: Vec<i32>,
+}
+
+impl<'a> FeedValidator<'a> {
+    pub fn run(&self) -> FnResult<()> {
+        let schedule_filename = self.args.value_of("schedule").unwrap(); // already validated by clap
+        info!("Parsing schedule {}…", schedule_filename);
+        let schedule = FileCache::get_cached_simple(&self.importer.main.gtfs_cache, schedule_filename)?;
+
+        let mut report = ValidationReport::default();
+        for filename in self.args.values_of("rt").unwrap() { // already validated by clap
+            let span = info_span!("realtime_file", file = %filename);
+            let _entered = span.enter();
+            if let Err(e) = self.validate_file(filename, &schedule, &mut report) {
+                error!("Could not read realtime file: {}", e);
+                report.failed_files += 1;
+            }
+        }
+        println!("{}", report);
+        Ok(())
+    }
+
+    fn validate_file(&self, filename: &str, schedule: &Gtfs, report: &mut ValidationReport) -> FnResult<()> {
+        let data = read_realtime_file(filename)?;
+        let message = self.importer.realtime_format.parse(&data, schedule)?;
+        report.files += 1;
+        report.add_header(&message, filename);
+        for trip_update in message.entity.iter().filter_map(|entity| entity.trip_update.as_ref()) {
+            report.add_trip_update(trip_update, message.header.timestamp, schedule);
+        }
+        Ok(())
+    }
+}
+
+impl ValidationReport {
+    fn add_header(&mut self, message: &GtfsRealtimeMessage, filename: &str) {
+        let time = match message.header.timestamp {
+            Some(timestamp) => Local.timestamp(timestamp as i64, 0),
+            None => {
+                self.headers_without_timestamp += 1;
+                return;
+            }
+        };
+        if time > Local::now() + Duration::seconds(MAX_CLOCK_SKEW_SECONDS) {
+            self.headers_in_future += 1;
+        }
+        // the importer assigns files to schedules by the date in their names, so it should match the content
+        let short_filename = Path::new(filename).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        if let Ok(date) = date_from_filename(&short_filename) {
+            if (time - date.and_hms(12, 0, 0)).num_hours().abs() > 36 {
+                self.headers_not_matching_filename += 1;
+            }
+        }
+    }
+
+    fn add_trip_update(&mut self, trip_update: &gtfs_rt::TripUpdate, header_timestamp: Option<u64>, schedule: &Gtfs) {
+        self.trip_updates += 1;
+        if let (Some(update_timestamp), Some(header_timestamp)) = (trip_update.timestamp, header_timestamp) {
+            if update_timestamp > header_timestamp {
+                self.trip_updates_newer_than_header += 1;
+            }
+        }
+        let trip_id = match &trip_update.trip.trip_id {
+            Some(trip_id) => trip_id,
+            None => {
+                self.trip_updates_without_trip_id += 1;
+                return;
+            }
+        };
+        let schedule_trip = match schedule.get_trip(trip_id) {
+            Ok(schedule_trip) => schedule_trip,
+            Err(_) => {
+                self.unknown_trip_updates += 1;
+                if self.unknown_trip_ids.len() < MAX_UNKNOWN_TRIP_IDS && !self.unknown_trip_ids.contains(trip_id) {
+                    self.unknown_trip_ids.push(trip_id.clone());
+                }
+                return;
+            }
+        };
+        self.matched_trip_updates += 1;
+
+        // without a valid start, the times of the events can't be checked. Like the importer, this uses the service day
+        // of the schedule, even if the feed gives trips after midnight with the calendar date.
+        let mut start = GtfsDateTime::from_trip_descriptor(&trip_update.trip).ok();
+        if let (Some(realtime_start), Some(first_departure)) = (&mut start, schedule_trip.stop_times.first().and_then(|st| st.departure_time)) {
+            *realtime_start = realtime_start.with_scheduled_time(first_departure as i32);
+            if realtime_start.duration() != Duration::seconds(first_departure as i64) {
+                self.start_time_mismatches += 1;
+            }
+        }
+
+        for stop_time_update in &trip_update.stop_time_update {
+            self.stop_time_updates += 1;
+            let stop_sequence = match stop_time_update.stop_sequence {
+                Some(stop_sequence) => stop_sequence,
+                None => {
+                    self.missing_stop_sequences += 1;
+                    continue;
+                }
+            };
+            let stop_time = match schedule_trip.stop_times.iter().find(|st| st.stop_sequence as u32 == stop_sequence) {
+                Some(stop_time) => stop_time,
+                None => {
+                    self.unknown_stop_sequences += 1;
+                    continue;
+                }
+            };
+            self.resolved_stop_sequences += 1;
+            if let Some(stop_id) = &stop_time_update.stop_id {
+                if *stop_id != stop_time.stop.id {
+                    self.stop_id_mismatches += 1;
+                }
+            }
+
+            let events = [(EventType::Arrival, &stop_time_update.arrival), (EventType::Departure, &stop_time_update.departure)];
+            for (event_type, event) in events.iter() {
+                let event = match event {
+                    Some(event) => event,
+                    None => continue,
+                };
+                self.events += 1;
+                let delay = match event.delay {
+                    Some(delay) => delay,
+                    None => {
+                        self.events_without_delay += 1;
+                        continue;
+                    }
+                };
+                self.delays.push(delay);
+                if let (Some(time), Some(start), Some(scheduled)) = (event.time, &start, stop_time.get_time(*event_type)) {
+                    // the scheduled time is relative to the service day, not to the start of the trip
+                    let expected = date_and_time(&start.service_day(), scheduled as i32).timestamp() + delay as i64;
+                    if (time - expected).abs() > MAX_TIME_DEVIATION {
+                        self.time_mismatches += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the delays at the quantiles of `DELAY_QUANTILES`, or `None` if there are no delays.
+    fn delay_quantiles(&self) -> Option<Vec<i32>> {
+        if self.delays.is_empty() {
+            return None;
+        }
+        let mut delays = self.delays.clone();
+        Some(DELAY_QUANTILES.iter().map(|(_, q)| quantile(&mut delays, *q)).collect())
+    }
+}
+
+impl Display for ValidationReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Realtime files: {} read, {} failed.", self.files, self.failed_files)?;
+        writeln!(f, "Feed headers: {} without timestamp, {} with a timestamp in the future, {} with a timestamp that doesn't match the date in the file name.",
+            self.headers_without_timestamp, self.headers_in_future, self.headers_not_matching_filename)?;
+        writeln!(f, "Trip updates: {}, of which {} match a trip of the schedule ({}), {} have no trip_id and {} have a trip_id that is not in the schedule.",
+            self.trip_updates, self.matched_trip_updates, percentage(self.matched_trip_updates, self.trip_updates), self.trip_updates_without_trip_id, self.unknown_trip_updates)?;
+        if !self.unknown_trip_ids.is_empty() {
+            writeln!(f, "  Unknown trip_ids include: {}", self.unknown_trip_ids.join(", "))?;
+        }
+        writeln!(f, "  {} have a timestamp newer than their feed header, {} have a start_time that differs from the schedule.",
+            self.trip_updates_newer_than_header, self.start_time_mismatches)?;
+        writeln!(f, "Stop time updates of matched trips: {}, of which {} have a stop_sequence of their trip ({}), {} have no stop_sequence and {} have a stop_sequence that the trip doesn't have.",
+            self.stop_time_updates, self.resolved_stop_sequences, percentage(self.resolved_stop_sequences, self.stop_time_updates), self.missing_stop_sequences, self.unknown_stop_sequences)?;
+        writeln!(f, "  {} have a stop_id that doesn't match their stop_sequence.", self.stop_id_mismatches)?;
+        writeln!(f, "Arrivals and departures: {}, of which {} have no delay and {} have a time that differs from the scheduled time plus delay by more than {} seconds.",
+            self.events, self.events_without_delay, self.time_mismatches, MAX_TIME_DEVIATION)?;
+        match self.delay_quantiles() {
+            Some(quantiles) => {
+                let parts: Vec<String> = DELAY_QUANTILES.iter().zip(quantiles.iter()).map(|((name, _), delay)| format!("{} {}", name, delay)).collect();
+                let implausible = self.delays.iter().filter(|delay| delay.abs() > MAX_PLAUSIBLE_DELAY).count();
+                write!(f, "Delays in seconds: {}. {} delays are more than {} seconds early or late.", parts.join(", "), implausible, MAX_PLAUSIBLE_DELAY)
+            },
+            None => write!(f, "No delays found."),
+        }
+    }
+}
+
+fn percentage(part: usize, total: usize) -> String {
+    if total == 0 {
+        return String::from("-");
+    }
+    format!("{:.1} %", part as f32 * 100.0 / total as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gtfs_rt::{TripDescriptor, TripUpdate};
+    use gtfs_rt::trip_update::{StopTimeEvent, StopTimeUpdate};
+    use crate::test_util::get_test_schedule;
+
+    // a trip update for the second stop of t1 on 2020-10-01, with the given delay and absolute time of the departure
+    fn trip_update(delay: i32, time: i64) -> TripUpdate {
+        TripUpdate {
+            trip: TripDescriptor {
+                trip_id: Some(String::from("t1")),
+                start_date: Some(String::from("20201001")),
+                start_time: Some(String::from("08:00:00")),
+                ..Default::default()
+            },
+            stop_time_update: vec![StopTimeUpdate {
+                stop_sequence: Some(2),
+                stop_id: Some(String::from("s2")),
+                departure: Some(StopTimeEvent { delay: Some(delay), time: Some(time), ..Default::default() }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_add_trip_update() {
+        let schedule = get_test_schedule();
+        let scheduled = Local.ymd(2020, 10, 1).and_hms(8, 10, 0).timestamp();
+        let mut report = ValidationReport::default();
+        report.add_trip_update(&trip_update(60, scheduled + 60), None, &schedule);
+        assert_eq!(report.matched_trip_updates, 1);
+        assert_eq!(report.start_time_mismatches, 0);
+        assert_eq!(report.resolved_stop_sequences, 1);
+        assert_eq!(report.stop_id_mismatches, 0);
+        assert_eq!(report.events, 1);
+        assert_eq!(report.delays, vec![60]);
+        assert_eq!(report.time_mismatches, 0);
+
+        // the time doesn't match the scheduled time plus delay
+        report.add_trip_update(&trip_update(60, scheduled + 600), None, &schedule);
+        assert_eq!(report.time_mismatches, 1);
+
+        let mut unknown = trip_update(0, scheduled);
+        unknown.trip.trip_id = Some(String::from("t9"));
+        report.add_trip_update(&unknown, None, &schedule);
+        assert_eq!(report.unknown_trip_updates, 1);
+        assert_eq!(report.unknown_trip_ids, vec![String::from("t9")]);
+    }
+
+    #[test]
+    fn test_trip_after_midnight() {
+        // t1 shifted to 00:30, which is 24:30 on the previous service day in the schedule
+        let mut schedule = get_test_schedule();
+        let mut trip = schedule.trips["t1"].clone();
+        for stop_time in &mut trip.stop_times {
+            stop_time.arrival_time = stop_time.arrival_time.map(|time| time + 16 * 3600 + 1800);
+            stop_time.departure_time = stop_time.departure_time.map(|time| time + 16 * 3600 + 1800);
+        }
+        schedule.trips.insert(String::from("t1"), trip);
+
+        // the feed gives the calendar date instead of the service day
+        let mut update = trip_update(60, Local.ymd(2020, 10, 2).and_hms(0, 41, 0).timestamp());
+        update.trip.start_date = Some(String::from("20201002"));
+        update.trip.start_time = Some(String::from("00:30:00"));
+        let mut report = ValidationReport::default();
+        report.add_trip_update(&update, None, &schedule);
+        assert_eq!(report.events, 1);
+        assert_eq!(report.start_time_mismatches, 0);
+        assert_eq!(report.time_mismatches, 0);
+    }
+
+    #[test]
+    fn test_delay_quantiles() {
+        let mut report = ValidationReport::default();
+        assert_eq!(report.delay_quantiles(), None);
+        report.delays = (0..=100).rev().map(|delay| delay * 60 - 600).collect();
+        report.delays.push(4000);
+        assert_eq!(report.delay_quantiles(), Some(vec![-600, -540, 0, 2460, 4800, 5340, 5400]));
+        let text = report.to_string();
+        assert!(text.ends_with("Delays in seconds: min -600, 1% -540, 10% 0, median 2460, 90% 4800, 99% 5340, max 5400. 31 delays are more than 3600 seconds early or late."), "{}", text);
+    }
+}
@@ -0,0 +1,67 @@
+//! Some agencies change the trip_ids of their trips during the day, e.g. when they publish a new
+//! schedule. The predictions table identifies predictions by their trip_id, so the same vehicle
+//! would get a second set of predictions under its new trip_id. To avoid that, the prediction
+//! statements (see `get_predictions_statements`) resolve the vehicle of a prediction by its route,
+//! its start date and time, its direction and its first stop, and update the existing predictions of
+//! that vehicle, including their trip_id, instead of adding new ones.
+
+use gtfs_structures::{DirectionType, Gtfs};
+use mysql::Pool;
+
+use crate::FnResult;
+
+/// Returns the direction of a trip as it is written into the `direction_id` column of the predictions.
+/// Trips without a direction match predictions of both directions.
+pub fn get_direction_id(schedule: &Gtfs, trip_id: &str) -> Option<u8> {
+    let trip = schedule.get_trip(trip_id).ok()?;
+    match trip.direction_id.as_ref()? {
+        DirectionType::Outbound => Some(0),
+        DirectionType::Inbound => Some(1),
+    }
+}
+
+/// Returns the id of the first stop of a trip as it is written into the `first_stop_id` column of the
+/// predictions. It tells apart vehicles of the same route that start at the same time in the same
+/// direction, e.g. on two branches of the route.
+pub fn get_first_stop_id(schedule: &Gtfs, trip_id: &str) -> Option<String> {
+    let trip = schedule.get_trip(trip_id).ok()?;
+    Some(trip.stop_times.first()?.stop.id.clone())
+}
+
+/// Adds the `direction_id` and `first_stop_id` columns to the `predictions` table, if they don't exist yet (see
+/// `super::add_column`). Predictions from before these columns have neither, and match predictions of all
+/// directions and first stops.
+pub fn add_columns(pool: &Pool, dry_run: bool) -> FnResult<()> {
+    super::add_column(pool, "predictions", "direction_id", "TINYINT UNSIGNED NULL DEFAULT NULL", dry_run)?;
+    super::add_column(pool, "predictions", "first_stop_id", "VARCHAR(255) NULL DEFAULT NULL", dry_run)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gtfs_structures::Trip;
+    use crate::test_util;
+
+    #[test]
+    fn test_get_direction_id() {
+        let mut schedule = Gtfs::default();
+        for (trip_id, direction_id) in &[("out", Some(DirectionType::Outbound)), ("in", Some(DirectionType::Inbound)), ("loop", None)] {
+            schedule.trips.insert(trip_id.to_string(), Trip { id: trip_id.to_string(), direction_id: direction_id.clone(), ..Default::default() });
+        }
+        assert_eq!(get_direction_id(&schedule, "out"), Some(0));
+        assert_eq!(get_direction_id(&schedule, "in"), Some(1));
+        assert_eq!(get_direction_id(&schedule, "loop"), None);
+        assert_eq!(get_direction_id(&schedule, "unknown"), None);
+    }
+
+    #[test]
+    fn test_get_first_stop_id() {
+        let schedule = test_util::schedule(vec![
+            test_util::trip("t1", "r1", 8 * 3600, 600, &["s1", "s2"]),
+            Trip { id: String::from("empty"), route_id: String::from("r1"), ..Default::default() },
+        ]);
+        assert_eq!(get_first_stop_id(&schedule, "t1"), Some(String::from("s1")));
+        assert_eq!(get_first_stop_id(&schedule, "empty"), None);
+        assert_eq!(get_first_stop_id(&schedule, "unknown"), None);
+    }
+}
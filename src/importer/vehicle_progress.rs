@@ -0,0 +1,195 @@
+use geo::prelude::*;
+use geo::point;
+use gtfs_rt::VehiclePosition;
+use gtfs_rt::vehicle_position::VehicleStopStatus;
+use gtfs_structures::Trip;
+
+use crate::time_util::date_and_time;
+use crate::types::{GtfsDateTime, PredictionBasis};
+
+/// Where a vehicle is on its trip, according to a vehicle position: between the stop with `stop_index`
+/// and the next one, or at the stop with `stop_index` if it is `stopped` there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VehicleProgress {
+    /// index into the stop times of the trip
+    pub stop_index: usize,
+    /// share of the way to the next stop, between 0 and 1
+    pub fraction: f32,
+    pub stopped: bool,
+    /// time at which the vehicle was at that position
+    pub timestamp: u64,
+}
+
+impl VehicleProgress {
+    /// Finds out where the vehicle is, from its current stop sequence and status, and its coordinates if
+    /// given. Returns None for vehicles that haven't departed from the first stop yet.
+    pub fn from_vehicle_position(vehicle: &VehiclePosition, trip: &Trip, header_timestamp: u64) -> Option<Self> {
+        let current_sequence = vehicle.current_stop_sequence?;
+        let current_index = trip.stop_times.iter().position(|stop_time| stop_time.stop_sequence as u32 == current_sequence)?;
+        let timestamp = vehicle.timestamp.unwrap_or(header_timestamp);
+        // the status defaults to "in transit to" the current stop, according to the specification
+        if vehicle.current_status == Some(VehicleStopStatus::StoppedAt as i32) {
+            return Some(VehicleProgress { stop_index: current_index, fraction: 0.0, stopped: true, timestamp });
+        }
+        if current_index == 0 {
+            return None;
+        }
+        let previous = &trip.stop_times[current_index - 1].stop;
+        let next = &trip.stop_times[current_index].stop;
+        let fraction = match (&vehicle.position, previous.latitude, previous.longitude, next.latitude, next.longitude) {
+            (Some(position), Some(previous_lat), Some(previous_lon), Some(next_lat), Some(next_lon)) => {
+                let previous_distance = point!(x: previous_lon, y: previous_lat).haversine_distance(&point!(x: position.longitude as f64, y: position.latitude as f64));
+                let next_distance = point!(x: next_lon, y: next_lat).haversine_distance(&point!(x: position.longitude as f64, y: position.latitude as f64));
+                get_fraction(previous_distance, next_distance)
+            },
+            // without coordinates, it's only known that the vehicle has left the previous stop
+            _ => 0.0,
+        };
+        Some(VehicleProgress { stop_index: current_index - 1, fraction, stopped: false, timestamp })
+    }
+
+    /// The delay (in seconds) of the vehicle at its position, compared to the scheduled time at that position,
+    /// which is interpolated between the scheduled departure at the previous stop and the arrival at the next one.
+    pub fn get_delay(&self, trip: &Trip, start: &GtfsDateTime) -> Option<i64> {
+        let departure = trip.stop_times.get(self.stop_index)?.departure_time?;
+        let scheduled_time = if self.stopped {
+            departure as f32
+        } else {
+            let arrival = trip.stop_times.get(self.stop_index + 1)?.arrival_time?;
+            interpolate_time(departure, arrival, self.fraction)
+        };
+        let delay = self.timestamp as i64 - date_and_time(&start.service_day(), scheduled_time.round() as i32).timestamp();
+        // a vehicle that is early at a stop usually waits until its scheduled departure
+        Some(if self.stopped { delay.max(0) } else { delay })
+    }
+
+    /// Returns a prediction basis which treats the delay at the current position like a departure delay
+    /// at the previous stop, so that the following stops are predicted from the progress of the vehicle.
+    pub fn get_basis(&self, trip: &Trip, start: &GtfsDateTime) -> Option<PredictionBasis> {
+        Some(PredictionBasis {
+            stop_sequence: trip.stop_times.get(self.stop_index)?.stop_sequence,
+            delay_departure: Some(self.get_delay(trip, start)?),
+        })
+    }
+
+    /// For a vehicle between two stops, returns a prediction basis which treats the delay at the current position
+    /// like a departure delay at the next stop, and its weight, which is the share of the way that the vehicle has
+    /// already covered. The predictions for the stops after the next one are mixed from this basis and the one of
+    /// `get_basis`, so that the travel-time curves from both stops are interpolated by the progress of the vehicle.
+    pub fn get_next_basis(&self, trip: &Trip, start: &GtfsDateTime) -> Option<(PredictionBasis, f32)> {
+        if self.stopped || self.fraction <= 0.0 {
+            return None;
+        }
+        let basis = PredictionBasis {
+            stop_sequence: trip.stop_times.get(self.stop_index + 1)?.stop_sequence,
+            delay_departure: Some(self.get_delay(trip, start)?),
+        };
+        Some((basis, self.fraction))
+    }
+}
+
+// share of the way between two stops, from the distances (in any unit) to both of them
+fn get_fraction(previous_distance: f64, next_distance: f64) -> f32 {
+    let total = previous_distance + next_distance;
+    if total <= 0.0 {
+        return 0.0;
+    }
+    (previous_distance / total).max(0.0).min(1.0) as f32
+}
+
+// scheduled time (in seconds since the start of the service day) at the given share of the way
+fn interpolate_time(departure: u32, arrival: u32, fraction: f32) -> f32 {
+    departure as f32 + fraction * (arrival as f32 - departure as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Local, TimeZone};
+    use gtfs_rt::Position;
+    use gtfs_structures::Stop;
+    use std::sync::Arc;
+    use crate::test_util;
+
+    #[test]
+    fn test_interpolation() {
+        assert_eq!(get_fraction(0.0, 0.0), 0.0);
+        assert_eq!(get_fraction(300.0, 100.0), 0.75);
+        assert_eq!(get_fraction(0.0, 250.0), 0.0);
+        assert_eq!(interpolate_time(36000, 36240, 0.75), 36180.0);
+        assert_eq!(interpolate_time(36000, 36000, 0.5), 36000.0);
+    }
+
+    // three stops along a meridian, 10:00, 10:04 and 10:08 on 2020-10-01, with gaps in the stop sequence
+    fn get_trip() -> Trip {
+        let stops = (0..3).map(|index| Arc::new(Stop {
+            id: format!("s{}", index),
+            latitude: Some(53.0 + index as f64 * 0.01),
+            longitude: Some(8.8),
+            ..Default::default()
+        })).collect();
+        let mut trip = test_util::trip_with_stops("t1", "r1", 36000, 240, stops);
+        for stop_time in &mut trip.stop_times {
+            stop_time.stop_sequence = (stop_time.stop_sequence - 1) * 10;
+        }
+        trip
+    }
+
+    fn vehicle(current_stop_sequence: u32, status: VehicleStopStatus, latitude: Option<f32>) -> VehiclePosition {
+        VehiclePosition {
+            current_stop_sequence: Some(current_stop_sequence),
+            current_status: Some(status as i32),
+            position: latitude.map(|latitude| Position { latitude, longitude: 8.8, ..Default::default() }),
+            timestamp: Some(Local.ymd(2020, 10, 1).and_hms(10, 5, 0).timestamp() as u64),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_from_vehicle_position() {
+        let trip = get_trip();
+        // three quarters of the way from the second to the third stop
+        let progress = VehicleProgress::from_vehicle_position(&vehicle(20, VehicleStopStatus::InTransitTo, Some(53.0175)), &trip, 0).unwrap();
+        assert_eq!((progress.stop_index, progress.stopped), (1, false));
+        assert!((progress.fraction - 0.75).abs() < 0.01, "{}", progress.fraction);
+
+        // without coordinates
+        let progress = VehicleProgress::from_vehicle_position(&vehicle(20, VehicleStopStatus::InTransitTo, None), &trip, 0).unwrap();
+        assert_eq!((progress.stop_index, progress.fraction, progress.stopped), (1, 0.0, false));
+
+        let progress = VehicleProgress::from_vehicle_position(&vehicle(10, VehicleStopStatus::StoppedAt, None), &trip, 0).unwrap();
+        assert_eq!((progress.stop_index, progress.fraction, progress.stopped), (1, 0.0, true));
+
+        // not departed yet, or an unknown stop
+        assert!(VehicleProgress::from_vehicle_position(&vehicle(0, VehicleStopStatus::InTransitTo, None), &trip, 0).is_none());
+        assert!(VehicleProgress::from_vehicle_position(&vehicle(15, VehicleStopStatus::InTransitTo, None), &trip, 0).is_none());
+
+        // the time of the message is used if the vehicle has no timestamp
+        let mut without_timestamp = vehicle(10, VehicleStopStatus::StoppedAt, None);
+        without_timestamp.timestamp = None;
+        assert_eq!(VehicleProgress::from_vehicle_position(&without_timestamp, &trip, 12345).unwrap().timestamp, 12345);
+    }
+
+    #[test]
+    fn test_get_delay_and_basis() {
+        let trip = get_trip();
+        let start = GtfsDateTime::new(Local.ymd(2020, 10, 1), 36000);
+        let at = |hour: u32, minute: u32, second: u32| Local.ymd(2020, 10, 1).and_hms(hour, minute, second).timestamp() as u64;
+
+        // at 10:06 according to the schedule, one minute late
+        let progress = VehicleProgress { stop_index: 1, fraction: 0.5, stopped: false, timestamp: at(10, 7, 0) };
+        assert_eq!(progress.get_delay(&trip, &start), Some(60));
+        assert_eq!(progress.get_basis(&trip, &start), Some(PredictionBasis { stop_sequence: 10, delay_departure: Some(60) }));
+        assert_eq!(progress.get_next_basis(&trip, &start), Some((PredictionBasis { stop_sequence: 20, delay_departure: Some(60) }, 0.5)));
+
+        // early at a stop, but waiting for the departure
+        let progress = VehicleProgress { stop_index: 1, fraction: 0.0, stopped: true, timestamp: at(10, 3, 0) };
+        assert_eq!(progress.get_delay(&trip, &start), Some(0));
+        assert_eq!(progress.get_next_basis(&trip, &start), None);
+
+        // there is no next stop after the last one
+        let progress = VehicleProgress { stop_index: 2, fraction: 0.5, stopped: false, timestamp: at(10, 9, 0) };
+        assert_eq!(progress.get_delay(&trip, &start), None);
+        assert_eq!(progress.get_basis(&trip, &start), None);
+    }
+}
@@ -0,0 +1,741 @@
+//! Imports GTFS realtime data into a database, analyses the delays and makes predictions from them.
+//!
+//! Besides the command line interface in the `dystonse-gtfs-data` binary, the prediction logic can be
+//! used from other Rust programs. The most important entry points are:
+//!
+//! * `Main`, which holds the database connection and the configuration. It can be created from
+//!   command line arguments with `Main::new`, or from arguments built by the caller with `Main::from_args`.
+//! * `types::DelayStatistics`, which contains all curves that are used for predictions, and can be loaded with `Loadable`.
+//! * `predictor::Predictor` and the `predictor::PredictionModel` trait for looking up predictions.
+//! * `types::DbPrediction`, a prediction as stored in the `predictions` table.
+//! * the curve creators in `analyser::curves`, `analyser::specific_curves` and `analyser::default_curves`.
+
+pub mod importer;
+pub mod analyser;
+pub mod predictor;
+pub mod types;
+pub mod time_util;
+pub mod stats_util;
+pub mod log_buffer;
+pub mod prediction_events;
+
+#[cfg(test)]
+mod test_util;
+
+#[cfg(feature = "monitor")]
+pub mod monitor;
+
+use std::error::Error;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate tracing;
+
+use clap::{App, Arg, ArgMatches};
+use mysql::*;
+use retry::delay::Fibonacci;
+use retry::retry;
+use simple_error::{SimpleError, bail};
+use chrono::{NaiveDate, Date, Local};
+use chrono::offset::TimeZone;
+use regex::Regex;
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use importer::Importer;
+use analyser::Analyser;
+use predictor::Predictor;
+
+#[cfg(feature = "monitor")]
+use monitor::Monitor;
+
+use gtfs_structures::Gtfs;
+use types::{DelayStatistics, FeedQuirks, HolidayCalendar, SymbolTable, WeatherProvider};
+
+use std::fmt::Debug;
+
+// This is handy, because mysql defines its own Result type and we don't
+// want to repeat std::result::Result
+pub type FnResult<R> = std::result::Result<R, Box<dyn Error>>;
+
+pub struct Main {
+    pub pool: Arc<Pool>,
+    pub args: ArgMatches,
+    pub source: String,
+    pub dir: String,
+    pub holidays: HolidayCalendar,
+    pub weather: Option<WeatherProvider>,
+    pub feed_quirks: FeedQuirks,
+    //file caches using Mutexes so main doesn't have to be mutable:
+    gtfs_cache: Mutex<FileCache<Gtfs>>,
+    all_statistics_cache: Mutex<FileCache<DelayStatistics>>,
+    default_statistics_cache: Mutex<FileCache<DelayStatistics>>,
+}
+
+/// Converts an `Option` or `Result` into an `FnResult` with a readable error message.
+pub trait OrError<T> {
+    fn or_error(self, message: &str) -> FnResult<T>;
+}
+
+impl<T> OrError<T> for Option<T> {
+    fn or_error(self, message: &str) -> FnResult<T> {
+        if self.is_none() {
+            bail!(message);
+        }
+        Ok(self.unwrap())
+    }
+}
+
+impl<T, E> OrError<T> for std::result::Result<T, E>
+where E: Debug
+{
+    fn or_error(self, message: &str) -> FnResult<T> {
+        match self {
+            Err(e) => bail!(format!("{}\nInner error message: {:?}", message, e)),
+            Ok(t) => Ok(t)
+        }
+    }
+}
+
+/// Reads contents of the given directory and returns an alphabetically sorted list of included files / subdirectories as Vector of Strings.
+pub fn read_dir_simple(path: &str) -> FnResult<Vec<String>> {
+    let mut path_list: Vec<String> = fs::read_dir(path)?
+        .filter_map(|r| r.ok()) // unwraps Options and ignores any None values
+        .map(|d| {
+            String::from(d.path().to_str().expect(&format!(
+                "Found file with invalid UTF8 in file name in directory {}.",
+                &path
+            )))
+        })
+        .collect();
+    path_list.sort();
+    Ok(path_list)
+}
+
+/// Like `read_dir_simple`, but also lists the files in date-based subdirectories (like `YYYY/MM/DD`
+/// or `YYYY-MM-DD`), as written by some fetchers. Other subdirectories are listed, but not entered.
+/// The files are sorted by the date in their path below `path` first and by their path second,
+/// so that the newest files are still the last ones.
+pub fn read_dir_recursive(path: &str) -> FnResult<Vec<String>> {
+    let mut path_list = Vec::new();
+    collect_files_in_date_dirs(Path::new(path), &mut path_list)?;
+    path_list.sort_by_cached_key(|filename| {
+        let relative = filename.strip_prefix(path).unwrap_or(filename); // all paths start with path
+        (date_from_filename(relative).ok(), filename.clone())
+    });
+    Ok(path_list)
+}
+
+fn collect_files_in_date_dirs(dir: &Path, path_list: &mut Vec<String>) -> FnResult<()> {
+    for entry in fs::read_dir(dir)?.filter_map(|r| r.ok()) {
+        let path = entry.path();
+        let filename = String::from(path.to_str().expect(&format!(
+            "Found file with invalid UTF8 in file name in directory {}.",
+            dir.display()
+        )));
+        if path.is_dir() && is_date_dir(&entry.file_name().to_string_lossy()) {
+            collect_files_in_date_dirs(&path, path_list)?;
+        } else {
+            path_list.push(filename);
+        }
+    }
+    Ok(())
+}
+
+// whether a directory is part of a date-based directory tree, like `2020`, `10` or `2020-10-17`
+fn is_date_dir(name: &str) -> bool {
+    let is_number = !name.is_empty() && name.chars().all(|c| c.is_ascii_digit());
+    (is_number && (name.len() == 4 || name.len() == 2)) || date_from_filename(name).is_ok()
+}
+
+pub fn date_from_filename(filename: &str) -> FnResult<Date<Local>> {
+    lazy_static! {
+        // dates in file names (YYYY-MM-DD) or in the directories of date-based directory trees (YYYY/MM/DD),
+        // but not with mixed separators
+        static ref FIND_DATE: Regex = Regex::new(r"(\d{4})-(\d{2})-(\d{2})|(\d{4})/(\d{2})/(\d{2})").unwrap(); // can't fail because our hard-coded regex is known to be ok
+    }
+    let date_element_captures =
+        FIND_DATE
+            .captures(&filename)
+            .or_error(&format!(
+            "File name does not contain a valid date (does not match format YYYY-MM-DD): {}",
+            filename
+        ))?;
+    // year, month and day from whichever of both formats matched
+    let date_elements: Vec<&str> = date_element_captures.iter().skip(1).flatten().map(|m| m.as_str()).collect();
+    let naive_date_option = NaiveDate::from_ymd_opt(
+        date_elements[0].parse().unwrap(), // can't fail because input string is known to be a bunch of decimal digits
+        date_elements[1].parse().unwrap(), // can't fail because input string is known to be a bunch of decimal digits
+        date_elements[2].parse().unwrap(), // can't fail because input string is known to be a bunch of decimal digits
+    );
+    let naive_date = naive_date_option.ok_or(SimpleError::new(format!("File name does not contain a valid date (format looks ok, but values are out of bounds): {}", filename)))?;
+    let date = Local.from_local_date(&naive_date).unwrap(); 
+    
+    Ok (date)
+}
+
+/// Returns the definition of all command line arguments and subcommands.
+pub fn get_app<'a>() -> App<'a> {
+    #[allow(unused_mut)]
+    let mut app = App::new("dystonse-gtfs-data")
+        .subcommand(Importer::get_subcommand())
+        .subcommand(Analyser::get_subcommand())
+        .subcommand(Predictor::get_subcommand())            
+        .arg(Arg::new("verbose")
+            .short('v')
+            .long("verbose")
+            .about("Output status messages during run. Shorthand for --log-level debug.")
+        ).arg(Arg::new("log-level")
+            .long("log-level")
+            .env("LOG_LEVEL")
+            .about("Only log messages with at least this level are written to stderr.")
+            .takes_value(true)
+            .value_name("LEVEL")
+            .possible_values(&["error", "warn", "info", "debug", "trace"])
+            .default_value("info")
+        ).arg(Arg::new("log-format")
+            .long("log-format")
+            .env("LOG_FORMAT")
+            .about("Format of the log messages. With json, each message is written as one JSON object per line, including its context (e.g. source, file name and trip_id).")
+            .takes_value(true)
+            .value_name("FORMAT")
+            .possible_values(&["text", "json"])
+            .default_value("text")
+        ).arg(Arg::new("password")
+            .short('p')
+            .long("password")
+            .env("DB_PASSWORD")
+            .takes_value(true)
+            .about("Password used to connect to the database.")
+            .required_unless("help")
+        ).arg(Arg::new("user")
+            .short('u')
+            .long("user")
+            .env("DB_USER")
+            .takes_value(true)
+            .about("User on the database.")
+            .default_value("dystonse")
+        ).arg(Arg::new("host")
+            .long("host")
+            .env("DB_HOST")
+            .takes_value(true)
+            .about("Host on which the database can be connected.")
+            .default_value("localhost")   
+        ).arg(Arg::new("port")
+            .long("port")
+            .env("DB_PORT")
+            .takes_value(true)
+            .about("Port on which the database can be connected.")
+            .default_value("3306")
+        ).arg(Arg::new("database")
+            .short('d')
+            .long("database")
+            .env("DB_DATABASE")
+            .takes_value(true)
+            .about("Database name which will be selected.")
+            .default_value("dystonse")
+        ).arg(Arg::new("source")
+            .short('s')
+            .long("source")
+            .env("GTFS_DATA_SOURCE_ID")
+            .takes_value(true)
+            .about("Source identifier for the data sets. Used to distinguish data sets with non-unique ids.")
+            .required_unless("help")
+        ).arg(Arg::new("dir")
+            .long("dir")
+            .value_name("DIRECTORY")
+            .required_unless("help")
+            .about("The directory which contains schedules, realtime files, and precomputed curves")
+            .long_about(
+                "The directory that contains the schedules, realtime files, (located in a subdirectory named 'schedules' or 'rt') \
+                and precomputed curve data."
+            )
+        ).arg(Arg::new("schedule")
+            .long("schedule")
+            .about("The path of the GTFS schedule that is used to look up any static GTFS data.")
+            .takes_value(true)
+            .value_name("GTFS_SCHEDULE")
+        ).arg(Arg::new("prediction-model")
+            .long("prediction-model")
+            .env("PREDICTION_MODEL")
+            .about("The model which is used to make delay predictions. Can be set per source via the environment, so that models can be compared.")
+            .takes_value(true)
+            .value_name("MODEL")
+            .possible_values(&predictor::MODEL_NAMES)
+            .default_value("statistics")
+        ).arg(Arg::new("curve-blending")
+            .long("curve-blending")
+            .env("CURVE_BLENDING")
+            .about("How specific curves are mixed with the default curves, depending on their sample size n. With shrinkage, the weight of the specific curve is n / (n + k), with linear it is min(n / k, 1), where k is set with curve-blending-samples. With off, specific curves are used alone.")
+            .takes_value(true)
+            .value_name("FORMULA")
+            .possible_values(&predictor::BLENDING_FORMULAS)
+            .default_value("off")
+        ).arg(Arg::new("curve-blending-samples")
+            .long("curve-blending-samples")
+            .env("CURVE_BLENDING_SAMPLES")
+            .about("The number of samples k in the formula of curve-blending.")
+            .takes_value(true)
+            .value_name("SAMPLES")
+            .default_value("50")
+        ).arg(Arg::new("holiday-state")
+            .long("holiday-state")
+            .env("HOLIDAY_STATE")
+            .about("Two-letter abbreviation of the German federal state (e.g. NI) whose public holidays get their own time slot.")
+            .takes_value(true)
+            .value_name("STATE")
+        ).arg(Arg::new("public-holidays-ics")
+            .long("public-holidays-ics")
+            .about("ICS file with additional public holidays, which get their own time slot. Can be given several times.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("FILE")
+        ).arg(Arg::new("school-holidays-ics")
+            .long("school-holidays-ics")
+            .about("ICS file with school holidays. Workdays within school holidays get their own time slots. Can be given several times.")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("FILE")
+        ).arg(Arg::new("weather-api")
+            .long("weather-api")
+            .env("WEATHER_API")
+            .about("URL of a weather API that is compatible with Bright Sky (https://brightsky.dev), including the location, e.g. https://api.brightsky.dev/weather?lat=53.08&lon=8.81")
+            .takes_value(true)
+            .value_name("URL")
+            .conflicts_with("weather-csv")
+        ).arg(Arg::new("weather-csv")
+            .long("weather-csv")
+            .about("CSV file with the columns time and condition, which is used instead of a weather API.")
+            .takes_value(true)
+            .value_name("FILE")
+        ).arg(Arg::new("zero-delay-means-missing")
+            .long("zero-delay-means-missing")
+            .env("ZERO_DELAY_MEANS_MISSING")
+            .about("Whether the realtime feed gives a delay of 0 instead of no delay when it has no data for a stop. If true, such delays are ignored by the importer and the curve creators.")
+            .takes_value(true)
+            .value_name("BOOL")
+            .possible_values(&["true", "false"])
+            .default_value("false")
+        ).arg(Arg::new("feed-delay-granularity")
+            .long("feed-delay-granularity")
+            .env("FEED_DELAY_GRANULARITY")
+            .about("The realtime feed rounds its delays towards zero to multiples of this many seconds. The curve creators round all delays that way, unless delay-rounding is given.")
+            .takes_value(true)
+            .value_name("SECONDS")
+            .default_value("12")
+        ).arg(Arg::new("max-trustworthy-delay")
+            .long("max-trustworthy-delay")
+            .env("MAX_TRUSTWORTHY_DELAY")
+            .about("If provided, delays of more than this many seconds (early or late) are considered errors of the realtime feed, and are ignored by the importer and the curve creators.")
+            .takes_value(true)
+            .value_name("SECONDS")
+        );
+
+        #[cfg(feature = "redis-events")]
+        {
+            app = app.arg(Arg::new("redis-url")
+                .long("redis-url")
+                .env("REDIS_URL")
+                .about("URL of a Redis server, like redis://localhost:6379. If provided, the importer publishes which predictions it has written, and the monitor subscribes to these events to update the stop pages right away.")
+                .takes_value(true)
+                .value_name("URL")
+            );
+        }
+
+        #[cfg(feature = "monitor")]
+        {
+            app = app.subcommand(Monitor::get_subcommand());
+        } 
+
+    return app;
+}
+
+fn parse_args() -> ArgMatches {
+        let app = get_app();
+
+        // use those lines to profile the bianry on MacOS
+        // due to a bug in [cargo-]flamegraph command line args are forbidden
+        // let testargs = ["dystonse-gtfs-data", "--host", "hetzner.dystonse.org", "--password", "PASSWORD_HERE", "--source", "vbn", "--dir", "data", "analyse", "compute-curves", "--route-ids", "35761_0"];
+        // let matches = app.get_matches_from(testargs.iter());
+        
+        let matches = app.get_matches();
+    return matches;
+}
+
+/// Sets up logging according to the `log-level` and `log-format` arguments. Log messages are
+/// written to stderr, so that they don't mix with the output of commands like `analyse count`.
+fn init_logging(args: &ArgMatches) -> FnResult<()> {
+    let mut level: tracing::Level = args.value_of("log-level").unwrap().parse()?; // already validated by clap
+    if args.is_present("verbose") && level != tracing::Level::TRACE {
+        level = tracing::Level::DEBUG;
+    }
+    // the recent warnings and errors are kept for the admin endpoints of the monitor
+    let registry = tracing_subscriber::registry()
+        .with(tracing_subscriber::filter::LevelFilter::from_level(level))
+        .with(log_buffer::RecentErrorsLayer);
+    let result = match args.value_of("log-format").unwrap() { // already validated by clap
+        "json" => registry.with(tracing_subscriber::fmt::layer().json().with_writer(std::io::stderr)).try_init(),
+        _ => registry.with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr)).try_init(),
+    };
+    if let Err(e) = result {
+        bail!("Could not initialize logging: {}", e);
+    }
+    Ok(())
+}
+
+impl Main {
+    /// Constructs a new instance of Main, with parsed arguments and a ready-to-use pool of database connections.
+    /// Also sets up logging.
+    pub fn new() -> FnResult<Main> {
+        let args = parse_args();
+        init_logging(&args)?;
+        Main::from_args(args)
+    }
+
+    /// Constructs a new instance of Main from the given arguments (see `get_app`), with a
+    /// ready-to-use pool of database connections. Logging is left to the caller.
+    pub fn from_args(args: ArgMatches) -> FnResult<Main> {
+        let source = String::from(args.value_of("source").unwrap()); // already validated by clap
+        let dir = String::from(args.value_of("dir").unwrap()); // already validated by clap
+        let holidays = HolidayCalendar::from_args(&args)?;
+        let weather = WeatherProvider::from_args(&args)?;
+        let feed_quirks = FeedQuirks::from_args(&args)?;
+
+        debug!("Connecting to database…");
+        let pool = retry(Fibonacci::from_millis(1000), || {
+            Main::open_db(&args)
+        })
+        .expect("DB connections should succeed eventually.");
+        Ok(Main {
+            args,
+            pool: Arc::new(pool),
+            source,
+            dir,
+            holidays,
+            weather,
+            feed_quirks,
+            gtfs_cache: Mutex::new(FileCache::<Gtfs>::new()),
+            all_statistics_cache: Mutex::new(FileCache::<DelayStatistics>::new()),
+            default_statistics_cache: Mutex::new(FileCache::<DelayStatistics>::new()),
+        })
+    }
+
+    /// Runs the actions that are selected via the command line args
+    pub fn run(self: Arc<Self>) -> FnResult<()> {
+        match self.args.clone().subcommand() {
+            ("import", Some(sub_args)) => {
+                let mut importer = Importer::new(&self, sub_args)?;
+                importer.run()
+            },
+            ("analyse", Some(sub_args)) => {
+                let mut analyser = Analyser::new(&self, sub_args);
+                analyser.run()
+            },
+            ("predict", Some(sub_args)) => {
+                let mut predictor = Predictor::new(&self, sub_args)?;
+                predictor.run()
+            },
+            #[cfg(feature = "monitor")]
+            ("monitor", Some(sub_args)) => {
+                Monitor::run(self.clone(), sub_args)
+            },
+            _ => panic!("Invalid arguments."),
+        }
+    }
+
+    /// Opens a connection to a database and returns the resulting connection pool.
+    /// Takes configuration values from DB_PASSWORD, DB_USER, DB_HOST, DB_PORT and DB_DATABASE
+    /// environment variables. For all values except DB_PASSWORD a default is provided.
+    fn open_db(args: &ArgMatches) -> FnResult<Pool> {
+        debug!("Trying to connect to the database.");
+        let url = format!(
+            "mysql://{}:{}@{}:{}/{}",
+            args.value_of("user").unwrap(), // already validated by clap
+            args.value_of("password").unwrap(), // already validated by clap
+            args.value_of("host").unwrap(), // already validated by clap
+            args.value_of("port").unwrap(), // already validated by clap
+            args.value_of("database").unwrap()  // already validated by clap
+        );
+        let pool = Pool::new(url)?;
+        Ok(pool)
+    }
+
+    // returns the schedule (from args or auto-lookup)
+    pub fn get_schedule(&self) -> FnResult<Arc<Gtfs>> {
+        let filename = self.get_schedule_filename()?;
+        FileCache::get_cached_simple(&self.gtfs_cache, &filename)
+    }
+
+    pub fn get_schedule_filename(&self) -> FnResult<String> {
+        // find out if schedule arg is given, the monitor may be pinned to a schedule with its own arg:
+        let schedule_arg = self.args.subcommand_matches("monitor")
+            .and_then(|monitor_args| monitor_args.value_of("schedule"))
+            .or_else(|| self.args.value_of("schedule"));
+        let schedule_filename : String = 
+        if let Some(filename) = schedule_arg {
+            filename.to_string()
+        } else {
+            // if the arg is not given, look up the newest schedule file:
+            info!("No schedule file name given, looking up the most recent schedule file…");
+            let dir = self.args.value_of("dir").unwrap(); // already validated by clap
+            let schedule_dir = format!("{}/schedule", dir);
+            let schedule_filenames = read_dir_recursive(&schedule_dir)?; //list of all schedule files
+            schedule_filenames.last().or_error("No schedule found when trying to find the newest schedule file.")?.clone() //return the newest file (last filename)
+        };
+        info!("Using schedule '{}'", schedule_filename);
+        Ok(schedule_filename)
+    }
+
+    /// Loads the schedule again if a newer schedule file exists or the file has changed, while
+    /// `get_schedule` keeps returning the previous schedule. Returns whether it has been loaded.
+    pub fn refresh_schedule(&self) -> FnResult<bool> {
+        let filename = self.get_schedule_filename()?;
+        FileCache::refresh_simple(&self.gtfs_cache, &filename)
+    }
+
+    /// Loads the files of the delay statistics again if they have changed, while `get_delay_statistics`
+    /// keeps using the previous ones. Returns whether any of them has been loaded, so that the merged
+    /// statistics need to be fetched again. Missing files are not an error here.
+    pub fn refresh_delay_statistics(&self) -> FnResult<bool> {
+        let mut changed = false;
+        for (cache, name) in &[(&self.all_statistics_cache, "all_curves.exp"), (&self.default_statistics_cache, "default_curves.exp")] {
+            let filename = format!("{}/{}", self.dir, name);
+            if fs::metadata(&filename).is_ok() {
+                changed |= FileCache::refresh_simple(cache, &filename)?;
+            }
+        }
+        Ok(changed)
+    }
+
+    pub fn get_delay_statistics(&self) -> FnResult<Arc<DelayStatistics>> {
+        let all_statistics_res     = FileCache::get_cached_simple(&self.all_statistics_cache    , &format!("{}/all_curves.exp"    , self.dir));
+        let default_statistics_res = FileCache::get_cached_simple(&self.default_statistics_cache, &format!("{}/default_curves.exp", self.dir));
+
+        if let Ok(all_statistics) = all_statistics_res {
+            if let Ok(default_statistics) = default_statistics_res {
+                info!("Merging all_curves.exp and default_curves.exp...");
+                let merged_statistics = DelayStatistics {
+                    specific: all_statistics.as_ref().specific.clone(),
+                    general: default_statistics.as_ref().general.clone(),
+                    operation: all_statistics.as_ref().operation.clone(),
+                    curve_parameters: all_statistics.as_ref().curve_parameters.clone(),
+                    outlier_policy: all_statistics.as_ref().outlier_policy,
+                    feed_quirks: all_statistics.as_ref().feed_quirks,
+                    calibration: all_statistics.as_ref().calibration.clone(),
+                };
+                info!("Using merged delay statistics.");
+                return Ok(Arc::new(merged_statistics));
+            } else {
+                info!("Using generated delay statistics (all_curves.exp).");
+                return Ok(all_statistics);
+            }
+        } else if let Ok(default_statistics) = default_statistics_res {
+            info!("Using default delay statistics (default_curves.exp).");
+            return Ok(default_statistics);
+        } else {
+            bail!("No delay statistics (neither all_curves.exp nor default_curves.exp were found)."); 
+        }
+    }
+}
+
+pub struct FileCache<T> {
+    object: Option<Arc<T>>,
+    filename: Option<String>,
+    modification_time: Option<std::time::SystemTime>,
+    // file name and modification time of the file that `refresh_simple` is loading right now
+    pending: Option<(String, std::time::SystemTime)>,
+}
+
+impl<T> FileCache<T> where T: Loadable<T> {
+
+    //creates a new, empty file cache
+    pub fn new() -> FileCache<T> {
+        return FileCache::<T> {
+            object: None,
+            filename: None,
+            modification_time: None,
+            pending: None,
+        }
+    }
+
+    // wrapper around get_cached so the mutex stuff does not have to be repeated
+    pub fn get_cached_simple(cache: &Mutex<Self>, filename: &str) -> FnResult<Arc<T>> {
+        let mut cache_lock = cache.lock().unwrap();
+        cache_lock.get_cached(filename)
+    }
+
+    // Loads the file again if it has changed, like get_cached_simple, but without holding the lock
+    // while loading, so that everyone else keeps getting the old object until the new one is ready.
+    // Returns whether a new object has been loaded.
+    pub fn refresh_simple(cache: &Mutex<Self>, filename: &str) -> FnResult<bool> {
+        let mod_time = fs::metadata(filename)?.modified()?;
+        {
+            let mut cache_lock = cache.lock().unwrap();
+            let is_current = cache_lock.object.is_some()
+                && cache_lock.filename.as_deref() == Some(filename)
+                && cache_lock.modification_time == Some(mod_time);
+            let is_pending = cache_lock.pending.as_ref().map_or(false, |(f, mt)| f == filename && *mt == mod_time);
+            if is_current || is_pending {
+                return Ok(false);
+            }
+            cache_lock.pending = Some((filename.to_string(), mod_time));
+        }
+
+        info!("Loading {} in the background...", filename);
+        let now = Instant::now();
+        let result = <T>::load(filename);
+        let mut cache_lock = cache.lock().unwrap();
+        cache_lock.pending = None;
+        let obj = result?;
+        info!("...loading {} took {} seconds.", filename, now.elapsed().as_secs());
+        cache_lock.object = Some(Arc::new(obj));
+        cache_lock.filename = Some(filename.to_string());
+        cache_lock.modification_time = Some(mod_time);
+        Ok(true)
+    }
+
+    // Returns the cached object. 
+    // If possible, use get_cached_simple instead to avoid dealing with mutex stuff directly.
+    pub fn get_cached(&mut self, filename: &str) -> FnResult<Arc<T>> {
+
+        let mut filename_changed = true;
+        let mut modtime_changed = true;
+
+        let metadata = fs::metadata(filename)?;
+        let mod_time = metadata.modified()?;
+
+        // while refresh_simple loads this file, the previous object is still good enough
+        if let (Some(o), Some((f, mt))) = (&self.object, &self.pending) {
+            if f == filename && *mt == mod_time {
+                return Ok(o.clone());
+            }
+        }
+
+        //compare filenames:
+        if let Some(f) = &self.filename {
+            if &f == &filename {
+                filename_changed = false;
+
+                //compare modification times:
+                if let Some(mt) = self.modification_time {
+                    if mt == mod_time {
+                        modtime_changed = false;
+                    } else {
+                        self.modification_time = Some(mod_time);
+                    }
+                } else {
+                    self.modification_time = Some(mod_time);
+                }
+            } else {
+                self.filename = Some(filename.to_string());
+                self.modification_time = Some(mod_time);
+            }
+        } else {
+            self.filename = Some(filename.to_string());
+            self.modification_time = Some(mod_time);
+        }
+
+        //reload file if anything changed:
+        if filename_changed || modtime_changed {
+            self.object = None;
+            info!("Loading {}...", filename);
+            let now = Instant::now();
+            let obj = <T>::load(filename)?;
+            info!("...loading {} took {} seconds.", filename, now.elapsed().as_secs());
+            self.object = Some(Arc::new(obj));
+        }
+
+        match &self.object {
+            Some(o) => Ok(o.clone()),
+            None => bail!("Object {} could not be returned from cache. Loading probably failed in a previous iteration.", filename)
+        }
+    }
+} 
+
+pub trait Loadable<T> {
+    fn load(filename: &str) -> FnResult<T>;
+}
+
+impl Loadable<Gtfs> for Gtfs {
+    fn load(filename: &str) -> FnResult<Gtfs> {
+        let gtfs = Gtfs::new(filename)?;
+        SymbolTable::global().add_schedule(&gtfs);
+        return Ok(gtfs);
+    }
+}
+
+impl Loadable<DelayStatistics> for DelayStatistics {
+    fn load(filename: &str) -> FnResult<DelayStatistics> {
+
+        let mut f = File::open(filename).expect(&format!("Could not open {}", filename));
+        let mut buffer = Vec::<u8>::new();
+        f.read_to_end(&mut buffer)?;
+        let parsed = rmp_serde::from_read_ref::<_, Self>(&buffer)?;
+
+        return Ok(parsed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_date_from_filename() {
+        assert_eq!(date_from_filename("rt_2020-10-17T08:00:00.pb").unwrap(), Local.ymd(2020, 10, 17));
+        assert_eq!(date_from_filename("/2020/10/17/rt.pb").unwrap(), Local.ymd(2020, 10, 17));
+        assert!(date_from_filename("rt_2020-10/17.pb").is_err());
+        assert!(date_from_filename("2020/10-17").is_err());
+        assert!(date_from_filename("rt_2020-13-01.pb").is_err());
+    }
+
+    #[test]
+    fn test_is_date_dir() {
+        assert!(is_date_dir("2020"));
+        assert!(is_date_dir("10"));
+        assert!(is_date_dir("2020-10-17"));
+        assert!(!is_date_dir("123"));
+        assert!(!is_date_dir("rt"));
+        assert!(!is_date_dir(""));
+    }
+
+    #[test]
+    fn test_read_dir_recursive() {
+        let dir = std::env::temp_dir().join(format!("read_dir_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        // files directly in the directory, in a date tree, in a date directory and in another directory
+        let files = [
+            "rt_2020-10-18T08:00:00.pb",
+            "rt_2020-10-16T08:00:00.pb",
+            "2020/10/17/rt_a.pb",
+            "2020-10-15/rt_b.pb",
+            "other/rt_2020-10-14T08:00:00.pb",
+        ];
+        for file in &files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path).unwrap();
+        }
+
+        let path = dir.to_str().unwrap();
+        let result = read_dir_recursive(path);
+        fs::remove_dir_all(&dir).unwrap();
+        let relative: Vec<String> = result.unwrap().iter().map(|filename| filename[path.len() + 1..].to_string()).collect();
+        // sorted by date, and the other directory is listed, but not entered
+        assert_eq!(relative, vec![
+            "other",
+            "2020-10-15/rt_b.pb",
+            "rt_2020-10-16T08:00:00.pb",
+            "2020/10/17/rt_a.pb",
+            "rt_2020-10-18T08:00:00.pb",
+        ]);
+    }
+}
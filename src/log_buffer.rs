@@ -0,0 +1,101 @@
+//! Keeps the most recent warnings and errors in memory, so that they can be inspected
+//! without access to the log output, e.g. via the admin endpoints of the monitor.
+
+use chrono::Local;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::sync::Mutex;
+use tracing::{Event, Level, Subscriber};
+use tracing::field::{Field, Visit};
+use tracing_subscriber::layer::{Context, Layer};
+
+// older messages are dropped
+const MAX_ENTRIES: usize = 200;
+
+lazy_static! {
+    static ref RECENT_ERRORS: Mutex<VecDeque<LoggedError>> = Mutex::new(VecDeque::new());
+}
+
+/// A warning or error that was logged.
+#[derive(Serialize, Clone, Debug)]
+pub struct LoggedError {
+    /// RFC 3339
+    pub time: String,
+    pub level: String,
+    /// the module that logged the message
+    pub target: String,
+    pub message: String,
+}
+
+/// A logging layer that keeps the last warnings and errors, independent of the log level.
+pub struct RecentErrorsLayer;
+
+impl<S: Subscriber> Layer<S> for RecentErrorsLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        // more verbose levels are greater
+        if *event.metadata().level() > Level::WARN {
+            return;
+        }
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        let mut entries = RECENT_ERRORS.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(LoggedError {
+            time: Local::now().to_rfc3339(),
+            level: event.metadata().level().to_string(),
+            target: String::from(event.metadata().target()),
+            message: visitor.into_line(),
+        });
+    }
+}
+
+// collects the message and all other fields of an event
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: Vec<String>,
+}
+
+impl MessageVisitor {
+    fn into_line(self) -> String {
+        std::iter::once(self.message).chain(self.fields.into_iter()).filter(|part| !part.is_empty()).collect::<Vec<_>>().join(" ")
+    }
+}
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.fields.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+}
+
+/// Returns the recent warnings and errors, the newest first.
+pub fn get_recent_errors() -> Vec<LoggedError> {
+    RECENT_ERRORS.lock().unwrap().iter().rev().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_keeps_warnings_and_errors() {
+        let subscriber = tracing_subscriber::registry().with(RecentErrorsLayer);
+        tracing::subscriber::with_default(subscriber, || {
+            info!("not kept");
+            warn!("kept warning {}", 42);
+            error!(file = "a.pb", "kept error");
+        });
+        let errors = get_recent_errors();
+        assert!(errors.iter().any(|e| e.level == "ERROR" && e.message == "kept error file=\"a.pb\""));
+        assert!(errors.iter().any(|e| e.level == "WARN" && e.message == "kept warning 42"));
+        assert!(!errors.iter().any(|e| e.message.contains("not kept")));
+    }
+}
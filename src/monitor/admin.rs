@@ -0,0 +1,164 @@
+use chrono::Duration;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper::header::HeaderValue;
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::FnResult;
+use crate::types::FeedQuirks;
+use crate::analyser::health::HealthReport;
+use crate::log_buffer::get_recent_errors;
+use super::{Monitor, into_response, generate_error_page};
+use super::health_page::{MAX_GAP_MINUTES, LOOKBACK_HOURS};
+
+/// State of the import pipeline and of the monitor itself.
+#[derive(Serialize)]
+struct AdminStatus {
+    schedule: String,
+    statistics_loaded: String,
+    /// true while the monitor runs without statistics and shows the scheduled departures only
+    statistics_missing: bool,
+    last_rt_file: Option<String>,
+    last_rt_file_time: Option<String>,
+    /// start of the time span of `rt_file_count` and `gaps`, see `HealthReport::since`
+    rt_files_since: String,
+    rt_file_count: usize,
+    last_recording: Option<String>,
+    /// whether the last realtime file is older than the maximum gap
+    stale: bool,
+    /// periods without realtime files since `rt_files_since`
+    gaps: Vec<(String, String)>,
+    scheduled_trips: usize,
+    covered_trips: usize,
+    cached_curve_images: usize,
+    /// how the realtime feed of this source is read
+    feed_quirks: FeedQuirks,
+    /// how the realtime feed was read when the loaded statistics were computed
+    statistics_feed_quirks: FeedQuirks,
+}
+
+/// Serves the endpoints under `/admin/`, which are only available if an admin token has been
+/// configured, and only to requests with that token in an `Authorization: Bearer` header:
+///
+/// * `GET /admin/status` shows the state of the realtime import and the monitor.
+/// * `GET /admin/errors` lists the recent warnings and errors from the log, the newest first.
+/// * `POST /admin/reload-statistics` loads the statistics again, if their files have changed.
+/// * `POST /admin/reload` loads the schedule and the statistics again, if their files have changed.
+/// * `POST /admin/clear-caches` drops all data that the monitor keeps between requests.
+pub async fn handle_admin_request(req: Request<Body>, monitor: Arc<Monitor>, path_parts: Vec<String>) -> Response<Body> {
+    // without a token, nobody can use the admin endpoints, so they don't exist
+    let token = match &monitor.admin_token {
+        Some(token) => token,
+        None => return generate_error_page(StatusCode::NOT_FOUND, "Not found.").unwrap(), // can't fail, see generate_error_page
+    };
+    if !is_authorized(&req, token) {
+        let mut response = generate_error_page(StatusCode::UNAUTHORIZED, "A valid admin token is needed.").unwrap(); // can't fail, see generate_error_page
+        response.headers_mut().insert(hyper::header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        return response;
+    }
+
+    let path_parts_str: Vec<&str> = path_parts.iter().map(|part| part.as_str()).collect();
+    let method = req.method().clone();
+    let action: fn(&Arc<Monitor>) -> FnResult<Response<Body>> = match (&method, &path_parts_str[..]) {
+        (&Method::GET, ["status"]) => generate_status,
+        (&Method::GET, ["errors"]) => generate_errors,
+        (&Method::POST, ["reload-statistics"]) => reload_statistics,
+        (&Method::POST, ["reload"]) => reload,
+        (&Method::POST, ["clear-caches"]) => clear_caches,
+        (_, ["status"]) | (_, ["errors"]) | (_, ["reload-statistics"]) | (_, ["reload"]) | (_, ["clear-caches"]) => {
+            return generate_error_page(StatusCode::METHOD_NOT_ALLOWED, &format!("Method {} is not allowed here.", method)).unwrap(); // can't fail, see generate_error_page
+        },
+        _ => return generate_error_page(StatusCode::NOT_FOUND, "Unknown admin endpoint.").unwrap(), // can't fail, see generate_error_page
+    };
+
+    // all actions may access the database or the disk, which must not block the threads of the server
+    let blocking_monitor = monitor.clone();
+    match tokio::task::spawn_blocking(move || into_response(action(&blocking_monitor))).await {
+        Ok(response) => response,
+        Err(e) => generate_error_page(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()).unwrap(), // can't fail, see generate_error_page
+    }
+}
+
+// compares the token in constant time, so that it can't be guessed from the response times
+fn is_authorized(req: &Request<Body>, token: &str) -> bool {
+    let given = match req.headers().get(hyper::header::AUTHORIZATION).and_then(|value| value.to_str().ok()) {
+        Some(value) if value.starts_with("Bearer ") => value["Bearer ".len()..].trim(),
+        _ => return false,
+    };
+    given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn json_response<T: Serialize>(value: &T) -> FnResult<Response<Body>> {
+    let mut response = Response::new(Body::from(serde_json::to_vec_pretty(value)?));
+    response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
+    response.headers_mut().append(hyper::header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    Ok(response)
+}
+
+fn generate_status(monitor: &Arc<Monitor>) -> FnResult<Response<Body>> {
+    let schedule = monitor.main.get_schedule()?;
+    let report = HealthReport::compute(&monitor.main, &schedule, Duration::minutes(MAX_GAP_MINUTES), Duration::hours(LOOKBACK_HOURS))?;
+    json_response(&AdminStatus {
+        schedule: monitor.main.get_schedule_filename()?,
+        statistics_loaded: monitor.get_stats_loaded_time().to_rfc3339(),
+        statistics_missing: monitor.is_statistics_missing(),
+        last_rt_file: report.last_rt_file.as_ref().map(|(name, _)| name.clone()),
+        last_rt_file_time: report.last_rt_file.as_ref().map(|(_, time)| time.to_rfc3339()),
+        rt_files_since: report.since.to_rfc3339(),
+        rt_file_count: report.rt_file_count,
+        last_recording: report.last_recording.map(|time| time.to_rfc3339()),
+        stale: report.is_stale(),
+        gaps: report.gaps.iter().map(|(start, end)| (start.to_rfc3339(), end.to_rfc3339())).collect(),
+        scheduled_trips: report.scheduled_trips(),
+        covered_trips: report.covered_trips(),
+        cached_curve_images: monitor.curve_images.count(),
+        feed_quirks: monitor.main.feed_quirks,
+        statistics_feed_quirks: monitor.get_stats().feed_quirks,
+    })
+}
+
+fn generate_errors(_monitor: &Arc<Monitor>) -> FnResult<Response<Body>> {
+    json_response(&get_recent_errors())
+}
+
+fn reload_statistics(monitor: &Arc<Monitor>) -> FnResult<Response<Body>> {
+    monitor.reload_stats()?;
+    info!("Reloaded the statistics on request of an admin.");
+    json_response(&serde_json::json!({ "statistics_loaded": monitor.get_stats_loaded_time().to_rfc3339() }))
+}
+
+fn reload(monitor: &Arc<Monitor>) -> FnResult<Response<Body>> {
+    let (schedule_changed, statistics_changed) = monitor.reload_changed()?;
+    info!("Reloaded the schedule ({}) and the statistics ({}) on request of an admin.",
+        if schedule_changed { "changed" } else { "unchanged" }, if statistics_changed { "changed" } else { "unchanged" });
+    json_response(&serde_json::json!({
+        "schedule": monitor.main.get_schedule_filename()?,
+        "schedule_changed": schedule_changed,
+        "statistics_loaded": monitor.get_stats_loaded_time().to_rfc3339(),
+        "statistics_changed": statistics_changed,
+    }))
+}
+
+fn clear_caches(monitor: &Arc<Monitor>) -> FnResult<Response<Body>> {
+    let dropped_images = monitor.clear_caches();
+    info!("Cleared the caches on request of an admin.");
+    json_response(&serde_json::json!({ "dropped_curve_images": dropped_images }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_with_authorization(value: &str) -> Request<Body> {
+        Request::builder().header("Authorization", value).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_is_authorized() {
+        assert!(is_authorized(&request_with_authorization("Bearer s3cret"), "s3cret"));
+        assert!(!is_authorized(&request_with_authorization("Bearer s3cre"), "s3cret"));
+        assert!(!is_authorized(&request_with_authorization("Bearer s3cret!"), "s3cret"));
+        assert!(!is_authorized(&request_with_authorization("Basic s3cret"), "s3cret"));
+        assert!(!is_authorized(&Request::new(Body::empty()), "s3cret"));
+    }
+}
@@ -0,0 +1,109 @@
+use chrono::{Duration, Local};
+use hyper::{Body, Response};
+use hyper::header::HeaderValue;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::FnResult;
+use super::{Monitor, bad_request, escape_html, format_delay, get_type_bubble};
+use super::departure_filter::DepartureFilter;
+use super::favorites::{get_current_stop_data, get_next_departures};
+use super::journey_data::WalkProfile;
+
+// defaults for the query parameters
+const DEFAULT_ROWS: usize = 8;
+const MAX_ROWS: usize = 40;
+const MIN_REFRESH_SECONDS: u64 = 10;
+
+// how far in the future departures are shown
+const LOOKAHEAD_MINUTES: i64 = 90;
+
+/// Serves `/board/<stop>`, a departure board for screens at stops or in offices. It shows the next
+/// departures in large letters and reloads itself, without any links for building journeys.
+/// The number of rows is set with `?rows=`, the departures can be restricted like on stop pages with
+/// `?routes=`, `?types=` and `?direction=` (see `DepartureFilter`) and the reload interval in seconds with `?refresh=` (default: the live update interval).
+pub fn generate_board_page(monitor: &Arc<Monitor>, stop_name: &str, query_params: &HashMap<String, String>, accessible: bool, walk_profile: WalkProfile) -> FnResult<Response<Body>> {
+    let rows = match query_params.get("rows").map(|rows| rows.parse::<usize>()) {
+        Some(Ok(rows)) if rows >= 1 && rows <= MAX_ROWS => rows,
+        Some(_) => return bad_request(&format!("Parameter 'rows' must be a number between 1 and {}.", MAX_ROWS)),
+        None => DEFAULT_ROWS,
+    };
+    let refresh = match query_params.get("refresh").map(|refresh| refresh.parse::<u64>()) {
+        Some(Ok(refresh)) if refresh >= MIN_REFRESH_SECONDS => refresh,
+        Some(_) => return bad_request(&format!("Parameter 'refresh' must be a number of at least {} seconds.", MIN_REFRESH_SECONDS)),
+        None => u64::max(monitor.live_update_interval.as_secs(), MIN_REFRESH_SECONDS),
+    };
+    let departure_filter = DepartureFilter::from_query_params(query_params)?;
+
+    let now = Local::now();
+    let stop_data = get_current_stop_data(monitor, stop_name, now, accessible, walk_profile)?;
+    let mut departures = get_next_departures(monitor, &stop_data, accessible, now, Duration::minutes(LOOKAHEAD_MINUTES))?;
+    departures.retain(|dep| departure_filter.matches(dep.meta_data.as_ref().unwrap())); // departures without meta data have been removed
+    departures.truncate(rows);
+
+    let mut w = Vec::new();
+    write!(&mut w, r#"
+    <html>
+        <head>
+            <title>{title}</title>
+            <link rel="stylesheet" href="/style.css">
+
+            {favicon_headers}
+
+            <meta name=viewport content="width=device-width, initial-scale=1">
+            <meta http-equiv="refresh" content="{refresh}">
+        </head>
+        <body class="board">
+            <div class="board-header"><span class="board-stop">{stop_name}</span><span class="board-clock">{time}</span></div>
+            <table class="board">"#,
+        title = monitor.theme.page_title(&stop_data.stop_name),
+        stop_name = escape_html(&stop_data.stop_name),
+        favicon_headers = monitor.theme.head_elements(),
+        refresh = refresh,
+        time = now.format("%H:%M"),
+    )?;
+
+    for dep in &departures {
+        let md = dep.meta_data.as_ref().unwrap(); // departures without meta data have been removed
+        let (type_letter, type_class) = get_type_bubble(md.route_type, &md.route_name);
+        let median = dep.get_absolute_time_for_probability(0.50)?;
+        let minutes = (median - now).num_minutes();
+        let delay = dep.get_relative_time_for_probability(0.50) / 60;
+        write!(&mut w, r#"
+                <tr>
+                    <td class="area type"><span class="bubble {type_class}">{type_letter}</span></td>
+                    <td class="board-route">{route_name}</td>
+                    <td class="board-headsign">{headsign}</td>
+                    <td class="board-scheduled">{scheduled}</td>
+                    <td class="board-delay{late}">{delay}</td>
+                    <td class="board-countdown">{countdown}</td>
+                </tr>"#,
+            type_class = type_class,
+            type_letter = type_letter,
+            route_name = escape_html(&md.route_name),
+            headsign = escape_html(&md.headsign),
+            scheduled = md.scheduled_time_absolute.format("%H:%M"),
+            late = if delay >= 3 { " late" } else { "" },
+            delay = if delay == 0 { String::new() } else { format_delay(delay) },
+            countdown = if minutes < 1 { String::from("sofort") } else { format!("{} min", minutes) },
+        )?;
+    }
+    if departures.is_empty() {
+        write!(&mut w, r#"
+                <tr><td class="board-empty">Keine Abfahrten in den nächsten {} Minuten.</td></tr>"#, LOOKAHEAD_MINUTES)?;
+    }
+
+    write!(&mut w, r#"
+            </table>
+            <div class="board-footer">Abfahrtszeiten: Median der Prognose · Datenquelle(n): {sources}</div>
+        </body>
+    </html>"#,
+        sources = monitor.source_attribution,
+    )?;
+
+    let mut response = Response::new(Body::from(w));
+    response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+    response.headers_mut().append(hyper::header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    Ok(response)
+}
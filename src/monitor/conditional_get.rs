@@ -0,0 +1,217 @@
+use chrono::{DateTime, Local, Utc};
+use hyper::{Body, Request, Response, StatusCode};
+use hyper::header::HeaderValue;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use crate::FnResult;
+use super::{Monitor, get_stop_page_time_range};
+use super::journey_data::{JourneyData, StopData};
+use super::live_updates::to_live_predictions_json;
+use super::page_models::StopPagePredictions;
+
+/// how long (in seconds) browsers and proxies may use static files without asking again
+pub const STATIC_FILES_MAX_AGE: u32 = 3600;
+
+/// The validators that a client sent along with a request, to find out whether its cached copy is still current.
+#[derive(Clone, Default)]
+pub struct ConditionalHeaders {
+    if_none_match: Option<String>,
+    if_modified_since: Option<DateTime<Utc>>,
+}
+
+impl ConditionalHeaders {
+    pub fn from_request(req: &Request<Body>) -> Self {
+        let get = |name: hyper::header::HeaderName| req.headers().get(name).and_then(|value| value.to_str().ok());
+        ConditionalHeaders {
+            if_none_match: get(hyper::header::IF_NONE_MATCH).map(String::from),
+            // invalid dates are ignored, as required by RFC 7232
+            if_modified_since: get(hyper::header::IF_MODIFIED_SINCE)
+                .and_then(|value| DateTime::parse_from_rfc2822(value).ok())
+                .map(|date| date.with_timezone(&Utc)),
+        }
+    }
+
+    /// Whether the cached copy of the client is still current. If-None-Match takes precedence over If-Modified-Since.
+    pub fn is_not_modified(&self, etag: &str, last_modified: Option<DateTime<Utc>>) -> bool {
+        if let Some(if_none_match) = &self.if_none_match {
+            // weak comparison, because the pages are equivalent but not byte-identical (e.g. curve image URLs)
+            return if_none_match.split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == "*" || tag == etag.trim_start_matches("W/"));
+        }
+        match (self.if_modified_since, last_modified) {
+            // HTTP dates only have whole seconds
+            (Some(since), Some(last_modified)) => last_modified.timestamp() <= since.timestamp(),
+            _ => false,
+        }
+    }
+}
+
+/// ETag and Last-Modified of a dynamic page, which can be computed without rendering the page.
+pub struct PageValidator {
+    pub etag: String,
+    pub last_modified: DateTime<Utc>,
+}
+
+impl PageValidator {
+    /// Answers with `304 Not Modified` if the client's copy is current, otherwise renders the page with `render`.
+    /// Either way, the response gets the validators and may be cached for `max_age` seconds.
+    pub fn respond<F>(&self, conditional: &ConditionalHeaders, max_age: u64, render: F) -> FnResult<Response<Body>>
+        where F: FnOnce() -> FnResult<Response<Body>>
+    {
+        let mut response = if conditional.is_not_modified(&self.etag, Some(self.last_modified)) {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            response
+        } else {
+            render()?
+        };
+        let headers = response.headers_mut();
+        headers.insert(hyper::header::ETAG, HeaderValue::from_str(&self.etag)?);
+        headers.insert(hyper::header::LAST_MODIFIED, HeaderValue::from_str(&format_http_date(self.last_modified))?);
+        headers.insert(hyper::header::CACHE_CONTROL, HeaderValue::from_str(&format!("max-age={}, must-revalidate", max_age))?);
+        // the accessible mode, the walk profile and the risk preference may be selected by cookies
+        headers.insert(hyper::header::VARY, HeaderValue::from_static("Cookie"));
+        Ok(response)
+    }
+}
+
+/// Remembers since when the predictions for each stop page have been unchanged. The database doesn't
+/// store when a prediction was written, so this is the time when the monitor saw them first.
+pub struct PredictionVersions {
+    // hash of the predictions, when they were seen first, and when the time span of the page is over
+    versions: Mutex<HashMap<String, (u64, DateTime<Utc>, DateTime<Local>)>>,
+}
+
+impl PredictionVersions {
+    pub fn new() -> Self {
+        PredictionVersions {
+            versions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn clear(&self) {
+        self.versions.lock().unwrap().clear();
+    }
+
+    /// Forgets the versions of the pages that show one of the stops, because their predictions have been updated.
+    #[cfg(feature = "redis-events")]
+    pub fn forget_stops(&self, stop_ids: &std::collections::BTreeSet<String>) {
+        // the keys start with the stop ids of the page, see get_stop_page_validator
+        self.versions.lock().unwrap().retain(|key, _| {
+            let page_stop_ids = key.split('|').next().unwrap_or_default();
+            !page_stop_ids.split(',').any(|stop_id| stop_ids.contains(stop_id))
+        });
+    }
+
+    fn get_modification_time(&self, key: String, hash: u64, expires: DateTime<Local>) -> DateTime<Utc> {
+        let mut versions = self.versions.lock().unwrap();
+        if let Some((known_hash, since, _)) = versions.get(&key) {
+            if *known_hash == hash {
+                return *since;
+            }
+        }
+        let now = Local::now();
+        versions.retain(|_, (_, _, expires)| *expires > now);
+        let since = Utc::now();
+        versions.insert(key, (hash, since, expires));
+        since
+    }
+}
+
+/// Computes the validators of a stop page from the predictions that are shown on it, the arrival of the
+/// user at the stop and the schedule. The predictions are those that the page is built from, so that
+/// they are only looked up once, and the page only needs to be rendered if it has changed.
+pub fn get_stop_page_validator(monitor: &Arc<Monitor>, journey_data: &JourneyData, stop_data: &StopData, page_predictions: &StopPagePredictions) -> FnResult<PageValidator> {
+    let (min_time, _len_time, max_time) = get_stop_page_time_range(journey_data, stop_data);
+    let predictions = to_live_predictions_json(&page_predictions.departures)?;
+    let schedule_filename = monitor.main.get_schedule_filename()?;
+    let schedule_modified = DateTime::<Utc>::from(std::fs::metadata(&schedule_filename)?.modified()?);
+
+    let mut prediction_hasher = DefaultHasher::new();
+    predictions.hash(&mut prediction_hasher);
+    let prediction_hash = prediction_hasher.finish();
+    let key = format!("{}|{}|{}", stop_data.extended_stop_ids.join(","), min_time.to_rfc3339(), max_time.to_rfc3339());
+    let predictions_modified = monitor.prediction_versions.get_modification_time(key, prediction_hash, max_time);
+
+    let mut hasher = DefaultHasher::new();
+    prediction_hash.hash(&mut hasher);
+    schedule_filename.hash(&mut hasher);
+    schedule_modified.timestamp().hash(&mut hasher);
+    journey_data.accessible.hash(&mut hasher);
+    journey_data.walk_profile.name().hash(&mut hasher);
+    let thresholds = &journey_data.display_thresholds;
+    for value in &[thresholds.min_chance, thresholds.curve_trim, thresholds.extended_stops_radius, thresholds.alternatives_threshold] {
+        value.to_bits().hash(&mut hasher);
+    }
+    thresholds.risk.name().hash(&mut hasher);
+    journey_data.departure_filter.hash(&mut hasher);
+    journey_data.time_window.hash(&mut hasher);
+    for probability in &[0.01, 0.50, 0.99] {
+        stop_data.start_curve.typed_x_at_y(*probability).timestamp().hash(&mut hasher);
+    }
+    stop_data.start_prob.to_bits().hash(&mut hasher);
+
+    Ok(PageValidator {
+        etag: format!("W/\"{:016x}\"", hasher.finish()),
+        last_modified: std::cmp::max(predictions_modified, schedule_modified),
+    })
+}
+
+/// Adds the cache headers to a static file, and answers with `304 Not Modified` if the client sent its current ETag.
+/// The file server already compares the modification time with If-Modified-Since.
+pub fn add_static_file_validation(mut response: Response<Body>, conditional: &ConditionalHeaders) -> Response<Body> {
+    if response.status() != StatusCode::OK && response.status() != StatusCode::NOT_MODIFIED {
+        return response;
+    }
+    let etag = response.headers().get(hyper::header::ETAG).and_then(|value| value.to_str().ok()).map(String::from);
+    if let Some(etag) = etag {
+        if response.status() == StatusCode::OK && conditional.is_not_modified(&etag, None) {
+            *response.body_mut() = Body::empty();
+            *response.status_mut() = StatusCode::NOT_MODIFIED;
+            response.headers_mut().remove(hyper::header::CONTENT_LENGTH);
+        }
+    }
+    response.headers_mut().insert(hyper::header::CACHE_CONTROL, HeaderValue::from_str(&format!("public, max-age={}", STATIC_FILES_MAX_AGE)).unwrap()); // only ASCII
+    response
+}
+
+// the date format of HTTP headers, e.g. "Sun, 06 Nov 1994 08:49:37 GMT"
+fn format_http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn request_with(name: &str, value: &str) -> Request<Body> {
+        Request::builder().header(name, value).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let conditional = ConditionalHeaders::from_request(&request_with("If-None-Match", r#""abc", W/"def""#));
+        assert!(conditional.is_not_modified(r#""abc""#, None));
+        assert!(conditional.is_not_modified(r#"W/"def""#, None));
+        assert!(!conditional.is_not_modified(r#""xyz""#, None));
+        // If-None-Match takes precedence, even if the date would match
+        assert!(!conditional.is_not_modified(r#""xyz""#, Some(Utc.ymd(2000, 1, 1).and_hms(0, 0, 0))));
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        let last_modified = Utc.ymd(2020, 11, 6).and_hms(8, 49, 37);
+        let conditional = ConditionalHeaders::from_request(&request_with("If-Modified-Since", &format_http_date(last_modified)));
+        assert!(conditional.is_not_modified(r#""abc""#, Some(last_modified)));
+        assert!(!conditional.is_not_modified(r#""abc""#, Some(last_modified + chrono::Duration::seconds(1))));
+        assert!(!conditional.is_not_modified(r#""abc""#, None));
+
+        let invalid = ConditionalHeaders::from_request(&request_with("If-Modified-Since", "yesterday"));
+        assert!(!invalid.is_not_modified(r#""abc""#, Some(last_modified)));
+    }
+}
@@ -0,0 +1,186 @@
+use hyper::{Body, Request, Response, StatusCode};
+use hyper::header::HeaderValue;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::FnResult;
+use super::{Monitor, escape_html, generate_error_page};
+use super::journey_url::parse_start_date_time;
+
+// the sitemap protocol allows at most this many URLs per file
+const MAX_SITEMAP_URLS: usize = 50000;
+
+// parts of the user agents of crawlers and scripts, in lower case
+const CRAWLER_MARKERS: [&str; 10] = ["bot", "crawl", "spider", "slurp", "scrapy", "python", "curl", "wget", "headless", "preview"];
+
+// prefixes of paths that contain a journey after the prefix
+const JOURNEY_PREFIXES: [&str; 3] = ["info", "ics", "live"];
+
+// rules of robots.txt. Journeys start with the date (e.g. `/17.10.20 12:00/`), so the journeys with more
+// elements than a stop page (i.e. with at least three more slashes) are matched by the first digit of the day.
+const DISALLOWED_PATHS: [&str; 17] = [
+    "/0*/*/*/",
+    "/1*/*/*/",
+    "/2*/*/*/",
+    "/3*/*/*/",
+    "/info/",
+    "/ics/",
+    "/live/",
+    "/curve/",
+    "/admin/",
+    "/autocomplete",
+    "/favorites",
+    "/map/data",
+    "/walk/",
+    "/*?from=",
+    "/*&from=",
+    "/*?to=",
+    "/*&to=",
+];
+
+/// Serves `/robots.txt`. Crawlers should only visit the search page and the stop pages from the sitemap,
+/// not the longer journeys, images and endpoints that are derived from them.
+pub fn generate_robots_txt(monitor: &Arc<Monitor>, req: &Request<Body>) -> FnResult<Response<Body>> {
+    let mut body = String::from("User-agent: *\n");
+    for path in &DISALLOWED_PATHS {
+        body.push_str(&format!("Disallow: {}\n", path));
+    }
+    body.push_str(&format!("\nSitemap: {}/sitemap.xml\n", get_base_url(monitor, req)));
+    let mut response = Response::new(Body::from(body));
+    response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+    Ok(response)
+}
+
+/// Serves `/sitemap.xml`, which lists one URL per stop name. The stop-by-name URLs always lead to the
+/// current departures, unlike the URLs of the stop pages, which contain the time of the request.
+pub fn generate_sitemap(monitor: &Arc<Monitor>, req: &Request<Body>) -> FnResult<Response<Body>> {
+    let schedule = monitor.main.get_schedule()?;
+    let stop_names: BTreeSet<&str> = schedule.stops.values().map(|stop| stop.name.as_str()).collect();
+    if stop_names.len() > MAX_SITEMAP_URLS {
+        warn!("The sitemap only contains the first {} of {} stop names.", MAX_SITEMAP_URLS, stop_names.len());
+    }
+    let base_url = get_base_url(monitor, req);
+
+    let mut w = Vec::new();
+    write!(&mut w, r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+    <url><loc>{}/</loc></url>"#, escape_html(&base_url))?;
+    for stop_name in stop_names.iter().take(MAX_SITEMAP_URLS - 1) {
+        write!(&mut w, r#"
+    <url><loc>{}</loc></url>"#,
+            escape_html(&format!("{}/stop-by-name?start={}", base_url, url::form_urlencoded::byte_serialize(stop_name.as_bytes()).collect::<String>())),
+        )?;
+    }
+    write!(&mut w, "\n</urlset>\n")?;
+
+    let mut response = Response::new(Body::from(w));
+    response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("application/xml; charset=utf-8"));
+    Ok(response)
+}
+
+/// Answers requests of crawlers for journeys with a time window (see `TimeWindow`) with 404, because it
+/// can be moved endlessly and each of the pages needs database lookups. If `max_depth` is set, the same
+/// goes for journeys with more elements after the start time, whose number is practically infinite.
+/// Returns None for all other requests.
+pub fn block_deep_crawl(req: &Request<Body>, path_parts: &[String], max_depth: Option<usize>) -> Option<Response<Body>> {
+    let depth = get_journey_depth(path_parts)?;
+    let time_window = has_time_window(req.uri().query());
+    if max_depth.map_or(true, |max_depth| depth <= max_depth) && !time_window {
+        return None;
+    }
+    let user_agent = req.headers().get(hyper::header::USER_AGENT).and_then(|value| value.to_str().ok());
+    if is_browser(user_agent) {
+        return None;
+    }
+    debug!("Refused journey with {} elements (time window: {}) for user agent {:?}.", depth, time_window, user_agent);
+    Some(generate_error_page(StatusCode::NOT_FOUND, "Journeys of this length or with a time window are not available for crawlers.").unwrap()) // can't fail, see generate_error_page
+}
+
+// whether the query contains one of the parameters of a `TimeWindow`
+fn has_time_window(query: Option<&str>) -> bool {
+    match query {
+        Some(query) => url::form_urlencoded::parse(query.as_bytes()).any(|(name, _)| name == "from" || name == "to"),
+        None => false,
+    }
+}
+
+// number of elements of the journey in the path (after the start time), or None if the path isn't a journey
+fn get_journey_depth(path_parts: &[String]) -> Option<usize> {
+    let journey = match path_parts.first() {
+        Some(prefix) if JOURNEY_PREFIXES.contains(&prefix.as_str()) => &path_parts[1..],
+        _ => path_parts,
+    };
+    match parse_start_date_time(journey.first()) {
+        Ok(_) => Some(journey.len() - 1),
+        Err(_) => None,
+    }
+}
+
+// whether the user agent looks like a browser operated by a person
+fn is_browser(user_agent: Option<&str>) -> bool {
+    match user_agent {
+        Some(user_agent) => {
+            let user_agent = user_agent.to_lowercase();
+            user_agent.starts_with("mozilla/") && !CRAWLER_MARKERS.iter().any(|marker| user_agent.contains(marker))
+        },
+        None => false,
+    }
+}
+
+// the URL of the website without trailing slash, as configured or as requested
+fn get_base_url(monitor: &Arc<Monitor>, req: &Request<Body>) -> String {
+    if let Some(public_url) = &monitor.public_url {
+        return public_url.trim_end_matches('/').to_string();
+    }
+    let host = req.headers().get(hyper::header::HOST).and_then(|value| value.to_str().ok()).unwrap_or("localhost");
+    format!("http://{}", host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crawl_control() {
+        let path = |path: &str| -> Vec<String> { path.split('/').filter(|part| !part.is_empty()).map(String::from).collect() };
+        assert_eq!(get_journey_depth(&path("/17.10.20 12:00/Domsheide/")), Some(1));
+        assert_eq!(get_journey_depth(&path("/info/17.10.20 12:00/Domsheide/Tram 4 nach Arsten um 12:05 am 17.10.20/")), Some(2));
+        assert_eq!(get_journey_depth(&path("/board/Domsheide")), None);
+        assert_eq!(get_journey_depth(&path("/")), None);
+
+        assert!(has_time_window(Some("from=2020-10-17T12%3A00")));
+        assert!(has_time_window(Some("types=bus&to=2020-10-17T12%3A00")));
+        assert!(!has_time_window(Some("types=bus&auto=1")));
+        assert!(!has_time_window(None));
+
+        assert!(is_browser(Some("Mozilla/5.0 (X11; Linux x86_64; rv:81.0) Gecko/20100101 Firefox/81.0")));
+        assert!(!is_browser(Some("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)")));
+        assert!(!is_browser(Some("curl/7.68.0")));
+        assert!(!is_browser(None));
+    }
+
+    // whether a rule of robots.txt matches the path, with `*` as wildcard like the big crawlers support it
+    fn matches_rule(rule: &[u8], path: &[u8]) -> bool {
+        match rule.split_first() {
+            None => true,
+            Some((&b'*', rest)) => (0..=path.len()).any(|i| matches_rule(rest, &path[i..])),
+            Some((c, rest)) => path.first() == Some(c) && matches_rule(rest, &path[1..]),
+        }
+    }
+
+    #[test]
+    fn test_robots_rules() {
+        let disallowed = |path: &str| DISALLOWED_PATHS.iter().any(|rule| matches_rule(rule.as_bytes(), path.as_bytes()));
+        assert!(!disallowed("/"));
+        assert!(!disallowed("/stop-by-name?start=Domsheide"));
+        assert!(!disallowed("/17.10.20%2012:00/"));
+        assert!(!disallowed("/17.10.20%2012:00/Domsheide/"));
+        assert!(!disallowed("/07.10.20%2012:00/Domsheide/?types=bus"));
+        assert!(disallowed("/17.10.20%2012:00/Domsheide/Tram%204%20nach%20Arsten%20um%2012:05%20am%2017.10.20/"));
+        assert!(disallowed("/31.10.20%2012:00/Domsheide/Tram%204%20nach%20Arsten%20um%2012:05%20am%2031.10.20/Arsten/"));
+        assert!(disallowed("/17.10.20%2012:00/Domsheide/?from=2020-10-17T12%3A00"));
+        assert!(disallowed("/info/17.10.20%2012:00/Domsheide/"));
+        assert!(!disallowed("/help/"));
+    }
+}
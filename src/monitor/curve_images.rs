@@ -0,0 +1,158 @@
+use hyper::{Body, Response, StatusCode};
+use hyper::header::HeaderValue;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::FnResult;
+use super::{Monitor, bad_request};
+
+// how many images are kept in memory. Pages that are older than the oldest image show empty strips.
+const MAX_CACHED_IMAGES: usize = 50_000;
+
+/// Keeps the PNG images of the probability strips, so that pages can reference them by URL
+/// instead of inlining them. The URLs are derived from the content of the images, so that
+/// the same strip has the same URL on all pages and browsers can cache it forever.
+pub struct CurveImageCache {
+    images: Mutex<(HashMap<String, Arc<Vec<u8>>>, VecDeque<String>)>,
+}
+
+impl CurveImageCache {
+    pub fn new() -> Self {
+        CurveImageCache {
+            images: Mutex::new((HashMap::new(), VecDeque::new())),
+        }
+    }
+
+    /// Stores the image (if it's not already known) and returns the URL under which it is served.
+    pub fn store(&self, png: Vec<u8>) -> String {
+        let mut hasher = DefaultHasher::new();
+        png.hash(&mut hasher);
+        let name = format!("{:016x}", hasher.finish());
+
+        let mut images = self.images.lock().unwrap();
+        let (by_name, order) = &mut *images;
+        if !by_name.contains_key(&name) {
+            if order.len() >= MAX_CACHED_IMAGES {
+                if let Some(oldest) = order.pop_front() {
+                    by_name.remove(&oldest);
+                }
+            }
+            by_name.insert(name.clone(), Arc::new(png));
+            order.push_back(name.clone());
+        }
+        format!("/curve/{}.png", name)
+    }
+
+    /// Removes all images and returns how many there were. Pages that are still open
+    /// in a browser may then show broken images, until they are reloaded.
+    pub fn clear(&self) -> usize {
+        let mut images = self.images.lock().unwrap();
+        let count = images.0.len();
+        images.0.clear();
+        images.1.clear();
+        count
+    }
+
+    pub fn count(&self) -> usize {
+        self.images.lock().unwrap().0.len()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Vec<u8>>> {
+        self.images.lock().unwrap().0.get(name).cloned()
+    }
+
+    /// Writes the images with the given names into `dir`, with the same file names as in their URLs, and
+    /// removes all other images from it. Returns the number of new files.
+    pub fn write_to_dir(&self, dir: &Path, names: &HashSet<String>) -> FnResult<usize> {
+        fs::create_dir_all(dir)?;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("");
+            if path.extension().map_or(false, |ext| ext == "png") && !names.contains(name) {
+                fs::remove_file(&path)?;
+            }
+        }
+        let mut written = 0;
+        for name in names {
+            let path = dir.join(format!("{}.png", name));
+            // the content of a file never changes, see store()
+            if path.exists() {
+                continue;
+            }
+            match self.get(name) {
+                Some(png) => {
+                    fs::write(path, png.as_ref())?;
+                    written += 1;
+                },
+                None => warn!("Curve image {} has expired from the cache, pages that reference it show an empty strip.", name),
+            }
+        }
+        Ok(written)
+    }
+}
+
+/// Serves the `/curve/<hash>.png` images.
+pub fn serve_curve_image(monitor: &Arc<Monitor>, file_name: &str) -> FnResult<Response<Body>> {
+    let name = match file_name.strip_suffix(".png") {
+        Some(name) => name,
+        None => return bad_request("Curve images need to end with .png"),
+    };
+    let response = match monitor.curve_images.get(name) {
+        Some(png) => {
+            let mut response = Response::new(Body::from(png.as_ref().clone()));
+            response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+            // the content of an URL never changes
+            response.headers_mut().append(hyper::header::CACHE_CONTROL, HeaderValue::from_static("public, max-age=31536000, immutable"));
+            response
+        },
+        None => {
+            let mut response = Response::new(Body::from("Unknown or expired image, please reload the page."));
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            response
+        }
+    };
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store() {
+        let cache = CurveImageCache::new();
+        let url_a = cache.store(vec![1, 2, 3]);
+        let url_b = cache.store(vec![4, 5, 6]);
+        assert_ne!(url_a, url_b);
+        assert_eq!(cache.store(vec![1, 2, 3]), url_a);
+        assert!(url_a.starts_with("/curve/") && url_a.ends_with(".png"));
+
+        let name = url_a.trim_start_matches("/curve/").trim_end_matches(".png");
+        assert_eq!(cache.get(name).unwrap().as_ref(), &vec![1, 2, 3]);
+        assert_eq!(cache.images.lock().unwrap().1.len(), 2);
+    }
+
+    #[test]
+    fn test_write_to_dir() {
+        let dir = std::env::temp_dir().join(format!("curve_images_test_{}", std::process::id()));
+        let cache = CurveImageCache::new();
+        let name = |url: String| url.trim_start_matches("/curve/").trim_end_matches(".png").to_string();
+        let a = name(cache.store(vec![1, 2, 3]));
+        let b = name(cache.store(vec![4, 5, 6]));
+
+        let names: HashSet<String> = vec![a.clone(), b.clone()].into_iter().collect();
+        assert_eq!(cache.write_to_dir(&dir, &names).unwrap(), 2);
+        assert_eq!(fs::read(dir.join(format!("{}.png", a))).unwrap(), vec![1, 2, 3]);
+
+        // images that are no longer referenced are removed, unknown ones are skipped
+        let names: HashSet<String> = vec![b.clone(), String::from("0000000000000000")].into_iter().collect();
+        assert_eq!(cache.write_to_dir(&dir, &names).unwrap(), 0);
+        assert!(!dir.join(format!("{}.png", a)).exists());
+        assert!(dir.join(format!("{}.png", b)).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,435 @@
+use chrono::{Duration, Local, NaiveDate, TimeZone};
+use gtfs_structures::Gtfs;
+use hyper::{Body, Response};
+use hyper::header::HeaderValue;
+use mysql::*;
+use mysql::prelude::*;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::FnResult;
+use crate::time_util::date_and_time;
+use crate::types::{DbPrediction, EventType, Id, OriginType, TimeSlot};
+use super::{Monitor, bad_request};
+use super::stats_page::{write_header, finish_response};
+
+// departures within this many minutes are included in the map of current delays
+const CURRENT_WINDOW_MINUTES: i64 = 30;
+// number of days of records from which the punctuality is computed
+const PUNCTUALITY_DAYS: i64 = 14;
+// departures that are less than this many seconds late count as punctual, like in the statistics of Deutsche Bahn
+const PUNCTUALITY_LIMIT: i64 = 360;
+// stops with fewer recorded departures in the time slot are left out, because their punctuality would be mostly noise
+const MIN_PUNCTUALITY_SAMPLES: usize = 10;
+// computing the punctuality reads lots of records, so the result is reused for this long
+const PUNCTUALITY_CACHE_SECONDS: u64 = 3600;
+
+/// What the colors of the delay map show.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MapMode {
+    /// median of the predicted delays of the departures in the next minutes
+    Current,
+    /// share of punctual departures in the recorded data of a time slot
+    Punctuality,
+}
+
+impl MapMode {
+    pub const NAMES: [&'static str; 2] = ["current", "punctuality"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "current" => Some(MapMode::Current),
+            "punctuality" => Some(MapMode::Punctuality),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MapMode::Current => "current",
+            MapMode::Punctuality => "punctuality",
+        }
+    }
+}
+
+/// One marker of the delay map. All stops with the same name are combined into one marker,
+/// like on the stop pages.
+#[derive(Serialize, Clone, Debug)]
+pub struct MapStop {
+    pub name: String,
+    pub lat: f64,
+    pub lon: f64,
+    /// median delay in seconds, or share of punctual departures in percent, depending on the mode
+    pub value: f32,
+    /// number of departures from which the value was computed
+    pub count: usize,
+    pub color: &'static str,
+}
+
+#[derive(Serialize)]
+struct MapData<'a> {
+    mode: &'static str,
+    time_slot: Option<u8>,
+    stops: &'a [MapStop],
+}
+
+/// The punctuality per time slot (by id), with the time at which it was computed.
+pub struct PunctualityCache {
+    entries: Mutex<HashMap<u8, (Instant, Arc<Vec<MapStop>>)>>,
+}
+
+impl PunctualityCache {
+    pub fn new() -> Self {
+        PunctualityCache {
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// Shows a map of all stops, colored by their current delays or by their historic punctuality,
+/// so that dispatchers see at a glance where problems accumulate.
+pub fn generate_delay_map_page(monitor: &Arc<Monitor>, query_params: &HashMap<String, String>) -> FnResult<Response<Body>> {
+    let (mode, time_slot) = parse_map_params(query_params)?;
+
+    let mut w = Vec::new();
+    write_header(&mut w, &monitor.theme, "Netzkarte")?;
+    write!(&mut w, r#"
+            <link rel="stylesheet" href="https://unpkg.com/leaflet@1.7.1/dist/leaflet.css">
+            <script src="https://unpkg.com/leaflet@1.7.1/dist/leaflet.js"></script>
+            <form class="map-options" method="get" action="/map">
+                <select name="mode">
+                    <option value="current"{current_selected}>Aktuelle Verspätung (nächste {window} Minuten)</option>
+                    <option value="punctuality"{punctuality_selected}>Pünktlichkeit der letzten {days} Tage</option>
+                </select>
+                <select name="time-slot">
+                    <option value="">aktuelles Zeitfenster</option>"#,
+        current_selected = if mode == MapMode::Current { " selected" } else { "" },
+        punctuality_selected = if mode == MapMode::Punctuality { " selected" } else { "" },
+        window = CURRENT_WINDOW_MINUTES,
+        days = PUNCTUALITY_DAYS,
+    )?;
+    for ts in TimeSlot::TIME_SLOTS_WITH_DEFAULT.iter() {
+        write!(&mut w, r#"
+                    <option value="{id}"{selected}>{description}</option>"#,
+            id = ts.id,
+            selected = if time_slot.map_or(false, |selected| selected.id == ts.id) { " selected" } else { "" },
+            description = ts.description,
+        )?;
+    }
+    write!(&mut w, r#"
+                </select>
+                <input type="submit" value="Anzeigen">
+            </form>
+            <div id="map" class="delay-map"></div>
+            <p class="map-legend">{legend}</p>
+            <script>
+                var map = L.map('map');
+                L.tileLayer('https://{{s}}.tile.openstreetmap.org/{{z}}/{{x}}/{{y}}.png', {{
+                    attribution: '&copy; <a href="https://www.openstreetmap.org/copyright">OpenStreetMap</a>-Mitwirkende'
+                }}).addTo(map);
+                var markers = L.layerGroup().addTo(map);
+                var fitted = false;
+                function describe(stop, mode) {{
+                    if (mode == 'current') {{
+                        return 'Verspätung (Median): ' + Math.round(stop.value / 60) + ' min bei ' + stop.count + ' Abfahrten';
+                    }}
+                    return stop.value.toFixed(1) + ' % pünktlich bei ' + stop.count + ' Abfahrten';
+                }}
+                function update() {{
+                    fetch('/map/data' + window.location.search).then(function(response) {{
+                        return response.json();
+                    }}).then(function(data) {{
+                        markers.clearLayers();
+                        var bounds = [];
+                        data.stops.forEach(function(stop) {{
+                            var link = document.createElement('a');
+                            link.href = '/stop-by-name?start=' + encodeURIComponent(stop.name);
+                            link.textContent = stop.name;
+                            var popup = document.createElement('div');
+                            popup.appendChild(link);
+                            popup.appendChild(document.createElement('br'));
+                            popup.appendChild(document.createTextNode(describe(stop, data.mode)));
+                            L.circleMarker([stop.lat, stop.lon], {{ radius: 6, color: stop.color, fillOpacity: 0.8 }})
+                                .bindPopup(popup).addTo(markers);
+                            bounds.push([stop.lat, stop.lon]);
+                        }});
+                        if (!fitted && bounds.length > 0) {{
+                            map.fitBounds(bounds);
+                            fitted = true;
+                        }}
+                    }});
+                }}
+                update();
+                if ({reload}) {{
+                    setInterval(update, 60000);
+                }}
+            </script>"#,
+        legend = match mode {
+            MapMode::Current => "Grün: weniger als 1 Minute, gelb: weniger als 3 Minuten, orange: weniger als 5 Minuten, rot: ab 5 Minuten Verspätung. Blau: mehr als 1 Minute zu früh.",
+            MapMode::Punctuality => "Anteil der Abfahrten mit weniger als 6 Minuten Verspätung. Grün: ab 95 %, gelb: ab 85 %, orange: ab 70 %, rot: darunter.",
+        },
+        reload = mode == MapMode::Current,
+    )?;
+
+    finish_response(w)
+}
+
+/// Serves the `/map/data` endpoint, which is documented in `web-assets/openapi.yaml`.
+pub fn generate_delay_map_data(monitor: &Arc<Monitor>, query_params: &HashMap<String, String>) -> FnResult<Response<Body>> {
+    let (mode, time_slot) = parse_map_params(query_params)?;
+    let schedule = monitor.main.get_schedule()?;
+    let time_slot = time_slot.unwrap_or_else(|| TimeSlot::from_datetime(Local::now(), &monitor.main.holidays));
+
+    let stops = match mode {
+        MapMode::Current => Arc::new(get_current_delays(monitor, &schedule)?),
+        MapMode::Punctuality => get_cached_punctuality(monitor, &schedule, time_slot)?,
+    };
+
+    let mut w = Vec::new();
+    serde_json::to_writer(&mut w, &MapData {
+        mode: mode.name(),
+        time_slot: if mode == MapMode::Punctuality { Some(time_slot.id) } else { None },
+        stops: &stops,
+    })?;
+    let mut response = Response::new(Body::from(w));
+    response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
+    Ok(response)
+}
+
+// the mode and the time slot, if one was selected
+fn parse_map_params(query_params: &HashMap<String, String>) -> FnResult<(MapMode, Option<&'static TimeSlot>)> {
+    let mode = match query_params.get("mode").map(|name| name.as_str()) {
+        None | Some("") => MapMode::Current,
+        Some(name) => match MapMode::from_name(name) {
+            Some(mode) => mode,
+            None => return bad_request(&format!("Unknown mode. Known modes are: {}", MapMode::NAMES.join(", "))),
+        },
+    };
+    let time_slot = match query_params.get("time-slot").map(|id| id.trim()) {
+        None | Some("") => None,
+        Some(id) => match id.parse().ok().and_then(TimeSlot::from_id) {
+            Some(time_slot) => Some(time_slot),
+            None => return bad_request("Parameter 'time-slot' must be the id of a time slot."),
+        },
+    };
+    Ok((mode, time_slot))
+}
+
+// median predicted delay of the departures with realtime data in the next minutes, per stop name
+fn get_current_delays(monitor: &Arc<Monitor>, schedule: &Gtfs) -> FnResult<Vec<MapStop>> {
+    let now = Local::now();
+    let mut conn = monitor.pool.get_conn()?;
+    // only the predictions that are based on realtime data say something about the current situation
+    let predictions: Vec<DbPrediction> = conn.exec(
+        r"SELECT
+            `route_id`,
+            `trip_id`,
+            `trip_start_date`,
+            `trip_start_time`,
+            `prediction_min`,
+            `prediction_max`,
+            `precision_type`,
+            `origin_type`,
+            `sample_size`,
+            `prediction_curve`,
+            `stop_id`,
+            `stop_sequence`,
+            `event_type`
+        FROM
+            `predictions`
+        WHERE
+            `source`=:source AND
+            `event_type`=:event_type AND
+            `origin_type`=:origin_type AND
+            `prediction_min` < :max_time AND
+            `prediction_max` > :min_time;",
+        params! {
+            "source" => &monitor.source,
+            "event_type" => EventType::Departure.to_int(),
+            "origin_type" => OriginType::Realtime.to_int(),
+            "min_time" => now.naive_local(),
+            "max_time" => (now + Duration::minutes(CURRENT_WINDOW_MINUTES)).naive_local(),
+        },
+    )?;
+
+    let mut delays_by_stop: HashMap<Id, Vec<f32>> = HashMap::new();
+    for prediction in &predictions {
+        delays_by_stop.entry(prediction.stop_id.clone()).or_insert_with(Vec::new).push(prediction.prediction_curve.x_at_y(0.5));
+    }
+
+    let mut delays_by_name: HashMap<String, (Vec<&str>, Vec<f32>)> = HashMap::new();
+    for (stop_id, delays) in &delays_by_stop {
+        if let Ok(stop) = schedule.get_stop(stop_id) {
+            let entry = delays_by_name.entry(stop.name.clone()).or_insert_with(|| (Vec::new(), Vec::new()));
+            entry.0.push(stop_id);
+            entry.1.extend_from_slice(delays);
+        }
+    }
+
+    Ok(delays_by_name.into_iter().filter_map(|(name, (stop_ids, mut delays))| {
+        let value = median(&mut delays)?;
+        map_stop(schedule, name, &stop_ids, value, delays.len(), delay_color(value))
+    }).collect())
+}
+
+fn get_cached_punctuality(monitor: &Arc<Monitor>, schedule: &Gtfs, time_slot: &TimeSlot) -> FnResult<Arc<Vec<MapStop>>> {
+    if let Some((computed, stops)) = monitor.punctuality_cache.entries.lock().unwrap().get(&time_slot.id) {
+        if computed.elapsed().as_secs() < PUNCTUALITY_CACHE_SECONDS {
+            return Ok(stops.clone());
+        }
+    }
+    // the lock is not held while the records are read, other requests may compute the same in the meantime
+    let stops = Arc::new(get_punctuality(monitor, schedule, time_slot)?);
+    monitor.punctuality_cache.entries.lock().unwrap().insert(time_slot.id, (Instant::now(), stops.clone()));
+    Ok(stops)
+}
+
+// share of punctual departures in the time slot during the last days, per stop name
+fn get_punctuality(monitor: &Arc<Monitor>, schedule: &Gtfs, time_slot: &TimeSlot) -> FnResult<Vec<MapStop>> {
+    let mut conn = monitor.pool.get_conn()?;
+    let mut result = conn.exec_iter(
+        r"SELECT
+            `stop_id`,
+            `trip_start_date`,
+            `trip_start_time`,
+            `delay_departure`
+        FROM
+            `records`
+        WHERE
+            `source`=:source AND
+            `trip_start_date` >= :min_date AND
+            `delay_departure` IS NOT NULL;",
+        params! {
+            "source" => &monitor.source,
+            "min_date" => (Local::today() - Duration::days(PUNCTUALITY_DAYS)).naive_local(),
+        },
+    )?;
+
+    // number of punctual departures and of all departures, per stop id
+    let mut counts_by_stop: HashMap<String, (usize, usize)> = HashMap::new();
+    let result_set = result.next_set().unwrap()?;
+    for row in result_set {
+        let (stop_id, trip_start_date, trip_start_time, delay): (String, NaiveDate, Duration, i64) = from_row(row?);
+        // like for the curves, the time slot is determined by the start of the trip
+        let trip_start = match Local.from_local_date(&trip_start_date).single() {
+            Some(date) => date_and_time(&date, trip_start_time.num_seconds() as i32),
+            None => continue,
+        };
+        if !time_slot.matches(trip_start, &monitor.main.holidays) {
+            continue;
+        }
+        let counts = counts_by_stop.entry(stop_id).or_insert((0, 0));
+        if delay < PUNCTUALITY_LIMIT {
+            counts.0 += 1;
+        }
+        counts.1 += 1;
+    }
+
+    let mut counts_by_name: HashMap<String, (Vec<String>, usize, usize)> = HashMap::new();
+    for (stop_id, (punctual, total)) in counts_by_stop {
+        if let Ok(stop) = schedule.get_stop(&stop_id) {
+            let entry = counts_by_name.entry(stop.name.clone()).or_insert_with(|| (Vec::new(), 0, 0));
+            entry.0.push(stop_id);
+            entry.1 += punctual;
+            entry.2 += total;
+        }
+    }
+
+    Ok(counts_by_name.into_iter().filter(|(_, (_, _, total))| *total >= MIN_PUNCTUALITY_SAMPLES).filter_map(|(name, (stop_ids, punctual, total))| {
+        let value = punctual as f32 * 100.0 / total as f32;
+        let stop_ids: Vec<&str> = stop_ids.iter().map(|id| id.as_str()).collect();
+        map_stop(schedule, name, &stop_ids, value, total, punctuality_color(value))
+    }).collect())
+}
+
+// places the marker at the center of all stops with the name that have coordinates
+fn map_stop(schedule: &Gtfs, name: String, stop_ids: &[&str], value: f32, count: usize, color: &'static str) -> Option<MapStop> {
+    let coordinates: Vec<(f64, f64)> = stop_ids.iter()
+        .filter_map(|id| schedule.get_stop(id).ok())
+        .filter_map(|stop| Some((stop.latitude?, stop.longitude?)))
+        .collect();
+    if coordinates.is_empty() {
+        return None;
+    }
+    let lat = coordinates.iter().map(|(lat, _)| lat).sum::<f64>() / coordinates.len() as f64;
+    let lon = coordinates.iter().map(|(_, lon)| lon).sum::<f64>() / coordinates.len() as f64;
+    Some(MapStop { name, lat, lon, value, count, color })
+}
+
+fn median(values: &mut Vec<f32>) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let middle = values.len() / 2;
+    if values.len() % 2 == 0 {
+        Some((values[middle - 1] + values[middle]) / 2.0)
+    } else {
+        Some(values[middle])
+    }
+}
+
+fn delay_color(delay_seconds: f32) -> &'static str {
+    match delay_seconds {
+        d if d < -60.0 => "#3182bd",
+        d if d < 60.0 => "#31a354",
+        d if d < 180.0 => "#e6c700",
+        d if d < 300.0 => "#fd8d3c",
+        _ => "#de2d26",
+    }
+}
+
+fn punctuality_color(percent: f32) -> &'static str {
+    match percent {
+        p if p >= 95.0 => "#31a354",
+        p if p >= 85.0 => "#e6c700",
+        p if p >= 70.0 => "#fd8d3c",
+        _ => "#de2d26",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::BadRequest;
+
+    #[test]
+    fn test_median() {
+        assert_eq!(median(&mut Vec::new()), None);
+        assert_eq!(median(&mut vec![120.0]), Some(120.0));
+        assert_eq!(median(&mut vec![300.0, -30.0, 60.0]), Some(60.0));
+        assert_eq!(median(&mut vec![300.0, -30.0, 60.0, 0.0]), Some(30.0));
+    }
+
+    #[test]
+    fn test_colors() {
+        assert_eq!(delay_color(-120.0), "#3182bd");
+        assert_eq!(delay_color(0.0), "#31a354");
+        assert_eq!(delay_color(600.0), "#de2d26");
+        assert_eq!(punctuality_color(100.0), "#31a354");
+        assert_eq!(punctuality_color(50.0), "#de2d26");
+    }
+
+    #[test]
+    fn test_parse_map_params() {
+        let mut params = HashMap::new();
+        assert_eq!(parse_map_params(&params).unwrap(), (MapMode::Current, None));
+
+        params.insert(String::from("mode"), String::from("punctuality"));
+        params.insert(String::from("time-slot"), String::from("9"));
+        assert_eq!(parse_map_params(&params).unwrap(), (MapMode::Punctuality, Some(&TimeSlot::SUNDAY_DAY)));
+
+        params.insert(String::from("time-slot"), String::from("99"));
+        assert!(parse_map_params(&params).unwrap_err().is::<BadRequest>());
+        params.insert(String::from("time-slot"), String::from("9"));
+        params.insert(String::from("mode"), String::from("weather"));
+        assert!(parse_map_params(&params).unwrap_err().is::<BadRequest>());
+    }
+}
@@ -0,0 +1,126 @@
+use gtfs_structures::RouteType;
+use std::collections::HashMap;
+
+use crate::FnResult;
+use crate::types::DbPredictionMetaData;
+use super::bad_request;
+
+/// Restricts the departures of a stop page or departure board to some routes, route types or directions.
+/// It is given by the query parameters `routes` (comma-separated route names), `types` (comma-separated
+/// route types, see `ROUTE_TYPE_NAMES`) and `direction` (part of the headsign). Names and headsigns are
+/// compared case-insensitively, e.g. `routes=n10` selects the night bus N10.
+#[derive(Debug, Clone, Default, PartialEq, Hash)]
+pub struct DepartureFilter {
+    pub route_names: Vec<String>,
+    pub route_types: Vec<&'static str>,
+    pub direction: Option<String>,
+}
+
+impl DepartureFilter {
+    pub const ROUTE_TYPE_NAMES: [&'static str; 8] = ["tram", "subway", "rail", "bus", "ferry", "cablecar", "gondola", "funicular"];
+
+    pub fn from_query_params(query_params: &HashMap<String, String>) -> FnResult<Self> {
+        let split = |name: &str| -> Vec<String> {
+            query_params.get(name).map_or_else(Vec::new, |values| {
+                values.split(',').map(|value| value.trim().to_lowercase()).filter(|value| !value.is_empty()).collect()
+            })
+        };
+        let mut route_types = Vec::new();
+        for name in split("types") {
+            match Self::ROUTE_TYPE_NAMES.iter().find(|known| **known == name) {
+                Some(known) => route_types.push(*known),
+                None => return bad_request(&format!("Unknown route type '{}'. Known route types are: {}", name, Self::ROUTE_TYPE_NAMES.join(", "))),
+            }
+        }
+        Ok(DepartureFilter {
+            route_names: split("routes"),
+            route_types,
+            direction: query_params.get("direction").map(|direction| direction.trim().to_lowercase()).filter(|direction| !direction.is_empty()),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.route_names.is_empty() && self.route_types.is_empty() && self.direction.is_none()
+    }
+
+    /// Whether a departure with this meta data is shown.
+    pub fn matches(&self, meta_data: &DbPredictionMetaData) -> bool {
+        (self.route_names.is_empty() || self.route_names.contains(&meta_data.route_name.to_lowercase()))
+            && (self.route_types.is_empty() || get_route_type_name(meta_data.route_type).map_or(false, |name| self.route_types.contains(&name)))
+            && self.direction.as_ref().map_or(true, |direction| meta_data.headsign.to_lowercase().contains(direction))
+    }
+
+    /// Returns the query parameters of the filter (without `?`), so that it can be passed on in links.
+    pub fn to_query(&self) -> String {
+        let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+        if !self.route_names.is_empty() {
+            serializer.append_pair("routes", &self.route_names.join(","));
+        }
+        if !self.route_types.is_empty() {
+            serializer.append_pair("types", &self.route_types.join(","));
+        }
+        if let Some(direction) = &self.direction {
+            serializer.append_pair("direction", direction);
+        }
+        serializer.finish()
+    }
+}
+
+/// Name of the route type in the `types` query parameter, if it can be selected there.
+pub fn get_route_type_name(route_type: RouteType) -> Option<&'static str> {
+    match route_type {
+        RouteType::Tramway   => Some("tram"),
+        RouteType::Subway    => Some("subway"),
+        RouteType::Rail      => Some("rail"),
+        RouteType::Bus       => Some("bus"),
+        RouteType::Ferry     => Some("ferry"),
+        RouteType::CableCar  => Some("cablecar"),
+        RouteType::Gondola   => Some("gondola"),
+        RouteType::Funicular => Some("funicular"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Local;
+
+    fn meta_data(route_name: &str, route_type: RouteType, headsign: &str) -> DbPredictionMetaData {
+        DbPredictionMetaData {
+            route_name: String::from(route_name),
+            headsign: String::from(headsign),
+            stop_index: 0,
+            scheduled_time_seconds: 0,
+            scheduled_time_absolute: Local::now(),
+            route_type,
+        }
+    }
+
+    fn filter(query: &str) -> FnResult<DepartureFilter> {
+        DepartureFilter::from_query_params(&url::form_urlencoded::parse(query.as_bytes()).into_owned().collect())
+    }
+
+    #[test]
+    fn test_departure_filter() {
+        let tram = meta_data("2", RouteType::Tramway, "Sebaldsbrück");
+        let bus = meta_data("N10", RouteType::Bus, "Gröpelingen");
+
+        let empty = filter("").unwrap();
+        assert!(empty.is_empty());
+        assert!(empty.matches(&tram) && empty.matches(&bus));
+        assert_eq!(empty.to_query(), "");
+
+        let by_route = filter("routes=n10,+3").unwrap();
+        assert!(!by_route.matches(&tram) && by_route.matches(&bus));
+        assert_eq!(by_route.to_query(), "routes=n10%2C3");
+
+        let by_type = filter("types=tram").unwrap();
+        assert!(by_type.matches(&tram) && !by_type.matches(&bus));
+        assert!(filter("types=zeppelin").is_err());
+
+        let by_direction = filter("direction=gröp&types=bus,tram").unwrap();
+        assert!(!by_direction.matches(&tram) && by_direction.matches(&bus));
+        assert_eq!(filter(&by_direction.to_query()).unwrap(), by_direction);
+    }
+}
@@ -0,0 +1,230 @@
+use clap::ArgMatches;
+use simple_error::bail;
+use std::collections::HashMap;
+
+use crate::FnResult;
+use super::bad_request;
+
+// larger radii would include so many stops that the pages become useless, bike rides are better then
+const MAX_EXTENDED_STOPS_RADIUS: f32 = 1000.0;
+
+// departures are hidden below this chance (in percent) for conservative users, even if the threshold is lower
+const CONSERVATIVE_MIN_CHANCE: f32 = 25.0;
+// risky users see departures down to this chance (in percent), even if the threshold is higher
+const RISKY_MIN_CHANCE: f32 = 1.0;
+
+/// How much risk of missing a transfer someone accepts. The preference changes which departures are hidden
+/// because they are unlikely to be caught, and which time of their predictions is used to order them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RiskPreference {
+    Conservative,
+    Balanced,
+    Risky,
+}
+
+impl RiskPreference {
+    pub const NAMES: [&'static str; 3] = ["conservative", "balanced", "risky"];
+
+    pub fn from_name(name: &str) -> FnResult<Self> {
+        match name {
+            "conservative" => Ok(RiskPreference::Conservative),
+            "balanced" => Ok(RiskPreference::Balanced),
+            "risky" => Ok(RiskPreference::Risky),
+            _ => bail!("Unknown risk preference: {}. Known preferences are: {}", name, Self::NAMES.join(", ")),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RiskPreference::Conservative => "conservative",
+            RiskPreference::Balanced => "balanced",
+            RiskPreference::Risky => "risky",
+        }
+    }
+
+    pub fn german_name(&self) -> &'static str {
+        match self {
+            RiskPreference::Conservative => "vorsichtig",
+            RiskPreference::Balanced => "ausgewogen",
+            RiskPreference::Risky => "risikofreudig",
+        }
+    }
+
+    /// Probability at which the predicted times of the departures are compared to order them. Conservative
+    /// users rather plan with an early departure, risky users bet on a late one.
+    pub fn get_ordering_probability(&self) -> f32 {
+        match self {
+            RiskPreference::Conservative => 0.10,
+            RiskPreference::Balanced => 0.50,
+            RiskPreference::Risky => 0.75,
+        }
+    }
+
+    /// Returns the chance (in percent) below which departures are hidden, based on the configured `min_chance`.
+    pub fn get_min_chance(&self, min_chance: f32) -> f32 {
+        match self {
+            RiskPreference::Conservative => f32::max(min_chance, CONSERVATIVE_MIN_CHANCE),
+            RiskPreference::Balanced => min_chance,
+            RiskPreference::Risky => f32::min(min_chance, RISKY_MIN_CHANCE),
+        }
+    }
+}
+
+/// Decides which departures and stops are shown on stop pages. Deployments choose their own balance between
+/// clutter and completeness with the `min-chance`, `curve-trim`, `extended-stops-radius` and `alternatives-threshold`
+/// arguments, and each request may override them with query parameters of the same names. The `risk` argument
+/// is only the default for users who haven't chosen their own risk preference.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DisplayThresholds {
+    /// departures with a lower chance (in percent) to catch them are not shown
+    pub min_chance: f32,
+    /// share (in percent) that is cut off at both ends of each prediction. A departure is only shown
+    /// if the remaining part of its prediction overlaps the time span of the page.
+    pub curve_trim: f32,
+    /// radius (in meters) in which other stops are included in a stop's page, if the schedule has no stations
+    pub extended_stops_radius: f32,
+    /// departures with a lower chance (in percent) get suggestions for later alternatives
+    pub alternatives_threshold: f32,
+    /// adjusts `min_chance` and the order of the departures
+    pub risk: RiskPreference,
+}
+
+impl DisplayThresholds {
+    pub fn from_args(args: &ArgMatches) -> FnResult<Self> {
+        let thresholds = DisplayThresholds {
+            min_chance: args.value_of("min-chance").unwrap().parse()?, // has a default value
+            curve_trim: args.value_of("curve-trim").unwrap().parse()?, // has a default value
+            extended_stops_radius: args.value_of("extended-stops-radius").unwrap().parse()?, // has a default value
+            alternatives_threshold: args.value_of("alternatives-threshold").unwrap().parse()?, // has a default value
+            risk: RiskPreference::from_name(args.value_of("risk").unwrap())?, // has a default value
+        };
+        if let Err(e) = thresholds.check() {
+            bail!("{}", e);
+        }
+        Ok(thresholds)
+    }
+
+    /// Returns the thresholds with the values of the query parameters `min-chance`, `curve-trim`,
+    /// `extended-stops-radius` and `alternatives-threshold`, if given. The risk preference is kept, as it
+    /// may also be selected by a cookie, see `with_risk`.
+    pub fn with_query_params(&self, query_params: &HashMap<String, String>) -> FnResult<Self> {
+        let parse = |name: &str, default: f32| match query_params.get(name) {
+            Some(value) => value.trim().parse::<f32>().or_else(|_| bad_request(&format!("Parameter '{}' must be a number.", name))),
+            None => Ok(default),
+        };
+        let thresholds = DisplayThresholds {
+            min_chance: parse("min-chance", self.min_chance)?,
+            curve_trim: parse("curve-trim", self.curve_trim)?,
+            extended_stops_radius: parse("extended-stops-radius", self.extended_stops_radius)?,
+            alternatives_threshold: parse("alternatives-threshold", self.alternatives_threshold)?,
+            risk: self.risk,
+        };
+        if let Err(message) = thresholds.check() {
+            return bad_request(message);
+        }
+        Ok(thresholds)
+    }
+
+    /// Returns the query parameters (without `?`) for the values that differ from `defaults`,
+    /// so that they can be passed on in links.
+    pub fn to_query(&self, defaults: &DisplayThresholds) -> String {
+        let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+        let values = [
+            ("min-chance", self.min_chance, defaults.min_chance),
+            ("curve-trim", self.curve_trim, defaults.curve_trim),
+            ("extended-stops-radius", self.extended_stops_radius, defaults.extended_stops_radius),
+            ("alternatives-threshold", self.alternatives_threshold, defaults.alternatives_threshold),
+        ];
+        for (name, value, default) in &values {
+            if value != default {
+                serializer.append_pair(name, &value.to_string());
+            }
+        }
+        if self.risk != defaults.risk {
+            serializer.append_pair("risk", self.risk.name());
+        }
+        serializer.finish()
+    }
+
+    pub fn with_risk(&self, risk: RiskPreference) -> Self {
+        DisplayThresholds { risk, ..*self }
+    }
+
+    /// Chance (in percent) below which departures are not shown, with the risk preference applied.
+    pub fn get_min_chance(&self) -> f32 {
+        self.risk.get_min_chance(self.min_chance)
+    }
+
+    fn check(&self) -> std::result::Result<(), &'static str> {
+        if !(0.0..=100.0).contains(&self.min_chance) {
+            return Err("The minimum chance must be between 0 and 100 percent.");
+        }
+        // with 50% or more, nothing would be left of the predictions
+        if !(0.0..50.0).contains(&self.curve_trim) {
+            return Err("The curve trim must be at least 0 and less than 50 percent.");
+        }
+        if !(0.0..=MAX_EXTENDED_STOPS_RADIUS).contains(&self.extended_stops_radius) {
+            return Err("The extended stops radius must be between 0 and 1000 meters.");
+        }
+        if !(0.0..=100.0).contains(&self.alternatives_threshold) {
+            return Err("The alternatives threshold must be between 0 and 100 percent.");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::BadRequest;
+
+    #[test]
+    fn test_with_query_params() {
+        let defaults = DisplayThresholds { min_chance: 5.0, curve_trim: 5.0, extended_stops_radius: 300.0, alternatives_threshold: 50.0, risk: RiskPreference::Balanced };
+        let mut params = HashMap::new();
+        assert_eq!(defaults.with_query_params(&params).unwrap(), defaults);
+
+        params.insert(String::from("min-chance"), String::from("0"));
+        params.insert(String::from("curve-trim"), String::from(" 1.5"));
+        params.insert(String::from("extended-stops-radius"), String::from("500"));
+        params.insert(String::from("alternatives-threshold"), String::from("80"));
+        let overridden = defaults.with_query_params(&params).unwrap();
+        assert_eq!(overridden, DisplayThresholds { min_chance: 0.0, curve_trim: 1.5, extended_stops_radius: 500.0, alternatives_threshold: 80.0, risk: RiskPreference::Balanced });
+
+        // the overrides are passed on in links, and lead to the same thresholds again
+        let query = overridden.to_query(&defaults);
+        assert_eq!(query, "min-chance=0&curve-trim=1.5&extended-stops-radius=500&alternatives-threshold=80");
+        let link_params: HashMap<String, String> = url::form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        assert_eq!(defaults.with_query_params(&link_params).unwrap(), overridden);
+
+        params.insert(String::from("curve-trim"), String::from("50"));
+        assert!(defaults.with_query_params(&params).unwrap_err().is::<BadRequest>());
+        params.insert(String::from("curve-trim"), String::from("many"));
+        assert!(defaults.with_query_params(&params).unwrap_err().is::<BadRequest>());
+        params.insert(String::from("curve-trim"), String::from("5"));
+        params.insert(String::from("extended-stops-radius"), String::from("5000"));
+        assert!(defaults.with_query_params(&params).unwrap_err().is::<BadRequest>());
+    }
+
+    #[test]
+    fn test_risk_preferences() {
+        for name in &RiskPreference::NAMES {
+            assert_eq!(RiskPreference::from_name(name).unwrap().name(), *name);
+        }
+        assert!(RiskPreference::from_name("reckless").is_err());
+
+        let defaults = DisplayThresholds { min_chance: 5.0, curve_trim: 5.0, extended_stops_radius: 300.0, alternatives_threshold: 50.0, risk: RiskPreference::Balanced };
+        assert_eq!(defaults.get_min_chance(), 5.0);
+        assert_eq!(defaults.with_risk(RiskPreference::Conservative).get_min_chance(), 25.0);
+        assert_eq!(defaults.with_risk(RiskPreference::Risky).get_min_chance(), 1.0);
+        // explicit thresholds beyond those of the preference are kept
+        let strict = DisplayThresholds { min_chance: 40.0, ..defaults };
+        assert_eq!(strict.with_risk(RiskPreference::Conservative).get_min_chance(), 40.0);
+        let lax = DisplayThresholds { min_chance: 0.0, ..defaults };
+        assert_eq!(lax.with_risk(RiskPreference::Risky).get_min_chance(), 0.0);
+
+        // only a preference other than the default is passed on in links
+        assert_eq!(defaults.to_query(&defaults), "");
+        assert_eq!(defaults.with_risk(RiskPreference::Risky).to_query(&defaults), "risk=risky");
+    }
+}
@@ -0,0 +1,199 @@
+use chrono::{DateTime, Duration, Local};
+use hyper::{Body, Response, StatusCode};
+use hyper::header::HeaderValue;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::FnResult;
+use crate::types::{DbPrediction, EventType};
+use super::{Monitor, PATH_ELEMENT_ESCAPE, bad_request, escape_html, format_delay, get_predictions_for_stop, get_type_bubble};
+use super::journey_data::{JourneyData, JourneyComponent, StopData, WalkProfile, is_trip_accessible};
+use super::stats_page::{write_header, finish_response};
+
+/// name of the cookie in which the favorite stops are remembered
+pub const COOKIE_NAME: &str = "favorites";
+
+// browsers only keep cookies of up to 4 KB, which is enough for this many stop names
+const MAX_FAVORITES: usize = 20;
+
+// how many departures are shown per stop, and how far they may be in the future
+const DEPARTURES_PER_STOP: usize = 5;
+const LOOKAHEAD_MINUTES: i64 = 60;
+
+/// Reads the names of the favorite stops from the value of the cookie. The names are percent-encoded
+/// and separated by `|`, because cookie values may not contain commas, semicolons or spaces.
+pub fn parse_favorites(cookie: Option<String>) -> Vec<String> {
+    match cookie {
+        Some(cookie) => cookie.split('|')
+            .map(|name| percent_decode_str(name).decode_utf8_lossy().into_owned())
+            .filter(|name| !name.is_empty())
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+fn format_cookie(favorites: &[String]) -> String {
+    if favorites.is_empty() {
+        return format!("{}=; Path=/; Max-Age=0; SameSite=Lax", COOKIE_NAME);
+    }
+    let value = favorites.iter().map(|name| utf8_percent_encode(name, NON_ALPHANUMERIC).to_string()).collect::<Vec<_>>().join("|");
+    format!("{}={}; Path=/; Max-Age=31536000; SameSite=Lax", COOKIE_NAME, value)
+}
+
+/// Serves `/favorites/add` and `/favorites/remove`, which add or remove the stop given by the `stop`
+/// query parameter, and then redirect to the favorites page.
+pub fn change_favorites(monitor: &Arc<Monitor>, mut favorites: Vec<String>, query_params: &HashMap<String, String>, add: bool) -> FnResult<Response<Body>> {
+    let stop_name = match query_params.get("stop") {
+        Some(stop_name) if !stop_name.trim().is_empty() => stop_name,
+        _ => return bad_request("Parameter 'stop' with the name of a stop is missing."),
+    };
+    if add {
+        let schedule = monitor.main.get_schedule()?;
+        if !schedule.stops.values().any(|stop| stop.name == *stop_name) {
+            return bad_request(&format!("No stops found for stop_name {}", stop_name));
+        }
+        if !favorites.contains(stop_name) {
+            if favorites.len() >= MAX_FAVORITES {
+                return bad_request(&format!("There can be at most {} favorite stops.", MAX_FAVORITES));
+            }
+            favorites.push(stop_name.clone());
+        }
+    } else {
+        favorites.retain(|name| name != stop_name);
+    }
+
+    let mut response = Response::new(Body::empty());
+    response.headers_mut().append(hyper::header::LOCATION, HeaderValue::from_static("/favorites"));
+    response.headers_mut().append(hyper::header::SET_COOKIE, HeaderValue::from_str(&format_cookie(&favorites))?);
+    *response.status_mut() = StatusCode::SEE_OTHER;
+    Ok(response)
+}
+
+/// Shows the next departures of each favorite stop at a glance. The stops are remembered in a cookie,
+/// so no account is needed. Each stop links to its full stop page.
+pub fn generate_favorites_page(monitor: &Arc<Monitor>, favorites: &[String], accessible: bool, walk_profile: WalkProfile) -> FnResult<Response<Body>> {
+    let now = Local::now();
+
+    let mut w = Vec::new();
+    write_header(&mut w, &monitor.theme, "Meine Haltestellen")?;
+    if favorites.is_empty() {
+        write!(&mut w, r#"
+            <p>Du hast noch keine Lieblings-Haltestellen. Auf der Seite einer Haltestelle kannst du sie mit „☆ Als Favorit merken“ hier hinzufügen.</p>"#)?;
+    }
+
+    for stop_name in favorites {
+        let remove_url = format!("/favorites/remove?stop={}", url::form_urlencoded::byte_serialize(stop_name.as_bytes()).collect::<String>());
+        // the stop might have been removed from the schedule since it was chosen
+        let stop_data = match get_current_stop_data(monitor, stop_name, now, accessible, walk_profile) {
+            Ok(stop_data) => stop_data,
+            Err(_) => {
+                write!(&mut w, r#"
+            <h2>{stop_name}</h2>
+            <p>Diese Haltestelle gibt es im aktuellen Fahrplan nicht mehr. <a href="{remove_url}">entfernen</a></p>"#,
+                    stop_name = escape_html(stop_name),
+                    remove_url = escape_html(&remove_url),
+                )?;
+                continue;
+            },
+        };
+
+        write!(&mut w, r#"
+            <h2><a href="{stop_url}">{stop_name}</a> <a href="{remove_url}" class="favorite-remove" title="Aus den Favoriten entfernen">✕</a></h2>"#,
+            stop_url = escape_html(&stop_data.url),
+            stop_name = escape_html(stop_name),
+            remove_url = escape_html(&remove_url),
+        )?;
+
+        let mut departures = get_next_departures(monitor, &stop_data, accessible, now, Duration::minutes(LOOKAHEAD_MINUTES))?;
+        departures.truncate(DEPARTURES_PER_STOP);
+        if departures.is_empty() {
+            write!(&mut w, r#"
+            <p>Keine Abfahrten in den nächsten {} Minuten.</p>"#, LOOKAHEAD_MINUTES)?;
+            continue;
+        }
+        write!(&mut w, r#"
+            <table class="stats-table favorites">
+                <tr><th>Plan</th><th>vermutlich</th><th>Typ</th><th>Linie</th><th>Ziel</th></tr>"#)?;
+        for dep in &departures {
+            let md = dep.meta_data.as_ref().unwrap(); // departures without meta data have been removed
+            let (type_letter, type_class) = get_type_bubble(md.route_type, &md.route_name);
+            write!(&mut w, r#"
+                <tr><td>{scheduled}</td><td title="in 98&nbsp;% der Fälle zwischen {min} und {max}">{median} ({delay})</td><td class="area type"><span class="bubble {type_class}">{type_letter}</span></td><td>{route_name}</td><td>{headsign}</td></tr>"#,
+                scheduled = md.scheduled_time_absolute.format("%H:%M"),
+                min = dep.get_absolute_time_for_probability(0.01)?.format("%H:%M"),
+                max = dep.get_absolute_time_for_probability(0.99)?.format("%H:%M"),
+                median = dep.get_absolute_time_for_probability(0.50)?.format("%H:%M"),
+                delay = format_delay(dep.get_relative_time_for_probability(0.50) / 60),
+                type_class = type_class,
+                type_letter = type_letter,
+                route_name = escape_html(&md.route_name),
+                headsign = escape_html(&md.headsign),
+            )?;
+        }
+        write!(&mut w, "
+            </table>")?;
+    }
+
+    let mut response = finish_response(w)?;
+    // the page depends on the cookie and changes all the time
+    response.headers_mut().insert(hyper::header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    Ok(response)
+}
+
+/// Returns the data of the stop with the given name for a journey that starts there now.
+pub(super) fn get_current_stop_data(monitor: &Arc<Monitor>, stop_name: &str, now: DateTime<Local>, accessible: bool, walk_profile: WalkProfile) -> FnResult<Arc<StopData>> {
+    let start = now.format("%d.%m.%y %H:%M").to_string();
+    let journey = JourneyData::new(&[start, utf8_percent_encode(stop_name, PATH_ELEMENT_ESCAPE).to_string()], monitor.clone(), accessible, walk_profile, monitor.display_thresholds)?;
+    match journey.get_last_component() {
+        Some(JourneyComponent::Stop(stop_data)) => Ok(stop_data),
+        _ => bad_request(&format!("No stops found for stop_name {}", stop_name)),
+    }
+}
+
+/// Returns the departures at the stop (and the other stops of its station) whose median time is between
+/// `now` and `now + lookahead`, sorted by median departure time, without those that can't be boarded.
+pub(super) fn get_next_departures(monitor: &Arc<Monitor>, stop_data: &StopData, accessible: bool, now: DateTime<Local>, lookahead: Duration) -> FnResult<Vec<DbPrediction>> {
+    let schedule = monitor.main.get_schedule()?;
+    let mut departures = Vec::new();
+    for stop_id in &stop_data.extended_stop_ids {
+        departures.extend(get_predictions_for_stop(monitor, monitor.source.clone(), EventType::Departure, stop_id, now, now + lookahead)?);
+    }
+    for dep in &mut departures {
+        if let Err(e) = dep.compute_meta_data(schedule.clone()) {
+            debug!("Could not compute metadata for departure with trip_id {}: {}", dep.trip_id, e);
+        }
+    }
+    departures.retain(|dep| {
+        let trip = match schedule.get_trip(&dep.trip_id) {
+            Ok(trip) => trip,
+            Err(_) => return false,
+        };
+        match &dep.meta_data {
+            // nobody can board at the last stop of a trip
+            Some(md) => md.stop_index + 1 < trip.stop_times.len()
+                && (!accessible || is_trip_accessible(trip))
+                && dep.get_absolute_time_for_probability(0.50).map_or(false, |time| time >= now && time <= now + lookahead),
+            None => false,
+        }
+    });
+    departures.sort_by_cached_key(|dep| dep.get_absolute_time_for_probability(0.50).unwrap());
+    Ok(departures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cookie_round_trip() {
+        let favorites = vec![String::from("Bremen Hauptbahnhof"), String::from("Am Brill; Süd|Nord"), String::from("Domsheide, 100%")];
+        let cookie = format_cookie(&favorites);
+        let value = cookie.trim_start_matches("favorites=").split(';').next().unwrap();
+        assert!(!value.contains(' ') && !value.contains(',') && !value.contains(';'));
+        assert_eq!(parse_favorites(Some(String::from(value))), favorites);
+        assert_eq!(parse_favorites(None), Vec::<String>::new());
+        assert!(format_cookie(&[]).contains("Max-Age=0"));
+    }
+}
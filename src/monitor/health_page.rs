@@ -0,0 +1,63 @@
+use chrono::Duration;
+use hyper::{Body, Response};
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::FnResult;
+use crate::analyser::health::HealthReport;
+use super::{Monitor, escape_html};
+use super::stats_page::{write_header, finish_response};
+
+// same defaults as for `analyse health`, also used for the admin status
+pub(super) const MAX_GAP_MINUTES: i64 = 10;
+pub(super) const LOOKBACK_HOURS: i64 = 24;
+
+/// Shows whether the realtime feed is working, so that operators notice broken feeds quickly.
+pub fn generate_health_page(monitor: &Arc<Monitor>) -> FnResult<Response<Body>> {
+    let schedule = monitor.main.get_schedule()?;
+    let report = HealthReport::compute(&monitor.main, &schedule, Duration::minutes(MAX_GAP_MINUTES), Duration::hours(LOOKBACK_HOURS))?;
+
+    let mut w = Vec::new();
+    write_header(&mut w, &monitor.theme, "Zustand der Echtzeitdaten")?;
+    write!(&mut w, r#"
+            <p class="health-status {class}">{status}</p>
+            <table class="stats-table">
+                <tr><td>Letzte Echtzeitdatei</td><td>{last_rt_file}</td></tr>
+                <tr><td>Echtzeitdateien seit {since}</td><td>{rt_file_count}</td></tr>
+                <tr><td>Letzter Datensatz</td><td>{last_recording}</td></tr>
+                <tr><td>Fahrten mit Echtzeitdaten heute (bisher)</td><td>{covered} von {scheduled}</td></tr>
+            </table>"#,
+        class = if report.is_stale() { "stale" } else { "ok" },
+        status = if report.is_stale() { "Die Echtzeitdaten sind veraltet!" } else { "Die Echtzeitdaten sind aktuell." },
+        last_rt_file = report.last_rt_file.as_ref().map_or(String::from("keine"), |(_, time)| time.format("%d.%m.%Y %H:%M:%S").to_string()),
+        since = report.since.format("%d.%m.%Y %H:%M"),
+        rt_file_count = report.rt_file_count,
+        last_recording = report.last_recording.map_or(String::from("keiner"), |time| time.format("%d.%m.%Y %H:%M:%S").to_string()),
+        covered = report.covered_trips(),
+        scheduled = report.scheduled_trips(),
+    )?;
+
+    write!(&mut w, r#"
+            <h2>Abdeckung pro Verkehrsunternehmen</h2>
+            <table class="stats-table">
+                <tr><th>Verkehrsunternehmen</th><th>Fahrten laut Fahrplan</th><th>mit Echtzeitdaten</th><th>Abdeckung</th></tr>"#)?;
+    for agency in &report.agencies {
+        write!(&mut w, r#"
+                <tr><td>{}</td><td>{}</td><td>{}</td><td class="coverage">{:.1}&nbsp;%</td></tr>"#,
+            escape_html(&agency.agency_name), agency.scheduled_trips, agency.covered_trips, agency.percentage())?;
+    }
+    write!(&mut w, r#"
+            </table>
+            <h2>Lücken von mehr als {} Minuten</h2>
+            <table class="stats-table">
+                <tr><th>von</th><th>bis</th><th>Minuten</th></tr>"#, MAX_GAP_MINUTES)?;
+    for (start, end) in &report.gaps {
+        write!(&mut w, "
+                <tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+            start.format("%d.%m.%Y %H:%M"), end.format("%d.%m.%Y %H:%M"), (*end - *start).num_minutes())?;
+    }
+    write!(&mut w, "
+            </table>")?;
+
+    finish_response(w)
+}
@@ -0,0 +1,169 @@
+use chrono::{DateTime, Local, Utc};
+use dystonse_curves::TypedCurve;
+use hyper::{Body, Response};
+use hyper::header::HeaderValue;
+use simple_error::bail;
+use std::io::Write;
+
+use crate::FnResult;
+use super::route_type_to_str;
+use super::journey_data::{JourneyData, JourneyComponent};
+
+// percentile of the departure time that is used as the start of a calendar event,
+// so that the reminder fires early enough in most cases
+const DEPARTURE_PERCENTILE: f32 = 0.1;
+
+// percentile of the arrival time that is used as the end of a calendar event
+const ARRIVAL_PERCENTILE: f32 = 0.9;
+
+/// Exports all trips of the journey as an iCalendar file, with one event per trip.
+/// Each event starts at the early (10th percentile) departure time, so that the
+/// calendar reminder is realistic rather than optimistic.
+pub fn generate_ics_file(journey: &JourneyData) -> FnResult<Response<Body>> {
+    let w = write_ics_file(journey, Local::now())?;
+
+    let mut response = Response::new(Body::from(w));
+    response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("text/calendar; charset=utf-8"));
+    response.headers_mut().append(hyper::header::CONTENT_DISPOSITION, HeaderValue::from_static("attachment; filename=\"reise.ics\""));
+    Ok(response)
+}
+
+// `now` is only used as the time stamp of the events. Their UIDs are derived from the trips, so that calendars
+// update the events when the same journey is exported again, instead of adding them a second time.
+fn write_ics_file(journey: &JourneyData, now: DateTime<Local>) -> FnResult<Vec<u8>> {
+    let mut w = Vec::new();
+
+    write!(&mut w, "BEGIN:VCALENDAR\r\n")?;
+    write!(&mut w, "VERSION:2.0\r\n")?;
+    write!(&mut w, "PRODID:-//dystonse//Dystonse OePNV-Reiseplaner//DE\r\n")?;
+    write!(&mut w, "CALSCALE:GREGORIAN\r\n")?;
+    write!(&mut w, "METHOD:PUBLISH\r\n")?;
+
+    let mut trip_count = 0;
+    for (i, component) in journey.components.iter().enumerate() {
+        let trip_data = match component {
+            JourneyComponent::Trip(trip_data) => trip_data,
+            _ => continue,
+        };
+        trip_count += 1;
+
+        let boarding_stop_name = match &trip_data.prev_component {
+            JourneyComponent::Stop(stop_data) => stop_data.stop_name.clone(),
+            _ => bail!("Trip has no previous stop component."),
+        };
+
+        let departure_early = trip_data.start_curve.typed_x_at_y(DEPARTURE_PERCENTILE);
+        let departure_median = trip_data.start_curve.typed_x_at_y(0.5);
+        let departure_late = trip_data.start_curve.typed_x_at_y(ARRIVAL_PERCENTILE);
+
+        // if the journey continues after this trip, the next component is the stop where we get off:
+        let (summary, end, arrival_info) = match journey.components.get(i + 1) {
+            Some(JourneyComponent::Stop(stop_data)) => {
+                let arrival_median = stop_data.start_curve.typed_x_at_y(0.5);
+                let arrival_late = stop_data.start_curve.typed_x_at_y(ARRIVAL_PERCENTILE);
+                (
+                    format!("{} {} von {} nach {}", route_type_to_str(trip_data.route_type), trip_data.route_name, boarding_stop_name, stop_data.stop_name),
+                    arrival_late,
+                    format!("\nAnkunft an {}: vermutlich {}, spätestens {}.", stop_data.stop_name, arrival_median.format("%H:%M"), arrival_late.format("%H:%M")),
+                )
+            },
+            _ => (
+                format!("{} {} ab {} nach {}", route_type_to_str(trip_data.route_type), trip_data.route_name, boarding_stop_name, trip_data.trip_headsign),
+                departure_late,
+                String::new(),
+            )
+        };
+
+        let description = format!(
+            "{} {} nach {}.\nAbfahrt laut Fahrplan: {}, frühestens {}, vermutlich {}, spätestens {}.{}\nWahrscheinlichkeit, diese Fahrt zu erreichen: {:.0} %.",
+            route_type_to_str(trip_data.route_type),
+            trip_data.route_name,
+            trip_data.trip_headsign,
+            trip_data.boarding_stop_departure.format("%H:%M"),
+            departure_early.format("%H:%M"),
+            departure_median.format("%H:%M"),
+            departure_late.format("%H:%M"),
+            arrival_info,
+            trip_data.start_prob * 100.0,
+        );
+
+        write!(&mut w, "BEGIN:VEVENT\r\n")?;
+        write!(&mut w, "UID:{}-{}@dystonse.org\r\n", trip_data.vehicle_id.start.service_day_param().format("%Y%m%d"), escape_ics_text(&trip_data.vehicle_id.trip_id))?;
+        write!(&mut w, "DTSTAMP:{}\r\n", format_ics_time(now))?;
+        write!(&mut w, "DTSTART:{}\r\n", format_ics_time(departure_early))?;
+        write!(&mut w, "DTEND:{}\r\n", format_ics_time(end))?;
+        write!(&mut w, "SUMMARY:{}\r\n", escape_ics_text(&summary))?;
+        write!(&mut w, "LOCATION:{}\r\n", escape_ics_text(&boarding_stop_name))?;
+        write!(&mut w, "DESCRIPTION:{}\r\n", escape_ics_text(&description))?;
+        write!(&mut w, "BEGIN:VALARM\r\n")?;
+        write!(&mut w, "ACTION:DISPLAY\r\n")?;
+        write!(&mut w, "DESCRIPTION:{}\r\n", escape_ics_text(&summary))?;
+        write!(&mut w, "TRIGGER:-PT5M\r\n")?;
+        write!(&mut w, "END:VALARM\r\n")?;
+        write!(&mut w, "END:VEVENT\r\n")?;
+    }
+
+    write!(&mut w, "END:VCALENDAR\r\n")?;
+
+    if trip_count == 0 {
+        bail!("Journey contains no trips that could be exported.");
+    }
+
+    Ok(w)
+}
+
+fn format_ics_time(time: DateTime<Local>) -> String {
+    time.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+// escapes text values according to RFC 5545, section 3.3.11
+fn escape_ics_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use dystonse_curves::{IrregularDynamicCurve, Tup};
+    use std::sync::Arc;
+    use super::super::display_thresholds::{DisplayThresholds, RiskPreference};
+    use super::super::journey_data::WalkProfile;
+    use super::super::journey_data::tests::{FixedPredictions, get_test_schedule};
+
+    fn get_journey(elements: &[&str]) -> JourneyData {
+        let predictions = Arc::new(FixedPredictions {
+            curve: IrregularDynamicCurve::new(vec![Tup { x: -60.0, y: 0.0 }, Tup { x: 120.0, y: 1.0 }]),
+            operation_probability: 1.0,
+        });
+        let thresholds = DisplayThresholds { min_chance: 5.0, curve_trim: 5.0, extended_stops_radius: 300.0, alternatives_threshold: 50.0, risk: RiskPreference::Balanced };
+        let elements: Vec<String> = elements.iter().map(|e| e.to_string()).collect();
+        JourneyData::with_prediction_source(&elements, get_test_schedule(), predictions, false, WalkProfile::Normal, thresholds).unwrap()
+    }
+
+    #[test]
+    fn test_write_ics_file() {
+        let journey = get_journey(&["17.10.20 07:40", "Am Markt", "Bus 1 nach Bahnhof um 08:00 am 17.10.20", "Bahnhof"]);
+        let ics = String::from_utf8(write_ics_file(&journey, Local.ymd(2020, 10, 17).and_hms(7, 40, 0)).unwrap()).unwrap();
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\n") && ics.ends_with("END:VCALENDAR\r\n"));
+        assert_eq!(ics.matches("BEGIN:VEVENT").count(), 1);
+        assert!(ics.contains("UID:20201017-t1@dystonse.org\r\n"), "{}", ics);
+        assert!(ics.contains("SUMMARY:Bus 1 von Am Markt nach Bahnhof\r\n"), "{}", ics);
+        assert!(ics.contains("LOCATION:Am Markt\r\n"), "{}", ics);
+
+        // the UID stays the same when the journey is exported again later
+        let later = String::from_utf8(write_ics_file(&journey, Local.ymd(2020, 10, 17).and_hms(7, 50, 0)).unwrap()).unwrap();
+        assert!(later.contains("UID:20201017-t1@dystonse.org\r\n"));
+
+        // a journey that ends at a stop has no trips to export
+        assert!(write_ics_file(&get_journey(&["17.10.20 07:40", "Am Markt"]), Local::now()).is_err());
+    }
+
+    #[test]
+    fn test_escape_ics_text() {
+        assert_eq!(escape_ics_text("Bus 1, Linie; a\\b\nc"), "Bus 1\\, Linie\\; a\\\\b\\nc");
+    }
+}
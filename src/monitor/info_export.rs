@@ -0,0 +1,153 @@
+use gtfs_structures::Trip;
+use hyper::{Body, Response};
+use hyper::header::HeaderValue;
+use serde::Serialize;
+use simple_error::bail;
+use std::sync::Arc;
+
+use crate::{FnResult, OrError};
+use crate::types::{EventType, TimeSlot, CurveSetKey, RouteVariantData, WeatherCondition};
+use super::{Monitor, DbStat, get_record_pair_statistics};
+use super::journey_data::{JourneyData, JourneyComponent};
+
+/// One line of the CSV export of the info page: the number of samples for the way between two stops
+/// of the route variant, either in the curve sets of the statistics or in the realtime records.
+/// Pairs of stops without samples are left out.
+#[derive(Debug, Serialize, PartialEq)]
+struct SampleCountRow<'t> {
+    /// "curve_set" or "record_pair"
+    data: &'static str,
+    /// only for curve sets, which are computed separately for each event type and time slot
+    event_type: Option<EventType>,
+    time_slot: Option<&'static str>,
+    start_stop_sequence: u16,
+    end_stop_sequence: u16,
+    start_stop_name: &'t str,
+    end_stop_name: &'t str,
+    sample_size: u32,
+}
+
+/// Writes the sample counts that the info page shows as tables as one CSV file, for analyses in other tools.
+pub fn generate_info_csv(monitor: &Arc<Monitor>, journey: &JourneyData) -> FnResult<Response<Body>> {
+    let schedule = monitor.main.get_schedule()?;
+    let trip_data = match journey.get_last_component().unwrap() {
+        JourneyComponent::Trip(trip_data) => trip_data,
+        _ => bail!("No trip at journey end"),
+    };
+    let route = schedule.get_route(&trip_data.route_id)?;
+    let trip = trip_data.get_trip(&schedule)?;
+    let route_variant = trip.route_variant.as_ref().or_error("Trip has no route_variant")?;
+
+    // the stop indices of merged variants are those of the other variant, so they are left out like on the info page
+    let stats = monitor.get_stats();
+    let route_variant_data = match stats.specific.get(&trip_data.route_id) {
+        Some(route_data) => route_data.variants.get(&route_variant.parse::<u64>()?),
+        None => None,
+    };
+    let record_pairs = get_record_pair_statistics(monitor, &monitor.source, &trip_data.route_id, route_variant)?;
+
+    let mut w = Vec::new();
+    {
+        let mut writer = csv::Writer::from_writer(&mut w);
+        for row in get_sample_count_rows(trip, route_variant_data, &record_pairs) {
+            writer.serialize(row)?;
+        }
+        writer.flush()?;
+    }
+
+    // route names may contain anything, but the file name should be plain ASCII
+    let file_name = format!("stichproben-{}-{}.csv",
+        route.short_name.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>(),
+        route_variant.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>(),
+    );
+    let mut response = Response::new(Body::from(w));
+    response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("text/csv; charset=utf-8"));
+    response.headers_mut().append(hyper::header::CONTENT_DISPOSITION, HeaderValue::from_str(&format!("attachment; filename=\"{}\"", file_name))?);
+    Ok(response)
+}
+
+// the rows of the CSV export: first the curve sets by event type, time slot and stops, then the record pairs
+fn get_sample_count_rows<'t>(trip: &'t Trip, route_variant_data: Option<&RouteVariantData>, record_pairs: &[DbStat]) -> Vec<SampleCountRow<'t>> {
+    let mut rows = Vec::new();
+    if let Some(route_variant_data) = route_variant_data {
+        for et in &EventType::TYPES {
+            for ts in TimeSlot::TIME_SLOTS_WITH_DEFAULT.iter() {
+                for (s_i, start) in trip.stop_times.iter().enumerate() {
+                    for (e_i, end) in trip.stop_times.iter().enumerate().skip(s_i + 1) {
+                        let key = CurveSetKey {
+                            start_stop_index: s_i as u32, end_stop_index: e_i as u32, time_slot: (**ts).clone(), weather: WeatherCondition::Unknown
+                        };
+                        if let Some(csd) = route_variant_data.curve_sets[**et].get(&key) {
+                            rows.push(SampleCountRow {
+                                data: "curve_set",
+                                event_type: Some(**et),
+                                time_slot: Some(ts.description),
+                                start_stop_sequence: start.stop_sequence,
+                                end_stop_sequence: end.stop_sequence,
+                                start_stop_name: &start.stop.name,
+                                end_stop_name: &end.stop.name,
+                                sample_size: csd.sample_size,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // the records may come from other trips of the route variant, so the stops are looked up by their sequence
+    let get_stop_name = |stop_sequence: u16| trip.stop_times.iter()
+        .find(|stop_time| stop_time.stop_sequence == stop_sequence)
+        .map_or("", |stop_time| stop_time.stop.name.as_str());
+    let mut sorted_pairs: Vec<&DbStat> = record_pairs.iter().collect();
+    sorted_pairs.sort_by_key(|pair| (pair.s, pair.e));
+    for pair in sorted_pairs {
+        rows.push(SampleCountRow {
+            data: "record_pair",
+            event_type: None,
+            time_slot: None,
+            start_stop_sequence: pair.s,
+            end_stop_sequence: pair.e,
+            start_stop_name: get_stop_name(pair.s),
+            end_stop_name: get_stop_name(pair.e),
+            sample_size: pair.c,
+        });
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::journey_data::tests::get_test_schedule;
+
+    #[test]
+    fn test_sample_count_rows() {
+        let schedule = get_test_schedule();
+        let trip = schedule.get_trip("t1").unwrap();
+        let record_pairs = vec![DbStat { s: 1, e: 2, c: 42 }, DbStat { s: 0, e: 1, c: 3 }];
+        let rows = get_sample_count_rows(trip, None, &record_pairs);
+        assert_eq!(rows.len(), 2);
+        // sorted by stop sequence, with an empty name for stops that this trip doesn't have
+        assert_eq!(rows[0].start_stop_name, "");
+        assert_eq!(rows[1], SampleCountRow {
+            data: "record_pair",
+            event_type: None,
+            time_slot: None,
+            start_stop_sequence: 1,
+            end_stop_sequence: 2,
+            start_stop_name: "Am Markt",
+            end_stop_name: "Bahnhof",
+            sample_size: 42,
+        });
+
+        let mut w = Vec::new();
+        {
+            let mut writer = csv::Writer::from_writer(&mut w);
+            writer.serialize(&rows[1]).unwrap();
+            writer.flush().unwrap();
+        }
+        let csv = String::from_utf8(w).unwrap();
+        assert_eq!(csv, "data,event_type,time_slot,start_stop_sequence,end_stop_sequence,start_stop_name,end_stop_name,sample_size\nrecord_pair,,,1,2,Am Markt,Bahnhof,42\n");
+    }
+}
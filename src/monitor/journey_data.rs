@@ -1,32 +1,48 @@
-use chrono::{Date, DateTime, Local, Duration, NaiveTime};
+use chrono::{Date, DateTime, Local, Duration, NaiveDate, NaiveTime};
 use chrono::offset::TimeZone;
 use simple_error::bail;
-use crate::{FnResult, OrError, date_and_time_local};
-use crate::types::{EventType, VehicleIdentifier, GtfsDateTime};
-use gtfs_structures::{Gtfs, RouteType, Stop, Trip};
+use crate::{FnResult, OrError};
+use crate::time_util::date_and_time;
+use crate::types::{EventType, Id, VehicleIdentifier, GtfsDateTime};
+use gtfs_structures::{Availability, Gtfs, LocationType, RouteType, Stop, Trip};
 use std::sync::Arc;
-use regex::Regex;
-use super::{Monitor, route_type_to_str, DbPrediction, time_curve::TimeCurve};
+use super::{Monitor, route_type_to_str, DbPrediction, time_curve::TimeCurve, bad_request, PATH_ELEMENT_ESCAPE, DisplayThresholds};
+use super::journey_url::{JourneyUrl, JourneyElement, TripElement, START_DATE_TIME_FORMAT};
+use super::stage_timings::{Stage, start_stage};
+use super::departure_filter::DepartureFilter;
+use super::time_window::TimeWindow;
 use geo::prelude::*;
 use geo::{point, Point};
 use std::collections::{HashSet, HashMap};
 use std::iter::FromIterator;
-use dystonse_curves::{IrregularDynamicCurve, Tup};
+use dystonse_curves::{IrregularDynamicCurve, Tup, TypedCurve};
 use mysql::*;
 use mysql::prelude::*;
 
-use percent_encoding::{percent_decode_str, utf8_percent_encode, CONTROLS, AsciiSet};
+use percent_encoding::utf8_percent_encode;
 
-const PATH_ELEMENT_ESCAPE: &AsciiSet = &CONTROLS.add(b'/').add(b'?').add(b'"').add(b'`');
+// maximum (airline) distance of a bike ride between two stops of a journey
+pub const BIKE_MAX_DISTANCE: f32 = 3000.0;
 
-// radius in which we look for other stops close by to include their departures in a stop's page
-const EXTENDED_STOPS_MAX_DISTANCE: f32 = 300.0; 
+// how long before the arrival at a stop a trip without date in its URL may depart, e.g. because it's late
+const UNDATED_TRIP_TOLERANCE_HOURS: i64 = 5;
 
 pub struct JourneyData {
     pub start_date_time: DateTime<Local>,
     pub components: Vec<JourneyComponent>,
-    pub monitor: Arc<Monitor>,
-    pub schedule: Arc<Gtfs>
+    pub prediction_source: Arc<dyn PredictionSource>,
+    pub schedule: Arc<Gtfs>,
+    /// if true, only wheelchair accessible stops and trips are used
+    pub accessible: bool,
+    /// used for all walks, including those between the stops of a station
+    pub walk_profile: WalkProfile,
+    pub display_thresholds: DisplayThresholds,
+    /// restricts the departures of the stop page at the end of the journey
+    pub departure_filter: DepartureFilter,
+    /// moves the time span of the stop page at the end of the journey
+    pub time_window: TimeWindow,
+    /// query parameters (without `?`) for the display thresholds that differ from the defaults of the monitor
+    pub display_query: String,
 }
 
 #[derive(Debug, Clone)]
@@ -59,13 +75,13 @@ impl StopData {
 
     // calculates the maximum airline distance between the main stops of two StopData objects
     pub fn get_max_distance(&self, other_stop_data: &StopData) -> f32 {
-        let other_stop_geos : Vec<Point<f64>> = other_stop_data.stops.iter().map(|stop| point!(x: stop.latitude.unwrap(), y: stop.longitude.unwrap())).collect();
+        let other_stop_geos : Vec<Point<f64>> = other_stop_data.stops.iter().filter_map(|stop| get_stop_geo(stop)).collect();
         return self.get_max_distance_from_geos(&other_stop_geos);
     }
 
     // calculates the maximum airline distance between the main stops of a StopData object and a vector of (geo) points
     pub fn get_max_distance_from_geos(&self, other_stop_geos: & Vec<Point<f64>>) -> f32 {
-        let this_stop_geos  : Vec<Point<f64>> = self.stops.iter().map(|stop| point!(x: stop.latitude.unwrap(), y: stop.longitude.unwrap())).collect();
+        let this_stop_geos  : Vec<Point<f64>> = self.stops.iter().filter_map(|stop| get_stop_geo(stop)).collect();
         
         let mut max_distance = 0.0;
         for this_stop_geo in this_stop_geos {
@@ -75,6 +91,26 @@ impl StopData {
         }
         return max_distance;
     }
+
+    /// Names of the stops that are too far away for a walk (at least `min_distance` meters), but can
+    /// be reached by bike, sorted by their distance (in meters), which is returned as well.
+    pub fn get_bike_destinations(&self, schedule: &Gtfs, min_distance: f32, max_count: usize) -> Vec<(String, f32)> {
+        let mut distances_by_name: HashMap<&str, f32> = HashMap::new();
+        for other_stop in schedule.stops.values() {
+            if let Some(other_stop_geo) = get_stop_geo(other_stop) {
+                let distance = self.get_max_distance_from_geos(&vec![other_stop_geo]);
+                let max_distance = distances_by_name.entry(&other_stop.name).or_insert(0.0);
+                *max_distance = f32::max(*max_distance, distance);
+            }
+        }
+        let mut destinations: Vec<(String, f32)> = distances_by_name.into_iter()
+            .filter(|(name, distance)| *distance >= min_distance && *distance <= BIKE_MAX_DISTANCE && !self.extended_stop_names.iter().any(|n| n == name))
+            .map(|(name, distance)| (String::from(name), distance))
+            .collect();
+        destinations.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap()); // distances are never NaN
+        destinations.truncate(max_count);
+        destinations
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -121,11 +157,21 @@ pub struct WalkData {
     pub start_prob: f32,
 }
 
+#[derive(Debug, Clone)]
+pub struct BikeData {
+    pub url: String,
+    pub prev_component: JourneyComponent,
+    
+    pub start_curve: TimeCurve,
+    pub start_prob: f32,
+}
+
 #[derive(Debug, Clone)]
 pub enum JourneyComponent {
     Stop(Arc<StopData>),
     Trip(Arc<TripData>),
     Walk(Arc<WalkData>),
+    Bike(Arc<BikeData>),
 }
 
 impl JourneyComponent {
@@ -134,6 +180,7 @@ impl JourneyComponent {
             JourneyComponent::Stop(stop_data) => &stop_data.start_curve,
             JourneyComponent::Trip(trip_data) => &trip_data.start_curve,
             JourneyComponent::Walk(walk_data) => &walk_data.start_curve,
+            JourneyComponent::Bike(bike_data) => &bike_data.start_curve,
         }
     }
 
@@ -142,6 +189,7 @@ impl JourneyComponent {
             JourneyComponent::Stop(stop_data) => stop_data.start_prob,
             JourneyComponent::Trip(trip_data) => trip_data.start_prob,
             JourneyComponent::Walk(walk_data) => walk_data.start_prob,
+            JourneyComponent::Bike(bike_data) => bike_data.start_prob,
         }
     }
 
@@ -151,6 +199,7 @@ impl JourneyComponent {
             JourneyComponent::Stop(stop_data) => stop_data.prev_component.clone(),
             JourneyComponent::Trip(trip_data) => Some(trip_data.prev_component.clone()),
             JourneyComponent::Walk(walk_data) => Some(walk_data.prev_component.clone()),
+            JourneyComponent::Bike(bike_data) => Some(bike_data.prev_component.clone()),
         }
     }
 
@@ -159,46 +208,114 @@ impl JourneyComponent {
             JourneyComponent::Stop(stop_data) => &stop_data.url,
             JourneyComponent::Trip(trip_data) => &trip_data.url,
             JourneyComponent::Walk(walk_data) => &walk_data.url,
+            JourneyComponent::Bike(bike_data) => &bike_data.url,
         }
     }
 }
 
 impl JourneyData {
     // parse string vector (from URL) to get all necessary data
-    pub fn new(journey: &[String], monitor: Arc<Monitor>) -> FnResult<Self> {
-        println!("JourneyData::new with {:?}", journey);
-        
+    pub fn new(journey: &[String], monitor: Arc<Monitor>, accessible: bool, walk_profile: WalkProfile, display_thresholds: DisplayThresholds) -> FnResult<Self> {
+        let schedule = monitor.main.get_schedule()?;
+        let display_query = display_thresholds.to_query(&monitor.display_thresholds);
+        let mut journey_data = Self::with_prediction_source(journey, schedule, monitor, accessible, walk_profile, display_thresholds)?;
+        journey_data.display_query = display_query;
+        Ok(journey_data)
+    }
+
+    /// Returns `url` with the query parameters that links to the following pages of the journey need to keep.
+    pub fn get_link(&self, url: &str) -> String {
+        with_query(url, &[self.departure_filter.to_query(), self.display_query.clone()])
+    }
+
+    /// Like `get_link`, but without the departure filter, e.g. to show all departures again.
+    pub fn get_unfiltered_link(&self, url: &str) -> String {
+        with_query(url, &[self.display_query.clone()])
+    }
+
+    /// Combines the legs of the journey into the distribution of its arrival at the end of its last trip,
+    /// which arrives there at `final_arrival`, for the case that all trips of the journey are caught. Each
+    /// transfer weights the following trip with the probability to catch it (see `TimeCurve::given_transfer`),
+    /// so that tight transfers shift the arrival towards the later percentiles, and walks and bike rides add
+    /// their durations. The chance to catch all trips is the `start_prob` of the last trip.
+    pub fn get_journey_arrival_curve(&self, final_arrival: &TimeCurve) -> FnResult<TimeCurve> {
+        // arrival at the current stop, and transfer and departure of the current trip
+        let mut arrival: Option<TimeCurve> = None;
+        let mut boarding: Option<(TimeCurve, TimeCurve)> = None;
+        for component in &self.components {
+            match component {
+                JourneyComponent::Stop(stop_data) => {
+                    let stop_arrival = match (&stop_data.prev_component, arrival.take()) {
+                        (Some(JourneyComponent::Trip(_)), _) => {
+                            let (transfer, departure) = boarding.take().or_error("Trip has no boarding")?;
+                            stop_data.start_curve.given_transfer(&transfer, &departure)
+                        },
+                        (Some(JourneyComponent::Walk(walk_data)), Some(prev_arrival)) => match &walk_data.prev_component {
+                            JourneyComponent::Stop(prev_stop) => prev_arrival.add_duration_curve(&self.walk_profile.get_walk_time(prev_stop.get_max_distance(stop_data))),
+                            _ => bail!("Walk has no prev stop component."),
+                        },
+                        (Some(JourneyComponent::Bike(bike_data)), Some(prev_arrival)) => match &bike_data.prev_component {
+                            JourneyComponent::Stop(prev_stop) => prev_arrival.add_duration_curve(&get_bike_time(prev_stop.get_max_distance(stop_data))),
+                            _ => bail!("Bike ride has no prev stop component."),
+                        },
+                        _ => stop_data.start_curve.clone(),
+                    };
+                    arrival = Some(stop_arrival);
+                },
+                JourneyComponent::Trip(trip_data) => {
+                    let stop_data = match &trip_data.prev_component {
+                        JourneyComponent::Stop(stop_data) => stop_data,
+                        _ => bail!("Trip has no previous stop component."),
+                    };
+                    // the same walk to the platform as in `parse_trip_data`
+                    let walk_distance = trip_data.boarding_stop_id.as_ref().and_then(|id| stop_data.extended_stops_distances.get(id)).copied().unwrap_or(0.0);
+                    let transfer = arrival.as_ref().or_error("Trip has no previous stop")?.add_duration_curve(&self.walk_profile.get_walk_time(walk_distance));
+                    boarding = Some((transfer, trip_data.start_curve.clone()));
+                },
+                JourneyComponent::Walk(_) | JourneyComponent::Bike(_) => {},
+            }
+        }
+        let (transfer, departure) = boarding.or_error("Journey does not end with a trip")?;
+        Ok(final_arrival.given_transfer(&transfer, &departure))
+    }
+
+    /// Like `new`, but with the predictions from `prediction_source` instead of the database of the monitor.
+    pub fn with_prediction_source(journey: &[String], schedule: Arc<Gtfs>, prediction_source: Arc<dyn PredictionSource>, accessible: bool, walk_profile: WalkProfile, display_thresholds: DisplayThresholds) -> FnResult<Self> {
+        debug!("JourneyData::new with {:?}", journey);
+        let journey_url = JourneyUrl::parse(journey)?;
+
         let mut journey_data = JourneyData{
             components: Vec::new(),
-            monitor: monitor.clone(),
-            start_date_time: Local::now(), // will be overwritten during parse 
-            schedule: monitor.main.get_schedule()?
+            prediction_source,
+            start_date_time: journey_url.start_date_time,
+            schedule,
+            accessible,
+            walk_profile,
+            display_thresholds,
+            departure_filter: DepartureFilter::default(),
+            time_window: TimeWindow::default(),
+            display_query: String::new(),
         };
 
-        journey_data.parse_journey(journey)?;
+        journey_data.parse_journey(&journey_url)?;
 
         Ok(journey_data)
     }
 
-    pub fn parse_journey(&mut self, journey: &[String]) -> FnResult<()> {
-        let mut journey_iter = journey.iter();
-        let timestring = journey_iter.next().unwrap(); 
-        self.start_date_time = Local.datetime_from_str(timestring, "%d.%m.%y %H:%M")?;
-
+    pub fn parse_journey(&mut self, journey_url: &JourneyUrl) -> FnResult<()> {
         let mut prev_component: Option<JourneyComponent> = None;
-        let mut expect_stop = true;
 
-        for string in journey_iter {
-            let decoded_string = &utf8_percent_encode(&string, PATH_ELEMENT_ESCAPE).to_string();
-            let component = if expect_stop {
-                expect_stop = false;
-                self.parse_stop_data(decoded_string, prev_component)?
+        for element in &journey_url.elements {
+            let component = if let JourneyElement::Stop(stop_name) = element {
+                self.parse_stop_data(stop_name, prev_component)?
             } else {
-                expect_stop = true;
-                if string == "Fußweg" {
-                    self.parse_walk_data(decoded_string, prev_component.unwrap())?
-                } else {
-                    self.parse_trip_data(decoded_string, prev_component.unwrap())?
+                // prev_component is always set here, because the first component is always a stop
+                let prev = prev_component.or_error("Journey does not start with a stop")?;
+                match element {
+                    JourneyElement::Walk => self.parse_walk_data(prev)?,
+                    JourneyElement::Bike => self.parse_bike_data(prev)?,
+                    JourneyElement::Trip(trip_element) => self.parse_trip_data(trip_element, prev)?,
+                    JourneyElement::Stop(_) => unreachable!(),
                 }
             };
             self.components.push(component.clone());
@@ -207,57 +324,79 @@ impl JourneyData {
         Ok(())
     }
 
-    pub fn parse_walk_data(&self, _walk_string: &str, prev_component: JourneyComponent) -> FnResult<JourneyComponent> {
+    pub fn parse_walk_data(&self, prev_component: JourneyComponent) -> FnResult<JourneyComponent> {
         Ok(JourneyComponent::Walk(Arc::new(WalkData{
             prev_component: prev_component.clone(),
-            url: format!("{}{}/", prev_component.get_url(), "Fußweg"),
+            url: format!("{}{}/", prev_component.get_url(), JourneyElement::Walk.to_path_element()),
             start_curve: prev_component.get_curve().clone(),
             start_prob: prev_component.get_prob(),
         })))
     }
 
-    pub fn parse_stop_data(&self, stop_string: &str, prev_component: Option<JourneyComponent>) -> FnResult<JourneyComponent> {
-        let stop_name = percent_decode_str(stop_string).decode_utf8_lossy().to_string();
+    pub fn parse_bike_data(&self, prev_component: JourneyComponent) -> FnResult<JourneyComponent> {
+        Ok(JourneyComponent::Bike(Arc::new(BikeData{
+            prev_component: prev_component.clone(),
+            url: format!("{}{}/", prev_component.get_url(), JourneyElement::Bike.to_path_element()),
+            start_curve: prev_component.get_curve().clone(),
+            start_prob: prev_component.get_prob(),
+        })))
+    }
+
+    pub fn parse_stop_data(&self, stop_name: &str, prev_component: Option<JourneyComponent>) -> FnResult<JourneyComponent> {
+        let stop_string = utf8_percent_encode(stop_name, PATH_ELEMENT_ESCAPE).to_string();
+        let stop_name = stop_name.to_string();
 
         let url = if let Some(prev) = &prev_component {
             format!("{}{}/", prev.get_url(), stop_string)
         } else {
-            format!("/{}/{}/", self.start_date_time.format("%d.%m.%y %H:%M"), stop_string)
+            format!("/{}/{}/", self.start_date_time.format(START_DATE_TIME_FORMAT), stop_string)
         };
 
         let stops : Vec<Arc<Stop>> = self.schedule.stops.iter().filter_map(|(_id, stop)| if stop_name == stop.name {Some(stop.clone())} else {None}).collect();
 
         if stops.is_empty() {
-            bail!("No stops found for stop_name {}", stop_name);
+            return bad_request(&format!("No stops found for stop_name {}", stop_name));
         }
 
-        let stop_geos : Vec<_> = stops.iter().map(|stop| point!(x: stop.latitude.unwrap(), y: stop.longitude.unwrap())).collect();
+        let stop_geos : Vec<_> = stops.iter().filter_map(|stop| get_stop_geo(stop)).collect();
 
-        // search nearby stops
+        // Search the other stops of the same station. If the schedule doesn't group the stops into
+        // stations, we use all stops close by instead.
+        let station_ids: HashSet<&str> = stops.iter().filter_map(|stop| get_station_id(stop)).collect();
+        let radius = self.display_thresholds.extended_stops_radius;
         let mut extended_stops : Vec<Arc<Stop>> = Vec::new();
         let mut extended_stop_ids : HashSet<String> = HashSet::new();
         let mut extended_stop_names : HashSet<String> = HashSet::new();
         let mut extended_stops_distances : HashMap<String, f32> = HashMap::new();
         for (other_stop_id, other_stop) in &self.schedule.stops {
-            let other_stop_geo = point!(x: other_stop.latitude.unwrap(), y: other_stop.longitude.unwrap());
-            for stop_geo in &stop_geos {
-                let distance = stop_geo.haversine_distance(&other_stop_geo) as f32;
-                if distance < EXTENDED_STOPS_MAX_DISTANCE {
-                    //println!("Added in {:>3.0} distance: {}.", distance, other_stop.name);
-                    extended_stops.push(other_stop.clone());
-                    extended_stop_ids.insert(other_stop_id.clone());
-                    if let Some(d) =  extended_stops_distances.get(other_stop_id) {
-                        if *d < distance {
-                            extended_stops_distances.insert(other_stop_id.clone(), distance);
-                            // println!("Added in {:>3.0} distance: {}.", distance, other_stop.name);
-                        }
-                    } else {
-                        if !stops.iter().any(|stop| stop.id == *other_stop_id) { //don't insert the main stop
-                           extended_stops_distances.insert(other_stop_id.clone(), distance as f32); 
-                           // println!("Added in {:>3.0} distance: {}.", distance, other_stop.name);
-                        }
-                    }
-                    extended_stop_names.insert(other_stop.name.clone());
+            // don't send wheelchair users to platforms that they can't use
+            if self.accessible && !is_stop_accessible(&self.schedule, other_stop) {
+                continue;
+            }
+            let distances: Vec<f32> = match get_stop_geo(other_stop) {
+                Some(other_stop_geo) => stop_geos.iter().map(|stop_geo| stop_geo.haversine_distance(&other_stop_geo) as f32).collect(),
+                None => Vec::new(),
+            };
+            // the distance is only known if both stops have a location
+            let distance = if station_ids.is_empty() {
+                let close_distances: Vec<f32> = distances.into_iter().filter(|distance| *distance < radius).collect();
+                if close_distances.is_empty() {
+                    continue;
+                }
+                close_distances.into_iter().fold(None, |max, distance| Some(f32::max(max.unwrap_or(distance), distance)))
+            } else {
+                if !get_station_id(other_stop).map_or(false, |id| station_ids.contains(id)) {
+                    continue;
+                }
+                distances.into_iter().fold(None, |max, distance| Some(f32::max(max.unwrap_or(distance), distance)))
+            };
+
+            extended_stops.push(other_stop.clone());
+            extended_stop_ids.insert(other_stop_id.clone());
+            extended_stop_names.insert(other_stop.name.clone());
+            if let Some(distance) = distance {
+                if !stops.iter().any(|stop| stop.id == *other_stop_id) { //don't insert the main stop
+                    extended_stops_distances.insert(other_stop_id.clone(), distance);
                 }
             }
         }
@@ -280,15 +419,18 @@ impl JourneyData {
 
                     let start_sequence = trip.stop_times[trip_data.boarding_stop_index.unwrap()].stop_sequence;
 
-                    let stop_time = trip.stop_times.iter().filter(|st| st.stop.name == stop_name)
-                    .filter(|st| st.stop_sequence > start_sequence).next().or_error("Could not get matching stop_time")?;
+                    let stop_time = match trip.stop_times.iter().filter(|st| st.stop.name == stop_name)
+                    .filter(|st| st.stop_sequence > start_sequence).next() {
+                        Some(stop_time) => stop_time,
+                        None => return bad_request(&format!("Trip does not stop at {} after boarding.", stop_name)),
+                    };
 
                     //set some of the arrival trip info:
                     arrival_trip_stop_index = Some(trip.get_stop_index_by_stop_sequence(stop_time.stop_sequence)?);
                     
-                    if let Ok(a_curve) = get_curve_for(self.monitor.clone(), stop_time.stop_sequence, &trip_data.vehicle_id, EventType::Arrival){
-                        let scheduled_arrival = date_and_time_local(&trip_data.vehicle_id.start.date(), stop_time.arrival_time.unwrap() as i32);
-                        start_curve = TimeCurve::new(a_curve, scheduled_arrival);
+                    if let Ok(a_prediction) = self.prediction_source.get_prediction(stop_time.stop_sequence, &trip_data.vehicle_id, EventType::Arrival){
+                        let scheduled_arrival = date_and_time(&trip_data.vehicle_id.start.service_day(), stop_time.arrival_time.or_error("Stop time has no arrival time")? as i32);
+                        start_curve = TimeCurve::new(a_prediction.prediction_curve, scheduled_arrival);
                         start_prob = prev.get_prob();
                     } else {
                         bail!("Could not get curve.");
@@ -299,7 +441,7 @@ impl JourneyData {
             } else if let JourneyComponent::Walk(walk_data) = prev {
                 if let JourneyComponent::Stop(prev_stop) = &walk_data.prev_component {
                     let distance_meters = prev_stop.get_max_distance_from_geos(&stop_geos);
-                    let walk_duration_curve: IrregularDynamicCurve<f32, f32> = get_walk_time(distance_meters);
+                    let walk_duration_curve: IrregularDynamicCurve<f32, f32> = self.walk_profile.get_walk_time(distance_meters);
                     let walk_start_curve: TimeCurve = walk_data.start_curve.clone();
                     let walk_end_curve = walk_start_curve.add_duration_curve(&walk_duration_curve);
                     // can't touch this!
@@ -311,6 +453,18 @@ impl JourneyData {
                 } else {
                     bail!("Walk has no prev stop component.");
                 }
+            } else if let JourneyComponent::Bike(bike_data) = prev {
+                if let JourneyComponent::Stop(prev_stop) = &bike_data.prev_component {
+                    let distance_meters = prev_stop.get_max_distance_from_geos(&stop_geos);
+                    if distance_meters > BIKE_MAX_DISTANCE {
+                        return bad_request(&format!("{} is too far away for a bike ride ({:.0} m, at most {:.0} m are allowed).", stop_name, distance_meters, BIKE_MAX_DISTANCE));
+                    }
+                    start_curve = bike_data.start_curve.add_duration_curve(&get_bike_time(distance_meters));
+                    // the chance to miss the transfer onto the bike is zero, so we can carry over the probability from before:
+                    start_prob = bike_data.start_prob;
+                } else {
+                    bail!("Bike ride has no prev stop component.");
+                }
             } else {
                 bail!("Stop has no plausible prev component.");
             }
@@ -338,44 +492,26 @@ impl JourneyData {
         })))
     }
 
-    pub fn parse_trip_data(&self, trip_string: &str, prev_component: JourneyComponent) -> FnResult<JourneyComponent> {
+    pub fn parse_trip_data(&self, trip_element: &TripElement, prev_component: JourneyComponent) -> FnResult<JourneyComponent> {
         let stop_data = if let JourneyComponent::Stop(stop) = &prev_component {
             stop
         } else {
-            bail!("Need stop before trip.");
+            return bad_request("Need stop before trip.");
         };
 
-        let url = format!("{}{}/", prev_component.get_url(), trip_string);
-
-        // Regex to parse stuff like: "Bus 420 nach Wolfenbüttel Bahnhof um 21:39", 
-        // or more generally: route_type route_name nach trip_headsign um start_departure.time
-        lazy_static! {
-            static ref TRIP_REGEX: Regex = Regex::new(r"(\S+) (.+) nach (.+) um (\d\d:\d\d)").unwrap(); // can't fail because our hard-coded regex is known to be ok
-        }
+        let url = format!("{}{}/", prev_component.get_url(), JourneyElement::Trip(trip_element.clone()).to_path_element());
 
-        let trip_element_captures = TRIP_REGEX
-            .captures(&trip_string)
-            .or_error(&format!(
-            "Trip string does not contain a valid trip descriptor: '{}'",
-            trip_string
-        ))?;
-
-        let route_type_string: String = trip_element_captures[1].to_string();
+        let route_type_string = &trip_element.route_type;
         let mut route_type;
-        let route_name: String = trip_element_captures[2].to_string();
-        let trip_headsign: String = percent_decode_str(&trip_element_captures[3]).decode_utf8_lossy().to_string();
+        let route_name = trip_element.route_name.clone();
+        let trip_headsign = trip_element.headsign.clone();
         let some_trip_headsign = Some(trip_headsign.clone());
-        let boarding_stop_departure_time: NaiveTime = NaiveTime::parse_from_str(&trip_element_captures[4], "%H:%M")?;
-        
-        let journey_start_date: Date<Local> = self.start_date_time.date();
-        // here we assume that we don't have journeys that span more than 24 hours:
-        // TODO Duration::hours(-5) is just a wild guess at how long ago a trip might have been scheduled
-        // and still be a trip in the near future.
-        let boarding_stop_departure = if boarding_stop_departure_time - self.start_date_time.time() >= Duration::hours(-5) {
-            journey_start_date.and_time(boarding_stop_departure_time).unwrap()
-        } else {
-            journey_start_date.and_time(boarding_stop_departure_time).unwrap() + Duration::days(1)
-        };
+        let boarding_stop_departure = get_boarding_stop_departure(
+            trip_element.departure_time,
+            trip_element.departure_date,
+            stop_data.start_curve.typed_x_at_y(0.5),
+        )?;
+        let boarding_date: Date<Local> = boarding_stop_departure.date();
 
         // now we will need the schedule, and info about the stop from where we want to start...
 
@@ -392,7 +528,7 @@ impl JourneyData {
                 }
 
                 // TODO use translated route type names!!
-                if route_type_to_str(route.route_type) != route_type_string {
+                if route_type_to_str(route.route_type) != route_type_string.as_str() {
                     continue;
                 } else {
                     route_type = route.route_type;
@@ -402,13 +538,14 @@ impl JourneyData {
                 continue; 
             }
 
-            // then, filter trips by date (we only want trips that are scheduled to the start_departure_date or the previous or next day)
-            let trip_days : Vec<u16> = self.schedule.trip_days(&trip.service_id, (journey_start_date - Duration::days(1)).naive_local());
+            // then, filter trips by date (we only want trips whose service day is the boarding date or one of the two days
+            // before, because departure times after midnight are given as times after 24:00 of the previous service day)
+            let trip_days : Vec<u16> = self.schedule.trip_days(&trip.service_id, (boarding_date - Duration::days(2)).naive_local());
             let filtered_trip_days : Vec<_> = trip_days.iter().filter(|d| **d <= 2).collect();
             // after this filter, only a subset of these values can be in filtered_trip_days:
-            // 0 day before start_departure_date
-            // 1 day of start_departure_date
-            // 2 day after start_departure_date
+            // 0 two days before the boarding date
+            // 1 day before the boarding date
+            // 2 day of the boarding date
             if  filtered_trip_days.is_empty() {
                 continue;
             } else {
@@ -416,7 +553,7 @@ impl JourneyData {
                 for stop_time in trip.stop_times.iter().filter(|st| stop_data.extended_stop_names.contains(&st.stop.name)) {
                     if let Some(scheduled_boarding_departure_time) = stop_time.departure_time {
                         for d in &filtered_trip_days {
-                            let service_date = journey_start_date + Duration::days(**d as i64 - 1);
+                            let service_date = boarding_date + Duration::days(**d as i64 - 2);
                             // find out for what time this trip is scheduled to depart from the stop we're looking at:
                             let scheduled_boarding_departure_datetime = GtfsDateTime::new(service_date, scheduled_boarding_departure_time as i32);
                             // compare if this is the one we're looking for:
@@ -426,23 +563,26 @@ impl JourneyData {
                                 // now we can finally gather the remaining info:
                                 let route_id = trip.route_id.clone();
                                 let boarding_stop_id = Some(stop_time.stop.id.clone());
-                                let boarding_stop_index = Some(trip.get_stop_index_by_stop_sequence(stop_time.stop_sequence).unwrap());
-                                let scheduled_trip_departure_datetime = GtfsDateTime::new(service_date, trip.stop_times[0].departure_time.unwrap() as i32);
+                                let boarding_stop_index = Some(trip.get_stop_index_by_stop_sequence(stop_time.stop_sequence)?);
+                                let scheduled_trip_departure_datetime = GtfsDateTime::new(service_date, trip.stop_times[0].departure_time.or_error("Trip has no departure time")? as i32);
                             
                                 let vehicle_id = VehicleIdentifier {
                                     start: scheduled_trip_departure_datetime,
-                                    trip_id: id.clone()
+                                    trip_id: Id::new(id)
                                 };
 
                                 // set curve and prob for departure at first stop:
-                                let (start_curve, start_prob) = if let Ok(s_d_curve) = get_curve_for(
-                                    self.monitor.clone(), 
+                                let (start_curve, start_prob) = if let Ok(s_d_prediction) = self.prediction_source.get_prediction(
                                     stop_time.stop_sequence, 
                                     &vehicle_id,
                                     EventType::Departure
                                 ) {
-                                    let departure_curve = TimeCurve::new(s_d_curve, scheduled_boarding_departure_datetime.date_time());
-                                    let start_departure_prob = stop_data.start_curve.get_transfer_probability(&departure_curve) * stop_data.start_prob;
+                                    let departure_curve = TimeCurve::new(s_d_prediction.prediction_curve.clone(), scheduled_boarding_departure_datetime.date_time());
+                                    let operation_prob = self.prediction_source.get_operation_probability(&s_d_prediction);
+                                    // even for a distance of 0 there is some walk time involved
+                                    let walk_distance = *stop_data.extended_stops_distances.get(&stop_time.stop.id).unwrap_or(&0.0);
+                                    let transfer_curve = stop_data.start_curve.add_duration_curve(&self.walk_profile.get_walk_time(walk_distance));
+                                    let start_departure_prob = transfer_curve.get_transfer_probability(&departure_curve) * operation_prob * stop_data.start_prob;
                                     (departure_curve, start_departure_prob)
                                 } else {
                                     bail!("Could not get curve for trip.");
@@ -472,7 +612,7 @@ impl JourneyData {
             }
         }
 
-        bail!("Trip not found")
+        bad_request("Trip not found")
     }
 
     pub fn get_last_component(&self) -> Option<JourneyComponent> {
@@ -484,17 +624,114 @@ impl JourneyData {
     }
 }
 
-pub fn get_curve_for(monitor: Arc<Monitor>, stop_sequence: u16, vehicle_id: &VehicleIdentifier, et: EventType) -> FnResult<IrregularDynamicCurve<f32, f32>> {
-
-    if let Ok(pred) = get_prediction_for_first_line(monitor, stop_sequence, vehicle_id, et) {
-        return Ok(pred.prediction_curve.clone());
+/// Finds out when a trip departs from the boarding stop. Without a date, which is the case for older URLs,
+/// the departure is assumed to be the first one at that time of day which is at most a few hours before
+/// `arrival`, the median arrival of the user at the boarding stop.
+pub fn get_boarding_stop_departure(time: NaiveTime, date: Option<NaiveDate>, arrival: DateTime<Local>) -> FnResult<DateTime<Local>> {
+    let date = match date {
+        Some(date) => date,
+        None => {
+            let earliest = arrival - Duration::hours(UNDATED_TRIP_TOLERANCE_HOURS);
+            if time >= earliest.time() { earliest.date().naive_local() } else { earliest.date().naive_local().succ() }
+        },
     };
-    
-    bail!("no curve found for {:?} at stop {} in trip {:?}", et, stop_sequence, vehicle_id.trip_id);
+    Local.from_local_datetime(&date.and_time(time)).earliest().or_error("Departure time does not exist on this date")
 }
 
-pub fn get_prediction_for_first_line(monitor: Arc<Monitor>, stop_sequence: u16, vehicle_id: &VehicleIdentifier, et: EventType) -> FnResult<DbPrediction> {
-    
+/// Whether wheelchair users can board at the stop. Stops without information inherit it from
+/// their parent station, and stops without any information are assumed to be accessible.
+pub fn is_stop_accessible(schedule: &Gtfs, stop: &Stop) -> bool {
+    match stop.wheelchair_boarding {
+        Availability::Available => true,
+        Availability::NotAvailable => false,
+        Availability::InformationNotAvailable => match stop.parent_station.as_ref().and_then(|id| schedule.stops.get(id)) {
+            Some(parent) => parent.wheelchair_boarding != Availability::NotAvailable,
+            None => true,
+        },
+    }
+}
+
+/// Whether the vehicle of the trip can carry wheelchair users. Trips without information are
+/// assumed to be accessible, but they are flagged as unknown in the monitor.
+pub fn is_trip_accessible(trip: &Trip) -> bool {
+    trip.wheelchair_accessible != Availability::NotAvailable
+}
+
+/// Duration (in s) of a bike ride between two stops, including the time to get the bike
+/// and to lock it at the destination.
+pub fn get_bike_time(distance_meters: f32) -> IrregularDynamicCurve<f32, f32> {
+    // like for walks, the air-line distance is multiplied to account for detours along the streets
+    let min_distance_factor = 1.1;
+    let max_distance_factor = 1.5;
+
+    // from leisurely riding to commuters on e-bikes
+    let min_bike_speed = 3.0; // m/s
+    let max_bike_speed = 7.0; // m/s
+
+    // unlocking, pushing the bike out of the station, locking it again
+    let min_delay = 60.0; // s
+    let max_delay = 180.0; // s
+
+    let min_duration = distance_meters * min_distance_factor / max_bike_speed + min_delay; // s
+    let max_duration = distance_meters * max_distance_factor / min_bike_speed + max_delay; // s
+
+    get_duration_curve(min_duration, max_duration)
+}
+
+/// Distribution of a duration between `min_duration` and `max_duration` (in s), which is used for walks and bike rides.
+fn get_duration_curve(min_duration: f32, max_duration: f32) -> IrregularDynamicCurve<f32, f32> {
+    let mut points = Vec::with_capacity(21);
+
+    // Fake a normal distribution by taking a nice slice out of a cosine's square root.
+    let pi = std::f32::consts::PI;
+    for p in (0..101).step_by(5) {
+        let duration = min_duration + (max_duration - min_duration) * p as f32 / 100.0;
+        let scaled_x = pi + pi * p as f32 / 100.0;
+        let y = (f32::cos(scaled_x).abs().sqrt() * f32::cos(scaled_x).signum() + 1.0) / 2.0;
+        points.push(Tup{x: duration, y});
+    }
+
+    let mut curve = IrregularDynamicCurve::new(points);
+    curve.simplify(0.01);
+    curve
+}
+
+// returns the id of the station that the stop belongs to (or is), if the schedule contains this information
+fn get_station_id(stop: &Stop) -> Option<&str> {
+    if let LocationType::StopArea = stop.location_type {
+        Some(&stop.id)
+    } else {
+        stop.parent_station.as_deref()
+    }
+}
+
+// returns the location of the stop, if it is known
+fn get_stop_geo(stop: &Stop) -> Option<Point<f64>> {
+    Some(point!(x: stop.latitude?, y: stop.longitude?))
+}
+
+/// Provides the predictions that the components of a journey are computed from. The monitor reads them
+/// from the database, while tests can use fixed predictions.
+pub trait PredictionSource: Send + Sync {
+    /// Returns the prediction of an event of the vehicle at the stop with `stop_sequence`.
+    fn get_prediction(&self, stop_sequence: u16, vehicle_id: &VehicleIdentifier, et: EventType) -> FnResult<DbPrediction>;
+
+    /// Returns the probability that the trip of the prediction is operated at all.
+    fn get_operation_probability(&self, prediction: &DbPrediction) -> f32;
+}
+
+impl PredictionSource for Monitor {
+    fn get_prediction(&self, stop_sequence: u16, vehicle_id: &VehicleIdentifier, et: EventType) -> FnResult<DbPrediction> {
+        get_prediction_for_first_line(self, stop_sequence, vehicle_id, et)
+    }
+
+    fn get_operation_probability(&self, prediction: &DbPrediction) -> f32 {
+        prediction.get_operation_probability(&self.get_stats(), &self.main.holidays)
+    }
+}
+
+pub fn get_prediction_for_first_line(monitor: &Monitor, stop_sequence: u16, vehicle_id: &VehicleIdentifier, et: EventType) -> FnResult<DbPrediction> {
+    let _timer = start_stage(Stage::Database);
     let mut conn = monitor.pool.get_conn()?;
 
     let stmt = conn.prep(
@@ -530,7 +767,7 @@ pub fn get_prediction_for_first_line(monitor: Arc<Monitor>, stop_sequence: u16,
             "event_type" => et.to_int(),
             "stop_sequence" => stop_sequence,
             "trip_id" => &vehicle_id.trip_id,
-            "trip_start_date" => vehicle_id.start.service_day().naive_local(),
+            "trip_start_date" => vehicle_id.start.service_day_param(),
             "trip_start_time" => vehicle_id.start.duration(),
         },
     )?;
@@ -545,7 +782,7 @@ pub fn get_prediction_for_first_line(monitor: Arc<Monitor>, stop_sequence: u16,
         .collect();
 
     if db_predictions.len() > 1 {
-        println!("More than one db prediction for first line: {:?}", db_predictions);
+        warn!("More than one db prediction for first line: {:?}", db_predictions);
     }
 
     if let Some(pred) = db_predictions.first() {
@@ -555,40 +792,290 @@ pub fn get_prediction_for_first_line(monitor: Arc<Monitor>, stop_sequence: u16,
     bail!("no prediction found for {:?} at stop {} in trip {:?}", et, stop_sequence, vehicle_id.trip_id);
 }
 
-pub fn get_walk_time(distance_meters: f32) -> IrregularDynamicCurve<f32, f32> {
-    if distance_meters < 20.0 {
-        return IrregularDynamicCurve::new(vec![Tup{x: -12.0, y: 0.0},Tup{x: 12.0, y: 1.0}]);
+/// How fast someone walks between stops. The profile determines the walk time curves, and thereby
+/// the probability to catch a departure at another stop or after arriving with a delayed vehicle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkProfile {
+    Fast,
+    Normal,
+    Slow,
+    MobilityImpaired,
+}
+
+impl WalkProfile {
+    pub const NAMES: [&'static str; 4] = ["fast", "normal", "slow", "mobility-impaired"];
+
+    pub fn from_name(name: &str) -> FnResult<Self> {
+        match name {
+            "fast" => Ok(WalkProfile::Fast),
+            "normal" => Ok(WalkProfile::Normal),
+            "slow" => Ok(WalkProfile::Slow),
+            "mobility-impaired" => Ok(WalkProfile::MobilityImpaired),
+            _ => bail!("Unknown walk profile: {}. Known profiles are: {}", name, Self::NAMES.join(", ")),
+        }
     }
 
-    // assing a factor to the distance, which is measured as air-line distance, to account for detours.
-    let min_distance_factor = 1.0;
-    // for short distances (near 0m), assume a factor of 1.8, for long distances (near 500m) assume a factor of 1.4.
-    let max_distance_factor = 1.4 + f32::max(0.0, f32::min(0.4, (500.0 - distance_meters) / 500.0 * 0.4));
+    pub fn name(&self) -> &'static str {
+        match self {
+            WalkProfile::Fast => "fast",
+            WalkProfile::Normal => "normal",
+            WalkProfile::Slow => "slow",
+            WalkProfile::MobilityImpaired => "mobility-impaired",
+        }
+    }
 
-    // people have different walking speeds. Walk speed numbers taken from https://de.wikipedia.org/wiki/Schrittgeschwindigkeit
-    let min_walk_speed = 0.8; // m/s
-    let _max_walk_speed = 1.65; // m/s
-    let max_sprint_speed = 3.5; // m/s taken from personal training
+    pub fn german_name(&self) -> &'static str {
+        match self {
+            WalkProfile::Fast => "zügig",
+            WalkProfile::Normal => "normal",
+            WalkProfile::Slow => "gemütlich",
+            WalkProfile::MobilityImpaired => "mobilitätseingeschränkt",
+        }
+    }
 
-    // additional time needed to orient, regardless of actual distance
-    let min_delay = 10.0; // s
-    let max_delay = 45.0; // s
+    // slowest walking speed and fastest sprinting speed (in m/s) that we expect
+    fn get_speeds(&self) -> (f32, f32) {
+        // Walk speed numbers taken from https://de.wikipedia.org/wiki/Schrittgeschwindigkeit
+        match self {
+            WalkProfile::Fast => (1.2, 4.0),
+            WalkProfile::Normal => (0.8, 3.5), // sprint speed taken from personal training
+            WalkProfile::Slow => (0.6, 2.0),
+            WalkProfile::MobilityImpaired => (0.4, 1.2),
+        }
+    }
 
-    let min_duration = distance_meters * min_distance_factor / max_sprint_speed + min_delay; // s
-    let max_duration = distance_meters * max_distance_factor / min_walk_speed + max_delay; // s
-    
-    let mut points = Vec::with_capacity(22);
+    // additional time (in s) needed to orient, regardless of actual distance
+    fn get_orientation_delays(&self) -> (f32, f32) {
+        match self {
+            WalkProfile::Fast => (5.0, 30.0),
+            WalkProfile::Normal => (10.0, 45.0),
+            WalkProfile::Slow => (15.0, 60.0),
+            WalkProfile::MobilityImpaired => (30.0, 120.0),
+        }
+    }
 
-    // Fake a normal distribution by taking a nice slice out of a cosine's square root.
-    let pi = std::f32::consts::PI;
-    for p in (0..101).step_by(5) {
-        let duration = min_duration + (max_duration - min_duration) * p as f32 / 100.0;
-        let scaled_x = pi + pi * p as f32 / 100.0;
-        let y = (f32::cos(scaled_x).abs().sqrt() * f32::cos(scaled_x).signum() + 1.0) / 2.0;
-        points.push(Tup{x: duration, y});
+    // walk time curve (in s) for changing vehicles at the same platform
+    fn get_short_walk_time(&self) -> IrregularDynamicCurve<f32, f32> {
+        let (min, max) = match self {
+            WalkProfile::Fast => (-15.0, 8.0),
+            WalkProfile::Normal => (-12.0, 12.0),
+            WalkProfile::Slow => (-10.0, 20.0),
+            WalkProfile::MobilityImpaired => (-5.0, 60.0),
+        };
+        IrregularDynamicCurve::new(vec![Tup{x: min, y: 0.0}, Tup{x: max, y: 1.0}])
     }
 
-    let mut curve = IrregularDynamicCurve::new(points);
-    curve.simplify(0.01);
-    return curve;
-}
\ No newline at end of file
+    pub fn get_walk_time(&self, distance_meters: f32) -> IrregularDynamicCurve<f32, f32> {
+        if distance_meters < 20.0 {
+            return self.get_short_walk_time();
+        }
+
+        // assing a factor to the distance, which is measured as air-line distance, to account for detours.
+        let min_distance_factor = 1.0;
+        // for short distances (near 0m), assume a factor of 1.8, for long distances (near 500m) assume a factor of 1.4.
+        let max_distance_factor = 1.4 + f32::max(0.0, f32::min(0.4, (500.0 - distance_meters) / 500.0 * 0.4));
+
+        // people have different walking speeds, even within one profile.
+        let (min_walk_speed, max_sprint_speed) = self.get_speeds();
+        let (min_delay, max_delay) = self.get_orientation_delays();
+
+        let min_duration = distance_meters * min_distance_factor / max_sprint_speed + min_delay; // s
+        let max_duration = distance_meters * max_distance_factor / min_walk_speed + max_delay; // s
+
+        get_duration_curve(min_duration, max_duration)
+    }
+}
+
+// appends the queries which are not empty to `url`
+fn with_query(url: &str, queries: &[String]) -> String {
+    let queries: Vec<&str> = queries.iter().map(|query| query.as_str()).filter(|query| !query.is_empty()).collect();
+    if queries.is_empty() {
+        String::from(url)
+    } else {
+        format!("{}?{}", url, queries.join("&"))
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use super::super::BadRequest;
+    use super::super::display_thresholds::RiskPreference;
+    use super::super::journey_url::parse_trip_element;
+    use crate::test_util;
+    use crate::types::{OriginType, PrecisionType};
+
+    // the same prediction for all events, which makes the probabilities of the journey easy to check
+    pub struct FixedPredictions {
+        pub curve: IrregularDynamicCurve<f32, f32>,
+        pub operation_probability: f32,
+    }
+
+    impl PredictionSource for FixedPredictions {
+        fn get_prediction(&self, stop_sequence: u16, vehicle_id: &VehicleIdentifier, et: EventType) -> FnResult<DbPrediction> {
+            Ok(DbPrediction {
+                route_id: Id::new("r1"),
+                trip_id: vehicle_id.trip_id.clone(),
+                trip_start_date: vehicle_id.start.service_day(),
+                trip_start_time: vehicle_id.start.duration(),
+                prediction_min: vehicle_id.start.date_time(),
+                prediction_max: vehicle_id.start.date_time(),
+                precision_type: PrecisionType::Specific,
+                origin_type: OriginType::Realtime,
+                sample_size: 100,
+                prediction_curve: self.curve.clone(),
+                stop_id: Id::new(if stop_sequence == 1 { "s1" } else { "s2" }),
+                stop_sequence: stop_sequence as usize,
+                event_type: et,
+                meta_data: None,
+            })
+        }
+
+        fn get_operation_probability(&self, _prediction: &DbPrediction) -> f32 {
+            self.operation_probability
+        }
+    }
+
+    // a bus from "Am Markt" to "Bahnhof" every day at 8:00, which takes 10 minutes
+    pub fn get_test_schedule() -> Arc<Gtfs> {
+        Arc::new(test_util::get_test_schedule())
+    }
+
+    // the test schedule with a bus back to "Am Markt", which leaves "Bahnhof" at `departure_time` and takes 10 minutes
+    fn get_two_leg_test_schedule(departure_time: u32) -> Arc<Gtfs> {
+        let mut schedule = test_util::get_test_schedule();
+        let stops = vec![schedule.stops["s2"].clone(), schedule.stops["s1"].clone()];
+        schedule.trips.insert(String::from("t2"), Trip {
+            service_id: String::from("daily"),
+            trip_headsign: Some(String::from("Am Markt")),
+            ..test_util::trip_with_stops("t2", "r1", departure_time, 600, stops)
+        });
+        Arc::new(schedule)
+    }
+
+    #[test]
+    fn test_journey_without_database() {
+        let predictions = Arc::new(FixedPredictions {
+            curve: IrregularDynamicCurve::new(vec![Tup { x: -60.0, y: 0.0 }, Tup { x: 120.0, y: 1.0 }]),
+            operation_probability: 0.5,
+        });
+        let thresholds = DisplayThresholds { min_chance: 5.0, curve_trim: 5.0, extended_stops_radius: 300.0, alternatives_threshold: 50.0, risk: RiskPreference::Balanced };
+        let journey: Vec<String> = ["17.10.20 07:40", "Am Markt", "Bus 1 nach Bahnhof um 08:00 am 17.10.20", "Bahnhof"].iter().map(|e| e.to_string()).collect();
+        let mut journey_data = JourneyData::with_prediction_source(&journey, get_test_schedule(), predictions.clone(), false, WalkProfile::Normal, thresholds).unwrap();
+        assert_eq!(journey_data.components.len(), 3);
+
+        // links keep the departure filter, unless it is reset
+        assert_eq!(journey_data.get_link("/a/"), "/a/");
+        journey_data.departure_filter = DepartureFilter { route_names: Vec::new(), route_types: vec!["tram", "bus"], direction: None };
+        journey_data.display_query = String::from("min_chance=10");
+        assert_eq!(journey_data.get_link("/a/"), "/a/?types=tram%2Cbus&min_chance=10");
+        assert_eq!(journey_data.get_unfiltered_link("/a/"), "/a/?min_chance=10");
+
+        // there are 20 minutes to catch the bus, so only its operation is uncertain
+        let trip_data = match &journey_data.components[1] {
+            JourneyComponent::Trip(trip_data) => trip_data,
+            other => panic!("expected a trip, got {:?}", other),
+        };
+        assert_eq!(&*trip_data.vehicle_id.trip_id, "t1");
+        assert_eq!(trip_data.boarding_stop_index, Some(0));
+        assert!((trip_data.start_prob - 0.5).abs() < 0.01, "{}", trip_data.start_prob);
+
+        let stop_data = match &journey_data.components[2] {
+            JourneyComponent::Stop(stop_data) => stop_data,
+            other => panic!("expected a stop, got {:?}", other),
+        };
+        assert_eq!(stop_data.url, "/17.10.20 07:40/Am Markt/Bus 1 nach Bahnhof um 08:00 am 17.10.20/Bahnhof/");
+        assert_eq!(stop_data.arrival_trip_stop_index, Some(1));
+        assert_eq!(stop_data.start_prob, trip_data.start_prob);
+
+        // no bus at 8:05
+        let journey: Vec<String> = ["17.10.20 07:40", "Am Markt", "Bus 1 nach Bahnhof um 08:05"].iter().map(|e| e.to_string()).collect();
+        let result = JourneyData::with_prediction_source(&journey, get_test_schedule(), predictions, false, WalkProfile::Normal, thresholds);
+        assert!(result.err().unwrap().is::<BadRequest>());
+    }
+
+    #[test]
+    fn test_journey_arrival_curve() {
+        let predictions = Arc::new(FixedPredictions {
+            curve: IrregularDynamicCurve::new(vec![Tup { x: -60.0, y: 0.0 }, Tup { x: 120.0, y: 1.0 }]),
+            operation_probability: 1.0,
+        });
+        let thresholds = DisplayThresholds { min_chance: 5.0, curve_trim: 5.0, extended_stops_radius: 300.0, alternatives_threshold: 50.0, risk: RiskPreference::Balanced };
+        let time = |hour: u32, minute: u32| Local.ymd(2020, 10, 17).and_hms(hour, minute, 0);
+        let journey = |departure: &str| -> Vec<String> {
+            vec![String::from("17.10.20 07:40"), String::from("Am Markt"), String::from("Bus 1 nach Bahnhof um 08:00 am 17.10.20"), String::from("Bahnhof"), format!("Bus 1 nach Am Markt um {} am 17.10.20", departure)]
+        };
+
+        // with 10 minutes to change, the second bus is caught almost always, whenever it leaves
+        let journey_data = JourneyData::with_prediction_source(&journey("08:20"), get_two_leg_test_schedule(8 * 3600 + 1200), predictions.clone(), false, WalkProfile::Normal, thresholds).unwrap();
+        let final_arrival = TimeCurve::new(predictions.curve.clone(), time(8, 30));
+        let arrival = journey_data.get_journey_arrival_curve(&final_arrival).unwrap();
+        for y in &[0.1, 0.5, 0.9] {
+            let difference = arrival.typed_x_at_y(*y).signed_duration_since(final_arrival.typed_x_at_y(*y)).num_seconds();
+            assert!(difference.abs() <= 5, "{} s at {}", difference, y);
+        }
+
+        // if the second bus leaves when the first one arrives, it is only caught if it is late
+        let journey_data = JourneyData::with_prediction_source(&journey("08:10"), get_two_leg_test_schedule(8 * 3600 + 600), predictions.clone(), false, WalkProfile::Normal, thresholds).unwrap();
+        let trip_data = match journey_data.components.last().unwrap() {
+            JourneyComponent::Trip(trip_data) => trip_data,
+            other => panic!("expected a trip, got {:?}", other),
+        };
+        assert!(trip_data.start_prob > 0.2 && trip_data.start_prob < 0.8, "{}", trip_data.start_prob);
+        let final_arrival = TimeCurve::new(predictions.curve.clone(), time(8, 20));
+        let arrival = journey_data.get_journey_arrival_curve(&final_arrival).unwrap();
+        assert!(arrival.typed_x_at_y(0.5) > final_arrival.typed_x_at_y(0.5) + Duration::seconds(15));
+        assert!(arrival.typed_max_x() <= final_arrival.typed_max_x());
+
+        // a journey that ends at a stop has no arrival of a last trip
+        let journey_data = JourneyData::with_prediction_source(&journey("08:10")[..4], get_two_leg_test_schedule(8 * 3600 + 600), predictions, false, WalkProfile::Normal, thresholds).unwrap();
+        assert!(journey_data.get_journey_arrival_curve(&final_arrival).is_err());
+    }
+
+    #[test]
+    fn test_walk_profiles() {
+        for name in &WalkProfile::NAMES {
+            assert_eq!(WalkProfile::from_name(name).unwrap().name(), *name);
+        }
+        assert!(WalkProfile::from_name("crawling").is_err());
+
+        let profiles = [WalkProfile::Fast, WalkProfile::Normal, WalkProfile::Slow, WalkProfile::MobilityImpaired];
+        for distance in &[0.0, 150.0, 400.0] {
+            let max_times: Vec<f32> = profiles.iter().map(|profile| profile.get_walk_time(*distance).max_x()).collect();
+            assert!(max_times.windows(2).all(|pair| pair[0] < pair[1]), "{:?} at {} m", max_times, distance);
+        }
+    }
+
+    #[test]
+    fn test_trip_elements() {
+        let element = parse_trip_element("Bus 420 nach Wolfenbüttel Bahnhof um 00:15 am 18.10.20").unwrap();
+        assert_eq!(element.route_name, "420");
+        assert_eq!(element.headsign, "Wolfenbüttel Bahnhof");
+        assert_eq!(element.departure_date, Some(NaiveDate::from_ymd(2020, 10, 18)));
+        let time = NaiveTime::from_hms(0, 15, 0);
+        let arrival = Local.ymd(2020, 10, 17).and_hms(23, 50, 0);
+        let expected = Local.ymd(2020, 10, 18).and_hms(0, 15, 0);
+        assert_eq!(get_boarding_stop_departure(time, element.departure_date, arrival).unwrap(), expected);
+        // explicit dates can be days ahead of the arrival
+        assert_eq!(get_boarding_stop_departure(time, Some(NaiveDate::from_ymd(2020, 10, 21)), arrival).unwrap(), Local.ymd(2020, 10, 21).and_hms(0, 15, 0));
+
+        // older URLs without date
+        let element = parse_trip_element("Tram 4 nach Arsten um 00:15").unwrap();
+        assert_eq!(element.headsign, "Arsten");
+        assert_eq!(element.departure_date, None);
+        assert_eq!(get_boarding_stop_departure(time, None, arrival).unwrap(), expected);
+        let late_arrival = Local.ymd(2020, 10, 18).and_hms(2, 0, 0);
+        assert_eq!(get_boarding_stop_departure(time, None, late_arrival).unwrap(), expected);
+
+        assert!(parse_trip_element("Tram 4 nach Arsten").is_err());
+        assert!(parse_trip_element("Tram 4 nach Arsten um 00:15 am 32.10.20").is_err());
+    }
+
+    #[test]
+    fn test_bike_is_faster_for_long_distances() {
+        let bike = get_bike_time(2000.0);
+        let walk = WalkProfile::Normal.get_walk_time(2000.0);
+        assert!(bike.max_x() < walk.max_x());
+        assert!(bike.min_x() >= 60.0);
+    }
+}
@@ -0,0 +1,272 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveTime};
+use chrono::offset::TimeZone;
+use percent_encoding::utf8_percent_encode;
+use regex::Regex;
+use std::fmt;
+
+use crate::FnResult;
+use super::{bad_request, PATH_ELEMENT_ESCAPE};
+
+// format of the start time, the first element of journey URLs
+pub const START_DATE_TIME_FORMAT: &str = "%d.%m.%y %H:%M";
+
+// format of the date of the departure in the trip elements of journey URLs
+pub const TRIP_DATE_FORMAT: &str = "%d.%m.%y";
+
+// elements between two stops that are no trips
+const WALK_ELEMENT: &str = "Fußweg";
+const BIKE_ELEMENT: &str = "Fahrrad";
+
+/// The path of a journey URL, like `/17.10.20 21:30/Am Markt/Bus 420 nach Wolfenbüttel Bahnhof um 21:39 am 17.10.20/Bahnhof/`:
+/// the start time of the journey, followed by stops which alternate with the trips, walks or bike rides between them.
+/// The journey may end with a trip, whose page then shows where to get off.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JourneyUrl {
+    pub start_date_time: DateTime<Local>,
+    pub elements: Vec<JourneyElement>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JourneyElement {
+    /// all stops with this name
+    Stop(String),
+    Trip(TripElement),
+    Walk,
+    Bike,
+}
+
+/// The parts of a trip element of a journey URL, like "Bus 420 nach Wolfenbüttel Bahnhof um 21:39 am 17.10.20",
+/// or more generally: route_type route_name nach trip_headsign um departure_time am departure_date. The time and
+/// date are those of the departure at the boarding stop. Older URLs don't contain the date.
+/// The route name must not contain " nach ", while the headsign may contain anything.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TripElement {
+    pub route_type: String,
+    pub route_name: String,
+    pub headsign: String,
+    pub departure_time: NaiveTime,
+    pub departure_date: Option<NaiveDate>,
+}
+
+impl JourneyUrl {
+    /// Parses the elements of the path, which have already been percent-decoded.
+    pub fn parse(path_elements: &[String]) -> FnResult<Self> {
+        let start_date_time = parse_start_date_time(path_elements.first())?;
+        let mut elements = Vec::with_capacity(path_elements.len().saturating_sub(1));
+        for (index, text) in path_elements.iter().skip(1).enumerate() {
+            // the journey starts with a stop, and every second element is a stop
+            let element = if index % 2 == 0 {
+                JourneyElement::Stop(text.clone())
+            } else if text == WALK_ELEMENT {
+                JourneyElement::Walk
+            } else if text == BIKE_ELEMENT {
+                JourneyElement::Bike
+            } else {
+                JourneyElement::Trip(parse_trip_element(text)?)
+            };
+            elements.push(element);
+        }
+        Ok(JourneyUrl { start_date_time, elements })
+    }
+
+    /// Returns the path of the URL, with a trailing slash like the URLs of the components of a journey.
+    pub fn to_path(&self) -> String {
+        let mut path = format!("/{}/", self.start_date_time.format(START_DATE_TIME_FORMAT));
+        for element in &self.elements {
+            path.push_str(&element.to_path_element());
+            path.push('/');
+        }
+        path
+    }
+}
+
+impl JourneyElement {
+    /// Returns the element as it is written in the path of a journey URL, percent-encoded where needed.
+    pub fn to_path_element(&self) -> String {
+        match self {
+            JourneyElement::Stop(stop_name) => utf8_percent_encode(stop_name, PATH_ELEMENT_ESCAPE).to_string(),
+            JourneyElement::Trip(trip_element) => utf8_percent_encode(&trip_element.to_string(), PATH_ELEMENT_ESCAPE).to_string(),
+            // written like in the links of the stop pages
+            JourneyElement::Walk => String::from(WALK_ELEMENT),
+            JourneyElement::Bike => String::from(BIKE_ELEMENT),
+        }
+    }
+}
+
+impl fmt::Display for TripElement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} nach {} um {}", self.route_type, self.route_name, self.headsign, self.departure_time.format("%H:%M"))?;
+        if let Some(date) = self.departure_date {
+            write!(f, " am {}", date.format(TRIP_DATE_FORMAT))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the first element of a journey URL, which is the start time of the journey.
+pub fn parse_start_date_time(timestring: Option<&String>) -> FnResult<DateTime<Local>> {
+    let timestring = match timestring {
+        Some(timestring) => timestring,
+        None => return bad_request("Journey has no start time."),
+    };
+    match Local.datetime_from_str(timestring, START_DATE_TIME_FORMAT) {
+        Ok(date_time) => Ok(date_time),
+        Err(e) => bad_request(&format!("Invalid start time '{}' (expected format DD.MM.YY HH:MM): {}", timestring, e)),
+    }
+}
+
+/// Parses a trip element, which has already been percent-decoded.
+pub fn parse_trip_element(trip_string: &str) -> FnResult<TripElement> {
+    lazy_static! {
+        // the route name ends at the first " nach ", and the headsign at the last " um ", so that headsigns may contain both
+        static ref TRIP_REGEX: Regex = Regex::new(r"(?s)^(\S+) (.*?) nach (.+) um ([0-9]{2}:[0-9]{2})(?: am ([0-9]{2}\.[0-9]{2}\.[0-9]{2}))?$").unwrap(); // can't fail because our hard-coded regex is known to be ok
+    }
+
+    let captures = match TRIP_REGEX.captures(trip_string) {
+        Some(captures) => captures,
+        None => return bad_request(&format!("Trip string does not contain a valid trip descriptor: '{}'", trip_string)),
+    };
+    let departure_time = match NaiveTime::parse_from_str(&captures[4], "%H:%M") {
+        Ok(time) => time,
+        Err(e) => return bad_request(&format!("Invalid departure time '{}' (expected format HH:MM): {}", &captures[4], e)),
+    };
+    let departure_date = match captures.get(5) {
+        Some(date) => match NaiveDate::parse_from_str(date.as_str(), TRIP_DATE_FORMAT) {
+            Ok(date) => Some(date),
+            Err(e) => return bad_request(&format!("Invalid departure date '{}' (expected format DD.MM.YY): {}", date.as_str(), e)),
+        },
+        None => None,
+    };
+    Ok(TripElement {
+        route_type: captures[1].to_string(),
+        route_name: captures[2].to_string(),
+        headsign: captures[3].to_string(),
+        departure_time,
+        departure_date,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{BadRequest, split_path};
+    use chrono::{Duration, NaiveDateTime};
+    use rand::{Rng, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+
+    // pieces of text that are likely to trip up the URL grammar, when they are put together randomly
+    const TOKENS: &[&str] = &["Bus", "Fähre", "U-Bahn", "nach", "um", "am", "Fußweg", "Fahrrad", "12:30", "25:61", "31.12.20", "29.02.21",
+        "Bahnhof", "1", "a", " ", " ", "/", "?", "#", "%", "%20", ".", ":", "\"", "ß", "\n", "\u{0}"];
+
+    const ROUTE_TYPES: &[&str] = &["Tram", "U-Bahn", "Zug", "Bus", "Fähre", "Standseilbahn", "Fahrzeug"];
+
+    fn random_text(rng: &mut XorShiftRng, min_tokens: usize, max_tokens: usize) -> String {
+        let count = rng.gen_range(min_tokens, max_tokens + 1);
+        (0..count).map(|_| TOKENS[rng.gen_range(0, TOKENS.len())]).collect()
+    }
+
+    fn random_date_time(rng: &mut XorShiftRng) -> NaiveDateTime {
+        // two-digit years are read as 1969 to 2068
+        NaiveDate::from_ymd(2000, 1, 1).and_hms(0, 0, 0) + Duration::minutes(rng.gen_range(0, 60 * 24 * 365 * 68))
+    }
+
+    fn random_trip_element(rng: &mut XorShiftRng) -> TripElement {
+        let route_name = loop {
+            let route_name = random_text(rng, 0, 4);
+            if !route_name.contains("nach") {
+                break route_name;
+            }
+        };
+        let departure = random_date_time(rng);
+        TripElement {
+            route_type: String::from(ROUTE_TYPES[rng.gen_range(0, ROUTE_TYPES.len())]),
+            route_name,
+            headsign: random_text(rng, 1, 8),
+            departure_time: departure.time(),
+            departure_date: if rng.gen_bool(0.5) { Some(departure.date()) } else { None },
+        }
+    }
+
+    fn random_journey(rng: &mut XorShiftRng) -> Option<JourneyUrl> {
+        // local times that don't exist or are ambiguous because of daylight saving time can't be part of a journey
+        let start_date_time = Local.from_local_datetime(&random_date_time(rng)).single()?;
+        let count = rng.gen_range(1, 8);
+        let elements = (0..count).map(|index| if index % 2 == 0 {
+            JourneyElement::Stop(random_text(rng, 1, 6))
+        } else {
+            match rng.gen_range(0, 4) {
+                0 => JourneyElement::Walk,
+                1 => JourneyElement::Bike,
+                _ => JourneyElement::Trip(random_trip_element(rng)),
+            }
+        }).collect();
+        Some(JourneyUrl { start_date_time, elements })
+    }
+
+    #[test]
+    fn test_journey_url() {
+        let path = "/17.10.20 21:30/Am Markt/Bus 420 nach Wolfenbüttel Bahnhof um 21:39 am 17.10.20/Bahnhof/Fußweg/Bahnhof%2FZOB/";
+        let journey = JourneyUrl::parse(&split_path(path)).unwrap();
+        assert_eq!(journey.start_date_time, Local.ymd(2020, 10, 17).and_hms(21, 30, 0));
+        assert_eq!(journey.elements, vec![
+            JourneyElement::Stop(String::from("Am Markt")),
+            JourneyElement::Trip(TripElement {
+                route_type: String::from("Bus"),
+                route_name: String::from("420"),
+                headsign: String::from("Wolfenbüttel Bahnhof"),
+                departure_time: NaiveTime::from_hms(21, 39, 0),
+                departure_date: Some(NaiveDate::from_ymd(2020, 10, 17)),
+            }),
+            JourneyElement::Stop(String::from("Bahnhof")),
+            JourneyElement::Walk,
+            JourneyElement::Stop(String::from("Bahnhof/ZOB")),
+        ]);
+        assert_eq!(journey.to_path(), "/17.10.20 21:30/Am Markt/Bus 420 nach Wolfenb%C3%BCttel Bahnhof um 21:39 am 17.10.20/Bahnhof/Fußweg/Bahnhof%2FZOB/");
+
+        // route types with umlauts, headsigns with the keywords of the grammar, and empty route names
+        let element = parse_trip_element("Fähre  nach Lemwerder nach Vegesack um 12:00 um 12:05").unwrap();
+        assert_eq!(element.route_type, "Fähre");
+        assert_eq!(element.route_name, "");
+        assert_eq!(element.headsign, "Lemwerder nach Vegesack um 12:00");
+        assert_eq!(element.departure_time, NaiveTime::from_hms(12, 5, 0));
+        assert_eq!(parse_trip_element(&element.to_string()).unwrap(), element);
+
+        assert!(JourneyUrl::parse(&[]).unwrap_err().is::<BadRequest>());
+        assert!(parse_trip_element("Bus 1 nach Bahnhof um 25:61").unwrap_err().is::<BadRequest>());
+        assert!(parse_trip_element("Bus 1 nach Bahnhof um 12:00 am 29.02.21").unwrap_err().is::<BadRequest>());
+        // a trip where a stop is expected is just a stop with a strange name, but not the other way round
+        assert!(JourneyUrl::parse(&split_path("/17.10.20 21:30/Am Markt/Bahnhof/")).unwrap_err().is::<BadRequest>());
+    }
+
+    #[test]
+    fn test_random_journeys_round_trip() {
+        let mut rng = XorShiftRng::seed_from_u64(2116);
+        let mut count = 0;
+        while count < 10000 {
+            let journey = match random_journey(&mut rng) {
+                Some(journey) => journey,
+                None => continue,
+            };
+            let path = journey.to_path();
+            assert_eq!(JourneyUrl::parse(&split_path(&path)).unwrap(), journey, "{}", path);
+            count += 1;
+        }
+    }
+
+    #[test]
+    fn test_random_paths_are_journeys_or_bad_requests() {
+        let mut rng = XorShiftRng::seed_from_u64(16);
+        for _ in 0..10000 {
+            let mut path = format!("/{}/", Local.timestamp(rng.gen_range(0, 3_000_000_000), 0).format(START_DATE_TIME_FORMAT));
+            for _ in 0..rng.gen_range(0, 6) {
+                path.push_str(&random_text(&mut rng, 0, 12));
+                path.push('/');
+            }
+            match JourneyUrl::parse(&split_path(&path)) {
+                // whatever could be parsed is written so that it's parsed the same way again
+                Ok(journey) => assert_eq!(JourneyUrl::parse(&split_path(&journey.to_path())).unwrap(), journey, "{}", path),
+                Err(e) => assert!(e.is::<BadRequest>(), "unexpected error for {}: {}", path, e),
+            }
+        }
+    }
+}
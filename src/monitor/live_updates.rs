@@ -0,0 +1,149 @@
+use chrono::{DateTime, Local};
+use hyper::{Body, Response};
+use hyper::body::Bytes;
+use hyper::header::HeaderValue;
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::broadcast::{Receiver, RecvError};
+
+use crate::FnResult;
+use crate::prediction_events::PredictionsUpdated;
+use crate::types::{EventType, OriginType, PrecisionType};
+use super::journey_data::{JourneyData, JourneyComponent, WalkProfile};
+use super::display_thresholds::DisplayThresholds;
+use super::time_window::TimeWindow;
+use super::{Monitor, DbPrediction, bad_request, get_predictions_for_stop, get_stop_page_time_range};
+
+/// The part of a prediction that is sent to the browser. The page itself is rendered on the
+/// server, so the browser only needs to know that something has changed, but other clients
+/// may use the data directly.
+#[derive(Serialize)]
+struct LivePrediction {
+    route_id: String,
+    trip_id: String,
+    trip_start_date: String,
+    stop_id: String,
+    stop_sequence: usize,
+    prediction_min: String,
+    prediction_max: String,
+    origin_type: OriginType,
+    precision_type: PrecisionType,
+    sample_size: i32,
+}
+
+impl From<&DbPrediction> for LivePrediction {
+    fn from(prediction: &DbPrediction) -> Self {
+        LivePrediction {
+            route_id: prediction.route_id.to_string(),
+            trip_id: prediction.trip_id.to_string(),
+            trip_start_date: prediction.trip_start_date.format("%Y-%m-%d").to_string(),
+            stop_id: prediction.stop_id.to_string(),
+            stop_sequence: prediction.stop_sequence,
+            prediction_min: prediction.prediction_min.to_rfc3339(),
+            prediction_max: prediction.prediction_max.to_rfc3339(),
+            origin_type: prediction.origin_type.clone(),
+            precision_type: prediction.precision_type.clone(),
+            sample_size: prediction.sample_size,
+        }
+    }
+}
+
+/// Serves the `/live/<journey>` endpoint, which sends server-sent events to the page of the stop
+/// at the end of the journey. The predictions for the stop are looked up periodically, and whenever
+/// they differ from the previous lookup, a `predictions` event with all of them is sent. If the importer
+/// publishes its updated predictions, they are looked up right away when one of the stops is affected.
+/// When the time span of the page is over, an `end` event is sent and the stream is closed.
+pub fn generate_live_updates(monitor: &Arc<Monitor>, journey: &[String], accessible: bool, walk_profile: WalkProfile, display_thresholds: DisplayThresholds, time_window: TimeWindow) -> FnResult<Response<Body>> {
+    // the live updates contain all predictions, but the extended stops depend on the radius of the page
+    let mut journey_data = JourneyData::new(journey, monitor.clone(), accessible, walk_profile, display_thresholds)?;
+    journey_data.time_window = time_window;
+    let stop_data = match journey_data.get_last_component() {
+        Some(JourneyComponent::Stop(stop_data)) => stop_data,
+        _ => return bad_request("Live updates are only available for stop pages."),
+    };
+    let (min_time, _len_time, max_time) = get_stop_page_time_range(&journey_data, &stop_data);
+    let stop_ids = stop_data.extended_stop_ids.clone();
+
+    let (mut sender, body) = Body::channel();
+    let monitor = monitor.clone();
+    let mut events = monitor.prediction_events.subscribe();
+    tokio::spawn(async move {
+        let mut previous: Option<String> = None;
+        while Local::now() < max_time {
+            // the database access is blocking, so it must not run on the threads of the server
+            let lookup_monitor = monitor.clone();
+            let lookup_stop_ids = stop_ids.clone();
+            let lookup = tokio::task::spawn_blocking(move || {
+                // errors can't be sent between threads, so only their message is kept
+                get_live_predictions(&lookup_monitor, &lookup_stop_ids, min_time, max_time).map_err(|e| e.to_string())
+            }).await;
+
+            let event = match lookup {
+                Ok(Ok(predictions)) => {
+                    // the page already shows the predictions that were present when it was opened
+                    let event = match &previous {
+                        Some(previous) if *previous != predictions => Some(format!("event: predictions\ndata: {}\n\n", predictions)),
+                        _ => None,
+                    };
+                    previous = Some(predictions);
+                    event
+                },
+                Ok(Err(e)) => {
+                    warn!("Could not look up predictions for live updates: {}", e);
+                    None
+                },
+                Err(e) => {
+                    warn!("Lookup of predictions for live updates failed: {}", e);
+                    None
+                }
+            };
+
+            // comments keep the connection alive and tell us when the client has gone away
+            let data = event.unwrap_or_else(|| String::from(": ping\n\n"));
+            if sender.send_data(Bytes::from(data)).await.is_err() {
+                debug!("Client closed live updates.");
+                return;
+            }
+            wait_for_predictions(&mut events, &stop_ids, monitor.live_update_interval).await;
+        }
+        sender.send_data(Bytes::from("event: end\ndata: {}\n\n")).await.ok();
+    });
+
+    let mut response = Response::new(body);
+    response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+    response.headers_mut().append(hyper::header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    Ok(response)
+}
+
+// Waits until the importer reports updated predictions for one of the stops, but at most for the interval.
+// Without events from the importer, this just waits for the interval.
+async fn wait_for_predictions(events: &mut Receiver<Arc<PredictionsUpdated>>, stop_ids: &[String], interval: std::time::Duration) {
+    let affecting_event = async {
+        loop {
+            match events.recv().await {
+                Ok(event) if event.affects_any_stop(stop_ids) => return,
+                Ok(_) => continue,
+                // the missed events might have affected the stops
+                Err(RecvError::Lagged(_)) => return,
+                Err(RecvError::Closed) => futures::future::pending::<()>().await,
+            }
+        }
+    };
+    tokio::time::timeout(interval, affecting_event).await.ok();
+}
+
+// all departure predictions of the stops as JSON, in a stable order, so that they can be compared
+pub fn get_live_predictions(monitor: &Arc<Monitor>, stop_ids: &[String], min_time: DateTime<Local>, max_time: DateTime<Local>) -> FnResult<String> {
+    let mut db_predictions = Vec::new();
+    for stop_id in stop_ids {
+        db_predictions.extend(get_predictions_for_stop(monitor, monitor.source.clone(), EventType::Departure, stop_id, min_time, max_time)?);
+    }
+    to_live_predictions_json(&db_predictions)
+}
+
+// the predictions as JSON, like get_live_predictions, for predictions that have already been looked up
+pub fn to_live_predictions_json(db_predictions: &[DbPrediction]) -> FnResult<String> {
+    let mut predictions: Vec<LivePrediction> = db_predictions.iter().map(LivePrediction::from).collect();
+    predictions.sort_by(|a, b| (&a.trip_id, &a.trip_start_date, &a.stop_id, a.stop_sequence).cmp(&(&b.trip_id, &b.trip_start_date, &b.stop_id, b.stop_sequence)));
+    Ok(serde_json::to_string(&predictions)?)
+}
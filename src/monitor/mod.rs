@@ -1,15 +1,43 @@
 mod journey_data;
+mod journey_url;
 mod time_curve;
+mod ics_export;
+mod stats_page;
+mod health_page;
+mod stop_search;
+mod live_updates;
+mod curve_images;
+mod static_render;
+mod request_limits;
+mod display_thresholds;
+mod delay_map;
+mod conditional_get;
+mod favorites;
+mod board;
+mod admin;
+mod stage_timings;
+mod schedule_only;
+mod observation_history;
+mod departure_filter;
+mod crawl_control;
+mod walk_isochrone;
+mod page_models;
+mod info_export;
+mod theme;
+mod schedule_check;
+mod time_window;
 
 use std::collections::HashMap;
 
-use crate::{FnResult, Main, date_and_time_local, OrError};
-use chrono::{Date, DateTime, Local, Duration, Timelike};
+use crate::{FnResult, Main, OrError};
+use crate::prediction_events::PredictionsUpdated;
+use chrono::{DateTime, Local, Duration, Timelike};
 use chrono_locale::LocaleDate;
 use clap::{App, ArgMatches, Arg};
-use crate::types::{EventType, OriginType, PrecisionType, CurveSetKey, TimeSlot, DelayStatistics, VehicleIdentifier};
-use std::sync::Arc;
-use gtfs_structures::{Gtfs, RouteType, Trip, StopTime};
+use crate::types::{EventType, OriginType, PrecisionType, CurveSetKey, TimeSlot, DelayStatistics, VehicleIdentifier, WeatherCondition, DbPrediction, HeadwayEntry, HeadwayStatistics};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use gtfs_structures::{Availability, Gtfs, RouteType, Trip};
 use mysql::*;
 use mysql::prelude::*;
 
@@ -17,6 +45,7 @@ use std::convert::Infallible;
 use std::net::SocketAddr;
 use hyper::{Body, Request, Response, Server, StatusCode};
 use hyper::header::{HeaderValue};
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper_staticfile::Static;
 use itertools::Itertools;
@@ -24,7 +53,7 @@ use simple_error::bail;
 
 use percent_encoding::{percent_decode_str, utf8_percent_encode, CONTROLS, AsciiSet};
 
-const PATH_ELEMENT_ESCAPE: &AsciiSet = &CONTROLS.add(b'/').add(b'?').add(b'"').add(b'`');
+const PATH_ELEMENT_ESCAPE: &AsciiSet = &CONTROLS.add(b'/').add(b'?').add(b'"').add(b'`').add(b'#').add(b'%').add(b'<').add(b'>');
 
 
 use dystonse_curves::{IrregularDynamicCurve, Curve, TypedCurve};
@@ -33,29 +62,85 @@ use colorous::*;
 
 use journey_data::*;
 use time_curve::TimeCurve;
+use ics_export::generate_ics_file;
+use stats_page::{generate_stats_overview, generate_route_stats_page, generate_route_variant_stats_page};
+use health_page::generate_health_page;
+use stop_search::{StopSearch, generate_autocomplete};
+use live_updates::generate_live_updates;
+use curve_images::{CurveImageCache, serve_curve_image};
+use schedule_check::check_schedule_consistency;
+use static_render::StaticRenderer;
+use request_limits::{RequestLimits, handle_limited_request};
+use display_thresholds::{DisplayThresholds, RiskPreference};
+use delay_map::{PunctualityCache, generate_delay_map_page, generate_delay_map_data};
+use conditional_get::{ConditionalHeaders, PredictionVersions, get_stop_page_validator, add_static_file_validation};
+use favorites::{generate_favorites_page, change_favorites, parse_favorites};
+use board::generate_board_page;
+use admin::handle_admin_request;
+use stage_timings::{Stage, start_stage, measure_request};
+use departure_filter::{DepartureFilter, get_route_type_name};
+use time_window::TimeWindow;
+use crawl_control::{generate_robots_txt, generate_sitemap, block_deep_crawl};
+use walk_isochrone::{generate_walk_isochrone_page, generate_walk_isochrone_data};
+use info_export::generate_info_csv;
+use theme::Theme;
+use page_models::{StopPageModel, StopPagePredictions, DepartureModel, TransferArrivalModel, TransferMode, TripPageModel, JourneyArrivalModel, TripStopModel};
+
+// how many stops that can be reached by bike are suggested on a stop page
+const MAX_BIKE_DESTINATIONS: usize = 8;
+
+// how many events about updated predictions are kept for live updates that haven't received them yet
+const PREDICTION_EVENTS_CAPACITY: usize = 16;
+
+// how often the statistics are looked for while the monitor runs without them
+const STATISTICS_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+// width (in pixels) of the journey arrival strip on the trip page
+const JOURNEY_STRIP_WIDTH: usize = 600;
 
-const FAVICON_HEADERS: &'static str = r##"
-<link rel="apple-touch-icon" sizes="180x180" href="/favicons/apple-touch-icon.png?v=m2ndzBjkKM">
-<link rel="icon" type="image/png" sizes="32x32" href="/favicons/favicon-32x32.png?v=m2ndzBjkKM">
-<link rel="icon" type="image/png" sizes="16x16" href="/favicons/favicon-16x16.png?v=m2ndzBjkKM">
-<link rel="manifest" href="/favicons/site.webmanifest?v=m2ndzBjkKM">
-<link rel="mask-icon" href="/favicons/safari-pinned-tab.svg?v=m2ndzBjkKM" color="#5bbad5">
-<link rel="shortcut icon" href="/favicons/favicon.ico?v=m2ndzBjkKM">
-<meta name="msapplication-TileColor" content="#00aba9">
-<meta name="msapplication-config" content="/favicons/browserconfig.xml?v=m2ndzBjkKM">
-<meta name="theme-color" content="#ffffff">
-"##;
-
-#[derive(Clone)]
 pub struct Monitor {
     //pub schedule: Arc<Gtfs>,
     pub pool: Arc<Pool>,
     pub source: String,
     pub source_long_name: String,
     pub source_attribution: String,
-    pub stats: Arc<DelayStatistics>,
+    /// the statistics, with the time at which they were loaded, replaced by `reload_stats`
+    stats: RwLock<(Arc<DelayStatistics>, DateTime<Local>)>,
+    /// set while no statistics could be loaded, then the departures are taken from the schedule alone
+    statistics_missing: AtomicBool,
     pub static_server: Static,
     pub main: Arc<Main>,
+    /// used unless the query parameters select other thresholds or radii
+    pub display_thresholds: DisplayThresholds,
+    /// how often the predictions are looked up for the live updates of stop pages
+    pub live_update_interval: std::time::Duration,
+    /// used if neither the URL nor a cookie select another walk profile
+    pub default_walk_profile: WalkProfile,
+    /// built on first use, and again when the schedule changes
+    stop_search: Mutex<Option<(Arc<Gtfs>, Arc<StopSearch>)>>,
+    /// the probability strips of all pages, served under /curve/
+    pub curve_images: CurveImageCache,
+    pub request_limits: RequestLimits,
+    /// the punctuality per time slot that is shown on the network map
+    pub punctuality_cache: PunctualityCache,
+    /// the route variants that are shown with their headways instead of single trips, if enabled
+    pub headways: Option<HeadwayStatistics>,
+    /// since when the predictions of the stop pages are unchanged, for their Last-Modified headers
+    prediction_versions: PredictionVersions,
+    /// needed for the endpoints under /admin/, which are disabled if it's not set
+    admin_token: Option<String>,
+    /// requests that take longer are logged with the durations of their stages
+    pub slow_request_threshold: std::time::Duration,
+    /// the updated predictions that the importer has published, if enabled, for the live updates
+    pub prediction_events: tokio::sync::broadcast::Sender<Arc<PredictionsUpdated>>,
+    /// how often the schedule and the statistics are loaded again if their files have changed, if at all
+    reload_interval: Option<std::time::Duration>,
+    /// crawlers get 404 for journeys with more elements than this, if set (and always for time windows)
+    max_crawl_depth: Option<usize>,
+    /// the URL of the website for the sitemap, if it differs from the Host header, e.g. behind a reverse proxy
+    public_url: Option<String>,
+    /// the branding of this deployment
+    pub theme: Theme,
 }
 
 impl Monitor {
@@ -74,28 +159,302 @@ impl Monitor {
         .takes_value(true)
         .about("Attribution for the data, in humand readable format. HTML can be used and will be written verbatim.")
     )
+        .arg(Arg::new("alternatives-threshold")
+            .long("alternatives-threshold")
+            .env("MONITOR_ALTERNATIVES_THRESHOLD")
+            .takes_value(true)
+            .default_value("50")
+            .about("If the chance (in percent) to catch a departure is below this threshold, the next departures of the same route or to the same destination are suggested as alternatives. Can be overridden per request with the query parameter alternatives-threshold.")
+        )
+        .arg(Arg::new("min-chance")
+            .long("min-chance")
+            .env("MONITOR_MIN_CHANCE")
+            .takes_value(true)
+            .default_value("5")
+            .about("Departures with a lower chance (in percent) to catch them are not shown on stop pages. Can be overridden per request with the query parameter min-chance.")
+        )
+        .arg(Arg::new("risk")
+            .long("risk")
+            .env("MONITOR_RISK")
+            .takes_value(true)
+            .possible_values(&RiskPreference::NAMES)
+            .default_value("balanced")
+            .about("Risk of missing a transfer that is assumed to be acceptable, unless the user selects another preference. Conservative users only see departures with at least 25% chance, risky users those down to 1%, and the departures are ordered by an earlier or later time of their predictions.")
+        )
+        .arg(Arg::new("curve-trim")
+            .long("curve-trim")
+            .env("MONITOR_CURVE_TRIM")
+            .takes_value(true)
+            .default_value("5")
+            .about("Share (in percent) that is cut off at both ends of each prediction. Departures are only shown on a stop page if the rest of their prediction overlaps the time span of the page. Can be overridden per request with the query parameter curve-trim.")
+        )
+        .arg(Arg::new("live-update-interval")
+            .long("live-update-interval")
+            .env("MONITOR_LIVE_UPDATE_INTERVAL")
+            .takes_value(true)
+            .default_value("20")
+            .about("Interval (in seconds) in which the predictions are looked up for stop pages that are open in a browser. If they have changed, the page is updated.")
+        )
+        .arg(Arg::new("walk-profile")
+            .long("walk-profile")
+            .env("MONITOR_WALK_PROFILE")
+            .takes_value(true)
+            .possible_values(&WalkProfile::NAMES)
+            .default_value("normal")
+            .about("Walking speed that is assumed for transfers, unless the user selects another one.")
+        )
+        .arg(Arg::new("extended-stops-radius")
+            .long("extended-stops-radius")
+            .env("MONITOR_EXTENDED_STOPS_RADIUS")
+            .takes_value(true)
+            .default_value("300")
+            .about("Radius (in meters) in which the departures of other stops are included in a stop's page. Only used for stops that don't belong to a station (via parent_station) in the schedule. Can be overridden per request with the query parameter extended-stops-radius, up to 1000 meters.")
+        )
+        .arg(Arg::new("rate-limit")
+            .long("rate-limit")
+            .env("MONITOR_RATE_LIMIT")
+            .takes_value(true)
+            .default_value("60")
+            .about("Number of requests per minute that each client (by IP address) may send, not counting static files and images. Use 0 to disable the limit.")
+        )
+        .arg(Arg::new("max-concurrent-requests")
+            .long("max-concurrent-requests")
+            .env("MONITOR_MAX_CONCURRENT_REQUESTS")
+            .takes_value(true)
+            .default_value("16")
+            .about("Number of requests that are answered at the same time, not counting static files and images. Further requests have to wait.")
+        )
+        .arg(Arg::new("headway-display")
+            .long("headway-display")
+            .env("MONITOR_HEADWAY_DISPLAY")
+            .about("If provided, the departures of high-frequency route variants are shown as one line per route and headsign with the time between their vehicles, instead of one line per trip. Needs the headways.json file from `analyse compute-headways` in dir.")
+        )
+        .arg(Arg::new("trust-forwarded-for")
+            .long("trust-forwarded-for")
+            .about("If provided, clients are identified by the X-Forwarded-For header. Use this only if the monitor runs behind a reverse proxy that sets it.")
+        )
+        .arg(Arg::new("trusted-proxies")
+            .long("trusted-proxies")
+            .env("MONITOR_TRUSTED_PROXIES")
+            .takes_value(true)
+            .default_value("1")
+            .about("Number of reverse proxies in front of the monitor that append to the X-Forwarded-For header. The client is identified by the address that the outermost of them has appended, all addresses before it may have been made up by the client. Only used with --trust-forwarded-for.")
+        )
+        .arg(Arg::new("admin-token")
+            .long("admin-token")
+            .env("MONITOR_ADMIN_TOKEN")
+            .takes_value(true)
+            .about("Secret token for the endpoints under /admin/, which must be sent as `Authorization: Bearer <token>`. If not provided, the admin endpoints are disabled.")
+        )
+        .arg(Arg::new("slow-request-threshold")
+            .long("slow-request-threshold")
+            .env("MONITOR_SLOW_REQUEST_THRESHOLD")
+            .takes_value(true)
+            .default_value("2000")
+            .about("Requests that take longer than this (in milliseconds) are logged as warnings, with the time spent on database queries, metadata, rendering and PNG encoding. In verbose mode, this is logged for all requests.")
+        )
+        .arg(Arg::new("reload-interval")
+            .long("reload-interval")
+            .env("MONITOR_RELOAD_INTERVAL")
+            .takes_value(true)
+            .default_value("300")
+            .about("Interval (in seconds) in which the monitor checks whether the schedule or the statistics have changed, and loads them in the background. Until they are loaded, the previous ones are used. 0 disables the checks, then they are only loaded again on SIGHUP or with POST /admin/reload.")
+        )
+        .arg(Arg::new("max-crawl-depth")
+            .long("max-crawl-depth")
+            .env("MONITOR_MAX_CRAWL_DEPTH")
+            .takes_value(true)
+            .about("If provided, clients that don't identify as a browser get 404 for journeys with more elements than this after the start time, e.g. 1 allows only stop pages and 2 allows trip pages as well. Journeys with a time window are refused to them in any case.")
+        )
+        .arg(Arg::new("public-url")
+            .long("public-url")
+            .env("MONITOR_PUBLIC_URL")
+            .takes_value(true)
+            .about("URL of the website, e.g. https://example.org, for the links in robots.txt and sitemap.xml. If not provided, it is taken from the Host header of the request.")
+        )
+        .arg(Arg::new("schedule")
+            .long("schedule")
+            .env("MONITOR_SCHEDULE")
+            .takes_value(true)
+            .value_name("GTFS_SCHEDULE")
+            .about("The path of the GTFS schedule that the monitor uses, instead of the newest schedule in the dir. Takes precedence over the global --schedule argument.")
+        )
+        .arg(Arg::new("theme-dir")
+            .long("theme-dir")
+            .env("MONITOR_THEME_DIR")
+            .takes_value(true)
+            .value_name("DIR")
+            .about("Directory with the branding of this deployment. Its files replace those of web-assets/ with the same path (e.g. style.css, images/logo.svg or favicons/), and theme.json can set site_name, agency_name, primary_color and background_color.")
+        )
+        .subcommand(App::new("render")
+            .about("Instead of starting the web server, renders the pages of some stops into static HTML files at a fixed interval.")
+            .arg(Arg::new("stops")
+                .long("stops")
+                .about("Names of the stops whose pages are rendered.")
+                .value_name("STOP_NAME")
+                .multiple(true)
+                .required(true)
+            ).arg(Arg::new("out")
+                .long("out")
+                .about("Directory into which the pages, the curve images and the assets of the website are written.")
+                .value_name("DIR")
+                .required(true)
+            ).arg(Arg::new("interval")
+                .long("interval")
+                .about("Interval (in seconds) in which the pages are rendered. The pages reload themselves in the same interval.")
+                .takes_value(true)
+                .default_value("60")
+            ).arg(Arg::new("once")
+                .long("once")
+                .about("If provided, the pages are only rendered once, e.g. when called from cron.")
+            )
+        )
     }
 
     /// Runs the actions that are selected via the command line args
     pub fn run(main: Arc<Main>, sub_args: &ArgMatches) -> FnResult<()> {
+        // without statistics, the monitor starts anyway and waits for them, see `wait_for_statistics`
+        let (stats, statistics_missing) = match main.get_delay_statistics() {
+            Ok(stats) => (stats, false),
+            Err(e) => {
+                warn!("Starting without statistics, only the scheduled departures are shown until they can be loaded: {}", e);
+                (Arc::new(DelayStatistics::new()), true)
+            },
+        };
         let monitor = Monitor {
             // schedule: main.get_schedule()?.clone(),
             pool: main.pool.clone(),
             source: main.source.clone(),
             source_long_name: String::from(sub_args.value_of("source-long-name").unwrap()),
             source_attribution: String::from(sub_args.value_of("source-attribution").unwrap_or("unbekannt")),
-            stats: main.get_delay_statistics()?,
+            stats: RwLock::new((stats, Local::now())),
+            statistics_missing: AtomicBool::new(statistics_missing),
             static_server: Static::new("web-assets/"),
+            theme: Theme::from_args(sub_args)?,
             main: main.clone(),
+            display_thresholds: DisplayThresholds::from_args(sub_args)?,
+            live_update_interval: std::time::Duration::from_secs(sub_args.value_of("live-update-interval").unwrap().parse()?), // has a default value
+            default_walk_profile: WalkProfile::from_name(sub_args.value_of("walk-profile").unwrap())?, // has a default value
+            stop_search: Mutex::new(None),
+            curve_images: CurveImageCache::new(),
+            request_limits: RequestLimits::from_args(sub_args)?,
+            punctuality_cache: PunctualityCache::new(),
+            headways: if sub_args.is_present("headway-display") {
+                Some(HeadwayStatistics::load_from_dir(&main.dir)?)
+            } else {
+                None
+            },
+            prediction_versions: PredictionVersions::new(),
+            admin_token: sub_args.value_of("admin-token").map(String::from),
+            slow_request_threshold: std::time::Duration::from_millis(sub_args.value_of("slow-request-threshold").unwrap().parse()?), // has a default value
+            prediction_events: tokio::sync::broadcast::channel(PREDICTION_EVENTS_CAPACITY).0,
+            reload_interval: match sub_args.value_of("reload-interval").unwrap().parse()? { // has a default value
+                0 => None,
+                seconds => Some(std::time::Duration::from_secs(seconds)),
+            },
+            max_crawl_depth: match sub_args.value_of("max-crawl-depth") {
+                Some(depth) => Some(depth.parse()?),
+                None => None,
+            },
+            public_url: sub_args.value_of("public-url").map(String::from),
         };
+        let monitor = Arc::new(monitor);
+
+        // predictions for another schedule are worth a warning, but no reason not to start
+        if let Err(e) = check_schedule_consistency(&monitor) {
+            warn!("Could not check whether the predictions match the schedule: {}", e);
+        }
+
+        if let ("render", Some(render_args)) = sub_args.subcommand() {
+            let renderer = StaticRenderer {
+                monitor,
+                args: render_args,
+            };
+            return renderer.run();
+        }
+
+        #[cfg(feature = "redis-events")]
+        {
+            if let Some(url) = main.args.value_of("redis-url") {
+                let events_monitor = monitor.clone();
+                crate::prediction_events::subscribe(url, &main.source, move |event| events_monitor.handle_prediction_event(event))?;
+            }
+        }
 
         let mut rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async {
-            serve_monitor(Arc::new(monitor)).await
+            serve_monitor(monitor).await
         });
 
         Ok(())
     }
+
+    pub fn get_stats(&self) -> Arc<DelayStatistics> {
+        self.stats.read().unwrap().0.clone()
+    }
+
+    /// Returns the time at which the statistics in use have been loaded.
+    pub fn get_stats_loaded_time(&self) -> DateTime<Local> {
+        self.stats.read().unwrap().1
+    }
+
+    /// Loads the statistics again, e.g. after new curves have been computed. Pages that are
+    /// being rendered keep using the old statistics.
+    pub fn reload_stats(&self) -> FnResult<()> {
+        let stats = self.main.get_delay_statistics()?;
+        *self.stats.write().unwrap() = (stats, Local::now());
+        self.statistics_missing.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Loads the schedule and the statistics again if their files have changed, and swaps them in once
+    /// they are loaded. Returns whether the schedule and whether the statistics have been replaced.
+    pub fn reload_changed(&self) -> FnResult<(bool, bool)> {
+        let schedule_changed = self.main.refresh_schedule()?;
+        // while the statistics are missing, any statistics file that appears is new
+        let statistics_changed = self.main.refresh_delay_statistics()? || self.is_statistics_missing();
+        if statistics_changed {
+            self.reload_stats()?;
+        }
+        Ok((schedule_changed, statistics_changed))
+    }
+
+    /// Whether the monitor runs without statistics and shows the scheduled departures only.
+    pub fn is_statistics_missing(&self) -> bool {
+        self.statistics_missing.load(Ordering::Relaxed)
+    }
+
+    /// Forgets all data that is kept in memory between requests, so that it is computed again.
+    /// Returns the number of curve images that have been dropped.
+    pub fn clear_caches(&self) -> usize {
+        *self.stop_search.lock().unwrap() = None;
+        self.punctuality_cache.clear();
+        self.prediction_versions.clear();
+        self.curve_images.clear()
+    }
+
+    /// Forgets since when the predictions of the stops in the event are unchanged, and tells the live
+    /// updates to look them up right away.
+    #[cfg(feature = "redis-events")]
+    fn handle_prediction_event(&self, event: PredictionsUpdated) {
+        self.prediction_versions.forget_stops(&event.stop_ids);
+        // fails if no stop page is open, which is fine
+        self.prediction_events.send(Arc::new(event)).ok();
+    }
+
+    /// Returns the stop search for the current schedule.
+    pub fn get_stop_search(&self) -> FnResult<Arc<StopSearch>> {
+        let schedule = self.main.get_schedule()?;
+        let mut cached = self.stop_search.lock().unwrap();
+        if let Some((cached_schedule, stop_search)) = &*cached {
+            if Arc::ptr_eq(cached_schedule, &schedule) {
+                return Ok(stop_search.clone());
+            }
+        }
+        let stop_search = Arc::new(StopSearch::from_schedule(&schedule)?);
+        *cached = Some((schedule, stop_search.clone()));
+        Ok(stop_search)
+    }
 }
 
 
@@ -107,16 +466,17 @@ async fn serve_monitor(monitor: Arc<Monitor>) {
 
     // A `Service` is needed for every connection, so this
     // creates one from our `handle_request` function.
-    let make_svc = make_service_fn(move |_conn| {
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
 
         let monitor = monitor.clone();
+        let remote_addr = conn.remote_addr();
         async move {
             // service_fn converts our function into a `Service`
             let monitor = monitor.clone();
             Ok::<_, Infallible>(service_fn( move |request: Request<Body>| {
                 let monitor = monitor.clone();
                 async move {
-                    handle_request(request, monitor.clone()).await
+                    handle_limited_request(request, monitor.clone(), remote_addr).await
                 }
             }))
         }
@@ -126,104 +486,333 @@ async fn serve_monitor(monitor: Arc<Monitor>) {
 
     // TODO let the server listen, then load the schedule.
     // Some requests can be served before the schedule is loaded.
-    println!("Initially loading schedule…");
+    info!("Initially loading schedule…");
     monitor2.main.get_schedule().ok();
 
-    println!("Waiting for connections on {}…", addr);
+    tokio::spawn(reload_regularly(monitor2.clone()));
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(monitor2.clone()));
+
+    info!("Waiting for connections on {}…", addr);
     // Run this server for... forever!
     if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+        error!("server error: {}", e);
+    }
+}
+
+/// Checks for a new schedule and new statistics every `reload_interval`, and for the statistics every
+/// `STATISTICS_RETRY_INTERVAL` while they are missing, so that they are used without a restart.
+async fn reload_regularly(monitor: Arc<Monitor>) {
+    loop {
+        let interval = match (monitor.reload_interval, monitor.is_statistics_missing()) {
+            (Some(interval), true) => interval.min(STATISTICS_RETRY_INTERVAL),
+            (Some(interval), false) => interval,
+            (None, true) => STATISTICS_RETRY_INTERVAL,
+            (None, false) => return,
+        };
+        tokio::time::delay_for(interval).await;
+        reload_in_background(monitor.clone()).await;
+    }
+}
+
+/// Loads the schedule and the statistics again if they have changed whenever the monitor receives SIGHUP.
+#[cfg(unix)]
+async fn reload_on_hangup(monitor: Arc<Monitor>) {
+    let mut hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("Could not listen for SIGHUP, reloading is only possible via /admin/reload: {}", e);
+            return;
+        },
+    };
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, looking for a new schedule and new statistics…");
+        reload_in_background(monitor.clone()).await;
+    }
+}
+
+async fn reload_in_background(monitor: Arc<Monitor>) {
+    let reload_monitor = monitor.clone();
+    // loading is blocking, and errors can't be sent between threads, so only their message is kept
+    match tokio::task::spawn_blocking(move || reload_monitor.reload_changed().map_err(|e| e.to_string())).await {
+        Ok(Ok((schedule_changed, statistics_changed))) => {
+            if schedule_changed {
+                info!("Loaded the new schedule, it is used from now on.");
+            }
+            if statistics_changed {
+                info!("Loaded the new statistics, they are used from now on.");
+            }
+        },
+        Ok(Err(message)) if monitor.is_statistics_missing() => debug!("Statistics are still missing: {}", message),
+        Ok(Err(message)) => warn!("Could not reload the schedule or the statistics: {}", message),
+        Err(e) => warn!("Could not reload the schedule or the statistics: {}", e),
+    }
+}
+
+/// Error for requests that can't be answered because of invalid input. 
+/// It leads to a 400 response instead of a 500 response.
+#[derive(Debug)]
+pub struct BadRequest(pub String);
+
+impl std::fmt::Display for BadRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
 
+impl std::error::Error for BadRequest {}
+
+pub fn bad_request<T>(message: &str) -> FnResult<T> {
+    Err(Box::new(BadRequest(message.to_string())))
+}
+
+/// Escapes text so that it can be used inside of HTML elements and (quoted) attribute values.
+/// Everything that comes from the schedule, the database or the request must be escaped before
+/// it is written into a page.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encodes text for use as a path element of an URL, and escapes the result for use in
+/// an HTML attribute.
+pub fn url_element(text: &str) -> String {
+    escape_html(&utf8_percent_encode(text, PATH_ELEMENT_ESCAPE).to_string())
+}
+
 async fn handle_request(req: Request<Body>, monitor: Arc<Monitor>) -> std::result::Result<Response<Body>, Infallible> {
-    let path_parts : Vec<String> = req.uri().path().split('/').map(|part| percent_decode_str(part).decode_utf8_lossy().into_owned()).filter(|p| !p.is_empty()).collect();
+    let path_parts = split_path(req.uri().path());
     let path_parts_str : Vec<&str> = path_parts.iter().map(|string| string.as_str()).collect();
-    let query_params: HashMap<String, String> = req
-        .uri()
-        .query()
-        .map(|v| {
-            url::form_urlencoded::parse(v.as_bytes())
-                .into_owned()
-                .collect()
-        }).unwrap_or_else(HashMap::new);
-    println!("path_parts_str: {:?}", path_parts_str);
-    let result: FnResult<Response<Body>> = match &path_parts_str[..] {
-        [] => generate_search_page(&monitor, false, false),
-        ["fonts", _] | ["favicons", _] | ["favicon.ico"] | ["impressum.html"]  | ["style.css"] | ["help", ..] | ["images", ..] => serve_static_file(&monitor, req).await,
-        ["embed"] => generate_search_page(&monitor, true, false),
-        ["noscript"] => generate_search_page(&monitor, false, true),
-        ["autocomplete"] => generate_autocomplete(&monitor, query_params),
-        ["stop-by-name"] => {
-            // an "stop-by-name" URL just redirects to the corresponding "stop" URL. We can't have pretty URLs in the first place because of the way HTML forms work
-            let query_params = url::form_urlencoded::parse(req.uri().query().unwrap().as_bytes());
-            let stop_name = query_params.filter_map(|(key, value)| if key == "start" { Some(value)} else { None } ).next().unwrap();
-            let start_time = Local::now().format("%d.%m.%y %H:%M");
-            let new_path = format!("/{}/{}/", 
-                start_time, 
-                utf8_percent_encode(&stop_name, PATH_ELEMENT_ESCAPE).to_string(),
-            );
-            let mut response = Response::new(Body::empty());
-            response.headers_mut().append(hyper::header::LOCATION, HeaderValue::from_str(&new_path).unwrap());
-            *response.status_mut() = StatusCode::FOUND;
-            Ok(response)
+    let query_params: HashMap<String, String> = req.uri().query().map(parse_query_params).unwrap_or_else(HashMap::new);
+    debug!("path_parts_str: {:?}", path_parts_str);
+    let accessible_param = query_params.get("accessible").map(|value| value == "1" || value == "true");
+    let accessible = accessible_param.unwrap_or_else(|| get_cookie(&req, "accessible").map_or(false, |value| value == "1"));
+    // unknown profiles are ignored, so that old links and cookies still work
+    let walk_param = query_params.get("walk").and_then(|name| WalkProfile::from_name(name).ok());
+    let walk_profile = walk_param
+        .or_else(|| get_cookie(&req, "walk").and_then(|name| WalkProfile::from_name(&name).ok()))
+        .unwrap_or(monitor.default_walk_profile);
+    let risk_param = query_params.get("risk").and_then(|name| RiskPreference::from_name(name).ok());
+    let risk = risk_param
+        .or_else(|| get_cookie(&req, "risk").and_then(|name| RiskPreference::from_name(&name).ok()))
+        .unwrap_or(monitor.display_thresholds.risk);
+    let conditional = ConditionalHeaders::from_request(&req);
+    // with ?timings=1, the durations of the stages are sent in a Server-Timing header
+    let show_timings = query_params.get("timings").map_or(false, |value| value == "1" || value == "true");
+    let favorites = parse_favorites(get_cookie(&req, favorites::COOKIE_NAME));
+    if let Some(response) = block_deep_crawl(&req, &path_parts, monitor.max_crawl_depth) {
+        return Ok(response);
+    }
+    let mut response = match &path_parts_str[..] {
+        ["robots.txt"] => into_response(generate_robots_txt(&monitor, &req)),
+        ["sitemap.xml"] => into_response(generate_sitemap(&monitor, &req)),
+        ["fonts", _] | ["favicons", _] | ["favicon.ico"] | ["impressum.html"] | ["openapi.yaml"] | ["style.css"] | ["help", ..] | ["images", ..] => into_response(serve_static_file(&monitor, req).await),
+        ["curve", file_name] => into_response(serve_curve_image(&monitor, file_name)),
+        // the live updates do their lookups in the background
+        ["live", ..] => into_response(monitor.display_thresholds.with_query_params(&query_params)
+            .and_then(|display_thresholds| {
+                let time_window = TimeWindow::from_query_params(&query_params)?;
+                generate_live_updates(&monitor, &path_parts[1..], accessible, walk_profile, display_thresholds.with_risk(risk), time_window)
+            })),
+        // the admin endpoints check the token and do their work in the background themselves
+        ["admin", ..] => handle_admin_request(req, monitor.clone(), path_parts[1..].to_vec()).await,
+        _ => {
+            // All other pages access the database synchronously, which must not block the threads of the
+            // server, or all other requests would have to wait. Errors can't be sent between threads,
+            // so the response (or error page) is created in the blocking thread already.
+            let blocking_monitor = monitor.clone();
+            let blocking_path_parts = path_parts.clone();
+            let path = String::from(req.uri().path());
+            let lookup = tokio::task::spawn_blocking(move || {
+                let (mut response, timings) = measure_request(|| {
+                    into_response(route_blocking_request(&blocking_monitor, &blocking_path_parts, query_params, accessible, walk_profile, risk, favorites, &conditional))
+                });
+                timings.log(&path, blocking_monitor.slow_request_threshold);
+                if show_timings {
+                    response.headers_mut().append("server-timing", HeaderValue::from_str(&timings.server_timing_header()).unwrap()); // plain ASCII
+                }
+                response
+            }).await;
+            match lookup {
+                Ok(response) => response,
+                Err(e) => generate_error_page(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()).unwrap(), // can't fail, see generate_error_page
+            }
         },
-        ["info", ..] => {
-            let journey = JourneyData::new(&path_parts[1..], monitor.clone()).unwrap();
+    };
 
-            generate_info_page(
-                &monitor, 
-                &journey
-            )
+    // remember the modes that were chosen via the query parameters for the following pages
+    if response.status().is_success() || response.status().is_redirection() {
+        if let Some(accessible) = accessible_param {
+            let cookie = if accessible { "accessible=1; Path=/; Max-Age=31536000; SameSite=Lax" } else { "accessible=0; Path=/; Max-Age=0; SameSite=Lax" };
+            response.headers_mut().append(hyper::header::SET_COOKIE, HeaderValue::from_static(cookie));
+        }
+        if let Some(walk_profile) = walk_param {
+            let cookie = format!("walk={}; Path=/; Max-Age=31536000; SameSite=Lax", walk_profile.name());
+            response.headers_mut().append(hyper::header::SET_COOKIE, HeaderValue::from_str(&cookie).unwrap()); // names are plain ASCII
+        }
+        if let Some(risk) = risk_param {
+            let cookie = format!("risk={}; Path=/; Max-Age=31536000; SameSite=Lax", risk.name());
+            response.headers_mut().append(hyper::header::SET_COOKIE, HeaderValue::from_str(&cookie).unwrap()); // names are plain ASCII
+        }
+    }
+    Ok(response)
+}
+
+// answers all requests that need the database, must not be called from async code
+fn route_blocking_request(
+    monitor: &Arc<Monitor>,
+    path_parts: &[String],
+    query_params: HashMap<String, String>,
+    accessible: bool,
+    walk_profile: WalkProfile,
+    risk: RiskPreference,
+    favorites: Vec<String>,
+    conditional: &ConditionalHeaders,
+) -> FnResult<Response<Body>> {
+    let path_parts_str : Vec<&str> = path_parts.iter().map(|string| string.as_str()).collect();
+    let display_thresholds = monitor.display_thresholds.with_query_params(&query_params)?.with_risk(risk);
+    match &path_parts_str[..] {
+        [] => generate_search_page(&monitor, false, false, walk_profile, risk),
+        ["embed"] => generate_search_page(&monitor, true, false, walk_profile, risk),
+        ["noscript"] => generate_search_page(&monitor, false, true, walk_profile, risk),
+        ["autocomplete"] => generate_autocomplete(&monitor, query_params),
+        ["stop-by-name"] => generate_stop_by_name_redirect(&query_params),
+        ["info", ..] => {
+            JourneyData::new(&path_parts[1..], monitor.clone(), accessible, walk_profile, display_thresholds).and_then(|journey| {
+                if query_params.get("format").map_or(false, |format| format == "csv") {
+                    generate_info_csv(&monitor, &journey)
+                } else {
+                    generate_info_page(&monitor, &journey)
+                }
+            })
+        },
+        ["ics", ..] => {
+            JourneyData::new(&path_parts[1..], monitor.clone(), accessible, walk_profile, display_thresholds).and_then(|journey| generate_ics_file(&journey))
         },
+        ["stats"] => generate_stats_overview(&monitor),
+        ["stats", route_id] => generate_route_stats_page(&monitor, route_id),
+        ["stats", route_id, route_variant] => generate_route_variant_stats_page(&monitor, route_id, route_variant),
+        ["health"] => generate_health_page(&monitor),
+        ["map"] => generate_delay_map_page(monitor, &query_params),
+        ["map", "data"] => generate_delay_map_data(&monitor, &query_params),
+        ["favorites"] => generate_favorites_page(&monitor, &favorites, accessible, walk_profile),
+        ["favorites", "add"] => change_favorites(&monitor, favorites, &query_params, true),
+        ["favorites", "remove"] => change_favorites(&monitor, favorites, &query_params, false),
+        ["board", stop_name] => generate_board_page(&monitor, stop_name, &query_params, accessible, walk_profile),
+        ["walk", stop_name] => generate_walk_isochrone_page(monitor, stop_name, &query_params, walk_profile),
+        ["walk", stop_name, "data"] => generate_walk_isochrone_data(&monitor, stop_name, &query_params, walk_profile),
         _ => {
             // TODO use https://crates.io/crates/chrono_locale for German day and month names
-            handle_route_with_stop(&monitor, &path_parts)
+            let departure_filter = DepartureFilter::from_query_params(&query_params)?;
+            let time_window = TimeWindow::from_query_params(&query_params)?;
+            handle_route_with_stop(&monitor, &path_parts, accessible, walk_profile, display_thresholds, departure_filter, time_window, conditional)
         },
-    };
+    }
+}
 
-    if let Err(e) = result {
-        Ok(generate_error_page(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()).unwrap())
-    } else {
-        Ok(result.unwrap())
+// turns errors into error pages
+fn into_response(result: FnResult<Response<Body>>) -> Response<Body> {
+    match result {
+        Ok(response) => response,
+        Err(e) => {
+            let code = if e.is::<BadRequest>() { StatusCode::BAD_REQUEST } else { StatusCode::INTERNAL_SERVER_ERROR };
+            generate_error_page(code, &e.to_string()).unwrap() // can't fail, see generate_error_page
+        }
     }
 }
 
-async fn serve_static_file(monitor: &Arc<Monitor>, request: Request<Body>) -> FnResult<Response<Body>> {
-    let response = monitor.static_server.clone().serve(request).await?;
+// value of a cookie that was set for an earlier page, e.g. to remember the accessible mode
+fn get_cookie(req: &Request<Body>, name: &str) -> Option<String> {
+    req.headers().get_all(hyper::header::COOKIE).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| {
+            let mut parts = cookie.trim().splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key == name => Some(String::from(value)),
+                _ => None,
+            }
+        })
+        .next()
+}
 
-    return Ok(response);
+// the values of repeated parameters, e.g. from a select with several selected options, are joined
+// with commas, like the lists of the departure filter
+fn parse_query_params(query: &str) -> HashMap<String, String> {
+    let mut query_params: HashMap<String, String> = HashMap::new();
+    for (name, value) in url::form_urlencoded::parse(query.as_bytes()).into_owned() {
+        match query_params.get_mut(&name) {
+            Some(values) => {
+                values.push(',');
+                values.push_str(&value);
+            },
+            None => {
+                query_params.insert(name, value);
+            },
+        }
+    }
+    query_params
 }
 
-fn contains_all(haystack: &str, needles: &Vec<&str>) -> bool {
-    needles.iter().all(|needle| haystack.contains(needle))
+// splits the path of an URL into its percent-decoded, non-empty elements
+fn split_path(path: &str) -> Vec<String> {
+    path.split('/').map(|part| percent_decode_str(part).decode_utf8_lossy().into_owned()).filter(|p| !p.is_empty()).collect()
 }
 
-fn generate_autocomplete(monitor: &Arc<Monitor>, params: HashMap<String, String>) -> FnResult<Response<Body>>  {
-    // TODO check if schedule is available instantly. If not, return a please-wait-message to the client.
-    let schedule = monitor.main.get_schedule()?;
-    let mut w = Vec::new();
-    let term = match params.get("term") {
-        Some(str) => str.to_lowercase(),
-        None => String::new()
+// an "stop-by-name" URL just redirects to the corresponding "stop" URL. We can't have pretty URLs in the first place because of the way HTML forms work
+fn generate_stop_by_name_redirect(query_params: &HashMap<String, String>) -> FnResult<Response<Body>> {
+    let stop_name = match query_params.get("start") {
+        Some(stop_name) if !stop_name.trim().is_empty() => stop_name,
+        _ => return bad_request("Parameter 'start' with the name of a stop is missing."),
     };
-    println!("Search term: {}", term);
-
-    let terms: Vec<&str> = term.split(' ').collect();
-
-    write!(&mut w, "[\n")?;
-    for name in schedule.stops.iter().map(|(_, stop)| stop.name.clone()).sorted().unique().filter(|name| contains_all(&name.to_lowercase(), &terms)).take(10) {
-        write!(&mut w, "\"{name}\",\n",
-        name=name)?;
+    let start_time = Local::now().format("%d.%m.%y %H:%M");
+    // the options of the search form are passed on, so that they are remembered for the following pages
+    let mut options = Vec::new();
+    if query_params.contains_key("accessible") {
+        options.push(String::from("accessible=1"));
     }
-    write!(&mut w, "\"\"]\n")?;
-    let mut response = Response::new(Body::from(w));
-    response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
-
+    if let Some(walk_profile) = query_params.get("walk").and_then(|name| WalkProfile::from_name(name).ok()) {
+        options.push(format!("walk={}", walk_profile.name()));
+    }
+    if let Some(risk) = query_params.get("risk").and_then(|name| RiskPreference::from_name(name).ok()) {
+        options.push(format!("risk={}", risk.name()));
+    }
+    let departure_filter = DepartureFilter::from_query_params(query_params)?;
+    if !departure_filter.is_empty() {
+        options.push(departure_filter.to_query());
+    }
+    let new_path = format!("/{}/{}/{}{}", 
+        start_time, 
+        utf8_percent_encode(&stop_name, PATH_ELEMENT_ESCAPE).to_string(),
+        if options.is_empty() { "" } else { "?" },
+        options.join("&"),
+    );
+    let mut response = Response::new(Body::empty());
+    response.headers_mut().append(hyper::header::LOCATION, HeaderValue::from_str(&new_path)?);
+    *response.status_mut() = StatusCode::FOUND;
     Ok(response)
 }
 
-fn generate_script_station_form(mut w: &mut Vec<u8>, embed: bool) -> FnResult<()> {
+async fn serve_static_file(monitor: &Arc<Monitor>, request: Request<Body>) -> FnResult<Response<Body>> {
+    let conditional = ConditionalHeaders::from_request(&request);
+    // files of the theme replace those of web-assets/
+    let static_server = monitor.theme.get_static_server(request.uri().path()).unwrap_or(&monitor.static_server);
+    let response = static_server.clone().serve(request).await?;
+
+    return Ok(add_static_file_validation(response, &conditional));
+}
+
+fn generate_script_station_form(mut w: &mut Vec<u8>, embed: bool, walk_profile: WalkProfile, risk: RiskPreference) -> FnResult<()> {
     write!(&mut w, r#"
     <form method="get" action="/stop-by-name" target="{target}">
         <div class="search">
@@ -232,6 +821,7 @@ fn generate_script_station_form(mut w: &mut Vec<u8>, embed: bool) -> FnResult<()
     target = if embed { "_blank" } else { "_self" },
     initial_value = if embed { "Bremen Hauptbahnhof" } else { "" },
     )?;
+    write_search_options(&mut w, walk_profile, risk)?;
 
     if embed {
         write!(&mut w, r#"
@@ -249,9 +839,9 @@ fn generate_script_station_form(mut w: &mut Vec<u8>, embed: bool) -> FnResult<()
     Ok(())
 }
 
-fn generate_noscript_station_form(mut w: &mut Vec<u8>, embed: bool, monitor: &Arc<Monitor>) -> FnResult<()> {
+fn generate_noscript_station_form(mut w: &mut Vec<u8>, embed: bool, monitor: &Arc<Monitor>, walk_profile: WalkProfile, risk: RiskPreference) -> FnResult<()> {
     let schedule = monitor.main.get_schedule()?;
-    println!("{} Haltestellen gefunden.", schedule.stops.len());
+    debug!("{} Haltestellen gefunden.", schedule.stops.len());
     
     write!(&mut w, r#"
     <form method="get" action="/stop-by-name" target="{target}">
@@ -265,19 +855,20 @@ fn generate_noscript_station_form(mut w: &mut Vec<u8>, embed: bool, monitor: &Ar
     for name in schedule.stops.iter().map(|(_, stop)| stop.name.clone()).sorted().unique() {
         write!(&mut w, r#"
                     <option>{name}</option>"#,
-        name=name)?;
+        name=escape_html(&name))?;
     }
+    write!(&mut w, r#"
+        </datalist>"#)?;
+    write_search_options(&mut w, walk_profile, risk)?;
 
     if embed {
         write!(&mut w, r#"
-        </datalist>
         <input class="btn project-btn" type="submit" value="Abfahrten anzeigen"/>
         </div>
         </form>"#
         )?;
     } else {
         write!(&mut w, r#"
-        </datalist>
         <input class="box" type="submit" value="Abfahrten anzeigen"/>
         </div>
         </form>"#
@@ -286,7 +877,35 @@ fn generate_noscript_station_form(mut w: &mut Vec<u8>, embed: bool, monitor: &Ar
     Ok(())
 }
 
-fn generate_search_page(monitor: &Arc<Monitor>, embed: bool, noscript: bool) -> FnResult<Response<Body>> {
+// the options of the search forms, which apply to all pages of the journey
+// the walk profile and risk preference of the user (from the cookies, or the defaults of the monitor) are pre-selected
+fn write_search_options(mut w: &mut Vec<u8>, selected_walk_profile: WalkProfile, selected_risk: RiskPreference) -> FnResult<()> {
+    write!(&mut w, r#"
+            <label class="accessible-option"><input type="checkbox" name="accessible" value="1" /> nur barrierefreie Haltestellen und Fahrten</label>
+            <label class="walk-option">Gehtempo: <select name="walk">"#)?;
+    for name in &WalkProfile::NAMES {
+        let walk_profile = WalkProfile::from_name(name)?;
+        write!(&mut w, r#"<option value="{value}"{selected}>{label}</option>"#,
+            value = name,
+            selected = if walk_profile == selected_walk_profile { " selected" } else { "" },
+            label = walk_profile.german_name(),
+        )?;
+    }
+    write!(&mut w, r#"</select></label>
+            <label class="risk-option">Umstiege: <select name="risk">"#)?;
+    for name in &RiskPreference::NAMES {
+        let risk = RiskPreference::from_name(name)?;
+        write!(&mut w, r#"<option value="{value}"{selected}>{label}</option>"#,
+            value = name,
+            selected = if risk == selected_risk { " selected" } else { "" },
+            label = risk.german_name(),
+        )?;
+    }
+    write!(&mut w, r#"</select></label>"#)?;
+    Ok(())
+}
+
+fn generate_search_page(monitor: &Arc<Monitor>, embed: bool, noscript: bool, walk_profile: WalkProfile, risk: RiskPreference) -> FnResult<Response<Body>> {
     // TODO: handle the different GTFS_SOURCE_IDs in some way
     // TODO: compress output, of this page specifically. Adding compression to hyper is
     // explained / shown in the middle of this blog post: https://dev.to/deciduously/hyper-webapp-template-4lj7
@@ -313,14 +932,15 @@ fn generate_search_page(monitor: &Arc<Monitor>, embed: bool, noscript: bool) ->
     write!(&mut w, r#"
     <html>
         <head>
-            <title>Haltestelle wählen | Dystonse ÖPNV-Reiseplaner</title>
+            <title>{title}</title>
             <link rel="stylesheet" href="/style.css">
 
             {favicon_headers}
             <meta name=viewport content="width=device-width, initial-scale=1">
             {scripts}
         </head>"#,
-        favicon_headers = FAVICON_HEADERS,
+        title = monitor.theme.page_title("Haltestelle wählen"),
+        favicon_headers = monitor.theme.head_elements(),
         scripts = scripts
     )?;
     
@@ -332,7 +952,7 @@ fn generate_search_page(monitor: &Arc<Monitor>, embed: bool, noscript: bool) ->
     if !embed {
         write!(&mut w, r#"
     <body>
-        <div class="g1"><a href="/help/" class="boxlink">Hilfe</a></div>
+        <div class="g1"><a href="/help/" class="boxlink">Hilfe</a> · <a href="/favorites" class="boxlink">Meine Haltestellen</a></div>
         <div class="g2"></div>
         <div class="g3"></div>
 
@@ -340,21 +960,24 @@ fn generate_search_page(monitor: &Arc<Monitor>, embed: bool, noscript: bool) ->
             
             <div class="headbox">
                 <div>
-                    <img src="/images/logo.svg" class="logo" />
+                    <img src="/images/logo.svg" class="logo" alt="{site_name}" />
                 </div>
             
             <h1>Reiseplaner</h1>
             <p class="official">
                 <b>Hier kannst du deine Reiseroute mit dem öffentlichen Nahverkehr im {source_long_name} planen.</b>
-            </p>"#,
-            source_long_name = monitor.source_long_name
+            </p>{agency}"#,
+            site_name = escape_html(&monitor.theme.site_name),
+            source_long_name = monitor.source_long_name,
+            agency = monitor.theme.agency_name.as_ref().map(|agency| format!(r#"
+            <p class="agency">Ein Angebot von {}</p>"#, escape_html(agency))).unwrap_or_default(),
         )?;
     }
 
     if noscript {
-        generate_noscript_station_form(&mut w, embed, monitor)?;
+        generate_noscript_station_form(&mut w, embed, monitor, walk_profile, risk)?;
     } else {
-        generate_script_station_form(&mut w, embed)?;
+        generate_script_station_form(&mut w, embed, walk_profile, risk)?;
     }
 
     if !embed {
@@ -399,15 +1022,18 @@ fn generate_search_page(monitor: &Arc<Monitor>, embed: bool, noscript: bool) ->
     Ok(response)
 }
 
-fn handle_route_with_stop(monitor: &Arc<Monitor>, journey: &[String]) -> FnResult<Response<Body>> {
-    let journey = JourneyData::new(&journey, monitor.clone())?;
+fn handle_route_with_stop(monitor: &Arc<Monitor>, journey: &[String], accessible: bool, walk_profile: WalkProfile, display_thresholds: DisplayThresholds, departure_filter: DepartureFilter, time_window: TimeWindow, conditional: &ConditionalHeaders) -> FnResult<Response<Body>> {
+    let mut journey = JourneyData::new(&journey, monitor.clone(), accessible, walk_profile, display_thresholds)?;
+    journey.departure_filter = departure_filter;
+    journey.time_window = time_window;
 
     // println!("Parsed journey: time: {}\n\nstops: {:?}\n\ntrips: {:?}", journey.start_date_time, journey.stops, journey.trips);
     
     let result: FnResult<Response<Body>> = match journey.get_last_component() {
-        Some(JourneyComponent::Stop(stop_data)) => generate_stop_page(monitor, &journey, &stop_data),
+        Some(JourneyComponent::Stop(stop_data)) => generate_stop_page(monitor, &journey, &stop_data, conditional),
         Some(JourneyComponent::Trip(trip_data)) => generate_trip_page(monitor, &journey, &trip_data),
         Some(JourneyComponent::Walk(_)) => generate_error_page(StatusCode::BAD_REQUEST, &format!("Journey may not end with a walk.")),
+        Some(JourneyComponent::Bike(_)) => generate_error_page(StatusCode::BAD_REQUEST, &format!("Journey may not end with a bike ride.")),
         None => generate_error_page(StatusCode::BAD_REQUEST, &format!("Empty journey.")),
     };
 
@@ -416,127 +1042,79 @@ fn handle_route_with_stop(monitor: &Arc<Monitor>, journey: &[String]) -> FnResul
 
 fn generate_error_page(code: StatusCode, message: &str) -> FnResult<Response<Body>> {
     let mut response = Response::new(Body::empty());
-    let doc_string = format!("{}: {}", code.as_str(), message);
+    let doc_string = format!("{}: {}", code.as_str(), escape_html(message));
     *response.body_mut() = Body::from(doc_string);
     *response.status_mut() = code;
     response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
     Ok(response)
 }
 
-fn generate_stop_page(monitor: &Arc<Monitor>, journey_data: &JourneyData, stop_data: &StopData) -> FnResult<Response<Body>> {
-    let schedule = monitor.main.get_schedule()?;
-
-    let mut response = Response::new(Body::empty());
-    let mut departures : Vec<DbPrediction> = Vec::new();
+/// Returns the start, length (in minutes) and end of the time span which is shown on the page of a stop.
+/// It covers the probable arrival at the stop, plus 30 minutes, rounded to nice times, unless the
+/// time window of the journey moves it elsewhere.
+fn get_stop_page_time_range(journey_data: &JourneyData, stop_data: &StopData) -> (DateTime<Local>, i64, DateTime<Local>) {
     let exact_min_time = stop_data.start_curve.typed_x_at_y(0.01);
     let exact_max_time = stop_data.start_curve.typed_x_at_y(0.99);
     let min_time = (exact_min_time - Duration::minutes(exact_min_time.time().minute() as i64 % 5)).with_second(0).unwrap(); // round to previous nice time
     let exact_len_time: i64 = exact_max_time.signed_duration_since(exact_min_time).num_minutes() + 30;
     let len_time: i64 = exact_len_time - (exact_len_time % 5);
-    let max_time = min_time + Duration::minutes(len_time);
-
-    let mut trip_arrival_option : Option<DbPrediction> = None;
-
-    //first line: arrival at this stop
-    if let Some(arrival_trip) = stop_data.get_previous_trip_data() {
-        //let arrival_stop_id = arrival_trip.get_trip(&monitor.schedule)?.stop_times[stop_data.arrival_trip_stop_index.unwrap()].stop.id.clone();
-        let arrival_stop_sequence = arrival_trip.get_trip(&schedule)?.stop_times[stop_data.arrival_trip_stop_index.unwrap()].stop_sequence;
-
-        if let Ok(arrival) = get_prediction_for_first_line(monitor.clone(), arrival_stop_sequence, &arrival_trip.vehicle_id, EventType::Arrival) {
-            trip_arrival_option = Some(arrival);
-        }
-    }
-    
-    for stop_id in &stop_data.extended_stop_ids {
-        departures.extend(get_predictions_for_stop(monitor, monitor.source.clone(), EventType::Departure, stop_id, min_time, max_time)?);
-    }
-
-    println!("Found {} departure predictions.", departures.len());
-
-    for dep in &mut departures {
-        if let Err(e) = dep.compute_meta_data(schedule.clone()){
-            eprintln!("Could not compute metadata for departure with trip_id {}: {}", dep.trip_id , e);
-        }
-    }
-
-    // Remove the top and bottom 5% of the predicted time span. 
-    // They mostly contain outliers with several hours of (sometimes negative) delay.
-    departures.retain(|dep| {
-        if dep.meta_data.is_some() {
-            let time_absolute_05 = dep.get_absolute_time_for_probability(0.05).unwrap();
-            let time_absolute_95 = dep.get_absolute_time_for_probability(0.95).unwrap();
-            
-            time_absolute_05 < max_time && time_absolute_95 > min_time
-        } else {
-            false
-        }
-    });
-
-    println!("Kept {} departure predictions based on removing the top and bottom 5%.", departures.len());
- 
-
-    // Remove duplicates, for which there is a scheduled predcition and a realtime prediction
-    // which concern the same vehicle, but have not been overwritten in the DB  due to
-    // different primary keys (probably a changed trip_id).
-    let departures_copy = departures.clone();
-
-    // local function, which is used in the retain predicate below
-    fn is_duplicate(a: &DbPrediction, b: &DbPrediction) -> bool {
-        b.route_id == a.route_id &&
-        b.trip_start_date == a.trip_start_date &&
-        b.trip_start_time == a.trip_start_time &&
-        b.origin_type == OriginType::Realtime
-    }
-
-    departures.retain(|dep| {
-        dep.origin_type == OriginType::Realtime || !departures_copy.iter().any(|dc| is_duplicate(dep, dc))
-    });
-
-    println!("Kept {} departure predictions after removing duplicates.", departures.len());
-
-    // remove departures where the current stop is the last one (which seem to happen for trains quite often):
-    
-    // local function for use in predicate below
-    fn is_at_last_stop(dep: &DbPrediction, schedule: Arc<Gtfs>) -> bool {
-        if let Ok(trip) = &schedule.get_trip(&dep.trip_id) {
-            if let Some(stop_time) = &trip.stop_times.last() {
-                let last_stop_id = &stop_time.stop.id;
-                return dep.stop_id == *last_stop_id && dep.stop_sequence == stop_time.stop_sequence as usize;
-            }
-        }
-        false
-    }
-    
-    departures.retain(|dep| !is_at_last_stop(&dep, schedule.clone()));
+    journey_data.time_window.apply(min_time, len_time)
+}
 
-    println!("Kept {} departure predictions after removing trips that are at their last stop.", departures.len());
+fn generate_stop_page(monitor: &Arc<Monitor>, journey_data: &JourneyData, stop_data: &StopData, conditional: &ConditionalHeaders) -> FnResult<Response<Body>> {
+    // browsers may keep the page until the next lookup of the live updates, and then revalidate it
+    let predictions = StopPagePredictions::lookup(monitor, journey_data, stop_data)?;
+    let validator = get_stop_page_validator(monitor, journey_data, stop_data, &predictions)?;
+    validator.respond(conditional, monitor.live_update_interval.as_secs(), || {
+        let mut response = Response::new(Body::from(write_stop_page(monitor, journey_data, stop_data, predictions, None)?));
+        response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+        Ok(response)
+    })
+}
 
-    // sort by median departure time:
-    departures.sort_by_cached_key(|dep| dep.get_absolute_time_for_probability(0.50).unwrap());
+/// Writes the HTML of a stop page from the predictions that have been looked up for it. If `reload_interval`
+/// (in seconds) is given, the page reloads itself regularly, otherwise it subscribes to the live updates of the monitor.
+fn write_stop_page(monitor: &Arc<Monitor>, journey_data: &JourneyData, stop_data: &StopData, predictions: StopPagePredictions, reload_interval: Option<u64>) -> FnResult<Vec<u8>> {
+    let model = StopPageModel::new(monitor, journey_data, stop_data, predictions)?;
+    let schedule = monitor.main.get_schedule()?;
+    let stats = monitor.get_stats();
+    let (min_time, len_time, max_time) = (model.min_time, model.len_time, model.max_time);
 
     let mut w = Vec::new();
     write!(&mut w, r#"
     <html>
         <head>
-            <title>{stop_name} | Dystonse ÖPNV-Reiseplaner</title>
+            <title>{title}</title>
             <link rel="stylesheet" href="/style.css">
             
             {favicon_headers}
 
-            <meta name=viewport content="width=device-width, initial-scale=1">
+            <meta name=viewport content="width=device-width, initial-scale=1">{reload}
         </head>
         <body class="monitorbody">
         <a href="/help/" class="help-link">Hilfe</a>"#,
-        stop_name = stop_data.stop_name,
-        favicon_headers = FAVICON_HEADERS,)?;
+        title = monitor.theme.page_title(&model.stop_name),
+        favicon_headers = monitor.theme.head_elements(),
+        reload = reload_interval.map(|seconds| format!(r#"
+            <meta http-equiv="refresh" content="{}">"#, seconds)).unwrap_or_default(),
+    )?;
 
     generate_breadcrumbs(&mut w, journey_data)?;
+    write!(&mut w, r#"
+        <a href="/favorites/add?stop={stop}" class="favorite-add" title="Diese Haltestelle auf der Seite „Meine Haltestellen“ anzeigen">☆ Als Favorit merken</a>"#,
+        stop = escape_html(&url::form_urlencoded::byte_serialize(model.stop_name.as_bytes()).collect::<String>()),
+    )?;
+    write_departure_filter_form(&mut w, journey_data, stop_data)?;
+    if model.statistics_missing {
+        write!(&mut w, r#"
+        <p class="statistics-warning">Zurzeit liegen keine Statistiken vor. Die Abfahrten werden nur laut Fahrplan angezeigt, ohne Prognose der Verspätungen.</p>"#)?;
+    }
 
-    let extended_stops_span = if stop_data.extended_stop_names.len() > 1 {
+    let extended_stops_span = if model.extended_stop_names.len() > 1 {
         format!(
             r#" <span class="extended_stops" title="{stop_names}">(und {stops_number} weitere)</span>"#,
-            stop_names = stop_data.extended_stop_names.join(",\n"),
-            stops_number = stop_data.extended_stop_names.len() - 1,
+            stop_names = escape_html(&model.extended_stop_names.join(",\n")),
+            stops_number = model.extended_stop_names.len() - 1,
         )
     } else {
         String::new()
@@ -544,9 +1122,11 @@ fn generate_stop_page(monitor: &Arc<Monitor>, journey_data: &JourneyData, stop_d
 
     write!(&mut w, r#"
         <h1>Abfahrten für {stop_name}{extended_stops_span}, {date} von {min_time} bis {max_time}</h1>
+            <input type="checkbox" id="realistic-toggle" class="realistic-toggle"><label for="realistic-toggle" class="realistic-toggle" title="Fahrplanzeit zuzüglich der mittleren Verspätung aus allen bisherigen Aufnahmen">Plan realistisch anzeigen</label>
             <div class="header">
             <div class="timing">
             <div class="head time" title="Abfahrt laut Fahrplan">Plan △</div>
+                <div class="head real" title="Abfahrt laut Fahrplan zuzüglich mittlerer Verspätung">Real</div>
                 <div class="head min" title="Früheste Abfahrt, die in 99% der Fälle nicht unterschritten wird">[−</div>
                 <div class="head med" title="Mittlere Abfahrt">○</div>
                 <div class="head max" title="Späteste Abfahrt, die in 99% der Fälle nicht überschritten wird">+]</div>
@@ -558,36 +1138,157 @@ fn generate_stop_page(monitor: &Arc<Monitor>, journey_data: &JourneyData, stop_d
             <div class="head source">Daten</div>
         </div>
         <div class="timeline">"#,
-        stop_name = stop_data.stop_name,
+        stop_name = escape_html(&model.stop_name),
         extended_stops_span = extended_stops_span,
         date = min_time.formatl("%A, %e. %B", "de"),
         min_time = min_time.format("%H:%M"),
         max_time = max_time.format("%H:%M")
     )?;
 
-    //optional first line for arrival by walk:
-    if let Some(JourneyComponent::Walk(walk_data)) = &stop_data.prev_component {
-        write_walk_arrival_output(&mut w, walk_data, stop_data, monitor, min_time, max_time)?;
+    //optional first line for arrival by walk or bike:
+    if let Some(transfer_arrival) = &model.transfer_arrival {
+        write_transfer_arrival_output(&mut w, transfer_arrival, &model.stop_name, &monitor.curve_images, min_time, max_time)?;
     }
 
     //optional first line for arrival by trip:
-    if let Some(mut arrival) = trip_arrival_option {
-        arrival.compute_meta_data(schedule.clone())?;
-        write_departure_output(&mut w, &arrival, &journey_data, &stop_data, min_time, max_time, EventType::Arrival, schedule.clone())?;
+    if let Some(arrival) = &model.arrival {
+        write_departure_output(&mut w, arrival, journey_data, &model.stop_name, min_time, max_time, &schedule, &stats, &monitor.curve_images)?;
     }
 
-    for dep in departures {
-        write_departure_output(&mut w, &dep, &journey_data, &stop_data, min_time, max_time, EventType::Departure, schedule.clone())?;
+    // route and headsign of the high-frequency departures that have already been written as one line
+    let mut written_headway_groups: Vec<(&str, &str)> = Vec::new();
+    for dep in &model.departures {
+        if let Some(entry) = &dep.headway {
+            let group_key = (dep.route_id.as_str(), dep.headsign.as_str());
+            if !written_headway_groups.contains(&group_key) {
+                written_headway_groups.push(group_key);
+                let group: Vec<&DepartureModel> = model.departures.iter()
+                    .filter(|other| other.route_id == dep.route_id && other.headsign == dep.headsign && other.headway.is_some())
+                    .collect();
+                write_headway_output(&mut w, &group, entry, stop_data, min_time, max_time, &stats)?;
+            }
+            continue;
+        }
+
+        write_departure_output(&mut w, dep, journey_data, &model.stop_name, min_time, max_time, &schedule, &stats, &monitor.curve_images)?;
+        write_alternatives_output(&mut w, &dep.alternatives)?;
     }
     generate_timeline(&mut w, min_time, len_time)?;
+    if reload_interval.is_none() {
+        // the rendered files only exist for the default time span
+        write_time_window_navigation(&mut w, journey_data, stop_data, min_time, len_time, max_time)?;
+    }
+    write_bike_destinations(&mut w, stop_data, &schedule, journey_data.display_thresholds.extended_stops_radius)?;
+    if reload_interval.is_none() {
+        write!(&mut w, r#"
+        <script>
+            // Reload the departures whenever the server reports changed predictions.
+            if (window.EventSource && window.fetch) {{
+                var source = new EventSource("/live" + window.location.pathname + window.location.search);
+                source.addEventListener("predictions", function() {{
+                    fetch(window.location.href).then(function(response) {{ return response.text(); }}).then(function(html) {{
+                        var page = new DOMParser().parseFromString(html, "text/html");
+                        document.querySelector(".timeline").replaceWith(page.querySelector(".timeline"));
+                    }});
+                }});
+                source.addEventListener("end", function() {{ source.close(); }});
+            }}
+        </script>"#,
+        )?;
+    }
     write!(&mut w, r#"
         </body>
         </html>"#,
         )?;
-    *response.body_mut() = Body::from(w);
-    response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+    Ok(w)
+}
 
-    Ok(response)
+// form to restrict the departures to some routes, route types or a direction, which is kept in the URL
+fn write_departure_filter_form(mut w: &mut Vec<u8>, journey_data: &JourneyData, stop_data: &StopData) -> FnResult<()> {
+    let filter = &journey_data.departure_filter;
+    write!(&mut w, r#"
+        <details class="departure-filter"{open}>
+            <summary>Abfahrten filtern</summary>
+            <form method="get" action="{url}">
+                <label>Linien <input type="text" name="routes" value="{routes}" placeholder="z.B. 2, 3, N10"></label>
+                <label>Verkehrsmittel <select name="types" multiple size="4" title="Ohne Auswahl werden alle Verkehrsmittel gezeigt">"#,
+        open = if filter.is_empty() { "" } else { " open" },
+        url = escape_html(&stop_data.url),
+        routes = escape_html(&filter.route_names.join(", ")),
+    )?;
+    for route_type in &[RouteType::Tramway, RouteType::Subway, RouteType::Rail, RouteType::Bus, RouteType::Ferry, RouteType::CableCar, RouteType::Gondola, RouteType::Funicular] {
+        let name = get_route_type_name(*route_type).unwrap(); // all of them have names
+        write!(&mut w, r#"
+                    <option value="{name}"{selected}>{label}</option>"#,
+            name = name,
+            selected = if filter.route_types.contains(&name) { " selected" } else { "" },
+            label = route_type_to_str(*route_type),
+        )?;
+    }
+    write!(&mut w, r#"
+                </select></label>
+                <label>Richtung <input type="text" name="direction" value="{direction}" placeholder="Teil des Ziels"></label>{hidden_inputs}
+                <input type="submit" value="Filtern">{reset}
+            </form>
+        </details>"#,
+        direction = escape_html(filter.direction.as_deref().unwrap_or("")),
+        // filtering keeps the time span and the display thresholds of the page
+        hidden_inputs = url::form_urlencoded::parse(journey_data.time_window.to_query().as_bytes())
+            .chain(url::form_urlencoded::parse(journey_data.display_query.as_bytes()))
+            .map(|(name, value)| format!(r#"
+                <input type="hidden" name="{}" value="{}">"#, escape_html(&name), escape_html(&value)))
+            .collect::<String>(),
+        reset = if filter.is_empty() { String::new() } else { format!(r#" <a href="{}">Filter aufheben</a>"#, escape_html(&journey_data.get_unfiltered_link(&stop_data.url))) },
+    )?;
+    Ok(())
+}
+
+// links to the departures before and after the time span of the page, which keep the journey and the filter
+fn write_time_window_navigation(mut w: &mut Vec<u8>, journey_data: &JourneyData, stop_data: &StopData, min_time: DateTime<Local>, len_time: i64, max_time: DateTime<Local>) -> FnResult<()> {
+    let link = |time_window: &TimeWindow| {
+        let query: Vec<String> = [journey_data.departure_filter.to_query(), time_window.to_query(), journey_data.display_query.clone()].iter().filter(|query| !query.is_empty()).cloned().collect();
+        escape_html(&format!("{}{}{}", stop_data.url, if query.is_empty() { "" } else { "?" }, query.join("&")))
+    };
+    write!(&mut w, r#"
+        <div class="time-window-navigation">
+            <a href="{earlier}" rel="nofollow" class="earlier" title="Abfahrten von {earlier_from} bis {min_time}">« Früher</a>{reset}
+            <a href="{later}" rel="nofollow" class="later" title="Abfahrten von {max_time} bis {later_to}">Später »</a>
+        </div>"#,
+        earlier = link(&TimeWindow::earlier(min_time, len_time)),
+        earlier_from = (min_time - Duration::minutes(len_time)).format("%H:%M"),
+        min_time = min_time.format("%H:%M"),
+        reset = if journey_data.time_window.is_empty() {
+            String::new()
+        } else {
+            format!(r#"
+            <a href="{}" rel="nofollow" class="reset" title="Abfahrten zur Ankunftszeit an der Haltestelle">Zur Ankunftszeit</a>"#, link(&TimeWindow::default()))
+        },
+        later = link(&TimeWindow::later(max_time, len_time)),
+        max_time = max_time.format("%H:%M"),
+        later_to = (max_time + Duration::minutes(len_time)).format("%H:%M"),
+    )?;
+    Ok(())
+}
+
+// links to the stops that are too far away for a walk, for people who take their bike along
+fn write_bike_destinations(mut w: &mut Vec<u8>, stop_data: &StopData, schedule: &Gtfs, min_distance: f32) -> FnResult<()> {
+    let destinations = stop_data.get_bike_destinations(schedule, min_distance, MAX_BIKE_DESTINATIONS);
+    if destinations.is_empty() {
+        return Ok(());
+    }
+    write!(&mut w, r#"
+        <div class="bike-destinations">Mit dem Fahrrad weiter nach: "#)?;
+    for (i, (stop_name, distance)) in destinations.iter().enumerate() {
+        write!(&mut w, r#"{separator}<a href="{url}Fahrrad/{stop}/">{stop_name} ({distance:.1}&nbsp;km)</a>"#,
+            separator = if i == 0 { "" } else { ", " },
+            url = escape_html(&stop_data.url),
+            stop = url_element(stop_name),
+            stop_name = escape_html(stop_name),
+            distance = distance / 1000.0,
+        )?;
+    }
+    write!(&mut w, r#"</div>"#)?;
+    Ok(())
 }
 
 fn generate_timeline(mut w: &mut Vec<u8>, min_time: DateTime<Local>, len_time: i64) -> FnResult<()> {
@@ -637,7 +1338,8 @@ fn generate_breadcrumbs(mut w: &mut Vec<u8>, journey_data: &JourneyData) -> FnRe
         bail!("No stop found, but a journey always has to begin at a stop.");
     }
     let mut trip_text : String;
-    let mut walked : bool;
+    // name of the walk or bike ride before the next stop, if the user didn't take a trip
+    let mut leg_name : Option<&str>;
 
     loop{
         if let Some(component) = journey_iter.next() {
@@ -647,95 +1349,71 @@ fn generate_breadcrumbs(mut w: &mut Vec<u8>, journey_data: &JourneyData) -> FnRe
                     if trip_data.route_type == RouteType::Bus || trip_data.route_type == RouteType::Tramway || char::is_numeric(trip_text.chars().next().unwrap()) {
                         trip_text = format!("{} {}", route_type_to_str(trip_data.route_type), trip_text);
                     }
-                    walked = false;
+                    leg_name = None;
                     //write link for previous stop:
-                    write!(&mut w, r#" ➞ <a href="{}">{}</a>"#, trip_data.prev_component.get_url(), stop_text)?;
+                    write!(&mut w, r#" ➞ <a href="{}">{}</a>"#, escape_html(&trip_data.prev_component.get_url()), escape_html(&stop_text))?;
                 },
                 JourneyComponent::Walk(walk_data) => {
                     trip_text = String::from(""); // dummy, never used
-                    walked = true;
+                    leg_name = Some("Fußweg");
                     //write link for previous stop:
-                    write!(&mut w, r#" ➞ <a href="{}">{}</a>"#, walk_data.prev_component.get_url(), stop_text)?;
+                    write!(&mut w, r#" ➞ <a href="{}">{}</a>"#, escape_html(&walk_data.prev_component.get_url()), escape_html(&stop_text))?;
+                },
+                JourneyComponent::Bike(bike_data) => {
+                    trip_text = String::from(""); // dummy, never used
+                    leg_name = Some("Fahrrad");
+                    //write link for previous stop:
+                    write!(&mut w, r#" ➞ <a href="{}">{}</a>"#, escape_html(&bike_data.prev_component.get_url()), escape_html(&stop_text))?;
                 },
                 JourneyComponent::Stop(stop_data) => { // there should not be a stop here!
-                    bail!("Expected trip or walk, found stop: {}", stop_data.stop_name);
+                    bail!("Expected trip, walk or bike ride, found stop: {}", stop_data.stop_name);
                 }
             } 
         }else { // previus stop was the last stop
             //write non-link for last stop:
-            write!(&mut w, r#" ➞ <span>{}</span>"#, stop_text)?;
+            write!(&mut w, r#" ➞ <span>{}</span>"#, escape_html(&stop_text))?;
             break;
         }
         if let Some(JourneyComponent::Stop(stop_data)) = journey_iter.next() {
             stop_text = stop_data.stop_name.clone();
-            if walked {
-                //write non-link for previous walk:
-                write!(&mut w, r#" ➞ <span>Fußweg</span>"#)?;
+            if let Some(leg_name) = leg_name {
+                //write non-link for previous walk or bike ride:
+                write!(&mut w, r#" ➞ <span>{}</span>"#, leg_name)?;
             } else {
                 //write link for previous trip:
-                write!(&mut w, r#" ➞ <a href="{}">{}</a>"#, stop_data.prev_component.as_ref().unwrap().get_url(), trip_text)?;
+                write!(&mut w, r#" ➞ <a href="{}">{}</a>"#, escape_html(&stop_data.prev_component.as_ref().unwrap().get_url()), escape_html(&trip_text))?;
             }
-        } else if !walked {
+        } else if leg_name.is_none() {
             //write non-link for last trip:
-            write!(&mut w, r#" ➞ <span>{}</span>"#, trip_text)?;
+            write!(&mut w, r#" ➞ <span>{}</span>"#, escape_html(&trip_text))?;
             break;
         }
     }
 
     // close the wrapping div:
     write!(&mut w, r#"</div>"#)?;
+
+    write!(&mut w, r#"<a href="?accessible={value}" class="accessible-toggle{active}" title="Nur barrierefreie Haltestellen und Fahrten anzeigen">&#9855; Barrierefrei: {state}</a>"#,
+        value = if journey_data.accessible { 0 } else { 1 },
+        active = if journey_data.accessible { " active" } else { "" },
+        state = if journey_data.accessible { "an" } else { "aus" },
+    )?;
     Ok(())
 }
 
 fn generate_trip_page(monitor: &Arc<Monitor>, journey_data: &JourneyData, trip_data: &TripData) -> FnResult<Response<Body>> {
+    let model = TripPageModel::new(monitor, journey_data, trip_data)?;
     let schedule = monitor.main.get_schedule()?;
+    let stats = monitor.get_stats();
+    let trip = trip_data.get_trip(&schedule)?;
+    let (min_time, len_time, max_time) = (model.min_time, model.len_time, model.max_time);
 
     let mut response = Response::new(Body::empty());
-    let trip = schedule.get_trip(&trip_data.vehicle_id.trip_id)?;
-    let route = schedule.get_route(&trip.route_id)?;
-    
-    let start_sequence = trip.stop_times[trip_data.boarding_stop_index.unwrap()].stop_sequence;
-    //let start_id = &trip.stop_times[trip_data.start_index.unwrap()].stop.id;
-
-    // departure from first stop: this is where the user changes into this trip
-    let mut departure = get_prediction_for_first_line(monitor.clone(), start_sequence, &trip_data.vehicle_id, EventType::Departure)?;
-
-    let mut arrivals = get_predictions_for_trip(
-        monitor,
-        monitor.source.clone(), 
-        EventType::Arrival,
-        &trip_data.vehicle_id,
-        start_sequence + 1)?;
-
-    if arrivals.is_empty() {
-        return generate_error_page(StatusCode::INTERNAL_SERVER_ERROR, "No predictions for this trip");
-    }
-
-    for arr in &mut arrivals {
-        if let Err(e) = arr.compute_meta_data(schedule.clone()){
-            eprintln!("Could not compute metadata for arrival with trip_id {}: {}", arr.trip_id , e);
-        }
-    }
-
-    departure.compute_meta_data(schedule.clone())?;
-    let exact_min_time = departure.get_absolute_time_for_probability(0.01).unwrap();
-
-    let exact_max_time = if let Some(time) = arrivals.iter().filter_map(|arr| arr.get_absolute_time_for_probability(0.99).ok()).max() {
-        time
-    } else {
-        arrivals.iter().map(|arr| arr.meta_data.as_ref().expect("No metadata").scheduled_time_absolute).max().or_error("No maximum")?
-    };
-
-    let min_time = exact_min_time - Duration::minutes(exact_min_time.time().minute() as i64 % 5); // round to previous nice time
-    let len_time: i64 = ((exact_max_time.signed_duration_since(min_time).num_minutes() as i64 + 6) / 5) * 5;
-    let max_time = min_time + Duration::minutes(len_time);
-    
-
     let mut w = Vec::new();
     write!(&mut w, r#"
         <html>
         <head>
-            <title>{route_type} Linie {route_name} | Dystonse ÖPNV-Reiseplaner</title>
+            <title>{title}</title>
             <link rel="stylesheet" href="/style.css">
 
             {favicon_headers}
@@ -744,18 +1422,19 @@ fn generate_trip_page(monitor: &Arc<Monitor>, journey_data: &JourneyData, trip_d
         </head>
         <body class="monitorbody">
         <a href="/help/" class="help-link">Hilfe</a>"#,
-        route_type = route_type_to_str(route.route_type),
-        route_name = route.short_name,
-        favicon_headers = FAVICON_HEADERS
+        title = monitor.theme.page_title(&format!("{} Linie {}", model.route_type, model.route_name)),
+        favicon_headers = monitor.theme.head_elements(),
         )?;
 
     generate_breadcrumbs(&mut w, journey_data)?;
     
     write!(&mut w, r#"
         <h1>Halte für {route_type} Linie {route_name} nach {headsign}</h1>
+            <input type="checkbox" id="realistic-toggle" class="realistic-toggle"><label for="realistic-toggle" class="realistic-toggle" title="Fahrplanzeit zuzüglich der mittleren Verspätung aus allen bisherigen Aufnahmen">Plan realistisch anzeigen</label>
             <div class="header">
             <div class="timing">
                 <div class="head time" title="Abfahrt laut Fahrplan">Plan △</div>
+                <div class="head real" title="Abfahrt laut Fahrplan zuzüglich mittlerer Verspätung">Real</div>
                 <div class="head min" title="Früheste Abfahrt, die in 99% der Fälle nicht unterschritten wird">[−</div>
                 <div class="head med" title="Mittlere Abfahrt">○</div>
                 <div class="head max" title="Späteste Abfahrt, die in 99% der Fälle nicht überschritten wird">+]</div>
@@ -765,28 +1444,38 @@ fn generate_trip_page(monitor: &Arc<Monitor>, journey_data: &JourneyData, trip_d
             <div class="head source">Daten</div>
         </div>
         <div class="timeline">"#,
-        route_type = route_type_to_str(route.route_type),
-        route_name = route.short_name,
-        headsign = trip.trip_headsign.as_ref().unwrap(),
+        route_type = model.route_type,
+        route_name = escape_html(&model.route_name),
+        headsign = escape_html(&model.headsign),
     )?;
-    for stop_time in &trip.stop_times {
-        // don't display stops that are before the stop where we change into this trip
-        if trip.get_stop_index_by_stop_sequence(stop_time.stop_sequence)? == trip_data.boarding_stop_index.unwrap() {
-            write_stop_time_output(&mut w, &stop_time, Some(&departure), min_time, max_time, EventType::Departure, Some(trip_data.start_prob))?;
-
-        } else if trip.get_stop_index_by_stop_sequence(stop_time.stop_sequence)? > trip_data.boarding_stop_index.unwrap() {
-            //arrivals at later stops:
-            let arrival = arrivals.iter().filter(|a| a.stop_sequence == stop_time.stop_sequence as usize).next();
-            write_stop_time_output(&mut w, &stop_time, arrival, min_time, max_time, EventType::Arrival, None)?;
-        }
-        
+
+    if journey_data.accessible && !model.accessible {
+        write!(&mut w, r#"
+        <p class="accessibility-warning">&#9855; Laut Fahrplan ist diese Fahrt nicht barrierefrei.</p>"#)?;
+    }
+
+    // the journey's arrival at the last stop of this trip, if the user stays on board until the end
+    if let Some(journey_arrival) = &model.journey_arrival {
+        write_journey_output(&mut w, journey_arrival, min_time, max_time, &monitor.curve_images)?;
+    }
+
+    for stop in &model.stops {
+        write_stop_time_output(&mut w, stop, journey_data, min_time, max_time, &stats, &schedule, trip, &monitor.curve_images)?;
     }
 
     generate_timeline(&mut w, min_time, len_time)?;
 
+    // the page is still useful without the observations, so it is shown anyway
+    match observation_history::get_observations(monitor, &trip_data.vehicle_id) {
+        Ok(observations) => observation_history::write_observation_history(&mut w, &observations, trip)?,
+        Err(e) => warn!("Could not look up the records of trip {}: {}", trip_data.vehicle_id.trip_id, e),
+    }
+
     write!(&mut w, r#"
+        <p class="calendar-export"><a href="/ics{url}">Reise als Kalendereintrag speichern</a></p>
         </body>
         </html>"#,
+        url = escape_html(&trip_data.url),
         )?;
     *response.body_mut() = Body::from(w);
     response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
@@ -794,27 +1483,55 @@ fn generate_trip_page(monitor: &Arc<Monitor>, journey_data: &JourneyData, trip_d
     Ok(response)
 }
 
-fn write_walk_arrival_output(
+/// Shows when the whole journey arrives at the given stop, as a wide strip of the arrival time density.
+/// The arrival curve combines all legs of the journey (see `JourneyData::get_journey_arrival_curve`) and
+/// is weighted with the probability to catch all trips, so that the strip fades out for journeys that
+/// will probably fail.
+fn write_journey_output(
+    mut w: &mut Vec<u8>,
+    arrival: &JourneyArrivalModel,
+    min_time: DateTime<Local>,
+    max_time: DateTime<Local>,
+    images: &CurveImageCache,
+    ) -> FnResult<()> {
+
+    let image_url = generate_journey_png_url(images, &arrival.curve, arrival.probability / 100.0, min_time, max_time, JOURNEY_STRIP_WIDTH)?;
+
+    write!(&mut w, r#"
+        <div class="journey-summary">
+            <p>Ankunft der Reise an {stop_name}: vermutlich um {med}, frühestens {min}, spätestens {max}. Chance, alle Anschlüsse zu erreichen: {prob:.0}&nbsp;%</p>
+            <div class="visu" style="background-image:url('{image_url}')"></div>
+        </div>"#,
+        stop_name = escape_html(&arrival.stop_name),
+        min = arrival.earliest_time.format("%H:%M"),
+        med = arrival.median_time.format("%H:%M"),
+        max = arrival.latest_time.format("%H:%M"),
+        prob = arrival.probability,
+        image_url = image_url,
+    )?;
+    Ok(())
+}
+
+// first line of a stop page if the user arrives by walk or bike
+fn write_transfer_arrival_output(
     mut w: &mut Vec<u8>, 
-    walk_data: &WalkData,
-    stop_data: &StopData,
-    _monitor: &Arc<Monitor>,
+    transfer_arrival: &TransferArrivalModel,
+    stop_name: &str,
+    images: &CurveImageCache,
     min_time: DateTime<Local>,
     max_time: DateTime<Local>,
     ) -> FnResult<()> {
 
-    let a_01 = stop_data.start_curve.typed_x_at_y(0.01);
-    let a_50 = stop_data.start_curve.typed_x_at_y(0.50);
-    let a_99 = stop_data.start_curve.typed_x_at_y(0.99);
-    let stop_name = &stop_data.stop_name;
-    let distance = if let JourneyComponent::Stop(prev_stop) = &walk_data.prev_component {
-        prev_stop.get_max_distance(&stop_data)
-    } else {
-        bail!("Walk has no prev_stop");
+    let a_01 = transfer_arrival.earliest_time;
+    let a_50 = transfer_arrival.median_time;
+    let a_99 = transfer_arrival.latest_time;
+    let leg_name = match transfer_arrival.mode {
+        TransferMode::Walk => "Fußweg",
+        TransferMode::Bike => "mit dem Fahrrad",
     };
     
-    let image_url = generate_png_data_url(&stop_data.start_curve, min_time, max_time, 120, EventType::Arrival)?;
-    let prob = stop_data.start_prob * 100.0;
+    let image_url = generate_png_url(images, &transfer_arrival.curve, min_time, max_time, 120, EventType::Arrival)?;
+    let prob = transfer_arrival.probability;
 
     write!(&mut w, r#"
         <div class="outer">    
@@ -826,7 +1543,7 @@ fn write_walk_arrival_output(
                     <div class="area max" title="Spätestmögliche Ankunft">{max}</div>
                 </div>
                 <!--div class="area type"><span class="bubble w">Fuß</span></div-->
-                <div class="area distance">{distance:.0} m Fußweg</div>
+                <div class="area distance">{distance:.0} m {leg_name}</div>
                 <div class="area headsign">Ankunft an {stop_name}</div>
                 <div class="area prob {probclass}">{prob:.0} %</div>
                 <div class="area source"></div>
@@ -837,8 +1554,9 @@ fn write_walk_arrival_output(
         min = format_delay((a_01 - a_50).num_minutes() as i32),
         med = format_delay((a_50 - a_50).num_minutes() as i32),
         max = format_delay((a_99 - a_50).num_minutes() as i32),
-        distance = distance,
-        stop_name = stop_name,
+        distance = transfer_arrival.distance,
+        leg_name = leg_name,
+        stop_name = escape_html(stop_name),
         image_url = image_url,
         probclass = if prob >= 99.5 { "hundred" } else { "" },
         prob = prob,
@@ -848,121 +1566,79 @@ fn write_walk_arrival_output(
 
 fn write_departure_output(
     mut w: &mut Vec<u8>, 
-    dep: &DbPrediction, 
-    _journey_data: &JourneyData,
-    stop_data: &StopData,
+    dep: &DepartureModel, 
+    journey_data: &JourneyData,
+    stop_name: &str,
     min_time: DateTime<Local>,
     max_time: DateTime<Local>,
-    event_type: EventType,
-    schedule: Arc<Gtfs>
+    schedule: &Gtfs,
+    stats: &DelayStatistics,
+    images: &CurveImageCache,
     ) -> FnResult<()> {
-    let md = dep.meta_data.as_ref().unwrap();
-    let a_scheduled = dep.meta_data.as_ref().unwrap().scheduled_time_absolute;
-    let a_01 = dep.get_absolute_time_for_probability(0.01).unwrap();
-    let a_50 = dep.get_absolute_time_for_probability(0.50).unwrap();
-    let a_99 = dep.get_absolute_time_for_probability(0.99).unwrap();
-    let r_01 = dep.get_relative_time_for_probability(0.01) / 60;
-    let r_50 = dep.get_relative_time_for_probability(0.50) / 60;
-    let r_99 = dep.get_relative_time_for_probability(0.99) / 60;
-
-    // prepare walk time. Even for a distance of 0 there is some walk time involved.
-    let walk_distance = *stop_data.extended_stops_distances.get(&dep.stop_id).unwrap_or(&0.0);
-    let walk_time = get_walk_time(walk_distance);
-
-    // compute local probability of getting the transfer (not accumulated for the whole journey, just for here)
-    let local_prob = match event_type {
-        EventType::Arrival => 100.0, // arrival is always 100%
-        EventType::Departure => stop_data.start_curve
-            .add_duration_curve(&walk_time)
-            .get_transfer_probability(&dep.get_time_curve()) * 100.0
-    };
-
-    // don't display anything below 5% local chance:
-    if local_prob < 5.0 {
-        println!("write departure output for stop page: Skipping departure with less than 5% chance.");
+    let prediction = &dep.prediction;
+    let event_type = prediction.event_type;
+    let md = prediction.meta_data.as_ref().unwrap();
+    let a_scheduled = dep.scheduled_time;
+    let a_01 = dep.earliest_time;
+    let a_50 = dep.median_time;
+    let a_99 = dep.latest_time;
+    let r_01 = prediction.get_relative_time_for_probability(0.01) / 60;
+    let r_50 = prediction.get_relative_time_for_probability(0.50) / 60;
+    let r_99 = prediction.get_relative_time_for_probability(0.99) / 60;
+
+    // don't display anything below the minimum local chance (5% by default):
+    if dep.local_probability < journey_data.display_thresholds.get_min_chance() {
+        debug!("write departure output for stop page: Skipping departure with less than {}% chance.", journey_data.display_thresholds.get_min_chance());
         return Ok(());
     }
 
-    // compute actual probability of getting the transfer (for later use in the output)
-    let prob = stop_data.start_prob * local_prob;
-
-    //let trip_link =  format!("{}/", dep.trip_id);
-    let _trip_start_date_time = dep.trip_start_date.and_hms(0, 0, 0) + dep.trip_start_time;
+    let prob = dep.probability;
 
-    // let source_link = format!("/info/{}/{}/{}/{}", dep.route_id, dep.trip_id, dep.trip_start_date, dep.trip_start_time.num_seconds());
-
-    let (type_letter, type_class) = match md.route_type {
-        RouteType::Bus     => ("Bus", "b"),
-        RouteType::Rail    => {
-            // RB RE S RS IC DPN MEX
-            if md.route_name.starts_with("RB") {
-                ("RB"  , "r")
-            } else if md.route_name.starts_with("RE") {
-                ("RE"  , "r")
-            } else if md.route_name.starts_with("S") {
-                ("S"  , "s")
-            } else if md.route_name.starts_with("RS") {
-                ("RS"  , "s")
-            } else if md.route_name.starts_with("IC") {
-                ("IC"  , "r")
-            } else {
-                ("Bahn"  , "z")
-            }
-        },
-        RouteType::Subway    => ("U"   , "u"),
-        RouteType::Tramway   => ("Tram", "m"),
-        RouteType::Ferry     => ("F"   , "f"),
-        RouteType::CableCar  => ("Seil", "c"),
-        RouteType::Gondola   => ("Seil", "c"),
-        RouteType::Funicular => ("Seil", "c"),
-        RouteType::Coach     => ("Bus" , "b"),
-        RouteType::Air       => ("Flug", "a"),
-        RouteType::Taxi      => ("Taxi", "t"),
-        _                    => ("?"   , "d"),
-    };
-
-    let mut stop_url = stop_data.url.clone();
+    let (type_letter, type_class) = get_type_bubble(md.route_type, &dep.route_name);
 
     // prepare info for departure from extended stops list
     let mut extended_stop_info : String = String::from("");
-    if let Some(d) = stop_data.extended_stops_distances.get(&dep.stop_id) {
+    if let Some(d) = dep.walk_distance {
+        let walk_time = journey_data.walk_profile.get_walk_time(d);
         let alternative_stop_name = schedule.get_stop(&dep.stop_id)?.name.clone();
         extended_stop_info = format!(
             r#"<div class="area walk" title="{min_walk_time} bis {max_walk_time} Fußweg bis {alternative_stop_name}"><span>{d:.0} m</span></div>"#,
-            alternative_stop_name = alternative_stop_name,
+            alternative_stop_name = escape_html(&alternative_stop_name),
             d = d, 
             min_walk_time = format_duration(Duration::seconds(walk_time.min_x() as i64)),
             max_walk_time = format_duration(Duration::seconds(walk_time.max_x() as i64))
         );
-        stop_url = format!(
-            "{original_url}Fußweg/{alternative_stop_name}/", 
-            original_url = stop_data.url,
-            alternative_stop_name = utf8_percent_encode(&alternative_stop_name, PATH_ELEMENT_ESCAPE).to_string(),
-        );
     }
     
-    // trip link
-    let trip_link = match event_type {
-        EventType::Arrival => String::from("<div"),
-        EventType::Departure => format!(r#"<a href="{stop_url}{r_type} {route} nach {headsign} um {time}/""#, 
-            stop_url = stop_url,
-            r_type = route_type_to_str(md.route_type), 
-            route = md.route_name, 
-            headsign = utf8_percent_encode(&md.headsign, PATH_ELEMENT_ESCAPE).to_string(),
-            time = md.scheduled_time_absolute.format("%H:%M")
-        )
+    // trip link, only for departures
+    let trip_link = match &dep.trip_url {
+        Some(url) => format!(r#"<a href="{url}""#, url = escape_html(url)),
+        None => String::from("<div"),
     };
-    let trip_link_type = match event_type {
-        EventType::Arrival => "div",
-        EventType::Departure => "a"
+    let trip_link_type = match &dep.trip_url {
+        Some(_) => "a",
+        None => "div",
     };
 
 
-    let image_url = generate_png_data_url(&dep.get_time_curve(), min_time, max_time, 120, event_type)?;
+    let image_url = generate_png_url(images, &prediction.get_time_curve(), min_time, max_time, 120, event_type)?;
+
+    let trip = schedule.get_trip(&dep.trip_id)?;
+    let realistic_area = get_realistic_area(stats, schedule, &trip, prediction.stop_sequence as u16, event_type, a_scheduled);
+
+    // in accessible mode, only accessible trips are shown, but some of them have no information about it
+    let wheelchair_flag = if journey_data.accessible && event_type == EventType::Departure {
+        match trip.wheelchair_accessible {
+            Availability::Available => r#" <span class="wheelchair" title="Barrierefrei">&#9855;</span>"#,
+            _ => r#" <span class="wheelchair unknown" title="Keine Angabe zur Barrierefreiheit">&#9855;?</span>"#,
+        }
+    } else {
+        ""
+    };
 
     let headsign = match event_type {
-        EventType::Arrival => format!("Ankunft an {}", stop_data.stop_name),
-        EventType::Departure => md.headsign.clone()
+        EventType::Arrival => format!("Ankunft an {}", escape_html(stop_name)),
+        EventType::Departure => escape_html(&dep.headsign)
     };
 
     write!(&mut w, r#"
@@ -970,12 +1646,13 @@ fn write_departure_output(
             <div class="line">
                 <div class="timing">
                     <div class="area time">{time}</div>
+                    {realistic_area}
                     <div class="area min" title="Frühestens {min_tooltip}">{min}</div>
                     <div class="area med" title="Vermutlich {med_tooltip}">{med}</div>
                     <div class="area max" title="Spätstens {max_tooltip}">{max}</div>
                 </div>
                 <div class="area type"><span class="bubble {type_class}">{type_letter}</span></div>
-                <div class="area route">{route_name}</div>
+                <div class="area route">{route_name}{wheelchair_flag}</div>
                 <div class="area headsign">{headsign}</div>
                 {extended_stop_info}
                 <div class="area prob {probclass}">{prob:.0} %</div>
@@ -984,7 +1661,8 @@ fn write_departure_output(
             <div class="visu" style="background-image:url('{image_url}')"></div>         
         "#,
         trip_link = trip_link,
-        time = md.scheduled_time_absolute.format("%H:%M"),
+        time = a_scheduled.format("%H:%M"),
+        realistic_area = realistic_area,
         min = format_delay(r_01),
         min_tooltip = a_01.format("%H:%M:%S"),
         med = format_delay(r_50),
@@ -993,12 +1671,13 @@ fn write_departure_output(
         max_tooltip = a_99.format("%H:%M:%S"),
         type_letter = type_letter,
         type_class = type_class,
-        route_name = md.route_name,
+        route_name = escape_html(&dep.route_name),
+        wheelchair_flag = wheelchair_flag,
         headsign = headsign,
         extended_stop_info = extended_stop_info,
         image_url = image_url,
         prob = prob,
-        source_area = get_source_area(Some(dep)),
+        source_area = get_source_area(Some(prediction), stats),
         probclass = if prob >= 99.5 { "hundred" } else { "" },
     )?;
 
@@ -1014,6 +1693,123 @@ fn write_departure_output(
     Ok(())
 }
 
+// letter and CSS class of the bubble that shows the route type
+fn get_type_bubble(route_type: RouteType, route_name: &str) -> (&'static str, &'static str) {
+    match route_type {
+        RouteType::Bus     => ("Bus", "b"),
+        RouteType::Rail    => {
+            // RB RE S RS IC DPN MEX
+            if route_name.starts_with("RB") {
+                ("RB"  , "r")
+            } else if route_name.starts_with("RE") {
+                ("RE"  , "r")
+            } else if route_name.starts_with("S") {
+                ("S"  , "s")
+            } else if route_name.starts_with("RS") {
+                ("RS"  , "s")
+            } else if route_name.starts_with("IC") {
+                ("IC"  , "r")
+            } else {
+                ("Bahn"  , "z")
+            }
+        },
+        RouteType::Subway    => ("U"   , "u"),
+        RouteType::Tramway   => ("Tram", "m"),
+        RouteType::Ferry     => ("F"   , "f"),
+        RouteType::CableCar  => ("Seil", "c"),
+        RouteType::Gondola   => ("Seil", "c"),
+        RouteType::Funicular => ("Seil", "c"),
+        RouteType::Coach     => ("Bus" , "b"),
+        RouteType::Air       => ("Flug", "a"),
+        RouteType::Taxi      => ("Taxi", "t"),
+        _                    => ("?"   , "d"),
+    }
+}
+
+/// Writes one line for all departures of a high-frequency route to the same headsign. Instead of
+/// their single delays, it shows how often the vehicles come and when the next one can be caught.
+fn write_headway_output(
+    mut w: &mut Vec<u8>,
+    group: &[&DepartureModel],
+    entry: &HeadwayEntry,
+    stop_data: &StopData,
+    min_time: DateTime<Local>,
+    max_time: DateTime<Local>,
+    stats: &DelayStatistics,
+    ) -> FnResult<()> {
+    // the first vehicle that probably departs after the user has arrived at the stop
+    let arrival = stop_data.start_curve.typed_x_at_y(0.50);
+    let next = *group.iter()
+        .find(|dep| dep.median_time >= arrival)
+        .or_else(|| group.last())
+        .or_error("No departures for headway output")?;
+    let md = next.prediction.meta_data.as_ref().unwrap();
+    let wait_min = i64::max((next.earliest_time - arrival).num_minutes(), 0);
+    let wait_max = i64::max((next.latest_time - arrival).num_minutes(), wait_min);
+
+    let prob = next.probability;
+    let (type_letter, type_class) = get_type_bubble(md.route_type, &next.route_name);
+
+    write!(&mut w, r#"
+        <a href="{url}" class="outer">
+            <div class="line headway">
+                <div class="timing">
+                    <div class="area headway-interval" title="Laut Fahrplan alle {scheduled} min, in 80&nbsp;% der Fälle zwischen {p10} und {p90} min">alle ~{median} min</div>
+                    <div class="area headway-next" title="Nächste Abfahrt vermutlich um {next_time}">nächste in {wait_min}–{wait_max} min</div>
+                </div>
+                <div class="area type"><span class="bubble {type_class}">{type_letter}</span></div>
+                <div class="area route">{route_name}</div>
+                <div class="area headsign">{headsign}</div>
+                <div class="area prob {probclass}">{prob:.0} %</div>
+                {source_area}
+            </div>
+            <div class="visu"></div>
+        "#,
+        url = escape_html(next.trip_url.as_ref().or_error("Departure has no trip URL")?),
+        scheduled = (entry.scheduled_headway + 30) / 60,
+        p10 = entry.observed_p10 / 60,
+        p90 = (entry.observed_p90 + 59) / 60,
+        median = u32::max((entry.observed_median + 30) / 60, 1),
+        next_time = next.median_time.format("%H:%M"),
+        wait_min = wait_min,
+        wait_max = wait_max,
+        type_letter = type_letter,
+        type_class = type_class,
+        route_name = escape_html(&next.route_name),
+        headsign = escape_html(&next.headsign),
+        prob = prob,
+        probclass = if prob >= 99.5 { "hundred" } else { "" },
+        source_area = get_source_area(Some(&next.prediction), stats),
+    )?;
+
+    for dep in group {
+        write_marker(w, dep.median_time, min_time, max_time, "median")?;
+    }
+    write!(&mut w, "</a>")?;
+    Ok(())
+}
+
+fn write_alternatives_output(mut w: &mut Vec<u8>, alternatives: &[DepartureModel]) -> FnResult<()> {
+    if alternatives.is_empty() {
+        return Ok(());
+    }
+    write!(&mut w, r#"
+        <div class="alternatives">Falls es nicht klappt: "#)?;
+    for (i, alternative) in alternatives.iter().enumerate() {
+        write!(&mut w, r#"{separator}<a href="{url}">{route_type} {route_name} nach {headsign} um {time} ({prob:.0}&nbsp;%)</a>"#,
+            separator = if i > 0 { ", " } else { "" },
+            url = escape_html(alternative.trip_url.as_ref().or_error("Alternative has no trip URL")?),
+            route_type = alternative.route_type,
+            route_name = escape_html(&alternative.route_name),
+            headsign = escape_html(&alternative.headsign),
+            time = alternative.scheduled_time.format("%H:%M"),
+            prob = alternative.probability,
+        )?;
+    }
+    write!(&mut w, "</div>")?;
+    Ok(())
+}
+
 fn write_marker(
     mut w: &mut Vec<u8>, 
     time: DateTime<Local>,
@@ -1031,11 +1827,12 @@ fn write_marker(
     Ok(())
 }
 
-fn get_source_area(db_prediction: Option<&DbPrediction>) -> String {
+fn get_source_area(db_prediction: Option<&DbPrediction>, stats: &DelayStatistics) -> String {
     if let Some(db_prediction) = db_prediction {
         let (origin_letter, origin_description) = match (&db_prediction.origin_type, &db_prediction.precision_type) {
             (OriginType::Realtime, PrecisionType::Specific) => ("E","Aktuelle Echtzeitdaten"),
             (OriginType::Realtime, PrecisionType::FallbackSpecific) => ("E","Aktuelle Echtzeitdaten"),
+            (OriginType::Realtime, PrecisionType::BlockPropagated) => ("V","Echtzeitdaten der vorherigen Fahrt des Fahrzeugs"),
             (OriginType::Realtime, _) => ("U","Ungenutzte Echtzeitdaten"),
             (OriginType::Schedule, _) => ("P","Fahrplandaten"),
             (OriginType::Unknown, _)  => ("?","Unbekannte Datenquelle")
@@ -1048,6 +1845,7 @@ fn get_source_area(db_prediction: Option<&DbPrediction>) -> String {
             PrecisionType::General            => ("G+", "Generelle Prognose für Fahrzeugart, Tageszeit und Routenabschnitt"),
             PrecisionType::FallbackGeneral    => ("G" , "Generelle Prognose für Fahrzeugart"),
             PrecisionType::SuperGeneral       => ("G-", "Standardprognose, sehr ungenau"),
+            PrecisionType::BlockPropagated    => ("B" , "Prognose aus der erwarteten Verspätung am Ende der vorherigen Fahrt"),
             PrecisionType::Unknown            => ("?" , "Unbekanntes Prognoseverfahren"),
         };
 
@@ -1057,17 +1855,21 @@ fn get_source_area(db_prediction: Option<&DbPrediction>) -> String {
             (_,"S+") => "b",
             (_,"S") => "b",
             (_,"S-") => "b",
+            (_,"B") => "b",
             (_,"G+") => "c",
             (_,"G") => "d",
             (_,"G-") => "d",
             (_,_) => "e",
         };
 
+        let (calibration_badge, calibration_description) = get_calibration_badge(db_prediction, stats);
+
         return format!(
-            r#"<div class="area source" title="{source_long}"><span class="bubble {source_class}">{source_short}</span></div>"#,
-            source_long = format!("{} und {}, basierend auf {} vorherigen Aufnahmen.", origin_description, precision_description, db_prediction.sample_size),
+            r#"<div class="area source" title="{source_long}"><span class="bubble {source_class}">{source_short}</span>{calibration_badge}</div>"#,
+            source_long = format!("{} und {}, basierend auf {} vorherigen Aufnahmen.{}", origin_description, precision_description, db_prediction.sample_size, calibration_description),
             source_short = format!("{}/{}", origin_letter, precision_letter),
             source_class = source_class,
+            calibration_badge = calibration_badge,
         );
     } else {
         return format!(
@@ -1079,54 +1881,76 @@ fn get_source_area(db_prediction: Option<&DbPrediction>) -> String {
     }
 }
 
+// stars that show how well this kind of prediction has been calibrated for the route so far, and a sentence for the tooltip
+fn get_calibration_badge(db_prediction: &DbPrediction, stats: &DelayStatistics) -> (String, String) {
+    let calibration = match stats.get_calibration(&db_prediction.route_id, &db_prediction.precision_type) {
+        Some(calibration) => calibration,
+        None => return (String::new(), String::new()),
+    };
+    let stars = match calibration.stars() {
+        Some(stars) => stars,
+        None => return (String::new(), String::new()),
+    };
+    let rating = match stars {
+        3 => "zuverlässig",
+        2 => "einigermaßen zuverlässig",
+        _ => "wenig zuverlässig",
+    };
+    (
+        format!(r#"<span class="calibration stars-{}">{}</span>"#, stars, "★".repeat(stars as usize)),
+        format!(" Diese Art von Prognose war für diese Linie bisher {} ({:.0}&nbsp;% Abweichung bei {} überprüften Prognosen).",
+            rating, calibration.calibration_error() * 100.0, calibration.count()),
+    )
+}
+
 fn write_stop_time_output(
     mut w: &mut Vec<u8>, 
-    stop_time: &StopTime, 
-    prediction: Option<&DbPrediction>, 
+    stop: &TripStopModel,
+    journey_data: &JourneyData,
     min_time: DateTime<Local>, 
     max_time: DateTime<Local>, 
-    event_type: EventType,
-    prob: Option<f32>
+    stats: &DelayStatistics,
+    schedule: &Gtfs,
+    trip: &Trip,
+    images: &CurveImageCache,
     ) -> FnResult<()> {
     
+    let event_type = stop.event_type;
     let stop_link = match event_type {
-        EventType::Arrival => format!(r#"<a href="{}/""#, stop_time.stop.name),
-        EventType::Departure => String::from("<div") //no link for first line
+        EventType::Arrival if stop.can_alight => format!(r#"<a href="{}""#, escape_html(&journey_data.get_link(&format!("{}/", url_element(&stop.stop_name))))),
+        _ => String::from("<div") //no link for first line
     };
     let stop_link_type = match event_type {
-        EventType::Arrival => "a",
-        EventType::Departure => "div"
-    };
-
-    let scheduled_time = match event_type {
-        EventType::Arrival   => date_and_time_local(&prediction.unwrap().trip_start_date, stop_time.arrival_time  .unwrap() as i32),
-        EventType::Departure => date_and_time_local(&prediction.unwrap().trip_start_date, stop_time.departure_time.unwrap() as i32)
+        EventType::Arrival if stop.can_alight => "a",
+        _ => "div"
     };
-
-    let (r_01, r_50,r_99) = if let Some(prediction) = prediction {
-        (
-            prediction.get_relative_time_for_probability(0.01),
-            prediction.get_relative_time_for_probability(0.50),
-            prediction.get_relative_time_for_probability(0.99),
-        )
+    let stopname = if stop.can_alight {
+        escape_html(&stop.stop_name)
     } else {
-        (0,0,0)
+        format!(r#"{} <span class="wheelchair unavailable" title="Nicht barrierefrei">&#9855;&#10007;</span>"#, escape_html(&stop.stop_name))
     };
-    let a_01 = scheduled_time + Duration::seconds(r_01 as i64);
-    let a_50 = scheduled_time + Duration::seconds(r_50 as i64);
-    let a_99 = scheduled_time + Duration::seconds(r_99 as i64);
 
-    let image_url = if let Some(prediction) = prediction {
-        generate_png_data_url(&prediction.get_time_curve(), min_time, max_time, 120, event_type)?
+    let scheduled_time = stop.scheduled_time;
+    let a_01 = stop.earliest_time.unwrap_or(scheduled_time);
+    let a_50 = stop.median_time.unwrap_or(scheduled_time);
+    let a_99 = stop.latest_time.unwrap_or(scheduled_time);
+    let r_01 = (a_01 - scheduled_time).num_seconds();
+    let r_50 = (a_50 - scheduled_time).num_seconds();
+    let r_99 = (a_99 - scheduled_time).num_seconds();
+
+    let image_url = if let Some(prediction) = &stop.prediction {
+        generate_png_url(images, &prediction.get_time_curve(), min_time, max_time, 120, event_type)?
     } else {
         String::new()
     };
 
-    let prob_area = if let Some(actual_prob) = prob {
+    let realistic_area = get_realistic_area(stats, schedule, trip, stop.stop_sequence, event_type, scheduled_time);
+
+    let prob_area = if let Some(prob) = stop.probability {
         format!(
             r#"<div class="area prob {probclass}">{prob:.0} %</div>"#, 
-            probclass = if actual_prob >= 0.995 { "hundred" } else { "" },
-            prob = actual_prob * 100.0)
+            probclass = if prob >= 99.5 { "hundred" } else { "" },
+            prob = prob)
     } else {
         String::new()
     };
@@ -1136,6 +1960,7 @@ fn write_stop_time_output(
             <div class="line">
                 <div class="timing">
                     <div class="area time">{time}</div>
+                    {realistic_area}
                     <div class="area min" title="Frühestens {min_tooltip}">{min}</div>
                     <div class="area med" title="Vermutlich {med_tooltip}">{med}</div>
                     <div class="area max" title="Spätstens {max_tooltip}">{max}</div>
@@ -1147,14 +1972,15 @@ fn write_stop_time_output(
             <div class="visu" style="background-image:url('{image_url}')"></div>"#,
         stop_link = stop_link,
         time = scheduled_time.format("%H:%M"),
+        realistic_area = realistic_area,
         min = format_delay(r_01 as i32 / 60),
         min_tooltip = a_01.format("%H:%M:%S"),
         med = format_delay(r_50 as i32 / 60),
         med_tooltip = a_50.format("%H:%M:%S"),
         max = format_delay(r_99 as i32 / 60),
         max_tooltip = a_99.format("%H:%M:%S"),
-        stopname = stop_time.stop.name,
-        source_area = get_source_area(prediction),
+        stopname = stopname,
+        source_area = get_source_area(stop.prediction.as_ref(), stats),
         prob_area = prob_area,
         image_url = image_url,
     )?;
@@ -1171,6 +1997,34 @@ fn write_stop_time_output(
     Ok(())
 }
 
+// creates the (initially hidden) column for the "realistic" schedule, which is the
+// scheduled time plus the median historic delay
+fn get_realistic_area(
+    stats: &DelayStatistics, 
+    schedule: &Gtfs, 
+    trip: &Trip, 
+    stop_sequence: u16, 
+    event_type: EventType, 
+    scheduled_time: DateTime<Local>
+    ) -> String {
+    let median_delay = match trip.get_stop_index_by_stop_sequence(stop_sequence) {
+        Ok(stop_index) => stats.get_median_delay(schedule, trip, stop_index, event_type).ok(),
+        Err(_) => None,
+    };
+
+    match median_delay {
+        Some(delay) => {
+            let realistic_time = scheduled_time + Duration::seconds(delay.round() as i64);
+            format!(
+                r#"<div class="area real" title="Mittlere Verspätung {delay}">{time}</div>"#,
+                delay = format_delay(delay.round() as i32 / 60),
+                time = realistic_time.format("%H:%M"),
+            )
+        },
+        None => String::from(r#"<div class="area real" title="Keine Statistik verfügbar">–</div>"#),
+    }
+}
+
 fn format_delay(delay: i32) -> String {
     if delay > 0 {
         format!("+{}", delay)
@@ -1207,49 +2061,81 @@ pub fn get_transfer_probability(
         let transfer_missed_prob = departure_dist.y_at_x(arrival_time_rel.num_seconds() as f32);
         total_miss_prob += transfer_missed_prob / (100.0 / step_size as f32);
     }
-    println!("Computed prob from {} to {} as {} %", arrival_time, departure_time, 1.0 - total_miss_prob);
+    debug!("Computed prob from {} to {} as {} %", arrival_time, departure_time, 1.0 - total_miss_prob);
     1.0 - total_miss_prob 
 }
 
-fn generate_png_data_url(time_curve: &TimeCurve, min_time: DateTime<Local>, max_time: DateTime<Local>, width: usize, event_type: EventType) -> FnResult<String> {
+fn generate_png_url(images: &CurveImageCache, time_curve: &TimeCurve, min_time: DateTime<Local>, max_time: DateTime<Local>, width: usize, event_type: EventType) -> FnResult<String> {
 
     let gradient = match event_type {
         EventType::Arrival => YELLOW_ORANGE_BROWN,
         EventType::Departure => YELLOW_GREEN_BLUE
     };
 
+    let (probs_cum, probs_uncum) = get_probabilities_per_pixel(time_curve, min_time, max_time, width);
+
+    let mut max = *probs_uncum.iter().max_by(|a,b| a.partial_cmp(b).unwrap()).unwrap();
+    if max < 0.05 {
+        max = 0.05;
+    }
+    let colors = (0..width).map(|i| {
+        let prob_uncum = probs_uncum[i] / max;
+        let prob_cum = probs_cum[i];
+        let crop_bottom = 0.2;
+        let crop_top = 0.2;
+        if prob_cum > 0.01 && prob_cum < 0.99 { 
+            gradient.eval_continuous((crop_bottom + (prob_uncum * (1.0 - crop_bottom - crop_top))) as f64)
+        } else if prob_cum > 0.0 && prob_cum < 1.0 {
+            gradient.eval_continuous(0.0 as f64)
+        } else {
+            Color{r: 255, g: 255, b: 255}
+        }
+    }).collect();
+
+    Ok(images.store(encode_png(colors)?))
+}
+
+// Like generate_png_url for arrivals, but the density is scaled with the probability
+// that the arrival happens at all.
+fn generate_journey_png_url(images: &CurveImageCache, time_curve: &TimeCurve, prob: f32, min_time: DateTime<Local>, max_time: DateTime<Local>, width: usize) -> FnResult<String> {
+    let (probs_cum, probs_uncum) = get_probabilities_per_pixel(time_curve, min_time, max_time, width);
+
+    let mut max = *probs_uncum.iter().max_by(|a,b| a.partial_cmp(b).unwrap()).unwrap();
+    if max < 0.01 {
+        max = 0.01;
+    }
+    let colors = (0..width).map(|i| {
+        if probs_cum[i] > 0.01 && probs_cum[i] < 0.99 {
+            YELLOW_ORANGE_BROWN.eval_continuous((prob * probs_uncum[i] / max * 0.8) as f64)
+        } else {
+            Color{r: 255, g: 255, b: 255}
+        }
+    }).collect();
+
+    Ok(images.store(encode_png(colors)?))
+}
+
+// returns the cumulated (width + 1 values) and uncumulated (width values) probabilities, in the image's reference system
+fn get_probabilities_per_pixel(time_curve: &TimeCurve, min_time: DateTime<Local>, max_time: DateTime<Local>, width: usize) -> (Vec<f32>, Vec<f32>) {
+    let f = (max_time - min_time) / width as i32;
+    let probs_cum : Vec<f32> = (0..(width + 1)).map(|x| time_curve.typed_y_at_x(min_time + f * x as i32)).collect();
+    let probs_uncum : Vec<f32> = probs_cum.iter().tuple_windows().map(|(a,b)| b-a).collect();
+    (probs_cum, probs_uncum)
+}
+
+// encodes a row of pixels as a PNG image with a height of 1 pixel
+fn encode_png(colors: Vec<Color>) -> FnResult<Vec<u8>> {
+    let _timer = start_stage(Stage::PngEncoding);
     let mut buf : Vec<u8> = Vec::new();
     // block for scoped borrow of buf
     {
-        let mut encoder = png::Encoder::new(&mut buf, width as u32, 1);
+        let mut encoder = png::Encoder::new(&mut buf, colors.len() as u32, 1);
         encoder.set_color(png::ColorType::RGBA);
         encoder.set_depth(png::BitDepth::Eight);
         let mut png = encoder.write_header()?;
 
-        let mut image_data = Vec::<u8>::with_capacity(width * 4);
-        let f = (max_time - min_time) / width as i32;
-        
-        // cumulated probabilities, in image's reference system:
-        let probs_cum : Vec<f32> = (0..(width + 1)).map(|x| time_curve.typed_y_at_x(min_time + f * x as i32)).collect();
-        // uncumulated ... 
-        let probs_uncum : Vec<f32> = probs_cum.iter().tuple_windows().map(|(a,b)| b-a).collect();
-        
-        let mut max = *probs_uncum.iter().max_by(|a,b| a.partial_cmp(b).unwrap()).unwrap();
-        if max < 0.05 {
-            max = 0.05;
-        }
-        for i in 0..width {
-            let prob_uncum = probs_uncum[i] / max;
-            let prob_cum = probs_cum[i];
-            let crop_bottom = 0.2;
-            let crop_top = 0.2;
-            let color = if prob_cum > 0.01 && prob_cum < 0.99 { 
-                gradient.eval_continuous((crop_bottom + (prob_uncum * (1.0 - crop_bottom - crop_top))) as f64)
-            } else if prob_cum > 0.0 && prob_cum < 1.0 {
-                gradient.eval_continuous(0.0 as f64)
-            } else {
-                Color{r: 255, g: 255, b: 255}
-            };
+        let mut image_data = Vec::<u8>::with_capacity(colors.len() * 4);
+        for color in colors {
             image_data.push(color.r);
             image_data.push(color.g);
             image_data.push(color.b);
@@ -1257,15 +2143,14 @@ fn generate_png_data_url(time_curve: &TimeCurve, min_time: DateTime<Local>, max_
         }
         png.write_image_data(&image_data)?; // Save
     }
-    let b64_data = base64::encode_config(buf, base64::STANDARD);
-    Ok(format!("data:image/png;base64,{}", b64_data))
+    Ok(buf)
 }
 
 fn generate_info_page(monitor: &Arc<Monitor>, journey: &JourneyData) -> FnResult<Response<Body>> {
     let schedule = monitor.main.get_schedule()?;
 
     let mut response = Response::new(Body::empty());
-    println!("generate_info_page");
+    debug!("generate_info_page");
     let trip_data = match journey.get_last_component().unwrap() {
         JourneyComponent::Trip(trip_data) => trip_data,
         _ => bail!("No trip at journey end"),
@@ -1278,7 +2163,7 @@ fn generate_info_page(monitor: &Arc<Monitor>, journey: &JourneyData) -> FnResult
     write!(&mut w, r#"
     <html>
         <head>
-            <title>Datenqualität für Linie {route_name} | Dystonse ÖPNV-Reiseplaner</title>
+            <title>{title}</title>
             <link rel="stylesheet" href="/style.css">
 
             {favicon_headers}
@@ -1286,19 +2171,26 @@ fn generate_info_page(monitor: &Arc<Monitor>, journey: &JourneyData) -> FnResult
         </head>
         <body class="monitorbody">
             <h1>Informationen für Linie {route_name} (route_id {route_id}, route_variant {route_variant}) nach {headsign}</h1>
+            <p class="csv-export"><a href="?format=csv">Alle Stichprobengrößen als CSV-Datei herunterladen</a></p>
             <h2>Statistische Analysen</h2>"#,
-            favicon_headers = FAVICON_HEADERS,
-            route_name = route.short_name.clone(),
-            route_id = trip_data.route_id,
-            route_variant = route_variant,
-            headsign = utf8_percent_encode(&trip.trip_headsign.as_ref().or_error("trip_headsign is None")?, PATH_ELEMENT_ESCAPE).to_string(),
+            title = monitor.theme.page_title(&format!("Datenqualität für Linie {}", route.short_name)),
+            favicon_headers = monitor.theme.head_elements(),
+            route_name = escape_html(&route.short_name),
+            route_id = escape_html(&trip_data.route_id),
+            route_variant = escape_html(route_variant),
+            headsign = escape_html(&trip.trip_headsign.as_ref().or_error("trip_headsign is None")?),
         )?;
 
-    match monitor.stats.specific.get(&trip_data.route_id) {
+    match monitor.get_stats().specific.get(&trip_data.route_id) {
         None => { writeln!(&mut w, "        Keine Linien-spezifischen Statistiken vorhanden.")?; },
         Some(route_data) => {
-            match route_data.variants.get(&route_variant.parse()?) {
-                None =>  { writeln!(&mut w, "        Keine Statistiken für die Linien-Variante {} vorhanden.</li></ul>", route_variant)?;} ,
+            let route_variant_id: u64 = route_variant.parse()?;
+            match route_data.variants.get(&route_variant_id) {
+                None => match route_data.merged_variants.get(&route_variant_id) {
+                    // the stop indices of the curves are those of the other variant, so they can't be shown here
+                    Some(merged_into) => { writeln!(&mut w, "        Die Statistiken der Linien-Variante {} wurden zusammen mit denen der Variante {} berechnet.", escape_html(route_variant), merged_into)?; },
+                    None => { writeln!(&mut w, "        Keine Statistiken für die Linien-Variante {} vorhanden.</li></ul>", escape_html(route_variant))?; },
+                },
                 Some(route_variant_data) => {
                     for et in &EventType::TYPES {
                         let curve_set_keys = route_variant_data.curve_sets[**et].keys();
@@ -1326,7 +2218,7 @@ fn generate_info_page(monitor: &Arc<Monitor>, journey: &JourneyData) -> FnResult
                                     for e_i in 0..trip.stop_times.len() {
                                         if e_i > s_i {
                                             let _count = match route_variant_data.curve_sets[**et].get(&CurveSetKey{
-                                                    start_stop_index: s_i as u32, end_stop_index: e_i as u32, time_slot: (**ts).clone()
+                                                    start_stop_index: s_i as u32, end_stop_index: e_i as u32, time_slot: (**ts).clone(), weather: WeatherCondition::Unknown
                                                 }) {
                                                 Some(csd) => write!(&mut w, "<td><b>{}</b></td>", csd.sample_size)?,
                                                 None => write!(&mut w, r#"<td style="color:#666;">0</td>"#)?
@@ -1389,116 +2281,11 @@ fn generate_info_page(monitor: &Arc<Monitor>, journey: &JourneyData) -> FnResult
     Ok(response)
 }
 
-#[derive(Debug, Clone)]
-pub struct DbPrediction {
-    pub route_id: String,
-    pub trip_id: String,
-    pub trip_start_date: Date<Local>,
-    pub trip_start_time: Duration, // time from midnight, may be outside 0:00 .. 24:00
-    pub prediction_min: DateTime<Local>, 
-    pub prediction_max: DateTime<Local>,
-    pub precision_type: PrecisionType,
-    pub origin_type: OriginType,
-    pub sample_size: i32,
-    pub prediction_curve: IrregularDynamicCurve<f32, f32>,
-    pub stop_id: String,
-    pub stop_sequence: usize,
-    pub event_type: EventType,
-
-    pub meta_data: Option<DbPredictionMetaData>,
-}
-
-#[derive(Debug, Clone)]
-pub struct DbPredictionMetaData {
-    pub route_name : String,
-    pub headsign : String,
-    pub stop_index : usize,
-    pub scheduled_time_seconds : u32,
-    pub scheduled_time_absolute : DateTime<Local>,
-    pub route_type: RouteType,
-}
-
+// the time curve is only needed for the monitor, so it is not defined with the other methods in types
 impl DbPrediction {
-    pub fn compute_meta_data(&mut self, schedule: Arc<Gtfs>) -> FnResult<()> {
-        if self.meta_data.is_some() {
-            return Ok(());
-        }
-
-        let trip = schedule.get_trip(&self.trip_id)?;
-        let route = schedule.get_route(&self.route_id)?;
-        let route_name = route.short_name.clone();
-        let route_type = route.route_type;
-        let headsign = trip.trip_headsign.as_ref().or_error("trip_headsign is None")?.clone();
-        let stop_index = trip.get_stop_index_by_stop_sequence(self.stop_sequence as u16).or_error("stop_index is None")?;
-        let scheduled_time_seconds = match self.event_type {
-            EventType::Arrival   => trip.stop_times[stop_index].arrival_time  .or_error("arrival_time is None"  )?,
-            EventType::Departure => trip.stop_times[stop_index].departure_time.or_error("departure_time is None")?
-        };
-        let scheduled_time_absolute = date_and_time_local(&self.trip_start_date, scheduled_time_seconds as i32);
-
-        self.meta_data = Some(DbPredictionMetaData{ 
-            route_name,
-            headsign,
-            stop_index,
-            scheduled_time_seconds,
-            scheduled_time_absolute,
-            route_type,
-        });
-        
-        Ok(())
-    }
-
     pub fn get_time_curve(&self) -> TimeCurve {
         TimeCurve::new(self.prediction_curve.clone(), self.meta_data.as_ref().unwrap().scheduled_time_absolute)
     }
-
-    pub fn get_absolute_time_for_probability(&self, prob: f32) -> FnResult<DateTime<Local>> {
-        let x = self.prediction_curve.x_at_y(prob);
-        Ok(date_and_time_local(&self.trip_start_date, self.meta_data.as_ref().or_error("Prediction has no meta_data")?.scheduled_time_seconds as i32 + x as i32))
-    }
-
-    pub fn get_relative_time_for_probability(&self, prob: f32) -> i32 {
-        self.prediction_curve.x_at_y(prob) as i32
-    }
-
-    #[allow(dead_code)]
-    pub fn get_relative_time(&self, time: DateTime<Local>) -> FnResult<f32> {
-        Ok(-self.meta_data.as_ref().or_error("Prediction has no meta_data")?.scheduled_time_absolute.signed_duration_since(time).num_seconds() as f32)
-    }
-
-    #[allow(dead_code)]
-    pub fn get_probability_for_relative_time(&self, relative_seconds: f32) -> f32 {
-        self.prediction_curve.y_at_x(relative_seconds)
-    }
-}
-
-impl FromRow for DbPrediction {
-    fn from_row_opt(row: Row) -> std::result::Result<Self, FromRowError> {
-        use chrono::{NaiveDate, NaiveDateTime};
-        use chrono::offset::TimeZone;
-
-        let naive_trip_start_date:NaiveDate    = row.get_opt(2).unwrap().unwrap();
-        let naive_prediction_min:NaiveDateTime = row.get_opt(4).unwrap().unwrap();
-        let naive_prediction_max:NaiveDateTime = row.get_opt(5).unwrap().unwrap();
-         // TODO the .single().unwrap() below will fail when daylight saving changes.
-        Ok(DbPrediction{
-            route_id:           row.get_opt(0).unwrap().unwrap(),
-            trip_id:            row.get_opt(1).unwrap().unwrap(),
-            trip_start_date:    Local.from_local_date(&naive_trip_start_date).single().unwrap(),
-            trip_start_time:    row.get_opt(3).unwrap().unwrap(),
-            prediction_min:     Local.from_local_datetime(&naive_prediction_min).single().unwrap(),
-            prediction_max:     Local.from_local_datetime(&naive_prediction_max).single().unwrap(),
-            precision_type:     PrecisionType::from_int(row.get_opt(6).unwrap().unwrap()),
-            origin_type:        OriginType::from_int(row.get_opt(7).unwrap().unwrap()),
-            sample_size:        row.get_opt(8).unwrap().unwrap(),
-            prediction_curve:   IrregularDynamicCurve::<f32, f32>
-                                    ::deserialize_compact(row.get_opt(9).unwrap().unwrap()),
-            stop_id:            row.get_opt(10).unwrap().unwrap(),
-            stop_sequence:      row.get_opt(11).unwrap().unwrap(),
-            event_type:         EventType::from_int(row.get_opt(12).unwrap().unwrap()),
-            meta_data:          None,
-        })
-    }
 }
 
 struct DbStat {
@@ -1508,6 +2295,7 @@ struct DbStat {
 }
 
 fn get_record_pair_statistics(monitor: &Arc<Monitor>, source: &str, route_id: &str, route_variant: &str) -> FnResult<Vec<DbStat>> {
+    let _timer = start_stage(Stage::Database);
     let mut conn = monitor.pool.get_conn()?;
     let stmt = conn.prep(
         r"SELECT 
@@ -1556,6 +2344,10 @@ fn get_predictions_for_stop(
     min_time: DateTime<Local>, 
     max_time: DateTime<Local>
 ) -> FnResult<Vec<DbPrediction>> {
+    if monitor.is_statistics_missing() {
+        return schedule_only::get_schedule_predictions_for_stop(&monitor.main.get_schedule()?, event_type, stop_id, min_time, max_time);
+    }
+    let _timer = start_stage(Stage::Database);
     let mut conn = monitor.pool.get_conn()?;
     let stmt = conn.prep(
         r"SELECT 
@@ -1612,6 +2404,10 @@ fn get_predictions_for_trip(
     vehicle_id: &VehicleIdentifier,
     start_sequence: u16,
 ) -> FnResult<Vec<DbPrediction>> {
+    if monitor.is_statistics_missing() {
+        return schedule_only::get_schedule_predictions_for_trip(&monitor.main.get_schedule()?, event_type, vehicle_id, start_sequence);
+    }
+    let _timer = start_stage(Stage::Database);
     let mut conn = monitor.pool.get_conn()?;
     let stmt = conn.prep(
         r"SELECT 
@@ -1645,7 +2441,7 @@ fn get_predictions_for_trip(
             "source" => source,
             "event_type" => event_type.to_int(),
             "trip_id" => vehicle_id.trip_id.clone(),
-            "trip_start_date" => vehicle_id.start.service_day().naive_local(),
+            "trip_start_date" => vehicle_id.start.service_day_param(),
             "trip_start_time" => vehicle_id.start.duration(),
             "start_sequence" => start_sequence,
         },
@@ -1679,3 +2475,95 @@ pub fn route_type_to_str(route_type: RouteType) -> &'static str {
         RouteType::Other(_u16) => "Fahrzeug",
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::journey_url::parse_start_date_time;
+    use rand::{Rng, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+
+    // characters that are likely to trip up the URL parsing
+    const ALPHABET: &[char] = &['a', 'Z', '0', '9', '/', '?', '&', '=', '%', '.', ':', ' ', '-', '"', 'ß', 'ü', '\n', '\u{0}'];
+
+    fn random_string(rng: &mut XorShiftRng) -> String {
+        let len = rng.gen_range(0, 30);
+        (0..len).map(|_| ALPHABET[rng.gen_range(0, ALPHABET.len())]).collect()
+    }
+
+    #[test]
+    fn test_split_path_does_not_panic() {
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        for _ in 0..10000 {
+            let path = random_string(&mut rng);
+            for part in split_path(&path) {
+                assert!(!part.is_empty());
+            }
+        }
+        assert_eq!(split_path("/info//a%20b/"), vec!["info", "a b"]);
+        // invalid UTF-8 after decoding is replaced instead of causing an error
+        assert_eq!(split_path("/%FF"), vec!["\u{fffd}"]);
+    }
+
+    #[test]
+    fn test_parse_query_params() {
+        let query_params = parse_query_params("types=tram&routes=2%2C3&types=bus&direction=");
+        assert_eq!(query_params.len(), 3);
+        assert_eq!(query_params["types"], "tram,bus");
+        assert_eq!(query_params["routes"], "2,3");
+        assert_eq!(query_params["direction"], "");
+        let filter = DepartureFilter::from_query_params(&query_params).unwrap();
+        assert_eq!(filter.route_types, vec!["tram", "bus"]);
+    }
+
+    #[test]
+    fn test_stop_by_name_without_start_is_bad_request() {
+        let mut params = HashMap::new();
+        let err = generate_stop_by_name_redirect(&params).unwrap_err();
+        assert!(err.is::<BadRequest>());
+
+        params.insert(String::from("start"), String::from("  "));
+        let err = generate_stop_by_name_redirect(&params).unwrap_err();
+        assert!(err.is::<BadRequest>());
+    }
+
+    #[test]
+    fn test_stop_by_name_with_random_names_does_not_panic() {
+        let mut rng = XorShiftRng::seed_from_u64(23);
+        for _ in 0..10000 {
+            let mut params = HashMap::new();
+            params.insert(String::from("start"), random_string(&mut rng));
+            match generate_stop_by_name_redirect(&params) {
+                Ok(response) => assert_eq!(response.status(), StatusCode::FOUND),
+                Err(e) => assert!(e.is::<BadRequest>(), "unexpected error: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_start_times_are_bad_requests() {
+        let mut rng = XorShiftRng::seed_from_u64(7);
+        assert!(parse_start_date_time(None).unwrap_err().is::<BadRequest>());
+        for _ in 0..10000 {
+            let timestring = random_string(&mut rng);
+            if let Err(e) = parse_start_date_time(Some(&timestring)) {
+                assert!(e.is::<BadRequest>(), "unexpected error: {}", e);
+            }
+        }
+        assert!(parse_start_date_time(Some(&String::from("24.12.20 18:30"))).is_ok());
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(escape_html("Bahnhof"), "Bahnhof");
+        assert_eq!(escape_html("<script>alert('x & y')</script>"), "&lt;script&gt;alert(&#39;x &amp; y&#39;)&lt;/script&gt;");
+        assert_eq!(escape_html(r#"title="x""#), "title=&quot;x&quot;");
+        assert_eq!(url_element("A & B/C <D>"), "A &amp; B%2FC %3CD%3E");
+    }
+
+    #[test]
+    fn test_error_page_for_bad_request() {
+        let response = generate_error_page(StatusCode::BAD_REQUEST, "Trip not found").unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,117 @@
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use gtfs_structures::Trip;
+use mysql::*;
+use mysql::prelude::*;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::FnResult;
+use crate::types::VehicleIdentifier;
+use super::{Monitor, escape_html, format_delay};
+use super::stage_timings::{Stage, start_stage};
+
+/// The delays at one stop, as they have been recorded for a vehicle. The `records` table only
+/// keeps the last observation per stop, so this is the latest known state of each stop.
+pub struct Observation {
+    pub stop_sequence: u16,
+    pub time_of_recording: DateTime<Local>,
+    pub delay_arrival: Option<i32>,
+    pub delay_departure: Option<i32>,
+}
+
+impl FromRow for Observation {
+    fn from_row_opt(row: Row) -> std::result::Result<Self, FromRowError> {
+        let (stop_sequence, time_of_recording, delay_arrival, delay_departure): (u16, NaiveDateTime, Option<i32>, Option<i32>) = FromRow::from_row_opt(row.clone())?;
+        // times of recording are written in local time, so only the gap of a DST change can't be converted back
+        let time_of_recording = Local.from_local_datetime(&time_of_recording).earliest().ok_or(FromRowError(row))?;
+        Ok(Observation {
+            stop_sequence,
+            time_of_recording,
+            delay_arrival,
+            delay_departure,
+        })
+    }
+}
+
+/// Looks up the recorded delays of the vehicle, ordered by stop sequence.
+pub fn get_observations(monitor: &Arc<Monitor>, vehicle_id: &VehicleIdentifier) -> FnResult<Vec<Observation>> {
+    let _timer = start_stage(Stage::Database);
+    let mut conn = monitor.pool.get_conn()?;
+    let observations = conn.exec(
+        r"SELECT
+            `stop_sequence`,
+            `time_of_recording`,
+            `delay_arrival`,
+            `delay_departure`
+        FROM
+            `records`
+        WHERE
+            `source`=:source AND
+            `trip_id`=:trip_id AND
+            `trip_start_date`=:trip_start_date AND
+            `trip_start_time`=:trip_start_time
+        ORDER BY `stop_sequence`;",
+        params! {
+            "source" => &monitor.source,
+            "trip_id" => vehicle_id.trip_id.clone(),
+            "trip_start_date" => vehicle_id.start.service_day_param(),
+            "trip_start_time" => vehicle_id.start.duration(),
+        },
+    )?;
+    Ok(observations)
+}
+
+/// Writes a collapsed section with the recorded delays of the vehicle, stop by stop, so that it can be
+/// seen on which observations the predictions are based.
+pub fn write_observation_history(mut w: &mut Vec<u8>, observations: &[Observation], trip: &Trip) -> FnResult<()> {
+    write!(&mut w, r#"
+        <details class="observation-history">
+            <summary>Echtzeitdaten dieser Fahrt ({count} Halte)</summary>"#,
+        count = observations.len(),
+    )?;
+    if observations.is_empty() {
+        write!(&mut w, r#"
+            <p>Für diese Fahrt wurden heute noch keine Verspätungen aufgezeichnet.</p>
+        </details>"#)?;
+        return Ok(());
+    }
+    write!(&mut w, r#"
+            <table class="stats-table">
+                <tr><th>Nr.</th><th>Haltestelle</th><th>Ankunft</th><th>Abfahrt</th><th>Aufgezeichnet</th></tr>"#)?;
+    for observation in observations {
+        let stop_name = trip.stop_times.iter()
+            .find(|stop_time| stop_time.stop_sequence == observation.stop_sequence)
+            .map_or("unbekannt", |stop_time| &stop_time.stop.name);
+        write!(&mut w, r#"
+                <tr><td>{stop_sequence}</td><td>{stop_name}</td><td>{arrival}</td><td>{departure}</td><td>{time}</td></tr>"#,
+            stop_sequence = observation.stop_sequence,
+            stop_name = escape_html(stop_name),
+            arrival = format_observed_delay(observation.delay_arrival),
+            departure = format_observed_delay(observation.delay_departure),
+            time = observation.time_of_recording.format("%H:%M:%S"),
+        )?;
+    }
+    write!(&mut w, r#"
+            </table>
+        </details>"#)?;
+    Ok(())
+}
+
+fn format_observed_delay(delay: Option<i32>) -> String {
+    match delay {
+        Some(delay) => format!("{}&nbsp;Sek.", format_delay(delay)),
+        None => String::from("–"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_observed_delay() {
+        assert_eq!(format_observed_delay(Some(75)), "+75&nbsp;Sek.");
+        assert_eq!(format_observed_delay(Some(-12)), "-12&nbsp;Sek.");
+        assert_eq!(format_observed_delay(None), "–");
+    }
+}
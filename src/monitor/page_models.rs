@@ -0,0 +1,741 @@
+use chrono::{DateTime, Duration, Local, Timelike};
+use dystonse_curves::TypedCurve;
+use gtfs_structures::Gtfs;
+use serde::{Serialize, Serializer};
+use simple_error::bail;
+use std::sync::Arc;
+
+use crate::{FnResult, OrError};
+use crate::time_util::date_and_time;
+use crate::types::{DbPrediction, DelayStatistics, EventType, GetByEventType, HeadwayEntry, HolidayCalendar, TimeSlot};
+use super::{Monitor, route_type_to_str, get_predictions_for_stop, get_predictions_for_trip, get_stop_page_time_range};
+use super::journey_data::{JourneyData, JourneyComponent, StopData, TripData, get_prediction_for_first_line, is_stop_accessible, is_trip_accessible};
+use super::journey_url::{JourneyElement, TripElement};
+use super::stage_timings::{Stage, start_stage};
+use super::time_curve::TimeCurve;
+
+// how many later departures are suggested if a transfer is unlikely, and how far they may be in the future
+const MAX_ALTERNATIVES: usize = 2;
+const ALTERNATIVES_MAX_MINUTES: i64 = 120;
+
+/// Everything that the page of a stop shows, independent of how it is rendered.
+/// All probabilities are given in percent.
+#[derive(Debug, Clone, Serialize)]
+pub struct StopPageModel {
+    pub stop_name: String,
+    /// names of all stops whose departures are shown, including this one
+    pub extended_stop_names: Vec<String>,
+    #[serde(serialize_with = "serialize_time")]
+    pub min_time: DateTime<Local>,
+    #[serde(serialize_with = "serialize_time")]
+    pub max_time: DateTime<Local>,
+    /// minutes from `min_time` to `max_time`
+    pub len_time: i64,
+    /// if true, the departures are only known from the schedule
+    pub statistics_missing: bool,
+    /// the arrival at the stop, if the journey gets here by walk or bike
+    pub transfer_arrival: Option<TransferArrivalModel>,
+    /// the arrival at the stop, if the journey gets here with a trip
+    pub arrival: Option<DepartureModel>,
+    /// sorted by the time that matches the risk preference, including those that are too unlikely to be shown
+    pub departures: Vec<DepartureModel>,
+}
+
+/// One arrival or departure of a trip at a stop.
+#[derive(Debug, Clone, Serialize)]
+pub struct DepartureModel {
+    pub trip_id: String,
+    pub route_id: String,
+    pub route_type: &'static str,
+    pub route_name: String,
+    pub headsign: String,
+    /// may be another stop of the station than the one of the page
+    pub stop_id: String,
+    #[serde(serialize_with = "serialize_time")]
+    pub scheduled_time: DateTime<Local>,
+    #[serde(serialize_with = "serialize_time")]
+    pub earliest_time: DateTime<Local>,
+    #[serde(serialize_with = "serialize_time")]
+    pub median_time: DateTime<Local>,
+    #[serde(serialize_with = "serialize_time")]
+    pub latest_time: DateTime<Local>,
+    /// chance to catch the departure once the user is at the stop
+    pub local_probability: f32,
+    /// chance to catch the departure with the whole journey so far
+    pub probability: f32,
+    /// airline distance (in meters) to the stop of the departure, if it is another stop of the station
+    pub walk_distance: Option<f32>,
+    /// link to the page of the trip, only for departures
+    pub trip_url: Option<String>,
+    /// set if the route runs so often that only its headways are shown
+    pub headway: Option<HeadwayEntry>,
+    /// later departures of the same route or to the same headsign, if this one will probably be missed
+    pub alternatives: Vec<DepartureModel>,
+    #[serde(skip)]
+    pub prediction: DbPrediction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferMode {
+    Walk,
+    Bike,
+}
+
+/// The arrival at a stop by walk or bike from the previous stop of the journey.
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferArrivalModel {
+    pub mode: TransferMode,
+    /// airline distance (in meters) from the previous stop
+    pub distance: f32,
+    #[serde(serialize_with = "serialize_time")]
+    pub earliest_time: DateTime<Local>,
+    #[serde(serialize_with = "serialize_time")]
+    pub median_time: DateTime<Local>,
+    #[serde(serialize_with = "serialize_time")]
+    pub latest_time: DateTime<Local>,
+    pub probability: f32,
+    #[serde(skip)]
+    pub curve: TimeCurve,
+}
+
+/// Everything that the page of a trip shows, independent of how it is rendered.
+/// All probabilities are given in percent.
+#[derive(Debug, Clone, Serialize)]
+pub struct TripPageModel {
+    pub trip_id: String,
+    pub route_type: &'static str,
+    pub route_name: String,
+    pub headsign: String,
+    #[serde(serialize_with = "serialize_time")]
+    pub min_time: DateTime<Local>,
+    #[serde(serialize_with = "serialize_time")]
+    pub max_time: DateTime<Local>,
+    /// minutes from `min_time` to `max_time`
+    pub len_time: i64,
+    /// false if the schedule says that the vehicle can't carry wheelchair users
+    pub accessible: bool,
+    /// the arrival of the journey at the last stop of the trip, if the user stays on board until the end
+    pub journey_arrival: Option<JourneyArrivalModel>,
+    /// the departure at the boarding stop, followed by the arrivals at all later stops
+    pub stops: Vec<TripStopModel>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JourneyArrivalModel {
+    pub stop_name: String,
+    #[serde(serialize_with = "serialize_time")]
+    pub earliest_time: DateTime<Local>,
+    #[serde(serialize_with = "serialize_time")]
+    pub median_time: DateTime<Local>,
+    #[serde(serialize_with = "serialize_time")]
+    pub latest_time: DateTime<Local>,
+    /// chance to catch all trips of the journey
+    pub probability: f32,
+    #[serde(skip)]
+    pub curve: TimeCurve,
+}
+
+/// One stop of a trip. The times are missing if there is no prediction for it.
+#[derive(Debug, Clone, Serialize)]
+pub struct TripStopModel {
+    pub stop_id: String,
+    pub stop_name: String,
+    pub stop_sequence: u16,
+    pub event_type: EventType,
+    #[serde(serialize_with = "serialize_time")]
+    pub scheduled_time: DateTime<Local>,
+    #[serde(serialize_with = "serialize_optional_time")]
+    pub earliest_time: Option<DateTime<Local>>,
+    #[serde(serialize_with = "serialize_optional_time")]
+    pub median_time: Option<DateTime<Local>>,
+    #[serde(serialize_with = "serialize_optional_time")]
+    pub latest_time: Option<DateTime<Local>>,
+    /// chance to catch the trip, only known for the boarding stop
+    pub probability: Option<f32>,
+    /// false in accessible mode if wheelchair users can't get off at the stop
+    pub can_alight: bool,
+    #[serde(skip)]
+    pub prediction: Option<DbPrediction>,
+}
+
+/// The predictions that a stop page is built from. They are looked up once, and also used for the validators of the page.
+pub struct StopPagePredictions {
+    pub arrival: Option<DbPrediction>,
+    pub departures: Vec<DbPrediction>,
+}
+
+impl StopPagePredictions {
+    /// Looks up the arrival of the journey at the stop, if it gets there with a trip, and the departures in the time span of the page.
+    pub fn lookup(monitor: &Arc<Monitor>, journey_data: &JourneyData, stop_data: &StopData) -> FnResult<Self> {
+        let schedule = monitor.main.get_schedule()?;
+        let (min_time, _len_time, max_time) = get_stop_page_time_range(journey_data, stop_data);
+
+        let mut arrival = None;
+        if let Some(arrival_trip) = stop_data.get_previous_trip_data() {
+            let arrival_stop_sequence = arrival_trip.get_trip(&schedule)?.stop_times[stop_data.arrival_trip_stop_index.unwrap()].stop_sequence;
+            arrival = get_prediction_for_first_line(monitor, arrival_stop_sequence, &arrival_trip.vehicle_id, EventType::Arrival).ok();
+        }
+
+        let mut departures = Vec::new();
+        for stop_id in &stop_data.extended_stop_ids {
+            departures.extend(get_predictions_for_stop(monitor, monitor.source.clone(), EventType::Departure, stop_id, min_time, max_time)?);
+        }
+        debug!("Found {} departure predictions.", departures.len());
+        Ok(StopPagePredictions { arrival, departures })
+    }
+}
+
+impl StopPageModel {
+    /// Builds the model from the predictions that have been looked up for the page, including the headways
+    /// of high-frequency routes and the alternatives for departures that will probably be missed.
+    pub fn new(monitor: &Arc<Monitor>, journey_data: &JourneyData, stop_data: &StopData, predictions: StopPagePredictions) -> FnResult<Self> {
+        let schedule = monitor.main.get_schedule()?;
+        let stats = monitor.get_stats();
+
+        let mut model = Self::from_predictions(journey_data, stop_data, &schedule, &stats, &monitor.main.holidays, predictions.arrival, predictions.departures)?;
+        model.statistics_missing = monitor.is_statistics_missing();
+
+        for departure in &mut model.departures {
+            departure.headway = get_headway_entry(monitor, &departure.prediction, &schedule).cloned();
+        }
+
+        // only departures that are rendered, but will probably be missed, get alternatives
+        let min_chance = journey_data.display_thresholds.get_min_chance();
+        let alternatives_threshold = journey_data.display_thresholds.alternatives_threshold;
+        let needing_alternatives: Vec<usize> = model.departures.iter().enumerate()
+            .filter(|(_, dep)| dep.headway.is_none() && dep.local_probability >= min_chance && dep.local_probability < alternatives_threshold)
+            .map(|(i, _)| i)
+            .collect();
+        if needing_alternatives.is_empty() {
+            return Ok(model);
+        }
+
+        // look up the candidates for all of them at once
+        let begin = needing_alternatives.iter().map(|&i| model.departures[i].scheduled_time).min().unwrap(); // not empty
+        let end = needing_alternatives.iter().map(|&i| model.departures[i].scheduled_time).max().unwrap() + Duration::minutes(ALTERNATIVES_MAX_MINUTES);
+        let mut candidates = Vec::new();
+        for stop_id in &stop_data.extended_stop_ids {
+            match get_predictions_for_stop(monitor, monitor.source.clone(), EventType::Departure, stop_id, begin, end) {
+                Ok(predictions) => candidates.extend(predictions),
+                Err(e) => {
+                    warn!("Could not look up alternatives at stop {}: {}", stop_id, e);
+                    return Ok(model);
+                },
+            }
+        }
+        candidates.retain(|candidate| candidate.compute_meta_data(schedule.clone()).is_ok());
+
+        // departures that are on the page already are reused, all others are only built once
+        let mut built: Vec<DepartureModel> = Vec::new();
+        let mut all_alternatives = Vec::new();
+        for &i in &needing_alternatives {
+            let mut alternatives = Vec::new();
+            for candidate in find_alternatives(&model.departures[i], &candidates) {
+                let is_same = |dep: &&DepartureModel| dep.prediction.trip_id == candidate.trip_id
+                    && dep.prediction.trip_start_date == candidate.trip_start_date
+                    && dep.prediction.stop_id == candidate.stop_id;
+                if let Some(known) = model.departures.iter().chain(built.iter()).find(is_same) {
+                    alternatives.push(known.clone());
+                    continue;
+                }
+                match DepartureModel::new(candidate.clone(), journey_data, stop_data, &schedule, &stats, &monitor.main.holidays) {
+                    Ok(alternative) => {
+                        built.push(alternative.clone());
+                        alternatives.push(alternative);
+                    },
+                    Err(e) => warn!("Could not use departure with trip_id {} as alternative: {}", candidate.trip_id, e),
+                }
+            }
+            all_alternatives.push((i, alternatives));
+        }
+        for (i, alternatives) in all_alternatives {
+            model.departures[i].alternatives = alternatives;
+        }
+        Ok(model)
+    }
+
+    /// Builds the model from predictions that have already been looked up. The departures are filtered
+    /// and sorted like on the page, but headways and alternatives are not filled in.
+    pub fn from_predictions(
+        journey_data: &JourneyData,
+        stop_data: &StopData,
+        schedule: &Arc<Gtfs>,
+        stats: &DelayStatistics,
+        holidays: &HolidayCalendar,
+        arrival: Option<DbPrediction>,
+        mut departures: Vec<DbPrediction>,
+    ) -> FnResult<Self> {
+        let (min_time, len_time, max_time) = get_stop_page_time_range(journey_data, stop_data);
+
+        let meta_data_timer = start_stage(Stage::MetaData);
+        for dep in &mut departures {
+            if let Err(e) = dep.compute_meta_data(schedule.clone()){
+                warn!("Could not compute metadata for departure with trip_id {}: {}", dep.trip_id , e);
+            }
+        }
+        drop(meta_data_timer);
+
+        // Remove the top and bottom of the predicted time span (5% by default).
+        // They mostly contain outliers with several hours of (sometimes negative) delay.
+        let trim = journey_data.display_thresholds.curve_trim / 100.0;
+        departures.retain(|dep| {
+            if dep.meta_data.is_some() {
+                let time_absolute_low = dep.get_absolute_time_for_probability(trim).unwrap();
+                let time_absolute_high = dep.get_absolute_time_for_probability(1.0 - trim).unwrap();
+
+                time_absolute_low < max_time && time_absolute_high > min_time
+            } else {
+                false
+            }
+        });
+        debug!("Kept {} departure predictions based on removing the top and bottom {}%.", departures.len(), journey_data.display_thresholds.curve_trim);
+
+        // remove departures where the current stop is the last one (which seem to happen for trains quite often):
+        departures.retain(|dep| !is_at_last_stop(dep, schedule));
+        debug!("Kept {} departure predictions after removing trips that are at their last stop.", departures.len());
+
+        if journey_data.accessible {
+            departures.retain(|dep| schedule.get_trip(&dep.trip_id).map_or(false, |trip| is_trip_accessible(trip)));
+            debug!("Kept {} departure predictions of wheelchair accessible trips.", departures.len());
+        }
+
+        if !journey_data.departure_filter.is_empty() {
+            departures.retain(|dep| journey_data.departure_filter.matches(dep.meta_data.as_ref().unwrap())); // departures without meta data have been removed
+            debug!("Kept {} departure predictions that match the filter {:?}.", departures.len(), journey_data.departure_filter);
+        }
+
+        // sort by departure time, at the probability that matches the risk preference (the median by default):
+        let ordering_probability = journey_data.display_thresholds.risk.get_ordering_probability();
+        departures.sort_by_cached_key(|dep| dep.get_absolute_time_for_probability(ordering_probability).unwrap());
+
+        let arrival = match arrival {
+            Some(mut arrival) => {
+                arrival.compute_meta_data(schedule.clone())?;
+                Some(DepartureModel::new(arrival, journey_data, stop_data, schedule, stats, holidays)?)
+            },
+            None => None,
+        };
+
+        Ok(StopPageModel {
+            stop_name: stop_data.stop_name.clone(),
+            extended_stop_names: stop_data.extended_stop_names.clone(),
+            min_time,
+            max_time,
+            len_time,
+            statistics_missing: false,
+            transfer_arrival: TransferArrivalModel::new(stop_data)?,
+            arrival,
+            departures: departures.into_iter()
+                .map(|dep| DepartureModel::new(dep, journey_data, stop_data, schedule, stats, holidays))
+                .collect::<FnResult<_>>()?,
+        })
+    }
+}
+
+impl DepartureModel {
+    /// Gathers what is shown about a prediction, whose meta data must have been computed.
+    /// Arrivals are those of the journey at the stop, so they are caught for sure.
+    pub fn new(prediction: DbPrediction, journey_data: &JourneyData, stop_data: &StopData, schedule: &Gtfs, stats: &DelayStatistics, holidays: &HolidayCalendar) -> FnResult<Self> {
+        let md = prediction.meta_data.as_ref().or_error("Prediction has no metadata")?;
+        let (local_probability, trip_url) = match prediction.event_type {
+            EventType::Arrival => (100.0, None),
+            EventType::Departure => (
+                get_local_transfer_probability(&prediction, stop_data, journey_data, stats, holidays),
+                Some(get_trip_url(&prediction, stop_data, journey_data, schedule)?),
+            ),
+        };
+
+        Ok(DepartureModel {
+            trip_id: prediction.trip_id.to_string(),
+            route_id: prediction.route_id.to_string(),
+            route_type: route_type_to_str(md.route_type),
+            route_name: md.route_name.clone(),
+            headsign: md.headsign.clone(),
+            stop_id: prediction.stop_id.to_string(),
+            scheduled_time: md.scheduled_time_absolute,
+            earliest_time: prediction.get_absolute_time_for_probability(0.01)?,
+            median_time: prediction.get_absolute_time_for_probability(0.50)?,
+            latest_time: prediction.get_absolute_time_for_probability(0.99)?,
+            local_probability,
+            probability: stop_data.start_prob * local_probability,
+            walk_distance: stop_data.extended_stops_distances.get(prediction.stop_id.as_str()).copied(),
+            trip_url,
+            headway: None,
+            alternatives: Vec::new(),
+            prediction,
+        })
+    }
+}
+
+impl TransferArrivalModel {
+    /// Returns the arrival at the stop if the previous component of the journey is a walk or a bike ride.
+    pub fn new(stop_data: &StopData) -> FnResult<Option<Self>> {
+        let (mode, prev_component) = match &stop_data.prev_component {
+            Some(JourneyComponent::Walk(walk_data)) => (TransferMode::Walk, &walk_data.prev_component),
+            Some(JourneyComponent::Bike(bike_data)) => (TransferMode::Bike, &bike_data.prev_component),
+            _ => return Ok(None),
+        };
+        let distance = match prev_component {
+            JourneyComponent::Stop(prev_stop) => prev_stop.get_max_distance(stop_data),
+            _ => bail!("{:?} has no prev_stop", mode),
+        };
+
+        Ok(Some(TransferArrivalModel {
+            mode,
+            distance,
+            earliest_time: stop_data.start_curve.typed_x_at_y(0.01),
+            median_time: stop_data.start_curve.typed_x_at_y(0.50),
+            latest_time: stop_data.start_curve.typed_x_at_y(0.99),
+            probability: stop_data.start_prob * 100.0,
+            curve: stop_data.start_curve.clone(),
+        }))
+    }
+}
+
+impl TripPageModel {
+    /// Looks up the departure at the boarding stop and the arrivals at all later stops and builds the model from them.
+    pub fn new(monitor: &Arc<Monitor>, journey_data: &JourneyData, trip_data: &TripData) -> FnResult<Self> {
+        let schedule = monitor.main.get_schedule()?;
+        let trip = trip_data.get_trip(&schedule)?;
+        let start_sequence = trip.stop_times[trip_data.boarding_stop_index.or_error("Trip has no boarding stop")?].stop_sequence;
+
+        // departure from first stop: this is where the user changes into this trip
+        let departure = get_prediction_for_first_line(monitor, start_sequence, &trip_data.vehicle_id, EventType::Departure)?;
+        let arrivals = get_predictions_for_trip(monitor, monitor.source.clone(), EventType::Arrival, &trip_data.vehicle_id, start_sequence + 1)?;
+
+        Self::from_predictions(journey_data, trip_data, &schedule, departure, arrivals)
+    }
+
+    /// Builds the model from predictions that have already been looked up.
+    pub fn from_predictions(journey_data: &JourneyData, trip_data: &TripData, schedule: &Arc<Gtfs>, mut departure: DbPrediction, mut arrivals: Vec<DbPrediction>) -> FnResult<Self> {
+        if arrivals.is_empty() {
+            bail!("No predictions for this trip");
+        }
+        let trip = trip_data.get_trip(schedule)?;
+        let route = schedule.get_route(&trip.route_id)?;
+        let boarding_stop_index = trip_data.boarding_stop_index.or_error("Trip has no boarding stop")?;
+
+        for arr in &mut arrivals {
+            if let Err(e) = arr.compute_meta_data(schedule.clone()){
+                warn!("Could not compute metadata for arrival with trip_id {}: {}", arr.trip_id , e);
+            }
+        }
+
+        departure.compute_meta_data(schedule.clone())?;
+        let exact_min_time = departure.get_absolute_time_for_probability(0.01)?;
+
+        let exact_max_time = if let Some(time) = arrivals.iter().filter_map(|arr| arr.get_absolute_time_for_probability(0.99).ok()).max() {
+            time
+        } else {
+            arrivals.iter().filter_map(|arr| arr.meta_data.as_ref()).map(|md| md.scheduled_time_absolute).max().or_error("No maximum")?
+        };
+
+        let min_time = exact_min_time - Duration::minutes(exact_min_time.time().minute() as i64 % 5); // round to previous nice time
+        let len_time: i64 = ((exact_max_time.signed_duration_since(min_time).num_minutes() as i64 + 6) / 5) * 5;
+        let max_time = min_time + Duration::minutes(len_time);
+
+        let last_stop_time = trip.stop_times.last().or_error("Trip has no stop times")?;
+        let journey_arrival = arrivals.iter()
+            .find(|a| a.stop_sequence == last_stop_time.stop_sequence as usize && a.meta_data.is_some())
+            .map(|last_arrival| {
+                // the page is still useful with the arrival of this trip alone
+                let curve = match journey_data.get_journey_arrival_curve(&last_arrival.get_time_curve()) {
+                    Ok(curve) => curve,
+                    Err(e) => {
+                        warn!("Could not combine the legs of the journey: {}", e);
+                        last_arrival.get_time_curve()
+                    },
+                };
+                JourneyArrivalModel {
+                    stop_name: last_stop_time.stop.name.clone(),
+                    earliest_time: curve.typed_x_at_y(0.01),
+                    median_time: curve.typed_x_at_y(0.50),
+                    latest_time: curve.typed_x_at_y(0.99),
+                    probability: trip_data.start_prob * 100.0,
+                    curve,
+                }
+            });
+
+        // stops before the one where the user changes into this trip are not shown
+        let service_day = trip_data.vehicle_id.start.service_day();
+        let mut stops = Vec::new();
+        for (stop_index, stop_time) in trip.stop_times.iter().enumerate().skip(boarding_stop_index) {
+            let (event_type, prediction, probability) = if stop_index == boarding_stop_index {
+                (EventType::Departure, Some(departure.clone()), Some(trip_data.start_prob * 100.0))
+            } else {
+                let arrival = arrivals.iter().find(|a| a.stop_sequence == stop_time.stop_sequence as usize).cloned();
+                (EventType::Arrival, arrival, None)
+            };
+            let scheduled_time = date_and_time(&service_day, stop_time.get_time(event_type).or_error("Stop time has no scheduled time")? as i32);
+            let time_for_probability = |prob: f32| prediction.as_ref().map(|p| scheduled_time + Duration::seconds(p.get_relative_time_for_probability(prob) as i64));
+            stops.push(TripStopModel {
+                stop_id: stop_time.stop.id.clone(),
+                stop_name: stop_time.stop.name.clone(),
+                stop_sequence: stop_time.stop_sequence,
+                event_type,
+                scheduled_time,
+                earliest_time: time_for_probability(0.01),
+                median_time: time_for_probability(0.50),
+                latest_time: time_for_probability(0.99),
+                probability,
+                // in accessible mode, there are no links to stops at which wheelchair users can't get off
+                can_alight: !journey_data.accessible || is_stop_accessible(schedule, &stop_time.stop),
+                prediction,
+            });
+        }
+
+        Ok(TripPageModel {
+            trip_id: trip.id.clone(),
+            route_type: route_type_to_str(route.route_type),
+            route_name: route.short_name.clone(),
+            headsign: trip.trip_headsign.clone().unwrap_or_default(),
+            min_time,
+            max_time,
+            len_time,
+            accessible: is_trip_accessible(trip),
+            journey_arrival,
+            stops,
+        })
+    }
+}
+
+// whether the departure is at the last stop of its trip, where nobody can board anymore
+fn is_at_last_stop(dep: &DbPrediction, schedule: &Gtfs) -> bool {
+    if let Ok(trip) = &schedule.get_trip(&dep.trip_id) {
+        if let Some(stop_time) = &trip.stop_times.last() {
+            let last_stop_id = &stop_time.stop.id;
+            return dep.stop_id == *last_stop_id && dep.stop_sequence == stop_time.stop_sequence as usize;
+        }
+    }
+    false
+}
+
+/// Returns the headways of the departure's route variant, if the monitor shows it as high-frequency at that time.
+fn get_headway_entry<'m>(monitor: &'m Monitor, dep: &DbPrediction, schedule: &Gtfs) -> Option<&'m HeadwayEntry> {
+    let headways = monitor.headways.as_ref()?;
+    let route_variant: u64 = schedule.get_trip(&dep.trip_id).ok()?.route_variant.as_ref()?.parse().ok()?;
+    let time_slot = TimeSlot::from_datetime(dep.meta_data.as_ref()?.scheduled_time_absolute, &monitor.main.holidays);
+    headways.get(&dep.route_id, route_variant, time_slot)
+}
+
+// local probability (in percent) of getting the transfer (not accumulated for the whole journey, just for here)
+fn get_local_transfer_probability(dep: &DbPrediction, stop_data: &StopData, journey_data: &JourneyData, stats: &DelayStatistics, holidays: &HolidayCalendar) -> f32 {
+    // Even for a distance of 0 there is some walk time involved.
+    let walk_distance = *stop_data.extended_stops_distances.get(dep.stop_id.as_str()).unwrap_or(&0.0);
+    stop_data.start_curve
+        .add_duration_curve(&journey_data.walk_profile.get_walk_time(walk_distance))
+        .get_transfer_probability(&dep.get_time_curve()) * dep.get_operation_probability(stats, holidays) * 100.0
+}
+
+// URL of the trip page for a departure, including the walk to another stop if needed
+fn get_trip_url(dep: &DbPrediction, stop_data: &StopData, journey_data: &JourneyData, schedule: &Gtfs) -> FnResult<String> {
+    let md = dep.meta_data.as_ref().or_error("Departure has no metadata")?;
+    let mut url = stop_data.url.clone();
+    if stop_data.extended_stops_distances.contains_key(dep.stop_id.as_str()) {
+        let alternative_stop_name = schedule.get_stop(&dep.stop_id)?.name.clone();
+        url = format!("{}{}/{}/", url, JourneyElement::Walk.to_path_element(), JourneyElement::Stop(alternative_stop_name).to_path_element());
+    }
+    let trip_element = TripElement {
+        route_type: String::from(route_type_to_str(md.route_type)),
+        route_name: md.route_name.clone(),
+        headsign: md.headsign.clone(),
+        departure_time: md.scheduled_time_absolute.time(),
+        departure_date: Some(md.scheduled_time_absolute.date().naive_local()),
+    };
+    Ok(journey_data.get_link(&format!("{}{}/", url, JourneyElement::Trip(trip_element).to_path_element())))
+}
+
+/// Finds the next departures after `departure` of the same route or to the same headsign among `candidates`,
+/// which can be used if the transfer to `departure` fails. Candidates without meta data are ignored.
+fn find_alternatives<'c>(departure: &DepartureModel, candidates: &'c [DbPrediction]) -> Vec<&'c DbPrediction> {
+    let begin = departure.scheduled_time;
+    let end = begin + Duration::minutes(ALTERNATIVES_MAX_MINUTES);
+    let mut alternatives: Vec<(&DbPrediction, DateTime<Local>)> = candidates.iter()
+        .filter_map(|candidate| candidate.meta_data.as_ref().map(|cmd| (candidate, cmd)))
+        .filter(|(candidate, cmd)| {
+            cmd.scheduled_time_absolute > begin
+                && cmd.scheduled_time_absolute <= end
+                && candidate.trip_id != departure.prediction.trip_id
+                && (candidate.route_id == departure.prediction.route_id || cmd.headsign == departure.headsign)
+        })
+        .map(|(candidate, cmd)| (candidate, cmd.scheduled_time_absolute))
+        .collect();
+
+    alternatives.sort_by_key(|(_, time)| *time);
+    alternatives.truncate(MAX_ALTERNATIVES);
+    alternatives.into_iter().map(|(candidate, _)| candidate).collect()
+}
+
+// times are written like in the live updates
+fn serialize_time<S: Serializer>(time: &DateTime<Local>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&time.to_rfc3339())
+}
+
+fn serialize_optional_time<S: Serializer>(time: &Option<DateTime<Local>>, serializer: S) -> Result<S::Ok, S::Error> {
+    match time {
+        Some(time) => serialize_time(time, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::journey_data::WalkProfile;
+    use super::super::journey_data::tests::{FixedPredictions, get_test_schedule};
+    use super::super::display_thresholds::{DisplayThresholds, RiskPreference};
+    use crate::types::{Id, OriginType, PrecisionType};
+    use chrono::{Date, TimeZone};
+    use dystonse_curves::{IrregularDynamicCurve, Tup};
+
+    fn get_journey_data(journey: &[&str]) -> JourneyData {
+        let predictions = Arc::new(FixedPredictions {
+            curve: IrregularDynamicCurve::new(vec![Tup { x: -60.0, y: 0.0 }, Tup { x: 120.0, y: 1.0 }]),
+            operation_probability: 1.0,
+        });
+        let thresholds = DisplayThresholds { min_chance: 5.0, curve_trim: 5.0, extended_stops_radius: 300.0, alternatives_threshold: 50.0, risk: RiskPreference::Balanced };
+        let journey: Vec<String> = journey.iter().map(|e| e.to_string()).collect();
+        JourneyData::with_prediction_source(&journey, get_test_schedule(), predictions, false, WalkProfile::Normal, thresholds).unwrap()
+    }
+
+    // an event of the bus of the test schedule that starts at 8:00 on `date`, as it would be read from the database
+    fn get_test_prediction(stop_sequence: u16, date: Date<Local>, event_type: EventType) -> DbPrediction {
+        let start = date.and_hms(8, 0, 0);
+        DbPrediction {
+            route_id: Id::new("r1"),
+            trip_id: Id::new("t1"),
+            trip_start_date: date,
+            trip_start_time: Duration::hours(8),
+            prediction_min: start,
+            prediction_max: start + Duration::minutes(12),
+            precision_type: PrecisionType::Specific,
+            origin_type: OriginType::Realtime,
+            sample_size: 100,
+            prediction_curve: IrregularDynamicCurve::new(vec![Tup { x: -60.0, y: 0.0 }, Tup { x: 120.0, y: 1.0 }]),
+            stop_id: Id::new(if stop_sequence == 1 { "s1" } else { "s2" }),
+            stop_sequence: stop_sequence as usize,
+            event_type,
+            meta_data: None,
+        }
+    }
+
+    #[test]
+    fn test_stop_page_model() {
+        let journey_data = get_journey_data(&["17.10.20 07:50", "Am Markt"]);
+        let stop_data = match journey_data.components.last() {
+            Some(JourneyComponent::Stop(stop_data)) => stop_data.clone(),
+            other => panic!("expected a stop, got {:?}", other),
+        };
+        let day = Local.ymd(2020, 10, 17);
+        let departures = vec![
+            get_test_prediction(1, day, EventType::Departure),
+            // the bus ends at the station
+            get_test_prediction(2, day, EventType::Departure),
+            // the bus of the next day is outside of the time span of the page
+            get_test_prediction(1, day.succ(), EventType::Departure),
+        ];
+        let model = StopPageModel::from_predictions(&journey_data, &stop_data, &journey_data.schedule, &DelayStatistics::new(), &HolidayCalendar::default(), None, departures).unwrap();
+        assert_eq!(model.stop_name, "Am Markt");
+        assert_eq!(model.len_time, 30);
+        assert!(model.transfer_arrival.is_none());
+        assert!(model.arrival.is_none());
+        assert_eq!(model.departures.len(), 1);
+
+        // there are 10 minutes to catch the bus, which is known to be operated
+        let departure = &model.departures[0];
+        assert_eq!(departure.trip_id, "t1");
+        assert_eq!(departure.route_type, "Bus");
+        assert_eq!(departure.scheduled_time, day.and_hms(8, 0, 0));
+        assert!(departure.earliest_time < departure.scheduled_time && departure.scheduled_time < departure.latest_time);
+        assert!(departure.local_probability > 99.0, "{}", departure.local_probability);
+        assert_eq!(departure.probability, departure.local_probability);
+        assert_eq!(departure.walk_distance, None);
+        assert_eq!(departure.trip_url.as_deref(), Some("/17.10.20 07:50/Am Markt/Bus 1 nach Bahnhof um 08:00 am 17.10.20/"));
+        assert!(departure.headway.is_none() && departure.alternatives.is_empty());
+
+        // the JSON contains the times like the live updates, but not the raw prediction
+        let json = serde_json::to_value(&model).unwrap();
+        assert_eq!(json["departures"][0]["headsign"], "Bahnhof");
+        assert_eq!(json["departures"][0]["scheduled_time"], day.and_hms(8, 0, 0).to_rfc3339());
+        assert!(json["departures"][0].get("prediction").is_none());
+    }
+
+    #[test]
+    fn test_find_alternatives() {
+        let journey_data = get_journey_data(&["17.10.20 07:50", "Am Markt"]);
+        let stop_data = match journey_data.components.last() {
+            Some(JourneyComponent::Stop(stop_data)) => stop_data.clone(),
+            other => panic!("expected a stop, got {:?}", other),
+        };
+        let schedule = &journey_data.schedule;
+        let day = Local.ymd(2020, 10, 17);
+        let mut prediction = get_test_prediction(1, day, EventType::Departure);
+        prediction.compute_meta_data(schedule.clone()).unwrap();
+        let departure = DepartureModel::new(prediction.clone(), &journey_data, &stop_data, schedule, &DelayStatistics::new(), &HolidayCalendar::default()).unwrap();
+
+        // variations of the departure with another trip, route, headsign or time
+        let candidate = |trip_id: &str, route_id: &str, headsign: &str, minutes: i64| {
+            let mut candidate = prediction.clone();
+            candidate.trip_id = Id::new(trip_id);
+            candidate.route_id = Id::new(route_id);
+            let md = candidate.meta_data.as_mut().unwrap();
+            md.headsign = headsign.to_string();
+            md.scheduled_time_absolute = md.scheduled_time_absolute + Duration::minutes(minutes);
+            candidate
+        };
+        let mut without_meta_data = candidate("t7", "r1", "Bahnhof", 5);
+        without_meta_data.meta_data = None;
+        let candidates = vec![
+            prediction.clone(),
+            candidate("t2", "r1", "Bahnhof", 30),
+            candidate("t3", "r2", "Markt", 20),
+            candidate("t4", "r2", "Bahnhof", 10),
+            candidate("t5", "r1", "Markt", 60),
+            candidate("t6", "r1", "Bahnhof", -10),
+            candidate("t8", "r1", "Bahnhof", ALTERNATIVES_MAX_MINUTES + 1),
+            without_meta_data,
+        ];
+
+        // the earliest two later departures of the same route or to the same headsign, but not the departure itself
+        let alternatives: Vec<&str> = find_alternatives(&departure, &candidates).iter().map(|alternative| alternative.trip_id.as_str()).collect();
+        assert_eq!(alternatives, vec!["t4", "t2"]);
+        assert!(find_alternatives(&departure, &candidates[..1]).is_empty());
+    }
+
+    #[test]
+    fn test_trip_page_model() {
+        let journey_data = get_journey_data(&["17.10.20 07:50", "Am Markt", "Bus 1 nach Bahnhof um 08:00 am 17.10.20"]);
+        let trip_data = match journey_data.components.last() {
+            Some(JourneyComponent::Trip(trip_data)) => trip_data.clone(),
+            other => panic!("expected a trip, got {:?}", other),
+        };
+        let day = Local.ymd(2020, 10, 17);
+        let departure = get_test_prediction(1, day, EventType::Departure);
+        assert!(TripPageModel::from_predictions(&journey_data, &trip_data, &journey_data.schedule, departure.clone(), Vec::new()).is_err());
+
+        let arrivals = vec![get_test_prediction(2, day, EventType::Arrival)];
+        let model = TripPageModel::from_predictions(&journey_data, &trip_data, &journey_data.schedule, departure, arrivals).unwrap();
+        assert_eq!(model.route_type, "Bus");
+        assert_eq!(model.route_name, "1");
+        assert_eq!(model.headsign, "Bahnhof");
+        assert!(model.accessible);
+        assert_eq!(model.stops.len(), 2);
+
+        let boarding = &model.stops[0];
+        assert_eq!(boarding.event_type, EventType::Departure);
+        assert_eq!(boarding.scheduled_time, day.and_hms(8, 0, 0));
+        assert_eq!(boarding.probability, Some(trip_data.start_prob * 100.0));
+        let alighting = &model.stops[1];
+        assert_eq!(alighting.stop_name, "Bahnhof");
+        assert_eq!(alighting.event_type, EventType::Arrival);
+        assert_eq!(alighting.scheduled_time, day.and_hms(8, 10, 0));
+        assert_eq!(alighting.probability, None);
+        assert!(alighting.can_alight);
+
+        let journey_arrival = model.journey_arrival.as_ref().unwrap();
+        assert_eq!(journey_arrival.stop_name, "Bahnhof");
+        assert!(model.min_time <= boarding.earliest_time.unwrap());
+        assert!(model.max_time >= journey_arrival.latest_time);
+    }
+}
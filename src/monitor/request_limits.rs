@@ -0,0 +1,189 @@
+use clap::ArgMatches;
+use simple_error::bail;
+use hyper::{Body, Request, Response, StatusCode};
+use hyper::header::HeaderValue;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+use crate::FnResult;
+use super::{Monitor, handle_request, generate_error_page};
+
+// how long a request may wait for one of the concurrent slots before it is rejected
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// if more clients are known, those that haven't sent requests for a while are forgotten
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Protects the database from clients that send too many requests: each client (by IP address)
+/// may only send a limited number of requests per minute, and only a limited number of requests
+/// are answered at the same time. Static files and curve images are cheap and don't count.
+pub struct RequestLimits {
+    /// requests per minute and client, 0 disables the rate limit
+    rate_limit: u32,
+    /// the buckets of the token bucket algorithm, with the time of their last update
+    clients: Mutex<HashMap<IpAddr, (f32, Instant)>>,
+    concurrency: Semaphore,
+    /// number of reverse proxies in front of the monitor that append to the X-Forwarded-For header,
+    /// 0 if the header is not used to identify clients
+    trusted_proxies: usize,
+}
+
+impl RequestLimits {
+    pub fn new(rate_limit: u32, max_concurrent_requests: usize, trusted_proxies: usize) -> Self {
+        RequestLimits {
+            rate_limit,
+            clients: Mutex::new(HashMap::new()),
+            concurrency: Semaphore::new(max_concurrent_requests),
+            trusted_proxies,
+        }
+    }
+
+    pub fn from_args(args: &ArgMatches) -> FnResult<Self> {
+        let trusted_proxies = if args.is_present("trust-forwarded-for") {
+            let trusted_proxies = args.value_of("trusted-proxies").unwrap().parse()?; // has a default value
+            if trusted_proxies < 1 {
+                bail!("Number of trusted proxies must be at least 1.");
+            }
+            trusted_proxies
+        } else {
+            0
+        };
+        Ok(Self::new(
+            args.value_of("rate-limit").unwrap().parse()?, // has a default value
+            args.value_of("max-concurrent-requests").unwrap().parse()?, // has a default value
+            trusted_proxies,
+        ))
+    }
+
+    fn get_client(&self, req: &Request<Body>, remote_addr: SocketAddr) -> IpAddr {
+        if self.trusted_proxies > 0 {
+            // Clients can send the header with any addresses, and each proxy appends the address it got
+            // the request from. So only the address that our outermost proxy has appended can be trusted.
+            let forwarded_for = req.headers().get("x-forwarded-for")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| {
+                    let addresses: Vec<&str> = value.split(',').collect();
+                    addresses.len().checked_sub(self.trusted_proxies).map(|index| addresses[index])
+                })
+                .and_then(|address| address.trim().parse().ok());
+            if let Some(address) = forwarded_for {
+                return address;
+            }
+        }
+        remote_addr.ip()
+    }
+
+    /// Takes a token from the client's bucket. If it's empty, returns the number of seconds
+    /// until the next token is available.
+    fn check_rate(&self, client: IpAddr, now: Instant) -> Result<(), u64> {
+        if self.rate_limit == 0 {
+            return Ok(());
+        }
+        let capacity = self.rate_limit as f32;
+        let tokens_per_second = capacity / 60.0;
+        let refill = |(tokens, updated): &mut (f32, Instant)| {
+            *tokens = f32::min(capacity, *tokens + now.saturating_duration_since(*updated).as_secs_f32() * tokens_per_second);
+            *updated = now;
+            *tokens
+        };
+
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() > MAX_TRACKED_CLIENTS {
+            // clients with a full bucket behave like new clients anyway
+            clients.retain(|_, bucket| refill(bucket) < capacity);
+        }
+        let bucket = clients.entry(client).or_insert((capacity, now));
+        if refill(bucket) >= 1.0 {
+            bucket.0 -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.0) * 60.0 / capacity).ceil() as u64)
+        }
+    }
+}
+
+// requests that don't need the database, and are needed many times for each page
+fn is_cheap_request(path: &str) -> bool {
+    ["/curve/", "/fonts/", "/favicons/", "/images/", "/help/", "/style.css", "/favicon.ico"].iter().any(|prefix| path.starts_with(prefix))
+}
+
+/// Answers the request within the limits and writes it to the access log.
+pub async fn handle_limited_request(req: Request<Body>, monitor: Arc<Monitor>, remote_addr: SocketAddr) -> Result<Response<Body>, Infallible> {
+    let started = Instant::now();
+    let method = req.method().clone();
+    let path = String::from(req.uri().path());
+    let limits = &monitor.request_limits;
+    let client = limits.get_client(&req, remote_addr);
+    let cheap = is_cheap_request(&path);
+
+    let response = if cheap {
+        handle_request(req, monitor.clone()).await?
+    } else if let Err(retry_after) = limits.check_rate(client, started) {
+        let mut response = generate_error_page(StatusCode::TOO_MANY_REQUESTS, "Too many requests, please try again later.").unwrap(); // can't fail, see generate_error_page
+        response.headers_mut().append(hyper::header::RETRY_AFTER, HeaderValue::from(retry_after));
+        response
+    } else {
+        match tokio::time::timeout(QUEUE_TIMEOUT, limits.concurrency.acquire()).await {
+            Ok(permit) => {
+                let response = handle_request(req, monitor.clone()).await?;
+                drop(permit);
+                response
+            },
+            Err(_) => generate_error_page(StatusCode::SERVICE_UNAVAILABLE, "The server is busy, please try again later.").unwrap(), // can't fail, see generate_error_page
+        }
+    };
+
+    let duration = started.elapsed().as_millis();
+    if cheap {
+        debug!("{} {} {} {} {} ms", client, method, path, response.status().as_u16(), duration);
+    } else {
+        info!("{} {} {} {} {} ms", client, method, path, response.status().as_u16(), duration);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_rate() {
+        let limits = RequestLimits::new(2, 1, 0);
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let other_client: IpAddr = "192.0.2.2".parse().unwrap();
+        let start = Instant::now();
+        assert_eq!(limits.check_rate(client, start), Ok(()));
+        assert_eq!(limits.check_rate(client, start), Ok(()));
+        assert_eq!(limits.check_rate(client, start), Err(30));
+        assert_eq!(limits.check_rate(other_client, start), Ok(()));
+        // one token every 30 seconds
+        assert_eq!(limits.check_rate(client, start + Duration::from_secs(31)), Ok(()));
+        assert!(limits.check_rate(client, start + Duration::from_secs(31)).is_err());
+
+        let unlimited = RequestLimits::new(0, 1, 0);
+        for _ in 0..100 {
+            assert_eq!(unlimited.check_rate(client, start), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_forwarded_for() {
+        // the client has sent a made-up address, the proxy has appended the real one
+        let req = Request::builder().header("X-Forwarded-For", "198.51.100.1, 203.0.113.7").body(Body::empty()).unwrap();
+        let remote_addr: SocketAddr = "10.0.0.1:4711".parse().unwrap();
+        assert_eq!(RequestLimits::new(1, 1, 0).get_client(&req, remote_addr), remote_addr.ip());
+        assert_eq!(RequestLimits::new(1, 1, 1).get_client(&req, remote_addr), "203.0.113.7".parse::<IpAddr>().unwrap());
+
+        // behind two proxies, the inner one appends the address of the outer one
+        let req = Request::builder().header("X-Forwarded-For", "198.51.100.1, 203.0.113.7, 10.0.0.2").body(Body::empty()).unwrap();
+        assert_eq!(RequestLimits::new(1, 1, 2).get_client(&req, remote_addr), "203.0.113.7".parse::<IpAddr>().unwrap());
+
+        // fewer addresses than proxies: the request didn't come through all of them
+        let req = Request::builder().header("X-Forwarded-For", "203.0.113.7").body(Body::empty()).unwrap();
+        assert_eq!(RequestLimits::new(1, 1, 2).get_client(&req, remote_addr), remote_addr.ip());
+    }
+}
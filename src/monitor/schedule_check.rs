@@ -0,0 +1,75 @@
+use chrono::Local;
+use gtfs_structures::Gtfs;
+use mysql::*;
+use mysql::prelude::*;
+use std::collections::BTreeSet;
+
+use crate::FnResult;
+use super::Monitor;
+
+// how many of the unknown trip_ids are named in the warning
+const MAX_LISTED_TRIP_IDS: usize = 10;
+
+/// Warns if current predictions in the database belong to trips that the schedule of the monitor
+/// doesn't know, e.g. because the monitor is pinned to another schedule than the importer uses.
+/// The departures of those trips would be missing from the pages without any other hint.
+pub fn check_schedule_consistency(monitor: &Monitor) -> FnResult<()> {
+    let schedule_filename = monitor.main.get_schedule_filename()?;
+    let schedule = monitor.main.get_schedule()?;
+    let mut conn = monitor.pool.get_conn()?;
+    let predicted_trips: Vec<(String, String)> = conn.exec(
+        r"SELECT DISTINCT
+            `trip_id`,
+            `schedule_file_name`
+        FROM
+            `predictions`
+        WHERE
+            `source`=:source AND
+            `prediction_max` > :now;",
+        params! {
+            "source" => &monitor.source,
+            "now" => Local::now().naive_local(),
+        },
+    )?;
+
+    let unknown_trips = find_unknown_trips(&schedule, &predicted_trips);
+    if unknown_trips.is_empty() {
+        debug!("All {} trips with current predictions are in the schedule '{}'.", predicted_trips.len(), schedule_filename);
+        return Ok(());
+    }
+    let unknown_trip_ids: BTreeSet<&str> = unknown_trips.iter().map(|(trip_id, _)| *trip_id).collect();
+    let other_schedules: BTreeSet<&str> = unknown_trips.iter().map(|(_, schedule_file_name)| *schedule_file_name).collect();
+    warn!(
+        "{} trips with current predictions are not in the schedule '{}', their departures won't be shown. The predictions were made with: {}. Unknown trip_ids: {}{}",
+        unknown_trip_ids.len(),
+        schedule_filename,
+        other_schedules.into_iter().collect::<Vec<_>>().join(", "),
+        unknown_trip_ids.iter().take(MAX_LISTED_TRIP_IDS).cloned().collect::<Vec<_>>().join(", "),
+        if unknown_trip_ids.len() > MAX_LISTED_TRIP_IDS { ", …" } else { "" },
+    );
+    Ok(())
+}
+
+// the pairs of trip_id and schedule file name whose trip_id is not in the schedule
+fn find_unknown_trips<'a>(schedule: &Gtfs, predicted_trips: &'a [(String, String)]) -> Vec<(&'a str, &'a str)> {
+    predicted_trips.iter()
+        .filter(|(trip_id, _)| !schedule.trips.contains_key(trip_id))
+        .map(|(trip_id, schedule_file_name)| (trip_id.as_str(), schedule_file_name.as_str()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::journey_data::tests::get_test_schedule;
+
+    #[test]
+    fn test_find_unknown_trips() {
+        let schedule = get_test_schedule();
+        let predicted_trips = vec![
+            (String::from("t1"), String::from("schedule/2020-10-01.zip")),
+            (String::from("t9"), String::from("schedule/2020-09-01.zip")),
+        ];
+        assert_eq!(find_unknown_trips(&schedule, &predicted_trips), vec![("t9", "schedule/2020-09-01.zip")]);
+    }
+}
@@ -0,0 +1,137 @@
+use chrono::{DateTime, Duration, Local};
+use dystonse_curves::{Curve, IrregularDynamicCurve, Tup};
+use gtfs_structures::{Gtfs, StopTime, Trip};
+use std::collections::HashMap;
+
+use crate::FnResult;
+use crate::time_util::date_and_time;
+use crate::types::{DbPrediction, EventType, Id, OriginType, PrecisionType, VehicleIdentifier};
+
+// Without statistics, nothing is known about the delays, so this curve is not derived from any recorded
+// data. It is a hand-picked placeholder, which assumes that vehicles are up to one minute early (a quarter
+// of them) and up to five minutes late, so that a page doesn't present a tight connection as certain. The
+// predictions are marked as schedule-based and `SuperGeneral` with a sample size of 0, so that they can't
+// be mistaken for predictions from the statistics.
+fn get_generic_curve() -> IrregularDynamicCurve<f32, f32> {
+    IrregularDynamicCurve::new(vec![Tup { x: -60.0, y: 0.0 }, Tup { x: 0.0, y: 0.25 }, Tup { x: 300.0, y: 1.0 }])
+}
+
+/// Creates the departures or arrivals at a stop between `min_time` and `max_time` from the schedule
+/// alone. They are used instead of the predictions while the monitor runs without statistics.
+pub fn get_schedule_predictions_for_stop(
+    schedule: &Gtfs,
+    event_type: EventType,
+    stop_id: &str,
+    min_time: DateTime<Local>,
+    max_time: DateTime<Local>,
+) -> FnResult<Vec<DbPrediction>> {
+    let curve = get_generic_curve();
+    let mut predictions = Vec::new();
+    // trips that run past midnight belong to the service day before
+    let first_day = min_time.date().pred();
+    let mut runs_by_service: HashMap<(&String, i64), bool> = HashMap::new();
+    for trip in schedule.trips.values() {
+        for stop_time in trip.stop_times.iter().filter(|stop_time| stop_time.stop.id == stop_id) {
+            let scheduled_time = match event_type.get_time_from_stop_time(stop_time) {
+                Some(time) => time as i32,
+                None => continue,
+            };
+            let mut day = first_day;
+            while day <= max_time.date() {
+                let time = date_and_time(&day, scheduled_time);
+                let offset = day.signed_duration_since(first_day).num_days();
+                if time + Duration::seconds(curve.max_x() as i64) > min_time && time + Duration::seconds(curve.min_x() as i64) < max_time
+                    && *runs_by_service.entry((&trip.service_id, offset)).or_insert_with(|| schedule.trip_days(&trip.service_id, day.naive_local()).contains(&0)) {
+                    predictions.push(make_prediction(trip, stop_time, day, event_type, scheduled_time, &curve));
+                }
+                day = day.succ();
+            }
+        }
+    }
+    Ok(predictions)
+}
+
+/// Creates the departures or arrivals of a vehicle from `start_sequence` on from the schedule alone.
+pub fn get_schedule_predictions_for_trip(
+    schedule: &Gtfs,
+    event_type: EventType,
+    vehicle_id: &VehicleIdentifier,
+    start_sequence: u16,
+) -> FnResult<Vec<DbPrediction>> {
+    let curve = get_generic_curve();
+    let trip = schedule.get_trip(&vehicle_id.trip_id)?;
+    let day = vehicle_id.start.service_day();
+    Ok(trip.stop_times.iter()
+        .filter(|stop_time| stop_time.stop_sequence >= start_sequence)
+        .filter_map(|stop_time| {
+            let scheduled_time = event_type.get_time_from_stop_time(stop_time)? as i32;
+            Some(make_prediction(trip, stop_time, day, event_type, scheduled_time, &curve))
+        })
+        .collect())
+}
+
+fn make_prediction(trip: &Trip, stop_time: &StopTime, day: chrono::Date<Local>, event_type: EventType, scheduled_time: i32, curve: &IrregularDynamicCurve<f32, f32>) -> DbPrediction {
+    let trip_start_time = trip.stop_times.first().and_then(|first| first.departure_time).unwrap_or(0);
+    DbPrediction {
+        route_id: Id::new(&trip.route_id),
+        trip_id: Id::new(&trip.id),
+        trip_start_date: day,
+        trip_start_time: Duration::seconds(trip_start_time as i64),
+        prediction_min: date_and_time(&day, scheduled_time + curve.min_x() as i32),
+        prediction_max: date_and_time(&day, scheduled_time + curve.max_x() as i32),
+        precision_type: PrecisionType::SuperGeneral,
+        origin_type: OriginType::Schedule,
+        sample_size: 0,
+        prediction_curve: curve.clone(),
+        stop_id: Id::new(&stop_time.stop.id),
+        stop_sequence: stop_time.stop_sequence as usize,
+        event_type,
+        meta_data: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::types::GtfsDateTime;
+    use super::super::journey_data::tests::get_test_schedule;
+
+    fn time(hour: u32, minute: u32) -> DateTime<Local> {
+        Local.ymd(2020, 10, 17).and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn test_get_schedule_predictions_for_stop() {
+        let schedule = get_test_schedule();
+        let predictions = get_schedule_predictions_for_stop(&schedule, EventType::Departure, "s1", time(7, 50), time(8, 30)).unwrap();
+        assert_eq!(predictions.len(), 1);
+        let prediction = &predictions[0];
+        assert_eq!(&*prediction.trip_id, "t1");
+        assert_eq!(prediction.trip_start_date, Local.ymd(2020, 10, 17));
+        // the range of the generic curve around the scheduled departure at 8:00
+        assert_eq!(prediction.prediction_min, time(7, 59));
+        assert_eq!(prediction.prediction_max, time(8, 5));
+        assert!(matches!(prediction.precision_type, PrecisionType::SuperGeneral));
+        assert!(matches!(prediction.origin_type, OriginType::Schedule));
+        assert_eq!(prediction.sample_size, 0);
+
+        // a departure that may still be late is included
+        assert_eq!(get_schedule_predictions_for_stop(&schedule, EventType::Departure, "s1", time(8, 4), time(9, 0)).unwrap().len(), 1);
+        assert!(get_schedule_predictions_for_stop(&schedule, EventType::Departure, "s1", time(8, 10), time(9, 0)).unwrap().is_empty());
+        assert!(get_schedule_predictions_for_stop(&schedule, EventType::Departure, "s2", time(7, 50), time(8, 30)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_get_schedule_predictions_for_trip() {
+        let schedule = get_test_schedule();
+        let vehicle_id = VehicleIdentifier { trip_id: Id::new("t1"), start: GtfsDateTime::new(Local.ymd(2020, 10, 17), 8 * 3600) };
+        let predictions = get_schedule_predictions_for_trip(&schedule, EventType::Arrival, &vehicle_id, 1).unwrap();
+        assert_eq!(predictions.len(), 2);
+        let predictions = get_schedule_predictions_for_trip(&schedule, EventType::Arrival, &vehicle_id, 2).unwrap();
+        assert_eq!(predictions.len(), 1);
+        assert_eq!(predictions[0].stop_sequence, 2);
+        assert_eq!(predictions[0].prediction_min, time(8, 9));
+        assert_eq!(predictions[0].prediction_max, time(8, 15));
+    }
+}
@@ -0,0 +1,174 @@
+use std::cell::RefCell;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+/// The stages of a request whose durations are measured separately. Everything else
+/// (mostly writing the HTML) counts as rendering.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Stage {
+    Database,
+    MetaData,
+    PngEncoding,
+}
+
+const STAGES: [Stage; 3] = [Stage::Database, Stage::MetaData, Stage::PngEncoding];
+
+impl Stage {
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    // used in the Server-Timing header
+    fn metric_name(self) -> &'static str {
+        match self {
+            Stage::Database => "db",
+            Stage::MetaData => "meta",
+            Stage::PngEncoding => "png",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Stage::Database => "database",
+            Stage::MetaData => "metadata",
+            Stage::PngEncoding => "PNG encoding",
+        }
+    }
+}
+
+/// How long the stages of one request took, and how often each of them was entered.
+#[derive(Default, Clone, Debug)]
+pub struct StageTimings {
+    pub total: Duration,
+    durations: [Duration; 3],
+    counts: [usize; 3],
+    /// whether one of the stages is being measured right now
+    in_stage: bool,
+}
+
+thread_local! {
+    // the timings of the request that is answered by this thread, if any
+    static CURRENT: RefCell<Option<StageTimings>> = RefCell::new(None);
+}
+
+/// Calls `f` and measures the stages of everything that it does in this thread. Requests that need
+/// the database are answered in a blocking thread of their own, so they don't interfere.
+pub fn measure_request<T>(f: impl FnOnce() -> T) -> (T, StageTimings) {
+    let start = Instant::now();
+    CURRENT.with(|current| *current.borrow_mut() = Some(StageTimings::default()));
+    let result = f();
+    let mut timings = CURRENT.with(|current| current.borrow_mut().take()).unwrap_or_default();
+    timings.total = start.elapsed();
+    (result, timings)
+}
+
+/// Starts measuring a stage, until the returned guard is dropped. Stages within other stages
+/// count for the outer stage only, and outside of `measure_request`, nothing is measured.
+pub fn start_stage(stage: Stage) -> StageGuard {
+    let measuring = CURRENT.with(|current| match current.borrow_mut().as_mut() {
+        Some(timings) if !timings.in_stage => {
+            timings.in_stage = true;
+            true
+        },
+        _ => false,
+    });
+    StageGuard { stage, start: Instant::now(), measuring }
+}
+
+pub struct StageGuard {
+    stage: Stage,
+    start: Instant,
+    measuring: bool,
+}
+
+impl Drop for StageGuard {
+    fn drop(&mut self) {
+        if !self.measuring {
+            return;
+        }
+        let elapsed = self.start.elapsed();
+        CURRENT.with(|current| {
+            if let Some(timings) = current.borrow_mut().as_mut() {
+                timings.add(self.stage, elapsed);
+                timings.in_stage = false;
+            }
+        });
+    }
+}
+
+impl StageTimings {
+    fn add(&mut self, stage: Stage, duration: Duration) {
+        self.durations[stage.index()] += duration;
+        self.counts[stage.index()] += 1;
+    }
+
+    pub fn get(&self, stage: Stage) -> Duration {
+        self.durations[stage.index()]
+    }
+
+    /// The time that was not spent in any of the measured stages.
+    pub fn rendering(&self) -> Duration {
+        STAGES.iter().fold(self.total, |rest, stage| rest.checked_sub(self.get(*stage)).unwrap_or_default())
+    }
+
+    /// Value for a `Server-Timing` header, which browsers show in their developer tools.
+    pub fn server_timing_header(&self) -> String {
+        let metric = |name: &str, duration: Duration| format!("{};dur={:.1}", name, duration.as_secs_f64() * 1000.0);
+        let mut metrics: Vec<String> = STAGES.iter().map(|stage| metric(stage.metric_name(), self.get(*stage))).collect();
+        metrics.push(metric("render", self.rendering()));
+        metrics.push(metric("total", self.total));
+        metrics.join(", ")
+    }
+
+    /// Logs the timings of a request as a warning if it took longer than `threshold`,
+    /// and otherwise only in verbose mode.
+    pub fn log(&self, path: &str, threshold: Duration) {
+        if self.total > threshold {
+            warn!("Slow request for {}: {}", path, self);
+        } else {
+            debug!("Request for {}: {}", path, self);
+        }
+    }
+}
+
+impl Display for StageTimings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ms in total", self.total.as_millis())?;
+        for stage in &STAGES {
+            write!(f, ", {} {} ms ({}×)", stage.description(), self.get(*stage).as_millis(), self.counts[stage.index()])?;
+        }
+        write!(f, ", rendering {} ms", self.rendering().as_millis())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nested_stages_count_once() {
+        let ((), timings) = measure_request(|| {
+            let _outer = start_stage(Stage::MetaData);
+            let _inner = start_stage(Stage::Database);
+            std::thread::sleep(Duration::from_millis(5));
+        });
+        assert_eq!(timings.counts, [0, 1, 0]);
+        assert!(timings.get(Stage::MetaData) >= Duration::from_millis(5));
+        assert_eq!(timings.get(Stage::Database), Duration::from_secs(0));
+        assert!(timings.rendering() <= timings.total - timings.get(Stage::MetaData));
+
+        // outside of a request, nothing is measured
+        drop(start_stage(Stage::Database));
+        assert!(CURRENT.with(|current| current.borrow().is_none()));
+    }
+
+    #[test]
+    fn test_server_timing_header() {
+        let mut timings = StageTimings::default();
+        timings.total = Duration::from_millis(100);
+        timings.add(Stage::Database, Duration::from_millis(60));
+        timings.add(Stage::PngEncoding, Duration::from_micros(2500));
+        assert_eq!(timings.server_timing_header(), "db;dur=60.0, meta;dur=0.0, png;dur=2.5, render;dur=37.5, total;dur=100.0");
+        assert_eq!(timings.to_string(), "100 ms in total, database 60 ms (1×), metadata 0 ms (0×), PNG encoding 2 ms (1×), rendering 37 ms");
+    }
+}
@@ -0,0 +1,216 @@
+use chrono::Local;
+use clap::ArgMatches;
+use regex::{Captures, Regex};
+use simple_error::bail;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::FnResult;
+use super::journey_data::{JourneyData, JourneyComponent};
+use super::page_models::StopPagePredictions;
+use super::{Monitor, escape_html, write_stop_page};
+use super::theme::Theme;
+
+// links to these paths also work on a static host, because the files are copied or written there
+const STATIC_PREFIXES: [&str; 9] = ["/style.css", "/curve/", "/fonts/", "/favicons/", "/images/", "/help/", "/impressum.html", "http://", "https://"];
+
+/// Renders the stop pages of some stops into static HTML files at a fixed interval, so that
+/// they can be hosted on any web server or shown on kiosk screens, without running the
+/// monitor publicly. The curve images and the assets of the website are written as well.
+pub struct StaticRenderer<'a> {
+    pub monitor: Arc<Monitor>,
+    pub args: &'a ArgMatches,
+}
+
+impl<'a> StaticRenderer<'a> {
+    pub fn run(&self) -> FnResult<()> {
+        let out_dir = Path::new(self.args.value_of("out").unwrap()); // already validated by clap
+        let stop_names: Vec<&str> = self.args.values_of("stops").unwrap().collect(); // already validated by clap
+        let interval = Duration::from_secs(self.args.value_of("interval").unwrap().parse()?); // has a default value
+
+        fs::create_dir_all(out_dir)?;
+        copy_dir(Path::new("web-assets"), out_dir)?;
+        // files of the theme replace those of web-assets/
+        if let Some(theme_dir) = self.monitor.theme.dir() {
+            copy_dir(theme_dir, out_dir)?;
+        }
+
+        loop {
+            let started = Instant::now();
+            self.render_all(out_dir, &stop_names, interval)?;
+            if self.args.is_present("once") {
+                return Ok(());
+            }
+            // if rendering took longer than the interval, the next round starts right away
+            if let Some(remaining) = interval.checked_sub(started.elapsed()) {
+                std::thread::sleep(remaining);
+            }
+        }
+    }
+
+    fn render_all(&self, out_dir: &Path, stop_names: &[&str], interval: Duration) -> FnResult<()> {
+        let mut rendered = Vec::new();
+        for stop_name in stop_names {
+            match self.render_stop(out_dir, stop_name, interval) {
+                Ok(file_name) => rendered.push((*stop_name, file_name)),
+                Err(e) => error!("Could not render page for {}: {}", stop_name, e),
+            }
+        }
+        write_file(&out_dir.join("index.html"), &render_index(&self.monitor.theme, &rendered)?)?;
+        // pages that could not be rendered again keep their previous version, which still needs its images
+        let mut image_names = HashSet::new();
+        for entry in fs::read_dir(out_dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "html") {
+                image_names.extend(get_image_names(&fs::read_to_string(&path)?));
+            }
+        }
+        let new_images = self.monitor.curve_images.write_to_dir(&out_dir.join("curve"), &image_names)?;
+        info!("Rendered {} of {} stop pages with {} new images.", rendered.len(), stop_names.len(), new_images);
+        Ok(())
+    }
+
+    // renders the page of one stop and returns the name of its file
+    fn render_stop(&self, out_dir: &Path, stop_name: &str, interval: Duration) -> FnResult<String> {
+        let journey = vec![Local::now().format("%d.%m.%y %H:%M").to_string(), String::from(stop_name)];
+        let journey_data = JourneyData::new(&journey, self.monitor.clone(), false, self.monitor.default_walk_profile, self.monitor.display_thresholds)?;
+        let stop_data = match journey_data.get_last_component() {
+            Some(JourneyComponent::Stop(stop_data)) => stop_data,
+            _ => bail!("Journey does not end with a stop."),
+        };
+        let predictions = StopPagePredictions::lookup(&self.monitor, &journey_data, &stop_data)?;
+        let html = write_stop_page(&self.monitor, &journey_data, &stop_data, predictions, Some(interval.as_secs()))?;
+        let html = remove_dynamic_parts(&String::from_utf8(html)?);
+        let file_name = format!("{}.html", get_file_name(stop_name));
+        write_file(&out_dir.join(&file_name), html.as_bytes())?;
+        Ok(file_name)
+    }
+}
+
+// an overview of all rendered pages
+fn render_index(theme: &Theme, rendered: &[(&str, String)]) -> FnResult<Vec<u8>> {
+    let mut w = Vec::new();
+    write!(&mut w, r#"
+    <html>
+        <head>
+            <title>{title}</title>
+            <link rel="stylesheet" href="/style.css">
+            {favicon_headers}
+            <meta name=viewport content="width=device-width, initial-scale=1">
+        </head>
+        <body>
+        <h1>Abfahrten</h1>
+        <ul>"#,
+        title = theme.page_title("Abfahrten"),
+        favicon_headers = theme.head_elements(),
+    )?;
+    for (stop_name, file_name) in rendered {
+        write!(&mut w, r#"
+            <li><a href="{file_name}">{stop_name}</a></li>"#,
+            file_name = escape_html(file_name),
+            stop_name = escape_html(stop_name),
+        )?;
+    }
+    write!(&mut w, r#"
+        </ul>
+        </body>
+    </html>"#)?;
+    Ok(w)
+}
+
+// Removes the links and forms that lead to pages of the monitor, e.g. trip pages or the departure filter,
+// because they don't exist on a static host. The links keep their text, the forms are removed completely.
+fn remove_dynamic_parts(html: &str) -> String {
+    lazy_static! {
+        static ref LINK: Regex = Regex::new(r#"<a href="([^"]*)""#).unwrap(); // can't fail because our hard-coded regex is known to be ok
+        static ref FORM: Regex = Regex::new(r"(?s)<form\b.*?</form>").unwrap(); // can't fail because our hard-coded regex is known to be ok
+    }
+    let html = LINK.replace_all(html, |captures: &Captures| {
+        if STATIC_PREFIXES.iter().any(|prefix| captures[1].starts_with(prefix)) {
+            captures[0].to_string()
+        } else {
+            String::from("<a")
+        }
+    });
+    FORM.replace_all(&html, "").into_owned()
+}
+
+// names of the curve images that are referenced by a page
+fn get_image_names(html: &str) -> Vec<String> {
+    lazy_static! {
+        static ref IMAGE: Regex = Regex::new(r"/curve/([0-9a-f]+)\.png").unwrap(); // can't fail because our hard-coded regex is known to be ok
+    }
+    IMAGE.captures_iter(html).map(|captures| captures[1].to_string()).collect()
+}
+
+// a file name for the page of a stop, which doesn't need to be escaped in URLs
+fn get_file_name(stop_name: &str) -> String {
+    let mut file_name = String::with_capacity(stop_name.len());
+    for c in stop_name.chars() {
+        match c {
+            'ä' => file_name.push_str("ae"),
+            'ö' => file_name.push_str("oe"),
+            'ü' => file_name.push_str("ue"),
+            'Ä' => file_name.push_str("Ae"),
+            'Ö' => file_name.push_str("Oe"),
+            'Ü' => file_name.push_str("Ue"),
+            'ß' => file_name.push_str("ss"),
+            c if c.is_ascii_alphanumeric() || c == '-' => file_name.push(c),
+            _ => file_name.push('_'),
+        }
+    }
+    file_name
+}
+
+// writes the file via a temporary file, so that web servers never serve half-written pages
+fn write_file(path: &Path, content: &[u8]) -> FnResult<()> {
+    let temp_path = path.with_extension("tmp");
+    fs::write(&temp_path, content)?;
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+// copies the static files of the website, so that the pages look the same as in the monitor
+fn copy_dir(source: &Path, target: &Path) -> FnResult<()> {
+    fs::create_dir_all(target)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let target_path = target.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target_path)?;
+        } else {
+            fs::copy(entry.path(), target_path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_file_name() {
+        assert_eq!(get_file_name("Bremen Hauptbahnhof"), "Bremen_Hauptbahnhof");
+        assert_eq!(get_file_name("Am Brill/Kreuzung"), "Am_Brill_Kreuzung");
+        assert_eq!(get_file_name("Bürgerweide"), "Buergerweide");
+        assert_eq!(get_file_name("Straße (Süd)"), "Strasse__Sued_");
+    }
+
+    #[test]
+    fn test_remove_dynamic_parts() {
+        let html = r#"<link rel="stylesheet" href="/style.css"><a href="/help/" class="help-link">Hilfe</a>
+            <a href="/17.10.20 12:00/Domsheide/Tram 4 nach Arsten um 12:05 am 17.10.20/" class="trip">Tram 4</a>
+            <form method="get" action="/17.10.20 12:00/Domsheide/">
+                <input type="submit" value="Filtern">
+            </form><img src="/curve/0123456789abcdef.png">"#;
+        assert_eq!(remove_dynamic_parts(html), r#"<link rel="stylesheet" href="/style.css"><a href="/help/" class="help-link">Hilfe</a>
+            <a class="trip">Tram 4</a>
+            <img src="/curve/0123456789abcdef.png">"#);
+        assert_eq!(get_image_names(html), vec![String::from("0123456789abcdef")]);
+    }
+}
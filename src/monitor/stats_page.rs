@@ -0,0 +1,258 @@
+use hyper::{Body, Response};
+use hyper::header::HeaderValue;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::FnResult;
+use crate::types::{EventType, RouteData, RouteVariantData, TimeSlot, WeatherCondition};
+use super::{Monitor, route_type_to_str, bad_request, escape_html, url_element};
+use super::theme::Theme;
+
+/// Key figures about the statistics of one route variant, or of all variants of a route.
+#[derive(Default)]
+struct CoverageSummary {
+    variant_count: usize,
+    curve_set_count: usize,
+    /// sum of the sample sizes of all general delay curves, i.e. the number of recorded events
+    sample_size: u32,
+    /// number of curve sets per entry of TimeSlot::TIME_SLOTS_WITH_DEFAULT
+    curve_sets_per_time_slot: Vec<usize>,
+    /// number of curve sets per time slot that would exist if every pair of stops had data for both event types
+    possible_curve_sets_per_time_slot: usize,
+}
+
+impl CoverageSummary {
+    fn for_variant(variant_data: &RouteVariantData) -> Self {
+        let mut summary = CoverageSummary::default();
+        summary.add_variant(variant_data);
+        summary
+    }
+
+    fn for_route(route_data: &RouteData) -> Self {
+        let mut summary = CoverageSummary::default();
+        for variant_data in route_data.variants.values() {
+            summary.add_variant(variant_data);
+        }
+        summary
+    }
+
+    fn add_variant(&mut self, variant_data: &RouteVariantData) {
+        if self.curve_sets_per_time_slot.is_empty() {
+            self.curve_sets_per_time_slot = vec![0; TimeSlot::TIME_SLOTS_WITH_DEFAULT.len()];
+        }
+        self.variant_count += 1;
+        let stop_count = variant_data.stop_ids.len();
+        self.possible_curve_sets_per_time_slot += stop_count * stop_count.saturating_sub(1) / 2 * EventType::TYPES.len();
+        for et in &EventType::TYPES {
+            self.curve_set_count += variant_data.curve_sets[**et].len();
+            self.sample_size += variant_data.general_delay[**et].values().map(|curve_data| curve_data.sample_size).sum::<u32>();
+            // curve sets for specific weather conditions would count the same pair of stops twice
+            for key in variant_data.curve_sets[**et].keys().filter(|key| key.weather == WeatherCondition::Unknown) {
+                if let Some(index) = TimeSlot::TIME_SLOTS_WITH_DEFAULT.iter().position(|ts| **ts == key.time_slot) {
+                    self.curve_sets_per_time_slot[index] += 1;
+                }
+            }
+        }
+    }
+
+    // share of possible curve sets that exist for the time slot, between 0 and 1
+    fn coverage(&self, time_slot_index: usize) -> f32 {
+        if self.possible_curve_sets_per_time_slot == 0 {
+            return 0.0;
+        }
+        self.curve_sets_per_time_slot[time_slot_index] as f32 / self.possible_curve_sets_per_time_slot as f32
+    }
+
+    fn write_time_slot_cells(&self, w: &mut Vec<u8>) -> FnResult<()> {
+        for i in 0..TimeSlot::TIME_SLOTS_WITH_DEFAULT.len() {
+            let coverage = self.coverage(i);
+            write!(w, r#"<td class="coverage" style="background-color: rgba(0, 128, 0, {alpha:.2});" title="{count} Curve Sets">{percent:.0}&nbsp;%</td>"#,
+                alpha = coverage,
+                count = self.curve_sets_per_time_slot[i],
+                percent = coverage * 100.0,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the beginning of a page. The title is plain text, it will be escaped.
+pub(super) fn write_header(w: &mut Vec<u8>, theme: &Theme, title: &str) -> FnResult<()> {
+    write!(w, r#"
+    <html>
+        <head>
+            <title>{page_title}</title>
+            <link rel="stylesheet" href="/style.css">
+
+            {favicon_headers}
+
+        </head>
+        <body class="monitorbody stats">
+            <h1>{title}</h1>"#,
+        page_title = theme.page_title(title),
+        title = escape_html(title),
+        favicon_headers = theme.head_elements(),
+    )?;
+    Ok(())
+}
+
+fn write_time_slot_header_cells(w: &mut Vec<u8>) -> FnResult<()> {
+    for ts in TimeSlot::TIME_SLOTS_WITH_DEFAULT.iter() {
+        write!(w, "<th>{}</th>", ts.description)?;
+    }
+    Ok(())
+}
+
+pub(super) fn finish_response(mut w: Vec<u8>) -> FnResult<Response<Body>> {
+    write!(&mut w, r#"
+        </body>
+    </html>"#)?;
+    let mut response = Response::new(Body::from(w));
+    response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+    Ok(response)
+}
+
+/// Lists all routes for which statistics exist, with their sample sizes and the coverage
+/// of curve sets per time slot. This helps to decide where more data needs to be collected.
+pub fn generate_stats_overview(monitor: &Arc<Monitor>) -> FnResult<Response<Body>> {
+    let schedule = monitor.main.get_schedule()?;
+    let stats = monitor.get_stats();
+
+    let mut routes: Vec<(String, String, &RouteData)> = stats.specific.iter().map(|(route_id, route_data)| {
+        match schedule.get_route(route_id) {
+            Ok(route) => (route.short_name.clone(), String::from(route_type_to_str(route.route_type)), route_data),
+            Err(_) => (String::from("?"), String::from("unbekannt"), route_data),
+        }
+    }).collect();
+    routes.sort_by(|a, b| (&a.1, &a.0).cmp(&(&b.1, &b.0)));
+
+    let mut w = Vec::new();
+    write_header(&mut w, &monitor.theme, "Statistiken pro Linie")?;
+    write!(&mut w, r#"
+            <p>{} Linien mit spezifischen Statistiken. Die Prozentwerte geben an, für welchen Anteil der möglichen Haltestellen-Paare es Curve Sets im jeweiligen Zeitraum gibt.</p>
+            <table class="stats-table">
+                <tr><th>Typ</th><th>Linie</th><th>route_id</th><th>Varianten</th><th>Curve Sets</th><th>Messwerte</th>"#,
+        routes.len(),
+    )?;
+    write_time_slot_header_cells(&mut w)?;
+    write!(&mut w, "</tr>")?;
+
+    for (route_name, route_type, route_data) in routes {
+        let summary = CoverageSummary::for_route(route_data);
+        write!(&mut w, r#"
+                <tr><td>{route_type}</td><td><a href="/stats/{route_id_url}/">{route_name}</a></td><td>{route_id}</td><td>{variants}</td><td>{curve_sets}</td><td>{samples}</td>"#,
+            route_type = route_type,
+            route_id_url = url_element(&route_data.route_id),
+            route_name = escape_html(&route_name),
+            route_id = escape_html(&route_data.route_id),
+            variants = summary.variant_count,
+            curve_sets = summary.curve_set_count,
+            samples = summary.sample_size,
+        )?;
+        summary.write_time_slot_cells(&mut w)?;
+        write!(&mut w, "</tr>")?;
+    }
+    write!(&mut w, "
+            </table>")?;
+
+    finish_response(w)
+}
+
+/// Shows the coverage for each variant of a route.
+pub fn generate_route_stats_page(monitor: &Arc<Monitor>, route_id: &str) -> FnResult<Response<Body>> {
+    let schedule = monitor.main.get_schedule()?;
+    let stats = monitor.get_stats();
+    let route_data = match stats.specific.get(route_id) {
+        Some(route_data) => route_data,
+        None => return bad_request(&format!("No statistics for route_id {}.", route_id)),
+    };
+    let route_name = schedule.get_route(route_id).map(|route| route.short_name.clone()).unwrap_or_else(|_| String::from("?"));
+
+    let mut variants: Vec<(&u64, &RouteVariantData)> = route_data.variants.iter().collect();
+    variants.sort_by_key(|(route_variant, _)| **route_variant);
+
+    let mut w = Vec::new();
+    write_header(&mut w, &monitor.theme, &format!("Statistiken für Linie {} (route_id {})", route_name, route_id))?;
+    write!(&mut w, r#"
+            <p><a href="/stats/">Zurück zur Übersicht</a></p>
+            <table class="stats-table">
+                <tr><th>route_variant</th><th>von</th><th>nach</th><th>Haltestellen</th><th>Curve Sets</th><th>Messwerte</th>"#)?;
+    write_time_slot_header_cells(&mut w)?;
+    write!(&mut w, "</tr>")?;
+
+    for (route_variant, variant_data) in variants {
+        let summary = CoverageSummary::for_variant(variant_data);
+        let stop_name = |stop_id: Option<&String>| stop_id.and_then(|id| schedule.stops.get(id)).map(|stop| stop.name.clone()).unwrap_or_else(|| String::from("?"));
+        write!(&mut w, r#"
+                <tr><td><a href="/stats/{route_id_url}/{route_variant}/">{route_variant}</a></td><td>{first_stop}</td><td>{last_stop}</td><td>{stops}</td><td>{curve_sets}</td><td>{samples}</td>"#,
+            route_id_url = url_element(route_id),
+            route_variant = route_variant,
+            first_stop = escape_html(&stop_name(variant_data.stop_ids.first())),
+            last_stop = escape_html(&stop_name(variant_data.stop_ids.last())),
+            stops = variant_data.stop_ids.len(),
+            curve_sets = summary.curve_set_count,
+            samples = summary.sample_size,
+        )?;
+        summary.write_time_slot_cells(&mut w)?;
+        write!(&mut w, "</tr>")?;
+    }
+    write!(&mut w, "
+            </table>")?;
+
+    finish_response(w)
+}
+
+/// Shows the sample sizes per stop and the number of curve sets per time slot for one route variant.
+pub fn generate_route_variant_stats_page(monitor: &Arc<Monitor>, route_id: &str, route_variant: &str) -> FnResult<Response<Body>> {
+    let schedule = monitor.main.get_schedule()?;
+    let stats = monitor.get_stats();
+    let variant_data = match route_variant.parse::<u64>().ok().and_then(|rv| stats.specific.get(route_id)?.variants.get(&rv)) {
+        Some(variant_data) => variant_data,
+        None => return bad_request(&format!("No statistics for route_id {} and route_variant {}.", route_id, route_variant)),
+    };
+    let route_name = schedule.get_route(route_id).map(|route| route.short_name.clone()).unwrap_or_else(|_| String::from("?"));
+
+    let mut w = Vec::new();
+    write_header(&mut w, &monitor.theme, &format!("Statistiken für Linie {} (route_id {}, route_variant {})", route_name, route_id, route_variant))?;
+    write!(&mut w, r#"
+            <p><a href="/stats/{route_id_url}/">Zurück zur Linie</a></p>
+            <h2>Curve Sets pro Zeitraum</h2>
+            <table class="stats-table">
+                <tr><th>Ereignis</th>"#,
+        route_id_url = url_element(route_id),
+    )?;
+    write_time_slot_header_cells(&mut w)?;
+    write!(&mut w, "</tr>")?;
+    for et in &EventType::TYPES {
+        write!(&mut w, "
+                <tr><td>{:?}</td>", **et)?;
+        for ts in TimeSlot::TIME_SLOTS_WITH_DEFAULT.iter() {
+            let curve_sets: Vec<u32> = variant_data.curve_sets[**et].iter().filter(|(key, _)| key.time_slot == **ts && key.weather == WeatherCondition::Unknown).map(|(_, csd)| csd.sample_size).collect();
+            write!(&mut w, r#"<td title="{samples} Messwerte">{count}</td>"#,
+                samples = curve_sets.iter().sum::<u32>(),
+                count = curve_sets.len(),
+            )?;
+        }
+        write!(&mut w, "</tr>")?;
+    }
+    write!(&mut w, r#"
+            </table>
+            <h2>Messwerte pro Haltestelle</h2>
+            <table class="stats-table">
+                <tr><th>Index</th><th>Haltestelle</th><th>stop_id</th><th>Ankunft</th><th>Abfahrt</th></tr>"#)?;
+    for (stop_index, stop_id) in variant_data.stop_ids.iter().enumerate() {
+        let sample_size = |et: EventType| variant_data.general_delay[et].get(&(stop_index as u32)).map(|curve_data| curve_data.sample_size).unwrap_or(0);
+        write!(&mut w, "
+                <tr><td>{index}</td><td>{name}</td><td>{stop_id}</td><td>{arrival}</td><td>{departure}</td></tr>",
+            index = stop_index,
+            name = escape_html(&schedule.stops.get(stop_id).map(|stop| stop.name.clone()).unwrap_or_else(|| String::from("?"))),
+            stop_id = escape_html(stop_id),
+            arrival = sample_size(EventType::Arrival),
+            departure = sample_size(EventType::Departure),
+        )?;
+    }
+    write!(&mut w, "
+            </table>")?;
+
+    finish_response(w)
+}
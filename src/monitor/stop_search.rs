@@ -0,0 +1,242 @@
+use chrono::{Duration, Local};
+use gtfs_structures::Gtfs;
+use hyper::{Body, Response};
+use hyper::header::HeaderValue;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::FnResult;
+use super::Monitor;
+
+// number of results if the request does not specify a limit, and the maximum that may be requested
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 50;
+// number of days for which the departures are counted to rank the stops
+const POPULARITY_DAYS: i64 = 7;
+
+/// A stop name with everything that is needed to find and rank it. All stops with the same name
+/// are combined into one entry, because the monitor handles them as one stop.
+pub struct StopSearchEntry {
+    pub name: String,
+    /// the words of the name, folded with `fold`
+    words: Vec<String>,
+    /// all stops with this name, with their id and coordinates
+    pub stops: Vec<StopLocation>,
+    /// average number of departures per day at all stops with this name, used for ranking
+    pub departures_per_day: f32,
+}
+
+#[derive(Serialize, Clone)]
+pub struct StopLocation {
+    pub id: String,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+}
+
+/// One element of the autocomplete response if details are requested. `label` and `value`
+/// are the names that jQuery UI's autocomplete expects.
+#[derive(Serialize)]
+struct DetailedResult<'a> {
+    label: &'a str,
+    value: &'a str,
+    departures_per_day: f32,
+    stops: &'a [StopLocation],
+}
+
+/// Finds stops by (parts of) their names. Each search term must match a word of the stop name,
+/// either as a prefix, as a substring, or with one typo. Case and diacritics are ignored.
+pub struct StopSearch {
+    entries: Vec<StopSearchEntry>,
+}
+
+impl StopSearch {
+    pub fn new(entries: Vec<StopSearchEntry>) -> Self {
+        StopSearch { entries }
+    }
+
+    pub fn from_schedule(schedule: &Gtfs) -> FnResult<Self> {
+        // count the departures of the coming week, so that stops on weekday and weekend routes are ranked fairly
+        let today = Local::today().naive_local();
+        let mut departures_by_name: HashMap<&str, usize> = HashMap::new();
+        for day in 0..POPULARITY_DAYS {
+            for trip in schedule.trips_for_date(today + Duration::days(day))? {
+                for stop_time in trip.stop_times.iter().filter(|st| st.departure_time.is_some()) {
+                    *departures_by_name.entry(&stop_time.stop.name).or_insert(0) += 1;
+                }
+            }
+        }
+
+        let mut stops_by_name: HashMap<&str, Vec<StopLocation>> = HashMap::new();
+        for stop in schedule.stops.values() {
+            stops_by_name.entry(&stop.name).or_default().push(StopLocation {
+                id: stop.id.clone(),
+                lat: stop.latitude,
+                lon: stop.longitude,
+            });
+        }
+
+        let entries = stops_by_name.into_iter().map(|(name, stops)| StopSearchEntry::new(
+            name,
+            stops,
+            *departures_by_name.get(name).unwrap_or(&0) as f32 / POPULARITY_DAYS as f32,
+        )).collect();
+        Ok(Self::new(entries))
+    }
+
+    /// Returns the best matching entries, best first.
+    pub fn search(&self, term: &str, limit: usize) -> Vec<&StopSearchEntry> {
+        let terms: Vec<String> = fold(term).split_whitespace().map(String::from).collect();
+        if terms.is_empty() {
+            return Vec::new();
+        }
+        let mut results: Vec<(u32, &StopSearchEntry)> = self.entries.iter()
+            .filter_map(|entry| entry.score(&terms).map(|score| (score, entry)))
+            .collect();
+        results.sort_by(|(a_score, a), (b_score, b)| {
+            b_score.cmp(a_score)
+                .then(b.departures_per_day.partial_cmp(&a.departures_per_day).unwrap_or(std::cmp::Ordering::Equal))
+                .then(a.name.cmp(&b.name))
+        });
+        results.into_iter().take(limit).map(|(_, entry)| entry).collect()
+    }
+}
+
+impl StopSearchEntry {
+    pub fn new(name: &str, stops: Vec<StopLocation>, departures_per_day: f32) -> Self {
+        StopSearchEntry {
+            name: String::from(name),
+            words: fold(name).split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()).map(String::from).collect(),
+            stops,
+            departures_per_day,
+        }
+    }
+
+    // Sum of the scores of all terms, or None if any term does not match.
+    fn score(&self, terms: &[String]) -> Option<u32> {
+        terms.iter().map(|term| self.words.iter().map(|word| score_word(word, term)).max().flatten()).sum()
+    }
+}
+
+// how well a search term matches a single word of a stop name
+fn score_word(word: &str, term: &str) -> Option<u32> {
+    if word == term {
+        Some(4)
+    } else if word.starts_with(term) {
+        Some(3)
+    } else if word.contains(term) {
+        Some(2)
+    } else if term.chars().count() >= 4 && levenshtein(&word.chars().take(term.chars().count()).collect::<String>(), term) <= 1 {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+/// Converts text to lower case and replaces letters with diacritics by their base letters,
+/// so that e.g. "Straße" and "strasse" or "Zürich" and "zurich" are considered equal.
+pub fn fold(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    for c in text.chars().flat_map(char::to_lowercase) {
+        match c {
+            'ä' | 'á' | 'à' | 'â' | 'ã' | 'å' => folded.push('a'),
+            'ö' | 'ó' | 'ò' | 'ô' | 'õ' | 'ø' => folded.push('o'),
+            'ü' | 'ú' | 'ù' | 'û' => folded.push('u'),
+            'é' | 'è' | 'ê' | 'ë' => folded.push('e'),
+            'í' | 'ì' | 'î' | 'ï' => folded.push('i'),
+            'ç' => folded.push('c'),
+            'ñ' => folded.push('n'),
+            'ß' => folded.push_str("ss"),
+            _ => folded.push(c),
+        }
+    }
+    folded
+}
+
+// number of single character edits that are needed to turn a into b
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let cost = if a_char == *b_char { 0 } else { 1 };
+            current.push((previous[j] + cost).min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Serves the `/autocomplete` endpoint, which is documented in `web-assets/openapi.yaml`.
+pub fn generate_autocomplete(monitor: &Arc<Monitor>, params: HashMap<String, String>) -> FnResult<Response<Body>>  {
+    // TODO check if schedule is available instantly. If not, return a please-wait-message to the client.
+    let stop_search = monitor.get_stop_search()?;
+    let term = params.get("term").map(|term| term.as_str()).unwrap_or("");
+    debug!("Search term: {}", term);
+    let limit = params.get("limit").and_then(|limit| limit.parse().ok()).unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+    let details = params.get("details").map_or(false, |details| details == "true" || details == "1");
+
+    let results = stop_search.search(term, limit);
+
+    let mut w = Vec::new();
+    if details {
+        let detailed_results: Vec<DetailedResult> = results.iter().map(|entry| DetailedResult {
+            label: &entry.name,
+            value: &entry.name,
+            departures_per_day: entry.departures_per_day,
+            stops: &entry.stops,
+        }).collect();
+        serde_json::to_writer(&mut w, &detailed_results)?;
+    } else {
+        let names: Vec<&str> = results.iter().map(|entry| entry.name.as_str()).collect();
+        serde_json::to_writer(&mut w, &names)?;
+    }
+    let mut response = Response::new(Body::from(w));
+    response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, departures_per_day: f32) -> StopSearchEntry {
+        StopSearchEntry::new(name, Vec::new(), departures_per_day)
+    }
+
+    fn names(search: &StopSearch, term: &str) -> Vec<String> {
+        search.search(term, 10).iter().map(|entry| entry.name.clone()).collect()
+    }
+
+    #[test]
+    fn test_fold() {
+        assert_eq!(fold("Bremen Hauptbahnhof"), "bremen hauptbahnhof");
+        assert_eq!(fold("Große Straße"), "grosse strasse");
+        assert_eq!(fold("Zürich Flughafen"), "zurich flughafen");
+    }
+
+    #[test]
+    fn test_search() {
+        let search = StopSearch::new(vec![
+            entry("Bremen Hauptbahnhof", 2000.0),
+            entry("Bremerhaven Hauptbahnhof", 500.0),
+            entry("Bremen Domsheide", 1500.0),
+            entry("Große Straße", 10.0),
+            entry("Am Brementor", 5.0),
+            entry("Bremen", 1.0),
+        ]);
+        // exact matches rank above prefix matches, then by popularity
+        assert_eq!(names(&search, "brem"), vec!["Bremen Hauptbahnhof", "Bremen Domsheide", "Bremerhaven Hauptbahnhof", "Am Brementor", "Bremen"]);
+        // "Brementor" starts with the term, while "Bremerhaven" only matches it with one typo
+        assert_eq!(names(&search, "bremen"), vec!["Bremen Hauptbahnhof", "Bremen Domsheide", "Bremen", "Am Brementor", "Bremerhaven Hauptbahnhof"]);
+        assert_eq!(names(&search, "bahnhof"), vec!["Bremen Hauptbahnhof", "Bremerhaven Hauptbahnhof"]);
+        assert_eq!(names(&search, "bremen hbf"), Vec::<String>::new());
+        assert_eq!(names(&search, "bremen haupt"), vec!["Bremen Hauptbahnhof", "Bremerhaven Hauptbahnhof"]);
+        // diacritics and typos
+        assert_eq!(names(&search, "grosse strasse"), vec!["Große Straße"]);
+        assert_eq!(names(&search, "domsheite"), vec!["Bremen Domsheide"]);
+        assert!(names(&search, "   ").is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+use clap::ArgMatches;
+use hyper_staticfile::Static;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use simple_error::bail;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::FnResult;
+use super::escape_html;
+
+const DEFAULT_SITE_NAME: &str = "Dystonse ÖPNV-Reiseplaner";
+const DEFAULT_PRIMARY_COLOR: &str = "#00aba9";
+const DEFAULT_ICON_COLOR: &str = "#5bbad5";
+const DEFAULT_BACKGROUND_COLOR: &str = "#ffffff";
+
+// name of the file with the template variables in the theme directory
+const THEME_FILE_NAME: &str = "theme.json";
+
+/// The contents of theme.json. All entries are optional.
+#[derive(Deserialize, Default)]
+struct ThemeFile {
+    site_name: Option<String>,
+    agency_name: Option<String>,
+    primary_color: Option<String>,
+    background_color: Option<String>,
+}
+
+/// The branding of one deployment of the monitor, for agencies that host it themselves.
+///
+/// Files in the theme directory replace the files with the same path in web-assets/, e.g. style.css,
+/// images/logo.svg or the favicons. theme.json in the theme directory sets the template variables.
+/// Everything that the theme doesn't set falls back to the Dystonse branding.
+pub struct Theme {
+    /// shown in the title of every page
+    pub site_name: String,
+    /// the agency that offers the monitor, shown on the start page if set
+    pub agency_name: Option<String>,
+    /// CSS colors, only set if they are valid, so that they can be written into the pages as they are
+    pub primary_color: Option<String>,
+    pub background_color: Option<String>,
+    dir: Option<PathBuf>,
+    static_server: Option<Static>,
+}
+
+impl Theme {
+    /// Reads the theme from the directory given by the `theme-dir` argument, or returns the default theme.
+    pub fn from_args(args: &ArgMatches) -> FnResult<Self> {
+        let dir = match args.value_of("theme-dir") {
+            Some(dir) => PathBuf::from(dir),
+            None => return Ok(Self::default()),
+        };
+        if !dir.is_dir() {
+            bail!("Theme directory {} does not exist.", dir.display());
+        }
+        let theme_file: ThemeFile = match fs::read_to_string(dir.join(THEME_FILE_NAME)) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(_) => ThemeFile::default(),
+        };
+        info!("Using the theme from {}.", dir.display());
+        Ok(Theme {
+            site_name: theme_file.site_name.unwrap_or_else(|| String::from(DEFAULT_SITE_NAME)),
+            agency_name: theme_file.agency_name,
+            primary_color: Self::checked_color(theme_file.primary_color),
+            background_color: Self::checked_color(theme_file.background_color),
+            static_server: Some(Static::new(dir.clone())),
+            dir: Some(dir),
+        })
+    }
+
+    // colors that can't be written into the pages safely are ignored, so that the defaults are used
+    fn checked_color(color: Option<String>) -> Option<String> {
+        let color = color?;
+        if is_valid_color(&color) {
+            Some(color)
+        } else {
+            warn!("Ignoring the color {:?} of the theme, only hex colors like #00aba9 and color names are supported.", color);
+            None
+        }
+    }
+
+    /// The elements of the head of every page: the favicons and the colors of the theme.
+    pub fn head_elements(&self) -> String {
+        let mut head = format!(r##"
+<link rel="apple-touch-icon" sizes="180x180" href="/favicons/apple-touch-icon.png?v=m2ndzBjkKM">
+<link rel="icon" type="image/png" sizes="32x32" href="/favicons/favicon-32x32.png?v=m2ndzBjkKM">
+<link rel="icon" type="image/png" sizes="16x16" href="/favicons/favicon-16x16.png?v=m2ndzBjkKM">
+<link rel="manifest" href="/favicons/site.webmanifest?v=m2ndzBjkKM">
+<link rel="mask-icon" href="/favicons/safari-pinned-tab.svg?v=m2ndzBjkKM" color="{icon_color}">
+<link rel="shortcut icon" href="/favicons/favicon.ico?v=m2ndzBjkKM">
+<meta name="msapplication-TileColor" content="{tile_color}">
+<meta name="msapplication-config" content="/favicons/browserconfig.xml?v=m2ndzBjkKM">
+<meta name="theme-color" content="{theme_color}">
+"##,
+            icon_color = self.primary_color.as_deref().unwrap_or(DEFAULT_ICON_COLOR),
+            tile_color = self.primary_color.as_deref().unwrap_or(DEFAULT_PRIMARY_COLOR),
+            theme_color = self.background_color.as_deref().unwrap_or(DEFAULT_BACKGROUND_COLOR),
+        );
+        // style.css uses these variables, with the default colors as fallback
+        let mut variables = String::new();
+        if let Some(color) = &self.primary_color {
+            variables.push_str(&format!(" --theme-primary-color: {};", color));
+        }
+        if let Some(color) = &self.background_color {
+            variables.push_str(&format!(" --theme-background-color: {};", color));
+        }
+        if !variables.is_empty() {
+            head.push_str(&format!("<style>html, body {{{} }}</style>\n", variables));
+        }
+        head
+    }
+
+    /// The title of a page, with the name of the site. The page title is plain text, it will be escaped.
+    pub fn page_title(&self, title: &str) -> String {
+        format!("{} | {}", escape_html(title), escape_html(&self.site_name))
+    }
+
+    /// Returns the server for the static files of the theme, if the theme has a file for the path of the request.
+    pub fn get_static_server(&self, request_path: &str) -> Option<&Static> {
+        let path = self.get_file_path(request_path)?;
+        if path.is_file() {
+            self.static_server.as_ref()
+        } else {
+            None
+        }
+    }
+
+    // the path of the file in the theme directory for the path of a request, which must not leave the directory
+    fn get_file_path(&self, request_path: &str) -> Option<PathBuf> {
+        let decoded = percent_decode_str(request_path).decode_utf8().ok()?;
+        let relative = Path::new(decoded.trim_start_matches('/'));
+        if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+            return None;
+        }
+        Some(self.dir.as_ref()?.join(relative))
+    }
+
+    /// The theme directory, whose files are written over those of web-assets/ when pages are rendered into files.
+    pub fn dir(&self) -> Option<&Path> {
+        self.dir.as_deref()
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            site_name: String::from(DEFAULT_SITE_NAME),
+            agency_name: None,
+            primary_color: None,
+            background_color: None,
+            dir: None,
+            static_server: None,
+        }
+    }
+}
+
+fn is_valid_color(color: &str) -> bool {
+    if let Some(hex) = color.strip_prefix('#') {
+        [3, 4, 6, 8].contains(&hex.len()) && hex.chars().all(|c| c.is_ascii_hexdigit())
+    } else {
+        !color.is_empty() && color.len() <= 20 && color.chars().all(|c| c.is_ascii_alphabetic())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_color() {
+        assert!(is_valid_color("#00aba9"));
+        assert!(is_valid_color("#FFF"));
+        assert!(is_valid_color("darkblue"));
+        assert!(!is_valid_color("#12345"));
+        assert!(!is_valid_color("red; } body { display: none"));
+        assert!(!is_valid_color("</style>"));
+        assert!(!is_valid_color(""));
+    }
+
+    #[test]
+    fn test_default_theme() {
+        let theme = Theme::default();
+        let head = theme.head_elements();
+        assert!(head.contains(r#"<meta name="msapplication-TileColor" content="#00aba9">"#));
+        assert!(head.contains(r#"color="#5bbad5""#));
+        assert!(!head.contains("<style>"));
+        assert_eq!(theme.page_title("Bremen <Hbf>"), "Bremen &lt;Hbf&gt; | Dystonse ÖPNV-Reiseplaner");
+        // without a theme directory, all files come from web-assets/
+        assert!(theme.get_static_server("/style.css").is_none());
+    }
+
+    #[test]
+    fn test_theme_colors_and_paths() {
+        let theme = Theme {
+            primary_color: Some(String::from("#c00000")),
+            dir: Some(PathBuf::from("theme")),
+            ..Theme::default()
+        };
+        let head = theme.head_elements();
+        assert!(head.contains(r#"<meta name="msapplication-TileColor" content="#c00000">"#));
+        assert!(head.contains("<style>html, body { --theme-primary-color: #c00000; }</style>"));
+        assert!(head.contains(r#"<meta name="theme-color" content="#ffffff">"#));
+
+        assert_eq!(theme.get_file_path("/images/logo%20neu.svg"), Some(PathBuf::from("theme/images/logo neu.svg")));
+        assert_eq!(theme.get_file_path("/../secret.txt"), None);
+        assert_eq!(theme.get_file_path("/images/%2E%2E/%2E%2E/secret.txt"), None);
+    }
+}
@@ -31,6 +31,36 @@ impl TimeCurve {
         1.0 - total_miss_prob 
     }
 
+    /// Returns the distribution of this arrival of a vehicle for passengers who board it after `transfer`,
+    /// if it leaves their boarding stop at `departure`. The delays of a vehicle along its trip are strongly
+    /// correlated, so each percentile of the arrival is assumed to belong to the same percentile of the
+    /// departure, and is weighted with the probability to reach that departure. If the departure can't be
+    /// reached at all, the arrival is returned unchanged.
+    pub fn given_transfer(&self, transfer: &TimeCurve, departure: &TimeCurve) -> TimeCurve {
+        let mut points = vec![Tup {x: self.curve.min_x(), y: 0.0}];
+        let mut sum = 0.0;
+        for percentile in 0..100 {
+            let departure_time_abs = departure.typed_x_at_y((percentile as f32 + 0.5) / 100.0);
+            sum += transfer.typed_y_at_x(departure_time_abs) / 100.0;
+            let x = self.curve.x_at_y((percentile + 1) as f32 / 100.0);
+            // the points need to be strictly increasing in x
+            if x > points.last().unwrap().x {
+                points.push(Tup {x, y: sum});
+            } else {
+                points.last_mut().unwrap().y = sum;
+            }
+        }
+        if sum <= 0.0 || points.len() < 2 {
+            return self.clone();
+        }
+        for point in &mut points {
+            point.y /= sum;
+        }
+        let mut curve = IrregularDynamicCurve::<f32, f32>::new(points);
+        curve.simplify(0.01);
+        TimeCurve::new(curve, self.ref_time)
+    }
+
     pub fn add_duration_curve(&self, duration: &IrregularDynamicCurve<f32, f32>) -> TimeCurve {
         // domain of the resulting curve:
         let mut min_n : i32 = (self.curve.x_at_y(0.01) + duration.x_at_y(0.01)).floor() as i32;
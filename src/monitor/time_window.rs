@@ -0,0 +1,139 @@
+use chrono::{DateTime, Duration, Local, NaiveDateTime, TimeZone, Timelike};
+use std::collections::HashMap;
+
+use crate::FnResult;
+use super::bad_request;
+
+// format of the `from` and `to` query parameters, as sent by <input type="datetime-local">
+const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M";
+
+// a longer time span would make the page too slow and the timeline unreadable
+const MAX_WINDOW_MINUTES: i64 = 6 * 60;
+
+/// Moves the time span of the departures on a stop page away from the one that is derived from the arrival
+/// at the stop. It is given by the query parameters `from` and `to` (e.g. `from=2020-10-01T14:30`). If only
+/// one of them is given, the time span keeps its default length.
+#[derive(Debug, Clone, Default, PartialEq, Hash)]
+pub struct TimeWindow {
+    pub from: Option<DateTime<Local>>,
+    pub to: Option<DateTime<Local>>,
+}
+
+impl TimeWindow {
+    pub fn from_query_params(query_params: &HashMap<String, String>) -> FnResult<Self> {
+        let parse = |name: &str| -> FnResult<Option<DateTime<Local>>> {
+            match query_params.get(name).map(|value| value.trim()).filter(|value| !value.is_empty()) {
+                Some(value) => match NaiveDateTime::parse_from_str(value, TIME_FORMAT).ok().and_then(|time| Local.from_local_datetime(&time).earliest()) {
+                    Some(time) => Ok(Some(time)),
+                    None => bad_request(&format!("Invalid time '{}' in parameter '{}', expected e.g. 2020-10-01T14:30.", value, name)),
+                },
+                None => Ok(None),
+            }
+        };
+        let window = TimeWindow {
+            from: parse("from")?,
+            to: parse("to")?,
+        };
+        if let (Some(from), Some(to)) = (window.from, window.to) {
+            if to <= from {
+                return bad_request("The end of the time span ('to') must be after its start ('from').");
+            }
+            if to.signed_duration_since(from).num_minutes() > MAX_WINDOW_MINUTES {
+                return bad_request(&format!("The time span may be at most {} hours long.", MAX_WINDOW_MINUTES / 60));
+            }
+        }
+        Ok(window)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.from.is_none() && self.to.is_none()
+    }
+
+    /// Returns the start, length (in minutes) and end of the time span, given the default start and length
+    /// for the page. The start is rounded down and the end is rounded up to the next multiple of 5 minutes.
+    pub fn apply(&self, default_min_time: DateTime<Local>, default_len_time: i64) -> (DateTime<Local>, i64, DateTime<Local>) {
+        let (min_time, max_time) = match (self.from, self.to) {
+            (Some(from), Some(to)) => (from, to),
+            (Some(from), None) => (from, from + Duration::minutes(default_len_time)),
+            (None, Some(to)) => (to - Duration::minutes(default_len_time), to),
+            (None, None) => return (default_min_time, default_len_time, default_min_time + Duration::minutes(default_len_time)),
+        };
+        let min_time = round_down(min_time);
+        let len_time = (max_time.signed_duration_since(min_time).num_seconds() + 299) / 300 * 5;
+        (min_time, len_time, min_time + Duration::minutes(len_time))
+    }
+
+    /// The window of `len_time` minutes that ends at `min_time`, for the link to earlier departures.
+    pub fn earlier(min_time: DateTime<Local>, len_time: i64) -> Self {
+        TimeWindow { from: Some(min_time - Duration::minutes(len_time)), to: Some(min_time) }
+    }
+
+    /// The window of `len_time` minutes that starts at `max_time`, for the link to later departures.
+    pub fn later(max_time: DateTime<Local>, len_time: i64) -> Self {
+        TimeWindow { from: Some(max_time), to: Some(max_time + Duration::minutes(len_time)) }
+    }
+
+    /// Returns the query parameters of the window (without `?`), so that it can be passed on in links.
+    pub fn to_query(&self) -> String {
+        let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+        if let Some(from) = self.from {
+            serializer.append_pair("from", &from.format(TIME_FORMAT).to_string());
+        }
+        if let Some(to) = self.to {
+            serializer.append_pair("to", &to.format(TIME_FORMAT).to_string());
+        }
+        serializer.finish()
+    }
+}
+
+// rounds to the previous multiple of 5 minutes
+fn round_down(time: DateTime<Local>) -> DateTime<Local> {
+    (time - Duration::minutes(time.minute() as i64 % 5)).with_second(0).unwrap().with_nanosecond(0).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(query: &str) -> FnResult<TimeWindow> {
+        TimeWindow::from_query_params(&url::form_urlencoded::parse(query.as_bytes()).into_owned().collect())
+    }
+
+    fn time(hour: u32, minute: u32) -> DateTime<Local> {
+        Local.ymd(2020, 10, 1).and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn test_parse_time_window() {
+        let empty = window("").unwrap();
+        assert!(empty.is_empty());
+        assert_eq!(empty.to_query(), "");
+
+        let both = window("from=2020-10-01T14:32&to=2020-10-01T15:10").unwrap();
+        assert_eq!(both, TimeWindow { from: Some(time(14, 32)), to: Some(time(15, 10)) });
+        assert_eq!(both.to_query(), "from=2020-10-01T14%3A32&to=2020-10-01T15%3A10");
+        assert_eq!(window(&both.to_query()).unwrap(), both);
+
+        assert!(window("from=14:32").is_err());
+        assert!(window("from=2020-10-01T15:10&to=2020-10-01T14:32").is_err());
+        assert!(window("from=2020-10-01T08:00&to=2020-10-01T20:00").is_err());
+    }
+
+    #[test]
+    fn test_apply_time_window() {
+        let default_min = time(14, 0);
+        assert_eq!(TimeWindow::default().apply(default_min, 40), (default_min, 40, time(14, 40)));
+
+        let both = TimeWindow { from: Some(time(14, 32)), to: Some(time(15, 11)) };
+        assert_eq!(both.apply(default_min, 40), (time(14, 30), 45, time(15, 15)));
+
+        let from = TimeWindow { from: Some(time(16, 0)), to: None };
+        assert_eq!(from.apply(default_min, 40), (time(16, 0), 40, time(16, 40)));
+
+        let to = TimeWindow { from: None, to: Some(time(16, 0)) };
+        assert_eq!(to.apply(default_min, 40), (time(15, 20), 40, time(16, 0)));
+
+        assert_eq!(TimeWindow::earlier(time(14, 0), 40), TimeWindow { from: Some(time(13, 20)), to: Some(time(14, 0)) });
+        assert_eq!(TimeWindow::later(time(14, 40), 40), TimeWindow { from: Some(time(14, 40)), to: Some(time(15, 20)) });
+    }
+}
@@ -0,0 +1,257 @@
+use dystonse_curves::Curve;
+use geo::prelude::*;
+use geo::point;
+use gtfs_structures::{Gtfs, Stop};
+use hyper::{Body, Response};
+use hyper::header::HeaderValue;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::FnResult;
+use super::{Monitor, bad_request, escape_html, url_element};
+use super::journey_data::WalkProfile;
+use super::stats_page::{write_header, finish_response};
+
+// defaults and limits for the query parameters
+const DEFAULT_MINUTES: u32 = 10;
+const MAX_MINUTES: u32 = 30;
+const DEFAULT_PROBABILITY_PERCENT: u32 = 80;
+
+// nobody walks faster than this (in m/s, see the sprint speeds of the walk profiles), so stops
+// that are further away than this speed times the time budget don't need to be looked at
+const MAX_WALK_SPEED: f32 = 4.0;
+
+/// A stop (all stops with the same name combined) that can be reached on foot within the time budget.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct ReachableStop {
+    pub name: String,
+    pub lat: f64,
+    pub lon: f64,
+    /// airline distance in meters between the closest stops of both names
+    pub distance: f32,
+    /// median walk time in seconds
+    pub median_seconds: f32,
+    /// probability to arrive within the time budget
+    pub probability: f32,
+}
+
+#[derive(Serialize)]
+struct WalkIsochrone<'a> {
+    stop_name: &'a str,
+    lat: f64,
+    lon: f64,
+    walk_profile: &'static str,
+    budget_seconds: u32,
+    min_probability: f32,
+    stops: &'a [ReachableStop],
+}
+
+/// Serves `/walk/<stop>/data`, which is documented in `web-assets/openapi.yaml`: all stops that can be reached
+/// on foot from the stop within `?minutes=` with at least the probability `?probability=` (in percent),
+/// according to the walk time distribution of the walk profile.
+pub fn generate_walk_isochrone_data(monitor: &Arc<Monitor>, stop_name: &str, query_params: &HashMap<String, String>, walk_profile: WalkProfile) -> FnResult<Response<Body>> {
+    let (minutes, probability_percent) = parse_walk_params(query_params)?;
+    let schedule = monitor.main.get_schedule()?;
+    let (lat, lon) = match get_center(&get_stops_by_name(&schedule, stop_name)) {
+        Some(center) => center,
+        None => return bad_request(&format!("No stop named {} with known coordinates.", stop_name)),
+    };
+    let budget_seconds = minutes * 60;
+    let min_probability = probability_percent as f32 / 100.0;
+    let stops = get_reachable_stops(&schedule, stop_name, walk_profile, budget_seconds as f32, min_probability);
+
+    let mut w = Vec::new();
+    serde_json::to_writer(&mut w, &WalkIsochrone {
+        stop_name,
+        lat,
+        lon,
+        walk_profile: walk_profile.name(),
+        budget_seconds,
+        min_probability,
+        stops: &stops,
+    })?;
+    let mut response = Response::new(Body::from(w));
+    response.headers_mut().append(hyper::header::CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
+    Ok(response)
+}
+
+/// Serves `/walk/<stop>`, a map of the stops that can be reached on foot from the stop, with the same parameters
+/// as `/walk/<stop>/data`. Each stop is colored by the probability to reach it within the time budget.
+pub fn generate_walk_isochrone_page(monitor: &Arc<Monitor>, stop_name: &str, query_params: &HashMap<String, String>, walk_profile: WalkProfile) -> FnResult<Response<Body>> {
+    let (minutes, probability_percent) = parse_walk_params(query_params)?;
+
+    let mut w = Vec::new();
+    write_header(&mut w, &monitor.theme, &format!("Zu Fuß von {}", stop_name))?;
+    write!(&mut w, r#"
+            <link rel="stylesheet" href="https://unpkg.com/leaflet@1.7.1/dist/leaflet.css">
+            <script src="https://unpkg.com/leaflet@1.7.1/dist/leaflet.js"></script>
+            <form class="map-options" method="get" action="/walk/{stop_url}">
+                <label>Zeit: <input type="number" name="minutes" min="1" max="{max_minutes}" value="{minutes}"> Minuten</label>
+                <label>Wahrscheinlichkeit: <input type="number" name="probability" min="1" max="99" value="{probability}"> %</label>
+                <input type="submit" value="Anzeigen">
+            </form>
+            <div id="map" class="delay-map"></div>
+            <p class="map-legend">Haltestellen, die mit dem Gehtempo „{walk_profile}“ mit mindestens {probability} % Wahrscheinlichkeit innerhalb von {minutes} Minuten erreicht werden. Grün: ab 95 %, gelb: ab 80 %, orange: darunter.</p>
+            <script>
+                var map = L.map('map');
+                L.tileLayer('https://{{s}}.tile.openstreetmap.org/{{z}}/{{x}}/{{y}}.png', {{
+                    attribution: '&copy; <a href="https://www.openstreetmap.org/copyright">OpenStreetMap</a>-Mitwirkende'
+                }}).addTo(map);
+                function color(probability) {{
+                    return probability >= 0.95 ? '#31a354' : probability >= 0.8 ? '#e6c700' : '#fd8d3c';
+                }}
+                fetch(window.location.pathname.replace(/\/$/, '') + '/data' + window.location.search).then(function(response) {{
+                    return response.json();
+                }}).then(function(data) {{
+                    var bounds = [[data.lat, data.lon]];
+                    L.circleMarker([data.lat, data.lon], {{ radius: 8, color: '#3182bd', fillOpacity: 0.8 }})
+                        .bindPopup(document.createTextNode(data.stop_name)).addTo(map);
+                    data.stops.forEach(function(stop) {{
+                        var link = document.createElement('a');
+                        link.href = '/stop-by-name?start=' + encodeURIComponent(stop.name);
+                        link.textContent = stop.name;
+                        var popup = document.createElement('div');
+                        popup.appendChild(link);
+                        popup.appendChild(document.createElement('br'));
+                        popup.appendChild(document.createTextNode(Math.round(stop.distance) + ' m, etwa ' + Math.ceil(stop.median_seconds / 60) + ' min, ' + Math.round(stop.probability * 100) + ' % rechtzeitig'));
+                        L.circleMarker([stop.lat, stop.lon], {{ radius: 6, color: color(stop.probability), fillOpacity: 0.8 }})
+                            .bindPopup(popup).addTo(map);
+                        bounds.push([stop.lat, stop.lon]);
+                    }});
+                    map.fitBounds(bounds, {{ maxZoom: 17 }});
+                }});
+            </script>"#,
+        stop_url = url_element(stop_name),
+        max_minutes = MAX_MINUTES,
+        minutes = minutes,
+        probability = probability_percent,
+        walk_profile = escape_html(walk_profile.german_name()),
+    )?;
+
+    finish_response(w)
+}
+
+// the time budget in minutes and the minimum probability in percent
+fn parse_walk_params(query_params: &HashMap<String, String>) -> FnResult<(u32, u32)> {
+    let minutes = match query_params.get("minutes").map(|minutes| minutes.trim().parse::<u32>()) {
+        Some(Ok(minutes)) if minutes >= 1 && minutes <= MAX_MINUTES => minutes,
+        Some(_) => return bad_request(&format!("Parameter 'minutes' must be a number between 1 and {}.", MAX_MINUTES)),
+        None => DEFAULT_MINUTES,
+    };
+    let probability = match query_params.get("probability").map(|probability| probability.trim().parse::<u32>()) {
+        Some(Ok(probability)) if probability >= 1 && probability <= 99 => probability,
+        Some(_) => return bad_request("Parameter 'probability' must be a number between 1 and 99."),
+        None => DEFAULT_PROBABILITY_PERCENT,
+    };
+    Ok((minutes, probability))
+}
+
+fn get_stops_by_name<'s>(schedule: &'s Gtfs, stop_name: &str) -> Vec<&'s Stop> {
+    schedule.stops.values().filter(|stop| stop.name == stop_name).map(|stop| stop.as_ref()).collect()
+}
+
+// center of the coordinates of the stops, if any of them has coordinates
+fn get_center(stops: &[&Stop]) -> Option<(f64, f64)> {
+    let coordinates: Vec<(f64, f64)> = stops.iter().filter_map(|stop| get_location(stop)).collect();
+    if coordinates.is_empty() {
+        return None;
+    }
+    let lat = coordinates.iter().map(|(lat, _)| lat).sum::<f64>() / coordinates.len() as f64;
+    let lon = coordinates.iter().map(|(_, lon)| lon).sum::<f64>() / coordinates.len() as f64;
+    Some((lat, lon))
+}
+
+fn get_location(stop: &Stop) -> Option<(f64, f64)> {
+    Some((stop.latitude?, stop.longitude?))
+}
+
+/// Returns the stops (by name) that can be reached from any stop named `stop_name` within `budget_seconds`
+/// with at least `min_probability`, the closest first.
+pub fn get_reachable_stops(schedule: &Gtfs, stop_name: &str, walk_profile: WalkProfile, budget_seconds: f32, min_probability: f32) -> Vec<ReachableStop> {
+    let origins: Vec<(f64, f64)> = get_stops_by_name(schedule, stop_name).iter().filter_map(|stop| get_location(stop)).collect();
+    let max_distance = budget_seconds * MAX_WALK_SPEED;
+
+    // shortest distance from any of the origins, and the stops with this name, per stop name
+    let mut candidates: HashMap<&str, (f32, Vec<&Stop>)> = HashMap::new();
+    for stop in schedule.stops.values().filter(|stop| stop.name != stop_name) {
+        let location = match get_location(stop) {
+            Some(location) => location,
+            None => continue,
+        };
+        let distance = origins.iter().map(|origin| get_distance(*origin, location)).fold(f32::INFINITY, f32::min);
+        if distance > max_distance {
+            continue;
+        }
+        let candidate = candidates.entry(stop.name.as_str()).or_insert_with(|| (f32::INFINITY, Vec::new()));
+        candidate.0 = f32::min(candidate.0, distance);
+        candidate.1.push(stop);
+    }
+
+    let mut reachable: Vec<ReachableStop> = candidates.into_iter().filter_map(|(name, (distance, stops))| {
+        let walk_time = walk_profile.get_walk_time(distance);
+        let probability = get_probability_within(&walk_time, budget_seconds);
+        if probability < min_probability {
+            return None;
+        }
+        let (lat, lon) = get_center(&stops)?;
+        Some(ReachableStop {
+            name: String::from(name),
+            lat,
+            lon,
+            distance,
+            median_seconds: walk_time.x_at_y(0.5),
+            probability,
+        })
+    }).collect();
+    reachable.sort_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap().then_with(|| a.name.cmp(&b.name))); // distances are never NaN
+    reachable
+}
+
+// airline distance in meters between two locations given as (lat, lon)
+fn get_distance(a: (f64, f64), b: (f64, f64)) -> f32 {
+    point!(x: a.1, y: a.0).haversine_distance(&point!(x: b.1, y: b.0)) as f32
+}
+
+// probability that a walk with the walk time distribution takes at most budget_seconds
+fn get_probability_within<C: Curve<f32, f32>>(walk_time: &C, budget_seconds: f32) -> f32 {
+    if budget_seconds >= walk_time.max_x() {
+        1.0
+    } else if budget_seconds <= walk_time.min_x() {
+        0.0
+    } else {
+        walk_time.y_at_x(budget_seconds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::BadRequest;
+
+    #[test]
+    fn test_parse_walk_params() {
+        let mut params = HashMap::new();
+        assert_eq!(parse_walk_params(&params).unwrap(), (DEFAULT_MINUTES, DEFAULT_PROBABILITY_PERCENT));
+        params.insert(String::from("minutes"), String::from("5"));
+        params.insert(String::from("probability"), String::from("95"));
+        assert_eq!(parse_walk_params(&params).unwrap(), (5, 95));
+        params.insert(String::from("minutes"), String::from("60"));
+        assert!(parse_walk_params(&params).unwrap_err().is::<BadRequest>());
+        params.insert(String::from("minutes"), String::from("5"));
+        params.insert(String::from("probability"), String::from("100"));
+        assert!(parse_walk_params(&params).unwrap_err().is::<BadRequest>());
+    }
+
+    #[test]
+    fn test_walk_probability() {
+        let walk_time = WalkProfile::Normal.get_walk_time(300.0);
+        assert_eq!(get_probability_within(&walk_time, 0.0), 0.0);
+        assert_eq!(get_probability_within(&walk_time, 3600.0), 1.0);
+        let median = walk_time.x_at_y(0.5);
+        assert!((get_probability_within(&walk_time, median) - 0.5).abs() < 0.05);
+        // about 111 m
+        assert!((get_distance((53.0760, 8.8070), (53.0770, 8.8070)) - 111.2).abs() < 1.0);
+    }
+}
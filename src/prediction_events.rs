@@ -0,0 +1,171 @@
+//! Tells other processes (like the monitor) which predictions have been written by the importer,
+//! so that they don't have to poll the database for changes. The events are sent via Redis pub/sub,
+//! which is only available with the `redis-events` feature and a `--redis-url`. Otherwise, no events
+//! are sent and the monitor falls back to polling.
+
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+use crate::FnResult;
+
+#[cfg(feature = "redis-events")]
+use std::time::Duration;
+
+// how long the subscriber waits before it connects again after an error
+#[cfg(feature = "redis-events")]
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Sent after a batch of predictions has been written into the database.
+#[derive(Serialize, Deserialize, Default, Debug, PartialEq)]
+pub struct PredictionsUpdated {
+    pub stop_ids: BTreeSet<String>,
+    pub trip_ids: BTreeSet<String>,
+}
+
+impl PredictionsUpdated {
+    pub fn is_empty(&self) -> bool {
+        self.stop_ids.is_empty() && self.trip_ids.is_empty()
+    }
+
+    /// Whether predictions for one of the stops have been updated.
+    pub fn affects_any_stop(&self, stop_ids: &[String]) -> bool {
+        stop_ids.iter().any(|stop_id| self.stop_ids.contains(stop_id))
+    }
+}
+
+/// The Redis channel on which the events of a source are sent.
+pub fn get_channel(source: &str) -> String {
+    format!("dystonse:predictions:{}", source)
+}
+
+/// Collects the stops and trips of the predictions that are written by the importer, and publishes
+/// them as one `PredictionsUpdated` event after each batch.
+pub struct PredictionEventPublisher {
+    #[cfg(feature = "redis-events")]
+    client: redis::Client,
+    /// kept between the events, and opened again after errors
+    #[cfg(feature = "redis-events")]
+    connection: Mutex<Option<redis::Connection>>,
+    channel: String,
+    pending: Mutex<PredictionsUpdated>,
+}
+
+impl PredictionEventPublisher {
+    /// Returns a publisher if a Redis URL is given, otherwise no events are sent.
+    #[cfg(feature = "redis-events")]
+    pub fn from_args(args: &ArgMatches, source: &str) -> FnResult<Option<Self>> {
+        match args.value_of("redis-url") {
+            Some(url) => Ok(Some(PredictionEventPublisher {
+                client: redis::Client::open(url)?,
+                connection: Mutex::new(None),
+                channel: get_channel(source),
+                pending: Mutex::new(PredictionsUpdated::default()),
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Without the `redis-events` feature, events can't be sent.
+    #[cfg(not(feature = "redis-events"))]
+    pub fn from_args(_args: &ArgMatches, _source: &str) -> FnResult<Option<Self>> {
+        Ok(None)
+    }
+
+    /// Remembers that a prediction for the stop and trip has been written.
+    pub fn add(&self, stop_id: &str, trip_id: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        if !pending.stop_ids.contains(stop_id) {
+            pending.stop_ids.insert(String::from(stop_id));
+        }
+        if !pending.trip_ids.contains(trip_id) {
+            pending.trip_ids.insert(String::from(trip_id));
+        }
+    }
+
+    /// Publishes the stops and trips since the last call, if there are any. The predictions are
+    /// in the database already, so errors are only logged.
+    pub fn publish(&self) {
+        let event = std::mem::take(&mut *self.pending.lock().unwrap());
+        if event.is_empty() {
+            return;
+        }
+        match self.send(&event) {
+            Ok(()) => debug!("Published updated predictions for {} stops and {} trips.", event.stop_ids.len(), event.trip_ids.len()),
+            Err(e) => warn!("Could not publish updated predictions: {}", e),
+        }
+    }
+
+    #[cfg(feature = "redis-events")]
+    fn send(&self, event: &PredictionsUpdated) -> FnResult<()> {
+        let message = serde_json::to_string(event)?;
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_none() {
+            *connection = Some(self.client.get_connection()?);
+        }
+        let result = redis::cmd("PUBLISH").arg(&self.channel).arg(&message).query::<i64>(connection.as_mut().unwrap());
+        if result.is_err() {
+            // the next event tries again with a new connection
+            *connection = None;
+        }
+        result?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "redis-events"))]
+    fn send(&self, _event: &PredictionsUpdated) -> FnResult<()> {
+        simple_error::bail!("Events for channel {} can't be sent without the redis-events feature.", self.channel)
+    }
+}
+
+/// Receives the events of the source in a thread of its own and calls `handler` for each of them.
+/// If the connection fails, it is opened again after a few seconds, so events may be lost, and
+/// receivers should still look for changes from time to time.
+#[cfg(feature = "redis-events")]
+pub fn subscribe<F>(url: &str, source: &str, mut handler: F) -> FnResult<()>
+    where F: FnMut(PredictionsUpdated) + Send + 'static
+{
+    let client = redis::Client::open(url)?;
+    let channel = get_channel(source);
+    std::thread::spawn(move || loop {
+        if let Err(e) = receive_events(&client, &channel, &mut handler) {
+            warn!("Lost subscription to updated predictions, trying again in {} seconds: {}", RECONNECT_DELAY.as_secs(), e);
+        }
+        std::thread::sleep(RECONNECT_DELAY);
+    });
+    Ok(())
+}
+
+#[cfg(feature = "redis-events")]
+fn receive_events<F: FnMut(PredictionsUpdated)>(client: &redis::Client, channel: &str, handler: &mut F) -> FnResult<()> {
+    let mut connection = client.get_connection()?;
+    let mut pubsub = connection.as_pubsub();
+    pubsub.subscribe(channel)?;
+    info!("Subscribed to updated predictions on channel {}.", channel);
+    loop {
+        let payload: String = pubsub.get_message()?.get_payload()?;
+        match serde_json::from_str(&payload) {
+            Ok(event) => handler(event),
+            Err(e) => warn!("Received invalid event about updated predictions: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_round_trip() {
+        let mut event = PredictionsUpdated::default();
+        assert!(event.is_empty());
+        event.stop_ids.insert(String::from("000008000001"));
+        event.trip_ids.insert(String::from("1234567"));
+        let parsed: PredictionsUpdated = serde_json::from_str(&serde_json::to_string(&event).unwrap()).unwrap();
+        assert_eq!(parsed, event);
+        assert!(parsed.affects_any_stop(&[String::from("other"), String::from("000008000001")]));
+        assert!(!parsed.affects_any_stop(&[String::from("other")]));
+        assert_eq!(get_channel("vbn"), "dystonse:predictions:vbn");
+    }
+}
@@ -0,0 +1,109 @@
+use chrono::{DateTime, Local, NaiveDateTime};
+use chrono::offset::TimeZone;
+use serde::{Serialize, Deserialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read};
+
+use crate::{FnResult, OrError};
+use crate::types::{EventType, PredictionResult};
+
+/// One query of a prediction batch, as read from the input file.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchQuery {
+    pub route_id: String,
+    pub trip_id: String,
+    /// the stop is given either by its stop_sequence or by its stop_id
+    #[serde(default)]
+    pub stop_sequence: Option<u16>,
+    /// if the trip visits the stop more than once, there is a result for each visit
+    #[serde(default)]
+    pub stop_id: Option<String>,
+    /// "arrival" or "departure"
+    pub event_type: String,
+    /// Date and time YYYY-MM-DDThh:mm:ss
+    pub date_time: String,
+}
+
+impl BatchQuery {
+    pub fn event_type(&self) -> FnResult<EventType> {
+        super::parse_event_type(&self.event_type)
+    }
+
+    pub fn date_time(&self) -> FnResult<DateTime<Local>> {
+        let naive = NaiveDateTime::parse_from_str(&self.date_time, "%Y-%m-%dT%H:%M:%S")?;
+        Local.from_local_datetime(&naive).earliest().or_error("Date and time does not exist in local time zone")
+    }
+}
+
+/// The result for one query of a prediction batch, as written to the output file.
+#[derive(Debug, Serialize)]
+pub struct BatchResult {
+    pub query: BatchQuery,
+    /// the visit of the stop that the prediction is for, which is needed for queries by stop_id
+    pub stop_sequence: Option<u16>,
+    /// name of the prediction model, so that results of different models can be compared
+    pub model: String,
+    pub prediction: Option<PredictionResult>,
+    pub error: Option<String>,
+}
+
+/// Reads queries from a JSON file (if the filename ends with .json) or a CSV file
+/// with a header line containing the column names.
+pub fn read_queries(filename: &str) -> FnResult<Vec<BatchQuery>> {
+    let file = File::open(filename)?;
+    if filename.ends_with(".json") {
+        let queries: Vec<BatchQuery> = serde_json::from_reader(BufReader::new(file))?;
+        return Ok(queries);
+    }
+
+    parse_csv_queries(file)
+}
+
+// columns are matched by their names in the header line, other columns are ignored
+fn parse_csv_queries<R: Read>(input: R) -> FnResult<Vec<BatchQuery>> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(input);
+    Ok(reader.deserialize().collect::<Result<Vec<BatchQuery>, _>>()?)
+}
+
+/// Writes all results as a JSON array.
+pub fn write_results(filename: &str, results: &Vec<BatchResult>) -> FnResult<()> {
+    let file = File::create(filename)?;
+    serde_json::to_writer_pretty(BufWriter::new(file), results)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_csv_queries() {
+        let csv = "trip_id, route_id,stop_sequence,stop_id,event_type,date_time,comment\n\
+            t1,r1,3,,arrival,2020-10-01T14:30:00,\"first, with comma\"\n\
+            \n\
+            t2, r2 ,12,,departure,2020-10-01T15:00:00,\n\
+            t3,r3,,s7,arrival,2020-10-01T15:00:00,\n";
+        let queries = parse_csv_queries(csv.as_bytes()).unwrap();
+        assert_eq!(queries.len(), 3);
+        assert_eq!(queries[0].trip_id, "t1");
+        assert_eq!(queries[0].route_id, "r1");
+        assert_eq!(queries[1].route_id, "r2");
+        assert_eq!(queries[1].stop_sequence, Some(12));
+        assert_eq!(queries[1].stop_id, None);
+        assert!(matches!(queries[1].event_type(), Ok(EventType::Departure)));
+        assert_eq!(queries[2].stop_sequence, None);
+        assert_eq!(queries[2].stop_id.as_deref(), Some("s7"));
+
+        // the column of stop_id may be left out
+        let queries = parse_csv_queries("route_id,trip_id,stop_sequence,event_type,date_time\nr1,t1,3,arrival,2020-10-01T14:30:00\n".as_bytes()).unwrap();
+        assert_eq!(queries[0].stop_sequence, Some(3));
+        assert_eq!(queries[0].stop_id, None);
+
+        let queries: Vec<BatchQuery> = serde_json::from_str(r#"[{"route_id": "r1", "trip_id": "t1", "stop_id": "s7", "event_type": "arrival", "date_time": "2020-10-01T14:30:00"}]"#).unwrap();
+        assert_eq!(queries[0].stop_id.as_deref(), Some("s7"));
+        assert_eq!(queries[0].stop_sequence, None);
+
+        assert!(parse_csv_queries("route_id,trip_id\nr1,t1\n".as_bytes()).is_err());
+        assert!(parse_csv_queries("route_id,trip_id,stop_sequence,event_type,date_time\nr1,t1,first,arrival,2020-10-01T14:30:00\n".as_bytes()).is_err());
+    }
+}
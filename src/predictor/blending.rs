@@ -0,0 +1,192 @@
+use clap::ArgMatches;
+use simple_error::bail;
+
+use dystonse_curves::Curve;
+use dystonse_curves::curve_set::CurveSet;
+use dystonse_curves::irregular_dynamic::*;
+
+use crate::FnResult;
+use crate::types::{CurveData, CurveSetData};
+
+/// Names of the formulas that can be selected with the `curve-blending` argument.
+pub const BLENDING_FORMULAS: [&str; 3] = ["off", "shrinkage", "linear"];
+
+/// How the weight of a specific curve is computed from its sample size n, with the configured
+/// number of samples k. The general curve gets the remaining weight.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlendingFormula {
+    /// n / (n + k), so a specific curve with k samples gets half of the weight
+    Shrinkage,
+    /// min(n / k, 1), so specific curves with at least k samples are used unchanged
+    Linear,
+}
+
+/// Mixes specific curves with the general (default) curve of the same event, instead of using
+/// the specific curve alone. Specific curves of stop pairs with few observations are quite
+/// random, so they are pulled towards the general curve, the more the fewer samples they have.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CurveBlending {
+    pub formula: BlendingFormula,
+    pub samples: f32,
+}
+
+impl CurveBlending {
+    /// Reads the global `curve-blending` and `curve-blending-samples` arguments.
+    /// Returns None if blending is off.
+    pub fn from_args(args: &ArgMatches) -> FnResult<Option<Self>> {
+        let formula = match args.value_of("curve-blending").unwrap() { // has a default value
+            "off" => return Ok(None),
+            "shrinkage" => BlendingFormula::Shrinkage,
+            "linear" => BlendingFormula::Linear,
+            other => bail!("Unknown curve blending formula: {}. Known formulas are: {}", other, BLENDING_FORMULAS.join(", ")),
+        };
+        let samples: f32 = args.value_of("curve-blending-samples").unwrap().parse()?; // has a default value
+        if samples <= 0.0 {
+            bail!("curve-blending-samples needs to be positive, but is {}.", samples);
+        }
+        Ok(Some(CurveBlending { formula, samples }))
+    }
+
+    /// The weight (between 0 and 1) of a specific curve with `n` samples.
+    pub fn get_weight(&self, n: f32) -> f32 {
+        match self.formula {
+            BlendingFormula::Shrinkage => n / (n + self.samples),
+            BlendingFormula::Linear => (n / self.samples).min(1.0),
+        }
+    }
+
+    /// Returns the mixture of both distributions, with the weight of `specific` according to its sample size.
+    /// The effective sample size is used, so that curves of mostly old records get less weight.
+    /// The precision type and sample size of `specific` are kept, so that blended predictions can still be
+    /// told apart by the kind of specific curve they are based on.
+    pub fn blend(&self, specific: &CurveData, general: &CurveData) -> CurveData {
+        let weight = self.get_weight(specific.get_effective_sample_size());
+        if weight >= 1.0 {
+            return specific.clone();
+        }
+        CurveData {
+            curve: mix_curves(&specific.curve, &general.curve, weight),
+            precision_type: specific.precision_type.clone(),
+            sample_size: specific.sample_size,
+            effective_sample_size: specific.effective_sample_size,
+        }
+    }
+
+    /// Like `blend`, for predictions without a known initial delay: each curve of the set is mixed with
+    /// the general curve, with the weight of the average sample size of the curves.
+    pub fn blend_curve_set(&self, specific: &CurveSetData, general: &CurveData) -> CurveSetData {
+        let weight = self.get_weight(specific.get_effective_sample_size());
+        if weight >= 1.0 {
+            return specific.clone();
+        }
+        let mut curve_set = CurveSet::new();
+        for (focus, curve) in &specific.curve_set.curves {
+            curve_set.add_curve(*focus, mix_curves(curve, &general.curve, weight));
+        }
+        CurveSetData {
+            curve_set,
+            precision_type: specific.precision_type.clone(),
+            sample_size: specific.sample_size,
+            effective_sample_size: specific.effective_sample_size,
+        }
+    }
+}
+
+/// Mixes two distributions: P(delay <= x) = weight * P_a(delay <= x) + (1 - weight) * P_b(delay <= x),
+/// evaluated at the points of both curves.
+pub fn mix_curves(a: &IrregularDynamicCurve<f32, f32>, b: &IrregularDynamicCurve<f32, f32>, weight: f32) -> IrregularDynamicCurve<f32, f32> {
+    let (a_xs, _) = a.get_values_as_vectors();
+    let (b_xs, _) = b.get_values_as_vectors();
+    // NaN can't be ordered, and there is no probability for it anyway
+    let mut xs: Vec<f32> = a_xs.into_iter().chain(b_xs.into_iter()).filter(|x| !x.is_nan()).collect();
+    xs.sort_by(|x1, x2| x1.partial_cmp(x2).unwrap()); // no NaN left
+    xs.dedup();
+    if xs.is_empty() {
+        return a.clone();
+    }
+
+    let points: Vec<Tup<f32, f32>> = xs.into_iter()
+        .map(|x| Tup { x, y: weight * a.y_at_x(x) + (1.0 - weight) * b.y_at_x(x) })
+        .collect();
+    let mut curve = IrregularDynamicCurve::new(points);
+    curve.simplify(0.001);
+    curve
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::PrecisionType;
+
+    fn curve_data(end: f32, sample_size: u32) -> CurveData {
+        CurveData {
+            curve: IrregularDynamicCurve::new(vec![Tup { x: 0.0, y: 0.0 }, Tup { x: end, y: 1.0 }]),
+            precision_type: PrecisionType::Specific,
+            sample_size,
+            effective_sample_size: None,
+        }
+    }
+
+    #[test]
+    fn test_get_weight() {
+        let shrinkage = CurveBlending { formula: BlendingFormula::Shrinkage, samples: 20.0 };
+        assert_eq!(shrinkage.get_weight(0.0), 0.0);
+        assert_eq!(shrinkage.get_weight(20.0), 0.5);
+        assert_eq!(shrinkage.get_weight(60.0), 0.75);
+
+        let linear = CurveBlending { formula: BlendingFormula::Linear, samples: 20.0 };
+        assert_eq!(linear.get_weight(5.0), 0.25);
+        assert_eq!(linear.get_weight(20.0), 1.0);
+        assert_eq!(linear.get_weight(100.0), 1.0);
+    }
+
+    #[test]
+    fn test_mix_curves() {
+        let a = curve_data(10.0, 1).curve;
+        let b = curve_data(20.0, 1).curve;
+        let mixed = mix_curves(&a, &b, 0.5);
+        assert_eq!(mixed.y_at_x(0.0), 0.0);
+        assert!((mixed.y_at_x(10.0) - 0.75).abs() < 0.001);
+        assert!((mixed.y_at_x(15.0) - 0.875).abs() < 0.001);
+        assert_eq!(mixed.y_at_x(20.0), 1.0);
+
+        let only_a = mix_curves(&a, &b, 1.0);
+        assert!((only_a.y_at_x(5.0) - 0.5).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_blend() {
+        let blending = CurveBlending { formula: BlendingFormula::Linear, samples: 20.0 };
+        let general = curve_data(20.0, 1000);
+
+        // enough samples, so the specific curve is used unchanged
+        assert_eq!(blending.blend(&curve_data(10.0, 40), &general).curve.y_at_x(10.0), 1.0);
+
+        let blended = blending.blend(&curve_data(10.0, 10), &general);
+        assert!((blended.curve.y_at_x(10.0) - 0.75).abs() < 0.001);
+        assert_eq!(blended.sample_size, 10);
+        assert_eq!(blended.precision_type.to_int(), PrecisionType::Specific.to_int());
+
+        // old records count less
+        let weighted = CurveData { effective_sample_size: Some(0.0), ..curve_data(10.0, 40) };
+        assert!((blending.blend(&weighted, &general).curve.y_at_x(10.0) - 0.5).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_blend_curve_set() {
+        let blending = CurveBlending { formula: BlendingFormula::Linear, samples: 20.0 };
+        let general = curve_data(20.0, 1000);
+        let mut curve_set = CurveSet::new();
+        curve_set.add_curve(-60.0, curve_data(10.0, 10).curve);
+        curve_set.add_curve(60.0, curve_data(10.0, 10).curve);
+        let specific = CurveSetData { curve_set, precision_type: PrecisionType::Specific, sample_size: 10, effective_sample_size: None };
+
+        let blended = blending.blend_curve_set(&specific, &general);
+        assert_eq!(blended.curve_set.curves.len(), 2);
+        for (_, curve) in &blended.curve_set.curves {
+            assert!((curve.y_at_x(10.0) - 0.75).abs() < 0.001);
+        }
+        let unchanged = blending.blend_curve_set(&CurveSetData { sample_size: 40, ..specific }, &general);
+        assert_eq!(unchanged.curve_set.curves[0].1.y_at_x(10.0), 1.0);
+    }
+}
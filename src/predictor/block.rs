@@ -0,0 +1,186 @@
+use chrono::{Date, Local};
+use gtfs_structures::{Gtfs, Trip};
+use simple_error::bail;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use dystonse_curves::Curve;
+use dystonse_curves::irregular_dynamic::*;
+
+use super::{PredictionModel, PredictionTarget, PredictionContext};
+use crate::types::{CurveData, EventType, PredictionBasis, PredictionResult, PrecisionType, MIXTURE_QUANTILES};
+use crate::{FnResult, OrError};
+
+/// minimum time (in seconds) that a vehicle needs at the end of a trip before it can start the next one
+pub const MIN_TURNAROUND_TIME: i32 = 60;
+
+/// The trips of each block of a schedule. All trips of a block are operated by the same vehicle,
+/// one after the other, so a vehicle that ends one trip late will probably start the next one late.
+pub struct BlockIndex {
+    /// trip_ids and scheduled start times (in seconds) of all trips per block_id, sorted by start time
+    trips_by_block: HashMap<String, Vec<(u32, String)>>,
+    /// whether a service runs on a service day, because looking that up in the schedule is slow
+    service_days: Mutex<HashMap<(String, Date<Local>), bool>>,
+}
+
+impl BlockIndex {
+    pub fn new(schedule: &Gtfs) -> Self {
+        let mut trips_by_block: HashMap<String, Vec<(u32, String)>> = HashMap::new();
+        for trip in schedule.trips.values() {
+            let start_time = trip.stop_times.first().and_then(|stop_time| stop_time.departure_time);
+            if let (Some(block_id), Some(start_time)) = (&trip.block_id, start_time) {
+                trips_by_block.entry(block_id.clone()).or_insert_with(Vec::new).push((start_time, trip.id.clone()));
+            }
+        }
+        for trips in trips_by_block.values_mut() {
+            trips.sort();
+        }
+        BlockIndex {
+            trips_by_block,
+            service_days: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the trip that the vehicle of `trip` operates next on the same service day, together with the
+    /// time (in seconds) between the scheduled arrival at the end of `trip` and the scheduled start of the next trip.
+    pub fn get_next_trip<'s>(&self, schedule: &'s Gtfs, trip: &Trip, service_day: Date<Local>) -> Option<(&'s Trip, i32)> {
+        let trips = self.trips_by_block.get(trip.block_id.as_ref()?)?;
+        let end_time = trip.stop_times.last()?.arrival_time?;
+        for (start_time, trip_id) in trips.iter().filter(|(start_time, _)| *start_time >= end_time) {
+            let next_trip = match schedule.get_trip(trip_id) {
+                Ok(next_trip) => next_trip,
+                Err(_) => continue,
+            };
+            // blocks may contain trips of other days, e.g. on weekends
+            if next_trip.id != trip.id && self.runs_on(schedule, &next_trip.service_id, service_day) {
+                return Some((next_trip, *start_time as i32 - end_time as i32));
+            }
+        }
+        None
+    }
+
+    fn runs_on(&self, schedule: &Gtfs, service_id: &str, service_day: Date<Local>) -> bool {
+        let key = (String::from(service_id), service_day);
+        if let Some(runs) = self.service_days.lock().unwrap().get(&key) {
+            return *runs;
+        }
+        let runs = schedule.trip_days(&key.0, service_day.naive_local()).contains(&0);
+        self.service_days.lock().unwrap().insert(key, runs);
+        runs
+    }
+}
+
+/// Predicts an event of a trip for which there is no realtime data yet, from the predicted arrival
+/// delay of the vehicle at the end of its previous trip in the same block. `layover` is the scheduled
+/// time between both trips. The result has the precision type BlockPropagated.
+pub fn predict_after_previous_trip(
+    model: &dyn PredictionModel,
+    previous_arrival: &CurveData,
+    layover: i32,
+    target: &PredictionTarget,
+    context: &PredictionContext,
+) -> FnResult<PredictionResult> {
+    let first_stop_sequence = target.trip.stop_times.first().or_error("Trip has no stops")?.stop_sequence;
+    let first_departure_target = PredictionTarget {
+        route_id: target.route_id,
+        trip: target.trip,
+        stop_sequence: first_stop_sequence,
+        event_type: EventType::Departure,
+    };
+    let own_departure = match model.predict(&None, &first_departure_target, context)? {
+        PredictionResult::CurveData(curve_data) => curve_data,
+        _ => bail!("Result of unexpected type for the first departure."),
+    };
+    let slack = (layover - MIN_TURNAROUND_TIME) as f32;
+    let first_departure = combine_with_previous_arrival(&own_departure.curve, &previous_arrival.curve, slack);
+
+    if target.stop_sequence == first_stop_sequence {
+        return Ok(PredictionResult::CurveData(CurveData {
+            curve: first_departure,
+            precision_type: PrecisionType::BlockPropagated,
+            sample_size: u32::min(own_departure.sample_size, previous_arrival.sample_size),
+            effective_sample_size: own_departure.effective_sample_size.or(previous_arrival.effective_sample_size)
+                .map(|_| f32::min(own_departure.get_effective_sample_size(), previous_arrival.get_effective_sample_size())),
+        }));
+    }
+
+    // The delay at later stops depends on the delay at the first stop, which is only known as a distribution.
+    // So the specific curves for several possible delays are averaged, weighted by their probability.
+    let mut curves = Vec::with_capacity(MIXTURE_QUANTILES.len());
+    for quantile in &MIXTURE_QUANTILES {
+        let basis = Some(PredictionBasis {
+            stop_sequence: first_stop_sequence,
+            delay_departure: Some(first_departure.x_at_y(*quantile) as i64),
+        });
+        match model.predict(&basis, target, context)? {
+            PredictionResult::CurveData(curve_data) => match curve_data.precision_type {
+                PrecisionType::Specific | PrecisionType::FallbackSpecific => curves.push(curve_data),
+                // other curves don't depend on the delay at the first stop, so nothing would be propagated
+                _ => bail!("No specific curves to propagate the delay of the previous trip."),
+            },
+            _ => bail!("Result of unexpected type."),
+        }
+    }
+    Ok(PredictionResult::CurveData(CurveData::average(&curves, PrecisionType::BlockPropagated)?))
+}
+
+/// The vehicle can't depart before it has arrived from its previous trip and turned around, which takes
+/// `slack` seconds less than scheduled. So the departure delay is the larger one of the delay the trip would
+/// have anyway and the arrival delay minus the slack. Assuming that both are independent,
+/// P(max(a, b - slack) <= x) = P(a <= x) * P(b <= x + slack).
+fn combine_with_previous_arrival(own_departure: &IrregularDynamicCurve<f32, f32>, previous_arrival: &IrregularDynamicCurve<f32, f32>, slack: f32) -> IrregularDynamicCurve<f32, f32> {
+    let (own_xs, _) = own_departure.get_values_as_vectors();
+    let (previous_xs, _) = previous_arrival.get_values_as_vectors();
+    // NaN can't be ordered, and there is no probability for it anyway
+    let mut xs: Vec<f32> = own_xs.into_iter().chain(previous_xs.into_iter().map(|x| x - slack)).filter(|x| !x.is_nan()).collect();
+    xs.sort_by(|a, b| a.partial_cmp(b).unwrap()); // no NaN left
+    xs.dedup();
+    if xs.is_empty() {
+        return own_departure.clone();
+    }
+
+    let points: Vec<Tup<f32, f32>> = xs.into_iter()
+        .map(|x| Tup { x, y: own_departure.y_at_x(x) * previous_arrival.y_at_x(x + slack) })
+        .collect();
+    // only keep one point with 0 at the start and one with 1 at the end
+    let first = points.iter().rposition(|point| point.y <= 0.0).unwrap_or(0);
+    let last = points.iter().position(|point| point.y >= 1.0).unwrap_or(points.len() - 1);
+    let mut curve = IrregularDynamicCurve::new(points[first..=last].to_vec());
+    curve.simplify(0.001);
+    curve
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve(start: f32, end: f32) -> IrregularDynamicCurve<f32, f32> {
+        IrregularDynamicCurve::new(vec![Tup { x: start, y: 0.0 }, Tup { x: end, y: 1.0 }])
+    }
+
+    #[test]
+    fn test_combine_with_previous_arrival() {
+        let own_departure = curve(0.0, 60.0);
+
+        // the vehicle is always back early enough, so the trip departs as it would anyway
+        let combined = combine_with_previous_arrival(&own_departure, &curve(-100.0, -50.0), 0.0);
+        assert_eq!(combined.get_values_as_vectors(), (vec![0.0, 60.0], vec![0.0, 1.0]));
+
+        // the vehicle is always back late, so the trip departs as late as the vehicle arrives
+        let combined = combine_with_previous_arrival(&own_departure, &curve(100.0, 160.0), 0.0);
+        assert_eq!(combined.get_values_as_vectors(), (vec![100.0, 160.0], vec![0.0, 1.0]));
+        assert!((combined.y_at_x(130.0) - 0.5).abs() < 0.001);
+
+        // the slack absorbs a minute of the delay
+        let combined = combine_with_previous_arrival(&own_departure, &curve(100.0, 160.0), 60.0);
+        assert_eq!(combined.get_values_as_vectors(), (vec![40.0, 100.0], vec![0.0, 1.0]));
+
+        // overlapping distributions are evaluated at the points of both curves
+        let combined = combine_with_previous_arrival(&own_departure, &curve(20.0, 40.0), 0.0);
+        let (xs, ys) = combined.get_values_as_vectors();
+        assert_eq!(xs, vec![20.0, 40.0, 60.0]);
+        assert_eq!(ys[0], 0.0);
+        assert!((ys[1] - 2.0 / 3.0).abs() < 0.001);
+        assert_eq!(ys[2], 1.0);
+    }
+}
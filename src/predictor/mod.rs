@@ -1,4 +1,4 @@
-use crate::types::{EventType, TimeSlot, RouteSection, PredictionResult, DelayStatistics};
+use crate::types::{CurveData, EventType, PredictionResult, PredictionBasis};
 
 use chrono::{DateTime, Local, NaiveDateTime};
 use chrono::offset::TimeZone;
@@ -8,20 +8,26 @@ use std::str::FromStr;
 
 use simple_error::bail;
 
-use crate::{Main, FnResult, OrError};
+use crate::{Main, FnResult};
 
 use std::sync::Arc;
 
-use crate::types::{PredictionBasis, DefaultCurveKey, PrecisionType, CurveData, CurveSetKey};
-
 mod real_time;
+mod batch;
+mod model;
+mod block;
+mod blending;
+
+pub use model::{PredictionModel, PredictionTarget, PredictionContext, StatisticsModel, MODEL_NAMES};
+pub use block::BlockIndex;
+pub use blending::{CurveBlending, BLENDING_FORMULAS, mix_curves};
 
 pub struct Predictor<'a> {
     #[allow(dead_code)]
     pub main: &'a Main,
     pub args: &'a ArgMatches,
     pub schedule: Arc<Gtfs>,
-    pub delay_statistics: Arc<DelayStatistics>,
+    pub model: Box<dyn PredictionModel>,
 }
 
 impl<'a> Predictor<'a> {
@@ -30,6 +36,24 @@ impl<'a> Predictor<'a> {
             .subcommand(App::new("start")
                 .about("Starts the predictor module and keeps running so it can answer requests for predictions.")
             )
+            .subcommand(App::new("batch")
+                .about("Answers many requests for predictions, which are read from a file, and writes the results to another file.")
+                .arg(Arg::new("input")
+                    .short('i')
+                    .long("input")
+                    .required(true)
+                    .about("CSV file (with header line) or JSON file (if the name ends with .json) with the queries. Each query needs route_id, trip_id, event_type, date_time and either stop_sequence or stop_id. For a stop_id, there is one result for each visit of the stop.")
+                    .takes_value(true)
+                    .value_name("INPUT_FILE")
+                ).arg(Arg::new("output")
+                    .short('o')
+                    .long("output")
+                    .required(true)
+                    .about("JSON file to which the predicted curves will be written.")
+                    .takes_value(true)
+                    .value_name("OUTPUT_FILE")
+                )
+            )
             .subcommand(App::new("single")
                 .about("Starts the predictor module and answers one request for a prediction, then quits.")
                 .arg(Arg::new("route-id")
@@ -52,6 +76,12 @@ impl<'a> Predictor<'a> {
                     .about("Sequence number of the stop for which the prediction shall be made. May be ommitted to get predictions for all stops of the route.")
                     .takes_value(true)
                     .value_name("END_STOP_SEQUENCE")
+                ).arg(Arg::new("end-stop-id")
+                    .long("end-stop-id")
+                    .conflicts_with("end-stop-sequence")
+                    .about("Id of the stop for which the prediction shall be made. Can be used instead of end-stop-sequence. If the trip visits this stop more than once, predictions for all visits are made.")
+                    .takes_value(true)
+                    .value_name("END_STOP_ID")
                 ).arg(Arg::new("event-type")
                     .short('t')
                     .long("event-type")
@@ -94,7 +124,7 @@ impl<'a> Predictor<'a> {
             main,
             args,
             schedule: main.get_schedule()?,
-            delay_statistics: main.get_delay_statistics()?,
+            model: model::create_model(main.args.value_of("prediction-model").unwrap(), main)?,
         })
     }
 
@@ -103,6 +133,7 @@ impl<'a> Predictor<'a> {
         match self.args.clone().subcommand() {
             ("start", Some(sub_args)) => self.run_start(sub_args),
             ("single", Some(sub_args)) => self.run_single(sub_args),
+            ("batch", Some(sub_args)) => self.run_batch(sub_args),
             _ => panic!("Invalid arguments."),
         }
     }
@@ -124,11 +155,7 @@ impl<'a> Predictor<'a> {
             None => None,
             Some(sss) => Some(str::parse::<u16>(sss)?)
         };
-        let event_type = match args.value_of("event-type").unwrap() {
-            "arrival" => EventType::Arrival,
-            "departure" => EventType::Departure,
-            _ => {panic!("Invalid event type argument!");}
-        };
+        let event_type = parse_event_type(args.value_of("event-type").unwrap())?;
         let date_time = Local.from_local_datetime(&NaiveDateTime::parse_from_str(args.value_of("date-time").unwrap(), "%Y-%m-%dT%H:%M:%S")?).unwrap();
 
         let trip = self.schedule.get_trip(trip_id)?;
@@ -152,11 +179,12 @@ impl<'a> Predictor<'a> {
             },
         };
 
-        // if no single stop_sequence is given, iterate over all stop_sequences of the trip
-        // TODO we currently ignore the stop_id from the args
-        let stop_sequences : Vec<u16> = match potential_end_stop_sequence {
-            Some(stop_sequence) => vec!{stop_sequence},
-            None => trip.stop_times.iter().map(|st| st.stop_sequence).collect()
+        // if no single stop_sequence is given, use all visits of the given stop_id, 
+        // or iterate over all stop_sequences of the trip
+        let stop_sequences : Vec<u16> = match (potential_end_stop_sequence, args.value_of("end-stop-id")) {
+            (Some(stop_sequence), _) => vec!{stop_sequence},
+            (None, Some(stop_id)) => Self::get_stop_sequences_for_stop_id(&trip, stop_id, &start)?,
+            (None, None) => trip.stop_times.iter().map(|st| st.stop_sequence).collect()
         };
 
         for stop_sequence in stop_sequences {
@@ -174,7 +202,73 @@ impl<'a> Predictor<'a> {
     }
 
 
-    /// finds out which kind of curve can be used for this prediction and looks up the requested curve
+    /// looks up predictions for all queries from the input file, reusing the loaded schedule
+    /// and statistics, and writes them to the output file
+    fn run_batch(&self, args: &ArgMatches) -> FnResult<()> {
+        let queries = batch::read_queries(args.value_of("input").unwrap())?;
+        info!("Read {} queries.", queries.len());
+
+        // queries with a stop_id get one result for each visit of the stop
+        let results : Vec<batch::BatchResult> = queries.into_iter().flat_map(|query| {
+            let predictions = query.event_type().and_then(|event_type| {
+                let date_time = query.date_time()?;
+                match (query.stop_sequence, &query.stop_id) {
+                    (Some(stop_sequence), None) => Ok(vec![(stop_sequence, self.predict(&query.route_id, &query.trip_id, &None, stop_sequence, event_type, date_time)?)]),
+                    (None, Some(stop_id)) => self.predict_by_stop_id(&query.route_id, &query.trip_id, &None, stop_id, event_type, date_time),
+                    _ => bail!("Query needs either stop_sequence or stop_id."),
+                }
+            });
+            match predictions {
+                Ok(predictions) => predictions.into_iter().map(|(stop_sequence, prediction)| 
+                    batch::BatchResult { query: query.clone(), model: String::from(self.model.name()), stop_sequence: Some(stop_sequence), prediction: Some(prediction), error: None }
+                ).collect(),
+                Err(e) => vec![batch::BatchResult { stop_sequence: query.stop_sequence, query, model: String::from(self.model.name()), prediction: None, error: Some(e.to_string()) }],
+            }
+        }).collect();
+
+        let failed_count = results.iter().filter(|r| r.error.is_some()).count();
+        info!("Made {} predictions, {} queries failed.", results.len() - failed_count, failed_count);
+
+        batch::write_results(args.value_of("output").unwrap(), &results)?;
+        Ok(())
+    }
+
+    /// Finds the stop_sequences at which the trip visits the stop with the given stop_id.
+    /// Trips on loop routes may visit the same stop more than once, so all visits are returned
+    /// in the order of the trip. If a prediction basis is given, visits before it are left out.
+    pub fn get_stop_sequences_for_stop_id(trip: &Trip, stop_id: &str, start: &Option<PredictionBasis>) -> FnResult<Vec<u16>> {
+        let min_stop_sequence = start.as_ref().map(|basis| basis.stop_sequence);
+        let stop_sequences : Vec<u16> = trip.stop_times.iter()
+            .filter(|st| st.stop.id == stop_id)
+            .map(|st| st.stop_sequence)
+            .filter(|stop_sequence| min_stop_sequence.map_or(true, |min| *stop_sequence >= min))
+            .collect();
+
+        if stop_sequences.is_empty() {
+            bail!("Trip {} does not visit stop {}.", trip.id, stop_id);
+        }
+
+        Ok(stop_sequences)
+    }
+
+    /// Same as `predict`, but for callers who only know the stop_id and not the stop_sequence.
+    /// Returns one prediction for each visit of the stop, together with its stop_sequence.
+    pub fn predict_by_stop_id(&self, 
+            route_id: &str, 
+            trip_id: &str, 
+            start: &Option<PredictionBasis>, 
+            stop_id: &str,
+            et: EventType, 
+            date_time: DateTime<Local>) -> FnResult<Vec<(u16, PredictionResult)>> {
+
+        let trip = self.schedule.get_trip(trip_id)?;
+        Self::get_stop_sequences_for_stop_id(&trip, stop_id, start)?
+            .into_iter()
+            .map(|stop_sequence| Ok((stop_sequence, self.predict(route_id, trip_id, start, stop_sequence, et, date_time)?)))
+            .collect()
+    }
+
+    /// looks up a prediction from the selected prediction model
     pub fn predict(&self, 
             route_id: &str, 
             trip_id: &str, 
@@ -183,154 +277,56 @@ impl<'a> Predictor<'a> {
             et: EventType, 
             date_time: DateTime<Local>) -> FnResult<PredictionResult> {
 
-        // parse lookup parameters from input
-        let ts = TimeSlot::from_datetime(date_time);
         let trip = self.schedule.get_trip(trip_id)?;
-       
-        let route_variant : u64 = u64::from_str(trip.route_variant.as_ref().unwrap()).unwrap(); 
-        // should never panic because we already checked the validity of 
-        // the trip, and route variants are always numbers.
-
-        // try to find a specific prediction:
-        let specific_prediction = self.predict_specific(route_id, route_variant, start, stop_sequence, ts, et, &trip);
-
-        // if route_id == "32727_3" {
-        //     println!(
-        //         "ROUTE_DEBUG: Made prediction for route {}, trip {}, starting at stop/delay {:?} with trip start time {}, stop_sequence {}, ET {:?}",
-        //         route_id,
-        //         trip_id,
-        //         start,
-        //         date_time.format("%d.%m. %H:%M"),
-        //         stop_sequence,
-        //         et
-        //     );
-        //     if let Ok(PredictionResult::CurveData(curve_data)) = &specific_prediction {
-        //         println!(
-        //             "ROUTE_DEBUG: Specific prediction has precision_type: {:?}",
-        //             curve_data.precision_type
-        //         );
-        //     }
-        // }
-
-        // unwrap that, or try a default prediction if it failed:
-        specific_prediction.or_else(|_| {
-            // eprintln!("⚠️ No specific_prediction because: {}", e);
-
-            // prepare some more lookup parameters
-            let key = DefaultCurveKey {
-                route_type: self.schedule.get_route(route_id)?.route_type,
-                route_section: RouteSection::get_route_section_by_stop_sequence(&self.schedule, trip_id, stop_sequence)?,
-                time_slot: ts.clone(),
-                event_type: et
-            };
-            let default_prediction = self.predict_default(&key);
-            // if route_id == "32727_3" {
-            //     println!(
-            //         "ROUTE_DEBUG: No specific prediction. Use default prediction instead, with key: {:?}",
-            //         key
-            //     );
-            //     println!(
-            //         "ROUTE_DEBUG: Default prediction is: {:?}",
-            //         default_prediction
-            //     );
-            // }
-            default_prediction
-        })
-    }
-
-    // looks up a curve from default curves and returns it
-    fn predict_default(&self, key: &DefaultCurveKey) // rt: RouteType, rs: RouteSection, ts: &TimeSlot, et: EventType) 
-            -> FnResult<PredictionResult> {
-
-        let potential_curve_data = self.delay_statistics.general.all_default_curves.get(key);
-        
-        if let Some(curve_data) = potential_curve_data {
-            Ok(PredictionResult::CurveData(curve_data.clone()))
-        } else {
-            // Once we hat the problem that default curves could not be found even though they existed.
-            // The following code helps to debug this, in case it happens again. You also need this:
-            use std::hash::{Hash, Hasher};
-            use std::collections::hash_map::DefaultHasher;
-
-            let mut hasher = DefaultHasher::new();
-            key.hash(&mut hasher);
-            println!("No default curve found for {:?} with hash {}.", key, hasher.finish());
-            // for (p_key, _p_val) in &self.delay_statistics.general.all_default_curves {
-            //     let mut hasher = DefaultHasher::new();
-            //     p_key.hash(&mut hasher);
-            //     println!("Instead, found key {:?} with hash {}.", p_key, hasher.finish());
-            // }
-
-            bail!("No default curve.");
-        }
-        
+        let target = PredictionTarget {
+            route_id,
+            trip,
+            stop_sequence,
+            event_type: et,
+        };
+        let context = PredictionContext {
+            schedule: &self.schedule,
+            holidays: &self.main.holidays,
+            weather: self.main.weather.as_ref(),
+            date_time,
+        };
+        self.model.predict(start, &target, &context)
     }
 
-    // looks up a curve (or curve set) from specific curves and returns it
-    fn predict_specific(&self, 
-            route_id: &str, 
-            route_variant: u64, 
-            start: &Option<PredictionBasis>, //&str for stop_id, f32 for initial delay
-            stop_sequence: u16, 
-            ts: &TimeSlot,
+    /// Like `predict`, but for a trip without realtime data, whose vehicle is predicted to arrive
+    /// at the end of its previous trip in the same block with the delay of `previous_arrival`.
+    /// `layover` is the scheduled time (in seconds) between both trips.
+    pub fn predict_after_previous_trip(&self,
+            route_id: &str,
+            trip_id: &str,
+            previous_arrival: &CurveData,
+            layover: i32,
+            stop_sequence: u16,
             et: EventType,
-            trip: &Trip) -> FnResult<PredictionResult> {
-
-        // find the route variant data that we need:
-        let rvdata = &self.delay_statistics.specific.get(route_id).or_error("No specific statistics for route_id")?.variants.get(&route_variant).or_error("No specific statistics for route_variant")?;
-        // find index of target stop:
-        // TODO use stop_sequence instead of stop_id, which has less chance of failure since it's always unique
-        let end_stop_index = trip.get_stop_index_by_stop_sequence(stop_sequence)? as u32;
-        
-        match start {
-            None => { 
-                // get general curve for target stop (a.k.a. SemiSpecific):
-                let curve_data = rvdata.general_delay[et].get(&end_stop_index).or_error(&format!("No curve_data for stop_sequence {}.", stop_sequence))?;
-                return Ok(PredictionResult::CurveData(curve_data.clone()));
-            },
-            Some(actual_start) => {
-                // TODO use stop_sequence instead of stop_id, which has less chance of failure since it's always unique
-                let start_stop_index = trip.get_stop_index_by_stop_sequence(actual_start.stop_sequence)? as u32;
-                let key = CurveSetKey {
-                    start_stop_index,
-                    end_stop_index,
-                    time_slot: ts.clone()
-                };
-                let potential_curveset_data = &rvdata.curve_sets[et].get(&key);
-                // let route_name = &self.schedule.get_route(route_id).unwrap().short_name;
-                let curve_set_data = match potential_curveset_data {
-                    Some(data) => *data,
-                    None => {
-                        if *ts == TimeSlot::DEFAULT {
-                            // println!("No specific curveset found for route {}, key {:?}", route_name, key);
-                            // println!("Present Keys: {:?}", rvdata.curve_sets[et].keys());
-                            bail!("No specific curveset found");
-                        } else {
-                            // println!("No specific curveset with specific TimeSlot found for route {}, key {:?}. Using TimeSlot::DEFAULT instead.", route_name, key);
-                            return self.predict_specific(route_id, route_variant, start, stop_sequence, &TimeSlot::DEFAULT, et, trip);
-                        }
-                    }
-                }; 
-                if curve_set_data.curve_set.curves.is_empty() {
-                    bail!("Found specific curveset, but it was empty.");
-                }
-                match actual_start.delay_departure {
-                    // get curve set for start-stop:
-                    None => {
-                        return Ok(PredictionResult::CurveSetData(curve_set_data.clone()));
-                    },
-                    // get curve for start-stop and initial delay:
-                    Some(delay) => {
-                        let curve = curve_set_data.curve_set.curve_at_x_with_continuation(delay as f32);
-                        let curve_data = CurveData {
-                            curve,
-                            precision_type: if *ts == TimeSlot::DEFAULT { PrecisionType::FallbackSpecific } else { PrecisionType::Specific },
-                            sample_size: curve_set_data.sample_size
-                        };
-                        return Ok(PredictionResult::CurveData(curve_data));
-                    }
-                };
-            },
+            date_time: DateTime<Local>) -> FnResult<PredictionResult> {
+
+        let trip = self.schedule.get_trip(trip_id)?;
+        let target = PredictionTarget {
+            route_id,
+            trip,
+            stop_sequence,
+            event_type: et,
+        };
+        let context = PredictionContext {
+            schedule: &self.schedule,
+            holidays: &self.main.holidays,
+            weather: self.main.weather.as_ref(),
+            date_time,
         };
+        block::predict_after_previous_trip(self.model.as_ref(), previous_arrival, layover, &target, &context)
+    }
+}
+
+/// parses the event type as given on the command line or in batch files
+pub fn parse_event_type(event_type: &str) -> FnResult<EventType> {
+    match event_type {
+        "arrival" => Ok(EventType::Arrival),
+        "departure" => Ok(EventType::Departure),
+        _ => bail!("Invalid event type: {}", event_type),
     }
 }
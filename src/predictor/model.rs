@@ -0,0 +1,386 @@
+use chrono::{DateTime, Local};
+use gtfs_structures::{Gtfs, Trip};
+use simple_error::bail;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use dystonse_curves::Curve;
+
+use super::blending::CurveBlending;
+use crate::{Main, FnResult, OrError};
+use crate::types::{EventType, TimeSlot, PredictionResult, PredictionBasis, DelayStatistics,
+    DefaultCurves, DefaultCurveKey, PrecisionType, CurveData, CurveSetKey, DwellTimeKey, RouteVariantData, CurveStore, HolidayCalendar,
+    WeatherCondition, WeatherProvider, MIXTURE_QUANTILES};
+
+/// Names of all models that can be selected with the `prediction-model` argument.
+pub const MODEL_NAMES: [&str; 2] = [StatisticsModel::NAME, DatabaseModel::NAME];
+
+// how many route variants the DatabaseModel keeps in memory
+const MAX_CACHED_ROUTE_VARIANTS: usize = 2000;
+
+/// The event for which a prediction shall be made.
+pub struct PredictionTarget<'b> {
+    pub route_id: &'b str,
+    pub trip: &'b Trip,
+    pub stop_sequence: u16,
+    pub event_type: EventType,
+}
+
+/// Everything else a model may need to know about the prediction.
+pub struct PredictionContext<'b> {
+    pub schedule: &'b Gtfs,
+    /// used to find the time slot on holidays
+    pub holidays: &'b HolidayCalendar,
+    /// used to get a weather forecast, if configured
+    pub weather: Option<&'b WeatherProvider>,
+    /// the (scheduled) time of the trip, used to find the time slot
+    pub date_time: DateTime<Local>,
+}
+
+impl<'b> PredictionContext<'b> {
+    /// Returns the expected weather at the time of the trip, or Unknown if no weather is configured
+    /// or the forecast has not been fetched. The weather API is never requested while predicting.
+    pub fn get_weather_condition(&self) -> WeatherCondition {
+        match self.weather {
+            Some(weather) => weather.get_forecast(self.date_time),
+            None => WeatherCondition::Unknown,
+        }
+    }
+}
+
+/// A source of delay predictions. Each implementation may use its own data and methods,
+/// as long as it can produce a delay curve (or curve set) for the target event.
+/// Models are shared between the threads of the importer, so they need to be thread-safe.
+pub trait PredictionModel: Send + Sync {
+    /// short name of the model, as used on the command line
+    fn name(&self) -> &'static str;
+
+    fn predict(&self, basis: &Option<PredictionBasis>, target: &PredictionTarget, context: &PredictionContext) -> FnResult<PredictionResult>;
+}
+
+/// Creates the model with the given name.
+pub fn create_model(name: &str, main: &Main) -> FnResult<Box<dyn PredictionModel>> {
+    let curve_blending = CurveBlending::from_args(&main.args)?;
+    match name {
+        StatisticsModel::NAME => Ok(Box::new(StatisticsModel { delay_statistics: main.get_delay_statistics()?, curve_blending })),
+        DatabaseModel::NAME => Ok(Box::new(DatabaseModel::new(main, curve_blending)?)),
+        _ => bail!("Unknown prediction model: {}. Known models are: {}", name, MODEL_NAMES.join(", ")),
+    }
+}
+
+/// The model that uses the curves and curve sets computed by the analyser.
+/// Specific curves are used where available, default curves otherwise.
+pub struct StatisticsModel {
+    pub delay_statistics: Arc<DelayStatistics>,
+    /// if set, specific curves are mixed with the default curves instead of being used alone
+    pub curve_blending: Option<CurveBlending>,
+}
+
+impl StatisticsModel {
+    pub const NAME: &'static str = "statistics";
+}
+
+// looks up a curve from default curves and returns it
+fn predict_default(default_curves: &DefaultCurves, key: &DefaultCurveKey) -> FnResult<PredictionResult> {
+
+    let potential_curve_data = default_curves.all_default_curves.get(key);
+
+    if let Some(curve_data) = potential_curve_data {
+        Ok(PredictionResult::CurveData(curve_data.clone()))
+    } else {
+        // Once we hat the problem that default curves could not be found even though they existed.
+        // The following code helps to debug this, in case it happens again. You also need this:
+        use std::hash::{Hash, Hasher};
+        use std::collections::hash_map::DefaultHasher;
+
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        debug!("No default curve found for {:?} with hash {}.", key, hasher.finish());
+        // for (p_key, _p_val) in &self.delay_statistics.general.all_default_curves {
+        //     let mut hasher = DefaultHasher::new();
+        //     p_key.hash(&mut hasher);
+        //     debug!("Instead, found key {:?} with hash {}.", p_key, hasher.finish());
+        // }
+
+        bail!("No default curve.");
+    }
+
+}
+
+// finds the index of the stop in the curves of the route variant, which might have been merged with others
+fn get_stop_index(rvdata: &RouteVariantData, trip: &Trip, stop_sequence: u16) -> FnResult<u32> {
+    let stop_index = trip.get_stop_index_by_stop_sequence(stop_sequence)?;
+    rvdata.get_stop_index(trip, stop_index).or_error(&format!("No curves for stop_sequence {} in merged route variant.", stop_sequence))
+}
+
+// looks up a curve (or curve set) from specific curves of a route variant and returns it
+fn predict_specific(
+        rvdata: &RouteVariantData,
+        start: &Option<PredictionBasis>, //&str for stop_id, f32 for initial delay
+        stop_sequence: u16,
+        ts: &TimeSlot,
+        weather: WeatherCondition,
+        et: EventType,
+        trip: &Trip) -> FnResult<PredictionResult> {
+
+    // find index of target stop:
+    // TODO use stop_sequence instead of stop_id, which has less chance of failure since it's always unique
+    let end_stop_index = get_stop_index(rvdata, trip, stop_sequence)?;
+
+    match start {
+        None => {
+            // get general curve for target stop (a.k.a. SemiSpecific):
+            let curve_data = rvdata.general_delay[et].get(&end_stop_index).or_error(&format!("No curve_data for stop_sequence {}.", stop_sequence))?;
+            return Ok(PredictionResult::CurveData(curve_data.clone()));
+        },
+        Some(actual_start) => {
+            // TODO use stop_sequence instead of stop_id, which has less chance of failure since it's always unique
+            let start_stop_index = get_stop_index(rvdata, trip, actual_start.stop_sequence)?;
+            let key = CurveSetKey {
+                start_stop_index,
+                end_stop_index,
+                time_slot: ts.clone(),
+                weather,
+            };
+            let potential_curveset_data = &rvdata.curve_sets[et].get(&key);
+            let curve_set_data = match potential_curveset_data {
+                Some(data) => *data,
+                None => {
+                    if weather != WeatherCondition::Unknown {
+                        // there are no curves for this weather, so use the ones for any weather
+                        return predict_specific(rvdata, start, stop_sequence, ts, WeatherCondition::Unknown, et, trip);
+                    } else if *ts == TimeSlot::DEFAULT {
+                        // println!("No specific curveset found for route {}, key {:?}", route_name, key);
+                        // println!("Present Keys: {:?}", rvdata.curve_sets[et].keys());
+                        bail!("No specific curveset found");
+                    } else {
+                        // println!("No specific curveset with specific TimeSlot found for route {}, key {:?}. Using TimeSlot::DEFAULT instead.", route_name, key);
+                        return predict_specific(rvdata, start, stop_sequence, &TimeSlot::DEFAULT, weather, et, trip);
+                    }
+                }
+            };
+            if curve_set_data.curve_set.curves.is_empty() {
+                bail!("Found specific curveset, but it was empty.");
+            }
+            match actual_start.delay_departure {
+                // get curve set for start-stop:
+                None => {
+                    return Ok(PredictionResult::CurveSetData(curve_set_data.clone()));
+                },
+                // get curve for start-stop and initial delay:
+                Some(delay) => {
+                    let curve = curve_set_data.curve_set.curve_at_x_with_continuation(delay as f32);
+                    let curve_data = CurveData {
+                        curve,
+                        precision_type: if *ts == TimeSlot::DEFAULT { PrecisionType::FallbackSpecific } else { PrecisionType::Specific },
+                        sample_size: curve_set_data.sample_size,
+                        effective_sample_size: curve_set_data.effective_sample_size,
+                    };
+                    return Ok(PredictionResult::CurveData(curve_data));
+                }
+            };
+        },
+    };
+}
+
+// Predicts the departure at a stop from the predicted arrival at the same stop and the dwell time curves
+// of that stop, instead of directly from the delay at the start stop. The arrival delay is only known as
+// a distribution, so the departure curves for several possible arrival delays are averaged.
+fn predict_specific_via_dwell_time(
+        rvdata: &RouteVariantData,
+        start: &Option<PredictionBasis>,
+        stop_sequence: u16,
+        ts: &TimeSlot,
+        weather: WeatherCondition,
+        trip: &Trip) -> FnResult<PredictionResult> {
+
+    let stop_index = get_stop_index(rvdata, trip, stop_sequence)?;
+    let dwell_times = rvdata.dwell_times.get(&DwellTimeKey { stop_index, time_slot: ts.clone() })
+        .or_else(|| rvdata.dwell_times.get(&DwellTimeKey { stop_index, time_slot: TimeSlot::DEFAULT }))
+        .or_error("No dwell time curves for this stop")?;
+
+    let arrival = match predict_specific(rvdata, start, stop_sequence, ts, weather, EventType::Arrival, trip)? {
+        PredictionResult::CurveData(curve_data) => curve_data,
+        _ => bail!("No single arrival curve to chain with the dwell time curves."),
+    };
+    let curves: Vec<CurveData> = MIXTURE_QUANTILES.iter().map(|quantile| CurveData {
+        curve: dwell_times.curve_set.curve_at_x_with_continuation(arrival.curve.x_at_y(*quantile)),
+        precision_type: arrival.precision_type.clone(),
+        sample_size: u32::min(arrival.sample_size, dwell_times.sample_size),
+        effective_sample_size: arrival.effective_sample_size.or(dwell_times.effective_sample_size)
+            .map(|_| f32::min(arrival.get_effective_sample_size(), dwell_times.effective_sample_size.unwrap_or(dwell_times.sample_size as f32))),
+    }).collect();
+    Ok(PredictionResult::CurveData(CurveData::average(&curves, arrival.precision_type)?))
+}
+
+/// Shared by all models that use curves computed by the analyser: a specific curve is used
+/// if there is one for the route variant, and a default curve otherwise. With `curve_blending`,
+/// specific curves and curve sets are mixed with the default curve according to their sample size.
+fn predict_from_curves(rvdata: Option<&RouteVariantData>, default_curves: &DefaultCurves, curve_blending: Option<&CurveBlending>, basis: &Option<PredictionBasis>, target: &PredictionTarget, context: &PredictionContext) -> FnResult<PredictionResult> {
+    let ts = TimeSlot::from_datetime(context.date_time, context.holidays);
+
+    // try to find a specific prediction, chained through the arrival at the same stop for departures
+    // with a known initial delay, because that takes into account that vehicles wait at some stops:
+    let specific_prediction = match rvdata {
+        Some(rvdata) => {
+            let chained_prediction = match (target.event_type, basis) {
+                (EventType::Departure, Some(PredictionBasis { delay_departure: Some(_), .. })) =>
+                    predict_specific_via_dwell_time(rvdata, basis, target.stop_sequence, ts, context.get_weather_condition(), target.trip),
+                _ => Err(Box::from("Only departures with a known initial delay are chained")),
+            };
+            chained_prediction.or_else(|_| predict_specific(rvdata, basis, target.stop_sequence, ts, context.get_weather_condition(), target.event_type, target.trip))
+        },
+        None => Err(Box::from("No specific statistics for route variant")),
+    };
+
+    // blend that with the default curve if configured, or try a default prediction if it failed:
+    match (specific_prediction, curve_blending) {
+        (Ok(PredictionResult::CurveData(specific)), Some(curve_blending)) => {
+            match predict_general(default_curves, target, context, ts) {
+                Ok(PredictionResult::CurveData(general)) => Ok(PredictionResult::CurveData(curve_blending.blend(&specific, &general))),
+                // without a default curve, the specific curve is still better than nothing
+                _ => Ok(PredictionResult::CurveData(specific)),
+            }
+        },
+        (Ok(PredictionResult::CurveSetData(specific)), Some(curve_blending)) => {
+            match predict_general(default_curves, target, context, ts) {
+                Ok(PredictionResult::CurveData(general)) => Ok(PredictionResult::CurveSetData(curve_blending.blend_curve_set(&specific, &general))),
+                _ => Ok(PredictionResult::CurveSetData(specific)),
+            }
+        },
+        (Ok(prediction), _) => Ok(prediction),
+        (Err(_), _) => predict_general(default_curves, target, context, ts),
+    }
+}
+
+// prepares the lookup parameters for a default curve and looks it up
+fn predict_general(default_curves: &DefaultCurves, target: &PredictionTarget, context: &PredictionContext, ts: &TimeSlot) -> FnResult<PredictionResult> {
+    let key = DefaultCurveKey {
+        route_type: context.schedule.get_route(target.route_id)?.route_type,
+        route_section: default_curves.route_sectioning.get_route_section_by_stop_sequence(context.schedule, &target.trip.id, target.stop_sequence)?,
+        time_slot: ts.clone(),
+        event_type: target.event_type
+    };
+    predict_default(default_curves, &key)
+}
+
+fn get_route_variant(trip: &Trip) -> FnResult<u64> {
+    Ok(trip.route_variant.as_ref().or_error("Trip has no route_variant")?.parse()?)
+}
+
+impl PredictionModel for StatisticsModel {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    /// finds out which kind of curve can be used for this prediction and looks up the requested curve
+    fn predict(&self, basis: &Option<PredictionBasis>, target: &PredictionTarget, context: &PredictionContext) -> FnResult<PredictionResult> {
+        let route_variant = get_route_variant(target.trip)?;
+        let rvdata = self.delay_statistics.get_route_variant_data(target.route_id, route_variant);
+        predict_from_curves(rvdata, &self.delay_statistics.general, self.curve_blending.as_ref(), basis, target, context)
+    }
+}
+
+/// The same as StatisticsModel, but the curves are read from the database (see `CurveStore`)
+/// instead of the all_curves.exp file. Only the route variants that are needed for predictions
+/// are loaded, and they are kept in a cache of limited size.
+pub struct DatabaseModel {
+    store: CurveStore,
+    default_curves: DefaultCurves,
+    curve_blending: Option<CurveBlending>,
+    /// None means that there are no curves for this route variant in the database
+    route_variants: Mutex<HashMap<(String, u64), Option<Arc<RouteVariantData>>>>,
+}
+
+impl DatabaseModel {
+    pub const NAME: &'static str = "database";
+
+    pub fn new(main: &Main, curve_blending: Option<CurveBlending>) -> FnResult<Self> {
+        let store = CurveStore::new(main.pool.clone(), &main.source);
+        // curves that were stored by an older version lack the tables that have been added since
+        store.create_tables()?;
+        let default_curves = store.load_default_curves()?;
+        info!("Loaded {} default curves from the database.", default_curves.all_default_curves.len());
+        Ok(DatabaseModel {
+            store,
+            default_curves,
+            curve_blending,
+            route_variants: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn get_route_variant_data(&self, route_id: &str, route_variant: u64) -> FnResult<Option<Arc<RouteVariantData>>> {
+        let key = (String::from(route_id), route_variant);
+        if let Some(cached) = self.route_variants.lock().unwrap().get(&key) {
+            return Ok(cached.clone());
+        }
+
+        // load without holding the lock, so that other threads are not blocked by the database
+        let loaded = self.store.load_route_variant(route_id, route_variant)?.map(Arc::new);
+
+        let mut route_variants = self.route_variants.lock().unwrap();
+        if route_variants.len() >= MAX_CACHED_ROUTE_VARIANTS {
+            route_variants.clear();
+        }
+        route_variants.insert(key, loaded.clone());
+        Ok(loaded)
+    }
+}
+
+impl PredictionModel for DatabaseModel {
+    fn name(&self) -> &'static str {
+        Self::NAME
+    }
+
+    fn predict(&self, basis: &Option<PredictionBasis>, target: &PredictionTarget, context: &PredictionContext) -> FnResult<PredictionResult> {
+        let route_variant = get_route_variant(target.trip)?;
+        let rvdata = self.get_route_variant_data(target.route_id, route_variant)?;
+        predict_from_curves(rvdata.as_ref().map(|rvdata| rvdata.as_ref()), &self.default_curves, self.curve_blending.as_ref(), basis, target, context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dystonse_curves::{IrregularDynamicCurve, Tup};
+    use dystonse_curves::curve_set::CurveSet;
+    use crate::test_util;
+    use crate::types::{CurveSetData, EventPair};
+
+    fn curve_set(start: f32, end: f32) -> CurveSetData {
+        // the same curve for all delays at the start, so that mixing them doesn't change it
+        let mut curve_set = CurveSet::new();
+        for focus in &[-60.0, 120.0] {
+            curve_set.add_curve(*focus, IrregularDynamicCurve::new(vec![Tup { x: start, y: 0.0 }, Tup { x: end, y: 1.0 }]));
+        }
+        CurveSetData { curve_set, precision_type: PrecisionType::Specific, sample_size: 50, effective_sample_size: None }
+    }
+
+    #[test]
+    fn test_predict_specific_via_dwell_time() {
+        let stop_ids = vec![String::from("a"), String::from("b"), String::from("c")];
+        let trip = test_util::trip("t1", "r1", 8 * 3600, 300, &["a", "b", "c"]);
+        let mut rvdata = RouteVariantData {
+            stop_ids,
+            curve_sets: EventPair { arrival: HashMap::new(), departure: HashMap::new() },
+            general_delay: EventPair { arrival: HashMap::new(), departure: HashMap::new() },
+            dwell_times: HashMap::new(),
+        };
+        rvdata.curve_sets[EventType::Arrival].insert(CurveSetKey { start_stop_index: 0, end_stop_index: 1, time_slot: TimeSlot::DEFAULT, weather: WeatherCondition::Unknown }, curve_set(0.0, 120.0));
+        let basis = Some(PredictionBasis { stop_sequence: 1, delay_departure: Some(0) });
+        let predict = |rvdata: &RouteVariantData| predict_specific_via_dwell_time(rvdata, &basis, 2, &TimeSlot::WORKDAY_MORNING, WeatherCondition::Unknown, &trip);
+
+        // without dwell times, the departure can't be chained
+        assert!(predict(&rvdata).is_err());
+
+        // vehicles always leave between one and three minutes late, whenever they arrive
+        rvdata.dwell_times.insert(DwellTimeKey { stop_index: 1, time_slot: TimeSlot::DEFAULT }, curve_set(60.0, 180.0));
+        let curve_data = match predict(&rvdata).unwrap() {
+            PredictionResult::CurveData(curve_data) => curve_data,
+            _ => panic!("expected a single curve"),
+        };
+        assert!(matches!(curve_data.precision_type, PrecisionType::FallbackSpecific));
+        assert_eq!(curve_data.sample_size, 50);
+        assert!((curve_data.curve.x_at_y(0.5) - 120.0).abs() < 1.0);
+    }
+}
@@ -6,8 +6,9 @@ use mysql::prelude::*;
 
 use simple_error::bail;
 
-use crate::FnResult;
+use crate::{FnResult, OrError};
 use crate::Main;
+use crate::types::GtfsDateTime;
 
 #[derive(Debug)]
 pub struct RealtimeItem {
@@ -29,6 +30,9 @@ pub struct RealtimeItem {
 }
 
 pub fn get_realtime_data(main: &Main, trip: &Trip) -> FnResult<(u16, i32)> {
+    // the current run of the trip may have started on the previous service day
+    let start_time = trip.stop_times[0].departure_time.or_error("Trip has no departure time")?;
+    let start = GtfsDateTime::latest_start_until(&Local::now(), start_time as i32);
     let mut con = main.pool.get_conn()?;
     let stmt = con.prep(
         r"SELECT 
@@ -42,7 +46,7 @@ pub fn get_realtime_data(main: &Main, trip: &Trip) -> FnResult<(u16, i32)> {
             `route_id` = :route_id AND
             `route_variant` = :route_variant AND
             `trip_id`= :trip_id AND 
-            `trip_start_date`=CURDATE() AND
+            `trip_start_date`= :trip_start_date AND
             `trip_start_time`= :trip_start_time
         ORDER BY 
             `time_of_recording` DESC,
@@ -56,7 +60,8 @@ pub fn get_realtime_data(main: &Main, trip: &Trip) -> FnResult<(u16, i32)> {
             "route_id" => &trip.route_id,
             "route_variant" => &trip.route_variant.as_ref().unwrap(),
             "trip_id" => &trip.id,
-            "trip_start_time" => trip.stop_times[0].departure_time
+            "trip_start_date" => start.service_day_param(),
+            "trip_start_time" => start.duration(),
         },
     )?;
 
@@ -69,7 +74,7 @@ pub fn get_realtime_data(main: &Main, trip: &Trip) -> FnResult<(u16, i32)> {
         })
         .collect();
 
-    println!("Got realtime data, found {} rows: {:?}.", realtime_items.len(), realtime_items);
+    debug!("Got realtime data, found {} rows: {:?}.", realtime_items.len(), realtime_items);
 
     // map the (relative) delays from the db to absolute_departures, which are tuples of (stop_id, time)
     let absolute_departures : Vec<(u16, NaiveTime, i32)> = realtime_items.iter().filter_map(|item| {
@@ -85,7 +90,7 @@ pub fn get_realtime_data(main: &Main, trip: &Trip) -> FnResult<(u16, i32)> {
         }
     }).collect();
 
-    println!("Mapped {} rows to absolute times: {:?}", absolute_departures.len(), absolute_departures);
+    debug!("Mapped {} rows to absolute times: {:?}", absolute_departures.len(), absolute_departures);
 
 
     // now find the most recent absolute_departure which is in the past. Since they are ordered
@@ -93,14 +98,14 @@ pub fn get_realtime_data(main: &Main, trip: &Trip) -> FnResult<(u16, i32)> {
     // that is encountered is the correct one.
 
     let now = chrono::Utc::now().time();
-    println!("Comparing to 'now', which is {}.", now);
+    debug!("Comparing to 'now', which is {}.", now);
     match absolute_departures.iter().filter(|(_stop_sequence, time, _delay)| time < &now).next() {
         Some((stop_sequence, time, delay)) => {
-            println!("Found  most recent absolute_departure: at stop_sequence {} on {} with delay {}.", stop_sequence, time, delay);
+            debug!("Found  most recent absolute_departure: at stop_sequence {} on {} with delay {}.", stop_sequence, time, delay);
             Ok((*stop_sequence, *delay))
         },
         None => {
-            println!("Did not find  most recent absolute_departure.");
+            debug!("Did not find  most recent absolute_departure.");
             bail!("No current delay found")
         }
     }
@@ -0,0 +1,31 @@
+/// Sorts `values` and returns the value at quantile `q` (between 0 and 1). The result is always one
+/// of the values, e.g. the upper median for `q = 0.5`, so that it can be compared with the values
+/// themselves. Panics if `values` is empty.
+pub fn quantile<T: Ord + Copy>(values: &mut [T], q: f32) -> T {
+    values.sort_unstable();
+    values[usize::min((values.len() as f32 * q) as usize, values.len() - 1)]
+}
+
+/// The upper median of `values`, see `quantile`.
+pub fn median<T: Ord + Copy>(values: &mut [T]) -> T {
+    quantile(values, 0.5)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantile() {
+        let mut values = vec![300, -30, 60, 0];
+        assert_eq!(median(&mut values), 60);
+        assert_eq!(values, vec![-30, 0, 60, 300]);
+        assert_eq!(median(&mut [5]), 5);
+
+        let mut values: Vec<i32> = (0..=100).rev().collect();
+        assert_eq!(quantile(&mut values, 0.0), 0);
+        assert_eq!(quantile(&mut values, 0.1), 10);
+        assert_eq!(quantile(&mut values, 0.99), 99);
+        assert_eq!(quantile(&mut values, 1.0), 100);
+    }
+}
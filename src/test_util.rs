@@ -0,0 +1,69 @@
+//! Schedules for the unit tests of several modules.
+
+use chrono::NaiveDate;
+use gtfs_structures::{Calendar, Gtfs, Route, RouteType, Stop, StopTime, Trip};
+use std::sync::Arc;
+
+/// A stop which only has an id.
+pub fn stop(id: &str) -> Arc<Stop> {
+    Arc::new(Stop { id: String::from(id), ..Default::default() })
+}
+
+/// A trip along the stops with the given ids, see `trip_with_stops`.
+pub fn trip(id: &str, route_id: &str, start_time: u32, interval: u32, stop_ids: &[&str]) -> Trip {
+    trip_with_stops(id, route_id, start_time, interval, stop_ids.iter().map(|stop_id| stop(stop_id)).collect())
+}
+
+/// A trip which leaves the first stop at `start_time` (in seconds after midnight), reaches each of the
+/// next stops `interval` seconds later than the one before, and doesn't wait at any of them.
+/// The stop sequence starts at 1.
+pub fn trip_with_stops(id: &str, route_id: &str, start_time: u32, interval: u32, stops: Vec<Arc<Stop>>) -> Trip {
+    Trip {
+        id: String::from(id),
+        route_id: String::from(route_id),
+        stop_times: stops.into_iter().enumerate().map(|(index, stop)| StopTime {
+            stop,
+            stop_sequence: index as u16 + 1,
+            arrival_time: Some(start_time + index as u32 * interval),
+            departure_time: Some(start_time + index as u32 * interval),
+            ..Default::default()
+        }).collect(),
+        ..Default::default()
+    }
+}
+
+/// A schedule with the given trips, and a bus route for each of their route ids.
+pub fn schedule(trips: Vec<Trip>) -> Gtfs {
+    let mut schedule = Gtfs::default();
+    for trip in trips {
+        schedule.routes.entry(trip.route_id.clone()).or_insert_with(|| Route {
+            id: trip.route_id.clone(),
+            route_type: RouteType::Bus,
+            ..Default::default()
+        });
+        schedule.trips.insert(trip.id.clone(), trip);
+    }
+    schedule
+}
+
+/// A bus (route "r1", named "1", trip "t1") from "Am Markt" (stop "s1") to "Bahnhof" (stop "s2")
+/// every day of 2020 at 8:00, which takes 10 minutes.
+pub fn get_test_schedule() -> Gtfs {
+    let market = Arc::new(Stop { id: String::from("s1"), name: String::from("Am Markt"), latitude: Some(53.0760), longitude: Some(8.8070), ..Default::default() });
+    let station = Arc::new(Stop { id: String::from("s2"), name: String::from("Bahnhof"), latitude: Some(53.0830), longitude: Some(8.8130), ..Default::default() });
+    let mut schedule = schedule(vec![Trip {
+        service_id: String::from("daily"),
+        trip_headsign: Some(String::from("Bahnhof")),
+        ..trip_with_stops("t1", "r1", 8 * 3600, 600, vec![market.clone(), station.clone()])
+    }]);
+    schedule.stops.insert(market.id.clone(), market);
+    schedule.stops.insert(station.id.clone(), station);
+    schedule.routes.insert(String::from("r1"), Route { id: String::from("r1"), short_name: String::from("1"), route_type: RouteType::Bus, ..Default::default() });
+    schedule.calendar.insert(String::from("daily"), Calendar {
+        id: String::from("daily"),
+        monday: true, tuesday: true, wednesday: true, thursday: true, friday: true, saturday: true, sunday: true,
+        start_date: NaiveDate::from_ymd(2020, 1, 1),
+        end_date: NaiveDate::from_ymd(2020, 12, 31),
+    });
+    schedule
+}
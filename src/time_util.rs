@@ -0,0 +1,133 @@
+use chrono::{Date, DateTime, Duration, TimeZone};
+
+/// Converts a service day and a time (as seconds relative to the service day) into a DateTime.
+///
+/// GTFS defines those times as measured from "noon minus 12h" of the service day, which is
+/// midnight except on days with a DST change. Following that definition makes this conversion
+/// total: it doesn't panic for negative times, for times beyond 24 or 48 hours, or for
+/// times that would fall into a DST gap as a local wall clock time.
+pub fn date_and_time<Tz: TimeZone>(service_day: &Date<Tz>, time: i32) -> DateTime<Tz> {
+    service_day_reference(service_day) + Duration::seconds(time as i64)
+}
+
+/// Returns "noon minus 12h" of the service day, which is the reference point for all
+/// GTFS times of that day.
+fn service_day_reference<Tz: TimeZone>(service_day: &Date<Tz>) -> DateTime<Tz> {
+    let time_zone = service_day.timezone();
+    let naive_noon = service_day.naive_local().and_hms(12, 0, 0);
+    // noon exists in all real-world time zones, but we don't want to panic if it doesn't:
+    let noon = time_zone
+        .from_local_datetime(&naive_noon)
+        .earliest()
+        .unwrap_or_else(|| time_zone.from_utc_datetime(&naive_noon));
+    noon - Duration::hours(12)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{FixedOffset, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+
+    /// Minimal time zone that behaves like Europe/Berlin in 2020
+    /// (DST from 2020-03-29 01:00 UTC to 2020-10-25 01:00 UTC).
+    #[derive(Clone, Copy, Debug)]
+    struct Berlin2020;
+
+    impl Berlin2020 {
+        fn dst_start() -> NaiveDateTime { NaiveDate::from_ymd(2020, 3, 29).and_hms(1, 0, 0) }
+        fn dst_end() -> NaiveDateTime { NaiveDate::from_ymd(2020, 10, 25).and_hms(1, 0, 0) }
+        fn cet() -> FixedOffset { FixedOffset::east(3600) }
+        fn cest() -> FixedOffset { FixedOffset::east(7200) }
+    }
+
+    impl TimeZone for Berlin2020 {
+        type Offset = FixedOffset;
+
+        fn from_offset(_offset: &FixedOffset) -> Self {
+            Berlin2020
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_hms(12, 0, 0))
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            let candidates: Vec<FixedOffset> = vec![Self::cet(), Self::cest()]
+                .into_iter()
+                .filter(|offset| self.offset_from_utc_datetime(&(*local - Duration::seconds(offset.local_minus_utc() as i64))) == *offset)
+                .collect();
+            match candidates.len() {
+                0 => LocalResult::None,
+                1 => LocalResult::Single(candidates[0]),
+                // CEST is the earlier one of both points in time
+                _ => LocalResult::Ambiguous(candidates[1], candidates[0]),
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_hms(12, 0, 0))
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            if *utc >= Self::dst_start() && *utc < Self::dst_end() {
+                Self::cest()
+            } else {
+                Self::cet()
+            }
+        }
+    }
+
+    // days around both DST changes, and some regular days
+    fn test_days() -> Vec<Date<Berlin2020>> {
+        let mut days = Vec::new();
+        for (month, day) in &[(3, 27), (10, 23), (6, 15), (12, 31)] {
+            let first = NaiveDate::from_ymd(2020, *month, *day);
+            for offset in 0..5 {
+                let naive = first + Duration::days(offset);
+                days.push(Berlin2020.from_local_date(&naive).unwrap());
+            }
+        }
+        days
+    }
+
+    fn test_times() -> Vec<i32> {
+        (-2 * 86400..4 * 86400).step_by(599).chain(vec![-1, 0, 1, 86399, 86400, 86401, 2 * 86400, 3 * 86400]).collect()
+    }
+
+    #[test]
+    fn test_date_and_time_is_linear() {
+        for day in test_days() {
+            let reference = date_and_time(&day, 0);
+            for time in test_times() {
+                let date_time = date_and_time(&day, time);
+                assert_eq!(date_time.signed_duration_since(reference.clone()), Duration::seconds(time as i64), "day {:?}, time {}", day, time);
+            }
+        }
+    }
+
+    #[test]
+    fn test_date_and_time_matches_wall_clock_on_regular_days() {
+        for day in test_days() {
+            let naive_day = day.naive_local();
+            if date_and_time(&day, 0).offset() != date_and_time(&day, 86400 * 2).offset() {
+                continue; // DST changes within the next two days, wall clock time differs
+            }
+            for time in test_times().into_iter().filter(|t| *t >= 0 && *t < 2 * 86400) {
+                assert_eq!(date_and_time(&day, time).naive_local(), naive_day.and_hms(0, 0, 0) + Duration::seconds(time as i64), "day {:?}, time {}", day, time);
+            }
+        }
+    }
+
+    #[test]
+    fn test_date_and_time_on_dst_days() {
+        // times after the DST change are shown at their scheduled wall clock time
+        let spring = Berlin2020.ymd(2020, 3, 29);
+        assert_eq!(date_and_time(&spring, 8 * 3600).naive_local().time(), NaiveTime::from_hms(8, 0, 0));
+        let autumn = Berlin2020.ymd(2020, 10, 25);
+        assert_eq!(date_and_time(&autumn, 8 * 3600).naive_local().time(), NaiveTime::from_hms(8, 0, 0));
+
+        // 02:30 does not exist as wall clock time on the spring day, but must not panic
+        let in_gap = date_and_time(&spring, 2 * 3600 + 1800);
+        assert_eq!(in_gap.naive_local().minute(), 30);
+    }
+}
@@ -0,0 +1,100 @@
+use chrono::{Date, Local};
+use clap::{Arg, ArgMatches};
+use simple_error::bail;
+
+use crate::FnResult;
+
+/// Gives older records less weight in the curves than recent ones, so that the curves follow changes of
+/// the timetable or of the traffic sooner. The weight halves every `half_life_days` days, counted from
+/// the start date of the trip of a record to `reference_date`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AgeWeighting {
+    pub half_life_days: f32,
+    pub reference_date: Date<Local>,
+}
+
+impl AgeWeighting {
+    /// The argument that is read by `from_args`, for the subcommands that compute curves.
+    pub fn get_arg<'a>() -> Arg<'a> {
+        Arg::new("half-life")
+            .long("half-life")
+            .about("If provided, older records count less for the curves than recent ones: their weight halves every this many days, counted back from today.")
+            .value_name("DAYS")
+            .takes_value(true)
+    }
+
+    /// Creates the weighting that is configured with the `half-life` argument, relative to today.
+    /// Returns None if all records shall be weighted equally.
+    pub fn from_args(args: &ArgMatches) -> FnResult<Option<Self>> {
+        let half_life_days: f32 = match args.value_of("half-life") {
+            Some(half_life) => half_life.parse()?,
+            None => return Ok(None),
+        };
+        if half_life_days <= 0.0 {
+            bail!("Half-life must be positive.");
+        }
+        Ok(Some(AgeWeighting { half_life_days, reference_date: Local::today() }))
+    }
+
+    /// Returns the weight (between 0 and 1) of a record of a trip that started on `trip_start_date`.
+    /// Records without date and records from the future get the full weight.
+    pub fn get_weight(&self, trip_start_date: Option<Date<Local>>) -> f32 {
+        let age_days = match trip_start_date {
+            Some(date) => (self.reference_date - date).num_days().max(0) as f32,
+            None => 0.0,
+        };
+        0.5f32.powf(age_days / self.half_life_days)
+    }
+}
+
+/// The number of equally weighted records that would give a curve of the same precision as the weighted
+/// ones (Kish's effective sample size). Returns None if all records have the full weight, because then
+/// it is just the number of records.
+pub fn effective_sample_size(weights: impl Iterator<Item = f32>) -> Option<f32> {
+    let mut is_weighted = false;
+    let (sum, sum_of_squares) = weights.fold((0.0, 0.0), |(sum, sum_of_squares), weight| {
+        is_weighted |= weight != 1.0;
+        (sum + weight, sum_of_squares + weight * weight)
+    });
+    if !is_weighted {
+        return None;
+    }
+    if sum_of_squares <= 0.0 {
+        return Some(0.0);
+    }
+    Some(sum * sum / sum_of_squares)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn test_age_weighting() {
+        let reference_date = Local.ymd(2020, 10, 17);
+        let weighting = AgeWeighting { half_life_days: 30.0, reference_date };
+        assert_eq!(weighting.get_weight(Some(reference_date)), 1.0);
+        assert_eq!(weighting.get_weight(Some(reference_date - Duration::days(30))), 0.5);
+        assert_eq!(weighting.get_weight(Some(reference_date - Duration::days(60))), 0.25);
+        assert_eq!(weighting.get_weight(Some(reference_date + Duration::days(1))), 1.0);
+        assert_eq!(weighting.get_weight(None), 1.0);
+
+        assert_eq!(effective_sample_size(vec![1.0; 40].into_iter()), None);
+        assert_eq!(effective_sample_size(vec![1.0, 1.0, 0.0, 0.0].into_iter()), Some(2.0));
+        assert_eq!(effective_sample_size(vec![0.5; 10].into_iter()), Some(10.0));
+        assert_eq!(effective_sample_size(vec![].into_iter()), None);
+    }
+
+    #[test]
+    fn test_from_args() {
+        let from_args = |args: &[&str]| -> FnResult<Option<AgeWeighting>> {
+            let matches = clap::App::new("compute-curves").arg(AgeWeighting::get_arg()).try_get_matches_from(args)?;
+            AgeWeighting::from_args(&matches)
+        };
+        assert_eq!(from_args(&["compute-curves"]).unwrap(), None);
+        assert_eq!(from_args(&["compute-curves", "--half-life", "30"]).unwrap().unwrap().half_life_days, 30.0);
+        assert!(from_args(&["compute-curves", "--half-life", "0"]).is_err());
+        assert!(from_args(&["compute-curves", "--half-life", "a month"]).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+use clap::ArgMatches;
+use gtfs_structures::Gtfs;
+use std::collections::HashSet;
+
+/// The agencies selected with the `agency-ids` argument. Feeds may contain the routes of many
+/// agencies, and only the trips of the selected agencies are imported or analysed.
+/// Without the argument, all agencies are selected.
+#[derive(Debug, Clone, Default)]
+pub struct AgencyFilter {
+    agency_ids: Option<HashSet<String>>,
+}
+
+impl AgencyFilter {
+    pub fn from_args(args: &ArgMatches) -> Self {
+        AgencyFilter {
+            agency_ids: args.values_of("agency-ids").map(|ids| ids.map(|id| String::from(id.trim())).collect()),
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.agency_ids.is_some()
+    }
+
+    /// Returns whether the route belongs to one of the selected agencies. Routes without
+    /// agency_id belong to the agency of the schedule, if it has only one.
+    /// Routes that are not in the schedule never belong to a selected agency.
+    pub fn contains_route(&self, schedule: &Gtfs, route_id: &str) -> bool {
+        if !self.is_active() {
+            return true;
+        }
+        match schedule.get_route(route_id) {
+            Ok(route) => {
+                let agency_id = route.agency_id.as_ref().or_else(|| match schedule.agencies.as_slice() {
+                    [agency] => agency.id.as_ref(),
+                    _ => None,
+                });
+                self.contains_agency(agency_id)
+            },
+            Err(_) => false,
+        }
+    }
+
+    fn contains_agency(&self, agency_id: Option<&String>) -> bool {
+        match (&self.agency_ids, agency_id) {
+            (None, _) => true,
+            (Some(agency_ids), Some(agency_id)) => agency_ids.contains(agency_id),
+            (Some(_), None) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contains_agency() {
+        let all = AgencyFilter::default();
+        assert!(!all.is_active());
+        assert!(all.contains_agency(None));
+        assert!(all.contains_agency(Some(&String::from("1"))));
+
+        let some = AgencyFilter { agency_ids: Some(vec![String::from("1"), String::from("5")].into_iter().collect()) };
+        assert!(some.is_active());
+        assert!(some.contains_agency(Some(&String::from("5"))));
+        assert!(!some.contains_agency(Some(&String::from("2"))));
+        assert!(!some.contains_agency(None));
+    }
+}
@@ -0,0 +1,111 @@
+use serde::{Serialize, Deserialize};
+
+use crate::types::PrecisionType;
+
+// with fewer scored predictions, the histogram is too noisy to tell good from bad calibration
+const MIN_PREDICTIONS_FOR_RATING: u32 = 500;
+
+// maximum calibration errors for three and two stars
+const THREE_STARS_MAX_ERROR: f32 = 0.1;
+const TWO_STARS_MAX_ERROR: f32 = 0.2;
+
+/// Identifies the predictions of a route that have been made with one kind of curve.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub struct CalibrationKey {
+    pub route_id: String,
+    /// see `PrecisionType::to_int`
+    pub precision_type: u8,
+}
+
+impl CalibrationKey {
+    pub fn new(route_id: &str, precision_type: &PrecisionType) -> Self {
+        CalibrationKey {
+            route_id: String::from(route_id),
+            precision_type: precision_type.to_int(),
+        }
+    }
+}
+
+/// Counts into which decile of the predicted distributions the delays fell that actually occured
+/// (a histogram of the probability integral transform). For perfectly calibrated predictions, each
+/// decile gets a tenth of the observations. Collected by `analyse replay --save-calibration`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct CalibrationCounts {
+    pub pit_histogram: [u32; 10],
+}
+
+impl CalibrationCounts {
+    /// Adds an observed delay, given as the predicted probability that the delay is at most the observed one.
+    pub fn add_observation(&mut self, probability: f32) {
+        let decile = (probability * 10.0) as usize;
+        self.pit_histogram[usize::min(decile, 9)] += 1;
+    }
+
+    pub fn add(&mut self, other: &CalibrationCounts) {
+        for (count, other_count) in self.pit_histogram.iter_mut().zip(other.pit_histogram.iter()) {
+            *count += other_count;
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        self.pit_histogram.iter().sum()
+    }
+
+    /// Share of observations that would have to fall into another decile of the predicted
+    /// distributions to make them perfectly calibrated, between 0 (perfect) and 0.9.
+    pub fn calibration_error(&self) -> f32 {
+        let count = self.count();
+        if count == 0 {
+            return 0.0;
+        }
+        let deviation: f32 = self.pit_histogram.iter().map(|c| (*c as f32 / count as f32 - 0.1).abs()).sum();
+        deviation / 2.0
+    }
+
+    /// Rates the calibration from 1 (bad) to 3 (good) stars, or returns None if there are
+    /// too few observations to rate it.
+    pub fn stars(&self) -> Option<u8> {
+        if self.count() < MIN_PREDICTIONS_FOR_RATING {
+            return None;
+        }
+        let error = self.calibration_error();
+        Some(if error <= THREE_STARS_MAX_ERROR {
+            3
+        } else if error <= TWO_STARS_MAX_ERROR {
+            2
+        } else {
+            1
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibration() {
+        let mut uniform = CalibrationCounts::default();
+        for i in 0..1000 {
+            uniform.add_observation(i as f32 / 1000.0);
+        }
+        assert_eq!(uniform.count(), 1000);
+        assert!(uniform.calibration_error() < 0.001);
+        assert_eq!(uniform.stars(), Some(3));
+
+        // all delays were later than predicted
+        let mut late = CalibrationCounts::default();
+        for _ in 0..600 {
+            late.add_observation(1.0);
+        }
+        assert!((late.calibration_error() - 0.9).abs() < 0.001);
+        assert_eq!(late.stars(), Some(1));
+
+        let mut few = CalibrationCounts::default();
+        few.add_observation(0.5);
+        assert_eq!(few.stars(), None);
+        few.add(&late);
+        assert_eq!(few.count(), 601);
+        assert_eq!(few.pit_histogram[5], 1);
+    }
+}
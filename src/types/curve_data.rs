@@ -9,12 +9,19 @@ use dystonse_curves::{
 
 use super::PrecisionType;
 
+/// Quantiles of an uncertain delay (e.g. the departure delay at the first stop) for which the
+/// curves that depend on that delay are averaged.
+pub const MIXTURE_QUANTILES: [f32; 10] = [0.05, 0.15, 0.25, 0.35, 0.45, 0.55, 0.65, 0.75, 0.85, 0.95];
+
 // A curve with some metadata about its quality and origin:
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct CurveData {
     pub curve: IrregularDynamicCurve<f32, f32>,
     pub precision_type: PrecisionType,
     pub sample_size: u32,
+    /// only set if the records have been weighted by their age, see `AgeWeighting`
+    #[serde(default)]
+    pub effective_sample_size: Option<f32>,
 }
 
 impl CurveData {
@@ -31,15 +38,28 @@ impl CurveData {
             sample_size += curve_data.sample_size;
         }
         sample_size /= curves.len() as u32;
+        // unweighted curves count with their sample size
+        let effective_sample_size = if data.iter().any(|curve_data| curve_data.effective_sample_size.is_some()) {
+            Some(data.iter().map(|curve_data| curve_data.get_effective_sample_size()).sum::<f32>() / curves.len() as f32)
+        } else {
+            None
+        };
 
         let curve = IrregularDynamicCurve::<f32, f32>::average(&curves);
 
         Ok(CurveData {
             curve,
             precision_type,
-            sample_size
+            sample_size,
+            effective_sample_size,
         })
-    } 
+    }
+
+    /// The sample size that the curve is worth, which is smaller than `sample_size` if older records
+    /// had less weight.
+    pub fn get_effective_sample_size(&self) -> f32 {
+        self.effective_sample_size.unwrap_or(self.sample_size as f32)
+    }
 }
 
 // A curveset with some metadata about its quality and origin:
@@ -48,4 +68,14 @@ pub struct CurveSetData {
     pub curve_set: CurveSet<f32, IrregularDynamicCurve<f32,f32>>,
     pub precision_type: PrecisionType,
     pub sample_size: u32,
+    /// only set if the records have been weighted by their age, average per curve like `sample_size`
+    #[serde(default)]
+    pub effective_sample_size: Option<f32>,
+}
+
+impl CurveSetData {
+    /// The sample size that each curve is worth on average, see `CurveData::get_effective_sample_size`.
+    pub fn get_effective_sample_size(&self) -> f32 {
+        self.effective_sample_size.unwrap_or(self.sample_size as f32)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,195 @@
+//! Versioned serialization of prediction curves, as they are stored in the `prediction_curve` column
+//! of the `predictions` table, and as JSON for external consumers.
+//!
+//! A curve is the cumulative distribution of a delay: a list of points (delay in seconds, probability
+//! between 0 and 1), ordered by delay, with non-decreasing probabilities.
+//!
+//! The compact binary format (version 1) consists of:
+//!
+//! * the magic bytes `DYC` and a version byte (currently 1),
+//! * the number of points as unsigned 16-bit little-endian integer,
+//! * for each point, the delay in whole seconds as signed 32-bit little-endian integer, and the
+//!   probability multiplied by 65535 as unsigned 16-bit little-endian integer.
+//!
+//! Rows that have been written before this format existed contain the compact format of the
+//! dystonse-curves crate without a header, which is still read by `decode_compact`.
+//!
+//! The JSON format is described by the schema that `PredictionCurve::json_schema` generates.
+
+use dystonse_curves::{Curve, IrregularDynamicCurve, Tup};
+use schemars::JsonSchema;
+use serde::{Serialize, Deserialize};
+use simple_error::bail;
+use std::convert::TryInto;
+
+use crate::FnResult;
+
+/// The version of the compact binary and the JSON format that is written.
+pub const CURVE_FORMAT_VERSION: u8 = 1;
+
+/// Curves with more points are simplified before they are written.
+pub const MAX_COMPACT_POINTS: usize = 120;
+
+const MAGIC: &[u8; 3] = b"DYC";
+const HEADER_LENGTH: usize = 6;
+const POINT_LENGTH: usize = 6;
+
+/// A prediction curve in its stable JSON representation.
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct PredictionCurve {
+    /// Version of the format, currently 1.
+    pub version: u8,
+    /// Points of the cumulative distribution of the delay, ordered by delay.
+    pub points: Vec<CurvePoint>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema, PartialEq, Clone, Copy)]
+pub struct CurvePoint {
+    /// Delay in seconds, negative if early.
+    pub delay: f32,
+    /// Probability (between 0 and 1) that the delay is at most `delay`.
+    pub probability: f32,
+}
+
+impl PredictionCurve {
+    pub fn from_curve(curve: &IrregularDynamicCurve<f32, f32>) -> Self {
+        let (xs, ys) = curve.get_values_as_vectors();
+        PredictionCurve {
+            version: CURVE_FORMAT_VERSION,
+            points: xs.into_iter().zip(ys.into_iter()).map(|(delay, probability)| CurvePoint { delay, probability }).collect(),
+        }
+    }
+
+    /// Checks the version and the points, and returns the curve.
+    pub fn to_curve(&self) -> FnResult<IrregularDynamicCurve<f32, f32>> {
+        if self.version != CURVE_FORMAT_VERSION {
+            bail!("Unsupported curve format version {}, only version {} is known.", self.version, CURVE_FORMAT_VERSION);
+        }
+        check_points(&self.points)?;
+        Ok(IrregularDynamicCurve::new(self.points.iter().map(|point| Tup { x: point.delay, y: point.probability }).collect()))
+    }
+
+    pub fn to_json(&self) -> FnResult<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &str) -> FnResult<Self> {
+        let curve: PredictionCurve = serde_json::from_str(json)?;
+        curve.to_curve()?;
+        Ok(curve)
+    }
+
+    /// Returns the JSON schema of the format, generated from the types of this module.
+    pub fn json_schema() -> FnResult<String> {
+        Ok(serde_json::to_string_pretty(&schemars::schema_for!(PredictionCurve))?)
+    }
+}
+
+/// Writes the curve in the compact binary format, simplified to at most `MAX_COMPACT_POINTS` points.
+pub fn encode_compact(curve: &IrregularDynamicCurve<f32, f32>) -> Vec<u8> {
+    let points = limit_points(curve);
+    let mut bytes = Vec::with_capacity(HEADER_LENGTH + points.len() * POINT_LENGTH);
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(CURVE_FORMAT_VERSION);
+    bytes.extend_from_slice(&(points.len() as u16).to_le_bytes());
+    for point in &points {
+        bytes.extend_from_slice(&(point.delay.round() as i32).to_le_bytes());
+        bytes.extend_from_slice(&((point.probability.max(0.0).min(1.0) * 65535.0).round() as u16).to_le_bytes());
+    }
+    bytes
+}
+
+/// Reads a curve from the compact binary format, or from the format of the dystonse-curves crate
+/// for rows that have been written before.
+pub fn decode_compact(bytes: &[u8]) -> FnResult<IrregularDynamicCurve<f32, f32>> {
+    if !bytes.starts_with(MAGIC) {
+        if bytes.is_empty() {
+            bail!("Curve data is empty.");
+        }
+        return Ok(IrregularDynamicCurve::<f32, f32>::deserialize_compact(bytes.to_vec()));
+    }
+    if bytes.len() < HEADER_LENGTH {
+        bail!("Curve data is too short for its header.");
+    }
+    if bytes[3] != CURVE_FORMAT_VERSION {
+        bail!("Unsupported curve format version {}, only version {} is known.", bytes[3], CURVE_FORMAT_VERSION);
+    }
+    let count = u16::from_le_bytes(bytes[4..6].try_into().unwrap()) as usize; // length was checked
+    if bytes.len() != HEADER_LENGTH + count * POINT_LENGTH {
+        bail!("Curve data has {} bytes, but {} points need {} bytes.", bytes.len(), count, HEADER_LENGTH + count * POINT_LENGTH);
+    }
+    let points: Vec<CurvePoint> = bytes[HEADER_LENGTH..].chunks(POINT_LENGTH).map(|chunk| CurvePoint {
+        delay: i32::from_le_bytes(chunk[0..4].try_into().unwrap()) as f32, // chunks have the right length
+        probability: u16::from_le_bytes(chunk[4..6].try_into().unwrap()) as f32 / 65535.0,
+    }).collect();
+    check_points(&points)?;
+    Ok(IrregularDynamicCurve::new(points.iter().map(|point| Tup { x: point.delay, y: point.probability }).collect()))
+}
+
+// simplifies the curve with increasing tolerance until it has few enough points
+fn limit_points(curve: &IrregularDynamicCurve<f32, f32>) -> Vec<CurvePoint> {
+    let mut curve = curve.clone();
+    let mut tolerance = 0.001;
+    while curve.get_values_as_vectors().0.len() > MAX_COMPACT_POINTS {
+        curve.simplify(tolerance);
+        tolerance *= 2.0;
+    }
+    PredictionCurve::from_curve(&curve).points
+}
+
+fn check_points(points: &[CurvePoint]) -> FnResult<()> {
+    if points.is_empty() {
+        bail!("Curve has no points.");
+    }
+    for pair in points.windows(2) {
+        if pair[1].delay < pair[0].delay || pair[1].probability < pair[0].probability {
+            bail!("Points of the curve are not ordered: {:?} is followed by {:?}.", pair[0], pair[1]);
+        }
+    }
+    if points.iter().any(|point| !point.delay.is_finite() || !(0.0..=1.0).contains(&point.probability)) {
+        bail!("Curve has points with invalid delays or probabilities.");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve() -> IrregularDynamicCurve<f32, f32> {
+        IrregularDynamicCurve::new(vec![Tup { x: -60.0, y: 0.0 }, Tup { x: 0.0, y: 0.25 }, Tup { x: 120.0, y: 0.75 }, Tup { x: 600.0, y: 1.0 }])
+    }
+
+    #[test]
+    fn test_compact_roundtrip() {
+        let bytes = encode_compact(&curve());
+        assert_eq!(&bytes[0..4], b"DYC\x01");
+        assert_eq!(bytes.len(), 6 + 4 * 6);
+        let decoded = decode_compact(&bytes).unwrap();
+        assert_eq!(PredictionCurve::from_curve(&decoded), PredictionCurve::from_curve(&curve()));
+
+        assert!(decode_compact(&bytes[..bytes.len() - 1]).is_err());
+        let mut future = bytes.clone();
+        future[3] = 2;
+        assert!(decode_compact(&future).is_err());
+    }
+
+    #[test]
+    fn test_compact_limit() {
+        let points: Vec<Tup<f32, f32>> = (0..=1000).map(|i| Tup { x: i as f32, y: (i as f32 / 1000.0).powi(2) }).collect();
+        let bytes = encode_compact(&IrregularDynamicCurve::new(points));
+        let decoded = decode_compact(&bytes).unwrap();
+        assert!(decoded.get_values_as_vectors().0.len() <= MAX_COMPACT_POINTS);
+        assert!((decoded.y_at_x(500.0) - 0.25).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_json() {
+        let json = PredictionCurve::from_curve(&curve()).to_json().unwrap();
+        assert!(json.starts_with(r#"{"version":1,"points":[{"delay":-60.0,"probability":0.0}"#));
+        assert_eq!(PredictionCurve::from_json(&json).unwrap(), PredictionCurve::from_curve(&curve()));
+        assert!(PredictionCurve::from_json(r#"{"version":1,"points":[{"delay":0.0,"probability":1.0},{"delay":10.0,"probability":0.5}]}"#).is_err());
+        assert!(PredictionCurve::from_json(r#"{"version":7,"points":[{"delay":0.0,"probability":1.0}]}"#).is_err());
+        assert!(PredictionCurve::json_schema().unwrap().contains("probability"));
+    }
+}
@@ -0,0 +1,26 @@
+use serde::{Serialize, Deserialize};
+
+/// Parameters for the computation of the curve sets of a route. They can be chosen per route
+/// with `analyse tune-curves`, routes without tuned parameters use `CurveParameters::DEFAULT`.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct CurveParameters {
+    /// tolerance with which the curves of a curve set are simplified. Larger values lead
+    /// to smaller curves, which are less exact.
+    pub simplification_tolerance: f32,
+    /// minimum number of data points between two markers of a curve set. Smaller values
+    /// lead to more curves per curve set, each of which is based on less data.
+    pub min_points_per_marker: f32,
+}
+
+impl CurveParameters {
+    pub const DEFAULT: CurveParameters = CurveParameters {
+        simplification_tolerance: 0.001,
+        min_points_per_marker: 20.0,
+    };
+}
+
+impl Default for CurveParameters {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
@@ -0,0 +1,319 @@
+use mysql::*;
+use mysql::prelude::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{FnResult, OrError};
+use super::{CurveData, CurveSetData, CurveSetKey, DefaultCurveKey, DwellTimeKey, DefaultCurves, EventType, RouteData, RouteSectioning, RouteVariantData, TimeSlot, WeatherCondition};
+
+/// Stores specific and default curves in database tables, as an alternative to the
+/// all_curves.exp file. Each route can be updated on its own, and readers can load
+/// only the route variants they need.
+pub struct CurveStore {
+    pool: Arc<Pool>,
+    source: String,
+}
+
+impl CurveStore {
+    pub fn new(pool: Arc<Pool>, source: &str) -> Self {
+        CurveStore {
+            pool,
+            source: String::from(source),
+        }
+    }
+
+    pub fn create_tables(&self) -> FnResult<()> {
+        let mut con = self.pool.get_conn()?;
+        con.query_drop(r"CREATE TABLE IF NOT EXISTS `curve_route_variants` (
+            `source` VARCHAR(255) NOT NULL,
+            `route_id` VARCHAR(255) NOT NULL,
+            `route_variant` BIGINT UNSIGNED NOT NULL,
+            `stop_ids` MEDIUMBLOB NOT NULL,
+            PRIMARY KEY (`source`, `route_id`, `route_variant`)
+        );")?;
+        con.query_drop(r"CREATE TABLE IF NOT EXISTS `curve_merged_variants` (
+            `source` VARCHAR(255) NOT NULL,
+            `route_id` VARCHAR(255) NOT NULL,
+            `route_variant` BIGINT UNSIGNED NOT NULL,
+            `merged_into` BIGINT UNSIGNED NOT NULL,
+            PRIMARY KEY (`source`, `route_id`, `route_variant`)
+        );")?;
+        con.query_drop(r"CREATE TABLE IF NOT EXISTS `curve_general_delays` (
+            `source` VARCHAR(255) NOT NULL,
+            `route_id` VARCHAR(255) NOT NULL,
+            `route_variant` BIGINT UNSIGNED NOT NULL,
+            `stop_index` INT UNSIGNED NOT NULL,
+            `event_type` TINYINT UNSIGNED NOT NULL,
+            `sample_size` INT UNSIGNED NOT NULL,
+            `curve_data` MEDIUMBLOB NOT NULL,
+            PRIMARY KEY (`source`, `route_id`, `route_variant`, `stop_index`, `event_type`)
+        );")?;
+        con.query_drop(r"CREATE TABLE IF NOT EXISTS `curve_sets` (
+            `source` VARCHAR(255) NOT NULL,
+            `route_id` VARCHAR(255) NOT NULL,
+            `route_variant` BIGINT UNSIGNED NOT NULL,
+            `start_stop_index` INT UNSIGNED NOT NULL,
+            `end_stop_index` INT UNSIGNED NOT NULL,
+            `time_slot` TINYINT UNSIGNED NOT NULL,
+            `weather_condition` TINYINT UNSIGNED NOT NULL DEFAULT 0,
+            `event_type` TINYINT UNSIGNED NOT NULL,
+            `sample_size` INT UNSIGNED NOT NULL,
+            `curve_set_data` MEDIUMBLOB NOT NULL,
+            PRIMARY KEY (`source`, `route_id`, `route_variant`, `start_stop_index`, `end_stop_index`, `time_slot`, `weather_condition`, `event_type`)
+        );")?;
+        con.query_drop(r"CREATE TABLE IF NOT EXISTS `curve_dwell_times` (
+            `source` VARCHAR(255) NOT NULL,
+            `route_id` VARCHAR(255) NOT NULL,
+            `route_variant` BIGINT UNSIGNED NOT NULL,
+            `stop_index` INT UNSIGNED NOT NULL,
+            `time_slot` TINYINT UNSIGNED NOT NULL,
+            `sample_size` INT UNSIGNED NOT NULL,
+            `curve_set_data` MEDIUMBLOB NOT NULL,
+            PRIMARY KEY (`source`, `route_id`, `route_variant`, `stop_index`, `time_slot`)
+        );")?;
+        con.query_drop(r"CREATE TABLE IF NOT EXISTS `curve_defaults` (
+            `source` VARCHAR(255) NOT NULL,
+            `curve_key` VARCHAR(255) NOT NULL,
+            `curve_data` MEDIUMBLOB NOT NULL,
+            PRIMARY KEY (`source`, `curve_key`)
+        );")?;
+        con.query_drop(r"CREATE TABLE IF NOT EXISTS `curve_route_sectioning` (
+            `source` VARCHAR(255) NOT NULL,
+            `route_sectioning` MEDIUMBLOB NOT NULL,
+            PRIMARY KEY (`source`)
+        );")?;
+        Ok(())
+    }
+
+    /// Replaces all curves of the route with the given ones. Other routes are not touched.
+    pub fn save_route_data(&self, route_data: &RouteData) -> FnResult<()> {
+        let route_id = &route_data.route_id;
+        let mut variant_params = Vec::new();
+        let mut general_params = Vec::new();
+        let mut curve_set_params = Vec::new();
+        let mut dwell_time_params = Vec::new();
+        let merged_params: Vec<Params> = route_data.merged_variants.iter().map(|(route_variant, merged_into)| params! {
+            "source" => &self.source,
+            route_id,
+            route_variant,
+            merged_into,
+        }).collect();
+        for (route_variant, variant_data) in &route_data.variants {
+            variant_params.push(params! {
+                "source" => &self.source,
+                route_id,
+                route_variant,
+                "stop_ids" => rmp_serde::to_vec(&variant_data.stop_ids)?,
+            });
+            for et in &EventType::TYPES {
+                for (stop_index, curve_data) in &variant_data.general_delay[**et] {
+                    general_params.push(params! {
+                        "source" => &self.source,
+                        route_id,
+                        route_variant,
+                        stop_index,
+                        "event_type" => et.to_int(),
+                        "sample_size" => curve_data.sample_size,
+                        "curve_data" => rmp_serde::to_vec(curve_data)?,
+                    });
+                }
+                for (key, curve_set_data) in &variant_data.curve_sets[**et] {
+                    curve_set_params.push(params! {
+                        "source" => &self.source,
+                        route_id,
+                        route_variant,
+                        "start_stop_index" => key.start_stop_index,
+                        "end_stop_index" => key.end_stop_index,
+                        "time_slot" => key.time_slot.id,
+                        "weather_condition" => key.weather.to_int(),
+                        "event_type" => et.to_int(),
+                        "sample_size" => curve_set_data.sample_size,
+                        "curve_set_data" => rmp_serde::to_vec(curve_set_data)?,
+                    });
+                }
+            }
+            for (key, curve_set_data) in &variant_data.dwell_times {
+                dwell_time_params.push(params! {
+                    "source" => &self.source,
+                    route_id,
+                    route_variant,
+                    "stop_index" => key.stop_index,
+                    "time_slot" => key.time_slot.id,
+                    "sample_size" => curve_set_data.sample_size,
+                    "curve_set_data" => rmp_serde::to_vec(curve_set_data)?,
+                });
+            }
+        }
+
+        // old curves of this route are deleted in the same transaction, so that readers
+        // never see a mix of old and new curves
+        let mut con = self.pool.get_conn()?;
+        let mut tx = con.start_transaction(TxOpts::default())?;
+        for table in &["curve_route_variants", "curve_merged_variants", "curve_general_delays", "curve_sets", "curve_dwell_times"] {
+            tx.exec_drop(
+                format!("DELETE FROM `{}` WHERE `source` = :source AND `route_id` = :route_id", table),
+                params! {
+                    "source" => &self.source,
+                    route_id,
+                },
+            )?;
+        }
+        tx.exec_batch(
+            r"INSERT INTO `curve_route_variants` (`source`, `route_id`, `route_variant`, `stop_ids`)
+            VALUES (:source, :route_id, :route_variant, :stop_ids)",
+            variant_params,
+        )?;
+        tx.exec_batch(
+            r"INSERT INTO `curve_merged_variants` (`source`, `route_id`, `route_variant`, `merged_into`)
+            VALUES (:source, :route_id, :route_variant, :merged_into)",
+            merged_params,
+        )?;
+        tx.exec_batch(
+            r"INSERT INTO `curve_general_delays` (`source`, `route_id`, `route_variant`, `stop_index`, `event_type`, `sample_size`, `curve_data`)
+            VALUES (:source, :route_id, :route_variant, :stop_index, :event_type, :sample_size, :curve_data)",
+            general_params,
+        )?;
+        tx.exec_batch(
+            r"INSERT INTO `curve_sets` (`source`, `route_id`, `route_variant`, `start_stop_index`, `end_stop_index`, `time_slot`, `weather_condition`, `event_type`, `sample_size`, `curve_set_data`)
+            VALUES (:source, :route_id, :route_variant, :start_stop_index, :end_stop_index, :time_slot, :weather_condition, :event_type, :sample_size, :curve_set_data)",
+            curve_set_params,
+        )?;
+        tx.exec_batch(
+            r"INSERT INTO `curve_dwell_times` (`source`, `route_id`, `route_variant`, `stop_index`, `time_slot`, `sample_size`, `curve_set_data`)
+            VALUES (:source, :route_id, :route_variant, :stop_index, :time_slot, :sample_size, :curve_set_data)",
+            dwell_time_params,
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Replaces all default curves and the route sectioning with which they have been computed.
+    pub fn save_default_curves(&self, default_curves: &DefaultCurves) -> FnResult<()> {
+        let mut params_vec = Vec::new();
+        for (key, curve_data) in &default_curves.all_default_curves {
+            params_vec.push(params! {
+                "source" => &self.source,
+                "curve_key" => format!("{:?}/{:?}/{}/{:?}", key.route_type, key.route_section, key.time_slot.id, key.event_type),
+                "curve_data" => rmp_serde::to_vec(&(key, curve_data))?,
+            });
+        }
+
+        let mut con = self.pool.get_conn()?;
+        let mut tx = con.start_transaction(TxOpts::default())?;
+        tx.exec_drop(r"DELETE FROM `curve_defaults` WHERE `source` = :source", params! { "source" => &self.source })?;
+        tx.exec_batch(
+            r"INSERT INTO `curve_defaults` (`source`, `curve_key`, `curve_data`) VALUES (:source, :curve_key, :curve_data)",
+            params_vec,
+        )?;
+        tx.exec_drop(
+            r"REPLACE INTO `curve_route_sectioning` (`source`, `route_sectioning`) VALUES (:source, :route_sectioning)",
+            params! {
+                "source" => &self.source,
+                "route_sectioning" => rmp_serde::to_vec(&default_curves.route_sectioning)?,
+            },
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Loads all curves of one route variant, or None if there are none. If the route variant has been
+    /// merged into another one, the curves of that one are returned.
+    pub fn load_route_variant(&self, route_id: &str, route_variant: u64) -> FnResult<Option<RouteVariantData>> {
+        let mut con = self.pool.get_conn()?;
+        // curves that were stored before route variants could be merged don't have an entry
+        let merged_into: Option<u64> = con.exec_first(
+            r"SELECT `merged_into` FROM `curve_merged_variants` WHERE `source` = :source AND `route_id` = :route_id AND `route_variant` = :route_variant",
+            params! {
+                "source" => &self.source,
+                route_id,
+                route_variant,
+            },
+        )?;
+        let route_variant = merged_into.unwrap_or(route_variant);
+
+        let key_params = params! {
+            "source" => &self.source,
+            route_id,
+            route_variant,
+        };
+
+        let stop_ids: Option<Vec<u8>> = con.exec_first(
+            r"SELECT `stop_ids` FROM `curve_route_variants` WHERE `source` = :source AND `route_id` = :route_id AND `route_variant` = :route_variant",
+            key_params.clone(),
+        )?;
+        let mut variant_data = match stop_ids {
+            Some(stop_ids) => RouteVariantData {
+                stop_ids: rmp_serde::from_read_ref(&stop_ids)?,
+                ..RouteVariantData::new()
+            },
+            None => return Ok(None),
+        };
+
+        let general_rows: Vec<(u32, u8, Vec<u8>)> = con.exec(
+            r"SELECT `stop_index`, `event_type`, `curve_data` FROM `curve_general_delays`
+            WHERE `source` = :source AND `route_id` = :route_id AND `route_variant` = :route_variant",
+            key_params.clone(),
+        )?;
+        for (stop_index, event_type, data) in general_rows {
+            let curve_data: CurveData = rmp_serde::from_read_ref(&data)?;
+            variant_data.general_delay[EventType::from_int(event_type)].insert(stop_index, curve_data);
+        }
+
+        let curve_set_rows: Vec<(u32, u32, u8, u8, u8, Vec<u8>)> = con.exec(
+            r"SELECT `start_stop_index`, `end_stop_index`, `time_slot`, `weather_condition`, `event_type`, `curve_set_data` FROM `curve_sets`
+            WHERE `source` = :source AND `route_id` = :route_id AND `route_variant` = :route_variant",
+            key_params.clone(),
+        )?;
+        for (start_stop_index, end_stop_index, time_slot, weather_condition, event_type, data) in curve_set_rows {
+            let key = CurveSetKey {
+                start_stop_index,
+                end_stop_index,
+                time_slot: TimeSlot::from_id(time_slot).or_error(&format!("Unknown time slot id {}", time_slot))?.clone(),
+                weather: WeatherCondition::from_int(weather_condition),
+            };
+            let curve_set_data: CurveSetData = rmp_serde::from_read_ref(&data)?;
+            variant_data.curve_sets[EventType::from_int(event_type)].insert(key, curve_set_data);
+        }
+
+        // curves that were stored before dwell times were computed don't have any
+        let dwell_time_rows: Vec<(u32, u8, Vec<u8>)> = con.exec(
+            r"SELECT `stop_index`, `time_slot`, `curve_set_data` FROM `curve_dwell_times`
+            WHERE `source` = :source AND `route_id` = :route_id AND `route_variant` = :route_variant",
+            key_params,
+        )?;
+        for (stop_index, time_slot, data) in dwell_time_rows {
+            let key = DwellTimeKey {
+                stop_index,
+                time_slot: TimeSlot::from_id(time_slot).or_error(&format!("Unknown time slot id {}", time_slot))?.clone(),
+            };
+            variant_data.dwell_times.insert(key, rmp_serde::from_read_ref(&data)?);
+        }
+
+        Ok(Some(variant_data))
+    }
+
+    pub fn load_default_curves(&self) -> FnResult<DefaultCurves> {
+        let mut con = self.pool.get_conn()?;
+        let rows: Vec<Vec<u8>> = con.exec(
+            r"SELECT `curve_data` FROM `curve_defaults` WHERE `source` = :source",
+            params! { "source" => &self.source },
+        )?;
+        let mut all_default_curves = HashMap::new();
+        for data in rows {
+            let (key, curve_data): (DefaultCurveKey, CurveData) = rmp_serde::from_read_ref(&data)?;
+            all_default_curves.insert(key, curve_data);
+        }
+        // if the curves were stored before the sectioning was configurable, there is no entry,
+        // and they have been computed by stop count
+        let route_sectioning: Option<Vec<u8>> = con.exec_first(
+            r"SELECT `route_sectioning` FROM `curve_route_sectioning` WHERE `source` = :source",
+            params! { "source" => &self.source },
+        )?;
+        let route_sectioning = match route_sectioning {
+            Some(data) => rmp_serde::from_read_ref(&data)?,
+            None => RouteSectioning::StopCount,
+        };
+        Ok(DefaultCurves { all_default_curves, route_sectioning })
+    }
+}
+
@@ -3,8 +3,8 @@ use chrono::offset::TimeZone;
 use mysql::*;
 use mysql::prelude::*;
 use gtfs_structures::{Trip, Gtfs};
-use super::{EventType, EventPair, GetByEventType};
-use crate::date_and_time_local;
+use super::{EventType, EventPair, GetByEventType, Id, WeatherCondition};
+use crate::time_util::date_and_time;
 
 #[derive(Clone)]
 pub struct DbItem {
@@ -13,10 +13,12 @@ pub struct DbItem {
     //pub delay_departure: Option<i32>,
     pub trip_start_date: Option<Date<Local>>,
     pub trip_start_time: Option<Duration>,
-    pub trip_id: String,
+    pub trip_id: Id,
     pub stop_sequence: u16,
-    pub stop_id: String,
-    pub route_variant: u64
+    pub stop_id: Id,
+    pub route_variant: u64,
+    /// only known if the query joins the weather_observations table
+    pub weather: WeatherCondition,
 }
 
 impl FromRow for DbItem {
@@ -32,10 +34,14 @@ impl FromRow for DbItem {
                 None
             },
             trip_start_time: row.get_opt(3).unwrap().ok(),
-            trip_id: row.get::<String, _>(4).unwrap(),
-            stop_id: row.get::<String, _>(5).unwrap(),
+            trip_id: row.get::<Id, _>(4).unwrap(),
+            stop_id: row.get::<Id, _>(5).unwrap(),
             stop_sequence: row.get::<u16, _>(6).unwrap(),
             route_variant: row.get::<u64, _>(7).unwrap(),
+            weather: match row.get_opt::<Option<u8>, _>(8) {
+                Some(Ok(Some(weather))) => WeatherCondition::from_int(weather),
+                _ => WeatherCondition::Unknown,
+            },
         })
     }
 }
@@ -55,7 +61,7 @@ impl DbItem {
         
         // get date from DbItem
         let date: Date<Local> = self.trip_start_date.unwrap(); //should never panic because date is always set
-        return Some(date_and_time_local(&date, seconds.unwrap() as i32));
+        return Some(date_and_time(&date, seconds.unwrap() as i32));
     }
 
     // generates a NaiveDateTime from a DbItem, given a flag for arrival or departure
@@ -0,0 +1,134 @@
+use chrono::{Date, DateTime, Local, Duration};
+use dystonse_curves::{IrregularDynamicCurve, Curve};
+use gtfs_structures::{Gtfs, RouteType};
+use mysql::*;
+use mysql::prelude::*;
+use std::sync::Arc;
+
+use crate::{FnResult, OrError};
+use crate::time_util::date_and_time;
+use super::curve_format::decode_compact;
+use super::{EventType, Id, OriginType, PrecisionType, TimeSlot, DelayStatistics, HolidayCalendar};
+
+/// A prediction as it is stored in the `predictions` table.
+#[derive(Debug, Clone)]
+pub struct DbPrediction {
+    pub route_id: Id,
+    pub trip_id: Id,
+    pub trip_start_date: Date<Local>,
+    pub trip_start_time: Duration, // time from midnight, may be outside 0:00 .. 24:00
+    pub prediction_min: DateTime<Local>, 
+    pub prediction_max: DateTime<Local>,
+    pub precision_type: PrecisionType,
+    pub origin_type: OriginType,
+    pub sample_size: i32,
+    pub prediction_curve: IrregularDynamicCurve<f32, f32>,
+    pub stop_id: Id,
+    pub stop_sequence: usize,
+    pub event_type: EventType,
+
+    pub meta_data: Option<DbPredictionMetaData>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DbPredictionMetaData {
+    pub route_name : String,
+    pub headsign : String,
+    pub stop_index : usize,
+    pub scheduled_time_seconds : u32,
+    pub scheduled_time_absolute : DateTime<Local>,
+    pub route_type: RouteType,
+}
+
+impl DbPrediction {
+    pub fn compute_meta_data(&mut self, schedule: Arc<Gtfs>) -> FnResult<()> {
+        if self.meta_data.is_some() {
+            return Ok(());
+        }
+
+        let trip = schedule.get_trip(&self.trip_id)?;
+        let route = schedule.get_route(&self.route_id)?;
+        let route_name = route.short_name.clone();
+        let route_type = route.route_type;
+        let headsign = trip.trip_headsign.as_ref().or_error("trip_headsign is None")?.clone();
+        let stop_index = trip.get_stop_index_by_stop_sequence(self.stop_sequence as u16).or_error("stop_index is None")?;
+        let scheduled_time_seconds = match self.event_type {
+            EventType::Arrival   => trip.stop_times[stop_index].arrival_time  .or_error("arrival_time is None"  )?,
+            EventType::Departure => trip.stop_times[stop_index].departure_time.or_error("departure_time is None")?
+        };
+        let scheduled_time_absolute = date_and_time(&self.trip_start_date, scheduled_time_seconds as i32);
+
+        self.meta_data = Some(DbPredictionMetaData{ 
+            route_name,
+            headsign,
+            stop_index,
+            scheduled_time_seconds,
+            scheduled_time_absolute,
+            route_type,
+        });
+        
+        Ok(())
+    }
+
+    /// Probability (between 0 and 1) that the trip is operated at all. Trips with realtime data
+    /// are known to be operated, for all others the historic operation probability is used.
+    pub fn get_operation_probability(&self, stats: &DelayStatistics, holidays: &HolidayCalendar) -> f32 {
+        if self.origin_type == OriginType::Realtime {
+            return 1.0;
+        }
+        let trip_start = date_and_time(&self.trip_start_date, self.trip_start_time.num_seconds() as i32);
+        stats.get_operation_probability(&self.route_id, TimeSlot::from_datetime(trip_start, holidays))
+    }
+
+    pub fn get_absolute_time_for_probability(&self, prob: f32) -> FnResult<DateTime<Local>> {
+        let x = self.prediction_curve.x_at_y(prob);
+        Ok(date_and_time(&self.trip_start_date, self.meta_data.as_ref().or_error("Prediction has no meta_data")?.scheduled_time_seconds as i32 + x as i32))
+    }
+
+    pub fn get_relative_time_for_probability(&self, prob: f32) -> i32 {
+        self.prediction_curve.x_at_y(prob) as i32
+    }
+
+    #[allow(dead_code)]
+    pub fn get_relative_time(&self, time: DateTime<Local>) -> FnResult<f32> {
+        Ok(-self.meta_data.as_ref().or_error("Prediction has no meta_data")?.scheduled_time_absolute.signed_duration_since(time).num_seconds() as f32)
+    }
+
+    #[allow(dead_code)]
+    pub fn get_probability_for_relative_time(&self, relative_seconds: f32) -> f32 {
+        self.prediction_curve.y_at_x(relative_seconds)
+    }
+}
+
+impl FromRow for DbPrediction {
+    fn from_row_opt(row: Row) -> std::result::Result<Self, FromRowError> {
+        use chrono::{NaiveDate, NaiveDateTime};
+        use chrono::offset::TimeZone;
+
+        let naive_trip_start_date:NaiveDate    = row.get_opt(2).unwrap().unwrap();
+        let naive_prediction_min:NaiveDateTime = row.get_opt(4).unwrap().unwrap();
+        let naive_prediction_max:NaiveDateTime = row.get_opt(5).unwrap().unwrap();
+        let curve_bytes: Vec<u8> = row.get_opt(9).unwrap().unwrap();
+        let prediction_curve = match decode_compact(&curve_bytes) {
+            Ok(curve) => curve,
+            Err(_) => return Err(FromRowError(row)),
+        };
+         // TODO the .single().unwrap() below will fail when daylight saving changes.
+        Ok(DbPrediction{
+            route_id:           row.get_opt(0).unwrap().unwrap(),
+            trip_id:            row.get_opt(1).unwrap().unwrap(),
+            trip_start_date:    Local.from_local_date(&naive_trip_start_date).single().unwrap(),
+            trip_start_time:    row.get_opt(3).unwrap().unwrap(),
+            prediction_min:     Local.from_local_datetime(&naive_prediction_min).single().unwrap(),
+            prediction_max:     Local.from_local_datetime(&naive_prediction_max).single().unwrap(),
+            precision_type:     PrecisionType::from_int(row.get_opt(6).unwrap().unwrap()),
+            origin_type:        OriginType::from_int(row.get_opt(7).unwrap().unwrap()),
+            sample_size:        row.get_opt(8).unwrap().unwrap(),
+            prediction_curve,
+            stop_id:            row.get_opt(10).unwrap().unwrap(),
+            stop_sequence:      row.get_opt(11).unwrap().unwrap(),
+            event_type:         EventType::from_int(row.get_opt(12).unwrap().unwrap()),
+            meta_data:          None,
+        })
+    }
+}
@@ -14,6 +14,7 @@ use crate::FnResult;
 use crate::types::{
     EventType,
     RouteSection,
+    RouteSectioning,
     TimeSlot,
     CurveData
 };
@@ -21,7 +22,10 @@ use crate::types::{
 /// a struct to hold a hash map of all the default curves
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DefaultCurves {
-    pub all_default_curves: HashMap<DefaultCurveKey, CurveData>
+    pub all_default_curves: HashMap<DefaultCurveKey, CurveData>,
+    /// the sectioning with which the route sections of the curves have been computed
+    #[serde(default)]
+    pub route_sectioning: RouteSectioning,
 }
 
 // Key type for the default curves hashmap, so we don't have to use a tuple:
@@ -38,7 +42,8 @@ impl DefaultCurves {
  
     pub fn new() -> Self {
         return Self {
-            all_default_curves: HashMap::new()
+            all_default_curves: HashMap::new(),
+            route_sectioning: RouteSectioning::StopCount,
         };
     }
 }
@@ -1,17 +1,40 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
 use serde::{Serialize, Deserialize};
+use gtfs_structures::{Gtfs, Trip};
 
+use dystonse_curves::Curve;
+use dystonse_curves::irregular_dynamic::IrregularDynamicCurve;
 use dystonse_curves::tree::{SerdeFormat, TreeData, NodeData};
 
-use crate::FnResult;
-use crate::types::{RouteData, DefaultCurves};
+use crate::{FnResult, OrError};
+use crate::types::{RouteData, RouteVariantData, DefaultCurves, DefaultCurveKey, EventType, TimeSlot, OperationKey, OperationCounts, CurveParameters, OutlierPolicy, FeedQuirks, CalibrationKey, CalibrationCounts, PrecisionType};
 
 use simple_error::bail;
 
+// operation probabilities based on less scheduled trips than this are not used
+const MIN_TRIPS_FOR_OPERATION_PROBABILITY: u32 = 20;
+
 #[derive(Serialize, Deserialize)]
 pub struct DelayStatistics {
     pub specific: HashMap<String, RouteData>,
-    pub general: DefaultCurves
+    pub general: DefaultCurves,
+    /// how many of the scheduled trips of each route and time slot were actually operated
+    #[serde(default)]
+    pub operation: HashMap<OperationKey, OperationCounts>,
+    /// parameters for the curve sets of each route, chosen by `analyse tune-curves`
+    #[serde(default)]
+    pub curve_parameters: HashMap<String, CurveParameters>,
+    /// how outliers were removed when the curve sets were computed
+    #[serde(default)]
+    pub outlier_policy: OutlierPolicy,
+    /// how the delays of the realtime feed were read when the curve sets were computed
+    #[serde(default)]
+    pub feed_quirks: FeedQuirks,
+    /// how well the predictions of each route and precision type matched the delays that occured, collected by `analyse replay`
+    /// per replayed day (as YYYY-MM-DD), so that replaying a day again replaces its counts instead of adding them twice
+    #[serde(default)]
+    pub calibration: BTreeMap<String, HashMap<CalibrationKey, CalibrationCounts>>,
 }
 
 impl DelayStatistics {
@@ -21,8 +44,102 @@ impl DelayStatistics {
     pub fn new() -> Self {
         return Self {
             specific: HashMap::new(),
-            general: DefaultCurves::new()
+            general: DefaultCurves::new(),
+            operation: HashMap::new(),
+            curve_parameters: HashMap::new(),
+            outlier_policy: OutlierPolicy::DEFAULT,
+            feed_quirks: FeedQuirks::DEFAULT,
+            calibration: BTreeMap::new(),
+        };
+    }
+
+    /// Returns the parameters for the curve sets of the route, or the default parameters
+    /// if none have been tuned for it.
+    pub fn get_curve_parameters(&self, route_id: &str) -> CurveParameters {
+        self.curve_parameters.get(route_id).cloned().unwrap_or_default()
+    }
+
+    /// Returns how well the predictions of the route with the precision type have been calibrated so far, summed up
+    /// over all replayed days, if they have been evaluated.
+    pub fn get_calibration(&self, route_id: &str, precision_type: &PrecisionType) -> Option<CalibrationCounts> {
+        let key = CalibrationKey::new(route_id, precision_type);
+        let mut days = self.calibration.values().filter_map(|day| day.get(&key)).peekable();
+        days.peek()?;
+        let mut counts = CalibrationCounts::default();
+        for day in days {
+            counts.add(day);
+        }
+        Some(counts)
+    }
+
+    /// Returns the specific curves of the route variant, which may have been computed together with
+    /// other route variants (see `RouteData::merged_variants`).
+    pub fn get_route_variant_data(&self, route_id: &str, route_variant: u64) -> Option<&RouteVariantData> {
+        self.specific.get(route_id)?.get_variant(route_variant)
+    }
+
+    /// Returns the probability that a scheduled trip of the route, which starts within the time slot,
+    /// is actually operated. Falls back to the default time slot if there are too few trips in the
+    /// given one, and to 1.0 if there is no data for the route at all.
+    pub fn get_operation_probability(&self, route_id: &str, time_slot: &TimeSlot) -> f32 {
+        for ts in &[time_slot, &TimeSlot::DEFAULT] {
+            let key = OperationKey {
+                route_id: String::from(route_id),
+                time_slot: (*ts).clone(),
+            };
+            if let Some(counts) = self.operation.get(&key) {
+                if counts.scheduled_trips >= MIN_TRIPS_FOR_OPERATION_PROBABILITY {
+                    return counts.probability();
+                }
+            }
+        }
+        1.0
+    }
+
+    /// Returns the median delay (in seconds) of an event, based only on the historic data and
+    /// independent of the time of day. Uses the semi-specific curve of the route variant if possible,
+    /// and the default curve for the route type otherwise.
+    pub fn get_median_delay(&self, schedule: &Gtfs, trip: &Trip, stop_index: usize, event_type: EventType) -> FnResult<f32> {
+        self.get_delay_quantile(schedule, trip, stop_index, event_type, 0.5)
+    }
+
+    /// Like `get_median_delay`, but for any quantile, e.g. 0.1 for the delay that is exceeded by 90% of the events.
+    pub fn get_delay_quantile(&self, schedule: &Gtfs, trip: &Trip, stop_index: usize, event_type: EventType, quantile: f32) -> FnResult<f32> {
+        let semi_specific_curve_data = trip.route_variant.as_ref()
+            .and_then(|route_variant| self.get_route_variant_data(&trip.route_id, u64::from_str(route_variant).ok()?))
+            .and_then(|route_variant_data| route_variant_data.general_delay[event_type].get(&route_variant_data.get_stop_index(trip, stop_index)?));
+
+        if let Some(curve_data) = semi_specific_curve_data {
+            return Ok(curve_data.curve.x_at_y(quantile));
+        }
+
+        let key = DefaultCurveKey {
+            route_type: schedule.get_route(&trip.route_id)?.route_type,
+            route_section: self.general.route_sectioning.get_route_section(trip, stop_index)?,
+            time_slot: TimeSlot::DEFAULT,
+            event_type
         };
+        let curve_data = self.general.all_default_curves.get(&key).or_error("No default curve")?;
+        Ok(curve_data.curve.x_at_y(quantile))
+    }
+
+    /// Like `get_delay_quantile`, but for trips that start within the time slot. Uses the specific curve set
+    /// from the first stop of the trip, mixed over the usual departure delays at the first stop, if possible.
+    pub fn get_delay_quantile_in_time_slot(&self, schedule: &Gtfs, trip: &Trip, stop_index: usize, event_type: EventType, time_slot: &TimeSlot, quantile: f32) -> FnResult<f32> {
+        match self.get_curve_from_first_stop(trip, stop_index, event_type, time_slot) {
+            Some(curve) => Ok(curve.x_at_y(quantile)),
+            None => self.get_delay_quantile(schedule, trip, stop_index, event_type, quantile),
+        }
+    }
+
+    // The curve set is looked up for the time slot and then for the default time slot.
+    fn get_curve_from_first_stop(&self, trip: &Trip, stop_index: usize, event_type: EventType, time_slot: &TimeSlot) -> Option<IrregularDynamicCurve<f32, f32>> {
+        let route_variant_data = self.get_route_variant_data(&trip.route_id, u64::from_str(trip.route_variant.as_ref()?).ok()?)?;
+        let start_stop_index = route_variant_data.get_stop_index(trip, 0)?;
+        let end_stop_index = route_variant_data.get_stop_index(trip, stop_index)?;
+        [time_slot, &TimeSlot::DEFAULT].iter()
+            .find_map(|ts| route_variant_data.get_curve_from_start_stop(start_stop_index, end_stop_index, event_type, ts))
+            .map(|(curve, _)| curve)
     }
 }
 
@@ -47,4 +164,87 @@ impl TreeData for DelayStatistics {
     fn load_tree(_dir_name: &str, _own_name: &str, _format: &SerdeFormat, _leaves: &Vec<&str>) -> FnResult<Self>{
         bail!("Not yet implemented!");
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dystonse_curves::Tup;
+    use dystonse_curves::curve_set::CurveSet;
+    use gtfs_structures::RouteType;
+    use crate::test_util;
+    use crate::types::{CurveData, CurveSetData, CurveSetKey, EventPair, RouteSection, WeatherCondition};
+
+    fn curve(min: f32, max: f32) -> IrregularDynamicCurve<f32, f32> {
+        IrregularDynamicCurve::new(vec![Tup { x: min, y: 0.0 }, Tup { x: max, y: 1.0 }])
+    }
+
+    fn curve_data(min: f32, max: f32) -> CurveData {
+        CurveData { curve: curve(min, max), precision_type: PrecisionType::SemiSpecific, sample_size: 100, effective_sample_size: None }
+    }
+
+    // a bus route with three stops and curves for the arrival at the last stop
+    fn example() -> (Gtfs, Trip, DelayStatistics) {
+        let trip = Trip {
+            route_variant: Some(String::from("7")),
+            ..test_util::trip("t1", "r1", 8 * 3600, 300, &["a", "b", "c"])
+        };
+        let schedule = test_util::schedule(vec![trip.clone()]);
+        let stop_ids = vec![String::from("a"), String::from("b"), String::from("c")];
+
+        let mut statistics = DelayStatistics::new();
+        statistics.general.all_default_curves.insert(DefaultCurveKey {
+            route_type: RouteType::Bus,
+            route_section: RouteSection::Middle,
+            time_slot: TimeSlot::DEFAULT,
+            event_type: EventType::Arrival,
+        }, curve_data(-60.0, 120.0));
+
+        let mut curve_set = CurveSet::new();
+        curve_set.add_curve(0.0, curve(100.0, 200.0));
+        curve_set.add_curve(60.0, curve(100.0, 200.0));
+        let mut curve_sets = HashMap::new();
+        curve_sets.insert(CurveSetKey { start_stop_index: 0, end_stop_index: 2, time_slot: TimeSlot::WORKDAY_MORNING_RUSH, weather: WeatherCondition::Unknown },
+            CurveSetData { curve_set, precision_type: PrecisionType::Specific, sample_size: 100, effective_sample_size: None });
+        let mut general_delay = EventPair { arrival: HashMap::new(), departure: HashMap::new() };
+        general_delay[EventType::Departure].insert(0, curve_data(0.0, 60.0));
+        general_delay[EventType::Arrival].insert(2, curve_data(0.0, 100.0));
+        let mut route_data = RouteData::new("r1");
+        route_data.variants.insert(7, RouteVariantData {
+            stop_ids,
+            curve_sets: EventPair { arrival: curve_sets, departure: HashMap::new() },
+            general_delay,
+            dwell_times: HashMap::new(),
+        });
+        statistics.specific.insert(String::from("r1"), route_data);
+        (schedule, trip, statistics)
+    }
+
+    #[test]
+    fn test_get_delay_quantile() {
+        let (schedule, trip, statistics) = example();
+        // semi-specific curve of the route variant
+        assert_eq!(statistics.get_delay_quantile(&schedule, &trip, 2, EventType::Arrival, 0.1).unwrap(), 10.0);
+        assert_eq!(statistics.get_median_delay(&schedule, &trip, 2, EventType::Arrival).unwrap(), 50.0);
+        // default curve for the middle of the route
+        assert_eq!(statistics.get_delay_quantile(&schedule, &trip, 1, EventType::Arrival, 0.5).unwrap(), 30.0);
+        // no default curve for the beginning of the route
+        assert!(statistics.get_delay_quantile(&schedule, &trip, 0, EventType::Arrival, 0.5).is_err());
+        // unknown route variant
+        let other_trip = Trip { route_variant: None, ..trip.clone() };
+        assert!(statistics.get_delay_quantile(&schedule, &other_trip, 2, EventType::Arrival, 0.5).is_err());
+    }
+
+    #[test]
+    fn test_get_delay_quantile_in_time_slot() {
+        let (schedule, trip, statistics) = example();
+        let quantile = |stop_index: usize, time_slot: &TimeSlot, q: f32|
+            statistics.get_delay_quantile_in_time_slot(&schedule, &trip, stop_index, EventType::Arrival, time_slot, q).unwrap();
+        // the curve set from the first stop, which is the same for all delays at the first stop
+        assert!((quantile(2, &TimeSlot::WORKDAY_MORNING_RUSH, 0.5) - 150.0).abs() < 1.0);
+        assert!((quantile(2, &TimeSlot::WORKDAY_MORNING_RUSH, 0.1) - 110.0).abs() < 1.0);
+        // no curve set for this time slot or the default one, so the semi-specific curve is used
+        assert_eq!(quantile(2, &TimeSlot::SUNDAY_DAY, 0.5), 50.0);
+        // no curve set to this stop, so the default curve is used
+        assert_eq!(quantile(1, &TimeSlot::WORKDAY_MORNING_RUSH, 0.5), 30.0);
+    }
+}
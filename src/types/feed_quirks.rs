@@ -0,0 +1,129 @@
+use clap::ArgMatches;
+use serde::{Serialize, Deserialize};
+use simple_error::bail;
+
+use crate::{FnResult, OrError};
+use super::DbItem;
+
+/// How the realtime feed of a source has to be read. Feeds differ in what they mean with their delays,
+/// so the importer and the curve creators consult these settings instead of hard-coding workarounds
+/// for particular feeds. They are configured per source via the environment, like the prediction model.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct FeedQuirks {
+    /// the feed gives a delay of 0 instead of no delay when it has no data for a stop
+    pub zero_means_missing: bool,
+    /// the feed rounds its delays towards zero to multiples of this many seconds
+    pub delay_granularity: i32,
+    /// delays of more than this many seconds (early or late) are errors of the feed, not actual delays
+    pub max_trustworthy_delay: Option<i32>,
+}
+
+impl FeedQuirks {
+    /// The settings for a feed without quirks, which rounds its delays like most of the feeds that we know.
+    pub const DEFAULT: FeedQuirks = FeedQuirks {
+        zero_means_missing: false,
+        delay_granularity: 12,
+        max_trustworthy_delay: None,
+    };
+
+    /// Reads the settings from the `zero-delay-means-missing`, `feed-delay-granularity` and `max-trustworthy-delay` arguments.
+    pub fn from_args(args: &ArgMatches) -> FnResult<Self> {
+        let zero_means_missing = args.value_of("zero-delay-means-missing").unwrap_or("false") == "true";
+        let delay_granularity: i32 = args.value_of("feed-delay-granularity").or_error("Argument feed-delay-granularity is missing.")?.parse()?;
+        if delay_granularity < 1 {
+            bail!("Feed delay granularity must be at least 1 second.");
+        }
+        let max_trustworthy_delay: Option<i32> = match args.value_of("max-trustworthy-delay") {
+            Some(max) => Some(max.parse()?),
+            None => None,
+        };
+        if max_trustworthy_delay.map_or(false, |max| max <= 0) {
+            bail!("Maximum trustworthy delay must be positive.");
+        }
+        Ok(FeedQuirks { zero_means_missing, delay_granularity, max_trustworthy_delay })
+    }
+
+    /// Whether a delay from the feed is an actual delay, or has to be treated like a missing delay.
+    pub fn is_trustworthy(&self, delay: i64) -> bool {
+        if self.zero_means_missing && delay == 0 {
+            return false;
+        }
+        match self.max_trustworthy_delay {
+            Some(max) => delay.abs() <= max as i64,
+            None => true,
+        }
+    }
+
+    /// Removes the delays of a record that can't be trusted. Records that have been imported before the
+    /// settings were configured may still contain such delays.
+    pub fn clean_item(&self, item: &mut DbItem) {
+        item.delay.arrival = item.delay.arrival.filter(|delay| self.is_trustworthy(*delay as i64));
+        item.delay.departure = item.delay.departure.filter(|delay| self.is_trustworthy(*delay as i64));
+    }
+}
+
+impl Default for FeedQuirks {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{EventPair, Id, WeatherCondition};
+
+    #[test]
+    fn test_is_trustworthy() {
+        assert!(FeedQuirks::DEFAULT.is_trustworthy(0));
+        assert!(FeedQuirks::DEFAULT.is_trustworthy(-100_000));
+
+        let quirks = FeedQuirks { zero_means_missing: true, delay_granularity: 60, max_trustworthy_delay: Some(3600) };
+        assert!(!quirks.is_trustworthy(0));
+        assert!(quirks.is_trustworthy(60));
+        assert!(quirks.is_trustworthy(-3600));
+        assert!(!quirks.is_trustworthy(3601));
+        assert!(!quirks.is_trustworthy(-7200));
+    }
+
+    fn from_args(args: &[&str]) -> FnResult<FeedQuirks> {
+        let mut all_args = vec!["dystonse-gtfs-data", "--password", "secret", "--source", "test", "--dir", "."];
+        all_args.extend_from_slice(args);
+        FeedQuirks::from_args(&crate::get_app().try_get_matches_from(all_args)?)
+    }
+
+    #[test]
+    fn test_from_args() {
+        assert_eq!(from_args(&[]).unwrap(), FeedQuirks::DEFAULT);
+        assert_eq!(from_args(&["--zero-delay-means-missing", "true", "--feed-delay-granularity", "60", "--max-trustworthy-delay", "3600"]).unwrap(),
+            FeedQuirks { zero_means_missing: true, delay_granularity: 60, max_trustworthy_delay: Some(3600) });
+        assert!(from_args(&["--zero-delay-means-missing", "yes"]).is_err());
+        assert!(from_args(&["--feed-delay-granularity", "0"]).is_err());
+        assert!(from_args(&["--max-trustworthy-delay", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_clean_item() {
+        let quirks = FeedQuirks { zero_means_missing: true, delay_granularity: 12, max_trustworthy_delay: Some(3600) };
+        let mut item = DbItem {
+            delay: EventPair { arrival: Some(0), departure: Some(120) },
+            trip_start_date: None,
+            trip_start_time: None,
+            trip_id: Id::new("t1"),
+            stop_sequence: 1,
+            stop_id: Id::new("s1"),
+            route_variant: 1,
+            weather: WeatherCondition::Unknown,
+        };
+        quirks.clean_item(&mut item);
+        assert_eq!((item.delay.arrival, item.delay.departure), (None, Some(120)));
+
+        item.delay = EventPair { arrival: Some(-4000), departure: Some(3600) };
+        quirks.clean_item(&mut item);
+        assert_eq!((item.delay.arrival, item.delay.departure), (None, Some(3600)));
+
+        item.delay = EventPair { arrival: Some(0), departure: Some(100_000) };
+        FeedQuirks::DEFAULT.clean_item(&mut item);
+        assert_eq!((item.delay.arrival, item.delay.departure), (Some(0), Some(100_000)));
+    }
+}
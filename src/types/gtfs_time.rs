@@ -1,11 +1,20 @@
 use chrono::*;
 use std::cmp::Ordering;
 use core::cmp::Ord;
+use std::hash::{Hash, Hasher};
 use gtfs_rt::TripDescriptor;
 use regex::Regex;
 use crate::{FnResult, OrError};
-
-#[derive(Eq, Clone, Hash, Debug)]
+use crate::time_util::date_and_time;
+
+/// A point in time, given as a time relative to a service day like in GTFS.
+///
+/// The same point in time has several representations: a trip that starts at 00:10 may be scheduled at
+/// 24:10:00 of the previous service day. Equality, ordering and hashing only consider the point in time.
+/// The `trip_start_date` columns of the database always hold the service day of the schedule, so queries
+/// need the representation that matches the scheduled start time of the trip (see `with_scheduled_time`),
+/// and they should use `service_day_param` for their parameters.
+#[derive(Eq, Clone, Debug)]
 pub struct GtfsDateTime {
     service_day: Date<Local>,
     time: i32
@@ -19,6 +28,23 @@ impl GtfsDateTime {
         }
     }
 
+    /// Returns the representation of a point in time that uses its calendar date as service day.
+    pub fn from_date_time(date_time: &DateTime<Local>) -> Self {
+        let service_day = date_time.date();
+        let time = date_time.signed_duration_since(date_and_time(&service_day, 0)).num_seconds() as i32;
+        Self::new(service_day, time)
+    }
+
+    /// Returns the start of the latest trip that started at `scheduled_time` (relative to its
+    /// service day) no later than `date_time`. For times after 24:00:00, this is a trip of an earlier service day.
+    pub fn latest_start_until(date_time: &DateTime<Local>, scheduled_time: i32) -> Self {
+        let mut service_day = date_time.date().succ();
+        while date_and_time(&service_day, scheduled_time) > *date_time {
+            service_day = service_day.pred();
+        }
+        Self::new(service_day, scheduled_time)
+    }
+
     pub fn from_trip_descriptor(trip_descriptor: &TripDescriptor) -> FnResult<Self> {
         lazy_static! {
             static ref FIND_TIME: Regex = Regex::new(r"(\d+):(\d+):(\d+)").unwrap(); // can't fail because our hard-coded regex is known to be ok
@@ -51,8 +77,7 @@ impl GtfsDateTime {
     }
 
     pub fn date_time(&self) -> DateTime<Local> {
-        // see https://developers.google.com/transit/gtfs/reference#field_types for this quirky thing:
-        return self.service_day.and_hms(12, 0, 0) + (Duration::seconds(self.time as i64) - Duration::hours(12));
+        return date_and_time(&self.service_day, self.time);
     }
 
     pub fn duration(&self) -> Duration {
@@ -72,6 +97,36 @@ impl GtfsDateTime {
     pub fn date(&self) -> Date<Local> {
         return self.date_time().date();
     }
+
+    /// The service day as parameter for the `trip_start_date` columns of the database.
+    pub fn service_day_param(&self) -> NaiveDate {
+        return self.service_day.naive_local();
+    }
+
+    /// Returns the same point in time, expressed relative to another service day.
+    pub fn relative_to(&self, service_day: Date<Local>) -> Self {
+        let time = self.date_time().signed_duration_since(date_and_time(&service_day, 0)).num_seconds() as i32;
+        Self::new(service_day, time)
+    }
+
+    /// Returns the representation of this point in time whose time is `scheduled_time`, if there is one on one
+    /// of the neighbouring service days. Realtime feeds sometimes give trips that start after midnight with
+    /// the calendar date and a time before 24:00:00, while the schedule has them on the previous service day.
+    pub fn with_scheduled_time(&self, scheduled_time: i32) -> Self {
+        for days in &[0, -1, -2, 1] {
+            let candidate = self.relative_to(self.service_day + Duration::days(*days));
+            if candidate.time == scheduled_time {
+                return candidate;
+            }
+        }
+        self.clone()
+    }
+}
+
+impl Hash for GtfsDateTime {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.date_time().hash(state);
+    }
 }
 
 impl Ord for GtfsDateTime {
@@ -90,4 +145,66 @@ impl PartialEq for GtfsDateTime {
     fn eq(&self, other: &Self) -> bool {
         self.date_time() == other.date_time()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // a day without DST changes nearby
+    fn day() -> Date<Local> {
+        Local.ymd(2020, 6, 15)
+    }
+
+    #[test]
+    fn test_representations_around_midnight() {
+        let late = GtfsDateTime::new(day(), 24 * 3600 + 600);
+        let early = GtfsDateTime::new(day().succ(), 600);
+        assert_eq!(late, early);
+        assert_eq!(late.date(), day().succ());
+        assert_ne!(late.service_day_param(), early.service_day_param());
+        assert_eq!(early.relative_to(day()).seconds(), 24 * 3600 + 600);
+        assert_eq!(late.relative_to(day().succ()).seconds(), 600);
+
+        // equal representations must not end up twice in hash based collections
+        let set: HashSet<GtfsDateTime> = vec![late.clone(), early.clone()].into_iter().collect();
+        assert_eq!(set.len(), 1);
+
+        let from_date_time = GtfsDateTime::from_date_time(&late.date_time());
+        assert_eq!(from_date_time.service_day(), day().succ());
+        assert_eq!(from_date_time.seconds(), 600);
+    }
+
+    #[test]
+    fn test_with_scheduled_time() {
+        // realtime data with the calendar date, schedule with the previous service day
+        let realtime = GtfsDateTime::new(day().succ(), 600);
+        let normalized = realtime.with_scheduled_time(24 * 3600 + 600);
+        assert_eq!(normalized.service_day(), day());
+        assert_eq!(normalized.seconds(), 24 * 3600 + 600);
+
+        // already matching, or not matching at all
+        let regular = GtfsDateTime::new(day(), 23 * 3600 + 3000);
+        assert_eq!(regular.with_scheduled_time(23 * 3600 + 3000).service_day(), day());
+        assert_eq!(regular.with_scheduled_time(8 * 3600).service_day(), day());
+        assert_eq!(regular.with_scheduled_time(8 * 3600).seconds(), 23 * 3600 + 3000);
+    }
+
+    #[test]
+    fn test_latest_start_until() {
+        let just_after_midnight = day().succ().and_hms(0, 5, 0);
+        // a trip scheduled at 23:50 that started just before midnight belongs to the previous day
+        let start = GtfsDateTime::latest_start_until(&just_after_midnight, 23 * 3600 + 3000);
+        assert_eq!(start.service_day(), day());
+        // a trip scheduled at 24:02 has started on the previous service day as well
+        let start = GtfsDateTime::latest_start_until(&just_after_midnight, 24 * 3600 + 120);
+        assert_eq!(start.service_day(), day());
+        // but a trip at 24:10 hasn't started yet, so the latest one is from the day before
+        let start = GtfsDateTime::latest_start_until(&just_after_midnight, 24 * 3600 + 600);
+        assert_eq!(start.service_day(), day().pred());
+        // a trip scheduled at 00:03 has started on the current day
+        let start = GtfsDateTime::latest_start_until(&just_after_midnight, 180);
+        assert_eq!(start.service_day(), day().succ());
+    }
+}
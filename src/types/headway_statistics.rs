@@ -0,0 +1,73 @@
+use serde::{Serialize, Deserialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use crate::FnResult;
+use super::TimeSlot;
+
+/// The headways (time between two consecutive vehicles at the same stop) of route variants that run so
+/// frequently that passengers don't look at the timetable, computed by `analyse compute-headways`.
+/// For these route variants, the monitor can show how regularly the vehicles come instead of their delays.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct HeadwayStatistics {
+    /// route variants with a longer median scheduled headway (in seconds) are not high-frequency
+    pub max_headway: u32,
+    pub variants: Vec<HeadwayEntry>,
+}
+
+/// Headways of one route variant in one time slot, in seconds, over all of its stops.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HeadwayEntry {
+    pub route_id: String,
+    pub route_variant: u64,
+    pub time_slot: u8,
+    pub scheduled_headway: u32,
+    /// 10th percentile, median and 90th percentile of the observed headways
+    pub observed_p10: u32,
+    pub observed_median: u32,
+    pub observed_p90: u32,
+    pub sample_size: usize,
+}
+
+impl HeadwayStatistics {
+    pub const FILE_NAME: &'static str = "headways.json";
+
+    pub fn load_from_dir(dir: &str) -> FnResult<Self> {
+        let file = File::open(format!("{}/{}", dir, Self::FILE_NAME))?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    pub fn save_to_dir(&self, dir: &str) -> FnResult<()> {
+        let file = File::create(format!("{}/{}", dir, Self::FILE_NAME))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    /// Returns the headways of the route variant in the time slot, or None if it is not high-frequency then.
+    pub fn get(&self, route_id: &str, route_variant: u64, time_slot: &TimeSlot) -> Option<&HeadwayEntry> {
+        self.variants.iter().find(|entry| entry.route_id == route_id && entry.route_variant == route_variant && entry.time_slot == time_slot.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get() {
+        let entry = HeadwayEntry {
+            route_id: String::from("r1"),
+            route_variant: 7,
+            time_slot: TimeSlot::TIME_SLOTS[0].id,
+            scheduled_headway: 300,
+            observed_p10: 120,
+            observed_median: 290,
+            observed_p90: 540,
+            sample_size: 100,
+        };
+        let statistics = HeadwayStatistics { max_headway: 600, variants: vec![entry.clone()] };
+        assert_eq!(statistics.get("r1", 7, TimeSlot::TIME_SLOTS[0]), Some(&entry));
+        assert_eq!(statistics.get("r1", 8, TimeSlot::TIME_SLOTS[0]), None);
+        assert_eq!(statistics.get("r1", 7, TimeSlot::TIME_SLOTS[1]), None);
+    }
+}
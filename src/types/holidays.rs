@@ -0,0 +1,248 @@
+use chrono::{Datelike, Duration, NaiveDate, Weekday};
+use clap::ArgMatches;
+use serde::{Serialize, Deserialize};
+use simple_error::bail;
+use std::collections::HashSet;
+use std::fs;
+
+use crate::FnResult;
+
+/// The kind of day, as far as it matters for delays. Each TimeSlot is valid for one kind of day,
+/// or for all of them.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum DayType {
+    /// matches every day, used for night time slots and the default time slot
+    Any,
+    /// a day that is neither a public holiday nor a workday within school holidays
+    Regular,
+    /// a workday (Monday to Friday) within school holidays, which is not a public holiday
+    SchoolHoliday,
+    PublicHoliday,
+}
+
+impl Default for DayType {
+    // curves that were computed before day types existed are valid for any day
+    fn default() -> Self {
+        DayType::Any
+    }
+}
+
+/// German federal states (Bundesländer), identified by their two-letter abbreviation.
+const STATES: [&str; 16] = ["BW", "BY", "BE", "BB", "HB", "HH", "HE", "MV", "NI", "NW", "RP", "SL", "SN", "ST", "SH", "TH"];
+
+/// Knows which days are public holidays or school holidays. Public holidays can be computed
+/// for a German federal state, both kinds can be read from ICS files. An empty calendar
+/// treats every day as a regular day, so that time slots work as if there were no holidays.
+#[derive(Debug, Default, Clone)]
+pub struct HolidayCalendar {
+    state: Option<String>,
+    public_holidays: HashSet<NaiveDate>,
+    school_holidays: HashSet<NaiveDate>,
+}
+
+impl HolidayCalendar {
+    /// Creates the calendar that is configured by the global `holiday-state`,
+    /// `public-holidays-ics` and `school-holidays-ics` arguments.
+    pub fn from_args(args: &ArgMatches) -> FnResult<Self> {
+        let mut calendar = HolidayCalendar::default();
+        if let Some(state) = args.value_of("holiday-state") {
+            calendar.set_state(state)?;
+        }
+        if let Some(filenames) = args.values_of("public-holidays-ics") {
+            for filename in filenames {
+                let count = calendar.public_holidays.len();
+                calendar.public_holidays.extend(read_ics_dates(filename)?);
+                info!("Read {} public holidays from {}.", calendar.public_holidays.len() - count, filename);
+            }
+        }
+        if let Some(filenames) = args.values_of("school-holidays-ics") {
+            for filename in filenames {
+                let count = calendar.school_holidays.len();
+                calendar.school_holidays.extend(read_ics_dates(filename)?);
+                info!("Read {} days of school holidays from {}.", calendar.school_holidays.len() - count, filename);
+            }
+        }
+        Ok(calendar)
+    }
+
+    pub fn set_state(&mut self, state: &str) -> FnResult<()> {
+        let state = state.to_uppercase();
+        if !STATES.contains(&state.as_str()) {
+            bail!("Unknown federal state {}. Known states are: {}", state, STATES.join(", "));
+        }
+        self.state = Some(state);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.is_none() && self.public_holidays.is_empty() && self.school_holidays.is_empty()
+    }
+
+    pub fn is_public_holiday(&self, date: NaiveDate) -> bool {
+        if self.public_holidays.contains(&date) {
+            return true;
+        }
+        match &self.state {
+            Some(state) => is_statutory_holiday(state, date),
+            None => false,
+        }
+    }
+
+    pub fn is_school_holiday(&self, date: NaiveDate) -> bool {
+        self.school_holidays.contains(&date)
+    }
+
+    /// Finds out which kind of day the date is. Never returns `DayType::Any`.
+    pub fn get_day_type(&self, date: NaiveDate) -> DayType {
+        if self.is_public_holiday(date) {
+            DayType::PublicHoliday
+        } else if self.is_school_holiday(date) && date.weekday().num_days_from_monday() < 5 {
+            DayType::SchoolHoliday
+        } else {
+            DayType::Regular
+        }
+    }
+}
+
+/// Computes the date of easter sunday (anonymous gregorian algorithm).
+fn easter_sunday(year: i32) -> NaiveDate {
+    let a = year % 19;
+    let b = year / 100;
+    let c = year % 100;
+    let d = b / 4;
+    let e = b % 4;
+    let f = (b + 8) / 25;
+    let g = (b - f + 1) / 3;
+    let h = (19 * a + b - d - g + 15) % 30;
+    let i = c / 4;
+    let k = c % 4;
+    let l = (32 + 2 * e + 2 * i - h - k) % 7;
+    let m = (a + 11 * h + 22 * l) / 451;
+    let month = (h + l - 7 * m + 114) / 31;
+    let day = (h + l - 7 * m + 114) % 31 + 1;
+    NaiveDate::from_ymd(year, month as u32, day as u32)
+}
+
+/// Checks if the date is a public holiday by law in the given federal state.
+/// Holidays which are only observed in some communities of a state are not included.
+fn is_statutory_holiday(state: &str, date: NaiveDate) -> bool {
+    let year = date.year();
+    let in_states = |states: &[&str]| states.contains(&state);
+
+    let days_after_easter = (date - easter_sunday(year)).num_days();
+    let is_movable_holiday = match days_after_easter {
+        -2 | 1 | 39 | 50 => true,                                     // Karfreitag, Ostermontag, Christi Himmelfahrt, Pfingstmontag
+        0 | 49 => in_states(&["BB"]),                                 // Ostersonntag, Pfingstsonntag
+        60 => in_states(&["BW", "BY", "HE", "NW", "RP", "SL"]),       // Fronleichnam
+        _ => false,
+    };
+    if is_movable_holiday {
+        return true;
+    }
+
+    // Buß- und Bettag is the wednesday before november 23rd
+    if date.month() == 11 && date.day() >= 16 && date.day() <= 22 && date.weekday() == Weekday::Wed {
+        return in_states(&["SN"]);
+    }
+
+    match (date.month(), date.day()) {
+        (1, 1) | (5, 1) | (10, 3) | (12, 25) | (12, 26) => true,
+        (1, 6) => in_states(&["BW", "BY", "ST"]),
+        (3, 8) => (in_states(&["BE"]) && year >= 2019) || (in_states(&["MV"]) && year >= 2023),
+        (8, 15) => in_states(&["SL"]),
+        (9, 20) => in_states(&["TH"]) && year >= 2019,
+        (10, 31) => in_states(&["BB", "MV", "SN", "ST", "TH"]) || (in_states(&["HB", "HH", "NI", "SH"]) && year >= 2018),
+        (11, 1) => in_states(&["BW", "BY", "NW", "RP", "SL"]),
+        _ => false,
+    }
+}
+
+/// Reads all days that are covered by the events of an ICS file. Events may be all-day
+/// events (`DTSTART;VALUE=DATE:20200716`) or have a time, which is ignored. As usual for
+/// all-day events, DTEND is the first day after the event.
+fn read_ics_dates(filename: &str) -> FnResult<HashSet<NaiveDate>> {
+    let content = fs::read_to_string(filename)?;
+    let mut dates = HashSet::new();
+    let mut start: Option<NaiveDate> = None;
+    let mut end: Option<NaiveDate> = None;
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line == "BEGIN:VEVENT" {
+            start = None;
+            end = None;
+        } else if line.starts_with("DTSTART") {
+            start = Some(parse_ics_date(line)?);
+        } else if line.starts_with("DTEND") {
+            end = Some(parse_ics_date(line)?);
+        } else if line == "END:VEVENT" {
+            let first_day = match start {
+                Some(date) => date,
+                None => bail!("Event without DTSTART in {}.", filename),
+            };
+            let mut day = first_day;
+            loop {
+                dates.insert(day);
+                day = day + Duration::days(1);
+                if end.map_or(true, |end| day >= end) {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(dates)
+}
+
+fn parse_ics_date(line: &str) -> FnResult<NaiveDate> {
+    let value = match line.rsplit(':').next() {
+        Some(value) if value.len() >= 8 => &value[0..8],
+        _ => bail!("Invalid date line in ICS file: {}", line),
+    };
+    Ok(NaiveDate::parse_from_str(value, "%Y%m%d")?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_easter_sunday() {
+        assert_eq!(easter_sunday(2019), NaiveDate::from_ymd(2019, 4, 21));
+        assert_eq!(easter_sunday(2020), NaiveDate::from_ymd(2020, 4, 12));
+        assert_eq!(easter_sunday(2021), NaiveDate::from_ymd(2021, 4, 4));
+    }
+
+    #[test]
+    fn test_statutory_holidays() {
+        assert!(is_statutory_holiday("HB", NaiveDate::from_ymd(2020, 4, 10))); // Karfreitag
+        assert!(is_statutory_holiday("NI", NaiveDate::from_ymd(2020, 10, 31)));
+        assert!(!is_statutory_holiday("NI", NaiveDate::from_ymd(2016, 10, 31)));
+        assert!(is_statutory_holiday("BY", NaiveDate::from_ymd(2020, 6, 11))); // Fronleichnam
+        assert!(!is_statutory_holiday("HB", NaiveDate::from_ymd(2020, 6, 11)));
+        assert!(is_statutory_holiday("SN", NaiveDate::from_ymd(2020, 11, 18))); // Buß- und Bettag
+        assert!(!is_statutory_holiday("HB", NaiveDate::from_ymd(2020, 7, 16)));
+    }
+
+    #[test]
+    fn test_day_type() -> FnResult<()> {
+        let mut calendar = HolidayCalendar::default();
+        assert_eq!(calendar.get_day_type(NaiveDate::from_ymd(2020, 5, 1)), DayType::Regular);
+
+        calendar.set_state("hb")?;
+        calendar.school_holidays.insert(NaiveDate::from_ymd(2020, 7, 17)); // friday
+        calendar.school_holidays.insert(NaiveDate::from_ymd(2020, 7, 18)); // saturday
+        assert_eq!(calendar.get_day_type(NaiveDate::from_ymd(2020, 5, 1)), DayType::PublicHoliday);
+        assert_eq!(calendar.get_day_type(NaiveDate::from_ymd(2020, 7, 17)), DayType::SchoolHoliday);
+        assert_eq!(calendar.get_day_type(NaiveDate::from_ymd(2020, 7, 18)), DayType::Regular);
+        assert!(calendar.set_state("XY").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_ics_date() -> FnResult<()> {
+        assert_eq!(parse_ics_date("DTSTART;VALUE=DATE:20200716")?, NaiveDate::from_ymd(2020, 7, 16));
+        assert_eq!(parse_ics_date("DTEND:20200826T000000Z")?, NaiveDate::from_ymd(2020, 8, 26));
+        assert!(parse_ics_date("DTSTART:foo").is_err());
+        Ok(())
+    }
+}
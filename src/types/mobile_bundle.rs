@@ -0,0 +1,302 @@
+use dystonse_curves::{Curve, IrregularDynamicCurve};
+use gtfs_structures::RouteType;
+use std::collections::HashMap;
+use std::io::Write;
+
+use serde::{Serialize, Deserialize};
+use simple_error::bail;
+
+use crate::FnResult;
+use super::{DelayStatistics, CurveData, EventType, RouteSection, RouteVariantData, TimeSlot};
+
+/// Version of the mobile bundle format. Increase it whenever the structure changes.
+pub const MOBILE_FORMAT_VERSION: u16 = 2;
+
+/// The first bytes of each mobile bundle.
+pub const MOBILE_MAGIC: &[u8; 8] = b"DYMOBILE";
+
+/// Probabilities for which the delays are stored in each quantile row.
+pub const MOBILE_QUANTILES: [f32; 5] = [0.05, 0.25, 0.50, 0.75, 0.95];
+
+/// A compact extract of the delay statistics for offline apps. Instead of full curves, it contains
+/// the delays at a few fixed probabilities (see `MOBILE_QUANTILES`), which are enough to approximate
+/// the predictions without realtime data.
+///
+/// The bundle consists of the 8 bytes of `MOBILE_MAGIC`, the format version as big endian u16, and
+/// this struct encoded as packed CBOR, i.e. with the fields of each struct as array in the order of
+/// their declaration, instead of maps with field names.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MobileBundle {
+    pub format_version: u16,
+    /// version of the crate that wrote the bundle, for information only
+    pub created_by: String,
+    /// the probabilities of the columns of all quantile rows
+    pub quantiles: Vec<f32>,
+    /// all stop IDs of the bundle, which are referenced by their index
+    pub stop_ids: Vec<String>,
+    pub routes: Vec<MobileRoute>,
+    /// the fallback for stops without their own quantiles, by route type, route section and time slot
+    pub default_tables: Vec<MobileDefaultTable>,
+    /// for each entry of `stop_ids`, where the stop appears in the route variants, so that an app can
+    /// find the departures of a stop without scanning all routes
+    pub stop_index: Vec<Vec<MobileStopReference>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MobileRoute {
+    pub route_id: String,
+    pub variants: Vec<MobileVariant>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MobileVariant {
+    pub route_variant: u64,
+    /// indices into `MobileBundle::stop_ids`, in the order of the stops of the route variant
+    pub stops: Vec<u32>,
+    /// quantiles of the arrival delay at each stop, if enough samples have been recorded
+    pub arrival: Vec<Option<QuantileRow>>,
+    /// quantiles of the departure delay at each stop, if enough samples have been recorded
+    pub departure: Vec<Option<QuantileRow>>,
+    /// quantiles of the delays at each stop for trips that start within a time slot, only for the
+    /// time slots with enough samples for at least one stop (missing in bundles of version 1)
+    #[serde(default)]
+    pub time_slots: Vec<MobileTimeSlot>,
+}
+
+/// The quantiles of the delays at each stop of a route variant, like in `MobileVariant`, but for trips
+/// that start within the time slot. They are taken from the curve sets from the first stop of the variant.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MobileTimeSlot {
+    /// `id` of the `TimeSlot`
+    pub time_slot: u8,
+    pub arrival: Vec<Option<QuantileRow>>,
+    pub departure: Vec<Option<QuantileRow>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MobileDefaultTable {
+    pub route_type: RouteType,
+    pub route_section: RouteSection,
+    /// `id` of the `TimeSlot`
+    pub time_slot: u8,
+    pub event_type: EventType,
+    pub quantiles: QuantileRow,
+}
+
+/// Identifies a stop of a route variant by the indices of the route in `MobileBundle::routes`, of the
+/// variant in `MobileRoute::variants` and of the stop in `MobileVariant::stops`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct MobileStopReference {
+    pub route: u32,
+    pub variant: u32,
+    pub position: u32,
+}
+
+/// Delays (in seconds) at the probabilities of `MobileBundle::quantiles`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct QuantileRow {
+    pub delays: Vec<i16>,
+    pub sample_size: u32,
+}
+
+impl QuantileRow {
+    pub fn from_curve(curve: &IrregularDynamicCurve<f32, f32>, sample_size: u32) -> Self {
+        QuantileRow {
+            delays: MOBILE_QUANTILES.iter().map(|probability| {
+                curve.x_at_y(*probability).round().max(i16::MIN as f32).min(i16::MAX as f32) as i16
+            }).collect(),
+            sample_size,
+        }
+    }
+}
+
+impl MobileBundle {
+    /// Extracts the quantiles of all route variants for which `include_variant` returns true, given the route
+    /// ID and the route variant. Curves with fewer than `min_samples` samples are left out, as an app is better
+    /// off with the default tables then.
+    pub fn from_delay_statistics(statistics: &DelayStatistics, min_samples: u32, include_variant: impl Fn(&str, &RouteVariantData) -> bool) -> Self {
+        let mut stop_ids: Vec<String> = Vec::new();
+        let mut stop_numbers: HashMap<String, u32> = HashMap::new();
+        let mut stop_index: Vec<Vec<MobileStopReference>> = Vec::new();
+        let mut routes = Vec::new();
+
+        // sorted, so that the same statistics always lead to the same bundle
+        let mut route_ids: Vec<&String> = statistics.specific.keys().collect();
+        route_ids.sort();
+        for route_id in route_ids {
+            let route_data = &statistics.specific[route_id];
+            let mut route_variants: Vec<(&u64, &RouteVariantData)> = route_data.variants.iter()
+                .filter(|(_, rvdata)| include_variant(route_id, rvdata))
+                .collect();
+            if route_variants.is_empty() {
+                continue;
+            }
+            route_variants.sort_by_key(|(route_variant, _)| **route_variant);
+
+            let route_number = routes.len() as u32;
+            let mut variants = Vec::new();
+            for (route_variant, rvdata) in route_variants {
+                let variant_number = variants.len() as u32;
+                let mut stops = Vec::new();
+                for (position, stop_id) in rvdata.stop_ids.iter().enumerate() {
+                    let stop_number = *stop_numbers.entry(stop_id.clone()).or_insert_with(|| {
+                        stop_ids.push(stop_id.clone());
+                        stop_index.push(Vec::new());
+                        stop_ids.len() as u32 - 1
+                    });
+                    stop_index[stop_number as usize].push(MobileStopReference { route: route_number, variant: variant_number, position: position as u32 });
+                    stops.push(stop_number);
+                }
+                let get_rows = |curves: &HashMap<u32, CurveData>| -> Vec<Option<QuantileRow>> {
+                    (0..rvdata.stop_ids.len() as u32).map(|index| curves.get(&index)
+                        .filter(|curve_data| curve_data.sample_size >= min_samples)
+                        .map(|curve_data| QuantileRow::from_curve(&curve_data.curve, curve_data.sample_size))
+                    ).collect()
+                };
+                variants.push(MobileVariant {
+                    route_variant: *route_variant,
+                    stops,
+                    arrival: get_rows(&rvdata.general_delay.arrival),
+                    departure: get_rows(&rvdata.general_delay.departure),
+                    time_slots: Self::get_time_slots(rvdata, min_samples),
+                });
+            }
+            routes.push(MobileRoute { route_id: route_id.clone(), variants });
+        }
+
+        let mut default_tables: Vec<MobileDefaultTable> = statistics.general.all_default_curves.iter().map(|(key, curve_data)| MobileDefaultTable {
+            route_type: key.route_type,
+            route_section: key.route_section.clone(),
+            time_slot: key.time_slot.id,
+            event_type: key.event_type,
+            quantiles: QuantileRow::from_curve(&curve_data.curve, curve_data.sample_size),
+        }).collect();
+        default_tables.sort_by_key(|table| (format!("{:?}", table.route_type), table.route_section.clone(), table.time_slot, table.event_type));
+
+        MobileBundle {
+            format_version: MOBILE_FORMAT_VERSION,
+            created_by: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            quantiles: MOBILE_QUANTILES.to_vec(),
+            stop_ids,
+            routes,
+            default_tables,
+            stop_index,
+        }
+    }
+
+    // the rows for the first stop stay empty, because there are no curve sets from the first stop to itself
+    fn get_time_slots(rvdata: &RouteVariantData, min_samples: u32) -> Vec<MobileTimeSlot> {
+        let get_rows = |event_type: EventType, time_slot: &TimeSlot| -> Vec<Option<QuantileRow>> {
+            (0..rvdata.stop_ids.len() as u32).map(|index| rvdata.get_curve_from_start_stop(0, index, event_type, time_slot)
+                .filter(|(_, sample_size)| *sample_size >= min_samples)
+                .map(|(curve, sample_size)| QuantileRow::from_curve(&curve, sample_size))
+            ).collect()
+        };
+        TimeSlot::TIME_SLOTS_WITH_DEFAULT.iter().filter_map(|time_slot| {
+            let arrival = get_rows(EventType::Arrival, time_slot);
+            let departure = get_rows(EventType::Departure, time_slot);
+            if arrival.iter().chain(departure.iter()).all(Option::is_none) {
+                return None;
+            }
+            Some(MobileTimeSlot { time_slot: time_slot.id, arrival, departure })
+        }).collect()
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> FnResult<()> {
+        writer.write_all(MOBILE_MAGIC)?;
+        writer.write_all(&self.format_version.to_be_bytes())?;
+        writer.write_all(&serde_cbor::ser::to_vec_packed(self)?)?;
+        Ok(())
+    }
+
+    pub fn read(data: &[u8]) -> FnResult<Self> {
+        let header_length = MOBILE_MAGIC.len() + 2;
+        if !data.starts_with(MOBILE_MAGIC) || data.len() < header_length {
+            bail!("Not a mobile bundle.");
+        }
+        let version = u16::from_be_bytes([data[MOBILE_MAGIC.len()], data[MOBILE_MAGIC.len() + 1]]);
+        if version == 0 || version > MOBILE_FORMAT_VERSION {
+            bail!("Unsupported mobile bundle version {}, this version of {} supports versions up to {}.", version, env!("CARGO_PKG_NAME"), MOBILE_FORMAT_VERSION);
+        }
+        Ok(serde_cbor::from_slice(&data[header_length..])?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{CurveSetData, CurveSetKey, DefaultCurveKey, RouteData, PrecisionType, WeatherCondition};
+    use dystonse_curves::Tup;
+    use dystonse_curves::curve_set::CurveSet;
+
+    fn curve_data(sample_size: u32) -> CurveData {
+        CurveData {
+            curve: IrregularDynamicCurve::new(vec![Tup { x: -100.0, y: 0.0 }, Tup { x: 100.0, y: 1.0 }]),
+            precision_type: PrecisionType::Specific,
+            sample_size,
+            effective_sample_size: None,
+        }
+    }
+
+    fn example_statistics() -> DelayStatistics {
+        let mut statistics = DelayStatistics::new();
+        statistics.general.all_default_curves.insert(DefaultCurveKey {
+            route_type: RouteType::Bus,
+            route_section: RouteSection::Middle,
+            time_slot: TimeSlot::DEFAULT,
+            event_type: EventType::Departure,
+        }, curve_data(500));
+        for (route_id, stop_ids) in &[("route 1", vec!["a", "b", "c"]), ("route 2", vec!["c", "d"])] {
+            let mut route_data = RouteData::new(route_id);
+            let mut rvdata = RouteVariantData::new();
+            rvdata.stop_ids = stop_ids.iter().map(|stop_id| stop_id.to_string()).collect();
+            rvdata.general_delay.departure.insert(0, curve_data(50));
+            rvdata.general_delay.departure.insert(1, curve_data(5));
+            if *route_id == "route 1" {
+                let mut curve_set = CurveSet::new();
+                curve_set.add_curve(-100.0, curve_data(0).curve);
+                curve_set.add_curve(100.0, curve_data(0).curve);
+                rvdata.curve_sets.arrival.insert(
+                    CurveSetKey { start_stop_index: 0, end_stop_index: 2, time_slot: TimeSlot::WORKDAY_MORNING_RUSH, weather: WeatherCondition::Unknown },
+                    CurveSetData { curve_set, precision_type: PrecisionType::Specific, sample_size: 40, effective_sample_size: None },
+                );
+            }
+            route_data.variants.insert(7, rvdata);
+            statistics.specific.insert(route_id.to_string(), route_data);
+        }
+        statistics
+    }
+
+    #[test]
+    fn test_mobile_bundle() {
+        let bundle = MobileBundle::from_delay_statistics(&example_statistics(), 10, |_, _| true);
+        assert_eq!(bundle.stop_ids, vec!["a", "b", "c", "d"]);
+        assert_eq!(bundle.routes.len(), 2);
+        let variant = &bundle.routes[0].variants[0];
+        assert_eq!(variant.departure[0], Some(QuantileRow { delays: vec![-90, -50, 0, 50, 90], sample_size: 50 }));
+        // too few samples
+        assert_eq!(variant.departure[1], None);
+        assert_eq!(variant.arrival, vec![None, None, None]);
+        // only the morning rush hour has a curve set, to the last stop
+        assert_eq!(variant.time_slots, vec![MobileTimeSlot {
+            time_slot: TimeSlot::WORKDAY_MORNING_RUSH.id,
+            arrival: vec![None, None, Some(QuantileRow { delays: vec![-90, -50, 0, 50, 90], sample_size: 40 })],
+            departure: vec![None, None, None],
+        }]);
+        assert!(bundle.routes[1].variants[0].time_slots.is_empty());
+        // stop c is served by both routes
+        assert_eq!(bundle.stop_index[2], vec![
+            MobileStopReference { route: 0, variant: 0, position: 2 },
+            MobileStopReference { route: 1, variant: 0, position: 0 },
+        ]);
+        assert_eq!(bundle.default_tables.len(), 1);
+
+        let mut data = Vec::new();
+        bundle.write(&mut data).unwrap();
+        assert!(data.starts_with(b"DYMOBILE\0\x02"));
+        assert_eq!(MobileBundle::read(&data).unwrap(), bundle);
+
+        let only_route_2 = MobileBundle::from_delay_statistics(&example_statistics(), 10, |_, rvdata| rvdata.stop_ids.contains(&String::from("d")));
+        assert_eq!(only_route_2.stop_ids, vec!["c", "d"]);
+    }
+}
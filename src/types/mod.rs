@@ -9,6 +9,23 @@ mod route_variant_data;
 mod time_slots;
 mod curve_data;
 mod gtfs_time;
+mod curve_store;
+mod holidays;
+mod weather;
+mod operation_statistics;
+mod portable_statistics;
+mod mobile_bundle;
+mod curve_parameters;
+mod outlier_policy;
+mod db_prediction;
+mod agency_filter;
+mod headway_statistics;
+mod symbol_table;
+mod stop_id_mapping;
+mod age_weighting;
+mod calibration;
+mod feed_quirks;
+pub mod curve_format;
 
 pub use db_item::DbItem;
 pub use default_curves::DefaultCurves;
@@ -17,11 +34,27 @@ pub use delay_statistics::DelayStatistics;
 pub use event_type::{EventType, EventPair, GetByEventType};
 pub use prediction_result::PredictionResult;
 pub use route_data::RouteData;
-pub use route_sections::RouteSection;
-pub use route_variant_data::{RouteVariantData, CurveSetKey};
+pub use route_sections::{RouteSection, RouteSectioning};
+pub use route_variant_data::{RouteVariantData, CurveSetKey, DwellTimeKey, align_stop_ids};
 pub use time_slots::TimeSlot;
-pub use curve_data::{CurveData, CurveSetData};
+pub use curve_data::{CurveData, CurveSetData, MIXTURE_QUANTILES};
 pub use gtfs_time::GtfsDateTime;
+pub use curve_store::CurveStore;
+pub use holidays::{HolidayCalendar, DayType};
+pub use weather::{WeatherCondition, WeatherProvider};
+pub use operation_statistics::{OperationKey, OperationCounts};
+pub use portable_statistics::{PortableStatistics, PortableFormat};
+pub use mobile_bundle::{MobileBundle, MOBILE_QUANTILES};
+pub use curve_parameters::CurveParameters;
+pub use outlier_policy::{OutlierPolicy, OutlierStrategy};
+pub use db_prediction::{DbPrediction, DbPredictionMetaData};
+pub use agency_filter::AgencyFilter;
+pub use headway_statistics::{HeadwayStatistics, HeadwayEntry};
+pub use symbol_table::{Id, SymbolTable};
+pub use stop_id_mapping::{StopIdMapping, StopIdMatch};
+pub use age_weighting::{AgeWeighting, effective_sample_size};
+pub use calibration::{CalibrationKey, CalibrationCounts};
+pub use feed_quirks::FeedQuirks;
 
 use serde::{Serialize, Deserialize};
 
@@ -68,7 +101,8 @@ pub enum PrecisionType {
     SemiSpecific,      // depends on recorded data for this specific stop, but without current realtime data
     General,           // depends on RouteType, TimeSlot, RouteSection
     FallbackGeneral,   // depends on RouteType
-    SuperGeneral       // average of everything
+    SuperGeneral,      // average of everything
+    BlockPropagated,   // depends on the predicted delay of the same vehicle at the end of its previous trip
 }
 
 impl PrecisionType {
@@ -81,6 +115,7 @@ impl PrecisionType {
             Self::General => 4,
             Self::FallbackGeneral => 5,
             Self::SuperGeneral => 6,
+            Self::BlockPropagated => 7,
         }
     }
 
@@ -93,6 +128,7 @@ impl PrecisionType {
             4 => Self::General,
             5 => Self::FallbackGeneral,
             6 => Self::SuperGeneral,
+            7 => Self::BlockPropagated,
             _ => Self::Unknown 
         }
     }
@@ -100,7 +136,7 @@ impl PrecisionType {
 
 #[derive(Hash, PartialEq, Eq, Clone, Debug)]
 pub struct VehicleIdentifier {
-    pub trip_id: String,
+    pub trip_id: Id,
     pub start: GtfsDateTime,
 }
 
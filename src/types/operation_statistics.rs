@@ -0,0 +1,39 @@
+use serde::{Serialize, Deserialize};
+
+use crate::types::TimeSlot;
+
+/// Identifies the scheduled trips of a route that start within a time slot.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub struct OperationKey {
+    pub route_id: String,
+    pub time_slot: TimeSlot,
+}
+
+/// Counts how many scheduled trips were actually operated. A trip counts as operated
+/// if there is any realtime data for it.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct OperationCounts {
+    pub scheduled_trips: u32,
+    pub operated_trips: u32,
+}
+
+impl OperationCounts {
+    /// Probability (between 0 and 1) that a scheduled trip is operated.
+    pub fn probability(&self) -> f32 {
+        if self.scheduled_trips == 0 {
+            return 1.0;
+        }
+        self.operated_trips as f32 / self.scheduled_trips as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probability() {
+        assert_eq!(OperationCounts::default().probability(), 1.0);
+        assert_eq!(OperationCounts { scheduled_trips: 40, operated_trips: 30 }.probability(), 0.75);
+    }
+}
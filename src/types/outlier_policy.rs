@@ -0,0 +1,225 @@
+use clap::{Arg, ArgMatches};
+use serde::{Serialize, Deserialize};
+use simple_error::bail;
+
+use crate::{FnResult, OrError};
+use crate::stats_util::{median, quantile};
+use super::FeedQuirks;
+
+/// Decides which delays are considered outliers and ignored when the curve sets are computed.
+/// Outliers are removed separately for the start and end delays of the pairs of each stop pair
+/// (or stop, for dwell times), so the percentile and MAD strategies adapt to the data of each of them.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum OutlierStrategy {
+    /// delays of at least this many seconds, early or late, are ignored
+    Cutoff(i32),
+    /// this fraction of the lowest and of the highest delays is ignored
+    Percentile(f32),
+    /// delays that are more than this many median absolute deviations away from the median are ignored
+    Mad(f32),
+    /// all delays are used
+    None,
+}
+
+/// How the delays are cleaned up before curve sets are computed from them. The policy is
+/// stored together with the statistics, so that it is known how they have been computed.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct OutlierPolicy {
+    pub strategy: OutlierStrategy,
+    /// delays are rounded towards zero to multiples of this many seconds. Much of the data that we get
+    /// from the agencies tends to be rounded that way, and mixing up rounded and non-rounded data leads
+    /// to all kinds of problems.
+    pub rounding: i32,
+}
+
+impl OutlierPolicy {
+    /// The policy that was used before it could be configured, and for statistics without a policy.
+    pub const DEFAULT: OutlierPolicy = OutlierPolicy {
+        strategy: OutlierStrategy::Cutoff(3000),
+        rounding: 12,
+    };
+
+    pub const NAMES: [&'static str; 4] = ["cutoff", "percentile", "mad", "none"];
+
+    /// The arguments that are read by `from_args`, for the subcommands that compute curve sets.
+    pub fn get_args<'a>() -> Vec<Arg<'a>> {
+        vec![
+            Arg::new("outliers")
+                .long("outliers")
+                .default_value("cutoff")
+                .possible_values(&Self::NAMES)
+                .about("How outliers are removed from the pairs of delays of which the curve sets are computed: delays beyond outlier-cutoff, the outlier-percentile of the lowest and highest delays, delays more than outlier-mad-factor median absolute deviations away from the median, or none at all.")
+                .value_name("STRATEGY")
+                .takes_value(true),
+            Arg::new("outlier-cutoff")
+                .long("outlier-cutoff")
+                .default_value("3000")
+                .about("Delays of at least this many seconds (early or late) are ignored, if outliers is cutoff.")
+                .value_name("SECONDS")
+                .takes_value(true),
+            Arg::new("outlier-percentile")
+                .long("outlier-percentile")
+                .default_value("1")
+                .about("Percentage of the lowest and of the highest delays of each stop pair that is ignored, if outliers is percentile.")
+                .value_name("PERCENT")
+                .takes_value(true),
+            Arg::new("outlier-mad-factor")
+                .long("outlier-mad-factor")
+                .default_value("5")
+                .about("Delays that are more than this many median absolute deviations away from the median delay of each stop pair are ignored, if outliers is mad.")
+                .value_name("FACTOR")
+                .takes_value(true),
+            Arg::new("delay-rounding")
+                .long("delay-rounding")
+                .about("Delays are rounded towards zero to multiples of this many seconds, because many agencies round their delays that way. 1 disables rounding. Defaults to feed-delay-granularity.")
+                .value_name("SECONDS")
+                .takes_value(true),
+        ]
+    }
+
+    /// Creates the policy that is configured by the `outliers`, `outlier-cutoff`, `outlier-percentile`,
+    /// `outlier-mad-factor` and `delay-rounding` arguments. Without `delay-rounding`, delays are rounded
+    /// to the granularity of the feed.
+    pub fn from_args(args: &ArgMatches, feed_quirks: &FeedQuirks) -> FnResult<Self> {
+        let get = |name: &str| args.value_of(name).or_error(&format!("Argument {} is missing.", name));
+        let strategy = match args.value_of("outliers").unwrap_or("cutoff") {
+            "cutoff" => {
+                let cutoff: i32 = get("outlier-cutoff")?.parse()?;
+                if cutoff <= 0 {
+                    bail!("Outlier cutoff must be positive.");
+                }
+                OutlierStrategy::Cutoff(cutoff)
+            },
+            "percentile" => {
+                let percent: f32 = get("outlier-percentile")?.parse()?;
+                if !(0.0..50.0).contains(&percent) {
+                    bail!("Outlier percentile must be at least 0 and less than 50.");
+                }
+                OutlierStrategy::Percentile(percent / 100.0)
+            },
+            "mad" => {
+                let factor: f32 = get("outlier-mad-factor")?.parse()?;
+                if factor <= 0.0 {
+                    bail!("Outlier MAD factor must be positive.");
+                }
+                OutlierStrategy::Mad(factor)
+            },
+            "none" => OutlierStrategy::None,
+            other => bail!("Unknown outlier strategy {}.", other),
+        };
+        let rounding: i32 = match args.value_of("delay-rounding") {
+            Some(rounding) => rounding.parse()?,
+            None => feed_quirks.delay_granularity,
+        };
+        if rounding < 1 {
+            bail!("Delay rounding must be at least 1 second.");
+        }
+        Ok(OutlierPolicy { strategy, rounding })
+    }
+
+    /// Removes the pairs of start and end delays in which either delay is an outlier,
+    /// and rounds the delays of the remaining pairs.
+    pub fn apply(&self, pairs: &[(i32, i32)]) -> Vec<(f32, f32)> {
+        let weighted_pairs: Vec<((i32, i32), f32)> = pairs.iter().map(|pair| (*pair, 1.0)).collect();
+        self.apply_weighted(&weighted_pairs).into_iter().map(|(pair, _)| pair).collect()
+    }
+
+    /// Like `apply`, but each pair comes with a weight (see `AgeWeighting`), which is kept.
+    pub fn apply_weighted(&self, pairs: &[((i32, i32), f32)]) -> Vec<((f32, f32), f32)> {
+        let start_bounds = self.get_bounds(pairs.iter().map(|((s, _), _)| *s).collect());
+        let end_bounds = self.get_bounds(pairs.iter().map(|((_, e), _)| *e).collect());
+        pairs.iter()
+            .filter(|((s, e), _)| is_within(*s, start_bounds) && is_within(*e, end_bounds))
+            .map(|((s, e), weight)| ((self.round(*s), self.round(*e)), *weight))
+            .collect()
+    }
+
+    fn round(&self, delay: i32) -> f32 {
+        ((delay / self.rounding) * self.rounding) as f32
+    }
+
+    // returns the lowest and highest delay that are not outliers, or None if all delays are used
+    fn get_bounds(&self, mut delays: Vec<i32>) -> Option<(f32, f32)> {
+        if delays.is_empty() {
+            return None;
+        }
+        match self.strategy {
+            OutlierStrategy::Cutoff(cutoff) => Some(((1 - cutoff) as f32, (cutoff - 1) as f32)),
+            OutlierStrategy::Percentile(fraction) => {
+                Some((quantile(&mut delays, fraction) as f32, quantile(&mut delays, 1.0 - fraction) as f32))
+            },
+            OutlierStrategy::Mad(factor) => {
+                let median_delay = median(&mut delays);
+                let mut deviations: Vec<i32> = delays.iter().map(|delay| (delay - median_delay).abs()).collect();
+                // if most delays are the same, a deviation of one rounding step is still normal
+                let mad = i32::max(median(&mut deviations), self.rounding) as f32;
+                Some((median_delay as f32 - factor * mad, median_delay as f32 + factor * mad))
+            },
+            OutlierStrategy::None => None,
+        }
+    }
+}
+
+impl Default for OutlierPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+fn is_within(delay: i32, bounds: Option<(f32, f32)>) -> bool {
+    match bounds {
+        Some((min, max)) => delay as f32 >= min && delay as f32 <= max,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(strategy: OutlierStrategy) -> OutlierPolicy {
+        OutlierPolicy { strategy, rounding: 12 }
+    }
+
+    #[test]
+    fn test_strategies() {
+        // like the hard-coded filter before
+        let pairs = vec![(0, 30), (-2999, 2999), (3000, 0), (0, -3000), (25, -25)];
+        assert_eq!(OutlierPolicy::DEFAULT.apply(&pairs), vec![(0.0, 24.0), (-2988.0, 2988.0), (24.0, -24.0)]);
+        assert_eq!(policy(OutlierStrategy::None).apply(&pairs).len(), 5);
+        let weighted_pairs: Vec<((i32, i32), f32)> = pairs.iter().map(|pair| (*pair, 0.5)).collect();
+        assert_eq!(OutlierPolicy::DEFAULT.apply_weighted(&weighted_pairs), vec![((0.0, 24.0), 0.5), ((-2988.0, 2988.0), 0.5), ((24.0, -24.0), 0.5)]);
+
+        let mut pairs: Vec<(i32, i32)> = (0..100).map(|i| (i * 12, 60)).collect();
+        pairs.push((60, 5000));
+        // 1% of 101 pairs is one pair at each end, for start and end delays
+        let trimmed = policy(OutlierStrategy::Percentile(0.01)).apply(&pairs);
+        assert_eq!(trimmed.len(), 98);
+        assert!(!trimmed.contains(&(60.0, 4992.0)));
+        assert!(!trimmed.contains(&(0.0, 60.0)));
+
+        // all end delays but one are the same, so the deviation of one rounding step applies
+        let trimmed = policy(OutlierStrategy::Mad(5.0)).apply(&pairs);
+        assert_eq!(trimmed.len(), 100);
+        assert!(trimmed.iter().all(|(_, e)| *e == 60.0));
+    }
+
+    fn from_args(args: &[&str]) -> FnResult<OutlierPolicy> {
+        let mut all_args = vec!["compute-curves"];
+        all_args.extend_from_slice(args);
+        let matches = clap::App::new("compute-curves").args(OutlierPolicy::get_args()).try_get_matches_from(all_args)?;
+        OutlierPolicy::from_args(&matches, &FeedQuirks::DEFAULT)
+    }
+
+    #[test]
+    fn test_from_args() {
+        assert_eq!(from_args(&[]).unwrap(), OutlierPolicy { strategy: OutlierStrategy::Cutoff(3000), rounding: FeedQuirks::DEFAULT.delay_granularity });
+        assert_eq!(from_args(&["--outliers", "percentile", "--outlier-percentile", "2"]).unwrap().strategy, OutlierStrategy::Percentile(0.02));
+        assert_eq!(from_args(&["--outliers", "mad", "--delay-rounding", "1"]).unwrap(), OutlierPolicy { strategy: OutlierStrategy::Mad(5.0), rounding: 1 });
+        assert_eq!(from_args(&["--outliers", "none"]).unwrap().strategy, OutlierStrategy::None);
+        assert!(from_args(&["--outliers", "cutoff", "--outlier-cutoff", "0"]).is_err());
+        assert!(from_args(&["--outliers", "percentile", "--outlier-percentile", "50"]).is_err());
+        assert!(from_args(&["--delay-rounding", "0"]).is_err());
+        assert!(from_args(&["--outliers", "median"]).is_err());
+    }
+}
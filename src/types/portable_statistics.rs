@@ -0,0 +1,732 @@
+use dystonse_curves::{IrregularDynamicCurve, Tup};
+use dystonse_curves::curve_set::CurveSet;
+use gtfs_structures::RouteType;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+
+use serde::{Serialize, Deserialize};
+use simple_error::bail;
+
+use crate::{FnResult, OrError};
+use crate::predictor::parse_event_type;
+use super::{DelayStatistics, DefaultCurves, DefaultCurveKey, RouteData, RouteVariantData, CurveData, CurveSetData,
+    CurveSetKey, DwellTimeKey, EventPair, EventType, OperationKey, OperationCounts, CurveParameters, RouteSection, RouteSectioning,
+    OutlierPolicy, OutlierStrategy, FeedQuirks, CalibrationKey, CalibrationCounts, PrecisionType, TimeSlot, WeatherCondition};
+use super::route_sections::ListedSection;
+use super::curve_format::CurvePoint;
+
+/// Version of the portable format. Increase it whenever the structure changes in a way
+/// that older versions of this crate can't read.
+pub const PORTABLE_FORMAT_VERSION: u16 = 1;
+
+/// The first bytes of each file in the binary format.
+pub const PORTABLE_MAGIC: &[u8; 8] = b"DYSTATS\0";
+
+/// A representation of `DelayStatistics` which does not depend on the internal data structures,
+/// so that it can be exchanged between installations with different versions of this crate.
+/// All maps are stored as lists of entries, because JSON only allows strings as map keys, and
+/// all enums are stored as their names or as the numbers that are also used in the database.
+///
+/// The binary format consists of the 8 bytes of `PORTABLE_MAGIC`, the format version as
+/// big endian u16, and this struct encoded as MessagePack with named fields. The JSON format
+/// contains the format version as a field instead.
+#[derive(Serialize, Deserialize)]
+pub struct PortableStatistics {
+    pub format_version: u16,
+    /// version of the crate that wrote the file, for information only
+    pub created_by: String,
+    pub default_curves: Vec<PortableDefaultCurve>,
+    /// `RouteSectioning::StopCount` if missing
+    #[serde(default)]
+    pub route_sectioning: Option<PortableRouteSectioning>,
+    pub route_variants: Vec<PortableRouteVariant>,
+    #[serde(default)]
+    pub merged_variants: Vec<PortableMergedVariant>,
+    #[serde(default)]
+    pub operation: Vec<PortableOperation>,
+    #[serde(default)]
+    pub curve_parameters: Vec<PortableCurveParameters>,
+    /// `OutlierPolicy::DEFAULT` if missing
+    #[serde(default)]
+    pub outlier_policy: Option<PortableOutlierPolicy>,
+    #[serde(default)]
+    pub calibration: Vec<PortableCalibration>,
+}
+
+/// A single curve, see `CurveData`.
+#[derive(Serialize, Deserialize)]
+pub struct PortableCurve {
+    pub points: Vec<CurvePoint>,
+    /// see `PrecisionType::to_int`
+    pub precision_type: u8,
+    pub sample_size: u32,
+    #[serde(default)]
+    pub effective_sample_size: Option<f32>,
+}
+
+/// A curve set, see `CurveSetData`.
+#[derive(Serialize, Deserialize)]
+pub struct PortableCurveSet {
+    pub curves: Vec<PortableFocusCurve>,
+    /// see `PrecisionType::to_int`
+    pub precision_type: u8,
+    pub sample_size: u32,
+    #[serde(default)]
+    pub effective_sample_size: Option<f32>,
+}
+
+/// One curve of a curve set, for the initial delay given by `focus`.
+#[derive(Serialize, Deserialize)]
+pub struct PortableFocusCurve {
+    pub focus: f32,
+    pub points: Vec<CurvePoint>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PortableDefaultCurve {
+    /// the route type as defined by GTFS, e.g. 3 for buses
+    pub route_type: i32,
+    /// beginning, middle or end
+    pub route_section: String,
+    /// see `TimeSlot::id`
+    pub time_slot: u8,
+    /// arrival or departure
+    pub event_type: String,
+    pub curve: PortableCurve,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PortableRouteSectioning {
+    /// one of `RouteSectioning::NAMES`
+    pub strategy: String,
+    /// only for the distance strategy, in meters
+    #[serde(default)]
+    pub section_length: Option<f64>,
+    /// only for the stop-list strategy
+    #[serde(default)]
+    pub section_stops: Vec<PortableListedSection>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PortableListedSection {
+    pub stop_id: String,
+    #[serde(default)]
+    pub route_id: Option<String>,
+    /// beginning, middle or end
+    pub section: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PortableRouteVariant {
+    pub route_id: String,
+    pub route_variant: u64,
+    pub stop_ids: Vec<String>,
+    pub general_delay: PortableEventPair<PortableStopCurve>,
+    pub curve_sets: PortableEventPair<PortableStopPairCurveSet>,
+    #[serde(default)]
+    pub dwell_times: Vec<PortableDwellTime>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PortableEventPair<T> {
+    #[serde(default)]
+    pub arrival: Vec<T>,
+    #[serde(default)]
+    pub departure: Vec<T>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PortableStopCurve {
+    pub stop_index: u32,
+    pub curve: PortableCurve,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PortableStopPairCurveSet {
+    pub start_stop_index: u32,
+    pub end_stop_index: u32,
+    /// see `TimeSlot::id`
+    pub time_slot: u8,
+    /// see `WeatherCondition::to_int`, 0 for curve sets that are valid for any weather
+    #[serde(default)]
+    pub weather: u8,
+    pub curve_set: PortableCurveSet,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PortableDwellTime {
+    pub stop_index: u32,
+    /// see `TimeSlot::id`
+    pub time_slot: u8,
+    pub curve_set: PortableCurveSet,
+}
+
+/// A route variant whose records have been pooled into the curves of another route variant.
+#[derive(Serialize, Deserialize)]
+pub struct PortableMergedVariant {
+    pub route_id: String,
+    pub route_variant: u64,
+    pub merged_into: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PortableOperation {
+    pub route_id: String,
+    /// see `TimeSlot::id`
+    pub time_slot: u8,
+    pub scheduled_trips: u32,
+    pub operated_trips: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PortableCurveParameters {
+    pub route_id: String,
+    pub simplification_tolerance: f32,
+    pub min_points_per_marker: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PortableOutlierPolicy {
+    /// one of `OutlierPolicy::NAMES`
+    pub strategy: String,
+    /// only for the cutoff strategy, in seconds
+    #[serde(default)]
+    pub cutoff: Option<i32>,
+    /// only for the percentile strategy, as a fraction (e.g. 0.01 for 1%)
+    #[serde(default)]
+    pub percentile: Option<f32>,
+    /// only for the mad strategy
+    #[serde(default)]
+    pub mad_factor: Option<f32>,
+    pub rounding: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PortableCalibration {
+    /// the replayed day, as YYYY-MM-DD
+    pub date: String,
+    pub route_id: String,
+    /// see `PrecisionType::to_int`
+    pub precision_type: u8,
+    pub pit_histogram: [u32; 10],
+}
+
+/// The formats in which statistics can be exported and imported.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PortableFormat {
+    Json,
+    Binary,
+}
+
+impl PortableFormat {
+    pub const NAMES: [&'static str; 2] = ["json", "binary"];
+
+    pub fn from_name(name: &str) -> FnResult<Self> {
+        match name {
+            "json" => Ok(PortableFormat::Json),
+            "binary" => Ok(PortableFormat::Binary),
+            _ => bail!("Unknown statistics format: {}. Known formats are: {}", name, Self::NAMES.join(", ")),
+        }
+    }
+}
+
+fn to_points(curve: &IrregularDynamicCurve<f32, f32>) -> Vec<CurvePoint> {
+    let (xs, ys) = curve.get_values_as_vectors();
+    xs.into_iter().zip(ys.into_iter()).map(|(delay, probability)| CurvePoint { delay, probability }).collect()
+}
+
+fn from_points(points: &[CurvePoint]) -> IrregularDynamicCurve<f32, f32> {
+    IrregularDynamicCurve::new(points.iter().map(|point| Tup { x: point.delay, y: point.probability }).collect())
+}
+
+impl PortableCurve {
+    fn from_curve_data(data: &CurveData) -> Self {
+        PortableCurve {
+            points: to_points(&data.curve),
+            precision_type: data.precision_type.to_int(),
+            sample_size: data.sample_size,
+            effective_sample_size: data.effective_sample_size,
+        }
+    }
+
+    fn to_curve_data(&self) -> CurveData {
+        CurveData {
+            curve: from_points(&self.points),
+            precision_type: PrecisionType::from_int(self.precision_type),
+            sample_size: self.sample_size,
+            effective_sample_size: self.effective_sample_size,
+        }
+    }
+}
+
+impl PortableCurveSet {
+    fn from_curve_set_data(data: &CurveSetData) -> Self {
+        PortableCurveSet {
+            curves: data.curve_set.curves.iter().map(|(focus, curve)| PortableFocusCurve { focus: *focus, points: to_points(curve) }).collect(),
+            precision_type: data.precision_type.to_int(),
+            sample_size: data.sample_size,
+            effective_sample_size: data.effective_sample_size,
+        }
+    }
+
+    fn to_curve_set_data(&self) -> CurveSetData {
+        let mut curve_set = CurveSet::new();
+        for curve in &self.curves {
+            curve_set.add_curve(curve.focus, from_points(&curve.points));
+        }
+        CurveSetData {
+            curve_set,
+            precision_type: PrecisionType::from_int(self.precision_type),
+            sample_size: self.sample_size,
+            effective_sample_size: self.effective_sample_size,
+        }
+    }
+}
+
+impl PortableRouteSectioning {
+    fn named(strategy: &str) -> Self {
+        PortableRouteSectioning { strategy: String::from(strategy), section_length: None, section_stops: Vec::new() }
+    }
+
+    fn from_route_sectioning(sectioning: &RouteSectioning) -> Self {
+        match sectioning {
+            RouteSectioning::StopCount => Self::named("stop-count"),
+            RouteSectioning::Distance(length) => PortableRouteSectioning { section_length: Some(*length), ..Self::named("distance") },
+            RouteSectioning::FareZone => Self::named("fare-zone"),
+            RouteSectioning::StopList(stops) => PortableRouteSectioning {
+                section_stops: stops.iter().flat_map(|(stop_id, sections)| sections.iter().map(move |listed| PortableListedSection {
+                    stop_id: stop_id.clone(),
+                    route_id: listed.route_id.clone(),
+                    section: String::from(route_section_name(&listed.section)),
+                })).collect(),
+                ..Self::named("stop-list")
+            },
+        }
+    }
+
+    fn to_route_sectioning(&self) -> FnResult<RouteSectioning> {
+        Ok(match self.strategy.as_str() {
+            "stop-count" => RouteSectioning::StopCount,
+            "distance" => RouteSectioning::Distance(self.section_length.or_error("Route sectioning by distance without section length.")?),
+            "fare-zone" => RouteSectioning::FareZone,
+            "stop-list" => {
+                let mut stops: HashMap<String, Vec<ListedSection>> = HashMap::new();
+                for listed in &self.section_stops {
+                    stops.entry(listed.stop_id.clone()).or_default().push(ListedSection {
+                        route_id: listed.route_id.clone(),
+                        section: RouteSection::from_name(&listed.section)?,
+                    });
+                }
+                RouteSectioning::StopList(stops)
+            },
+            other => bail!("Unknown route sectioning {}. Known strategies are: {}", other, RouteSectioning::NAMES.join(", ")),
+        })
+    }
+}
+
+impl PortableOutlierPolicy {
+    fn from_outlier_policy(policy: &OutlierPolicy) -> Self {
+        let named = |strategy: &str| PortableOutlierPolicy {
+            strategy: String::from(strategy),
+            cutoff: None,
+            percentile: None,
+            mad_factor: None,
+            rounding: policy.rounding,
+        };
+        match policy.strategy {
+            OutlierStrategy::Cutoff(cutoff) => PortableOutlierPolicy { cutoff: Some(cutoff), ..named("cutoff") },
+            OutlierStrategy::Percentile(percentile) => PortableOutlierPolicy { percentile: Some(percentile), ..named("percentile") },
+            OutlierStrategy::Mad(factor) => PortableOutlierPolicy { mad_factor: Some(factor), ..named("mad") },
+            OutlierStrategy::None => named("none"),
+        }
+    }
+
+    fn to_outlier_policy(&self) -> FnResult<OutlierPolicy> {
+        let strategy = match self.strategy.as_str() {
+            "cutoff" => OutlierStrategy::Cutoff(self.cutoff.or_error("Outlier policy without cutoff.")?),
+            "percentile" => OutlierStrategy::Percentile(self.percentile.or_error("Outlier policy without percentile.")?),
+            "mad" => OutlierStrategy::Mad(self.mad_factor.or_error("Outlier policy without MAD factor.")?),
+            "none" => OutlierStrategy::None,
+            other => bail!("Unknown outlier strategy {}. Known strategies are: {}", other, OutlierPolicy::NAMES.join(", ")),
+        };
+        Ok(OutlierPolicy { strategy, rounding: self.rounding })
+    }
+}
+
+fn route_section_name(section: &RouteSection) -> &'static str {
+    match section {
+        RouteSection::Beginning => "beginning",
+        RouteSection::Middle => "middle",
+        RouteSection::End => "end",
+    }
+}
+
+fn event_type_name(event_type: &EventType) -> &'static str {
+    match event_type {
+        EventType::Arrival => "arrival",
+        EventType::Departure => "departure",
+    }
+}
+
+fn route_type_code(route_type: &RouteType) -> i32 {
+    match route_type {
+        RouteType::Tramway => 0,
+        RouteType::Subway => 1,
+        RouteType::Rail => 2,
+        RouteType::Bus => 3,
+        RouteType::Ferry => 4,
+        RouteType::CableCar => 5,
+        RouteType::Gondola => 6,
+        RouteType::Funicular => 7,
+        RouteType::Coach => 200,
+        RouteType::Air => 1100,
+        RouteType::Taxi => 1500,
+        RouteType::Other(code) => i32::from(*code),
+    }
+}
+
+fn route_type_from_code(code: i32) -> RouteType {
+    match code {
+        0 => RouteType::Tramway,
+        1 => RouteType::Subway,
+        2 => RouteType::Rail,
+        3 => RouteType::Bus,
+        4 => RouteType::Ferry,
+        5 => RouteType::CableCar,
+        6 => RouteType::Gondola,
+        7 => RouteType::Funicular,
+        200 => RouteType::Coach,
+        1100 => RouteType::Air,
+        1500 => RouteType::Taxi,
+        other => RouteType::Other(other as _),
+    }
+}
+
+fn time_slot(id: u8) -> FnResult<TimeSlot> {
+    Ok(TimeSlot::from_id(id).or_error(&format!("Unknown time slot id {}.", id))?.clone())
+}
+
+impl PortableStatistics {
+    pub fn from_delay_statistics(statistics: &DelayStatistics) -> Self {
+        let mut route_variants = Vec::new();
+        let mut merged_variants = Vec::new();
+        for route_data in statistics.specific.values() {
+            for (route_variant, merged_into) in &route_data.merged_variants {
+                merged_variants.push(PortableMergedVariant {
+                    route_id: route_data.route_id.clone(),
+                    route_variant: *route_variant,
+                    merged_into: *merged_into,
+                });
+            }
+            for (route_variant, rvdata) in &route_data.variants {
+                let general_delay = |event_type: EventType| rvdata.general_delay[event_type].iter()
+                    .map(|(stop_index, data)| PortableStopCurve { stop_index: *stop_index, curve: PortableCurve::from_curve_data(data) })
+                    .collect();
+                let curve_sets = |event_type: EventType| rvdata.curve_sets[event_type].iter()
+                    .map(|(key, data)| PortableStopPairCurveSet {
+                        start_stop_index: key.start_stop_index,
+                        end_stop_index: key.end_stop_index,
+                        time_slot: key.time_slot.id,
+                        weather: key.weather.to_int(),
+                        curve_set: PortableCurveSet::from_curve_set_data(data),
+                    })
+                    .collect();
+                route_variants.push(PortableRouteVariant {
+                    route_id: route_data.route_id.clone(),
+                    route_variant: *route_variant,
+                    stop_ids: rvdata.stop_ids.clone(),
+                    general_delay: PortableEventPair {
+                        arrival: general_delay(EventType::Arrival),
+                        departure: general_delay(EventType::Departure),
+                    },
+                    curve_sets: PortableEventPair {
+                        arrival: curve_sets(EventType::Arrival),
+                        departure: curve_sets(EventType::Departure),
+                    },
+                    dwell_times: rvdata.dwell_times.iter().map(|(key, data)| PortableDwellTime {
+                        stop_index: key.stop_index,
+                        time_slot: key.time_slot.id,
+                        curve_set: PortableCurveSet::from_curve_set_data(data),
+                    }).collect(),
+                });
+            }
+        }
+
+        PortableStatistics {
+            format_version: PORTABLE_FORMAT_VERSION,
+            created_by: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            default_curves: statistics.general.all_default_curves.iter().map(|(key, data)| PortableDefaultCurve {
+                route_type: route_type_code(&key.route_type),
+                route_section: String::from(route_section_name(&key.route_section)),
+                time_slot: key.time_slot.id,
+                event_type: String::from(event_type_name(&key.event_type)),
+                curve: PortableCurve::from_curve_data(data),
+            }).collect(),
+            route_sectioning: Some(PortableRouteSectioning::from_route_sectioning(&statistics.general.route_sectioning)),
+            route_variants,
+            merged_variants,
+            operation: statistics.operation.iter().map(|(key, counts)| PortableOperation {
+                route_id: key.route_id.clone(),
+                time_slot: key.time_slot.id,
+                scheduled_trips: counts.scheduled_trips,
+                operated_trips: counts.operated_trips,
+            }).collect(),
+            curve_parameters: statistics.curve_parameters.iter().map(|(route_id, parameters)| PortableCurveParameters {
+                route_id: route_id.clone(),
+                simplification_tolerance: parameters.simplification_tolerance,
+                min_points_per_marker: parameters.min_points_per_marker,
+            }).collect(),
+            outlier_policy: Some(PortableOutlierPolicy::from_outlier_policy(&statistics.outlier_policy)),
+            calibration: statistics.calibration.iter().flat_map(|(date, day)| day.iter().map(move |(key, counts)| PortableCalibration {
+                date: date.clone(),
+                route_id: key.route_id.clone(),
+                precision_type: key.precision_type,
+                pit_histogram: counts.pit_histogram,
+            })).collect(),
+        }
+    }
+
+    pub fn into_delay_statistics(self) -> FnResult<DelayStatistics> {
+        let mut specific: HashMap<String, RouteData> = HashMap::new();
+        for variant in self.route_variants {
+            let general_delay = |curves: &[PortableStopCurve]| -> HashMap<u32, CurveData> {
+                curves.iter().map(|curve| (curve.stop_index, curve.curve.to_curve_data())).collect()
+            };
+            let curve_sets = |entries: &[PortableStopPairCurveSet]| -> FnResult<HashMap<CurveSetKey, CurveSetData>> {
+                entries.iter().map(|curve_set| Ok((CurveSetKey {
+                    start_stop_index: curve_set.start_stop_index,
+                    end_stop_index: curve_set.end_stop_index,
+                    time_slot: time_slot(curve_set.time_slot)?,
+                    weather: WeatherCondition::from_int(curve_set.weather),
+                }, curve_set.curve_set.to_curve_set_data()))).collect()
+            };
+            let dwell_times: HashMap<DwellTimeKey, CurveSetData> = variant.dwell_times.iter().map(|dwell_time| Ok((DwellTimeKey {
+                stop_index: dwell_time.stop_index,
+                time_slot: time_slot(dwell_time.time_slot)?,
+            }, dwell_time.curve_set.to_curve_set_data()))).collect::<FnResult<_>>()?;
+            let rvdata = RouteVariantData {
+                stop_ids: variant.stop_ids,
+                general_delay: EventPair {
+                    arrival: general_delay(&variant.general_delay.arrival),
+                    departure: general_delay(&variant.general_delay.departure),
+                },
+                curve_sets: EventPair {
+                    arrival: curve_sets(&variant.curve_sets.arrival)?,
+                    departure: curve_sets(&variant.curve_sets.departure)?,
+                },
+                dwell_times,
+            };
+            let route_data = specific.entry(variant.route_id.clone()).or_insert_with(|| RouteData::new(&variant.route_id));
+            route_data.variants.insert(variant.route_variant, rvdata);
+        }
+        for merged in self.merged_variants {
+            let route_data = specific.entry(merged.route_id.clone()).or_insert_with(|| RouteData::new(&merged.route_id));
+            route_data.merged_variants.insert(merged.route_variant, merged.merged_into);
+        }
+
+        let mut all_default_curves = HashMap::new();
+        for default_curve in &self.default_curves {
+            all_default_curves.insert(DefaultCurveKey {
+                route_type: route_type_from_code(default_curve.route_type),
+                route_section: RouteSection::from_name(&default_curve.route_section)?,
+                time_slot: time_slot(default_curve.time_slot)?,
+                event_type: parse_event_type(&default_curve.event_type)?,
+            }, default_curve.curve.to_curve_data());
+        }
+        let mut calibration: BTreeMap<String, HashMap<CalibrationKey, CalibrationCounts>> = BTreeMap::new();
+        for entry in &self.calibration {
+            calibration.entry(entry.date.clone()).or_default().insert(CalibrationKey {
+                route_id: entry.route_id.clone(),
+                precision_type: entry.precision_type,
+            }, CalibrationCounts { pit_histogram: entry.pit_histogram });
+        }
+        let mut operation = HashMap::new();
+        for entry in &self.operation {
+            operation.insert(OperationKey { route_id: entry.route_id.clone(), time_slot: time_slot(entry.time_slot)? },
+                OperationCounts { scheduled_trips: entry.scheduled_trips, operated_trips: entry.operated_trips });
+        }
+
+        Ok(DelayStatistics {
+            specific,
+            general: DefaultCurves {
+                all_default_curves,
+                route_sectioning: match &self.route_sectioning {
+                    Some(sectioning) => sectioning.to_route_sectioning()?,
+                    None => RouteSectioning::default(),
+                },
+            },
+            operation,
+            curve_parameters: self.curve_parameters.iter().map(|entry| (entry.route_id.clone(), CurveParameters {
+                simplification_tolerance: entry.simplification_tolerance,
+                min_points_per_marker: entry.min_points_per_marker,
+            })).collect(),
+            outlier_policy: match &self.outlier_policy {
+                Some(policy) => policy.to_outlier_policy()?,
+                None => OutlierPolicy::DEFAULT,
+            },
+            // not part of the portable format
+            feed_quirks: FeedQuirks::DEFAULT,
+            calibration,
+        })
+    }
+
+    pub fn write(&self, writer: &mut impl Write, format: PortableFormat) -> FnResult<()> {
+        match format {
+            PortableFormat::Json => serde_json::to_writer_pretty(writer, self)?,
+            PortableFormat::Binary => {
+                writer.write_all(PORTABLE_MAGIC)?;
+                writer.write_all(&self.format_version.to_be_bytes())?;
+                writer.write_all(&rmp_serde::to_vec_named(self)?)?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Reads statistics in either format. The format is recognized by the header of the binary format.
+    pub fn read(reader: &mut impl Read) -> FnResult<Self> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+
+        let statistics: Self = if data.starts_with(PORTABLE_MAGIC) {
+            let header_length = PORTABLE_MAGIC.len() + 2;
+            if data.len() < header_length {
+                bail!("Incomplete header.");
+            }
+            let version = u16::from_be_bytes([data[PORTABLE_MAGIC.len()], data[PORTABLE_MAGIC.len() + 1]]);
+            Self::check_version(version)?;
+            rmp_serde::from_read_ref(&data[header_length..])?
+        } else {
+            serde_json::from_slice(&data)?
+        };
+        Self::check_version(statistics.format_version)?;
+        Ok(statistics)
+    }
+
+    fn check_version(version: u16) -> FnResult<()> {
+        if version == 0 || version > PORTABLE_FORMAT_VERSION {
+            bail!("Unsupported format version {}, this version of {} supports versions up to {}.", version, env!("CARGO_PKG_NAME"), PORTABLE_FORMAT_VERSION);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example_statistics() -> DelayStatistics {
+        let mut statistics = DelayStatistics::new();
+        statistics.general.all_default_curves.insert(DefaultCurveKey {
+            route_type: RouteType::Bus,
+            route_section: RouteSection::Middle,
+            time_slot: TimeSlot::DEFAULT,
+            event_type: EventType::Departure,
+        }, CurveData {
+            curve: IrregularDynamicCurve::new(vec![Tup { x: -30.0, y: 0.0 }, Tup { x: 90.0, y: 1.0 }]),
+            precision_type: PrecisionType::General,
+            sample_size: 42,
+            effective_sample_size: None,
+        });
+        let mut route_data = RouteData::new("route 1");
+        let mut general_delay = HashMap::new();
+        general_delay.insert(3, statistics.general.all_default_curves.values().next().unwrap().clone());
+        let mut curve_set = CurveSet::new();
+        curve_set.add_curve(-60.0, IrregularDynamicCurve::new(vec![Tup { x: 0.0, y: 0.0 }, Tup { x: 30.0, y: 1.0 }]));
+        let mut dwell_times = HashMap::new();
+        dwell_times.insert(DwellTimeKey { stop_index: 1, time_slot: TimeSlot::DEFAULT },
+            CurveSetData { curve_set, precision_type: PrecisionType::Specific, sample_size: 25, effective_sample_size: None });
+        route_data.variants.insert(7, RouteVariantData {
+            stop_ids: vec![String::from("a"), String::from("b")],
+            curve_sets: EventPair { arrival: HashMap::new(), departure: HashMap::new() },
+            general_delay: EventPair { arrival: HashMap::new(), departure: general_delay },
+            dwell_times,
+        });
+        route_data.merged_variants.insert(8, 7);
+        statistics.specific.insert(String::from("route 1"), route_data);
+        statistics.operation.insert(OperationKey { route_id: String::from("route 1"), time_slot: TimeSlot::DEFAULT },
+            OperationCounts { scheduled_trips: 10, operated_trips: 9 });
+        statistics.curve_parameters.insert(String::from("route 1"), CurveParameters { simplification_tolerance: 0.005, min_points_per_marker: 40.0 });
+        statistics.general.route_sectioning = RouteSectioning::Distance(3000.0);
+        statistics.outlier_policy = OutlierPolicy { strategy: OutlierStrategy::Mad(4.5), rounding: 1 };
+        for date in &["2020-10-01", "2020-10-02"] {
+            statistics.calibration.entry(String::from(*date)).or_default()
+                .insert(CalibrationKey::new("route 1", &PrecisionType::Specific), CalibrationCounts { pit_histogram: [30; 10] });
+        }
+        statistics
+    }
+
+    fn roundtrip(format: PortableFormat) -> DelayStatistics {
+        let mut data = Vec::new();
+        PortableStatistics::from_delay_statistics(&example_statistics()).write(&mut data, format).unwrap();
+        PortableStatistics::read(&mut data.as_slice()).unwrap().into_delay_statistics().unwrap()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for format in &[PortableFormat::Json, PortableFormat::Binary] {
+            let statistics = roundtrip(*format);
+            assert_eq!(statistics.general.all_default_curves.len(), 1);
+            assert_eq!(statistics.general.all_default_curves.values().next().unwrap().sample_size, 42);
+            let rvdata = &statistics.specific["route 1"].variants[&7];
+            assert_eq!(rvdata.stop_ids, vec!["a", "b"]);
+            assert_eq!(rvdata.general_delay.departure[&3].sample_size, 42);
+            assert!(rvdata.general_delay.arrival.is_empty());
+            assert_eq!(statistics.specific["route 1"].merged_variants[&8], 7);
+            assert!(statistics.get_route_variant_data("route 1", 8).is_some());
+            assert_eq!(rvdata.dwell_times[&DwellTimeKey { stop_index: 1, time_slot: TimeSlot::DEFAULT }].sample_size, 25);
+            assert_eq!(statistics.get_operation_probability("route 1", &TimeSlot::DEFAULT), 1.0); // too few trips
+            assert_eq!(statistics.operation.values().next().unwrap().operated_trips, 9);
+            assert_eq!(statistics.get_curve_parameters("route 1").min_points_per_marker, 40.0);
+            assert_eq!(statistics.get_curve_parameters("route 2"), CurveParameters::DEFAULT);
+            assert_eq!(statistics.general.route_sectioning, RouteSectioning::Distance(3000.0));
+            assert_eq!(statistics.outlier_policy, OutlierPolicy { strategy: OutlierStrategy::Mad(4.5), rounding: 1 });
+            assert_eq!(statistics.get_calibration("route 1", &PrecisionType::Specific).unwrap().count(), 600);
+            assert!(statistics.get_calibration("route 1", &PrecisionType::General).is_none());
+        }
+    }
+
+    #[test]
+    fn test_stable_representation() {
+        let mut statistics = example_statistics();
+        let mut stops = HashMap::new();
+        stops.insert(String::from("a"), vec![ListedSection { route_id: Some(String::from("route 1")), section: RouteSection::End }]);
+        statistics.general.route_sectioning = RouteSectioning::StopList(stops);
+        let portable = PortableStatistics::from_delay_statistics(&statistics);
+        let curve = &portable.default_curves[0];
+        assert_eq!((curve.route_type, curve.route_section.as_str(), curve.time_slot, curve.event_type.as_str()), (3, "middle", TimeSlot::DEFAULT.id, "departure"));
+        assert_eq!(curve.curve.points, vec![CurvePoint { delay: -30.0, probability: 0.0 }, CurvePoint { delay: 90.0, probability: 1.0 }]);
+        assert_eq!(portable.outlier_policy.as_ref().unwrap().mad_factor, Some(4.5));
+
+        let json = serde_json::to_string(&portable).unwrap();
+        let statistics = serde_json::from_str::<PortableStatistics>(&json).unwrap().into_delay_statistics().unwrap();
+        assert_eq!(statistics.general.route_sectioning, RouteSectioning::StopList(
+            vec![(String::from("a"), vec![ListedSection { route_id: Some(String::from("route 1")), section: RouteSection::End }])].into_iter().collect()));
+    }
+
+    #[test]
+    fn test_invalid_entries() {
+        let mut portable = PortableStatistics::from_delay_statistics(&example_statistics());
+        portable.operation[0].time_slot = 200;
+        assert!(portable.into_delay_statistics().is_err());
+
+        let mut portable = PortableStatistics::from_delay_statistics(&example_statistics());
+        portable.default_curves[0].route_section = String::from("somewhere");
+        assert!(portable.into_delay_statistics().is_err());
+
+        let mut portable = PortableStatistics::from_delay_statistics(&example_statistics());
+        portable.route_sectioning = None;
+        portable.outlier_policy = None;
+        let statistics = portable.into_delay_statistics().unwrap();
+        assert_eq!(statistics.general.route_sectioning, RouteSectioning::StopCount);
+        assert_eq!(statistics.outlier_policy, OutlierPolicy::DEFAULT);
+    }
+
+    #[test]
+    fn test_version_check() {
+        let mut data = Vec::new();
+        data.extend_from_slice(PORTABLE_MAGIC);
+        data.extend_from_slice(&(PORTABLE_FORMAT_VERSION + 1).to_be_bytes());
+        assert!(PortableStatistics::read(&mut data.as_slice()).is_err());
+        assert!(PortableStatistics::read(&mut &b"DYSTATS\0\x00"[..]).is_err());
+    }
+}
@@ -3,6 +3,7 @@ use gtfs_rt::{StopTimeEventExtension, PredictionType};
 use itertools::multizip;
 use std::fmt::{Debug, Display, Formatter};
 use crate::types::{CurveData, CurveSetData};
+use serde::Serialize;
 
 /*
 pub enum PredictionResult {
@@ -13,7 +14,7 @@ pub enum PredictionResult {
 }
 */
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub enum PredictionResult {
     CurveData(CurveData),
     CurveSetData(CurveSetData),
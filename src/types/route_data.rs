@@ -13,7 +13,11 @@ use simple_error::bail;
 #[derive(Serialize, Deserialize, Clone)]
 pub struct RouteData {
     pub route_id: String,
-    pub variants: HashMap<u64, RouteVariantData>
+    pub variants: HashMap<u64, RouteVariantData>,
+    /// route variants whose records have been pooled into the curves of another
+    /// (near-identical) route variant, mapped to that route variant
+    #[serde(default)]
+    pub merged_variants: HashMap<u64, u64>,
 }
 
 impl RouteData {
@@ -22,9 +26,16 @@ impl RouteData {
     pub fn new(route_id: &str) -> Self {
         return Self {
             route_id: String::from(route_id),
-            variants: HashMap::new()
+            variants: HashMap::new(),
+            merged_variants: HashMap::new(),
         };
     }
+
+    /// Returns the curves of the route variant, or those of the route variant into which it has been merged.
+    pub fn get_variant(&self, route_variant: u64) -> Option<&RouteVariantData> {
+        let route_variant = self.merged_variants.get(&route_variant).unwrap_or(&route_variant);
+        self.variants.get(route_variant)
+    }
 }
 
 impl TreeData for RouteData {
@@ -1,6 +1,13 @@
+use clap::ArgMatches;
+use geo::prelude::*;
+use geo::point;
 use gtfs_structures::{Gtfs, Trip};
 use serde::{Serialize, Deserialize};
-use crate::FnResult;
+use simple_error::bail;
+use std::collections::HashMap;
+use std::fs;
+
+use crate::{FnResult, OrError};
 
 /// Route sections are sets of stops that form a part of the route (beginning, middle, or end)
 #[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Serialize, Deserialize, Clone)]
@@ -11,25 +18,13 @@ pub enum RouteSection {
 }
 
 impl RouteSection {
-    pub fn get_route_section_by_stop_sequence(schedule: &Gtfs, trip_id: &str, stop_sequence: u16) -> FnResult<RouteSection> {
-        // check if trip_id is valid for the given schedule
-        // and get the right trip object
-        let trip = schedule.get_trip(&trip_id).unwrap(); //panics if trip is not found!
-
-        // Find the index of the stop in question
-        let stop_index = trip.get_stop_index_by_stop_sequence(stop_sequence)?;
-        return Self::get_route_section_by_stop_index(&trip, stop_index);
-    }
-
-    #[allow(dead_code)]
-    pub fn get_route_section_by_stop_id(schedule: &Gtfs, trip_id: &str, stop_id: &str) -> FnResult<RouteSection> {
-        // check if trip_id is valid for the given schedule
-        // and get the right trip object
-        let trip = schedule.get_trip(&trip_id).unwrap(); //panics if trip is not found!
-
-        // Find the index of the stop in question
-        let stop_index = trip.get_stop_index_by_id(stop_id)?;
-        return Self::get_route_section_by_stop_index(&trip, stop_index);
+    pub fn from_name(name: &str) -> FnResult<RouteSection> {
+        match name.trim().to_lowercase().as_str() {
+            "beginning" => Ok(RouteSection::Beginning),
+            "middle" => Ok(RouteSection::Middle),
+            "end" => Ok(RouteSection::End),
+            _ => bail!("Unknown route section {}, must be beginning, middle or end.", name),
+        }
     }
 
     // this finds out for a given stop, in which section of a route it is.
@@ -50,4 +45,188 @@ impl RouteSection {
         }
         return Ok(RouteSection::Middle);
     }
-}
\ No newline at end of file
+}
+
+/// An entry of `RouteSectioning::StopList`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ListedSection {
+    /// if given, the entry only applies to trips of this route
+    pub route_id: Option<String>,
+    pub section: RouteSection,
+}
+
+/// Decides which stops of a trip belong to which route section. The default curves are computed
+/// per route section, so they are stored together with the sectioning that was used to compute
+/// them, and predictions use the same sectioning.
+///
+/// If a strategy can't be applied to a trip (e.g. because its stops have no fare zones), the
+/// trip is divided by `StopCount` instead.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum RouteSectioning {
+    /// beginning and end are the first and last third of the stops, but at most 5 stops each
+    StopCount,
+    /// beginning and end are the stops within this distance (in meters) from the first or last stop,
+    /// measured from stop to stop, but at most a third of the length of the trip each
+    Distance(f64),
+    /// beginning and end are the stops in the fare zone of the first or last stop
+    FareZone,
+    /// sections given explicitly by stop id, regardless of the direction of the trip. Stops which
+    /// are not listed are divided by `StopCount`.
+    StopList(HashMap<String, Vec<ListedSection>>),
+}
+
+impl Default for RouteSectioning {
+    fn default() -> Self {
+        RouteSectioning::StopCount
+    }
+}
+
+impl RouteSectioning {
+    pub const NAMES: [&'static str; 4] = ["stop-count", "distance", "fare-zone", "stop-list"];
+
+    /// Creates the sectioning that is configured by the `route-sections`, `section-length`
+    /// and `section-stops` arguments.
+    pub fn from_args(args: &ArgMatches) -> FnResult<Self> {
+        Ok(match args.value_of("route-sections").unwrap_or("stop-count") {
+            "stop-count" => RouteSectioning::StopCount,
+            "distance" => {
+                let length: f64 = args.value_of("section-length").or_error("Argument section-length is missing.")?.parse()?;
+                if length <= 0.0 {
+                    bail!("Section length must be positive.");
+                }
+                RouteSectioning::Distance(length)
+            },
+            "fare-zone" => RouteSectioning::FareZone,
+            "stop-list" => {
+                let filename = args.value_of("section-stops").or_error("Route sections by stop list need the argument section-stops.")?;
+                let stops = read_section_stops(&fs::read_to_string(filename)?)?;
+                info!("Read route sections of {} stops from {}.", stops.len(), filename);
+                RouteSectioning::StopList(stops)
+            },
+            other => bail!("Unknown route sectioning {}.", other),
+        })
+    }
+
+    pub fn get_route_section_by_stop_sequence(&self, schedule: &Gtfs, trip_id: &str, stop_sequence: u16) -> FnResult<RouteSection> {
+        let trip = schedule.get_trip(&trip_id)?;
+        let stop_index = trip.get_stop_index_by_stop_sequence(stop_sequence)?;
+        self.get_route_section(&trip, stop_index)
+    }
+
+    /// Finds out for a given stop, in which section of the route it is.
+    pub fn get_route_section(&self, trip: &Trip, stop_index: usize) -> FnResult<RouteSection> {
+        let stop = &trip.stop_times.get(stop_index).or_error("Stop index out of range")?.stop;
+        let section = match self {
+            RouteSectioning::StopCount => None,
+            RouteSectioning::Distance(length) => Self::get_route_section_by_distance(trip, stop_index, *length),
+            RouteSectioning::FareZone => {
+                let first_zone = trip.stop_times.first().and_then(|st| st.stop.zone_id.as_ref());
+                let last_zone = trip.stop_times.last().and_then(|st| st.stop.zone_id.as_ref());
+                match (first_zone, last_zone, stop.zone_id.as_ref()) {
+                    // if the trip does not leave its zone, the zones can't tell anything
+                    (Some(first_zone), Some(last_zone), Some(zone)) if first_zone != last_zone => {
+                        Some(if zone == first_zone {
+                            RouteSection::Beginning
+                        } else if zone == last_zone {
+                            RouteSection::End
+                        } else {
+                            RouteSection::Middle
+                        })
+                    },
+                    _ => None,
+                }
+            },
+            RouteSectioning::StopList(stops) => {
+                stops.get(&stop.id).and_then(|entries| {
+                    // entries for the route take precedence over entries for all routes
+                    entries.iter().find(|e| e.route_id.as_ref() == Some(&trip.route_id))
+                        .or_else(|| entries.iter().find(|e| e.route_id.is_none()))
+                        .map(|e| e.section.clone())
+                })
+            },
+        };
+
+        match section {
+            Some(section) => Ok(section),
+            None => RouteSection::get_route_section_by_stop_index(trip, stop_index),
+        }
+    }
+
+    // returns None if the location of any stop of the trip is unknown
+    fn get_route_section_by_distance(trip: &Trip, stop_index: usize, length: f64) -> Option<RouteSection> {
+        let mut distances = Vec::with_capacity(trip.stop_times.len());
+        let mut total = 0.0;
+        let mut previous = None;
+        for st in &trip.stop_times {
+            let location = point!(x: st.stop.longitude?, y: st.stop.latitude?);
+            if let Some(previous) = previous {
+                total += location.haversine_distance(&previous);
+            }
+            distances.push(total);
+            previous = Some(location);
+        }
+
+        let section_length = f64::min(length, total / 3.0);
+        Some(if distances[stop_index] < section_length {
+            RouteSection::Beginning
+        } else if total - distances[stop_index] < section_length {
+            RouteSection::End
+        } else {
+            RouteSection::Middle
+        })
+    }
+}
+
+/// Reads the content of a CSV file with the columns `stop_id`, `section` (`beginning`, `middle` or
+/// `end`) and optionally `route_id`. A header line is optional. A stop may be listed several times
+/// for different routes.
+fn read_section_stops(content: &str) -> FnResult<HashMap<String, Vec<ListedSection>>> {
+    let mut reader = csv::ReaderBuilder::new().has_headers(false).flexible(true).trim(csv::Trim::All).from_reader(content.as_bytes());
+    let mut stops: HashMap<String, Vec<ListedSection>> = HashMap::new();
+    for (index, record) in reader.records().enumerate() {
+        let record = record?;
+        if index == 0 && record.get(0) == Some("stop_id") {
+            continue;
+        }
+        let line = record.position().map_or(index as u64 + 1, |position| position.line());
+        match (record.get(0), record.get(1)) {
+            (Some(stop_id), Some(section)) => {
+                let route_id = record.get(2).filter(|r| !r.is_empty()).map(String::from);
+                stops.entry(String::from(stop_id)).or_default().push(ListedSection {
+                    route_id,
+                    section: RouteSection::from_name(section)?,
+                });
+            },
+            _ => bail!("Line {} has less than two columns.", line),
+        }
+    }
+    Ok(stops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_section_stops() {
+        let content = "stop_id,section,route_id\n\
+            1000,beginning\n\
+            1000, End ,route 1\n\
+            \n\
+            2000,middle,\n";
+        let stops = read_section_stops(content).unwrap();
+        assert_eq!(stops.len(), 2);
+        assert_eq!(stops["1000"], vec![
+            ListedSection { route_id: None, section: RouteSection::Beginning },
+            ListedSection { route_id: Some(String::from("route 1")), section: RouteSection::End },
+        ]);
+        assert_eq!(stops["2000"][0].route_id, None);
+
+        assert!(read_section_stops("1000,somewhere").is_err());
+        assert!(read_section_stops("1000").is_err());
+
+        // quoted fields may contain commas
+        let stops = read_section_stops("1000,end,\"route 1, night\"\n").unwrap();
+        assert_eq!(stops["1000"][0].route_id, Some(String::from("route 1, night")));
+    }
+}
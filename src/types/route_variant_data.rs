@@ -1,11 +1,14 @@
 use std::collections::HashMap;
 
+use gtfs_structures::Trip;
 use serde::{Serialize, Deserialize};
 
+use dystonse_curves::Curve;
+use dystonse_curves::irregular_dynamic::IrregularDynamicCurve;
 use dystonse_curves::tree::{SerdeFormat, TreeData, NodeData};
 
 use crate::{FnResult};
-use super::{TimeSlot, CurveSetData, CurveData, EventPair, EventType};
+use super::{TimeSlot, CurveSetData, CurveData, EventPair, EventType, WeatherCondition, MIXTURE_QUANTILES};
 
 use simple_error::bail;
 
@@ -13,7 +16,17 @@ use simple_error::bail;
 pub struct CurveSetKey {
     pub start_stop_index: u32,
     pub end_stop_index: u32,
-    pub time_slot: TimeSlot
+    pub time_slot: TimeSlot,
+    /// Unknown for curve sets that are valid for any weather
+    #[serde(default)]
+    pub weather: WeatherCondition,
+}
+
+/// Identifies the dwell time curve set of one stop of a route variant.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Hash, Debug, Clone)]
+pub struct DwellTimeKey {
+    pub stop_index: u32,
+    pub time_slot: TimeSlot,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -21,6 +34,9 @@ pub struct RouteVariantData {
     pub stop_ids: Vec<String>,
     pub curve_sets: EventPair<HashMap<CurveSetKey, CurveSetData>>,
     pub general_delay: EventPair<HashMap<u32, CurveData>>,
+    /// curve sets of the departure delay at a stop, depending on the arrival delay at the same stop
+    #[serde(default)]
+    pub dwell_times: HashMap<DwellTimeKey, CurveSetData>,
 }
 
 impl TreeData for RouteVariantData {
@@ -32,12 +48,20 @@ impl TreeData for RouteVariantData {
             self.general_delay.save_to_file(dir_name, "general_delay", format)?;
             for et in &EventType::TYPES {
                 for (key, curve_set_data) in &self.curve_sets[**et] {
-                    let sub_dir_name = format!("{}/{}/{}/{:?}", dir_name, own_name, key.time_slot.description, et);
+                    let sub_dir_name = if key.weather == WeatherCondition::Unknown {
+                        format!("{}/{}/{}/{:?}", dir_name, own_name, key.time_slot.description, et)
+                    } else {
+                        format!("{}/{}/{}/{:?}/{:?}", dir_name, own_name, key.time_slot.description, key.weather, et)
+                    };
                     let own_name = format!("from_{}_to_{}", key.start_stop_index, key.end_stop_index);
                     curve_set_data.curve_set.save_tree(&sub_dir_name, &own_name, format, leaves)?;
                     //TODO: this ignores the CurveSetData's meta data, but we don't use it anyway, so we can fix this later.
                 }
             }
+            for (key, curve_set_data) in &self.dwell_times {
+                let sub_dir_name = format!("{}/{}/{}/Dwell", dir_name, own_name, key.time_slot.description);
+                curve_set_data.curve_set.save_tree(&sub_dir_name, &format!("at_{}", key.stop_index), format, leaves)?;
+            }
 
         }
 
@@ -64,7 +88,90 @@ impl RouteVariantData {
             general_delay: EventPair{
                 arrival: HashMap::new(),
                 departure: HashMap::new(),
-            }
+            },
+            dwell_times: HashMap::new(),
         };
     }
+
+    /// Returns the index in `stop_ids` of the stop with the given index in the trip. Both are the same unless
+    /// the curves have been computed for another route variant, into which the one of the trip has been merged.
+    pub fn get_stop_index(&self, trip: &Trip, stop_index: usize) -> Option<u32> {
+        let trip_stop_ids: Vec<&str> = trip.stop_times.iter().map(|st| st.stop.id.as_str()).collect();
+        if stop_index >= trip_stop_ids.len() {
+            return None;
+        }
+        if self.stop_ids.iter().map(|id| id.as_str()).eq(trip_stop_ids.iter().copied()) {
+            return Some(stop_index as u32);
+        }
+        align_stop_ids(&trip_stop_ids, &self.stop_ids)[stop_index].map(|index| index as u32)
+    }
+
+    /// Returns the distribution of the delays at the stop with `end_stop_index` for vehicles that leave the stop
+    /// with `start_stop_index` within the time slot, and the sample size of the curve set it has been taken from.
+    /// The departure delays at the start stop are only known independent of the time of day, so the curves of the
+    /// curve set are mixed over the quantiles of the semi-specific departure curve of the start stop.
+    pub fn get_curve_from_start_stop(&self, start_stop_index: u32, end_stop_index: u32, event_type: EventType, time_slot: &TimeSlot) -> Option<(IrregularDynamicCurve<f32, f32>, u32)> {
+        let start_delay = self.general_delay[EventType::Departure].get(&start_stop_index)?;
+        let curve_set_data = self.curve_sets[event_type].get(&CurveSetKey {
+            start_stop_index,
+            end_stop_index,
+            time_slot: time_slot.clone(),
+            weather: WeatherCondition::Unknown,
+        })?;
+        if curve_set_data.curve_set.curves.is_empty() {
+            return None;
+        }
+        let curves: Vec<IrregularDynamicCurve<f32, f32>> = MIXTURE_QUANTILES.iter()
+            .map(|q| curve_set_data.curve_set.curve_at_x_with_continuation(start_delay.curve.x_at_y(*q)))
+            .collect();
+        let curves: Vec<&IrregularDynamicCurve<f32, f32>> = curves.iter().collect();
+        Some((IrregularDynamicCurve::<f32, f32>::average(&curves), curve_set_data.sample_size))
+    }
+}
+
+/// Aligns two sequences of stop_ids by their longest common subsequence. Returns the index in `b`
+/// of each stop of `a`, or None for the stops that are not part of the common subsequence.
+pub fn align_stop_ids<A: AsRef<str>, B: AsRef<str>>(a: &[A], b: &[B]) -> Vec<Option<usize>> {
+    // lengths[i][j] is the length of the longest common subsequence of a[i..] and b[j..]
+    let mut lengths = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i].as_ref() == b[j].as_ref() {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                usize::max(lengths[i + 1][j], lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut alignment = vec![None; a.len()];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i].as_ref() == b[j].as_ref() {
+            alignment[i] = Some(j);
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_align_stop_ids() {
+        let stop_ids = ["a", "b", "c", "d", "b"];
+        assert_eq!(align_stop_ids(&stop_ids, &stop_ids), vec![Some(0), Some(1), Some(2), Some(3), Some(4)]);
+        // a variant which skips a stop
+        assert_eq!(align_stop_ids(&["a", "c", "d", "b"], &stop_ids), vec![Some(0), Some(2), Some(3), Some(4)]);
+        // a variant with an extra stop
+        assert_eq!(align_stop_ids(&["a", "x", "b", "c"], &stop_ids), vec![Some(0), None, Some(1), Some(2)]);
+        assert_eq!(align_stop_ids::<&str, &str>(&[], &stop_ids), vec![]);
+    }
 }
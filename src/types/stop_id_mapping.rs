@@ -0,0 +1,149 @@
+use geo::prelude::*;
+use geo::point;
+use gtfs_structures::{Gtfs, Stop};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::FnResult;
+
+/// Maps the stop IDs of older schedules to the IDs of the same stops in the current schedule, for agencies
+/// which rename their stop IDs between schedule versions. Without it, the records of the old stop IDs
+/// would not be used for the curves anymore. It is computed by `analyse match-stop-ids` and stored as
+/// CSV file with the columns of `StopIdMatch`.
+#[derive(Debug, Default)]
+pub struct StopIdMapping {
+    pub matches: Vec<StopIdMatch>,
+    new_stop_ids: HashMap<String, String>,
+}
+
+/// A stop of an older schedule and the stop of the newer schedule with the same name, which is closest to it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StopIdMatch {
+    pub old_stop_id: String,
+    pub new_stop_id: String,
+    pub stop_name: String,
+    /// in meters, if both stops have a location
+    pub distance: Option<f32>,
+}
+
+impl StopIdMapping {
+    pub const FILE_NAME: &'static str = "stop_id_mapping.csv";
+
+    pub fn new(matches: Vec<StopIdMatch>) -> Self {
+        let new_stop_ids = matches.iter().map(|m| (m.old_stop_id.clone(), m.new_stop_id.clone())).collect();
+        StopIdMapping { matches, new_stop_ids }
+    }
+
+    /// Loads the mapping from dir, or returns an empty mapping if there is none.
+    pub fn load_from_dir(dir: &str) -> FnResult<Self> {
+        let file_name = format!("{}/{}", dir, Self::FILE_NAME);
+        if !Path::new(&file_name).exists() {
+            return Ok(Self::default());
+        }
+        let mut reader = csv::Reader::from_path(&file_name)?;
+        let matches = reader.deserialize().collect::<Result<Vec<StopIdMatch>, _>>()?;
+        info!("Loaded {} renamed stop IDs from {}.", matches.len(), file_name);
+        Ok(Self::new(matches))
+    }
+
+    pub fn save_to_dir(&self, dir: &str) -> FnResult<()> {
+        let mut writer = csv::Writer::from_path(format!("{}/{}", dir, Self::FILE_NAME))?;
+        for stop_id_match in &self.matches {
+            writer.serialize(stop_id_match)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.matches.is_empty()
+    }
+
+    /// Returns the stop ID of the current schedule for a stop ID of an older schedule. Stop IDs which
+    /// haven't been renamed are returned unchanged.
+    pub fn map<'s>(&'s self, stop_id: &'s str) -> &'s str {
+        self.new_stop_ids.get(stop_id).map_or(stop_id, |new_stop_id| new_stop_id.as_str())
+    }
+
+    /// Matches the stops which only exist in the `old` schedule with the stops which only exist in the `new` one,
+    /// by their name and location. Of the new stops with the same name, the closest one is used if it is at most
+    /// `max_distance` meters away. Stops without location are only matched if there is exactly one candidate.
+    pub fn match_schedules(old: &Gtfs, new: &Gtfs, max_distance: f32) -> Self {
+        let mut candidates_by_name: HashMap<&str, Vec<&Stop>> = HashMap::new();
+        for stop in new.stops.values().filter(|stop| !old.stops.contains_key(&stop.id)) {
+            candidates_by_name.entry(stop.name.as_str()).or_default().push(stop);
+        }
+
+        let mut matches: Vec<StopIdMatch> = old.stops.values()
+            .filter(|stop| !new.stops.contains_key(&stop.id))
+            .filter_map(|stop| {
+                let candidates = candidates_by_name.get(stop.name.as_str())?;
+                let locations: Vec<Option<(f64, f64)>> = candidates.iter().map(|candidate| get_location(candidate)).collect();
+                let (index, distance) = find_closest(get_location(stop), &locations, max_distance)?;
+                Some(StopIdMatch {
+                    old_stop_id: stop.id.clone(),
+                    new_stop_id: candidates[index].id.clone(),
+                    stop_name: stop.name.clone(),
+                    distance,
+                })
+            })
+            .collect();
+        matches.sort_by(|a, b| a.old_stop_id.cmp(&b.old_stop_id));
+        Self::new(matches)
+    }
+}
+
+fn get_location(stop: &Stop) -> Option<(f64, f64)> {
+    Some((stop.latitude?, stop.longitude?))
+}
+
+// index of the closest candidate within max_distance, and its distance if it's known
+fn find_closest(location: Option<(f64, f64)>, candidates: &[Option<(f64, f64)>], max_distance: f32) -> Option<(usize, Option<f32>)> {
+    let location = match location {
+        Some(location) => location,
+        None if candidates.len() == 1 => return Some((0, None)),
+        None => return None,
+    };
+    let distances = candidates.iter().enumerate().filter_map(|(index, candidate)| {
+        let (lat, lon) = (*candidate)?;
+        let distance = point!(x: location.1, y: location.0).haversine_distance(&point!(x: lon, y: lat)) as f32;
+        Some((index, distance))
+    });
+    match distances.fold(None, |closest: Option<(usize, f32)>, (index, distance)| match closest {
+        Some((_, closest_distance)) if closest_distance <= distance => closest,
+        _ => Some((index, distance)),
+    }) {
+        Some((index, distance)) if distance <= max_distance => Some((index, Some(distance))),
+        Some(_) => None,
+        None if candidates.len() == 1 => Some((0, None)),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_closest() {
+        let location = Some((53.0760, 8.8070));
+        // about 11 m and 111 m north of the location
+        let near = Some((53.0761, 8.8070));
+        let far = Some((53.0770, 8.8070));
+        assert_eq!(find_closest(location, &[far, near], 50.0).map(|(index, _)| index), Some(1));
+        assert_eq!(find_closest(location, &[far], 50.0), None);
+        assert_eq!(find_closest(location, &[None], 50.0), Some((0, None)));
+        assert_eq!(find_closest(None, &[near], 50.0), Some((0, None)));
+        assert_eq!(find_closest(None, &[near, far], 50.0), None);
+
+        let mapping = StopIdMapping::new(vec![StopIdMatch {
+            old_stop_id: String::from("000100"),
+            new_stop_id: String::from("de:04011:100"),
+            stop_name: String::from("Domsheide"),
+            distance: Some(3.5),
+        }]);
+        assert_eq!(mapping.map("000100"), "de:04011:100");
+        assert_eq!(mapping.map("000200"), "000200");
+    }
+}
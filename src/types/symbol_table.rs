@@ -0,0 +1,254 @@
+use gtfs_structures::Gtfs;
+use mysql::{FromValueError, Value};
+use mysql::prelude::{ConvIr, FromValue};
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Deref;
+use std::iter;
+use std::sync::{Arc, Mutex, RwLock};
+
+lazy_static! {
+    static ref SYMBOLS: SymbolTable = SymbolTable::new();
+}
+
+/// An interned ID of a trip, stop or route. All `Id`s with the same text share one allocation, so
+/// cloning them is cheap, and the many records and predictions of the same trip or stop don't keep
+/// their own copies of its ID. It can be used like a `&str`, and compared with strings directly.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Id(Arc<str>);
+
+impl Id {
+    /// Returns the interned ID for `text`, from the global symbol table.
+    pub fn new(text: &str) -> Self {
+        SYMBOLS.intern(text)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for Id {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for Id {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Id {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&*self.0, f)
+    }
+}
+
+impl fmt::Debug for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl From<&str> for Id {
+    fn from(text: &str) -> Self {
+        Id::new(text)
+    }
+}
+
+impl From<&String> for Id {
+    fn from(text: &String) -> Self {
+        Id::new(text)
+    }
+}
+
+impl PartialEq<str> for Id {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Id {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.0 == *other
+    }
+}
+
+impl PartialEq<String> for Id {
+    fn eq(&self, other: &String) -> bool {
+        &*self.0 == other.as_str()
+    }
+}
+
+impl PartialEq<Id> for String {
+    fn eq(&self, other: &Id) -> bool {
+        self.as_str() == &*other.0
+    }
+}
+
+impl From<Id> for Value {
+    fn from(id: Id) -> Self {
+        Value::Bytes(id.as_bytes().to_vec())
+    }
+}
+
+/// Interns the IDs right from the bytes of the database rows, without an intermediate `String`.
+pub struct IdIr {
+    id: Id,
+    value: Value,
+}
+
+impl ConvIr<Id> for IdIr {
+    fn new(value: Value) -> std::result::Result<IdIr, FromValueError> {
+        match value {
+            Value::Bytes(bytes) => match std::str::from_utf8(&bytes) {
+                Ok(text) => Ok(IdIr { id: Id::new(text), value: Value::Bytes(bytes) }),
+                Err(_) => Err(FromValueError(Value::Bytes(bytes))),
+            },
+            value => Err(FromValueError(value)),
+        }
+    }
+
+    fn commit(self) -> Id {
+        self.id
+    }
+
+    fn rollback(self) -> Value {
+        self.value
+    }
+}
+
+impl FromValue for Id {
+    type Intermediate = IdIr;
+}
+
+/// The set of all interned IDs. There is one global table, which is filled with the IDs of each
+/// schedule when it is loaded. IDs that are not in the schedule (e.g. from old records) are added
+/// when they are first used.
+pub struct SymbolTable {
+    ids: RwLock<HashSet<Arc<str>>>,
+    /// the IDs of the current schedule, which are kept even while nothing else uses them
+    schedule_ids: Mutex<Vec<Id>>,
+}
+
+impl SymbolTable {
+    fn new() -> Self {
+        SymbolTable { ids: RwLock::new(HashSet::new()), schedule_ids: Mutex::new(Vec::new()) }
+    }
+
+    pub fn global() -> &'static SymbolTable {
+        &SYMBOLS
+    }
+
+    pub fn intern(&self, text: &str) -> Id {
+        if let Some(id) = self.ids.read().unwrap().get(text) {
+            return Id(id.clone());
+        }
+        // another thread may have added it in the meantime
+        Id(get_or_insert(&mut self.ids.write().unwrap(), text))
+    }
+
+    /// Adds the IDs of all trips, stops and routes of the schedule, and keeps them until the next schedule
+    /// is added. IDs of previous schedules which are not used anymore are removed, so that the table doesn't
+    /// grow with each new schedule.
+    pub fn add_schedule(&self, schedule: &Gtfs) {
+        let texts = schedule.trips.values()
+            .flat_map(|trip| iter::once(&trip.id).chain(iter::once(&trip.route_id)))
+            .chain(schedule.stops.keys())
+            .chain(schedule.routes.keys());
+        let schedule_ids: Vec<Id> = {
+            // one lock for the whole schedule instead of one for each ID
+            let mut ids = self.ids.write().unwrap();
+            texts.map(|text| Id(get_or_insert(&mut ids, text))).collect()
+        };
+        // this releases the IDs of the previous schedule
+        *self.schedule_ids.lock().unwrap() = schedule_ids;
+        self.remove_unused();
+        debug!("Symbol table contains {} IDs after adding the schedule.", self.len());
+    }
+
+    /// Removes the IDs which are only referenced by the table itself.
+    pub fn remove_unused(&self) {
+        self.ids.write().unwrap().retain(|id| Arc::strong_count(id) > 1);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.read().unwrap().len()
+    }
+}
+
+fn get_or_insert(ids: &mut HashSet<Arc<str>>, text: &str) -> Arc<str> {
+    if let Some(id) = ids.get(text) {
+        return id.clone();
+    }
+    let id: Arc<str> = Arc::from(text);
+    ids.insert(id.clone());
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gtfs_structures::{Stop, Trip};
+
+    #[test]
+    fn test_interning() {
+        let table = SymbolTable::new();
+        let a = table.intern("trip 1");
+        let b = table.intern(&String::from("trip 1"));
+        assert!(Arc::ptr_eq(&a.0, &b.0));
+        assert_eq!(a, "trip 1");
+        assert_eq!(a, String::from("trip 1"));
+        assert_eq!(format!("{}", a), "trip 1");
+        assert_eq!(table.len(), 1);
+
+        let c = table.intern("stop 1");
+        assert_ne!(a, c);
+        drop(c);
+        table.remove_unused();
+        assert_eq!(table.len(), 1);
+        drop(a);
+        drop(b);
+        table.remove_unused();
+        assert_eq!(table.len(), 0);
+    }
+
+    #[test]
+    fn test_add_schedule() {
+        let trip = |id: &str| Trip { id: String::from(id), route_id: String::from("r1"), ..Default::default() };
+        let table = SymbolTable::new();
+        let mut schedule = Gtfs::default();
+        schedule.trips.insert(String::from("t1"), trip("t1"));
+        schedule.stops.insert(String::from("s1"), Arc::new(Stop { id: String::from("s1"), ..Default::default() }));
+        table.add_schedule(&schedule);
+
+        // the IDs of the schedule are kept, although nothing else uses them yet
+        table.remove_unused();
+        assert_eq!(table.len(), 3);
+        let trip_id = table.intern("t1");
+        assert!(Arc::ptr_eq(&trip_id.0, &table.intern("t1").0));
+        drop(trip_id);
+
+        // with the next schedule, only the IDs of the previous one that are still used are kept
+        let stop_id = table.intern("s1");
+        let mut next_schedule = Gtfs::default();
+        next_schedule.trips.insert(String::from("t2"), trip("t2"));
+        table.add_schedule(&next_schedule);
+        let ids = table.ids.read().unwrap();
+        assert!(ids.contains("t2") && ids.contains("r1") && ids.contains("s1"));
+        assert!(!ids.contains("t1"));
+        assert_eq!(ids.len(), 3);
+        drop(stop_id);
+    }
+}
@@ -2,7 +2,7 @@ use chrono::{Weekday, Datelike, Timelike, DateTime, Local};
 use serde::{Serialize, Deserialize};
 use gtfs_structures::Trip;
 use crate::types::{
-    EventType, DbItem
+    EventType, DbItem, DayType, HolidayCalendar
 };
 use std::fmt::{Display, Formatter};
 use std::cmp::Ordering;
@@ -10,7 +10,9 @@ use std::hash::{Hash, Hasher};
 
 /// Time slots are specific ranges in time that occur repeatedly. 
 /// Any DateTime should be able to be mapped to exactly one TimeSlot constant.
-/// TimeSlots are defined by: id, description, weekday and hour criteria
+/// TimeSlots are defined by: id, description, weekday, hour and day type criteria.
+/// The day type is only known if a HolidayCalendar is configured, otherwise every day
+/// is a regular day and the holiday time slots are never used.
 
 #[derive(Eq, Debug, Serialize, Deserialize, Clone)]
 pub struct TimeSlot {
@@ -21,6 +23,8 @@ pub struct TimeSlot {
     pub max_weekday: Weekday,
     pub min_hour: u32, //including
     pub max_hour: u32, //excluding
+    #[serde(default)]
+    pub day_type: DayType,
 }
 
 impl TimeSlot {
@@ -31,6 +35,7 @@ impl TimeSlot {
         max_weekday: Weekday::Fri,
         min_hour: 4,
         max_hour: 6,
+        day_type: DayType::Regular,
     };
     pub const WORKDAY_MORNING_RUSH : TimeSlot = TimeSlot {
         id: 2, 
@@ -39,6 +44,7 @@ impl TimeSlot {
         max_weekday: Weekday::Fri,
         min_hour: 6,
         max_hour: 8,
+        day_type: DayType::Regular,
     };
     pub const WORKDAY_LATE_MORNING : TimeSlot = TimeSlot {
         id: 3, 
@@ -47,6 +53,7 @@ impl TimeSlot {
         max_weekday: Weekday::Fri,
         min_hour: 8,
         max_hour: 12,
+        day_type: DayType::Regular,
     };
     pub const WORKDAY_NOON_RUSH : TimeSlot = TimeSlot {
         id: 4, 
@@ -55,6 +62,7 @@ impl TimeSlot {
         max_weekday: Weekday::Fri,
         min_hour: 12,
         max_hour: 14,
+        day_type: DayType::Regular,
     };
     pub const WORKDAY_AFTERNOON : TimeSlot = TimeSlot {
         id: 5, 
@@ -63,6 +71,7 @@ impl TimeSlot {
         max_weekday: Weekday::Fri,
         min_hour: 14,
         max_hour: 16,
+        day_type: DayType::Regular,
     };
     pub const WORKDAY_AFTERNOON_RUSH : TimeSlot = TimeSlot {
         id: 6, 
@@ -71,6 +80,7 @@ impl TimeSlot {
         max_weekday: Weekday::Fri,
         min_hour: 16,
         max_hour: 18,
+        day_type: DayType::Regular,
     };
     pub const WORKDAY_EVENING : TimeSlot = TimeSlot {
         id: 7, 
@@ -79,6 +89,7 @@ impl TimeSlot {
         max_weekday: Weekday::Fri,
         min_hour: 18,
         max_hour: 20,
+        day_type: DayType::Regular,
     };
     pub const SATURDAY_DAY : TimeSlot = TimeSlot {
         id: 8, 
@@ -87,6 +98,7 @@ impl TimeSlot {
         max_weekday: Weekday::Sat,
         min_hour: 4,
         max_hour: 20,
+        day_type: DayType::Regular,
     };
     pub const SUNDAY_DAY : TimeSlot = TimeSlot {
         id: 9, 
@@ -95,6 +107,7 @@ impl TimeSlot {
         max_weekday: Weekday::Sun,
         min_hour: 4,
         max_hour: 20,
+        day_type: DayType::Regular,
     };
     pub const NIGHT_BEFORE_WORKDAY : TimeSlot = TimeSlot {
         id: 10, 
@@ -103,6 +116,7 @@ impl TimeSlot {
         max_weekday: Weekday::Thu,
         min_hour: 20,
         max_hour: 4,
+        day_type: DayType::Any,
     };
     pub const NIGHT_BEFORE_WEEKEND_DAY : TimeSlot = TimeSlot {
         id: 11, 
@@ -111,6 +125,44 @@ impl TimeSlot {
         max_weekday: Weekday::Sat,
         min_hour: 20,
         max_hour: 4,
+        day_type: DayType::Any,
+    };
+
+    pub const PUBLIC_HOLIDAY_DAY : TimeSlot = TimeSlot {
+        id: 13,
+        description: "Public holidays from 4 to 20h",
+        min_weekday: Weekday::Mon,
+        max_weekday: Weekday::Sun,
+        min_hour: 4,
+        max_hour: 20,
+        day_type: DayType::PublicHoliday,
+    };
+    pub const SCHOOL_HOLIDAY_MORNING : TimeSlot = TimeSlot {
+        id: 14,
+        description: "Workdays in school holidays from 4 to 9h",
+        min_weekday: Weekday::Mon,
+        max_weekday: Weekday::Fri,
+        min_hour: 4,
+        max_hour: 9,
+        day_type: DayType::SchoolHoliday,
+    };
+    pub const SCHOOL_HOLIDAY_MIDDAY : TimeSlot = TimeSlot {
+        id: 15,
+        description: "Workdays in school holidays from 9 to 15h",
+        min_weekday: Weekday::Mon,
+        max_weekday: Weekday::Fri,
+        min_hour: 9,
+        max_hour: 15,
+        day_type: DayType::SchoolHoliday,
+    };
+    pub const SCHOOL_HOLIDAY_AFTERNOON : TimeSlot = TimeSlot {
+        id: 16,
+        description: "Workdays in school holidays from 15 to 20h",
+        min_weekday: Weekday::Mon,
+        max_weekday: Weekday::Fri,
+        min_hour: 15,
+        max_hour: 20,
+        day_type: DayType::SchoolHoliday,
     };
 
     pub const DEFAULT : TimeSlot = TimeSlot {
@@ -120,9 +172,10 @@ impl TimeSlot {
         max_weekday: Weekday::Sun,
         min_hour: 0,
         max_hour: 24,
+        day_type: DayType::Any,
     };
 
-    pub const TIME_SLOTS : [&'static TimeSlot; 11] = [
+    pub const TIME_SLOTS : [&'static TimeSlot; 15] = [
         &Self::WORKDAY_MORNING, 
         &Self::WORKDAY_MORNING_RUSH, 
         &Self::WORKDAY_LATE_MORNING,
@@ -133,10 +186,14 @@ impl TimeSlot {
         &Self::SATURDAY_DAY,
         &Self::SUNDAY_DAY,
         &Self::NIGHT_BEFORE_WORKDAY,
-        &Self::NIGHT_BEFORE_WEEKEND_DAY
+        &Self::NIGHT_BEFORE_WEEKEND_DAY,
+        &Self::PUBLIC_HOLIDAY_DAY,
+        &Self::SCHOOL_HOLIDAY_MORNING,
+        &Self::SCHOOL_HOLIDAY_MIDDAY,
+        &Self::SCHOOL_HOLIDAY_AFTERNOON
         ];
 
-    pub const TIME_SLOTS_WITH_DEFAULT : [&'static TimeSlot; 12] = [
+    pub const TIME_SLOTS_WITH_DEFAULT : [&'static TimeSlot; 16] = [
         &Self::WORKDAY_MORNING, 
         &Self::WORKDAY_MORNING_RUSH, 
         &Self::WORKDAY_LATE_MORNING,
@@ -148,15 +205,24 @@ impl TimeSlot {
         &Self::SUNDAY_DAY,
         &Self::NIGHT_BEFORE_WORKDAY,
         &Self::NIGHT_BEFORE_WEEKEND_DAY,
+        &Self::PUBLIC_HOLIDAY_DAY,
+        &Self::SCHOOL_HOLIDAY_MORNING,
+        &Self::SCHOOL_HOLIDAY_MIDDAY,
+        &Self::SCHOOL_HOLIDAY_AFTERNOON,
         &Self::DEFAULT
         ];
 
 
+    /// find the TimeSlot with the given id, e.g. when reading it from the database
+    pub fn from_id(id: u8) -> Option<&'static TimeSlot> {
+        Self::TIME_SLOTS_WITH_DEFAULT.iter().find(|ts| ts.id == id).copied()
+    }
+
     /// find the matching TimeSlot for a given DateTime
-    pub fn from_datetime(dt: DateTime<Local>) -> &'static TimeSlot {
+    pub fn from_datetime(dt: DateTime<Local>, calendar: &HolidayCalendar) -> &'static TimeSlot {
         
         for ts in &Self::TIME_SLOTS {
-            if ts.matches(dt) {
+            if ts.matches(dt, calendar) {
                 return ts;
             }
         } 
@@ -165,8 +231,12 @@ impl TimeSlot {
     }
 
     /// check if a given DateTime fits inside the TimeSlot
-    pub fn matches(&self, dt: DateTime<Local>) -> bool {
+    pub fn matches(&self, dt: DateTime<Local>, calendar: &HolidayCalendar) -> bool {
         
+        if self.day_type != DayType::Any && self.day_type != calendar.get_day_type(dt.date().naive_local()) {
+            return false;
+        }
+
         let mut day = false;
         let mut hour = false;
 
@@ -201,9 +271,9 @@ impl TimeSlot {
     }
 
     #[allow(dead_code)]
-    pub fn matches_item(&self, item: &DbItem, trip: &Trip, et: EventType) -> bool {
+    pub fn matches_item(&self, item: &DbItem, trip: &Trip, et: EventType, calendar: &HolidayCalendar) -> bool {
         if let Some(dt) = item.get_datetime_from_trip(trip, et) {
-            self.matches(dt)
+            self.matches(dt, calendar)
         } else {
             false
         }
@@ -242,4 +312,28 @@ impl PartialEq for TimeSlot {
     fn eq(&self, other: &Self) -> bool {
         self.id == other.id
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    // every point in time must belong to exactly one time slot, with and without holidays
+    #[test]
+    fn test_time_slots_are_disjoint() -> crate::FnResult<()> {
+        let mut calendar = HolidayCalendar::default();
+        calendar.set_state("NI")?;
+        for calendar in &[HolidayCalendar::default(), calendar] {
+            // a week in may, which contains a public holiday on thursday (Christi Himmelfahrt)
+            for day in 18..25 {
+                for hour in 0..24 {
+                    let dt = Local.ymd(2020, 5, day).and_hms(hour, 30, 0);
+                    let matching = TimeSlot::TIME_SLOTS.iter().filter(|ts| ts.matches(dt, calendar)).count();
+                    assert_eq!(matching, 1, "{} matches {} time slots", dt, matching);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,324 @@
+use chrono::{DateTime, Local, TimeZone};
+use clap::ArgMatches;
+use mysql::*;
+use mysql::prelude::*;
+use serde::{Serialize, Deserialize};
+use simple_error::bail;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use crate::FnResult;
+
+// forecasts change, so the conditions from the API are requested again after this time
+const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+// after a failed request, the API is not requested again for this time
+const RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+// conditions of hours that are longer ago than this are removed from the cache
+const MAX_PAST_HOURS: i64 = 48;
+// for connecting to the API and for reading its response, in milliseconds
+const API_TIMEOUT_MS: u64 = 10_000;
+
+/// Weather conditions that are distinguished for delay statistics.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum WeatherCondition {
+    /// the weather is not known, or not relevant (e.g. curve sets that are valid for any weather)
+    Unknown,
+    Dry,
+    Rain,
+    Snow,
+}
+
+impl Default for WeatherCondition {
+    fn default() -> Self {
+        WeatherCondition::Unknown
+    }
+}
+
+impl WeatherCondition {
+    /// all conditions for which separate curve sets can be computed
+    pub const KNOWN_CONDITIONS: [WeatherCondition; 3] = [WeatherCondition::Dry, WeatherCondition::Rain, WeatherCondition::Snow];
+
+    pub fn to_int(&self) -> u8 {
+        match self {
+            Self::Unknown => 0,
+            Self::Dry => 1,
+            Self::Rain => 2,
+            Self::Snow => 3,
+        }
+    }
+
+    pub fn from_int(num: u8) -> Self {
+        match num {
+            1 => Self::Dry,
+            2 => Self::Rain,
+            3 => Self::Snow,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Maps a condition as used by the Bright Sky API (and our CSV files) to one of ours.
+    pub fn from_description(description: &str) -> Self {
+        match description.trim().to_lowercase().as_str() {
+            "dry" | "fog" | "cloudy" | "clear" => Self::Dry,
+            "rain" | "sleet" | "hail" | "thunderstorm" => Self::Rain,
+            "snow" => Self::Snow,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+enum WeatherSource {
+    /// URL of an API that is compatible with the `/weather` endpoint of Bright Sky
+    /// (https://brightsky.dev), including the location, e.g. `https://api.brightsky.dev/weather?lat=53.08&lon=8.81`
+    Api(String),
+    /// weather conditions from a CSV file, sorted by time
+    Csv(BTreeMap<DateTime<Local>, WeatherCondition>),
+}
+
+#[derive(Deserialize)]
+struct ApiResponse {
+    weather: Vec<ApiWeatherRecord>,
+}
+
+#[derive(Deserialize)]
+struct ApiWeatherRecord {
+    timestamp: String,
+    condition: Option<String>,
+}
+
+#[derive(Default)]
+struct ApiCache {
+    /// conditions by hour (as hours since the epoch), with the time when they were fetched
+    conditions: HashMap<i64, (WeatherCondition, Instant)>,
+    /// failures are not cached, but they keep the API from being requested again right away
+    last_failure: Option<Instant>,
+}
+
+/// Provides the weather condition for any hour, in the past (for recording) or in the future
+/// (as a forecast for predictions). The weather is assumed to be the same in the whole area of a source.
+pub struct WeatherProvider {
+    source: WeatherSource,
+    api_cache: Mutex<ApiCache>,
+}
+
+impl WeatherProvider {
+    /// Creates the provider that is configured by the global `weather-api` or `weather-csv`
+    /// argument, or None if neither is given.
+    pub fn from_args(args: &ArgMatches) -> FnResult<Option<Self>> {
+        let source = if let Some(url) = args.value_of("weather-api") {
+            // fail early if the url is invalid, instead of on each request
+            Url::parse(url)?;
+            WeatherSource::Api(String::from(url))
+        } else if let Some(filename) = args.value_of("weather-csv") {
+            let conditions = read_weather_csv(filename)?;
+            info!("Read {} weather conditions from {}.", conditions.len(), filename);
+            WeatherSource::Csv(conditions)
+        } else {
+            return Ok(None);
+        };
+        Ok(Some(WeatherProvider {
+            source,
+            api_cache: Mutex::new(ApiCache::default()),
+        }))
+    }
+
+    /// Returns the weather condition at the given time, requesting it from the API if needed.
+    /// Unknown if there is no data for that time.
+    pub fn get_condition(&self, time: DateTime<Local>) -> FnResult<WeatherCondition> {
+        if let WeatherSource::Api(url) = &self.source {
+            self.fetch_if_needed(url, time)?;
+        }
+        Ok(self.get_forecast(time))
+    }
+
+    /// Returns the weather condition at the given time, without ever requesting the API, so that it
+    /// can be used while predicting. Unknown if the condition has not been fetched with `refresh` before.
+    pub fn get_forecast(&self, time: DateTime<Local>) -> WeatherCondition {
+        match &self.source {
+            WeatherSource::Csv(conditions) => {
+                conditions.range(..=time).next_back().map(|(_, condition)| *condition).unwrap_or_default()
+            },
+            WeatherSource::Api(_) => {
+                let hour = time.timestamp().div_euclid(3600);
+                self.api_cache.lock().unwrap().conditions.get(&hour).map(|(condition, _)| *condition).unwrap_or_default()
+            },
+        }
+    }
+
+    /// Requests the conditions from `from` until `until` from the API, unless they have been fetched recently.
+    /// Failures are only logged, as predictions can be made without the weather as well.
+    pub fn refresh(&self, from: DateTime<Local>, until: DateTime<Local>) {
+        if let WeatherSource::Api(url) = &self.source {
+            let mut time = from;
+            while time <= until {
+                if let Err(e) = self.fetch_if_needed(url, time) {
+                    warn!("Could not get weather forecast: {}", e);
+                    return;
+                }
+                // each request returns the conditions of a whole day
+                time = time + chrono::Duration::days(1);
+            }
+        }
+    }
+
+    // requests the day that starts at `time`, unless its first hour is in the cache and not outdated
+    fn fetch_if_needed(&self, url: &str, time: DateTime<Local>) -> FnResult<()> {
+        let hour = time.timestamp().div_euclid(3600);
+        {
+            let api_cache = self.api_cache.lock().unwrap();
+            if let Some((_, fetched)) = api_cache.conditions.get(&hour) {
+                if fetched.elapsed() < CACHE_TTL {
+                    return Ok(());
+                }
+            }
+            if let Some(failure) = api_cache.last_failure {
+                if failure.elapsed() < RETRY_DELAY {
+                    bail!("The weather API failed less than {} minutes ago.", RETRY_DELAY.as_secs() / 60);
+                }
+            }
+        }
+        // the lock is not held during the request, so that cached conditions can still be read
+        let fetched = fetch_from_api(url, time);
+
+        let mut api_cache = self.api_cache.lock().unwrap();
+        match fetched {
+            Ok(conditions) => {
+                let now = Instant::now();
+                api_cache.last_failure = None;
+                for (fetched_hour, condition) in conditions {
+                    api_cache.conditions.insert(fetched_hour, (condition, now));
+                }
+                let oldest_hour = Local::now().timestamp().div_euclid(3600) - MAX_PAST_HOURS;
+                api_cache.conditions.retain(|cached_hour, _| *cached_hour >= oldest_hour);
+                Ok(())
+            },
+            Err(e) => {
+                api_cache.last_failure = Some(Instant::now());
+                Err(e)
+            },
+        }
+    }
+
+    /// Gets the current weather condition and stores it in the `weather_observations` table.
+    pub fn record(&self, pool: &Pool, source: &str, time: DateTime<Local>) -> FnResult<WeatherCondition> {
+        let condition = self.get_condition(time)?;
+        if condition != WeatherCondition::Unknown {
+            let mut con = pool.get_conn()?;
+            con.exec_drop(
+                r"INSERT INTO `weather_observations` (`source`, `hour`, `weather_condition`)
+                VALUES (:source, DATE_FORMAT(:time, '%Y-%m-%d %H:00:00'), :weather_condition)
+                ON DUPLICATE KEY UPDATE `weather_condition` = :weather_condition",
+                params! {
+                    source,
+                    "time" => time.naive_local(),
+                    "weather_condition" => condition.to_int(),
+                },
+            )?;
+        }
+        Ok(condition)
+    }
+
+    pub fn create_table(pool: &Pool) -> FnResult<()> {
+        let mut con = pool.get_conn()?;
+        con.query_drop(r"CREATE TABLE IF NOT EXISTS `weather_observations` (
+            `source` VARCHAR(255) NOT NULL,
+            `hour` DATETIME NOT NULL,
+            `weather_condition` TINYINT UNSIGNED NOT NULL,
+            PRIMARY KEY (`source`, `hour`)
+        );")?;
+        Ok(())
+    }
+}
+
+// requests the conditions of the day that starts at the given time, by hours since the epoch
+fn fetch_from_api(url: &str, time: DateTime<Local>) -> FnResult<Vec<(i64, WeatherCondition)>> {
+    let mut url = Url::parse(url)?;
+    url.query_pairs_mut().append_pair("date", &time.to_rfc3339());
+    let response = ureq::get(url.as_str()).timeout_connect(API_TIMEOUT_MS).timeout_read(API_TIMEOUT_MS).call();
+    if !response.ok() {
+        bail!("Weather API returned status {} for {}.", response.status(), url);
+    }
+    let api_response: ApiResponse = serde_json::from_str(&response.into_string()?)?;
+    let mut conditions = Vec::new();
+    for record in api_response.weather {
+        let timestamp = DateTime::parse_from_rfc3339(&record.timestamp)?;
+        let condition = record.condition.map(|c| WeatherCondition::from_description(&c)).unwrap_or_default();
+        conditions.push((timestamp.timestamp().div_euclid(3600), condition));
+    }
+    Ok(conditions)
+}
+
+/// Reads a CSV file with the columns `time` (RFC 3339, e.g. `2020-07-16T14:00:00+02:00`)
+/// and `condition` (e.g. `dry`, `rain` or `snow`). A header line is optional. Each condition
+/// is valid from its time until the time of the next line.
+fn read_weather_csv(filename: &str) -> FnResult<BTreeMap<DateTime<Local>, WeatherCondition>> {
+    parse_weather_csv(File::open(filename)?)
+}
+
+fn parse_weather_csv<R: Read>(input: R) -> FnResult<BTreeMap<DateTime<Local>, WeatherCondition>> {
+    let mut reader = csv::ReaderBuilder::new().has_headers(false).flexible(true).trim(csv::Trim::All).from_reader(input);
+    let mut conditions = BTreeMap::new();
+    for (index, record) in reader.records().enumerate() {
+        let record = record?;
+        if index == 0 && record.get(0) == Some("time") {
+            continue;
+        }
+        let line = record.position().map_or(index as u64 + 1, |position| position.line());
+        match (record.get(0), record.get(1)) {
+            (Some(time), Some(condition)) => {
+                let time = Local.from_utc_datetime(&DateTime::parse_from_rfc3339(time)?.naive_utc());
+                conditions.insert(time, WeatherCondition::from_description(condition));
+            },
+            _ => bail!("Line {} has less than two columns.", line),
+        }
+    }
+    Ok(conditions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_weather_csv() {
+        let csv = "time,condition\n2020-07-16T14:00:00+02:00, rain\n\n2020-07-16T16:00:00+02:00,Dry\n";
+        let provider = WeatherProvider {
+            source: WeatherSource::Csv(parse_weather_csv(csv.as_bytes()).unwrap()),
+            api_cache: Mutex::new(ApiCache::default()),
+        };
+        let time = |hour: u32, minute: u32| Local.from_utc_datetime(&chrono::NaiveDate::from_ymd(2020, 7, 16).and_hms(hour - 2, minute, 0));
+        assert_eq!(provider.get_forecast(time(13, 59)), WeatherCondition::Unknown);
+        assert_eq!(provider.get_forecast(time(14, 0)), WeatherCondition::Rain);
+        assert_eq!(provider.get_forecast(time(15, 30)), WeatherCondition::Rain);
+        assert_eq!(provider.get_condition(time(16, 0)).unwrap(), WeatherCondition::Dry);
+
+        assert!(parse_weather_csv("2020-07-16T14:00:00+02:00\n".as_bytes()).is_err());
+        assert!(parse_weather_csv("14:00,rain\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_failures_are_not_cached() {
+        // nothing listens on port 1, so the request fails right away
+        let provider = WeatherProvider {
+            source: WeatherSource::Api(String::from("http://127.0.0.1:1/weather")),
+            api_cache: Mutex::new(ApiCache::default()),
+        };
+        let now = Local::now();
+        assert!(provider.get_condition(now).is_err());
+        assert!(provider.api_cache.lock().unwrap().conditions.is_empty());
+        assert!(provider.api_cache.lock().unwrap().last_failure.is_some());
+        // the API is not requested again right away, and predictions just don't know the weather
+        assert!(provider.get_condition(now).is_err());
+        provider.refresh(now, now);
+        assert_eq!(provider.get_forecast(now), WeatherCondition::Unknown);
+
+        // fetched conditions are used until they are outdated
+        let hour = now.timestamp().div_euclid(3600);
+        provider.api_cache.lock().unwrap().conditions.insert(hour, (WeatherCondition::Snow, Instant::now()));
+        assert_eq!(provider.get_condition(now).unwrap(), WeatherCondition::Snow);
+    }
+}